@@ -288,6 +288,12 @@ impl ApiServer {
             VmmAction::LoadSnapshot(_) => {
                 Some((&METRICS.latencies_us.load_snapshot, "load snapshot"))
             }
+            VmmAction::ReceiveMigration(_) => {
+                Some((&METRICS.latencies_us.receive_migration, "receive migration"))
+            }
+            VmmAction::SendMigration(_) => {
+                Some((&METRICS.latencies_us.send_migration, "send migration"))
+            }
             VmmAction::Pause => Some((&METRICS.latencies_us.pause_vm, "pause vm")),
             VmmAction::Resume => Some((&METRICS.latencies_us.resume_vm, "resume vm")),
             _ => None,
@@ -337,7 +343,7 @@ mod tests {
     use vmm::rpc_interface::VmmActionError;
     use vmm::seccomp_filters::{get_filters, SeccompConfig};
     use vmm::vmm_config::instance_info::InstanceInfo;
-    use vmm::vmm_config::snapshot::CreateSnapshotParams;
+    use vmm::vmm_config::snapshot::{CreateSnapshotParams, MemFileFormat};
 
     use super::*;
 
@@ -401,6 +407,7 @@ mod tests {
                 snapshot_type: SnapshotType::Diff,
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
+                mem_file_format: MemFileFormat::Raw,
                 version: None,
             })),
             start_time_us,
@@ -415,6 +422,7 @@ mod tests {
                 snapshot_type: SnapshotType::Diff,
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
+                mem_file_format: MemFileFormat::Raw,
                 version: None,
             })),
             start_time_us,
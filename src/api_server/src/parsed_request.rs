@@ -11,17 +11,19 @@ use super::VmmData;
 use crate::request::actions::parse_put_actions;
 use crate::request::balloon::{parse_get_balloon, parse_patch_balloon, parse_put_balloon};
 use crate::request::boot_source::parse_put_boot_source;
-use crate::request::drive::{parse_patch_drive, parse_put_drive};
+use crate::request::drive::{parse_patch_drive, parse_put_drive, parse_put_drive_detach};
 use crate::request::instance_info::parse_get_instance_info;
 use crate::request::logger::parse_put_logger;
 use crate::request::machine_configuration::{
     parse_get_machine_config, parse_patch_machine_config, parse_put_machine_config,
 };
 use crate::request::metrics::parse_put_metrics;
+use crate::request::migration::parse_put_migration;
 use crate::request::mmds::{parse_get_mmds, parse_patch_mmds, parse_put_mmds};
 use crate::request::net::{parse_patch_net, parse_put_net};
 use crate::request::snapshot::{parse_patch_vm_state, parse_put_snapshot};
 use crate::request::version::parse_get_version;
+use crate::request::vhost_user::{parse_put_vhost_user_block, parse_put_vhost_user_net};
 use crate::request::vsock::parse_put_vsock;
 use crate::ApiServer;
 
@@ -105,9 +107,13 @@ impl ParsedRequest {
             (Method::Put, "balloon", Some(body)) => parse_put_balloon(body),
             (Method::Put, "boot-source", Some(body)) => parse_put_boot_source(body),
             (Method::Put, "drives", Some(body)) => parse_put_drive(body, path_tokens.get(1)),
+            (Method::Put, "drives", None) if path_tokens.get(2) == Some(&"detach") => {
+                parse_put_drive_detach(path_tokens.get(1))
+            }
             (Method::Put, "logger", Some(body)) => parse_put_logger(body),
             (Method::Put, "machine-config", Some(body)) => parse_put_machine_config(body),
             (Method::Put, "metrics", Some(body)) => parse_put_metrics(body),
+            (Method::Put, "migration", Some(body)) => parse_put_migration(body, path_tokens.get(1)),
             (Method::Put, "mmds", Some(body)) => parse_put_mmds(body, path_tokens.get(1)),
             (Method::Put, "network-interfaces", Some(body)) => {
                 parse_put_net(body, path_tokens.get(1))
@@ -116,6 +122,12 @@ impl ParsedRequest {
                 Ok(ParsedRequest::new(RequestAction::ShutdownInternal))
             }
             (Method::Put, "snapshot", Some(body)) => parse_put_snapshot(body, path_tokens.get(1)),
+            (Method::Put, "vhost-user-block", Some(body)) => {
+                parse_put_vhost_user_block(body, path_tokens.get(1))
+            }
+            (Method::Put, "vhost-user-net", Some(body)) => {
+                parse_put_vhost_user_net(body, path_tokens.get(1))
+            }
             (Method::Put, "vsock", Some(body)) => parse_put_vsock(body),
             (Method::Put, _, None) => method_to_error(Method::Put),
             (Method::Patch, "balloon", Some(body)) => parse_patch_balloon(body, path_tokens.get(1)),
@@ -748,6 +760,26 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_put_drive_detach() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        sender
+            .write_all(http_request("PUT", "/drives/string/detach", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+
+        // Only the `detach` sub-resource accepts a body-less PUT.
+        sender
+            .write_all(http_request("PUT", "/drives/string", None).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_err());
+    }
+
     #[test]
     fn test_try_from_put_logger() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -872,6 +904,30 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_put_migration() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \"destination\": { \"transport\": \"Unix\", \"address\": \"foo\" } }";
+        sender
+            .write_all(http_request("PUT", "/migration/send", Some(&body)).as_bytes())
+            .unwrap();
+
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+
+        let body = "{ \"source\": { \"transport\": \"Tcp\", \"address\": \"0.0.0.0:8000\" }, \
+                    \"resume_vm\": true }";
+        sender
+            .write_all(http_request("PUT", "/migration/receive", Some(&body)).as_bytes())
+            .unwrap();
+
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_put_shutdown() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -913,6 +969,27 @@ pub(crate) mod tests {
         assert!(ParsedRequest::try_from_request(&req).is_ok());
     }
 
+    #[test]
+    fn test_try_from_put_vhost_user() {
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        let mut connection = HttpConnection::new(receiver);
+        let body = "{ \"drive_id\": \"blk\", \"socket\": \"string\" }";
+        sender
+            .write_all(http_request("PUT", "/vhost-user-block/blk", Some(&body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+
+        let body = "{ \"iface_id\": \"net\", \"socket\": \"string\" }";
+        sender
+            .write_all(http_request("PUT", "/vhost-user-net/net", Some(&body)).as_bytes())
+            .unwrap();
+        assert!(connection.try_read().is_ok());
+        let req = connection.pop_parsed_request().unwrap();
+        assert!(ParsedRequest::try_from_request(&req).is_ok());
+    }
+
     #[test]
     fn test_try_from_patch_balloon() {
         let (mut sender, receiver) = UnixStream::pair().unwrap();
@@ -165,5 +165,27 @@ mod tests {
                 "stats_polling_interval_s": 0
             }"#;
         assert!(parse_put_balloon(&Body::new(body)).is_ok());
+
+        // PUT with free page reporting enabled.
+        let body = r#"{
+                "amount_mib": 1000,
+                "deflate_on_oom": true,
+                "free_page_reporting": true
+            }"#;
+        assert!(parse_put_balloon(&Body::new(body)).is_ok());
+
+        // PUT with the balloon controller enabled.
+        let body = r#"{
+                "amount_mib": 0,
+                "deflate_on_oom": true,
+                "stats_polling_interval_s": 1,
+                "auto_adjust": {
+                    "target_free_memory_percent": 20,
+                    "min_amount_mib": 0,
+                    "max_amount_mib": 1000,
+                    "step_mib": 10
+                }
+            }"#;
+        assert!(parse_put_balloon(&Body::new(body)).is_ok());
     }
 }
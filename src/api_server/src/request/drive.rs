@@ -38,6 +38,20 @@ pub(crate) fn parse_put_drive(
     }
 }
 
+pub(crate) fn parse_put_drive_detach(id_from_path: Option<&&str>) -> Result<ParsedRequest, Error> {
+    METRICS.put_api_requests.drive_count.inc();
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        METRICS.put_api_requests.drive_fails.inc();
+        return Err(Error::EmptyID);
+    };
+
+    Ok(ParsedRequest::new_sync(VmmAction::RemoveBlockDevice(
+        id.to_string(),
+    )))
+}
+
 pub(crate) fn parse_patch_drive(
     body: &Body,
     id_from_path: Option<&&str>,
@@ -67,14 +81,17 @@ pub(crate) fn parse_patch_drive(
     // Validate request - we need to have at least one parameter set:
     // - path_on_host
     // - rate_limiter
+    // - cow_overlay_path
     if block_device_update_cfg.path_on_host.is_none()
         && block_device_update_cfg.rate_limiter.is_none()
+        && block_device_update_cfg.cow_overlay_path.is_none()
     {
         METRICS.patch_api_requests.drive_fails.inc();
         return Err(Error::Generic(
             StatusCode::BadRequest,
             String::from(
-                "Please specify at least one property to patch: path_on_host, rate_limiter.",
+                "Please specify at least one property to patch: path_on_host, rate_limiter, \
+                 cow_overlay_path.",
             ),
         ));
     }
@@ -89,6 +106,17 @@ mod tests {
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
 
+    #[test]
+    fn test_parse_put_drive_detach_request() {
+        assert!(parse_put_drive_detach(None).is_err());
+        assert!(parse_put_drive_detach(Some(&"invalid id")).is_err());
+
+        match vmm_action_from_request(parse_put_drive_detach(Some(&"scratch")).unwrap()) {
+            VmmAction::RemoveBlockDevice(drive_id) => assert_eq!(drive_id, "scratch"),
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+    }
+
     #[test]
     fn test_parse_patch_drive_request() {
         assert!(parse_patch_drive(&Body::new("invalid_payload"), None).is_err());
@@ -199,6 +227,20 @@ mod tests {
         // Validate that updating both path and rate limiter succeds.
         assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_ok());
 
+        let body = r#"{
+            "drive_id": "foo",
+            "cow_overlay_path": "/overlay"
+        }"#;
+        // Validate that updating just the copy-on-write overlay works.
+        #[allow(clippy::match_wild_err_arm)]
+        match vmm_action_from_request(parse_patch_drive(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::UpdateBlockDevice(cfg) => {
+                assert_eq!(cfg.cow_overlay_path.unwrap(), "/overlay".to_string());
+                assert!(cfg.path_on_host.is_none());
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+
         let body = r#"{
             "drive_id": "foo",
             "path_on_host": "/there",
@@ -245,6 +287,9 @@ mod tests {
                 "is_read_only": true,
                 "cache_type": "Unsafe",
                 "io_engine": "Sync",
+                "num_queues": 2,
+                "format": "qcow2",
+                "cow_overlay_path": "dummy_overlay",
                 "rate_limiter": {
                     "bandwidth": {
                         "size": 0,
@@ -45,7 +45,7 @@ pub(crate) fn parse_patch_machine_config(body: &Body) -> Result<ParsedRequest, E
 
 #[cfg(test)]
 mod tests {
-    use vmm::vmm_config::machine_config::CpuFeaturesTemplate;
+    use vmm::vmm_config::machine_config::{CpuFeaturesTemplate, MemoryBacking};
 
     use super::*;
     use crate::parsed_request::tests::vmm_action_from_request;
@@ -84,6 +84,10 @@ mod tests {
             smt: Some(false),
             cpu_template: Some(CpuFeaturesTemplate::None),
             track_dirty_pages: Some(false),
+            backing: Some(MemoryBacking::Anonymous),
+            hotplug_size_mib: Some(0),
+            hotplug_requested_mib: Some(0),
+            max_vcpu_count: Some(0),
         };
 
         match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -103,6 +107,10 @@ mod tests {
             smt: Some(false),
             cpu_template: Some(CpuFeaturesTemplate::None),
             track_dirty_pages: Some(true),
+            backing: Some(MemoryBacking::Anonymous),
+            hotplug_size_mib: Some(0),
+            hotplug_requested_mib: Some(0),
+            max_vcpu_count: Some(0),
         };
 
         match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -110,6 +118,35 @@ mod tests {
             _ => panic!("Test failed."),
         }
 
+        let body = r#"{
+                "vcpu_count": 8,
+                "mem_size_mib": 1024,
+                "backing": "hugetlbfs-2M"
+            }"#;
+        let expected_config = VmUpdateConfig {
+            vcpu_count: Some(8),
+            mem_size_mib: Some(1024),
+            smt: Some(false),
+            cpu_template: Some(CpuFeaturesTemplate::None),
+            track_dirty_pages: Some(false),
+            backing: Some(MemoryBacking::Hugetlbfs2M),
+            hotplug_size_mib: Some(0),
+            hotplug_requested_mib: Some(0),
+            max_vcpu_count: Some(0),
+        };
+
+        match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
+            VmmAction::UpdateVmConfiguration(config) => assert_eq!(config, expected_config),
+            _ => panic!("Test failed."),
+        }
+
+        let body = r#"{
+                "vcpu_count": 8,
+                "mem_size_mib": 1024,
+                "backing": "hugetlbfs"
+            }"#;
+        assert!(parse_put_machine_config(&Body::new(body)).is_err());
+
         // 4. Test that applying a CPU template is successful on x86_64 while on aarch64, it is not.
         let body = r#"{
                 "vcpu_count": 8,
@@ -128,6 +165,10 @@ mod tests {
                 smt: Some(false),
                 cpu_template: Some(CpuFeaturesTemplate::T2),
                 track_dirty_pages: Some(true),
+                backing: Some(MemoryBacking::Anonymous),
+                hotplug_size_mib: Some(0),
+                hotplug_requested_mib: Some(0),
+                max_vcpu_count: Some(0),
             };
 
             match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -157,6 +198,10 @@ mod tests {
                 smt: Some(true),
                 cpu_template: Some(CpuFeaturesTemplate::None),
                 track_dirty_pages: Some(true),
+                backing: Some(MemoryBacking::Anonymous),
+                hotplug_size_mib: Some(0),
+                hotplug_requested_mib: Some(0),
+                max_vcpu_count: Some(0),
             };
 
             match vmm_action_from_request(parse_put_machine_config(&Body::new(body)).unwrap()) {
@@ -182,6 +227,21 @@ mod tests {
               }"#;
         assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
 
+        let body = r#"{
+                "backing": "memfd"
+              }"#;
+        assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
+
+        let body = r#"{
+                "hotplug_requested_mib": 512
+              }"#;
+        assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
+
+        let body = r#"{
+                "max_vcpu_count": 8
+              }"#;
+        assert!(parse_patch_machine_config(&Body::new(body)).is_ok());
+
         // On aarch64, CPU template is also not patch compatible.
         let body = r#"{
                 "cpu_template": "T2"
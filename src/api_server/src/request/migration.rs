@@ -0,0 +1,131 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use vmm::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
+
+use super::super::VmmAction;
+use crate::parsed_request::{Error, ParsedRequest};
+use crate::request::{Body, Method, StatusCode};
+
+pub(crate) fn parse_put_migration(
+    body: &Body,
+    request_type_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    match request_type_from_path {
+        Some(&request_type) => match request_type {
+            "send" => Ok(ParsedRequest::new_sync(VmmAction::SendMigration(
+                serde_json::from_slice::<SendMigrationParams>(body.raw())?,
+            ))),
+            "receive" => Ok(ParsedRequest::new_sync(VmmAction::ReceiveMigration(
+                serde_json::from_slice::<ReceiveMigrationParams>(body.raw())?,
+            ))),
+            _ => Err(Error::InvalidPathMethod(
+                format!("/migration/{}", request_type),
+                Method::Put,
+            )),
+        },
+        None => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "Missing migration operation type.".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vmm::vmm_config::migration::{MigrationSocketConfig, MigrationTransport};
+
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+
+    #[test]
+    fn test_parse_put_migration() {
+        let body = r#"{
+                "destination": {
+                    "transport": "Tcp",
+                    "address": "10.0.0.2:8000"
+                }
+              }"#;
+        let expected_params = SendMigrationParams {
+            destination: MigrationSocketConfig {
+                transport: MigrationTransport::Tcp,
+                address: "10.0.0.2:8000".to_string(),
+            },
+            max_rounds: 8,
+            dirty_pages_threshold: 256,
+        };
+        match vmm_action_from_request(parse_put_migration(&Body::new(body), Some(&"send")).unwrap())
+        {
+            VmmAction::SendMigration(params) => assert_eq!(params, expected_params),
+            _ => panic!("Test failed."),
+        }
+
+        let body = r#"{
+                "destination": {
+                    "transport": "Unix",
+                    "address": "/tmp/migration.sock"
+                },
+                "max_rounds": 2,
+                "dirty_pages_threshold": 0
+              }"#;
+        let expected_params = SendMigrationParams {
+            destination: MigrationSocketConfig {
+                transport: MigrationTransport::Unix,
+                address: "/tmp/migration.sock".to_string(),
+            },
+            max_rounds: 2,
+            dirty_pages_threshold: 0,
+        };
+        match vmm_action_from_request(parse_put_migration(&Body::new(body), Some(&"send")).unwrap())
+        {
+            VmmAction::SendMigration(params) => assert_eq!(params, expected_params),
+            _ => panic!("Test failed."),
+        }
+
+        let body = r#"{
+                "source": {
+                    "transport": "Unix",
+                    "address": "/tmp/migration.sock"
+                },
+                "resume_vm": true
+              }"#;
+        let expected_params = ReceiveMigrationParams {
+            source: MigrationSocketConfig {
+                transport: MigrationTransport::Unix,
+                address: "/tmp/migration.sock".to_string(),
+            },
+            enable_diff_snapshots: false,
+            resume_vm: true,
+        };
+        match vmm_action_from_request(
+            parse_put_migration(&Body::new(body), Some(&"receive")).unwrap(),
+        ) {
+            VmmAction::ReceiveMigration(params) => assert_eq!(params, expected_params),
+            _ => panic!("Test failed."),
+        }
+
+        // Unknown transport.
+        let body = r#"{
+                "source": {
+                    "transport": "Vsock",
+                    "address": "3:8000"
+                }
+              }"#;
+        assert!(parse_put_migration(&Body::new(body), Some(&"receive")).is_err());
+
+        // Missing socket.
+        let body = r#"{
+                "resume_vm": true
+              }"#;
+        assert!(parse_put_migration(&Body::new(body), Some(&"receive")).is_err());
+
+        let body = r#"{
+                "source": {
+                    "transport": "Unix",
+                    "address": "/tmp/migration.sock"
+                }
+              }"#;
+        assert!(parse_put_migration(&Body::new(body), Some(&"invalid")).is_err());
+        assert!(parse_put_migration(&Body::new(body), None).is_err());
+    }
+}
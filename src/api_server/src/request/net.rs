@@ -83,7 +83,9 @@ mod tests {
         let body = r#"{
                 "iface_id": "foo",
                 "host_dev_name": "bar",
-                "guest_mac": "12:34:56:78:9A:BC"
+                "guest_mac": "12:34:56:78:9A:BC",
+                "num_queue_pairs": 2,
+                "backend": "vhost-net"
               }"#;
         // 1. Exercise infamous "The id from the path does not match id from the body!".
         assert!(parse_put_net(&Body::new(body), Some(&"bar")).is_err());
@@ -116,6 +118,14 @@ mod tests {
         }"#;
 
         assert!(parse_put_net(&Body::new(body), Some(&"foo")).is_err());
+
+        // 5. Serde error for an unknown backend.
+        let body = r#"{
+                "iface_id": "foo",
+                "host_dev_name": "bar",
+                "backend": "vhost-user"
+              }"#;
+        assert!(parse_put_net(&Body::new(body), Some(&"foo")).is_err());
     }
 
     #[test]
@@ -157,5 +167,35 @@ mod tests {
             }
         }"#;
         assert!(parse_patch_net(&Body::new(body), Some(&"foo")).is_err());
+
+        // 5. Success case for a TX filter update.
+        let body = r#"{
+                "iface_id": "foo",
+                "tx_filter": {
+                    "drop_spoofed_mac": true,
+                    "allowed_ipv4_sources": ["192.168.0.2"],
+                    "drop_spoofed_arp": true
+                }
+        }"#;
+        match vmm_action_from_request(parse_patch_net(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::UpdateNetworkInterface(netif) => {
+                let tx_filter = netif.tx_filter.unwrap();
+                assert!(tx_filter.drop_spoofed_mac && tx_filter.drop_spoofed_arp);
+                assert_eq!(
+                    tx_filter.allowed_ipv4_sources,
+                    Some(vec!["192.168.0.2".parse().unwrap()])
+                );
+            }
+            _ => panic!("Test failed."),
+        }
+
+        // 6. Serde error for an invalid IPv4 source.
+        let body = r#"{
+                "iface_id": "foo",
+                "tx_filter": {
+                    "allowed_ipv4_sources": ["192.168.0.256"]
+                }
+        }"#;
+        assert!(parse_patch_net(&Body::new(body), Some(&"foo")).is_err());
     }
 }
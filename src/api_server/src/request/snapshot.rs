@@ -92,6 +92,7 @@ fn parse_put_snapshot_load(body: &Body) -> Result<ParsedRequest, Error> {
     let snapshot_params = LoadSnapshotParams {
         snapshot_path: snapshot_config.snapshot_path,
         mem_backend,
+        mem_diff_paths: snapshot_config.mem_diff_paths,
         enable_diff_snapshots: snapshot_config.enable_diff_snapshots,
         resume_vm: snapshot_config.resume_vm,
     };
@@ -118,7 +119,7 @@ mod tests {
     fn test_parse_put_snapshot() {
         use std::path::PathBuf;
 
-        use vmm::vmm_config::snapshot::SnapshotType;
+        use vmm::vmm_config::snapshot::{MemFileFormat, SnapshotType};
 
         let mut body = r#"{
                 "snapshot_type": "Diff",
@@ -131,6 +132,7 @@ mod tests {
             snapshot_type: SnapshotType::Diff,
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
+            mem_file_format: MemFileFormat::Raw,
             version: Some(String::from("0.23.0")),
         };
 
@@ -150,6 +152,28 @@ mod tests {
             snapshot_type: SnapshotType::Full,
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
+            mem_file_format: MemFileFormat::Raw,
+            version: None,
+        };
+
+        match vmm_action_from_request(
+            parse_put_snapshot(&Body::new(body), Some(&"create")).unwrap(),
+        ) {
+            VmmAction::CreateSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "mem_file_format": "Compressed"
+              }"#;
+
+        expected_cfg = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: PathBuf::from("bar"),
+            mem_file_format: MemFileFormat::Compressed,
             version: None,
         };
 
@@ -181,6 +205,7 @@ mod tests {
                 backend_path: PathBuf::from("bar"),
                 backend_type: MemBackendType::File,
             },
+            mem_diff_paths: Vec::new(),
             enable_diff_snapshots: false,
             resume_vm: false,
         };
@@ -202,6 +227,7 @@ mod tests {
                     "backend_path": "bar",
                     "backend_type": "File"
                 },
+                "mem_diff_paths": ["diff1", "diff2"],
                 "enable_diff_snapshots": true
               }"#;
 
@@ -211,6 +237,7 @@ mod tests {
                 backend_path: PathBuf::from("bar"),
                 backend_type: MemBackendType::File,
             },
+            mem_diff_paths: vec![PathBuf::from("diff1"), PathBuf::from("diff2")],
             enable_diff_snapshots: true,
             resume_vm: false,
         };
@@ -240,6 +267,7 @@ mod tests {
                 backend_path: PathBuf::from("bar"),
                 backend_type: MemBackendType::Uffd,
             },
+            mem_diff_paths: Vec::new(),
             enable_diff_snapshots: false,
             resume_vm: true,
         };
@@ -266,6 +294,7 @@ mod tests {
                 backend_path: PathBuf::from("bar"),
                 backend_type: MemBackendType::File,
             },
+            mem_diff_paths: Vec::new(),
             enable_diff_snapshots: false,
             resume_vm: true,
         };
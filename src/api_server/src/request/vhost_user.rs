@@ -0,0 +1,143 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use logger::{IncMetric, METRICS};
+use vmm::vmm_config::vhost_user::{VhostUserBlockConfig, VhostUserNetConfig};
+
+use super::super::VmmAction;
+use crate::parsed_request::{checked_id, Error, ParsedRequest};
+use crate::request::{Body, StatusCode};
+
+fn checked_path_id<'a>(id_from_path: Option<&&'a str>) -> Result<&'a str, Error> {
+    match id_from_path {
+        Some(id) => checked_id(*id),
+        None => Err(Error::EmptyID),
+    }
+}
+
+fn id_mismatch(id: &str, id_from_body: &str) -> Error {
+    Error::Generic(
+        StatusCode::BadRequest,
+        format!(
+            "The id from the path [{}] does not match the id from the body [{}]!",
+            id, id_from_body
+        ),
+    )
+}
+
+pub(crate) fn parse_put_vhost_user_block(
+    body: &Body,
+    id_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.put_api_requests.vhost_user_count.inc();
+    let id = checked_path_id(id_from_path).map_err(|err| {
+        METRICS.put_api_requests.vhost_user_fails.inc();
+        err
+    })?;
+
+    let config = serde_json::from_slice::<VhostUserBlockConfig>(body.raw()).map_err(|err| {
+        METRICS.put_api_requests.vhost_user_fails.inc();
+        err
+    })?;
+    if id != config.drive_id {
+        METRICS.put_api_requests.vhost_user_fails.inc();
+        return Err(id_mismatch(id, &config.drive_id));
+    }
+    Ok(ParsedRequest::new_sync(VmmAction::SetVhostUserBlockDevice(
+        config,
+    )))
+}
+
+pub(crate) fn parse_put_vhost_user_net(
+    body: &Body,
+    id_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.put_api_requests.vhost_user_count.inc();
+    let id = checked_path_id(id_from_path).map_err(|err| {
+        METRICS.put_api_requests.vhost_user_fails.inc();
+        err
+    })?;
+
+    let config = serde_json::from_slice::<VhostUserNetConfig>(body.raw()).map_err(|err| {
+        METRICS.put_api_requests.vhost_user_fails.inc();
+        err
+    })?;
+    if id != config.iface_id {
+        METRICS.put_api_requests.vhost_user_fails.inc();
+        return Err(id_mismatch(id, &config.iface_id));
+    }
+    Ok(ParsedRequest::new_sync(VmmAction::SetVhostUserNetDevice(
+        config,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+
+    #[test]
+    fn test_parse_put_vhost_user_block_request() {
+        let body = r#"{
+                "drive_id": "foo",
+                "socket": "/tmp/vhost-user-blk.sock",
+                "num_queues": 2
+              }"#;
+        // 1. The id from the path must match the one from the body.
+        assert!(parse_put_vhost_user_block(&Body::new(body), Some(&"bar")).is_err());
+        // 2. The `id_from_path` cannot be None.
+        assert!(parse_put_vhost_user_block(&Body::new(body), None).is_err());
+
+        // 3. Success case, with the default queue size.
+        let expected = VhostUserBlockConfig {
+            drive_id: String::from("foo"),
+            socket: String::from("/tmp/vhost-user-blk.sock"),
+            num_queues: 2,
+            queue_size: 256,
+        };
+        match vmm_action_from_request(
+            parse_put_vhost_user_block(&Body::new(body), Some(&"foo")).unwrap(),
+        ) {
+            VmmAction::SetVhostUserBlockDevice(config) => assert_eq!(config, expected),
+            _ => panic!("Test failed."),
+        }
+
+        // 4. Serde error for an unknown field.
+        let body = r#"{
+                "drive_id": "foo",
+                "socket": "/tmp/vhost-user-blk.sock",
+                "is_read_only": true
+              }"#;
+        assert!(parse_put_vhost_user_block(&Body::new(body), Some(&"foo")).is_err());
+    }
+
+    #[test]
+    fn test_parse_put_vhost_user_net_request() {
+        let body = r#"{
+                "iface_id": "foo",
+                "socket": "/tmp/vhost-user-net.sock",
+                "guest_mac": "12:34:56:78:9A:BC",
+                "queue_size": 512
+              }"#;
+        // 1. The id from the path must match the one from the body.
+        assert!(parse_put_vhost_user_net(&Body::new(body), Some(&"bar")).is_err());
+        // 2. The `id_from_path` cannot be None.
+        assert!(parse_put_vhost_user_net(&Body::new(body), None).is_err());
+
+        // 3. Success case.
+        let expected = serde_json::from_str::<VhostUserNetConfig>(body).unwrap();
+        assert_eq!(expected.queue_size, 512);
+        match vmm_action_from_request(
+            parse_put_vhost_user_net(&Body::new(body), Some(&"foo")).unwrap(),
+        ) {
+            VmmAction::SetVhostUserNetDevice(config) => assert_eq!(config, expected),
+            _ => panic!("Test failed."),
+        }
+
+        // 4. Serde error for a missing socket.
+        let body = r#"{
+                "iface_id": "foo"
+              }"#;
+        assert!(parse_put_vhost_user_net(&Body::new(body), Some(&"foo")).is_err());
+    }
+}
@@ -41,6 +41,20 @@ pub fn arch_memory_regions(size: usize) -> Vec<(GuestAddress, usize)> {
     vec![(GuestAddress(layout::DRAM_MEM_START), dram_size)]
 }
 
+/// Returns the start address and the size of the hot-pluggable memory region of `size` bytes,
+/// for a guest with `ram_size` bytes of boot memory.
+/// The region follows the DRAM.
+pub fn hotplug_memory_region(ram_size: usize, size: usize) -> (GuestAddress, usize) {
+    let dram_size = min(ram_size as u64, layout::DRAM_MEM_MAX_SIZE);
+    (
+        GuestAddress(crate::align_up(
+            layout::DRAM_MEM_START + dram_size,
+            crate::HOTPLUG_MEM_ALIGNMENT,
+        )),
+        size,
+    )
+}
+
 /// Configures the system and should be called once per vm before starting vcpu threads.
 /// For aarch64, we only setup the FDT.
 ///
@@ -127,6 +141,16 @@ mod tests {
         assert_eq!(super::layout::DRAM_MEM_MAX_SIZE, regions[0].1 as u64);
     }
 
+    #[test]
+    fn test_hotplug_memory_region() {
+        let (addr, size) = hotplug_memory_region(1usize << 29, 1usize << 30);
+        assert_eq!(GuestAddress(layout::DRAM_MEM_START + (1u64 << 30)), addr);
+        assert_eq!(1usize << 30, size);
+
+        let (addr, _) = hotplug_memory_region(1usize << 30, 1usize << 30);
+        assert_eq!(GuestAddress(layout::DRAM_MEM_START + (1u64 << 30)), addr);
+    }
+
     #[test]
     fn test_get_fdt_addr() {
         let regions = arch_memory_regions(layout::FDT_MAX_SIZE - 0x1000);
@@ -15,9 +15,9 @@ pub mod aarch64;
 
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::{
-    arch_memory_regions, configure_system, get_kernel_start, initrd_load_addr,
-    layout::CMDLINE_MAX_SIZE, layout::IRQ_BASE, layout::IRQ_MAX, regs, Error, MMIO_MEM_SIZE,
-    MMIO_MEM_START,
+    arch_memory_regions, configure_system, get_kernel_start, hotplug_memory_region,
+    initrd_load_addr, layout::CMDLINE_MAX_SIZE, layout::IRQ_BASE, layout::IRQ_MAX, regs, Error,
+    MMIO_MEM_SIZE, MMIO_MEM_START,
 };
 
 /// Module for x86_64 related functionality.
@@ -26,9 +26,9 @@ pub mod x86_64;
 
 #[cfg(target_arch = "x86_64")]
 pub use crate::x86_64::{
-    arch_memory_regions, configure_system, get_kernel_start, initrd_load_addr,
-    layout::CMDLINE_MAX_SIZE, layout::IRQ_BASE, layout::IRQ_MAX, Error, MMIO_MEM_SIZE,
-    MMIO_MEM_START,
+    arch_memory_regions, configure_system, get_kernel_start, hotplug_memory_region,
+    initrd_load_addr, layout::CMDLINE_MAX_SIZE, layout::IRQ_BASE, layout::IRQ_MAX, Error,
+    MMIO_MEM_SIZE, MMIO_MEM_START,
 };
 
 /// Type for returning public functions outcome.
@@ -47,6 +47,9 @@ pub enum DeviceType {
     Rtc,
     /// Device Type: BootTimer.
     BootTimer,
+    /// Device Type: CpuHotplug.
+    #[cfg(target_arch = "x86_64")]
+    CpuHotplug,
 }
 
 /// Type for passing information about the initrd in the guest memory.
@@ -60,6 +63,14 @@ pub struct InitrdConfig {
 /// Default (smallest) memory page size for the supported architectures.
 pub const PAGE_SIZE: usize = 4096;
 
+/// Alignment of the start of the hot-pluggable memory region, which leaves room for the largest
+/// memory blocks the guest can plug.
+pub const HOTPLUG_MEM_ALIGNMENT: u64 = 1 << 30;
+
+fn align_up(addr: u64, alignment: u64) -> u64 {
+    (addr + alignment - 1) & !(alignment - 1)
+}
+
 impl fmt::Display for DeviceType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -0,0 +1,437 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Builds the ACPI tables describing the hot-pluggable vCPUs to the guest.
+//!
+//! The tables are only written when vCPUs can be hot-plugged, and describe nothing but the vCPUs:
+//! the FADT declares hardware-reduced ACPI, and the MADT lists no I/O APIC, so the guest keeps
+//! finding the interrupt routing in the MP table, and the devices on the kernel command line.
+//! The vCPUs are described by processor devices in the DSDT, whose presence is read from the
+//! vCPU hotplug controller. The controller interrupts the guest through a Generic Event Device
+//! when vCPUs are plugged or are to be unplugged.
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::aml::{self, FieldAccess, FieldEntry};
+
+// The guest kernel scans the BIOS read-only memory area for the RSDP.
+const RSDP_START: u64 = 0xe_0000;
+const BIOS_ROM_END: u64 = 0x10_0000;
+// Size reserved for the RSDP, which is followed by the other tables.
+const RSDP_AREA_SIZE: u64 = 0x40;
+const TABLE_ALIGNMENT: u64 = 8;
+
+const OEM_ID: &[u8; 6] = b"FIRECK";
+const OEM_REVISION: u32 = 0;
+const CREATOR_ID: &[u8; 4] = b"FCAT";
+const CREATOR_REVISION: u32 = 1;
+
+// Offsets of the FADT fields, from the start of the table.
+const FADT_LENGTH: usize = 276;
+const FADT_DSDT_OFFSET: usize = 40;
+const FADT_IAPC_BOOT_ARCH_OFFSET: usize = 109;
+const FADT_FLAGS_OFFSET: usize = 112;
+const FADT_X_DSDT_OFFSET: usize = 140;
+// The guest has an i8042 controller, which it uses for reboots.
+const FADT_IAPC_8042: u16 = 1 << 1;
+const FADT_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+const MADT_LOCAL_APIC_ADDR: u32 = 0xfee0_0000;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_LENGTH: u8 = 8;
+const MADT_CPU_ENABLED: u32 = 1 << 0;
+const MADT_CPU_ONLINE_CAPABLE: u32 = 1 << 1;
+
+// Layout of the registers of the vCPU hotplug controller (`devices::pseudo::cpu_hotplug`).
+const CPU_HOTPLUG_REGION_SIZE: u64 = 8;
+const CPU_SELECTOR_BITS: usize = 32;
+
+// `Notify` values for processor devices.
+const NOTIFY_DEVICE_CHECK: u64 = 1;
+const NOTIFY_EJECT_REQUEST: u64 = 3;
+
+/// Errors thrown while writing the ACPI tables.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The tables don't fit in the BIOS read-only memory area.
+    NotEnoughMemory,
+    /// Failure while writing the tables to guest memory.
+    WriteTables,
+}
+
+/// Describes the vCPUs which can be hot-plugged, and the controller signaling their (un)plugging.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuHotplugInfo {
+    /// Number of vCPUs the guest can have, which includes the boot vCPUs.
+    pub max_cpus: u8,
+    /// Address of the MMIO region of the vCPU hotplug controller.
+    pub mmio_addr: u64,
+    /// Interrupt line of the vCPU hotplug controller.
+    pub irq: u32,
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)))
+}
+
+/// Builds a System Description Table from its signature and the content following its header.
+fn sdt(signature: &[u8; 4], revision: u8, content: &[u8]) -> Vec<u8> {
+    let length = 36 + content.len() as u32;
+    let mut table = Vec::with_capacity(length as usize);
+    table.extend(signature);
+    table.extend(&length.to_le_bytes());
+    table.push(revision);
+    // The checksum is filled once the table is complete.
+    table.push(0);
+    table.extend(OEM_ID);
+    table.extend(b"FCVM");
+    table.extend(signature);
+    table.extend(&OEM_REVISION.to_le_bytes());
+    table.extend(CREATOR_ID);
+    table.extend(&CREATOR_REVISION.to_le_bytes());
+    table.extend(content);
+    table[9] = checksum(&table);
+    table
+}
+
+fn rsdp(xsdt_addr: u64) -> Vec<u8> {
+    let mut rsdp = Vec::with_capacity(36);
+    rsdp.extend(b"RSD PTR ");
+    // Checksum of the ACPI 1.0 part of the structure.
+    rsdp.push(0);
+    rsdp.extend(OEM_ID);
+    rsdp.push(2);
+    // The RSDT is superseded by the XSDT.
+    rsdp.extend(&0u32.to_le_bytes());
+    rsdp.extend(&36u32.to_le_bytes());
+    rsdp.extend(&xsdt_addr.to_le_bytes());
+    // Checksum of the whole structure.
+    rsdp.push(0);
+    rsdp.extend(&[0u8; 3]);
+    rsdp[8] = checksum(&rsdp[..20]);
+    rsdp[32] = checksum(&rsdp);
+    rsdp
+}
+
+fn xsdt(table_addrs: &[u64]) -> Vec<u8> {
+    let content: Vec<u8> = table_addrs
+        .iter()
+        .flat_map(|addr| addr.to_le_bytes().to_vec())
+        .collect();
+    sdt(b"XSDT", 1, &content)
+}
+
+fn fadt(dsdt_addr: u64) -> Vec<u8> {
+    let mut content = vec![0u8; FADT_LENGTH - 36];
+    let mut set = |offset: usize, bytes: &[u8]| {
+        content[offset - 36..offset - 36 + bytes.len()].copy_from_slice(bytes)
+    };
+    set(FADT_DSDT_OFFSET, &(dsdt_addr as u32).to_le_bytes());
+    set(FADT_IAPC_BOOT_ARCH_OFFSET, &FADT_IAPC_8042.to_le_bytes());
+    set(FADT_FLAGS_OFFSET, &FADT_HW_REDUCED_ACPI.to_le_bytes());
+    set(FADT_X_DSDT_OFFSET, &dsdt_addr.to_le_bytes());
+    sdt(b"FACP", 6, &content)
+}
+
+fn madt(boot_cpus: u8, max_cpus: u8) -> Vec<u8> {
+    let mut content = Vec::new();
+    content.extend(&MADT_LOCAL_APIC_ADDR.to_le_bytes());
+    // No legacy PIC.
+    content.extend(&0u32.to_le_bytes());
+    for cpu in 0..max_cpus {
+        // The APIC ID of each vCPU is its index.
+        content.extend(&[MADT_LOCAL_APIC, MADT_LOCAL_APIC_LENGTH, cpu, cpu]);
+        let flags = if cpu < boot_cpus {
+            MADT_CPU_ENABLED
+        } else {
+            MADT_CPU_ONLINE_CAPABLE
+        };
+        content.extend(&flags.to_le_bytes());
+    }
+    // Revision 5 defines the online capable flag.
+    sdt(b"APIC", 5, &content)
+}
+
+/// Builds the container of the processor devices, along with the methods accessing the vCPU
+/// hotplug controller.
+fn cpus_device(info: &CpuHotplugInfo) -> Vec<u8> {
+    let max_cpus = u64::from(info.max_cpus);
+    let mut children = vec![
+        aml::name("_HID", &aml::string("ACPI0010")),
+        aml::name("_CID", &aml::eisa_id("PNP0A05")),
+        aml::mutex("CLCK", 0),
+        aml::memory_region("PRST", info.mmio_addr, CPU_HOTPLUG_REGION_SIZE),
+        aml::field(
+            "PRST",
+            FieldAccess::DWord,
+            &[FieldEntry::Named("CSEL", CPU_SELECTOR_BITS)],
+        ),
+        aml::field(
+            "PRST",
+            FieldAccess::Byte,
+            &[
+                FieldEntry::Reserved(CPU_SELECTOR_BITS),
+                FieldEntry::Named("CPEN", 1),
+                FieldEntry::Named("CINS", 1),
+                FieldEntry::Named("CRMV", 1),
+                FieldEntry::Named("CEJF", 1),
+            ],
+        ),
+        // Returns the _STA value of a vCPU.
+        aml::method(
+            "CSTA",
+            1,
+            true,
+            &[
+                aml::acquire("CLCK", 0xffff),
+                aml::store(&aml::arg(0), &aml::name_string("CSEL")),
+                aml::store(aml::ZERO, &aml::local(0)),
+                aml::if_then(
+                    &aml::equal(&aml::name_string("CPEN"), aml::ONE),
+                    &[aml::store(&aml::integer(0x0f), &aml::local(0))],
+                ),
+                aml::release("CLCK"),
+                aml::ret(&aml::local(0)),
+            ],
+        ),
+        // Ejects a vCPU.
+        aml::method(
+            "CEJ0",
+            1,
+            true,
+            &[
+                aml::acquire("CLCK", 0xffff),
+                aml::store(&aml::arg(0), &aml::name_string("CSEL")),
+                aml::store(aml::ONE, &aml::name_string("CEJF")),
+                aml::release("CLCK"),
+            ],
+        ),
+        // Notifies the processor devices of the pending events, and acknowledges them.
+        aml::method(
+            "CSCN",
+            0,
+            true,
+            &[
+                aml::acquire("CLCK", 0xffff),
+                aml::store(aml::ZERO, &aml::local(0)),
+                aml::while_loop(
+                    &aml::less(&aml::local(0), &aml::integer(max_cpus)),
+                    &[
+                        aml::store(&aml::local(0), &aml::name_string("CSEL")),
+                        aml::if_then(
+                            &aml::equal(&aml::name_string("CINS"), aml::ONE),
+                            &[
+                                aml::call(
+                                    "CTFY",
+                                    &[aml::local(0), aml::integer(NOTIFY_DEVICE_CHECK)],
+                                ),
+                                aml::store(aml::ONE, &aml::name_string("CINS")),
+                            ],
+                        ),
+                        aml::if_then(
+                            &aml::equal(&aml::name_string("CRMV"), aml::ONE),
+                            &[
+                                aml::call(
+                                    "CTFY",
+                                    &[aml::local(0), aml::integer(NOTIFY_EJECT_REQUEST)],
+                                ),
+                                aml::store(aml::ONE, &aml::name_string("CRMV")),
+                            ],
+                        ),
+                        aml::increment(&aml::local(0)),
+                    ],
+                ),
+                aml::release("CLCK"),
+            ],
+        ),
+        // Notifies the processor device of a vCPU, as `Notify` can't take a computed object.
+        aml::method(
+            "CTFY",
+            2,
+            false,
+            &(0..info.max_cpus)
+                .map(|cpu| {
+                    aml::if_then(
+                        &aml::equal(&aml::arg(0), &aml::integer(u64::from(cpu))),
+                        &[aml::notify(&aml::name_string(&cpu_name(cpu)), &aml::arg(1))],
+                    )
+                })
+                .collect::<Vec<_>>(),
+        ),
+    ];
+    children.extend((0..info.max_cpus).map(processor_device));
+    aml::device("CPUS", &children)
+}
+
+fn cpu_name(cpu: u8) -> String {
+    format!("C{:03X}", cpu)
+}
+
+/// Builds the processor device of a vCPU. Its methods call the methods of the container, found
+/// by searching the namespace upwards.
+fn processor_device(cpu: u8) -> Vec<u8> {
+    let index = aml::integer(u64::from(cpu));
+    // The local APIC structure of the MADT, enabled.
+    let mat = [
+        MADT_LOCAL_APIC,
+        MADT_LOCAL_APIC_LENGTH,
+        cpu,
+        cpu,
+        MADT_CPU_ENABLED as u8,
+        0,
+        0,
+        0,
+    ];
+    aml::device(
+        &cpu_name(cpu),
+        &[
+            aml::name("_HID", &aml::string("ACPI0007")),
+            aml::name("_UID", &index),
+            aml::name("_MAT", &aml::buffer(&mat)),
+            aml::method(
+                "_STA",
+                0,
+                false,
+                &[aml::ret(&aml::call("CSTA", &[index.clone()]))],
+            ),
+            aml::method("_EJ0", 1, false, &[aml::call("CEJ0", &[index])]),
+        ],
+    )
+}
+
+/// Builds the Generic Event Device through which the vCPU hotplug controller interrupts the
+/// guest.
+fn ged_device(info: &CpuHotplugInfo) -> Vec<u8> {
+    aml::device(
+        "GED0",
+        &[
+            aml::name("_HID", &aml::string("ACPI0013")),
+            aml::name("_UID", aml::ZERO),
+            aml::name("_CRS", &aml::interrupt_resource_template(info.irq)),
+            aml::method(
+                "_EVT",
+                1,
+                false,
+                &[aml::if_then(
+                    &aml::equal(&aml::arg(0), &aml::integer(u64::from(info.irq))),
+                    &[aml::call("\\_SB.CPUS.CSCN", &[])],
+                )],
+            ),
+        ],
+    )
+}
+
+fn dsdt(info: &CpuHotplugInfo) -> Vec<u8> {
+    let aml = aml::scope("\\_SB", &[cpus_device(info), ged_device(info)]);
+    // Revision 2 makes integers 64 bits wide.
+    sdt(b"DSDT", 2, &aml)
+}
+
+fn align_up(addr: u64) -> u64 {
+    (addr + TABLE_ALIGNMENT - 1) & !(TABLE_ALIGNMENT - 1)
+}
+
+/// Writes the ACPI tables describing `boot_cpus` vCPUs, which can be hot-plugged up to
+/// `info.max_cpus`.
+pub fn setup_acpi_tables(
+    guest_mem: &GuestMemoryMmap,
+    boot_cpus: u8,
+    info: &CpuHotplugInfo,
+) -> Result<(), Error> {
+    let dsdt = dsdt(info);
+    let madt = madt(boot_cpus, info.max_cpus);
+
+    // The tables follow the RSDP and the XSDT, which points to the FADT and the MADT.
+    let xsdt_addr = RSDP_START + RSDP_AREA_SIZE;
+    let fadt_addr = align_up(xsdt_addr + xsdt(&[0, 0]).len() as u64);
+    let madt_addr = align_up(fadt_addr + FADT_LENGTH as u64);
+    let dsdt_addr = align_up(madt_addr + madt.len() as u64);
+    if dsdt_addr + dsdt.len() as u64 > BIOS_ROM_END {
+        return Err(Error::NotEnoughMemory);
+    }
+
+    let tables = [
+        (RSDP_START, rsdp(xsdt_addr)),
+        (xsdt_addr, xsdt(&[fadt_addr, madt_addr])),
+        (fadt_addr, fadt(dsdt_addr)),
+        (madt_addr, madt),
+        (dsdt_addr, dsdt),
+    ];
+    for (addr, table) in tables.iter() {
+        guest_mem
+            .write_slice(table, GuestAddress(*addr))
+            .map_err(|_| Error::WriteTables)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_table(guest_mem: &GuestMemoryMmap, addr: u64) -> Vec<u8> {
+        let length: u32 = guest_mem.read_obj(GuestAddress(addr + 4)).unwrap();
+        let mut table = vec![0u8; length as usize];
+        guest_mem
+            .read_slice(&mut table, GuestAddress(addr))
+            .unwrap();
+        assert_eq!(checksum(&table), 0);
+        table
+    }
+
+    #[test]
+    fn test_setup_acpi_tables() {
+        let info = CpuHotplugInfo {
+            max_cpus: 32,
+            mmio_addr: 0xd000_0000,
+            irq: 5,
+        };
+
+        // The guest memory doesn't cover the BIOS area.
+        let gm =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false)
+                .unwrap();
+        assert_eq!(setup_acpi_tables(&gm, 2, &info), Err(Error::WriteTables));
+
+        let gm =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 2 << 20)], false)
+                .unwrap();
+        setup_acpi_tables(&gm, 2, &info).unwrap();
+
+        let mut rsdp = [0u8; 36];
+        gm.read_slice(&mut rsdp, GuestAddress(RSDP_START)).unwrap();
+        assert_eq!(&rsdp[..8], b"RSD PTR ");
+        assert_eq!(checksum(&rsdp[..20]), 0);
+        assert_eq!(checksum(&rsdp), 0);
+
+        let mut xsdt_addr = [0u8; 8];
+        xsdt_addr.copy_from_slice(&rsdp[24..32]);
+        let xsdt = read_table(&gm, u64::from_le_bytes(xsdt_addr));
+        assert_eq!(&xsdt[..4], b"XSDT");
+        let table_addrs: Vec<u64> = xsdt[36..]
+            .chunks(8)
+            .map(|addr| {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(addr);
+                u64::from_le_bytes(bytes)
+            })
+            .collect();
+        assert_eq!(table_addrs.len(), 2);
+
+        let fadt = read_table(&gm, table_addrs[0]);
+        assert_eq!(&fadt[..4], b"FACP");
+        assert_eq!(fadt.len(), FADT_LENGTH);
+        let mut dsdt_addr = [0u8; 8];
+        dsdt_addr.copy_from_slice(&fadt[FADT_X_DSDT_OFFSET..FADT_X_DSDT_OFFSET + 8]);
+        let dsdt = read_table(&gm, u64::from_le_bytes(dsdt_addr));
+        assert_eq!(&dsdt[..4], b"DSDT");
+        assert!(u64::from_le_bytes(dsdt_addr) + dsdt.len() as u64 <= BIOS_ROM_END);
+
+        let madt = read_table(&gm, table_addrs[1]);
+        assert_eq!(&madt[..4], b"APIC");
+        let entries: Vec<&[u8]> = madt[44..].chunks(8).collect();
+        assert_eq!(entries.len(), 32);
+        assert_eq!(entries[1], &[0, 8, 1, 1, 1, 0, 0, 0]);
+        assert_eq!(entries[2], &[0, 8, 2, 2, 2, 0, 0, 0]);
+    }
+}
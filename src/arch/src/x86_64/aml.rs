@@ -0,0 +1,465 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encodes the subset of the ACPI Machine Language (AML) needed to describe hot-pluggable vCPUs,
+//! as specified in chapter 20 of the ACPI specification.
+//! Each function returns the encoding of one term, which can be nested in other terms.
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const METHOD_OP: u8 = 0x14;
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const ROOT_CHAR: u8 = b'\\';
+const PARENT_PREFIX_CHAR: u8 = b'^';
+const LOCAL0_OP: u8 = 0x60;
+const ARG0_OP: u8 = 0x68;
+const STORE_OP: u8 = 0x70;
+const INCREMENT_OP: u8 = 0x75;
+const NOTIFY_OP: u8 = 0x86;
+const LEQUAL_OP: u8 = 0x93;
+const LLESS_OP: u8 = 0x95;
+const IF_OP: u8 = 0xa0;
+const WHILE_OP: u8 = 0xa2;
+const RETURN_OP: u8 = 0xa4;
+
+// Extended opcodes, following `EXT_OP_PREFIX`.
+const MUTEX_OP: u8 = 0x01;
+const ACQUIRE_OP: u8 = 0x23;
+const RELEASE_OP: u8 = 0x27;
+const OP_REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+
+const SYSTEM_MEMORY_SPACE: u8 = 0x00;
+// Fields are accessed without the global lock, and the bits of the accessed unit which aren't
+// part of the field are written as zeros.
+const FIELD_NO_LOCK_WRITE_AS_ZEROS: u8 = 2 << 5;
+
+// Resource descriptors, as specified in chapter 6.4 of the ACPI specification.
+const EXTENDED_INTERRUPT_DESCRIPTOR: u8 = 0x89;
+// Interrupt consumed by the device, edge-triggered, active high and exclusive.
+const EXTENDED_INTERRUPT_FLAGS: u8 = 0x03;
+const END_TAG_DESCRIPTOR: u8 = 0x79;
+
+/// The `Zero` constant.
+pub const ZERO: &[u8] = &[ZERO_OP];
+/// The `One` constant.
+pub const ONE: &[u8] = &[ONE_OP];
+
+/// Width of the accesses to a field.
+#[derive(Clone, Copy, Debug)]
+pub enum FieldAccess {
+    Byte = 1,
+    DWord = 3,
+}
+
+/// An element of a field list.
+#[derive(Clone, Copy, Debug)]
+pub enum FieldEntry {
+    /// A named field, of some size in bits.
+    Named(&'static str, usize),
+    /// Skips some bits of the region.
+    Reserved(usize),
+}
+
+/// Encodes a length, as found in the field lists.
+fn encode_length(length: usize) -> Vec<u8> {
+    if length < 0x40 {
+        vec![length as u8]
+    } else if length < 0x1000 {
+        vec![0x40 | (length & 0xf) as u8, (length >> 4) as u8]
+    } else if length < 0x10_0000 {
+        vec![
+            0x80 | (length & 0xf) as u8,
+            (length >> 4) as u8,
+            (length >> 12) as u8,
+        ]
+    } else {
+        vec![
+            0xc0 | (length & 0xf) as u8,
+            (length >> 4) as u8,
+            (length >> 12) as u8,
+            (length >> 20) as u8,
+        ]
+    }
+}
+
+/// Encodes the length of a package of `content_len` bytes, which counts the bytes encoding it.
+fn pkg_length(content_len: usize) -> Vec<u8> {
+    let mut len_bytes = 1;
+    loop {
+        let encoded = encode_length(content_len + len_bytes);
+        if encoded.len() == len_bytes {
+            return encoded;
+        }
+        len_bytes += 1;
+    }
+}
+
+/// Encodes a term made of an opcode followed by a package of terms.
+fn package(opcode: &[u8], content: &[u8]) -> Vec<u8> {
+    let mut bytes = opcode.to_vec();
+    bytes.extend(pkg_length(content.len()));
+    bytes.extend(content);
+    bytes
+}
+
+/// Encodes a path in the namespace, such as `\_SB_.CPUS`, `^CSTA` or `CSEL`.
+/// The name segments shorter than 4 characters are padded with underscores.
+pub fn name_string(path: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut path = path;
+    if let Some(relative) = path.strip_prefix('\\') {
+        bytes.push(ROOT_CHAR);
+        path = relative;
+    }
+    while let Some(relative) = path.strip_prefix('^') {
+        bytes.push(PARENT_PREFIX_CHAR);
+        path = relative;
+    }
+
+    let segments: Vec<&str> = path.split('.').filter(|seg| !seg.is_empty()).collect();
+    match segments.len() {
+        0 => bytes.push(ZERO_OP),
+        1 => (),
+        2 => bytes.push(DUAL_NAME_PREFIX),
+        count => {
+            bytes.push(MULTI_NAME_PREFIX);
+            bytes.push(count as u8);
+        }
+    }
+    for segment in segments {
+        assert!(segment.len() <= 4, "Invalid AML name segment: {}", segment);
+        let mut segment = segment.as_bytes().to_vec();
+        segment.resize(4, b'_');
+        bytes.extend(segment);
+    }
+    bytes
+}
+
+/// Encodes an integer, using the shortest encoding.
+pub fn integer(value: u64) -> Vec<u8> {
+    match value {
+        0 => vec![ZERO_OP],
+        1 => vec![ONE_OP],
+        _ if value <= u64::from(u8::MAX) => vec![BYTE_PREFIX, value as u8],
+        _ if value <= u64::from(u16::MAX) => {
+            let mut bytes = vec![WORD_PREFIX];
+            bytes.extend(&(value as u16).to_le_bytes());
+            bytes
+        }
+        _ if value <= u64::from(u32::MAX) => {
+            let mut bytes = vec![DWORD_PREFIX];
+            bytes.extend(&(value as u32).to_le_bytes());
+            bytes
+        }
+        _ => {
+            let mut bytes = vec![QWORD_PREFIX];
+            bytes.extend(&value.to_le_bytes());
+            bytes
+        }
+    }
+}
+
+/// Encodes a string.
+pub fn string(value: &str) -> Vec<u8> {
+    let mut bytes = vec![STRING_PREFIX];
+    bytes.extend(value.as_bytes());
+    bytes.push(0);
+    bytes
+}
+
+/// Encodes an EISA identifier, such as `PNP0A05`, as its compressed integer form.
+pub fn eisa_id(id: &str) -> Vec<u8> {
+    let chars = id.as_bytes();
+    assert!(chars.len() == 7, "Invalid EISA ID: {}", id);
+    let product = u32::from_str_radix(&id[3..], 16).expect("Invalid EISA ID");
+    let value = (u32::from(chars[0] - 0x40) << 26)
+        | (u32::from(chars[1] - 0x40) << 21)
+        | (u32::from(chars[2] - 0x40) << 16)
+        | product;
+    integer(u64::from(value.swap_bytes()))
+}
+
+/// Encodes a buffer holding `data`.
+pub fn buffer(data: &[u8]) -> Vec<u8> {
+    let mut content = integer(data.len() as u64);
+    content.extend(data);
+    package(&[BUFFER_OP], &content)
+}
+
+/// Encodes a resource template describing a single interrupt consumed by a device.
+pub fn interrupt_resource_template(irq: u32) -> Vec<u8> {
+    let mut descriptors = vec![
+        EXTENDED_INTERRUPT_DESCRIPTOR,
+        // Length of the descriptor, without its type and length.
+        6,
+        0,
+        EXTENDED_INTERRUPT_FLAGS,
+        // Number of interrupts.
+        1,
+    ];
+    descriptors.extend(&irq.to_le_bytes());
+    // The end tag, without checksum.
+    descriptors.extend(&[END_TAG_DESCRIPTOR, 0]);
+    buffer(&descriptors)
+}
+
+/// Encodes the `LocalN` variable of a method.
+pub fn local(index: u8) -> Vec<u8> {
+    assert!(index < 8);
+    vec![LOCAL0_OP + index]
+}
+
+/// Encodes the `ArgN` argument of a method.
+pub fn arg(index: u8) -> Vec<u8> {
+    assert!(index < 7);
+    vec![ARG0_OP + index]
+}
+
+/// Encodes `Name (path, value)`.
+pub fn name(path: &str, value: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![NAME_OP];
+    bytes.extend(name_string(path));
+    bytes.extend(value);
+    bytes
+}
+
+/// Encodes `Scope (path) { children }`.
+pub fn scope(path: &str, children: &[Vec<u8>]) -> Vec<u8> {
+    let mut content = name_string(path);
+    content.extend(children.concat());
+    package(&[SCOPE_OP], &content)
+}
+
+/// Encodes `Device (path) { children }`.
+pub fn device(path: &str, children: &[Vec<u8>]) -> Vec<u8> {
+    let mut content = name_string(path);
+    content.extend(children.concat());
+    package(&[EXT_OP_PREFIX, DEVICE_OP], &content)
+}
+
+/// Encodes `Method (path, args, Serialized/NotSerialized) { children }`.
+pub fn method(path: &str, args: u8, serialized: bool, children: &[Vec<u8>]) -> Vec<u8> {
+    assert!(args < 8);
+    let mut content = name_string(path);
+    content.push(args | (u8::from(serialized) << 3));
+    content.extend(children.concat());
+    package(&[METHOD_OP], &content)
+}
+
+/// Encodes the invocation of the method at `path`.
+pub fn call(path: &str, args: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = name_string(path);
+    bytes.extend(args.concat());
+    bytes
+}
+
+/// Encodes `Return (value)`.
+pub fn ret(value: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![RETURN_OP];
+    bytes.extend(value);
+    bytes
+}
+
+/// Encodes `If (predicate) { children }`.
+pub fn if_then(predicate: &[u8], children: &[Vec<u8>]) -> Vec<u8> {
+    let mut content = predicate.to_vec();
+    content.extend(children.concat());
+    package(&[IF_OP], &content)
+}
+
+/// Encodes `While (predicate) { children }`.
+pub fn while_loop(predicate: &[u8], children: &[Vec<u8>]) -> Vec<u8> {
+    let mut content = predicate.to_vec();
+    content.extend(children.concat());
+    package(&[WHILE_OP], &content)
+}
+
+fn binary_op(opcode: u8, left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![opcode];
+    bytes.extend(left);
+    bytes.extend(right);
+    bytes
+}
+
+/// Encodes `LEqual (left, right)`.
+pub fn equal(left: &[u8], right: &[u8]) -> Vec<u8> {
+    binary_op(LEQUAL_OP, left, right)
+}
+
+/// Encodes `LLess (left, right)`.
+pub fn less(left: &[u8], right: &[u8]) -> Vec<u8> {
+    binary_op(LLESS_OP, left, right)
+}
+
+/// Encodes `Store (value, target)`.
+pub fn store(value: &[u8], target: &[u8]) -> Vec<u8> {
+    binary_op(STORE_OP, value, target)
+}
+
+/// Encodes `Increment (target)`.
+pub fn increment(target: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![INCREMENT_OP];
+    bytes.extend(target);
+    bytes
+}
+
+/// Encodes `Notify (object, value)`.
+pub fn notify(object: &[u8], value: &[u8]) -> Vec<u8> {
+    binary_op(NOTIFY_OP, object, value)
+}
+
+/// Encodes `Mutex (path, sync_level)`.
+pub fn mutex(path: &str, sync_level: u8) -> Vec<u8> {
+    let mut bytes = vec![EXT_OP_PREFIX, MUTEX_OP];
+    bytes.extend(name_string(path));
+    bytes.push(sync_level);
+    bytes
+}
+
+/// Encodes `Acquire (mutex, timeout_ms)`. A timeout of 0xffff waits forever.
+pub fn acquire(mutex: &str, timeout_ms: u16) -> Vec<u8> {
+    let mut bytes = vec![EXT_OP_PREFIX, ACQUIRE_OP];
+    bytes.extend(name_string(mutex));
+    bytes.extend(&timeout_ms.to_le_bytes());
+    bytes
+}
+
+/// Encodes `Release (mutex)`.
+pub fn release(mutex: &str) -> Vec<u8> {
+    let mut bytes = vec![EXT_OP_PREFIX, RELEASE_OP];
+    bytes.extend(name_string(mutex));
+    bytes
+}
+
+/// Encodes `OperationRegion (path, SystemMemory, addr, len)`.
+pub fn memory_region(path: &str, addr: u64, len: u64) -> Vec<u8> {
+    let mut bytes = vec![EXT_OP_PREFIX, OP_REGION_OP];
+    bytes.extend(name_string(path));
+    bytes.push(SYSTEM_MEMORY_SPACE);
+    bytes.extend(integer(addr));
+    bytes.extend(integer(len));
+    bytes
+}
+
+/// Encodes `Field (region, access, NoLock, WriteAsZeros) { entries }`.
+pub fn field(region: &str, access: FieldAccess, entries: &[FieldEntry]) -> Vec<u8> {
+    let mut content = name_string(region);
+    content.push(access as u8 | FIELD_NO_LOCK_WRITE_AS_ZEROS);
+    for entry in entries {
+        match entry {
+            FieldEntry::Named(name, bits) => {
+                assert!(name.len() == 4, "Invalid AML field name: {}", name);
+                content.extend(name.as_bytes());
+                content.extend(encode_length(*bits));
+            }
+            FieldEntry::Reserved(bits) => {
+                content.push(0);
+                content.extend(encode_length(*bits));
+            }
+        }
+    }
+    package(&[EXT_OP_PREFIX, FIELD_OP], &content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkg_length() {
+        assert_eq!(pkg_length(0x3e), vec![0x3f]);
+        assert_eq!(pkg_length(0x3f), vec![0x41, 0x04]);
+        assert_eq!(pkg_length(0xffd), vec![0x4f, 0xff]);
+        assert_eq!(pkg_length(0xffe), vec![0x81, 0x00, 0x01]);
+        assert_eq!(pkg_length(0x10_0000), vec![0xc4, 0x00, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_name_string() {
+        assert_eq!(name_string("CSEL"), b"CSEL".to_vec());
+        assert_eq!(name_string("_SB"), b"_SB_".to_vec());
+        assert_eq!(name_string("\\_SB"), b"\\_SB_".to_vec());
+        assert_eq!(name_string("^CSTA"), b"^CSTA".to_vec());
+        assert_eq!(name_string("\\_SB.CPUS"), b"\\._SB_CPUS".to_vec());
+        assert_eq!(
+            name_string("\\_SB.CPUS.CSCN"),
+            b"\\/\x03_SB_CPUSCSCN".to_vec()
+        );
+        assert_eq!(name_string("\\"), vec![b'\\', 0x00]);
+    }
+
+    #[test]
+    fn test_integer() {
+        assert_eq!(integer(0), vec![0x00]);
+        assert_eq!(integer(1), vec![0x01]);
+        assert_eq!(integer(0x0f), vec![0x0a, 0x0f]);
+        assert_eq!(integer(0x1234), vec![0x0b, 0x34, 0x12]);
+        assert_eq!(integer(0xfee0_0000), vec![0x0c, 0x00, 0x00, 0xe0, 0xfe]);
+        assert_eq!(
+            integer(1 << 32),
+            vec![0x0e, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_eisa_id() {
+        // Values compiled by iasl.
+        assert_eq!(eisa_id("PNP0A05"), vec![0x0c, 0x41, 0xd0, 0x0a, 0x05]);
+        assert_eq!(eisa_id("PNP0C80"), vec![0x0c, 0x41, 0xd0, 0x0c, 0x80]);
+    }
+
+    #[test]
+    fn test_terms() {
+        // Name (_HID, "ACPI0013")
+        assert_eq!(
+            name("_HID", &string("ACPI0013")),
+            b"\x08_HID\x0dACPI0013\x00".to_vec()
+        );
+        // Method (_STA, 0, NotSerialized) { Return (0x0F) }
+        assert_eq!(
+            method("_STA", 0, false, &[ret(&integer(0x0f))]),
+            vec![0x14, 0x09, b'_', b'S', b'T', b'A', 0x00, 0xa4, 0x0a, 0x0f]
+        );
+        // Device (GED0) { Name (_UID, Zero) }
+        assert_eq!(
+            device("GED0", &[name("_UID", ZERO)]),
+            b"\x5b\x82\x0bGED0\x08_UID\x00".to_vec()
+        );
+        // If (LEqual (Arg0, One)) { Increment (Local0) }
+        assert_eq!(
+            if_then(&equal(&arg(0), ONE), &[increment(&local(0))]),
+            vec![0xa0, 0x06, 0x93, 0x68, 0x01, 0x75, 0x60]
+        );
+        // Acquire (CLCK, 0xFFFF)
+        assert_eq!(acquire("CLCK", 0xffff), b"\x5b\x23CLCK\xff\xff".to_vec());
+        // Field (PRST, ByteAcc, NoLock, WriteAsZeros) { Offset (4), CPEN, 1 }
+        assert_eq!(
+            field(
+                "PRST",
+                FieldAccess::Byte,
+                &[FieldEntry::Reserved(32), FieldEntry::Named("CPEN", 1)]
+            ),
+            b"\x5b\x81\x0dPRST\x41\x00\x20CPEN\x01".to_vec()
+        );
+        // Interrupt (ResourceConsumer, Edge, ActiveHigh, Exclusive) { 5 }
+        assert_eq!(
+            interrupt_resource_template(5),
+            vec![
+                0x11, 0x0e, 0x0a, 0x0b, 0x89, 0x06, 0x00, 0x03, 0x01, 0x05, 0x00, 0x00, 0x00, 0x79,
+                0x00
+            ]
+        );
+    }
+}
@@ -5,6 +5,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+/// Logic for describing the hot-pluggable vCPUs through ACPI.
+pub mod acpi;
+mod aml;
 mod gdt;
 /// Contains logic for setting up Advanced Programmable Interrupt Controller (local version).
 pub mod interrupts;
@@ -31,6 +34,8 @@ const E820_RAM: u32 = 1;
 pub enum Error {
     /// Invalid e820 setup params.
     E820Configuration,
+    /// Error writing the ACPI tables to memory.
+    AcpiSetup(acpi::Error),
     /// Error writing MP table to memory.
     MpTableSetup(mptable::Error),
     /// Error writing the zero page of guest memory.
@@ -67,6 +72,21 @@ pub fn arch_memory_regions(size: usize) -> Vec<(GuestAddress, usize)> {
     }
 }
 
+/// Returns the start address and the size of the hot-pluggable memory region of `size` bytes,
+/// for a guest with `ram_size` bytes of boot memory.
+/// The region follows the boot memory, past the end of the 32bit address space.
+pub fn hotplug_memory_region(ram_size: usize, size: usize) -> (GuestAddress, usize) {
+    let ram_end = match arch_memory_regions(ram_size).last() {
+        Some((addr, len)) => addr.unchecked_add(*len as u64).raw_value(),
+        None => 0,
+    };
+    let start = std::cmp::max(ram_end, FIRST_ADDR_PAST_32BITS);
+    (
+        GuestAddress(crate::align_up(start, crate::HOTPLUG_MEM_ALIGNMENT)),
+        size,
+    )
+}
+
 /// Returns the memory address where the kernel could be loaded.
 pub fn get_kernel_start() -> u64 {
     layout::HIMEM_START
@@ -97,12 +117,14 @@ pub fn initrd_load_addr(guest_mem: &GuestMemoryMmap, initrd_size: usize) -> supe
 /// * `cmdline_size` - Size of the kernel command line in bytes including the null terminator.
 /// * `initrd` - Information about where the ramdisk image was loaded in the `guest_mem`.
 /// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `cpu_hotplug` - Information about the virtual CPUs which can be hot-plugged, if any.
 pub fn configure_system(
     guest_mem: &GuestMemoryMmap,
     cmdline_addr: GuestAddress,
     cmdline_size: usize,
     initrd: &Option<InitrdConfig>,
     num_cpus: u8,
+    cpu_hotplug: Option<&acpi::CpuHotplugInfo>,
 ) -> super::Result<()> {
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x5372_6448;
@@ -116,6 +138,11 @@ pub fn configure_system(
     // Note that this puts the mptable at the last 1k of Linux's 640k base RAM
     mptable::setup_mptable(guest_mem, num_cpus)?;
 
+    // The hot-pluggable vCPUs are only described through ACPI.
+    if let Some(cpu_hotplug) = cpu_hotplug {
+        acpi::setup_acpi_tables(guest_mem, num_cpus, cpu_hotplug)?;
+    }
+
     let mut params = boot_params::default();
 
     params.hdr.type_of_loader = KERNEL_LOADER_OTHER;
@@ -212,13 +239,27 @@ mod tests {
         assert_eq!(GuestAddress(1u64 << 32), regions[1].0);
     }
 
+    #[test]
+    fn test_hotplug_memory_region() {
+        // The region never starts in the 32bit address space.
+        let (addr, size) = hotplug_memory_region(1usize << 29, 1usize << 30);
+        assert_eq!(GuestAddress(FIRST_ADDR_PAST_32BITS), addr);
+        assert_eq!(1usize << 30, size);
+
+        // The region follows the boot memory, aligned to 1GiB.
+        let (addr, _) = hotplug_memory_region((1usize << 32) + 0x8000, 1usize << 30);
+        assert_eq!(GuestAddress(5u64 << 30), addr);
+        let (addr, _) = hotplug_memory_region(MMIO_MEM_START as usize + (1 << 30), 1 << 30);
+        assert_eq!(GuestAddress(5u64 << 30), addr);
+    }
+
     #[test]
     fn test_system_configuration() {
         let no_vcpus = 4;
         let gm =
             vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false)
                 .unwrap();
-        let config_err = configure_system(&gm, GuestAddress(0), 0, &None, 1);
+        let config_err = configure_system(&gm, GuestAddress(0), 0, &None, 1, None);
         assert!(config_err.is_err());
         assert_eq!(
             config_err.unwrap_err(),
@@ -229,19 +270,26 @@ mod tests {
         let mem_size = 128 << 20;
         let arch_mem_regions = arch_memory_regions(mem_size);
         let gm = vm_memory::test_utils::create_anon_guest_memory(&arch_mem_regions, false).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, &None, no_vcpus).unwrap();
+        configure_system(&gm, GuestAddress(0), 0, &None, no_vcpus, None).unwrap();
+        // The hot-pluggable vCPUs are described through ACPI.
+        let cpu_hotplug = acpi::CpuHotplugInfo {
+            max_cpus: 8,
+            mmio_addr: MMIO_MEM_START,
+            irq: 5,
+        };
+        configure_system(&gm, GuestAddress(0), 0, &None, no_vcpus, Some(&cpu_hotplug)).unwrap();
 
         // Now assigning some memory that is equal to the start of the 32bit memory hole.
         let mem_size = 3328 << 20;
         let arch_mem_regions = arch_memory_regions(mem_size);
         let gm = vm_memory::test_utils::create_anon_guest_memory(&arch_mem_regions, false).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, &None, no_vcpus).unwrap();
+        configure_system(&gm, GuestAddress(0), 0, &None, no_vcpus, None).unwrap();
 
         // Now assigning some memory that falls after the 32bit memory hole.
         let mem_size = 3330 << 20;
         let arch_mem_regions = arch_memory_regions(mem_size);
         let gm = vm_memory::test_utils::create_anon_guest_memory(&arch_mem_regions, false).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, &None, no_vcpus).unwrap();
+        configure_system(&gm, GuestAddress(0), 0, &None, no_vcpus, None).unwrap();
     }
 
     #[test]
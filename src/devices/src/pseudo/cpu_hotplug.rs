@@ -0,0 +1,270 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Emulates the controller through which the guest finds out which of the hot-pluggable vCPUs
+//! are plugged.
+//!
+//! The guest doesn't drive the controller with a driver of its own, but with the ACPI methods
+//! describing the vCPUs, so the register layout must match the one those methods are generated
+//! for (see `arch::x86_64::acpi`):
+//! * offset 0, 4 bytes: selects the vCPU the status register refers to;
+//! * offset 4, 1 byte: status of the selected vCPU. Reading it returns the `CPU_STATUS_*` flags
+//!   of the vCPU. Writing the `CPU_STATUS_INSERTING` or `CPU_STATUS_REMOVING` flags
+//!   acknowledges the event, and writing `CPU_STATUS_EJECT` ejects the vCPU.
+
+use std::io;
+
+use snapshot::Persist;
+use utils::eventfd::EventFd;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
+use crate::bus::BusDevice;
+
+/// Offset of the register selecting a vCPU.
+pub const CPU_SELECTOR_OFFSET: u64 = 0;
+/// Offset of the status register of the selected vCPU.
+pub const CPU_STATUS_OFFSET: u64 = 4;
+
+/// The vCPU is plugged.
+pub const CPU_STATUS_ENABLED: u8 = 1 << 0;
+/// The vCPU was plugged, and the guest hasn't been notified yet.
+pub const CPU_STATUS_INSERTING: u8 = 1 << 1;
+/// The vCPU is requested to be unplugged, and the guest hasn't been notified yet.
+pub const CPU_STATUS_REMOVING: u8 = 1 << 2;
+/// Written by the guest once it has offlined the vCPU.
+pub const CPU_STATUS_EJECT: u8 = 1 << 3;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The vCPU isn't handled by the controller.
+    InvalidCpu(u8),
+    /// Failed to signal the guest.
+    Interrupt(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Pseudo device signaling the guest that vCPUs were plugged or are to be unplugged.
+pub struct CpuHotplugController {
+    // The `CPU_STATUS_*` flags of each vCPU.
+    status: Vec<u8>,
+    selector: u32,
+    interrupt_evt: EventFd,
+}
+
+impl CpuHotplugController {
+    /// Creates a controller for `max_cpus` vCPUs, where the first `plugged_cpus` are plugged.
+    pub fn new(max_cpus: u8, plugged_cpus: u8) -> io::Result<Self> {
+        let status = (0..max_cpus)
+            .map(|cpu| {
+                if cpu < plugged_cpus {
+                    CPU_STATUS_ENABLED
+                } else {
+                    0
+                }
+            })
+            .collect();
+        Ok(CpuHotplugController {
+            status,
+            selector: 0,
+            interrupt_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+        })
+    }
+
+    /// Returns the event signaled to interrupt the guest.
+    pub fn interrupt_evt(&self) -> &EventFd {
+        &self.interrupt_evt
+    }
+
+    /// Returns the number of vCPUs handled by the controller.
+    pub fn max_cpus(&self) -> u8 {
+        self.status.len() as u8
+    }
+
+    /// Returns whether the vCPU is plugged, as far as the guest knows.
+    pub fn is_enabled(&self, cpu: u8) -> bool {
+        self.status
+            .get(cpu as usize)
+            .map_or(false, |status| status & CPU_STATUS_ENABLED != 0)
+    }
+
+    /// Marks the vCPU as plugged. The guest finds out once notified.
+    pub fn plug(&mut self, cpu: u8) -> Result<()> {
+        let status = self
+            .status
+            .get_mut(cpu as usize)
+            .ok_or(Error::InvalidCpu(cpu))?;
+        if *status & CPU_STATUS_ENABLED == 0 {
+            *status = CPU_STATUS_ENABLED | CPU_STATUS_INSERTING;
+        } else {
+            // Cancels a pending unplug request.
+            *status &= !CPU_STATUS_REMOVING;
+        }
+        Ok(())
+    }
+
+    /// Requests the guest to unplug the vCPU, once notified. The vCPU stays plugged until the
+    /// guest ejects it.
+    pub fn unplug(&mut self, cpu: u8) -> Result<()> {
+        let status = self
+            .status
+            .get_mut(cpu as usize)
+            .ok_or(Error::InvalidCpu(cpu))?;
+        if *status & CPU_STATUS_ENABLED != 0 {
+            *status = (*status & !CPU_STATUS_INSERTING) | CPU_STATUS_REMOVING;
+        }
+        Ok(())
+    }
+
+    /// Interrupts the guest, so that it scans the vCPUs for pending events.
+    pub fn notify(&self) -> Result<()> {
+        self.interrupt_evt.write(1).map_err(Error::Interrupt)
+    }
+
+    fn selected_status(&mut self) -> Option<&mut u8> {
+        self.status.get_mut(self.selector as usize)
+    }
+}
+
+impl BusDevice for CpuHotplugController {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        match (offset, data.len()) {
+            (CPU_SELECTOR_OFFSET, 4) => data.copy_from_slice(&self.selector.to_le_bytes()),
+            (CPU_STATUS_OFFSET, 1) => data[0] = self.selected_status().map_or(0, |s| *s),
+            _ => {
+                for b in data.iter_mut() {
+                    *b = 0;
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        match (offset, data.len()) {
+            (CPU_SELECTOR_OFFSET, 4) => {
+                let mut selector = [0u8; 4];
+                selector.copy_from_slice(data);
+                self.selector = u32::from_le_bytes(selector);
+            }
+            (CPU_STATUS_OFFSET, 1) => {
+                let value = data[0];
+                if let Some(status) = self.selected_status() {
+                    *status &= !(value & (CPU_STATUS_INSERTING | CPU_STATUS_REMOVING));
+                    if value & CPU_STATUS_EJECT != 0 {
+                        *status = 0;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Holds the state of the vCPU hotplug controller.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct CpuHotplugControllerState {
+    status: Vec<u8>,
+    selector: u32,
+}
+
+impl Persist<'_> for CpuHotplugController {
+    type State = CpuHotplugControllerState;
+    type ConstructorArgs = ();
+    type Error = io::Error;
+
+    fn save(&self) -> Self::State {
+        CpuHotplugControllerState {
+            status: self.status.clone(),
+            selector: self.selector,
+        }
+    }
+
+    fn restore(_: Self::ConstructorArgs, state: &Self::State) -> io::Result<Self> {
+        Ok(CpuHotplugController {
+            status: state.status.clone(),
+            selector: state.selector,
+            interrupt_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_status(controller: &mut CpuHotplugController, cpu: u32) -> u8 {
+        controller.write(CPU_SELECTOR_OFFSET, &cpu.to_le_bytes());
+        let mut data = [0u8];
+        controller.read(CPU_STATUS_OFFSET, &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn test_plug_unplug() {
+        let mut controller = CpuHotplugController::new(4, 2).unwrap();
+        assert_eq!(controller.max_cpus(), 4);
+        assert!(controller.is_enabled(1));
+        assert!(!controller.is_enabled(2));
+        assert_eq!(read_status(&mut controller, 1), CPU_STATUS_ENABLED);
+        assert_eq!(read_status(&mut controller, 2), 0);
+        // Out of range vCPUs read as unplugged.
+        assert_eq!(read_status(&mut controller, 7), 0);
+        assert!(matches!(controller.plug(4), Err(Error::InvalidCpu(4))));
+
+        controller.plug(2).unwrap();
+        controller.notify().unwrap();
+        assert_eq!(controller.interrupt_evt().read().unwrap(), 1);
+        assert_eq!(
+            read_status(&mut controller, 2),
+            CPU_STATUS_ENABLED | CPU_STATUS_INSERTING
+        );
+        // The guest acknowledges the event.
+        controller.write(CPU_STATUS_OFFSET, &[CPU_STATUS_INSERTING]);
+        assert_eq!(read_status(&mut controller, 2), CPU_STATUS_ENABLED);
+
+        controller.unplug(2).unwrap();
+        // Unplugging a vCPU which isn't plugged is a no-op.
+        controller.unplug(3).unwrap();
+        assert_eq!(read_status(&mut controller, 3), 0);
+        assert_eq!(
+            read_status(&mut controller, 2),
+            CPU_STATUS_ENABLED | CPU_STATUS_REMOVING
+        );
+        controller.write(CPU_STATUS_OFFSET, &[CPU_STATUS_REMOVING]);
+        assert_eq!(read_status(&mut controller, 2), CPU_STATUS_ENABLED);
+        assert!(controller.is_enabled(2));
+        controller.write(CPU_STATUS_OFFSET, &[CPU_STATUS_EJECT]);
+        assert_eq!(read_status(&mut controller, 2), 0);
+        assert!(!controller.is_enabled(2));
+
+        // Plugging cancels a pending unplug request.
+        controller.unplug(1).unwrap();
+        controller.plug(1).unwrap();
+        assert_eq!(read_status(&mut controller, 1), CPU_STATUS_ENABLED);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let mut controller = CpuHotplugController::new(4, 1).unwrap();
+        controller.plug(3).unwrap();
+        controller.write(CPU_SELECTOR_OFFSET, &3u32.to_le_bytes());
+
+        let mut mem = vec![0; 4096];
+        let version_map = VersionMap::new();
+        controller
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+        let state =
+            CpuHotplugControllerState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap();
+        let mut restored = CpuHotplugController::restore((), &state).unwrap();
+
+        assert!(restored.is_enabled(0));
+        assert!(restored.is_enabled(3));
+        let mut data = [0u8];
+        restored.read(CPU_STATUS_OFFSET, &mut data);
+        assert_eq!(data[0], CPU_STATUS_ENABLED | CPU_STATUS_INSERTING);
+    }
+}
@@ -2,5 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 mod boot_timer;
+pub mod cpu_hotplug;
 
 pub use self::boot_timer::BootTimer;
+pub use self::cpu_hotplug::CpuHotplugController;
@@ -0,0 +1,130 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The policy followed by the VMM to adjust the target size of the balloon from the statistics.
+
+use serde::{Deserialize, Serialize};
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
+/// The policy of the balloon controller, which inflates or deflates the balloon each time the
+/// guest provides new statistics, so that the guest keeps a given share of its memory free.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BalloonAutoAdjustConfig {
+    /// Percentage of its memory the guest should keep available.
+    pub target_free_memory_percent: u8,
+    /// Minimum target size of the balloon in MiB.
+    pub min_amount_mib: u32,
+    /// Maximum target size of the balloon in MiB.
+    pub max_amount_mib: u32,
+    /// Maximum change of the target size in MiB after a statistics update.
+    pub step_mib: u32,
+}
+
+impl BalloonAutoAdjustConfig {
+    /// Checks that the policy is consistent, and can be used with a balloon device polling
+    /// the statistics every `stats_polling_interval_s` seconds.
+    pub fn validate(&self, stats_polling_interval_s: u16) -> Result<(), &'static str> {
+        if stats_polling_interval_s == 0 {
+            return Err("the statistics of the balloon are not enabled.");
+        }
+        if self.target_free_memory_percent > 100 {
+            return Err("the target free memory percentage is larger than 100.");
+        }
+        if self.min_amount_mib > self.max_amount_mib {
+            return Err("the minimum balloon size is larger than the maximum one.");
+        }
+        if self.step_mib == 0 {
+            return Err("the step is 0.");
+        }
+        Ok(())
+    }
+}
+
+/// The saved state of a `BalloonAutoAdjustConfig`.
+#[derive(Clone, Debug, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct BalloonAutoAdjustState {
+    target_free_memory_percent: u8,
+    min_amount_mib: u32,
+    max_amount_mib: u32,
+    step_mib: u32,
+}
+
+impl From<&BalloonAutoAdjustConfig> for BalloonAutoAdjustState {
+    fn from(config: &BalloonAutoAdjustConfig) -> Self {
+        BalloonAutoAdjustState {
+            target_free_memory_percent: config.target_free_memory_percent,
+            min_amount_mib: config.min_amount_mib,
+            max_amount_mib: config.max_amount_mib,
+            step_mib: config.step_mib,
+        }
+    }
+}
+
+impl From<&BalloonAutoAdjustState> for BalloonAutoAdjustConfig {
+    fn from(state: &BalloonAutoAdjustState) -> Self {
+        BalloonAutoAdjustConfig {
+            target_free_memory_percent: state.target_free_memory_percent,
+            min_amount_mib: state.min_amount_mib,
+            max_amount_mib: state.max_amount_mib,
+            step_mib: state.step_mib,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let config = BalloonAutoAdjustConfig {
+            target_free_memory_percent: 20,
+            min_amount_mib: 0,
+            max_amount_mib: 64,
+            step_mib: 8,
+        };
+        assert!(config.validate(1).is_ok());
+        // The controller is driven by the statistics.
+        assert!(config.validate(0).is_err());
+
+        let invalid_configs = [
+            BalloonAutoAdjustConfig {
+                target_free_memory_percent: 101,
+                ..config
+            },
+            BalloonAutoAdjustConfig {
+                min_amount_mib: 65,
+                ..config
+            },
+            BalloonAutoAdjustConfig {
+                step_mib: 0,
+                ..config
+            },
+        ];
+        for config in invalid_configs.iter() {
+            assert!(config.validate(1).is_err());
+        }
+    }
+
+    #[test]
+    fn test_persistence() {
+        let config = BalloonAutoAdjustConfig {
+            target_free_memory_percent: 20,
+            min_amount_mib: 16,
+            max_amount_mib: 64,
+            step_mib: 8,
+        };
+        let version_map = VersionMap::new();
+        let mut mem = vec![0; 64];
+
+        BalloonAutoAdjustState::from(&config)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+        let state =
+            BalloonAutoAdjustState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap();
+        assert_eq!(BalloonAutoAdjustConfig::from(&state), config);
+    }
+}
@@ -16,10 +16,12 @@ use virtio_gen::virtio_blk::VIRTIO_F_VERSION_1;
 use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
 
 use super::super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BALLOON};
+use super::auto_adjust::BalloonAutoAdjustConfig;
 use super::utils::{compact_page_frame_numbers, remove_range};
 use super::{
-    BALLOON_DEV_ID, DEFLATE_INDEX, INFLATE_INDEX, MAX_PAGES_IN_DESC, MAX_PAGE_COMPACT_BUFFER,
-    MIB_TO_4K_PAGES, NUM_QUEUES, QUEUE_SIZES, STATS_INDEX, VIRTIO_BALLOON_F_DEFLATE_ON_OOM,
+    BALLOON_DEV_ID, DEFLATE_INDEX, FREE_PAGE_REPORTING_INDEX, INFLATE_INDEX, MAX_PAGES_IN_DESC,
+    MAX_PAGE_COMPACT_BUFFER, MIB_TO_4K_PAGES, NUM_QUEUES, QUEUE_SIZES, STATS_INDEX,
+    VIRTIO_BALLOON_F_DEFLATE_ON_OOM, VIRTIO_BALLOON_F_FREE_PAGE_REPORTING,
     VIRTIO_BALLOON_F_STATS_VQ, VIRTIO_BALLOON_PFN_SHIFT, VIRTIO_BALLOON_S_AVAIL,
     VIRTIO_BALLOON_S_CACHES, VIRTIO_BALLOON_S_HTLB_PGALLOC, VIRTIO_BALLOON_S_HTLB_PGFAIL,
     VIRTIO_BALLOON_S_MAJFLT, VIRTIO_BALLOON_S_MEMFREE, VIRTIO_BALLOON_S_MEMTOT,
@@ -69,6 +71,8 @@ pub struct BalloonConfig {
     pub amount_mib: u32,
     pub deflate_on_oom: bool,
     pub stats_polling_interval_s: u16,
+    pub free_page_reporting: bool,
+    pub auto_adjust: Option<BalloonAutoAdjustConfig>,
 }
 
 // BalloonStats holds statistics returned from the stats_queue.
@@ -146,6 +150,10 @@ pub struct Balloon {
     // it is acknowledged after the stats queue is processed.
     pub(crate) stats_desc_index: Option<u16>,
     pub(crate) latest_stats: BalloonStats,
+    // Signaled each time the driver provides new statistics.
+    pub(crate) stats_update_evt: EventFd,
+    // The policy the VMM follows to adjust the target size, saved along with the device.
+    pub(crate) auto_adjust: Option<BalloonAutoAdjustConfig>,
     // A buffer used as pfn accumulator during descriptor processing.
     pub(crate) pfn_buffer: [u32; MAX_PAGE_COMPACT_BUFFER],
 }
@@ -155,6 +163,7 @@ impl Balloon {
         amount_mib: u32,
         deflate_on_oom: bool,
         stats_polling_interval_s: u16,
+        free_page_reporting: bool,
         restored: bool,
     ) -> Result<Balloon, BalloonError> {
         let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
@@ -167,16 +176,25 @@ impl Balloon {
             avail_features |= 1u64 << VIRTIO_BALLOON_F_STATS_VQ;
         }
 
+        if free_page_reporting {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_FREE_PAGE_REPORTING;
+        }
+
         let queue_evts = [
             EventFd::new(libc::EFD_NONBLOCK).map_err(BalloonError::EventFd)?,
             EventFd::new(libc::EFD_NONBLOCK).map_err(BalloonError::EventFd)?,
             EventFd::new(libc::EFD_NONBLOCK).map_err(BalloonError::EventFd)?,
+            EventFd::new(libc::EFD_NONBLOCK).map_err(BalloonError::EventFd)?,
         ];
 
         let mut queues: Vec<Queue> = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
 
-        // The VirtIO specification states that the statistics queue should
-        // not be present at all if the statistics are not enabled.
+        // The VirtIO specification states that the statistics and free page reporting queues
+        // should not be present at all if the matching features are not enabled. The queues
+        // following a missing one take its place.
+        if !free_page_reporting {
+            let _ = queues.remove(FREE_PAGE_REPORTING_INDEX);
+        }
         if stats_polling_interval_s == 0 {
             let _ = queues.remove(STATS_INDEX);
         }
@@ -201,6 +219,8 @@ impl Balloon {
             stats_timer,
             stats_desc_index: None,
             latest_stats: BalloonStats::default(),
+            stats_update_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(BalloonError::EventFd)?,
+            auto_adjust: None,
             pfn_buffer: [0u32; MAX_PAGE_COMPACT_BUFFER],
         })
     }
@@ -226,6 +246,13 @@ impl Balloon {
         self.process_stats_queue()
     }
 
+    pub(crate) fn process_free_page_reporting_queue_event(&mut self) -> Result<(), BalloonError> {
+        self.queue_evts[self.free_page_reporting_idx()]
+            .read()
+            .map_err(BalloonError::EventFd)?;
+        self.process_free_page_reporting_queue()
+    }
+
     pub(crate) fn process_stats_timer_event(&mut self) -> Result<(), BalloonError> {
         self.stats_timer.read();
         self.trigger_stats_update()
@@ -348,6 +375,7 @@ impl Balloon {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
         METRICS.balloon.stats_updates_count.inc();
+        let mut stats_updated = false;
 
         while let Some(head) = self.queues[STATS_INDEX].pop(mem) {
             if let Some(prev_stats_desc) = self.stats_desc_index {
@@ -377,11 +405,59 @@ impl Balloon {
             }
 
             self.stats_desc_index = Some(head.index);
+            stats_updated = true;
+        }
+
+        if stats_updated {
+            self.stats_update_evt
+                .write(1)
+                .map_err(BalloonError::EventFd)?;
         }
 
         Ok(())
     }
 
+    pub(crate) fn process_free_page_reporting_queue(&mut self) -> Result<(), BalloonError> {
+        // This is safe since we checked in the event handler that the device is activated.
+        let mem = self.device_state.mem().unwrap();
+        METRICS.balloon.free_page_report_count.inc();
+
+        let queue_index = self.free_page_reporting_idx();
+        let queue = &mut self.queues[queue_index];
+        let mut needs_interrupt = false;
+
+        // Each descriptor of a report describes a range of free guest memory, which can be
+        // removed right away. The driver doesn't reuse the pages until the report is acknowledged.
+        while let Some(head) = queue.pop(mem) {
+            let head_index = head.index;
+            let mut next_desc = Some(head);
+            while let Some(desc) = next_desc {
+                match remove_range(mem, (desc.addr, u64::from(desc.len)), self.restored) {
+                    Ok(()) => METRICS
+                        .balloon
+                        .free_page_report_freed
+                        .add(desc.len as usize),
+                    Err(err) => {
+                        error!("Error removing reported memory range: {:?}", err);
+                        METRICS.balloon.free_page_report_fails.inc();
+                    }
+                }
+                next_desc = desc.next_descriptor();
+            }
+
+            queue
+                .add_used(mem, head_index, 0)
+                .map_err(BalloonError::Queue)?;
+            needs_interrupt = true;
+        }
+
+        if needs_interrupt {
+            self.signal_used_queue()
+        } else {
+            Ok(())
+        }
+    }
+
     pub(crate) fn signal_used_queue(&self) -> Result<(), BalloonError> {
         self.irq_trigger.trigger_irq(IrqType::Vring).map_err(|err| {
             METRICS.balloon.event_fails.inc();
@@ -393,6 +469,9 @@ impl Balloon {
     pub fn process_virtio_queues(&mut self) {
         let _ = self.process_inflate();
         let _ = self.process_deflate_queue();
+        if self.free_page_reporting() {
+            let _ = self.process_free_page_reporting_queue();
+        }
     }
 
     pub fn id(&self) -> &str {
@@ -468,6 +547,25 @@ impl Balloon {
         self.stats_polling_interval_s
     }
 
+    pub fn free_page_reporting(&self) -> bool {
+        self.avail_features & (1u64 << VIRTIO_BALLOON_F_FREE_PAGE_REPORTING) != 0
+    }
+
+    /// Returns the event signaled each time the driver provides new statistics.
+    pub fn stats_update_evt(&self) -> &EventFd {
+        &self.stats_update_evt
+    }
+
+    /// Returns the policy of the balloon controller, if the target size is adjusted by the VMM.
+    pub fn auto_adjust(&self) -> Option<BalloonAutoAdjustConfig> {
+        self.auto_adjust
+    }
+
+    /// Sets the policy of the balloon controller.
+    pub fn set_auto_adjust(&mut self, auto_adjust: Option<BalloonAutoAdjustConfig>) {
+        self.auto_adjust = auto_adjust;
+    }
+
     pub fn latest_stats(&mut self) -> Option<&BalloonStats> {
         if self.stats_enabled() {
             self.latest_stats.target_pages = self.config_space.num_pages;
@@ -485,6 +583,8 @@ impl Balloon {
             amount_mib: self.size_mb(),
             deflate_on_oom: self.deflate_on_oom(),
             stats_polling_interval_s: self.stats_polling_interval_s(),
+            free_page_reporting: self.free_page_reporting(),
+            auto_adjust: self.auto_adjust,
         }
     }
 
@@ -492,6 +592,16 @@ impl Balloon {
         self.stats_polling_interval_s > 0
     }
 
+    // The free page reporting queue takes the place of the statistics queue when the statistics
+    // are disabled.
+    pub(crate) fn free_page_reporting_idx(&self) -> usize {
+        if self.stats_enabled() {
+            FREE_PAGE_REPORTING_INDEX
+        } else {
+            STATS_INDEX
+        }
+    }
+
     pub(crate) fn set_stats_desc_index(&mut self, stats_desc_index: Option<u16>) {
         self.stats_desc_index = stats_desc_index;
     }
@@ -682,36 +792,55 @@ pub(crate) mod tests {
         // Test all feature combinations.
         for deflate_on_oom in vec![true, false].iter() {
             for stats_interval in vec![0, 1].iter() {
-                let mut balloon = Balloon::new(0, *deflate_on_oom, *stats_interval, false).unwrap();
-                assert_eq!(balloon.device_type(), TYPE_BALLOON);
-
-                let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
-                    | ((if *deflate_on_oom { 1 } else { 0 }) << VIRTIO_BALLOON_F_DEFLATE_ON_OOM)
-                    | ((*stats_interval as u64) << VIRTIO_BALLOON_F_STATS_VQ);
+                for free_page_reporting in vec![true, false].iter() {
+                    let mut balloon = Balloon::new(
+                        0,
+                        *deflate_on_oom,
+                        *stats_interval,
+                        *free_page_reporting,
+                        false,
+                    )
+                    .unwrap();
+                    assert_eq!(balloon.device_type(), TYPE_BALLOON);
+
+                    let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
+                        | ((if *deflate_on_oom { 1 } else { 0 })
+                            << VIRTIO_BALLOON_F_DEFLATE_ON_OOM)
+                        | ((*stats_interval as u64) << VIRTIO_BALLOON_F_STATS_VQ)
+                        | ((if *free_page_reporting { 1 } else { 0 })
+                            << VIRTIO_BALLOON_F_FREE_PAGE_REPORTING);
+
+                    assert_eq!(balloon.avail_features_by_page(0), features as u32);
+                    assert_eq!(balloon.avail_features_by_page(1), (features >> 32) as u32);
+                    for i in 2..10 {
+                        assert_eq!(balloon.avail_features_by_page(i), 0u32);
+                    }
 
-                assert_eq!(balloon.avail_features_by_page(0), features as u32);
-                assert_eq!(balloon.avail_features_by_page(1), (features >> 32) as u32);
-                for i in 2..10 {
-                    assert_eq!(balloon.avail_features_by_page(i), 0u32);
-                }
+                    for i in 0..10 {
+                        balloon.ack_features_by_page(i, u32::MAX);
+                    }
+                    // Only present features should be acknowledged.
+                    assert_eq!(balloon.acked_features, features);
 
-                for i in 0..10 {
-                    balloon.ack_features_by_page(i, u32::MAX);
+                    // Only the queues of the present features should exist.
+                    let num_queues =
+                        2 + (*stats_interval as usize) + (if *free_page_reporting { 1 } else { 0 });
+                    assert_eq!(balloon.queues().len(), num_queues);
                 }
-                // Only present features should be acknowledged.
-                assert_eq!(balloon.acked_features, features);
             }
         }
     }
 
     #[test]
     fn test_virtio_read_config() {
-        let balloon = Balloon::new(0x10, true, 0, false).unwrap();
+        let balloon = Balloon::new(0x10, true, 0, false, false).unwrap();
 
         let cfg = BalloonConfig {
             amount_mib: 16,
             deflate_on_oom: true,
             stats_polling_interval_s: 0,
+            free_page_reporting: false,
+            auto_adjust: None,
         };
         assert_eq!(balloon.config(), cfg);
 
@@ -737,7 +866,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_virtio_write_config() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 0, false, false).unwrap();
 
         let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
             [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
@@ -757,7 +886,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_invalid_request() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 0, false, false).unwrap();
         let mem = default_mem();
         // Only initialize the inflate queue to demonstrate invalid request handling.
         let infq = VirtQueue::new(GuestAddress(0), &mem, 16);
@@ -816,7 +945,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_inflate() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 0, false, false).unwrap();
         let mem = default_mem();
         let infq = VirtQueue::new(GuestAddress(0), &mem, 16);
         balloon.set_queue(INFLATE_INDEX, infq.create_queue());
@@ -874,7 +1003,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_deflate() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 0, false, false).unwrap();
         let mem = default_mem();
         let defq = VirtQueue::new(GuestAddress(0), &mem, 16);
         balloon.set_queue(DEFLATE_INDEX, defq.create_queue());
@@ -910,7 +1039,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_stats() {
-        let mut balloon = Balloon::new(0, true, 1, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 1, false, false).unwrap();
         let mem = default_mem();
         let statsq = VirtQueue::new(GuestAddress(0), &mem, 16);
         balloon.set_queue(STATS_INDEX, statsq.create_queue());
@@ -965,6 +1094,8 @@ pub(crate) mod tests {
                 balloon.process_stats_queue_event().unwrap();
                 // Don't check for completion yet.
             });
+            // The update of the statistics is signaled.
+            assert_eq!(balloon.stats_update_evt().read().unwrap(), 1);
 
             let stats = balloon.latest_stats().unwrap();
             let expected_stats = BalloonStats {
@@ -989,9 +1120,66 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_free_page_reporting() {
+        for stats_interval in vec![0, 1].iter() {
+            let mut balloon = Balloon::new(0, true, *stats_interval, true, false).unwrap();
+            let mem = default_mem();
+            let reportq = VirtQueue::new(GuestAddress(0), &mem, 16);
+            let reporting_index = balloon.free_page_reporting_idx();
+            assert_eq!(reporting_index, 2 + *stats_interval as usize);
+            balloon.set_queue(reporting_index, reportq.create_queue());
+            balloon.activate(mem.clone()).unwrap();
+
+            // Fill the second and the fourth pages with non-zero bytes.
+            for i in 0..0x1000 {
+                mem.write_obj::<u8>(1, GuestAddress((1 << 12) + i)).unwrap();
+                mem.write_obj::<u8>(1, GuestAddress((3 << 12) + i)).unwrap();
+            }
+
+            // Error case: forgot to trigger the free page reporting queue event.
+            {
+                set_request(&reportq, 0, 1 << 12, 0x1000, VIRTQ_DESC_F_WRITE);
+                check_metric_after_block!(
+                    METRICS.balloon.event_fails,
+                    1,
+                    balloon
+                        .process_free_page_reporting_queue_event()
+                        .unwrap_or_else(report_balloon_event_fail)
+                );
+                // Verify that nothing got processed.
+                assert_eq!(reportq.used.idx.get(), 0);
+            }
+
+            // Happy case: a report made of two chained descriptors.
+            {
+                set_request(
+                    &reportq,
+                    0,
+                    1 << 12,
+                    0x1000,
+                    VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+                );
+                reportq.dtable[1].set(3 << 12, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+                check_metric_after_block!(
+                    METRICS.balloon.free_page_report_freed,
+                    0x2000,
+                    invoke_handler_for_queue_event(&mut balloon, reporting_index)
+                );
+                check_request_completion(&reportq, 0);
+
+                // Check that both pages were zeroed.
+                for i in 0..0x1000 {
+                    assert_eq!(mem.read_obj::<u8>(GuestAddress((1 << 12) + i)).unwrap(), 0);
+                    assert_eq!(mem.read_obj::<u8>(GuestAddress((3 << 12) + i)).unwrap(), 0);
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_process_balloon_queues() {
-        let mut balloon = Balloon::new(0x10, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0x10, true, 0, false, false).unwrap();
         let mem = default_mem();
         balloon.activate(mem).unwrap();
         balloon.process_virtio_queues()
@@ -999,7 +1187,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_update_stats_interval() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 0, false, false).unwrap();
         let mem = default_mem();
         balloon.activate(mem).unwrap();
         assert_eq!(
@@ -1008,7 +1196,7 @@ pub(crate) mod tests {
         );
         assert!(balloon.update_stats_polling_interval(0).is_ok());
 
-        let mut balloon = Balloon::new(0, true, 1, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 1, false, false).unwrap();
         let mem = default_mem();
         balloon.activate(mem).unwrap();
         assert_eq!(
@@ -1021,7 +1209,7 @@ pub(crate) mod tests {
 
     #[test]
     fn test_num_pages() {
-        let mut balloon = Balloon::new(0, true, 0, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 0, false, false).unwrap();
         // Assert that we can't update an inactive device.
         assert!(balloon.update_size(1).is_err());
         // Switch the state to active.
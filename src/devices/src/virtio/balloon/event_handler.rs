@@ -27,6 +27,17 @@ impl Balloon {
                 error!("Failed to register stats timerfd event: {}", err);
             }
         }
+        if self.free_page_reporting() {
+            if let Err(err) = ops.add(Events::new(
+                &self.queue_evts[self.free_page_reporting_idx()],
+                EventSet::IN,
+            )) {
+                error!(
+                    "Failed to register free page reporting queue event: {}",
+                    err
+                );
+            }
+        }
     }
 
     fn register_activate_event(&self, ops: &mut EventOps) {
@@ -65,6 +76,11 @@ impl MutEventSubscriber for Balloon {
             let virtq_inflate_ev_fd = self.queue_evts[INFLATE_INDEX].as_raw_fd();
             let virtq_deflate_ev_fd = self.queue_evts[DEFLATE_INDEX].as_raw_fd();
             let virtq_stats_ev_fd = self.queue_evts[STATS_INDEX].as_raw_fd();
+            // The free page reporting queue uses the statistics queue event when the statistics
+            // are disabled, so it has to be matched first.
+            let virtq_free_page_reporting_ev_fd = self
+                .free_page_reporting()
+                .then(|| self.queue_evts[self.free_page_reporting_idx()].as_raw_fd());
             let stats_timer_fd = self.stats_timer.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
 
@@ -76,6 +92,9 @@ impl MutEventSubscriber for Balloon {
                 _ if source == virtq_deflate_ev_fd => self
                     .process_deflate_queue_event()
                     .unwrap_or_else(report_balloon_event_fail),
+                _ if Some(source) == virtq_free_page_reporting_ev_fd => self
+                    .process_free_page_reporting_queue_event()
+                    .unwrap_or_else(report_balloon_event_fail),
                 _ if source == virtq_stats_ev_fd => self
                     .process_stats_queue_event()
                     .unwrap_or_else(report_balloon_event_fail),
@@ -122,7 +141,7 @@ pub mod tests {
     #[test]
     fn test_event_handler() {
         let mut event_manager = EventManager::new().unwrap();
-        let mut balloon = Balloon::new(0, true, 10, false).unwrap();
+        let mut balloon = Balloon::new(0, true, 10, false, false).unwrap();
         let mem = default_mem();
         let infq = VirtQueue::new(GuestAddress(0), &mem, 16);
         balloon.set_queue(INFLATE_INDEX, infq.create_queue());
@@ -9,10 +9,11 @@ use std::time::Duration;
 
 use snapshot::Persist;
 use timerfd::{SetTimeFlags, TimerState};
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::GuestMemoryMmap;
 
+use super::auto_adjust::BalloonAutoAdjustState;
 use super::*;
 use crate::virtio::balloon::device::{BalloonStats, ConfigSpace};
 use crate::virtio::persist::VirtioDeviceState;
@@ -84,6 +85,21 @@ pub struct BalloonState {
     latest_stats: BalloonStatsState,
     config_space: BalloonConfigSpaceState,
     virtio_state: VirtioDeviceState,
+    #[version(start = 2, ser_fn = "auto_adjust_ser")]
+    auto_adjust: Option<BalloonAutoAdjustState>,
+}
+
+impl BalloonState {
+    fn auto_adjust_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would leave the target size of the balloon as it is.
+        if target_version < 2 && self.auto_adjust.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support the balloon controller.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 pub struct BalloonConstructorArgs {
@@ -105,6 +121,7 @@ impl Persist<'_> for Balloon {
                 actual_pages: self.config_space.actual_pages,
             },
             virtio_state: VirtioDeviceState::from_device(self),
+            auto_adjust: self.auto_adjust.as_ref().map(BalloonAutoAdjustState::from),
         }
     }
 
@@ -114,14 +131,25 @@ impl Persist<'_> for Balloon {
     ) -> std::result::Result<Self, Self::Error> {
         // We can safely create the balloon with arbitrary flags and
         // num_pages because we will overwrite them after.
-        let mut balloon = Balloon::new(0, false, state.stats_polling_interval_s, true)?;
+        let free_page_reporting =
+            state.virtio_state.avail_features & (1u64 << VIRTIO_BALLOON_F_FREE_PAGE_REPORTING) != 0;
+        let mut balloon = Balloon::new(
+            0,
+            false,
+            state.stats_polling_interval_s,
+            free_page_reporting,
+            true,
+        )?;
 
         let mut num_queues = NUM_QUEUES;
-        // As per the virtio 1.1 specification, the statistics queue
-        // should not exist if the statistics are not enabled.
+        // As per the virtio 1.1 specification, the statistics and free page
+        // reporting queues should not exist if the features are not enabled.
         if state.stats_polling_interval_s == 0 {
             num_queues -= 1;
         }
+        if !free_page_reporting {
+            num_queues -= 1;
+        }
         balloon.queues = state
             .virtio_state
             .build_queues_checked(&constructor_args.mem, TYPE_BALLOON, num_queues, QUEUE_SIZE)
@@ -135,6 +163,10 @@ impl Persist<'_> for Balloon {
             num_pages: state.config_space.num_pages,
             actual_pages: state.config_space.actual_pages,
         };
+        balloon.auto_adjust = state
+            .auto_adjust
+            .as_ref()
+            .map(BalloonAutoAdjustConfig::from);
 
         if state.virtio_state.activated {
             balloon.device_state = DeviceState::Activated(constructor_args.mem);
@@ -173,38 +205,78 @@ mod tests {
         let mut mem = vec![0; 4096];
         let version_map = VersionMap::new();
 
-        // Create and save the balloon device.
-        let balloon = Balloon::new(0x42, false, 2, false).unwrap();
+        // Test the device with and without the free page reporting queue.
+        for free_page_reporting in vec![false, true].iter() {
+            // Create and save the balloon device.
+            let balloon = Balloon::new(0x42, false, 2, *free_page_reporting, false).unwrap();
 
-        <Balloon as Persist>::save(&balloon)
-            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            <Balloon as Persist>::save(&balloon)
+                .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+                .unwrap();
+
+            // Deserialize and restore the balloon device.
+            let restored_balloon = Balloon::restore(
+                BalloonConstructorArgs {
+                    mem: guest_mem.clone(),
+                },
+                &BalloonState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+            )
             .unwrap();
 
-        // Deserialize and restore the balloon device.
+            assert_eq!(restored_balloon.device_type(), TYPE_BALLOON);
+            assert!(restored_balloon.restored);
+
+            assert_eq!(restored_balloon.acked_features, balloon.acked_features);
+            assert_eq!(restored_balloon.avail_features, balloon.avail_features);
+            assert_eq!(restored_balloon.free_page_reporting(), *free_page_reporting);
+            assert_eq!(restored_balloon.config_space, balloon.config_space);
+            assert_eq!(restored_balloon.queues(), balloon.queues());
+            assert_eq!(
+                restored_balloon.interrupt_status().load(Ordering::Relaxed),
+                balloon.interrupt_status().load(Ordering::Relaxed)
+            );
+            assert_eq!(restored_balloon.is_activated(), balloon.is_activated());
+
+            assert_eq!(
+                restored_balloon.stats_polling_interval_s,
+                balloon.stats_polling_interval_s
+            );
+            assert_eq!(restored_balloon.stats_desc_index, balloon.stats_desc_index);
+            assert_eq!(restored_balloon.latest_stats, balloon.latest_stats);
+        }
+    }
+
+    #[test]
+    fn test_auto_adjust_persistence() {
+        let guest_mem = default_mem();
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BalloonState::type_id(), 2);
+
+        let mut balloon = Balloon::new(0x42, false, 2, false, false).unwrap();
+        let auto_adjust = BalloonAutoAdjustConfig {
+            target_free_memory_percent: 20,
+            min_amount_mib: 0,
+            max_amount_mib: 64,
+            step_mib: 8,
+        };
+        balloon.set_auto_adjust(Some(auto_adjust));
+
+        // Older versions would leave the target size as it is.
+        assert!(<Balloon as Persist>::save(&balloon)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Balloon as Persist>::save(&balloon)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
         let restored_balloon = Balloon::restore(
             BalloonConstructorArgs { mem: guest_mem },
-            &BalloonState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+            &BalloonState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
         )
         .unwrap();
-
-        assert_eq!(restored_balloon.device_type(), TYPE_BALLOON);
-        assert!(restored_balloon.restored);
-
-        assert_eq!(restored_balloon.acked_features, balloon.acked_features);
-        assert_eq!(restored_balloon.avail_features, balloon.avail_features);
-        assert_eq!(restored_balloon.config_space, balloon.config_space);
-        assert_eq!(restored_balloon.queues(), balloon.queues());
-        assert_eq!(
-            restored_balloon.interrupt_status().load(Ordering::Relaxed),
-            balloon.interrupt_status().load(Ordering::Relaxed)
-        );
-        assert_eq!(restored_balloon.is_activated(), balloon.is_activated());
-
-        assert_eq!(
-            restored_balloon.stats_polling_interval_s,
-            balloon.stats_polling_interval_s
-        );
-        assert_eq!(restored_balloon.stats_desc_index, balloon.stats_desc_index);
-        assert_eq!(restored_balloon.latest_stats, balloon.latest_stats);
+        assert_eq!(restored_balloon.auto_adjust(), Some(auto_adjust));
     }
 }
@@ -20,6 +20,10 @@ pub fn invoke_handler_for_queue_event(b: &mut Balloon, queue_index: usize) {
     match queue_index {
         INFLATE_INDEX => b.process_inflate_queue_event().unwrap(),
         DEFLATE_INDEX => b.process_deflate_queue_event().unwrap(),
+        // Matched first, since it can share its index with the statistics queue.
+        _ if b.free_page_reporting() && queue_index == b.free_page_reporting_idx() => {
+            b.process_free_page_reporting_queue_event().unwrap()
+        }
         STATS_INDEX => b.process_stats_queue_event().unwrap(),
         _ => unreachable!(),
     };
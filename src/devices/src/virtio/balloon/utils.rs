@@ -83,7 +83,8 @@ pub(crate) fn remove_range(
         // Mmap a new anonymous region over the present one in order to create a hole.
         // This workaround is (only) needed after resuming from a snapshot because the guest memory
         // is mmaped from file as private and there is no `madvise` flag that works for this case.
-        if restored {
+        // Shared guest memory is restored in memfds, which must stay mapped.
+        if restored && region.flags() & libc::MAP_SHARED == 0 {
             let ret = unsafe {
                 libc::mmap(
                     phys_address as *mut _,
@@ -99,14 +100,14 @@ pub(crate) fn remove_range(
             }
         };
 
-        // Madvise the region in order to mark it as not used.
-        let ret = unsafe {
-            libc::madvise(
-                phys_address as *mut _,
-                range_len as usize,
-                libc::MADV_DONTNEED,
-            )
+        // Madvise the region in order to mark it as not used. Dropping the pages of a shared
+        // mapping only unmaps them, so they are punched out of the backing memfd instead.
+        let advice = if region.flags() & libc::MAP_SHARED != 0 {
+            libc::MADV_REMOVE
+        } else {
+            libc::MADV_DONTNEED
         };
+        let ret = unsafe { libc::madvise(phys_address as *mut _, range_len as usize, advice) };
         if ret < 0 {
             return Err(RemoveRegionError::MadviseFail(io::Error::last_os_error()));
         }
@@ -119,6 +120,8 @@ pub(crate) fn remove_range(
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::fs::FileExt;
+
     use vm_memory::Bytes;
 
     use super::*;
@@ -226,6 +229,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_remove_range_on_shared() {
+        let page_size: usize = 0x1000;
+        let mem =
+            vm_memory::create_shared_guest_memory(&[(GuestAddress(0), 2 * page_size)], None, false)
+                .unwrap();
+
+        let ones = vec![1u8; 2 * page_size];
+        mem.write(&ones[..], GuestAddress(0)).unwrap();
+
+        // The page is punched out of the memfd, so it reads back as zeroes.
+        assert!(remove_range(&mem, (GuestAddress(0), page_size as u64), false).is_ok());
+        let mut actual_page = vec![0u8; page_size];
+        mem.read(&mut actual_page.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(vec![0u8; page_size], actual_page);
+        mem.read(
+            &mut actual_page.as_mut_slice(),
+            GuestAddress(page_size as u64),
+        )
+        .unwrap();
+        assert_eq!(vec![1u8; page_size], actual_page);
+
+        // Restored shared memory is punched out of its memfd as well.
+        assert!(remove_range(
+            &mem,
+            (GuestAddress(page_size as u64), page_size as u64),
+            true
+        )
+        .is_ok());
+        let region = mem.find_region(GuestAddress(0)).unwrap();
+        assert_eq!(region.flags() & libc::MAP_SHARED, libc::MAP_SHARED);
+        let mut byte = [1u8];
+        region
+            .file_offset()
+            .unwrap()
+            .file()
+            .read_exact_at(&mut byte, page_size as u64)
+            .unwrap();
+        assert_eq!(byte[0], 0);
+    }
+
     #[test]
     fn test_remove_range_on_restored() {
         let page_size: usize = 0x1000;
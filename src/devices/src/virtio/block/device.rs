@@ -9,27 +9,31 @@ use std::convert::From;
 use std::fs::{File, OpenOptions};
 use std::io::{Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicUsize;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::{cmp, result};
 
-use block_io::FileEngine;
+use block_io::cow::CowFile;
+use block_io::nbd::NbdClient;
+use block_io::qcow2::Qcow2File;
+use block_io::{CowFileEngine, FileEngine, NbdFileEngine, Qcow2FileEngine};
 use logger::{error, warn, IncMetric, METRICS};
 use rate_limiter::{BucketUpdate, RateLimiter};
 use serde::{Deserialize, Serialize};
 use utils::eventfd::EventFd;
 use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
 use virtio_gen::virtio_blk::{
-    VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO, VIRTIO_BLK_ID_BYTES, VIRTIO_F_VERSION_1,
+    VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO,
+    VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_ID_BYTES, VIRTIO_F_VERSION_1,
 };
 use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
-use vm_memory::GuestMemoryMmap;
+use vm_memory::{ByteValued, GuestMemoryMmap};
 
 use super::super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK};
 use super::io::async_io;
 use super::request::*;
-use super::{io as block_io, Error, CONFIG_SPACE_SIZE, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE};
+use super::{io as block_io, Error, MAX_NUM_QUEUES, QUEUE_SIZE, SECTOR_SHIFT, SECTOR_SIZE};
 use crate::virtio::{IrqTrigger, IrqType};
 
 /// Configuration options for disk caching.
@@ -50,12 +54,14 @@ impl Default for CacheType {
     }
 }
 
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub enum FileEngineType {
     /// Use an Async engine, based on io_uring.
     Async,
     /// Use a Sync engine, based on blocking system calls.
     Sync,
+    /// Use an NBD client, connected to the export whose URI is given as the drive path.
+    Nbd,
 }
 
 impl Default for FileEngineType {
@@ -73,11 +79,59 @@ impl FileEngineType {
     }
 }
 
+/// Format of the disk image backing a block device.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    /// The image is a plain copy of the disk.
+    Raw,
+    /// The image is a qcow2 file, possibly backed by other images.
+    Qcow2,
+}
+
+impl Default for ImageFormat {
+    fn default() -> Self {
+        Self::Raw
+    }
+}
+
+/// The virtio block configuration space, laid out as `struct virtio_blk_config`.
+///
+/// We only populate the disk capacity, the number of queues and the discard/write zeroes limits,
+/// the other fields are exposed as zeroes.
+#[derive(Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct ConfigSpace {
+    pub capacity: u64,
+    size_max: u32,
+    seg_max: u32,
+    geometry: [u8; 4],
+    blk_size: u32,
+    topology: [u8; 8],
+    writeback: u8,
+    unused0: u8,
+    num_queues: u16,
+    pub max_discard_sectors: u32,
+    pub max_discard_seg: u32,
+    pub discard_sector_alignment: u32,
+    pub max_write_zeroes_sectors: u32,
+    pub max_write_zeroes_seg: u32,
+    pub write_zeroes_may_unmap: u8,
+    unused1: [u8; 3],
+}
+
+// Safe because ConfigSpace only contains plain data.
+unsafe impl ByteValued for ConfigSpace {}
+
 /// Helper object for setting up all `Block` fields derived from its backing file.
 pub(crate) struct DiskProperties {
     cache_type: CacheType,
     file_path: String,
-    file_engine: FileEngine<PendingRequest>,
+    image_format: ImageFormat,
+    // One IO engine per virtio queue, each of them working on its own handle of the backing file.
+    file_engines: Vec<FileEngine<PendingRequest>>,
+    // Overlay receiving the writes to a read-only backing file, shared by all the IO engines.
+    cow_file: Option<Arc<Mutex<CowFile>>>,
     nsectors: u64,
     image_id: [u8; VIRTIO_BLK_ID_BYTES as usize],
 }
@@ -88,16 +142,135 @@ impl DiskProperties {
         is_disk_read_only: bool,
         cache_type: CacheType,
         file_engine_type: FileEngineType,
+        num_queues: usize,
+        image_format: ImageFormat,
+        cow_overlay_path: Option<String>,
     ) -> result::Result<Self, Error> {
+        // The overlay takes the writes of the guest, so the backing file itself must not be
+        // writable. Overlays are only implemented on top of raw images, using the Sync engine.
+        if cow_overlay_path.is_some()
+            && (!is_disk_read_only
+                || image_format != ImageFormat::Raw
+                || file_engine_type != FileEngineType::Sync)
+        {
+            return Err(Error::UnsupportedCowOverlay);
+        }
+
+        if file_engine_type == FileEngineType::Nbd {
+            return Self::new_nbd(
+                disk_image_path,
+                is_disk_read_only,
+                cache_type,
+                num_queues,
+                image_format,
+            );
+        }
+
         let mut disk_image = OpenOptions::new()
             .read(true)
             .write(!is_disk_read_only)
             .open(PathBuf::from(&disk_image_path))
             .map_err(Error::BackingFile)?;
-        let disk_size = disk_image
-            .seek(SeekFrom::End(0))
-            .map_err(Error::BackingFile)? as u64;
+        let image_id = Self::build_disk_image_id(&disk_image);
+
+        let mut cow_file = None;
+        let (disk_size, file_engines) = match (image_format, cow_overlay_path) {
+            (ImageFormat::Raw, Some(cow_overlay_path)) => {
+                let overlay =
+                    CowFile::new(disk_image, cow_overlay_path).map_err(Error::CowOverlay)?;
+                let disk_size = overlay.size();
+                let overlay = Arc::new(Mutex::new(overlay));
+                let file_engines = (0..num_queues)
+                    .map(|_| FileEngine::Cow(CowFileEngine::new(overlay.clone())))
+                    .collect();
+                cow_file = Some(overlay);
+                (disk_size, file_engines)
+            }
+            (ImageFormat::Raw, None) => {
+                let disk_size = disk_image
+                    .seek(SeekFrom::End(0))
+                    .map_err(Error::BackingFile)? as u64;
+                let mut file_engines = Vec::with_capacity(num_queues);
+                for _ in 1..num_queues {
+                    let file = disk_image.try_clone().map_err(Error::BackingFile)?;
+                    file_engines.push(
+                        FileEngine::from_file(file, file_engine_type).map_err(Error::FileEngine)?,
+                    );
+                }
+                file_engines.push(
+                    FileEngine::from_file(disk_image, file_engine_type)
+                        .map_err(Error::FileEngine)?,
+                );
+                (disk_size, file_engines)
+            }
+            (ImageFormat::Qcow2, _) => {
+                // Requests to qcow2 images are always executed synchronously.
+                if file_engine_type != FileEngineType::Sync {
+                    return Err(Error::UnsupportedImageFormat(
+                        image_format,
+                        file_engine_type,
+                    ));
+                }
+                let image = Qcow2File::from_file(disk_image, Path::new(&disk_image_path))
+                    .map_err(Error::Qcow2Image)?;
+                let disk_size = image.virtual_size();
+                // The image metadata is shared by the IO engines of all the queues.
+                let image = Arc::new(Mutex::new(image));
+                let file_engines = (0..num_queues)
+                    .map(|_| FileEngine::Qcow2(Qcow2FileEngine::new(image.clone())))
+                    .collect();
+                (disk_size, file_engines)
+            }
+        };
+
+        Self::check_disk_size(disk_size);
+        Ok(Self {
+            cache_type,
+            nsectors: disk_size >> SECTOR_SHIFT,
+            image_id,
+            file_path: disk_image_path,
+            image_format,
+            file_engines,
+            cow_file,
+        })
+    }
+
+    fn new_nbd(
+        uri: String,
+        is_disk_read_only: bool,
+        cache_type: CacheType,
+        num_queues: usize,
+        image_format: ImageFormat,
+    ) -> result::Result<Self, Error> {
+        // The export is a raw disk from our point of view, the server takes care of its format.
+        if image_format != ImageFormat::Raw {
+            return Err(Error::UnsupportedImageFormat(
+                image_format,
+                FileEngineType::Nbd,
+            ));
+        }
+        let client = NbdClient::connect(&uri, is_disk_read_only).map_err(Error::NbdExport)?;
+        let disk_size = client.size();
+        let image_id = Self::build_disk_image_id_from_str(client.export_name());
+        // The connection is shared by the IO engines of all the queues.
+        let client = Arc::new(Mutex::new(client));
+        let file_engines = (0..num_queues)
+            .map(|_| FileEngine::Nbd(NbdFileEngine::new(client.clone())))
+            .collect();
+
+        Self::check_disk_size(disk_size);
+        Ok(Self {
+            cache_type,
+            nsectors: disk_size >> SECTOR_SHIFT,
+            image_id,
+            file_path: uri,
+            image_format,
+            file_engines,
+            cow_file: None,
+        })
+    }
 
+    fn check_disk_size(disk_size: u64) {
         // We only support disk size, which uses the first two words of the configuration space.
         // If the image is not a multiple of the sector size, the tail bits are not exposed.
         if disk_size % SECTOR_SIZE != 0 {
@@ -107,28 +280,19 @@ impl DiskProperties {
                 disk_size, SECTOR_SIZE
             );
         }
-
-        Ok(Self {
-            cache_type,
-            nsectors: disk_size >> SECTOR_SHIFT,
-            image_id: Self::build_disk_image_id(&disk_image),
-            file_path: disk_image_path,
-            file_engine: FileEngine::from_file(disk_image, file_engine_type)
-                .map_err(Error::FileEngine)?,
-        })
     }
 
-    pub fn file_engine(&self) -> &FileEngine<PendingRequest> {
-        &self.file_engine
+    pub fn file_engines(&self) -> &[FileEngine<PendingRequest>] {
+        &self.file_engines
     }
 
-    pub fn file_engine_mut(&mut self) -> &mut FileEngine<PendingRequest> {
-        &mut self.file_engine
+    pub fn file_engine_mut(&mut self, queue_index: usize) -> &mut FileEngine<PendingRequest> {
+        &mut self.file_engines[queue_index]
     }
 
     #[cfg(test)]
     pub fn file(&self) -> &File {
-        &self.file_engine.file()
+        self.file_engines[0].file()
     }
 
     pub fn nsectors(&self) -> u64 {
@@ -152,19 +316,22 @@ impl DiskProperties {
     }
 
     fn build_disk_image_id(disk_file: &File) -> [u8; VIRTIO_BLK_ID_BYTES as usize] {
-        let mut default_id = [0; VIRTIO_BLK_ID_BYTES as usize];
         match Self::build_device_id(disk_file) {
             Err(_) => {
                 warn!("Could not generate device id. We'll use a default.");
+                [0; VIRTIO_BLK_ID_BYTES as usize]
             }
-            Ok(disk_id_string) => {
-                // The kernel only knows to read a maximum of VIRTIO_BLK_ID_BYTES.
-                // This will also zero out any leftover bytes.
-                let disk_id = disk_id_string.as_bytes();
-                let bytes_to_copy = cmp::min(disk_id.len(), VIRTIO_BLK_ID_BYTES as usize);
-                default_id[..bytes_to_copy].copy_from_slice(&disk_id[..bytes_to_copy]);
-            }
+            Ok(disk_id_string) => Self::build_disk_image_id_from_str(&disk_id_string),
         }
+    }
+
+    fn build_disk_image_id_from_str(disk_id: &str) -> [u8; VIRTIO_BLK_ID_BYTES as usize] {
+        let mut default_id = [0; VIRTIO_BLK_ID_BYTES as usize];
+        // The kernel only knows to read a maximum of VIRTIO_BLK_ID_BYTES.
+        // This will also zero out any leftover bytes.
+        let disk_id = disk_id.as_bytes();
+        let bytes_to_copy = cmp::min(disk_id.len(), VIRTIO_BLK_ID_BYTES as usize);
+        default_id[..bytes_to_copy].copy_from_slice(&disk_id[..bytes_to_copy]);
         default_id
     }
 
@@ -173,16 +340,60 @@ impl DiskProperties {
         &self.file_path
     }
 
+    /// Backing file format.
+    pub fn image_format(&self) -> ImageFormat {
+        self.image_format
+    }
+
+    /// Path of the copy-on-write overlay, if any.
+    pub fn cow_overlay_path(&self) -> Option<String> {
+        self.cow_file.as_ref().map(|cow_file| {
+            cow_file
+                .lock()
+                .expect("Poisoned lock")
+                .overlay_path()
+                .clone()
+        })
+    }
+
+    /// Bitmap of the chunks written to the copy-on-write overlay, if any.
+    pub fn cow_bitmap(&self) -> Option<Vec<u64>> {
+        self.cow_file
+            .as_ref()
+            .map(|cow_file| cow_file.lock().expect("Poisoned lock").bitmap().to_vec())
+    }
+
+    /// Restores the bitmap of the copy-on-write overlay.
+    pub fn set_cow_bitmap(&mut self, bitmap: Vec<u64>) -> result::Result<(), Error> {
+        match self.cow_file.as_ref() {
+            Some(cow_file) => cow_file
+                .lock()
+                .expect("Poisoned lock")
+                .set_bitmap(bitmap)
+                .map_err(Error::CowOverlay),
+            None => Err(Error::NoCowOverlay),
+        }
+    }
+
     /// Provides vec containing the virtio block configuration space
     /// buffer. The config space is populated with the disk size based
-    /// on the backing file size.
+    /// on the backing file size and with the discard and write zeroes limits.
     pub fn virtio_block_config_space(&self) -> Vec<u8> {
-        // The config space is little endian.
-        let mut config = Vec::with_capacity(CONFIG_SPACE_SIZE);
-        for i in 0..CONFIG_SPACE_SIZE {
-            config.push((self.nsectors >> (8 * i)) as u8);
-        }
-        config
+        // The config space is little endian, and so is every platform Firecracker runs on.
+        let config = ConfigSpace {
+            capacity: self.nsectors,
+            num_queues: self.file_engines.len() as u16,
+            // Discard and write zeroes requests carry a single segment, with no limit on
+            // its size other than the disk boundaries.
+            max_discard_sectors: u32::MAX,
+            max_discard_seg: 1,
+            discard_sector_alignment: 1,
+            max_write_zeroes_sectors: u32::MAX,
+            max_write_zeroes_seg: 1,
+            write_zeroes_may_unmap: 1,
+            ..Default::default()
+        };
+        config.as_slice().to_vec()
     }
 
     pub fn cache_type(&self) -> CacheType {
@@ -203,7 +414,7 @@ pub struct Block {
 
     // Transport related fields.
     pub(crate) queues: Vec<Queue>,
-    pub(crate) queue_evts: [EventFd; 1],
+    pub(crate) queue_evts: Vec<EventFd>,
     pub(crate) device_state: DeviceState,
     pub(crate) irq_trigger: IrqTrigger,
 
@@ -212,14 +423,17 @@ pub struct Block {
     pub(crate) partuuid: Option<String>,
     pub(crate) root_device: bool,
     pub(crate) rate_limiter: RateLimiter,
-    is_io_engine_throttled: bool,
+    is_io_engine_throttled: Vec<bool>,
 }
 
 macro_rules! unwrap_async_file_engine_or_return {
     ($file_engine: expr) => {
         match $file_engine {
             FileEngine::Async(engine) => engine,
-            FileEngine::Sync(_) => {
+            FileEngine::Sync(_)
+            | FileEngine::Qcow2(_)
+            | FileEngine::Cow(_)
+            | FileEngine::Nbd(_) => {
                 error!("The block device doesn't use an async IO engine");
                 return;
             }
@@ -241,12 +455,22 @@ impl Block {
         is_disk_root: bool,
         rate_limiter: RateLimiter,
         file_engine_type: FileEngineType,
+        num_queues: usize,
+        image_format: ImageFormat,
+        cow_overlay_path: Option<String>,
     ) -> result::Result<Block, Error> {
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+
         let disk_properties = DiskProperties::new(
             disk_image_path,
             is_disk_read_only,
             cache_type,
             file_engine_type,
+            num_queues,
+            image_format,
+            cow_overlay_path,
         )?;
 
         let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
@@ -255,13 +479,22 @@ impl Block {
             avail_features |= 1u64 << VIRTIO_BLK_F_FLUSH;
         }
 
-        if is_disk_read_only {
+        // Disks with a copy-on-write overlay are writable from the guest's point of view.
+        if is_disk_read_only && disk_properties.cow_file.is_none() {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        } else {
+            avail_features |= (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
         };
 
-        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?];
+        if num_queues > 1 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
+
+        let queue_evts = (0..num_queues)
+            .map(|_| EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd))
+            .collect::<result::Result<Vec<EventFd>, Error>>()?;
 
-        let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
 
         Ok(Block {
             id,
@@ -277,35 +510,37 @@ impl Block {
             device_state: DeviceState::Inactive,
             irq_trigger: IrqTrigger::new().map_err(Error::IrqTrigger)?,
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
-            is_io_engine_throttled: false,
+            is_io_engine_throttled: vec![false; num_queues],
         })
     }
 
-    pub(crate) fn process_queue_event(&mut self) {
+    pub(crate) fn process_queue_event(&mut self, queue_index: usize) {
         METRICS.block.queue_event_count.inc();
-        if let Err(err) = self.queue_evts[0].read() {
+        if let Err(err) = self.queue_evts[queue_index].read() {
             error!("Failed to get queue event: {:?}", err);
             METRICS.block.event_fails.inc();
         } else if self.rate_limiter.is_blocked() {
             METRICS.block.rate_limiter_throttled_events.inc();
-        } else if self.is_io_engine_throttled {
+        } else if self.is_io_engine_throttled[queue_index] {
             METRICS.block.io_engine_throttled_events.inc();
         } else {
-            self.process_virtio_queues();
+            self.process_queue(queue_index);
         }
     }
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        self.process_queue(0);
+        for queue_index in 0..self.queues.len() {
+            self.process_queue(queue_index);
+        }
     }
 
     pub(crate) fn process_rate_limiter_event(&mut self) {
         METRICS.block.rate_limiter_event_count.inc();
         // Upon rate limiter event, call the rate limiter handler
-        // and restart processing the queue.
+        // and restart processing the queues.
         if self.rate_limiter.event_handler().is_ok() {
-            self.process_queue(0);
+            self.process_virtio_queues();
         }
     }
 
@@ -346,7 +581,7 @@ impl Block {
                     }
 
                     used_any = true;
-                    request.process(&mut self.disk, head.index, mem)
+                    request.process(&mut self.disk, queue_index, head.index, mem)
                 }
                 Err(err) => {
                     error!("Failed to parse available descriptor chain: {:?}", err);
@@ -362,7 +597,7 @@ impl Block {
                 ProcessingResult::Submitted => {}
                 ProcessingResult::Throttled => {
                     queue.undo_pop();
-                    self.is_io_engine_throttled = true;
+                    self.is_io_engine_throttled[queue_index] = true;
                     break;
                 }
                 ProcessingResult::Executed(finished) => {
@@ -377,7 +612,7 @@ impl Block {
             }
         }
 
-        if let FileEngine::Async(engine) = self.disk.file_engine_mut() {
+        if let FileEngine::Async(engine) = self.disk.file_engine_mut(queue_index) {
             if let Err(err) = engine.kick_submission_queue() {
                 error!("Error submitting pending block requests: {:?}", err);
             }
@@ -388,12 +623,12 @@ impl Block {
         }
     }
 
-    fn process_async_completion_queue(&mut self) {
-        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engine);
+    fn process_async_completion_queue(&mut self, queue_index: usize) {
+        let engine = unwrap_async_file_engine_or_return!(self.disk.file_engine_mut(queue_index));
 
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
-        let queue = &mut self.queues[0];
+        let queue = &mut self.queues[queue_index];
 
         loop {
             match engine.pop(mem) {
@@ -429,28 +664,34 @@ impl Block {
         }
     }
 
-    pub fn process_async_completion_event(&mut self) {
-        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engine);
+    pub fn process_async_completion_event(&mut self, queue_index: usize) {
+        let engine = unwrap_async_file_engine_or_return!(self.disk.file_engine_mut(queue_index));
 
         if let Err(err) = engine.completion_evt().read() {
             error!("Failed to get async completion event: {:?}", err);
         } else {
-            self.process_async_completion_queue();
+            self.process_async_completion_queue(queue_index);
 
-            if self.is_io_engine_throttled {
-                self.is_io_engine_throttled = false;
-                self.process_queue(0);
+            if self.is_io_engine_throttled[queue_index] {
+                self.is_io_engine_throttled[queue_index] = false;
+                self.process_queue(queue_index);
             }
         }
     }
 
     /// Update the backing file and the config space of the block device.
+    ///
+    /// The copy-on-write overlay, if any, is started over since it no longer matches the new
+    /// backing file.
     pub fn update_disk_image(&mut self, disk_image_path: String) -> result::Result<(), Error> {
         let disk_properties = DiskProperties::new(
             disk_image_path,
             self.is_read_only(),
             self.cache_type(),
             self.file_engine_type(),
+            self.num_queues(),
+            self.image_format(),
+            self.cow_overlay_path(),
         )?;
         self.disk = disk_properties;
         self.config_space = self.disk.virtio_block_config_space();
@@ -462,6 +703,26 @@ impl Block {
         Ok(())
     }
 
+    /// Redirects the writes of the guest to a new, empty copy-on-write overlay. The data written
+    /// to the previous overlay is no longer visible to the guest.
+    pub fn update_cow_overlay(&mut self, cow_overlay_path: String) -> result::Result<(), Error> {
+        if self.cow_overlay_path().is_none() {
+            return Err(Error::NoCowOverlay);
+        }
+        self.disk = DiskProperties::new(
+            self.file_path().clone(),
+            self.is_read_only(),
+            self.cache_type(),
+            self.file_engine_type(),
+            self.num_queues(),
+            self.image_format(),
+            Some(cow_overlay_path),
+        )?;
+
+        METRICS.block.update_count.inc();
+        Ok(())
+    }
+
     /// Updates the parameters for the rate limiter
     pub fn update_rate_limiter(&mut self, bytes: BucketUpdate, ops: BucketUpdate) {
         self.rate_limiter.update_buckets(bytes, ops);
@@ -477,14 +738,25 @@ impl Block {
         self.disk.file_path()
     }
 
+    /// Provides the format of the backing file of this block device.
+    pub fn image_format(&self) -> ImageFormat {
+        self.disk.image_format()
+    }
+
+    /// Provides the path of the copy-on-write overlay of this block device, if any.
+    pub fn cow_overlay_path(&self) -> Option<String> {
+        self.disk.cow_overlay_path()
+    }
+
     /// Provides the PARTUUID of this block device.
     pub fn partuuid(&self) -> Option<&String> {
         self.partuuid.as_ref()
     }
 
-    /// Specifies if this block device is read only.
+    /// Specifies if the backing file of this block device is read only. This is also the case
+    /// for devices with a copy-on-write overlay, even though the guest can write to them.
     pub fn is_read_only(&self) -> bool {
-        self.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0
+        self.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0 || self.disk.cow_file.is_some()
     }
 
     /// Specifies if this block device is read only.
@@ -497,21 +769,34 @@ impl Block {
         self.disk.cache_type()
     }
 
+    /// Provides the number of virtio queues of this block device.
+    pub fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
     /// Provides non-mutable reference to this device's rate limiter.
     pub fn rate_limiter(&self) -> &RateLimiter {
         &self.rate_limiter
     }
 
     pub fn file_engine_type(&self) -> FileEngineType {
-        match self.disk.file_engine() {
-            FileEngine::Sync(_) => FileEngineType::Sync,
+        // All the queues use the same type of IO engine.
+        match &self.disk.file_engines()[0] {
+            FileEngine::Sync(_) | FileEngine::Qcow2(_) | FileEngine::Cow(_) => FileEngineType::Sync,
             FileEngine::Async(_) => FileEngineType::Async,
+            FileEngine::Nbd(_) => FileEngineType::Nbd,
         }
     }
 
     fn drain_and_flush(&mut self, discard: bool) {
-        if let Err(err) = self.disk.file_engine_mut().drain_and_flush(discard) {
-            error!("Failed to drain ops and flush block data: {:?}", err);
+        for queue_index in 0..self.num_queues() {
+            if let Err(err) = self
+                .disk
+                .file_engine_mut(queue_index)
+                .drain_and_flush(discard)
+            {
+                error!("Failed to drain ops and flush block data: {:?}", err);
+            }
         }
     }
 
@@ -521,8 +806,10 @@ impl Block {
         }
 
         self.drain_and_flush(false);
-        if let FileEngine::Async(_engine) = self.disk.file_engine_mut() {
-            self.process_async_completion_queue();
+        if let FileEngineType::Async = self.file_engine_type() {
+            for queue_index in 0..self.num_queues() {
+                self.process_async_completion_queue(queue_index);
+            }
         }
     }
 }
@@ -616,8 +903,10 @@ impl Drop for Block {
     fn drop(&mut self) {
         match self.disk.cache_type {
             CacheType::Unsafe => {
-                if let Err(err) = self.disk.file_engine_mut().drain(true) {
-                    error!("Failed to drain ops on drop: {:?}", err);
+                for queue_index in 0..self.num_queues() {
+                    if let Err(err) = self.disk.file_engine_mut(queue_index).drain(true) {
+                        error!("Failed to drain ops on drop: {:?}", err);
+                    }
                 }
             }
             CacheType::Writeback => {
@@ -632,6 +921,7 @@ pub(crate) mod tests {
     use std::fs::metadata;
     use std::io::Read;
     use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileExt;
     use std::time::Duration;
     use std::{thread, u32};
 
@@ -642,11 +932,14 @@ pub(crate) mod tests {
 
     use super::*;
     use crate::check_metric_after_block;
+    use crate::virtio::block::io::qcow2::tests::create_qcow2_image;
+    use crate::virtio::block::io::qcow2::Error::InvalidMagic;
     use crate::virtio::block::test_utils::{
         default_block, default_engine_type_for_kv, set_queue, set_rate_limiter,
         simulate_async_completion_event, simulate_queue_and_async_completion_events,
         simulate_queue_event,
     };
+    use crate::virtio::block::{CONFIG_SPACE_SIZE, DEFAULT_NUM_QUEUES};
     use crate::virtio::queue::tests::*;
     use crate::virtio::test_utils::{default_mem, initialize_virtqueue, VirtQueue};
     use crate::virtio::IO_URING_NUM_ENTRIES;
@@ -663,6 +956,9 @@ pub(crate) mod tests {
             true,
             CacheType::Unsafe,
             default_engine_type_for_kv(),
+            DEFAULT_NUM_QUEUES,
+            ImageFormat::Raw,
+            None,
         )
         .unwrap();
 
@@ -670,9 +966,16 @@ pub(crate) mod tests {
         assert_eq!(disk_properties.nsectors, num_sectors);
         let cfg = disk_properties.virtio_block_config_space();
         assert_eq!(cfg.len(), CONFIG_SPACE_SIZE);
-        for (i, byte) in cfg.iter().enumerate() {
+        for (i, byte) in cfg[..8].iter().enumerate() {
             assert_eq!(*byte, (num_sectors >> (8 * i)) as u8);
         }
+        let config_space = ConfigSpace::from_slice(&cfg).unwrap();
+        assert_eq!({ config_space.max_discard_sectors }, u32::MAX);
+        assert_eq!({ config_space.max_discard_seg }, 1);
+        assert_eq!({ config_space.max_write_zeroes_sectors }, u32::MAX);
+        assert_eq!({ config_space.max_write_zeroes_seg }, 1);
+        assert_eq!({ config_space.write_zeroes_may_unmap }, 1);
+        assert_eq!({ config_space.num_queues }, DEFAULT_NUM_QUEUES as u16);
         // Testing `backing_file.virtio_block_disk_image_id()` implies
         // duplicating that logic in tests, so skipping it.
 
@@ -681,17 +984,241 @@ pub(crate) mod tests {
             true,
             CacheType::Unsafe,
             default_engine_type_for_kv(),
+            DEFAULT_NUM_QUEUES,
+            ImageFormat::Raw,
+            None,
         )
         .is_err());
     }
 
+    #[test]
+    fn test_qcow2_disk() {
+        let f = TempFile::new().unwrap();
+        create_qcow2_image(f.as_file(), 0x20000, None);
+        let path = f.as_path().to_str().unwrap().to_string();
+
+        // The capacity is the virtual size of the image, not the size of the file.
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            path.clone(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Sync,
+            2,
+            ImageFormat::Qcow2,
+            None,
+        )
+        .unwrap();
+        assert_eq!(block.image_format(), ImageFormat::Qcow2);
+        assert_eq!(block.file_engine_type(), FileEngineType::Sync);
+        assert_eq!(block.disk.nsectors(), 0x20000 >> SECTOR_SHIFT);
+        assert_eq!(block.disk.file_engines().len(), 2);
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+
+        // Write a sector of the second cluster, then read it back.
+        let request_header = RequestHeader::new(VIRTIO_BLK_T_OUT, 0x90);
+        mem.write_obj::<RequestHeader>(request_header, request_type_addr)
+            .unwrap();
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1].len.set(512);
+        mem.write_obj::<u64>(123_456_789, data_addr).unwrap();
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+        let request_header = RequestHeader::new(VIRTIO_BLK_T_IN, 0x90);
+        mem.write_obj::<RequestHeader>(request_header, request_type_addr)
+            .unwrap();
+        vq.dtable[1]
+            .flags
+            .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        mem.write_obj::<u64>(0, data_addr).unwrap();
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+        assert_eq!(mem.read_obj::<u64>(data_addr).unwrap(), 123_456_789);
+
+        // The backing file can be swapped with another qcow2 image.
+        let f2 = TempFile::new().unwrap();
+        create_qcow2_image(f2.as_file(), 0x30000, None);
+        block
+            .update_disk_image(f2.as_path().to_str().unwrap().to_string())
+            .unwrap();
+        assert_eq!(block.image_format(), ImageFormat::Qcow2);
+        assert_eq!(block.disk.nsectors(), 0x30000 >> SECTOR_SHIFT);
+
+        // qcow2 images are only served by the Sync engine.
+        assert!(matches!(
+            DiskProperties::new(
+                path,
+                false,
+                CacheType::Unsafe,
+                FileEngineType::Async,
+                DEFAULT_NUM_QUEUES,
+                ImageFormat::Qcow2,
+                None,
+            ),
+            Err(Error::UnsupportedImageFormat(
+                ImageFormat::Qcow2,
+                FileEngineType::Async
+            ))
+        ));
+
+        // Raw images are not mistaken for qcow2 ones.
+        let raw = TempFile::new().unwrap();
+        raw.as_file().set_len(0x1000).unwrap();
+        assert!(matches!(
+            DiskProperties::new(
+                raw.as_path().to_str().unwrap().to_string(),
+                false,
+                CacheType::Unsafe,
+                FileEngineType::Sync,
+                DEFAULT_NUM_QUEUES,
+                ImageFormat::Qcow2,
+                None,
+            ),
+            Err(Error::Qcow2Image(InvalidMagic))
+        ));
+    }
+
+    #[test]
+    fn test_cow_overlay() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x2000).unwrap();
+        let path = f.as_path().to_str().unwrap().to_string();
+        let overlay = TempFile::new().unwrap();
+        let overlay_path = overlay.as_path().to_str().unwrap().to_string();
+
+        // The guest can write to read-only disks with an overlay.
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            path.clone(),
+            true,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Sync,
+            DEFAULT_NUM_QUEUES,
+            ImageFormat::Raw,
+            Some(overlay_path.clone()),
+        )
+        .unwrap();
+        assert!(block.is_read_only());
+        assert_eq!(block.avail_features() & (1u64 << VIRTIO_BLK_F_RO), 0);
+        assert_ne!(
+            block.avail_features() & (1u64 << VIRTIO_BLK_F_WRITE_ZEROES),
+            0
+        );
+        assert_eq!(block.cow_overlay_path(), Some(overlay_path.clone()));
+        assert_eq!(block.file_engine_type(), FileEngineType::Sync);
+        assert_eq!(block.disk.nsectors(), 0x2000 >> SECTOR_SHIFT);
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+
+        let request_header = RequestHeader::new(VIRTIO_BLK_T_OUT, 9);
+        mem.write_obj::<RequestHeader>(request_header, request_type_addr)
+            .unwrap();
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1].len.set(512);
+        mem.write_obj::<u64>(123_456_789, data_addr).unwrap();
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+        // The data landed in the overlay, the backing file is left untouched.
+        assert_eq!(block.disk.cow_bitmap(), Some(vec![0b10]));
+        let mut buf = [0u8; 8];
+        f.as_file()
+            .read_exact_at(&mut buf, 9 * SECTOR_SIZE)
+            .unwrap();
+        assert_eq!(u64::from_le_bytes(buf), 0);
+        overlay
+            .as_file()
+            .read_exact_at(&mut buf, 9 * SECTOR_SIZE)
+            .unwrap();
+        assert_eq!(u64::from_le_bytes(buf), 123_456_789);
+
+        // Switching to a new overlay drops the data written so far.
+        let new_overlay = TempFile::new().unwrap();
+        let new_overlay_path = new_overlay.as_path().to_str().unwrap().to_string();
+        block.update_cow_overlay(new_overlay_path.clone()).unwrap();
+        assert_eq!(block.cow_overlay_path(), Some(new_overlay_path.clone()));
+        assert_eq!(block.disk.cow_bitmap(), Some(vec![0]));
+
+        // Updating the backing file keeps the overlay, but starts it over.
+        block.disk.set_cow_bitmap(vec![0b1]).unwrap();
+        block.update_disk_image(path.clone()).unwrap();
+        assert_eq!(block.cow_overlay_path(), Some(new_overlay_path));
+        assert_eq!(block.disk.cow_bitmap(), Some(vec![0]));
+        assert!(matches!(
+            block.disk.set_cow_bitmap(vec![0, 0]),
+            Err(Error::CowOverlay(_))
+        ));
+
+        // Overlays are only supported on read-only raw disks served by the Sync engine.
+        for (is_disk_read_only, file_engine_type, image_format) in [
+            (false, FileEngineType::Sync, ImageFormat::Raw),
+            (true, FileEngineType::Async, ImageFormat::Raw),
+            (true, FileEngineType::Sync, ImageFormat::Qcow2),
+        ]
+        .iter()
+        {
+            assert!(matches!(
+                DiskProperties::new(
+                    path.clone(),
+                    *is_disk_read_only,
+                    CacheType::Unsafe,
+                    *file_engine_type,
+                    DEFAULT_NUM_QUEUES,
+                    *image_format,
+                    Some(overlay_path.clone()),
+                ),
+                Err(Error::UnsupportedCowOverlay)
+            ));
+        }
+
+        // Disks without an overlay can't switch to one.
+        let mut block = default_block(FileEngineType::Sync);
+        assert!(matches!(
+            block.update_cow_overlay(overlay_path),
+            Err(Error::NoCowOverlay)
+        ));
+        assert!(matches!(
+            block.disk.set_cow_bitmap(vec![0]),
+            Err(Error::NoCowOverlay)
+        ));
+    }
+
     #[test]
     fn test_virtio_features() {
         let mut block = default_block(default_engine_type_for_kv());
 
         assert_eq!(block.device_type(), TYPE_BLOCK);
 
-        let features: u64 = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
+        let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_BLK_F_DISCARD)
+            | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
 
         assert_eq!(block.avail_features_by_page(0), features as u32);
         assert_eq!(block.avail_features_by_page(1), (features >> 32) as u32);
@@ -710,18 +1237,16 @@ pub(crate) mod tests {
     fn test_virtio_read_config() {
         let block = default_block(default_engine_type_for_kv());
 
-        let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
+        let mut actual_config_space = [0u8; 8];
         block.read_config(0, &mut actual_config_space);
         // This will read the number of sectors.
         // The block's backing file size is 0x1000, so there are 8 (4096/512) sectors.
         // The config space is little endian.
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; 8] = [0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
         assert_eq!(actual_config_space, expected_config_space);
 
         // Invalid read.
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
+        let expected_config_space: [u8; 8] = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
         actual_config_space = expected_config_space;
         block.read_config(CONFIG_SPACE_SIZE as u64 + 1, &mut actual_config_space);
 
@@ -733,11 +1258,10 @@ pub(crate) mod tests {
     fn test_virtio_write_config() {
         let mut block = default_block(default_engine_type_for_kv());
 
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; 8] = [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
         block.write_config(0, &expected_config_space);
 
-        let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
+        let mut actual_config_space = [0u8; 8];
         block.read_config(0, &mut actual_config_space);
         assert_eq!(actual_config_space, expected_config_space);
 
@@ -751,7 +1275,7 @@ pub(crate) mod tests {
 
         // Invalid write.
         let new_config_space = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
-        block.write_config(5, &new_config_space);
+        block.write_config(CONFIG_SPACE_SIZE as u64 - 5, &new_config_space);
         // Make sure nothing got written.
         block.read_config(0, &mut actual_config_space);
         assert_eq!(actual_config_space, expected_config_space);
@@ -1296,6 +1820,222 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_discard_write_zeroes() {
+        let mut block = default_block(default_engine_type_for_kv());
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+
+        let rand_data = utils::rand::rand_alphanumerics(0x1000).as_bytes().to_vec();
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1]
+            .len
+            .set(std::mem::size_of::<DiscardWriteZeroesSegment>() as u32);
+
+        // Discard the second and third sectors.
+        {
+            block.disk.file().seek(SeekFrom::Start(0)).unwrap();
+            block.disk.file().write_all(&rand_data).unwrap();
+
+            mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(1, 2, 0), data_addr)
+                .unwrap();
+
+            check_metric_after_block!(
+                &METRICS.block.discard_count,
+                1,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+            // Check that only the discarded range reads back as zeroes.
+            let mut buf = vec![0u8; 0x1000];
+            block.disk.file().seek(SeekFrom::Start(0)).unwrap();
+            block.disk.file().read_exact(&mut buf).unwrap();
+            assert_eq!(buf[..512], rand_data[..512]);
+            assert_eq!(buf[512..1536], [0u8; 1024]);
+            assert_eq!(buf[1536..], rand_data[1536..]);
+            // The disk size doesn't change.
+            assert_eq!(block.disk.file().metadata().unwrap().len(), 0x1000);
+        }
+
+        // Write zeroes over the whole disk, allowing the range to be unmapped.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+
+            mem.write_obj::<u32>(VIRTIO_BLK_T_WRITE_ZEROES, request_type_addr)
+                .unwrap();
+            mem.write_obj(
+                DiscardWriteZeroesSegment::new(0, 8, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+                data_addr,
+            )
+            .unwrap();
+
+            check_metric_after_block!(
+                &METRICS.block.write_zeroes_count,
+                1,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+            let mut buf = vec![0u8; 0x1000];
+            block.disk.file().seek(SeekFrom::Start(0)).unwrap();
+            block.disk.file().read_exact(&mut buf).unwrap();
+            assert_eq!(buf, vec![0u8; 0x1000]);
+            assert_eq!(block.disk.file().metadata().unwrap().len(), 0x1000);
+        }
+
+        // Discard a range that goes beyond the end of the disk.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+
+            mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(7, 2, 0), data_addr)
+                .unwrap();
+
+            simulate_queue_and_async_completion_events(&mut block, true);
+
+            // The descriptor should have been discarded.
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+        }
+    }
+
+    #[test]
+    fn test_read_only_features() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+
+        // Read only devices don't advertise discard and write zeroes support.
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            true,
+            false,
+            RateLimiter::default(),
+            default_engine_type_for_kv(),
+            DEFAULT_NUM_QUEUES,
+            ImageFormat::Raw,
+            None,
+        )
+        .unwrap();
+
+        assert_ne!(block.avail_features() & (1u64 << VIRTIO_BLK_F_RO), 0);
+        assert_eq!(block.avail_features() & (1u64 << VIRTIO_BLK_F_DISCARD), 0);
+        assert_eq!(
+            block.avail_features() & (1u64 << VIRTIO_BLK_F_WRITE_ZEROES),
+            0
+        );
+    }
+
+    #[test]
+    fn test_multiple_queues() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            default_engine_type_for_kv(),
+            2,
+            ImageFormat::Raw,
+            None,
+        )
+        .unwrap();
+
+        assert_ne!(block.avail_features() & (1u64 << VIRTIO_BLK_F_MQ), 0);
+        assert_eq!(block.queues().len(), 2);
+        assert_eq!(block.queue_events().len(), 2);
+        assert_eq!(block.disk.file_engines().len(), 2);
+        let config_space = ConfigSpace::from_slice(&block.config_space).unwrap();
+        assert_eq!({ config_space.num_queues }, 2);
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 1, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+
+        // Push a write request on the second queue.
+        mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, request_type_addr)
+            .unwrap();
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1].len.set(512);
+        mem.write_obj::<u64>(123_456_789, data_addr).unwrap();
+
+        block.queue_evts[1].write(1).unwrap();
+        block.process_queue_event(1);
+        if let FileEngine::Async(engine) = block.disk.file_engine_mut(1) {
+            // The request has been submitted to the IO engine of the second queue.
+            engine.drain(false).unwrap();
+            thread::sleep(Duration::from_millis(150));
+            block.process_async_completion_event(1);
+        }
+
+        assert!(block.irq_trigger.has_pending_irq(IrqType::Vring));
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().id, 0);
+        assert_eq!(vq.used.ring[0].get().len, 1);
+        assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+        // The data ended up in the shared backing file.
+        let mut buf = [0u8; 8];
+        block.disk.file().seek(SeekFrom::Start(0)).unwrap();
+        block.disk.file().read_exact(&mut buf).unwrap();
+        assert_eq!(u64::from_le_bytes(buf), 123_456_789);
+
+        // Zero queues or more than the supported maximum are rejected.
+        for num_queues in &[0, MAX_NUM_QUEUES + 1] {
+            assert!(matches!(
+                Block::new(
+                    "test".to_string(),
+                    None,
+                    CacheType::Unsafe,
+                    f.as_path().to_str().unwrap().to_string(),
+                    false,
+                    false,
+                    RateLimiter::default(),
+                    default_engine_type_for_kv(),
+                    *num_queues,
+                    ImageFormat::Raw,
+                    None,
+                ),
+                Err(Error::InvalidNumQueues(_))
+            ));
+        }
+    }
+
     #[test]
     fn test_get_device_id() {
         let mut block = default_block(default_engine_type_for_kv());
@@ -1433,25 +2173,25 @@ pub(crate) mod tests {
             // Run scenario that doesn't trigger FullSq Error: Add sq_size flush requests.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert_eq!(block.is_io_engine_throttled, false);
+            assert_eq!(block.is_io_engine_throttled[0], false);
             simulate_async_completion_event(&mut block, true);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES, &mem, &vq);
 
             // Run scenario that triggers FullSqError : Add sq_size + 10 flush requests.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES + 10);
             simulate_queue_event(&mut block, Some(false));
-            assert_eq!(block.is_io_engine_throttled, true);
+            assert_eq!(block.is_io_engine_throttled[0], true);
             // When the async_completion_event is triggered:
             // 1. sq_size requests should be processed processed.
             // 2. is_io_engine_throttled should be set back to false.
             // 3. process_queue() should be called again.
             simulate_async_completion_event(&mut block, true);
-            assert_eq!(block.is_io_engine_throttled, false);
+            assert_eq!(block.is_io_engine_throttled[0], false);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES, &mem, &vq);
             // check that process_queue() was called again resulting in the processing of the
             // remaining 10 ops.
             simulate_async_completion_event(&mut block, true);
-            assert_eq!(block.is_io_engine_throttled, false);
+            assert_eq!(block.is_io_engine_throttled[0], false);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES + 10, &mem, &vq);
         }
 
@@ -1467,20 +2207,20 @@ pub(crate) mod tests {
             // completion. Then try to push another entry.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert_eq!(block.is_io_engine_throttled, false);
+            assert_eq!(block.is_io_engine_throttled[0], false);
             thread::sleep(Duration::from_millis(150));
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert_eq!(block.is_io_engine_throttled, false);
+            assert_eq!(block.is_io_engine_throttled[0], false);
             thread::sleep(Duration::from_millis(150));
 
             add_flush_requests_batch(&mut block, &mem, &vq, 1);
             simulate_queue_event(&mut block, Some(false));
-            assert_eq!(block.is_io_engine_throttled, true);
+            assert_eq!(block.is_io_engine_throttled[0], true);
             assert_eq!(block.queues[0].len(&mem), 1);
 
             simulate_async_completion_event(&mut block, true);
-            assert_eq!(block.is_io_engine_throttled, false);
+            assert_eq!(block.is_io_engine_throttled[0], false);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES * 2, &mem, &vq);
         }
     }
@@ -0,0 +1,480 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Copy-on-write overlay for read-only disk images.
+//!
+//! The base image is never written: the chunks the guest writes to are redirected to a sparse
+//! overlay file of the same size and a bitmap records which chunks live in the overlay. The
+//! first write to a chunk which doesn't cover all of it copies the rest of the chunk from the
+//! base image.
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::result::Result;
+use std::sync::{Arc, Mutex};
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+/// Granularity of the overlay, in bytes.
+pub const COW_CHUNK_SIZE: u64 = 4096;
+// Upper bound of the buffer used to serve write zeroes requests.
+const MAX_ZEROES_BUF_LEN: u64 = 1 << 20;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to read or write disk data.
+    Data(io::Error),
+    /// Failed to flush the overlay to the host disk.
+    Flush(io::Error),
+    /// The bitmap doesn't match the size of the disk.
+    InvalidBitmap,
+    /// Failed to open or resize the overlay file.
+    Overlay(io::Error),
+    /// Failed to seek within the disk.
+    Seek(io::Error),
+    /// Failed to transfer data between the disk and the guest memory.
+    Transfer(GuestMemoryError),
+}
+
+fn to_io_error(err: Error) -> io::Error {
+    match err {
+        Error::Data(err) | Error::Flush(err) | Error::Overlay(err) | Error::Seek(err) => err,
+        err => io::Error::new(io::ErrorKind::Other, format!("{:?}", err)),
+    }
+}
+
+fn bitmap_len(size: u64) -> usize {
+    let num_chunks = (size + COW_CHUNK_SIZE - 1) / COW_CHUNK_SIZE;
+    ((num_chunks + 63) / 64) as usize
+}
+
+/// A read-only base image along with the overlay holding the data written on top of it.
+pub struct CowFile {
+    base: File,
+    overlay: File,
+    overlay_path: String,
+    size: u64,
+    // One bit per chunk of the disk, set once the chunk was written to the overlay.
+    bitmap: Vec<u64>,
+    // Virtual position used by the `Read`, `Write` and `Seek` implementations.
+    position: u64,
+}
+
+impl CowFile {
+    /// Redirects the writes to `base` to the file at `overlay_path`, which is created if it
+    /// doesn't exist. Any data already present in the overlay is ignored.
+    pub fn new(mut base: File, overlay_path: String) -> Result<CowFile, Error> {
+        let size = base.seek(SeekFrom::End(0)).map_err(Error::Seek)?;
+        let overlay = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&overlay_path)
+            .map_err(Error::Overlay)?;
+        // Growing the overlay doesn't allocate any blocks, the file only takes up space for the
+        // chunks written by the guest.
+        overlay.set_len(size).map_err(Error::Overlay)?;
+
+        Ok(CowFile {
+            base,
+            overlay,
+            overlay_path,
+            size,
+            bitmap: vec![0; bitmap_len(size)],
+            position: 0,
+        })
+    }
+
+    /// Size of the disk, which is the size of the base image.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Path of the overlay file.
+    pub fn overlay_path(&self) -> &String {
+        &self.overlay_path
+    }
+
+    /// Bitmap of the chunks which live in the overlay.
+    pub fn bitmap(&self) -> &[u64] {
+        &self.bitmap
+    }
+
+    /// Replaces the bitmap of the overlay, e.g. with one saved along with a snapshot.
+    pub fn set_bitmap(&mut self, bitmap: Vec<u64>) -> Result<(), Error> {
+        if bitmap.len() != self.bitmap.len() {
+            return Err(Error::InvalidBitmap);
+        }
+        self.bitmap = bitmap;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn overlay(&self) -> &File {
+        &self.overlay
+    }
+
+    pub fn sync_all(&self) -> Result<(), Error> {
+        self.overlay.sync_all().map_err(Error::Flush)
+    }
+
+    fn is_chunk_copied(&self, chunk: u64) -> bool {
+        self.bitmap[(chunk / 64) as usize] & (1 << (chunk % 64)) != 0
+    }
+
+    fn mark_chunk_copied(&mut self, chunk: u64) {
+        self.bitmap[(chunk / 64) as usize] |= 1 << (chunk % 64);
+    }
+
+    /// Reads from the disk at `offset`. Runs of chunks are read from the base image or from the
+    /// overlay, depending on where they live.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize, Error> {
+        if offset >= self.size {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len() as u64, self.size - offset);
+        let end = offset + len;
+
+        let mut pos = offset;
+        while pos < end {
+            let copied = self.is_chunk_copied(pos / COW_CHUNK_SIZE);
+            let mut run_end = cmp::min((pos / COW_CHUNK_SIZE + 1) * COW_CHUNK_SIZE, end);
+            while run_end < end && self.is_chunk_copied(run_end / COW_CHUNK_SIZE) == copied {
+                run_end = cmp::min(run_end + COW_CHUNK_SIZE, end);
+            }
+
+            let file = if copied { &self.overlay } else { &self.base };
+            file.read_exact_at(
+                &mut buf[(pos - offset) as usize..(run_end - offset) as usize],
+                pos,
+            )
+            .map_err(Error::Data)?;
+            pos = run_end;
+        }
+
+        Ok(len as usize)
+    }
+
+    /// Writes to the overlay at `offset`, copying up the chunks which are partially written for
+    /// the first time.
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<usize, Error> {
+        if offset >= self.size {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len() as u64, self.size - offset);
+        let end = offset + len;
+
+        let mut pos = offset;
+        while pos < end {
+            let chunk = pos / COW_CHUNK_SIZE;
+            let chunk_start = chunk * COW_CHUNK_SIZE;
+            let chunk_end = cmp::min(chunk_start + COW_CHUNK_SIZE, self.size);
+            let count = cmp::min(chunk_end, end) - pos;
+            let data = &buf[(pos - offset) as usize..(pos - offset + count) as usize];
+
+            if count < chunk_end - chunk_start && !self.is_chunk_copied(chunk) {
+                let mut chunk_data = vec![0u8; (chunk_end - chunk_start) as usize];
+                self.base
+                    .read_exact_at(&mut chunk_data, chunk_start)
+                    .map_err(Error::Data)?;
+                let start = (pos - chunk_start) as usize;
+                chunk_data[start..start + count as usize].copy_from_slice(data);
+                self.overlay
+                    .write_all_at(&chunk_data, chunk_start)
+                    .map_err(Error::Data)?;
+            } else {
+                self.overlay.write_all_at(data, pos).map_err(Error::Data)?;
+            }
+            // The chunk only counts as copied once its data made it to the overlay.
+            self.mark_chunk_copied(chunk);
+            pos += count;
+        }
+
+        Ok(len as usize)
+    }
+
+    /// Zeroes `len` bytes of the disk, starting at `offset`.
+    pub fn write_zeroes_at(&mut self, offset: u64, len: u64) -> Result<(), Error> {
+        let end = cmp::min(offset.saturating_add(len), self.size);
+        let zeroes = vec![0u8; cmp::min(end.saturating_sub(offset), MAX_ZEROES_BUF_LEN) as usize];
+
+        let mut pos = offset;
+        while pos < end {
+            let count = cmp::min(end - pos, zeroes.len() as u64) as usize;
+            pos += self.write_at(&zeroes[..count], pos)? as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Read for CowFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.read_at(buf, self.position).map_err(to_io_error)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Write for CowFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.write_at(buf, self.position).map_err(to_io_error)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.overlay.flush()
+    }
+}
+
+impl Seek for CowFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::End(delta) => (self.size, delta),
+            SeekFrom::Current(delta) => (self.position, delta),
+        };
+        let position = if delta >= 0 {
+            base.checked_add(delta as u64)
+        } else {
+            base.checked_sub(delta.unsigned_abs())
+        };
+        self.position = position.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.position)
+    }
+}
+
+/// Serves guest requests from a base image and its copy-on-write overlay. The overlay is shared
+/// by the engines of all the queues of a device, so that they agree on its bitmap.
+pub struct CowFileEngine {
+    cow_file: Arc<Mutex<CowFile>>,
+}
+
+impl CowFileEngine {
+    pub fn new(cow_file: Arc<Mutex<CowFile>>) -> CowFileEngine {
+        CowFileEngine { cow_file }
+    }
+
+    pub fn read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, Error> {
+        let mut cow_file = self.cow_file.lock().expect("Poisoned lock");
+        cow_file
+            .seek(SeekFrom::Start(offset))
+            .map_err(Error::Seek)?;
+        mem.read_from(addr, &mut *cow_file, count as usize)
+            .map(|count| count as u32)
+            .map_err(Error::Transfer)
+    }
+
+    pub fn write(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, Error> {
+        let mut cow_file = self.cow_file.lock().expect("Poisoned lock");
+        cow_file
+            .seek(SeekFrom::Start(offset))
+            .map_err(Error::Seek)?;
+        mem.write_to(addr, &mut *cow_file, count as usize)
+            .map(|count| count as u32)
+            .map_err(Error::Transfer)
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.cow_file.lock().expect("Poisoned lock").sync_all()
+    }
+
+    pub fn write_zeroes(&mut self, offset: u64, len: u64) -> Result<(), Error> {
+        self.cow_file
+            .lock()
+            .expect("Poisoned lock")
+            .write_zeroes_at(offset, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::tempfile::TempFile;
+
+    use super::*;
+
+    const BASE_LEN: u64 = 4 * COW_CHUNK_SIZE + 512;
+
+    fn new_cow_file() -> (TempFile, TempFile, CowFile) {
+        let base = TempFile::new().unwrap();
+        let pattern: Vec<u8> = (0..BASE_LEN).map(|i| (i % 251) as u8 + 1).collect();
+        base.as_file().write_all_at(&pattern, 0).unwrap();
+        let overlay = TempFile::new().unwrap();
+        overlay.as_file().set_len(0).unwrap();
+
+        let cow_file = CowFile::new(
+            File::open(base.as_path()).unwrap(),
+            overlay.as_path().to_str().unwrap().to_string(),
+        )
+        .unwrap();
+        (base, overlay, cow_file)
+    }
+
+    fn base_data(offset: u64, len: u64) -> Vec<u8> {
+        (offset..offset + len)
+            .map(|i| (i % 251) as u8 + 1)
+            .collect()
+    }
+
+    #[test]
+    fn test_new() {
+        let (_base, overlay, cow_file) = new_cow_file();
+        assert_eq!(cow_file.size(), BASE_LEN);
+        assert_eq!(
+            cow_file.overlay_path(),
+            &overlay.as_path().to_str().unwrap().to_string()
+        );
+        assert_eq!(overlay.as_file().metadata().unwrap().len(), BASE_LEN);
+        // 5 chunks fit in a single word.
+        assert_eq!(cow_file.bitmap(), &[0]);
+
+        // The overlay file is created if needed.
+        let base = TempFile::new().unwrap();
+        base.as_file().set_len(BASE_LEN).unwrap();
+        let overlay_path = format!("{}.cow", base.as_path().to_str().unwrap());
+        CowFile::new(base.as_file().try_clone().unwrap(), overlay_path.clone()).unwrap();
+        assert_eq!(std::fs::metadata(&overlay_path).unwrap().len(), BASE_LEN);
+        std::fs::remove_file(&overlay_path).unwrap();
+    }
+
+    #[test]
+    fn test_read_write() {
+        let (base, _overlay, mut cow_file) = new_cow_file();
+
+        let mut buf = vec![0u8; BASE_LEN as usize];
+        assert_eq!(cow_file.read_at(&mut buf, 0).unwrap(), BASE_LEN as usize);
+        assert_eq!(buf, base_data(0, BASE_LEN));
+
+        // A write to the middle of a chunk copies up the rest of it.
+        let data = vec![0xaa; 100];
+        assert_eq!(cow_file.write_at(&data, COW_CHUNK_SIZE + 10).unwrap(), 100);
+        assert_eq!(cow_file.bitmap(), &[0b10]);
+        let mut chunk = vec![0u8; COW_CHUNK_SIZE as usize];
+        cow_file
+            .overlay()
+            .read_exact_at(&mut chunk, COW_CHUNK_SIZE)
+            .unwrap();
+        let mut expected = base_data(COW_CHUNK_SIZE, COW_CHUNK_SIZE);
+        expected[10..110].copy_from_slice(&data);
+        assert_eq!(chunk, expected);
+
+        // A write spanning several chunks, ending in the middle of the last one.
+        let data = vec![0xbb; 2 * COW_CHUNK_SIZE as usize];
+        assert_eq!(
+            cow_file.write_at(&data, 2 * COW_CHUNK_SIZE).unwrap(),
+            data.len()
+        );
+        assert_eq!(cow_file.bitmap(), &[0b110]);
+        // The partial last chunk of the disk.
+        let data = vec![0xcc; 512];
+        assert_eq!(cow_file.write_at(&data, 4 * COW_CHUNK_SIZE).unwrap(), 512);
+        assert_eq!(cow_file.bitmap(), &[0b1_1110]);
+        // Writes beyond the end of the disk are truncated.
+        assert_eq!(cow_file.write_at(&data, BASE_LEN - 12).unwrap(), 12);
+        assert_eq!(cow_file.write_at(&data, BASE_LEN).unwrap(), 0);
+
+        // The base file is left untouched.
+        let mut base_buf = vec![0u8; BASE_LEN as usize];
+        base.as_file().read_exact_at(&mut base_buf, 0).unwrap();
+        assert_eq!(base_buf, base_data(0, BASE_LEN));
+
+        // Reads mix the base and the overlay.
+        let mut expected = base_data(0, BASE_LEN);
+        for (i, byte) in expected.iter_mut().enumerate() {
+            let i = i as u64;
+            if (COW_CHUNK_SIZE + 10..COW_CHUNK_SIZE + 110).contains(&i) {
+                *byte = 0xaa;
+            } else if (2 * COW_CHUNK_SIZE..4 * COW_CHUNK_SIZE).contains(&i) {
+                *byte = 0xbb;
+            } else if i >= 4 * COW_CHUNK_SIZE {
+                *byte = 0xcc;
+            }
+        }
+        assert_eq!(cow_file.read_at(&mut buf, 0).unwrap(), BASE_LEN as usize);
+        assert_eq!(buf, expected);
+        let mut buf = vec![0u8; 1000];
+        assert_eq!(
+            cow_file.read_at(&mut buf, COW_CHUNK_SIZE - 500).unwrap(),
+            1000
+        );
+        assert_eq!(
+            buf,
+            expected[(COW_CHUNK_SIZE - 500) as usize..(COW_CHUNK_SIZE + 500) as usize]
+        );
+        assert_eq!(cow_file.read_at(&mut buf, BASE_LEN - 10).unwrap(), 10);
+        assert_eq!(cow_file.read_at(&mut buf, BASE_LEN).unwrap(), 0);
+
+        // Through the `Read`, `Write` and `Seek` implementations.
+        cow_file.seek(SeekFrom::Start(10)).unwrap();
+        cow_file.write_all(&[0xdd; 20]).unwrap();
+        assert_eq!(cow_file.seek(SeekFrom::Current(-20)).unwrap(), 10);
+        let mut buf = [0u8; 20];
+        cow_file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0xdd; 20]);
+        assert_eq!(cow_file.seek(SeekFrom::End(0)).unwrap(), BASE_LEN);
+        assert!(cow_file
+            .seek(SeekFrom::Current(-(BASE_LEN as i64) - 1))
+            .is_err());
+        assert_eq!(cow_file.bitmap(), &[0b1_1111]);
+    }
+
+    #[test]
+    fn test_write_zeroes() {
+        let (_base, _overlay, mut cow_file) = new_cow_file();
+
+        cow_file.write_zeroes_at(100, 2 * COW_CHUNK_SIZE).unwrap();
+        assert_eq!(cow_file.bitmap(), &[0b111]);
+        let mut buf = vec![0u8; 3 * COW_CHUNK_SIZE as usize];
+        cow_file.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf[..100], base_data(0, 100)[..]);
+        assert!(buf[100..(2 * COW_CHUNK_SIZE + 100) as usize]
+            .iter()
+            .all(|&byte| byte == 0));
+        assert_eq!(
+            buf[(2 * COW_CHUNK_SIZE + 100) as usize..],
+            base_data(2 * COW_CHUNK_SIZE + 100, COW_CHUNK_SIZE - 100)[..]
+        );
+
+        // Zeroing past the end of the disk stops at its end.
+        cow_file.write_zeroes_at(BASE_LEN - 1, u64::MAX).unwrap();
+        cow_file.write_zeroes_at(BASE_LEN, 10).unwrap();
+        assert_eq!(cow_file.bitmap(), &[0b1_0111]);
+    }
+
+    #[test]
+    fn test_set_bitmap() {
+        let (_base, overlay, mut cow_file) = new_cow_file();
+        cow_file.write_at(&[0xaa; 512], COW_CHUNK_SIZE).unwrap();
+        let bitmap = cow_file.bitmap().to_vec();
+
+        // A new overlay on top of the same file picks up the data once given the bitmap back.
+        let base = cow_file.base.try_clone().unwrap();
+        let mut cow_file =
+            CowFile::new(base, overlay.as_path().to_str().unwrap().to_string()).unwrap();
+        let mut buf = [0u8; 512];
+        cow_file.read_at(&mut buf, COW_CHUNK_SIZE).unwrap();
+        assert_eq!(buf[..], base_data(COW_CHUNK_SIZE, 512)[..]);
+
+        assert!(matches!(
+            cow_file.set_bitmap(vec![0, 0]),
+            Err(Error::InvalidBitmap)
+        ));
+        cow_file.set_bitmap(bitmap).unwrap();
+        cow_file.read_at(&mut buf, COW_CHUNK_SIZE).unwrap();
+        assert_eq!(buf, [0xaa; 512]);
+    }
+}
@@ -2,6 +2,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod async_io;
+pub mod cow;
+pub mod nbd;
+pub mod qcow2;
 pub mod sync_io;
 
 use std::fs::File;
@@ -9,9 +12,17 @@ use std::fs::File;
 use vm_memory::{GuestAddress, GuestMemoryMmap};
 
 pub use self::async_io::AsyncFileEngine;
+pub use self::cow::CowFileEngine;
+pub use self::nbd::NbdFileEngine;
+pub use self::qcow2::Qcow2FileEngine;
 pub use self::sync_io::SyncFileEngine;
 use crate::virtio::block::device::FileEngineType;
 
+// Deallocates the range, which reads back as zeroes afterwards.
+const PUNCH_HOLE_MODE: u32 = (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u32;
+// Zeroes the range, while keeping it allocated.
+const ZERO_RANGE_MODE: u32 = (libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE) as u32;
+
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub struct UserDataOk<T> {
     pub user_data: T,
@@ -28,6 +39,9 @@ pub enum FileEngineOk<T> {
 pub enum Error {
     Sync(sync_io::Error),
     Async(async_io::Error),
+    Qcow2(qcow2::Error),
+    Cow(cow::Error),
+    Nbd(nbd::Error),
     UnsupportedEngine(FileEngineType),
     GetKernelVersion(utils::kernel_version::Error),
 }
@@ -51,6 +65,9 @@ pub enum FileEngine<T> {
     #[allow(unused)]
     Async(AsyncFileEngine<T>),
     Sync(SyncFileEngine),
+    Qcow2(Qcow2FileEngine),
+    Cow(CowFileEngine),
+    Nbd(NbdFileEngine),
 }
 
 impl<T> FileEngine<T> {
@@ -66,6 +83,8 @@ impl<T> FileEngine<T> {
                 AsyncFileEngine::from_file(file).map_err(Error::Async)?,
             )),
             FileEngineType::Sync => Ok(FileEngine::Sync(SyncFileEngine::from_file(file))),
+            // NBD exports aren't backed by a local file.
+            FileEngineType::Nbd => Err(Error::UnsupportedEngine(engine_type)),
         }
     }
 
@@ -74,6 +93,9 @@ impl<T> FileEngine<T> {
         match self {
             FileEngine::Async(engine) => engine.file(),
             FileEngine::Sync(engine) => engine.file(),
+            FileEngine::Qcow2(_) => panic!("qcow2 images are not exposed as raw files"),
+            FileEngine::Cow(_) => panic!("copy-on-write disks are not exposed as raw files"),
+            FileEngine::Nbd(_) => panic!("NBD exports are not backed by files"),
         }
     }
 
@@ -102,6 +124,27 @@ impl<T> FileEngine<T> {
                     error: Error::Sync(err),
                 }),
             },
+            FileEngine::Qcow2(engine) => match engine.read(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Qcow2(err),
+                }),
+            },
+            FileEngine::Cow(engine) => match engine.read(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Cow(err),
+                }),
+            },
+            FileEngine::Nbd(engine) => match engine.read(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Nbd(err),
+                }),
+            },
         }
     }
 
@@ -130,6 +173,27 @@ impl<T> FileEngine<T> {
                     error: Error::Sync(err),
                 }),
             },
+            FileEngine::Qcow2(engine) => match engine.write(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Qcow2(err),
+                }),
+            },
+            FileEngine::Cow(engine) => match engine.write(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Cow(err),
+                }),
+            },
+            FileEngine::Nbd(engine) => match engine.write(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Nbd(err),
+                }),
+            },
         }
     }
 
@@ -152,6 +216,130 @@ impl<T> FileEngine<T> {
                     error: Error::Sync(err),
                 }),
             },
+            FileEngine::Qcow2(engine) => match engine.flush() {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Qcow2(err),
+                }),
+            },
+            FileEngine::Cow(engine) => match engine.flush() {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Cow(err),
+                }),
+            },
+            FileEngine::Nbd(engine) => match engine.flush() {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Nbd(err),
+                }),
+            },
+        }
+    }
+
+    /// Deallocates `len` bytes of the backing file, starting at `offset`.
+    pub fn discard(
+        &mut self,
+        offset: u64,
+        len: u64,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        self.fallocate(PUNCH_HOLE_MODE, offset, len, user_data)
+    }
+
+    /// Zeroes `len` bytes of the backing file, starting at `offset`. If `unmap` is set, the
+    /// range is deallocated instead of being explicitly zeroed.
+    pub fn write_zeroes(
+        &mut self,
+        offset: u64,
+        len: u64,
+        unmap: bool,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        let mode = match unmap {
+            true => PUNCH_HOLE_MODE,
+            false => ZERO_RANGE_MODE,
+        };
+        self.fallocate(mode, offset, len, user_data)
+    }
+
+    fn fallocate(
+        &mut self,
+        mode: u32,
+        offset: u64,
+        len: u64,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        match self {
+            FileEngine::Async(engine) => {
+                match engine.push_fallocate(mode, offset, len, user_data) {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: Error::Async(err.error),
+                    }),
+                }
+            }
+            FileEngine::Sync(engine) => match engine.fallocate(mode, offset, len) {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Sync(err),
+                }),
+            },
+            // Freeing clusters in the middle of the image is not supported, so both discard and
+            // write zeroes requests are served by zeroing the range.
+            FileEngine::Qcow2(engine) => match engine.write_zeroes(offset, len) {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Qcow2(err),
+                }),
+            },
+            // Punching holes in the overlay would expose the base image again, so the range is
+            // zeroed in the overlay instead.
+            FileEngine::Cow(engine) => match engine.write_zeroes(offset, len) {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Cow(err),
+                }),
+            },
+            // Like for the other engines, discarded ranges read back as zeroes. The server is
+            // allowed to punch holes when zeroing them.
+            FileEngine::Nbd(engine) => {
+                match engine.write_zeroes(offset, len, mode == PUNCH_HOLE_MODE) {
+                    Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                        user_data,
+                        count: 0,
+                    })),
+                    Err(err) => Err(UserDataError {
+                        user_data,
+                        error: Error::Nbd(err),
+                    }),
+                }
+            }
         }
     }
 
@@ -159,6 +347,9 @@ impl<T> FileEngine<T> {
         match self {
             FileEngine::Async(engine) => engine.drain(discard).map_err(Error::Async),
             FileEngine::Sync(_engine) => Ok(()),
+            FileEngine::Qcow2(_engine) => Ok(()),
+            FileEngine::Cow(_engine) => Ok(()),
+            FileEngine::Nbd(_engine) => Ok(()),
         }
     }
 
@@ -166,20 +357,28 @@ impl<T> FileEngine<T> {
         match self {
             FileEngine::Async(engine) => engine.drain_and_flush(discard).map_err(Error::Async),
             FileEngine::Sync(engine) => engine.flush().map_err(Error::Sync),
+            FileEngine::Qcow2(engine) => engine.flush().map_err(Error::Qcow2),
+            FileEngine::Cow(engine) => engine.flush().map_err(Error::Cow),
+            FileEngine::Nbd(engine) => engine.flush().map_err(Error::Nbd),
         }
     }
 }
 
 #[cfg(test)]
 pub mod tests {
+    use std::io::Read;
     use std::os::unix::ffi::OsStrExt;
     use std::os::unix::io::FromRawFd;
+    use std::sync::{Arc, Mutex};
 
     use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
     use utils::tempfile::TempFile;
     use utils::{skip_if_io_uring_supported, skip_if_io_uring_unsupported};
     use vm_memory::{Bitmap, Bytes, GuestMemory};
 
+    use super::cow::CowFile;
+    use super::qcow2::tests::create_qcow2_image;
+    use super::qcow2::Qcow2File;
     use super::*;
     use crate::virtio::block::device::FileEngineType;
     use crate::virtio::block::request::PendingRequest;
@@ -275,6 +474,8 @@ pub mod tests {
         assert_err!(res, Error::Sync(sync_io::Error::Seek(_e)));
         let res = engine.flush(());
         assert_err!(res, Error::Sync(sync_io::Error::SyncAll(_e)));
+        let res = engine.discard(0, u64::from(FILE_LEN), ());
+        assert_err!(res, Error::Sync(sync_io::Error::Fallocate(_e)));
 
         // Create backing file.
         let file = TempFile::new().unwrap().into_file();
@@ -337,6 +538,20 @@ pub mod tests {
         mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
         assert_eq!(buf, data.as_slice());
 
+        // Discard
+        assert_sync_execution!(engine.discard(0, u64::from(FILE_LEN), ()), 0);
+        // Write zeroes with unmap
+        assert_sync_execution!(engine.write_zeroes(0, u64::from(FILE_LEN), true, ()), 0);
+        // Check data
+        assert_sync_execution!(
+            engine.read(0, &mem, GuestAddress(0), FILE_LEN, ()),
+            FILE_LEN
+        );
+        let mut buf = vec![0u8; FILE_LEN as usize];
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert_eq!(buf, vec![0u8; FILE_LEN as usize]);
+        assert_eq!(engine.file().metadata().unwrap().len(), u64::from(FILE_LEN));
+
         // Check other ops
         assert!(engine.flush(()).is_ok());
         assert!(engine.drain(true).is_ok());
@@ -403,6 +618,130 @@ pub mod tests {
         assert_queued!(engine.flush(()));
         assert_async_execution(&mem, &mut engine, 0);
 
+        // Discard
+        assert_queued!(engine.discard(0, u64::from(FILE_LEN), ()));
+        assert_async_execution(&mem, &mut engine, 0);
+        // Write zeroes with unmap
+        assert_queued!(engine.write_zeroes(0, u64::from(FILE_LEN), true, ()));
+        assert_async_execution(&mem, &mut engine, 0);
+        // Check data
+        let mem = create_mem();
+        mem.write(&data, GuestAddress(0)).unwrap();
+        assert_queued!(engine.read(0, &mem, addr, FILE_LEN, ()));
+        assert_async_execution(&mem, &mut engine, FILE_LEN as u32);
+        let mut buf = vec![0u8; FILE_LEN as usize];
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert_eq!(buf, vec![0u8; FILE_LEN as usize]);
+        assert_eq!(engine.file().metadata().unwrap().len(), u64::from(FILE_LEN));
+
+        assert!(engine.drain(true).is_ok());
+        assert!(engine.drain_and_flush(true).is_ok());
+    }
+
+    #[test]
+    fn test_qcow2() {
+        let tmp = TempFile::new().unwrap();
+        create_qcow2_image(tmp.as_file(), u64::from(FILE_LEN), None);
+        let file = tmp.as_file().try_clone().unwrap();
+        let image = Qcow2File::from_file(file, tmp.as_path()).unwrap();
+        let mut engine: FileEngine<()> =
+            FileEngine::Qcow2(Qcow2FileEngine::new(Arc::new(Mutex::new(image))));
+
+        let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
+            .as_bytes()
+            .to_vec();
+
+        // Offset write
+        let mem = create_mem();
+        let offset = 100;
+        let partial_len = 50;
+        let addr = GuestAddress(0);
+        mem.write(&data, addr).unwrap();
+        assert_sync_execution!(
+            engine.write(offset, &mem, addr, partial_len, ()),
+            partial_len as u32
+        );
+        // Offset read
+        let mem = create_mem();
+        assert_sync_execution!(
+            engine.read(offset, &mem, addr, partial_len, ()),
+            partial_len as u32
+        );
+        // Check data
+        let mut buf = vec![0u8; partial_len as usize];
+        mem.read_slice(&mut buf, addr).unwrap();
+        assert_eq!(buf, data[..partial_len as usize]);
+
+        // Accesses are truncated at the end of the disk.
+        mem.write(&data, addr).unwrap();
+        assert_sync_execution!(engine.write(0, &mem, addr, 2 * FILE_LEN, ()), FILE_LEN);
+        let mem = create_mem();
+        assert_sync_execution!(engine.read(0, &mem, addr, 2 * FILE_LEN, ()), FILE_LEN);
+        let mut buf = vec![0u8; FILE_LEN as usize];
+        mem.read_slice(&mut buf, addr).unwrap();
+        assert_eq!(buf, data.as_slice());
+
+        // Discard
+        assert_sync_execution!(engine.discard(0, 10, ()), 0);
+        // Write zeroes with unmap
+        assert_sync_execution!(engine.write_zeroes(20, 10, true, ()), 0);
+        // Check data
+        assert_sync_execution!(engine.read(0, &mem, addr, FILE_LEN, ()), FILE_LEN);
+        mem.read_slice(&mut buf, addr).unwrap();
+        assert_eq!(buf[..10], [0u8; 10]);
+        assert_eq!(buf[10..20], data[10..20]);
+        assert_eq!(buf[20..30], [0u8; 10]);
+        assert_eq!(buf[30..], data[30..]);
+
+        // Check other ops
+        assert!(engine.flush(()).is_ok());
+        assert!(engine.drain(true).is_ok());
+        assert!(engine.drain_and_flush(true).is_ok());
+    }
+
+    #[test]
+    fn test_cow() {
+        let base = TempFile::new().unwrap();
+        base.as_file().set_len(u64::from(FILE_LEN)).unwrap();
+        let overlay = TempFile::new().unwrap();
+        let file = base.as_file().try_clone().unwrap();
+        let cow_file = CowFile::new(file, overlay.as_path().to_str().unwrap().to_string()).unwrap();
+        let mut engine: FileEngine<()> =
+            FileEngine::Cow(CowFileEngine::new(Arc::new(Mutex::new(cow_file))));
+
+        let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
+            .as_bytes()
+            .to_vec();
+
+        // Write and read back the whole disk.
+        let mem = create_mem();
+        let addr = GuestAddress(0);
+        mem.write(&data, addr).unwrap();
+        assert_sync_execution!(engine.write(0, &mem, addr, FILE_LEN, ()), FILE_LEN);
+        let mem = create_mem();
+        assert_sync_execution!(engine.read(0, &mem, addr, FILE_LEN, ()), FILE_LEN);
+        let mut buf = vec![0u8; FILE_LEN as usize];
+        mem.read_slice(&mut buf, addr).unwrap();
+        assert_eq!(buf, data.as_slice());
+
+        // The writes only reached the overlay.
+        assert!(base.as_file().bytes().all(|byte| byte.unwrap() == 0));
+        let mut overlay_data = vec![0u8; FILE_LEN as usize];
+        overlay.as_file().read_exact(&mut overlay_data).unwrap();
+        assert_eq!(overlay_data, data);
+
+        // Discard and write zeroes both zero the range.
+        assert_sync_execution!(engine.discard(0, 10, ()), 0);
+        assert_sync_execution!(engine.write_zeroes(20, 10, true, ()), 0);
+        assert_sync_execution!(engine.read(0, &mem, addr, FILE_LEN, ()), FILE_LEN);
+        mem.read_slice(&mut buf, addr).unwrap();
+        assert_eq!(buf[..10], [0u8; 10]);
+        assert_eq!(buf[10..20], data[10..20]);
+        assert_eq!(buf[20..30], [0u8; 10]);
+        assert_eq!(buf[30..], data[30..]);
+
+        // Check other ops
+        assert!(engine.flush(()).is_ok());
         assert!(engine.drain(true).is_ok());
         assert!(engine.drain_and_flush(true).is_ok());
     }
@@ -0,0 +1,901 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Client for disks exported by a Network Block Device server.
+//!
+//! The client connects to the server over a unix socket or TCP, selects the export through the
+//! fixed newstyle handshake and then sends the guest requests one at a time, as simple NBD
+//! commands. When the connection breaks, it is re-established and the request is sent again, up
+//! to `MAX_RECONNECT_ATTEMPTS` times. Structured replies and the other protocol extensions are
+//! not negotiated.
+//!
+//! The requests are synchronous: the VMM thread waits for their replies, so a slow server stalls
+//! the whole microVM. Connecting, sending and receiving are bounded by timeouts, and a request,
+//! reconnections included, fails after `REQUEST_TIMEOUT`.
+//!
+//! The server is described by an NBD URI, either `nbd://<ip>[:<port>][/<export>]` or
+//! `nbd+unix:///[<export>]?socket=<path>`. Host names are not resolved, since the client also
+//! reconnects from the sandboxed VMM thread.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::result::Result;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use logger::warn;
+use utils::byte_order::{
+    read_be_u16, read_be_u32, read_be_u64, write_be_u16, write_be_u32, write_be_u64,
+};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+/// "NBDMAGIC", sent by the server at the start of the handshake.
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+/// "IHAVEOPT", the magic of the option haggling phase of the newstyle handshake.
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+// Handshake flags, sent by the server.
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+// Client flags, acknowledging the handshake flags.
+const FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const MAX_EXPORT_NAME_LEN: usize = 4096;
+// Size of the reply to `NBD_OPT_EXPORT_NAME`: the export size, the transmission flags and the
+// zeroes the server omits if `NBD_FLAG_NO_ZEROES` was negotiated.
+const EXPORT_REPLY_LEN: usize = 134;
+const EXPORT_REPLY_NO_ZEROES_LEN: usize = 10;
+
+// Transmission flags, describing the export.
+const FLAG_READ_ONLY: u16 = 1 << 1;
+const FLAG_SEND_FLUSH: u16 = 1 << 2;
+const FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_WRITE_ZEROES: u16 = 6;
+// Asks the server to write actual zeroes instead of punching a hole.
+const CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+const REQUEST_LEN: usize = 28;
+const REPLY_LEN: usize = 16;
+
+/// Default port of NBD servers.
+pub const DEFAULT_PORT: u16 = 10809;
+/// Largest amount of data transferred by a single command. Bigger guest requests are split.
+const MAX_DATA_LEN: u32 = 1 << 20;
+/// Largest range covered by a single write zeroes command.
+const MAX_ZEROES_LEN: u64 = 1 << 30;
+/// Number of times a request is retried on a new connection before failing.
+const MAX_RECONNECT_ATTEMPTS: u32 = 3;
+/// Bounds the time the VMM thread can hang connecting to an unreachable server.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Bounds the time the VMM thread can hang on an unresponsive server.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+/// Bounds the time spent on a request, including the reconnections.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to connect to the server.
+    Connect(io::Error),
+    /// The server failed to execute the command and replied with the given error.
+    Command(u32),
+    /// Failed to negotiate the export with the server.
+    Handshake(io::Error),
+    /// The server sent an invalid value during the handshake.
+    InvalidHandshake(&'static str),
+    /// The server sent an invalid reply.
+    InvalidReply(&'static str),
+    /// The server address is not a valid NBD URI.
+    InvalidUri(String),
+    /// Failed to send a request or to receive its reply.
+    Io(io::Error),
+    /// The export is read only, but the drive isn't.
+    ReadOnlyExport,
+    /// The size of the export changed across connections.
+    SizeChanged(u64, u64),
+    /// Failed to transfer data between the guest memory and the connection buffers.
+    Transfer(GuestMemoryError),
+}
+
+impl Error {
+    // Errors which may go away on a new connection.
+    fn is_connection_error(&self) -> bool {
+        matches!(
+            self,
+            Error::Connect(_)
+                | Error::Handshake(_)
+                | Error::InvalidHandshake(_)
+                | Error::InvalidReply(_)
+                | Error::Io(_)
+        )
+    }
+}
+
+/// Location of an NBD server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NbdAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// An export of an NBD server, as described by its URI.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NbdExport {
+    pub address: NbdAddress,
+    pub name: String,
+}
+
+impl NbdExport {
+    /// Parses `nbd://<ip>[:<port>][/<export>]` and `nbd+unix:///[<export>]?socket=<path>` URIs.
+    pub fn from_uri(uri: &str) -> Result<NbdExport, Error> {
+        let invalid_uri = || Error::InvalidUri(uri.to_string());
+
+        let (address, name) = if let Some(rest) = uri.strip_prefix("nbd+unix://") {
+            // The host part must be empty, the socket is given as a query parameter.
+            let (path, query) = rest.split_once('?').ok_or_else(invalid_uri)?;
+            let name = match path {
+                "" => "",
+                path => path.strip_prefix('/').ok_or_else(invalid_uri)?,
+            };
+            let socket = query.strip_prefix("socket=").ok_or_else(invalid_uri)?;
+            if socket.is_empty() {
+                return Err(invalid_uri());
+            }
+            (NbdAddress::Unix(PathBuf::from(socket)), name)
+        } else if let Some(rest) = uri.strip_prefix("nbd://") {
+            let (authority, name) = rest.split_once('/').unwrap_or((rest, ""));
+            let address = match authority.parse::<SocketAddr>() {
+                Ok(address) => address,
+                Err(_) => {
+                    let ip = authority.trim_start_matches('[').trim_end_matches(']');
+                    let ip = ip.parse::<IpAddr>().map_err(|_| invalid_uri())?;
+                    SocketAddr::new(ip, DEFAULT_PORT)
+                }
+            };
+            (NbdAddress::Tcp(address), name)
+        } else {
+            return Err(invalid_uri());
+        };
+
+        if name.len() > MAX_EXPORT_NAME_LEN {
+            return Err(invalid_uri());
+        }
+        Ok(NbdExport {
+            address,
+            name: name.to_string(),
+        })
+    }
+}
+
+enum NbdStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl NbdStream {
+    /// Connects to the server, waiting for at most `timeout`.
+    fn connect(address: &NbdAddress, timeout: Duration) -> io::Result<NbdStream> {
+        let stream = match address {
+            NbdAddress::Tcp(address) => {
+                let stream = TcpStream::connect_timeout(address, timeout)?;
+                // Requests are sent one at a time, don't let them wait for more data.
+                stream.set_nodelay(true)?;
+                NbdStream::Tcp(stream)
+            }
+            // Connecting to a unix socket doesn't wait for the server.
+            NbdAddress::Unix(path) => NbdStream::Unix(UnixStream::connect(path)?),
+        };
+        stream.set_timeout(timeout)?;
+        Ok(stream)
+    }
+
+    /// Bounds the time each read or write can take.
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        let timeout = Some(cmp::min(timeout, IO_TIMEOUT));
+        match self {
+            NbdStream::Tcp(stream) => stream
+                .set_read_timeout(timeout)
+                .and_then(|()| stream.set_write_timeout(timeout)),
+            NbdStream::Unix(stream) => stream
+                .set_read_timeout(timeout)
+                .and_then(|()| stream.set_write_timeout(timeout)),
+        }
+    }
+}
+
+impl Read for NbdStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            NbdStream::Tcp(stream) => stream.read(buf),
+            NbdStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for NbdStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            NbdStream::Tcp(stream) => stream.write(buf),
+            NbdStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            NbdStream::Tcp(stream) => stream.flush(),
+            NbdStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Negotiates `export_name` with the server through the fixed newstyle handshake and returns
+/// the export size and transmission flags.
+fn handshake(stream: &mut NbdStream, export_name: &str) -> Result<(u64, u16), Error> {
+    let mut greeting = [0u8; 18];
+    stream.read_exact(&mut greeting).map_err(Error::Handshake)?;
+    if read_be_u64(&greeting[0..8]) != NBD_MAGIC {
+        return Err(Error::InvalidHandshake("not an NBD server"));
+    }
+    if read_be_u64(&greeting[8..16]) != IHAVEOPT {
+        return Err(Error::InvalidHandshake(
+            "the server doesn't support newstyle",
+        ));
+    }
+    let handshake_flags = read_be_u16(&greeting[16..18]);
+    if handshake_flags & FLAG_FIXED_NEWSTYLE == 0 {
+        return Err(Error::InvalidHandshake(
+            "the server doesn't support fixed newstyle",
+        ));
+    }
+    let no_zeroes = handshake_flags & FLAG_NO_ZEROES != 0;
+
+    let mut client_flags = FLAG_C_FIXED_NEWSTYLE;
+    if no_zeroes {
+        client_flags |= FLAG_C_NO_ZEROES;
+    }
+    let mut option = vec![0u8; 20 + export_name.len()];
+    write_be_u32(&mut option[0..4], client_flags);
+    write_be_u64(&mut option[4..12], IHAVEOPT);
+    write_be_u32(&mut option[12..16], OPT_EXPORT_NAME);
+    write_be_u32(&mut option[16..20], export_name.len() as u32);
+    option[20..].copy_from_slice(export_name.as_bytes());
+    stream.write_all(&option).map_err(Error::Handshake)?;
+
+    // The server closes the connection if the export doesn't exist.
+    let mut reply = [0u8; EXPORT_REPLY_LEN];
+    let reply_len = match no_zeroes {
+        true => EXPORT_REPLY_NO_ZEROES_LEN,
+        false => EXPORT_REPLY_LEN,
+    };
+    stream
+        .read_exact(&mut reply[..reply_len])
+        .map_err(Error::Handshake)?;
+    Ok((read_be_u64(&reply[0..8]), read_be_u16(&reply[8..10])))
+}
+
+/// Connection to an NBD export.
+pub struct NbdClient {
+    export: NbdExport,
+    // `None` while disconnected. The connection is re-established by the next request.
+    stream: Option<NbdStream>,
+    size: u64,
+    transmission_flags: u16,
+    // Identifies the requests, so their replies can be checked.
+    next_handle: u64,
+}
+
+impl NbdClient {
+    /// Connects to the export described by `uri`, which must be writable unless `read_only`.
+    pub fn connect(uri: &str, read_only: bool) -> Result<NbdClient, Error> {
+        let export = NbdExport::from_uri(uri)?;
+        let mut stream =
+            NbdStream::connect(&export.address, CONNECT_TIMEOUT).map_err(Error::Connect)?;
+        let (size, transmission_flags) = handshake(&mut stream, &export.name)?;
+        if !read_only && transmission_flags & FLAG_READ_ONLY != 0 {
+            return Err(Error::ReadOnlyExport);
+        }
+
+        Ok(NbdClient {
+            export,
+            stream: Some(stream),
+            size,
+            transmission_flags,
+            next_handle: 0,
+        })
+    }
+
+    /// Size of the export, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Name of the export.
+    pub fn export_name(&self) -> &str {
+        &self.export.name
+    }
+
+    fn reconnect(&mut self, timeout: Duration) -> Result<NbdStream, Error> {
+        let mut stream =
+            NbdStream::connect(&self.export.address, cmp::min(timeout, CONNECT_TIMEOUT))
+                .map_err(Error::Connect)?;
+        let (size, transmission_flags) = handshake(&mut stream, &self.export.name)?;
+        // The guest was told about the size of the disk, it can't change under its feet.
+        if size != self.size {
+            return Err(Error::SizeChanged(self.size, size));
+        }
+        self.transmission_flags = transmission_flags;
+        Ok(stream)
+    }
+
+    /// Sends a request and waits for its reply, for at most `REQUEST_TIMEOUT`. The request is
+    /// sent again on a new connection if the current one breaks.
+    fn execute(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+        reply_data: &mut [u8],
+    ) -> Result<(), Error> {
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        let mut attempts = 0;
+        loop {
+            // Sockets reject null timeouts.
+            let timeout = cmp::max(
+                deadline.saturating_duration_since(Instant::now()),
+                Duration::from_millis(1),
+            );
+            match self.try_execute(timeout, command, flags, offset, len, data, reply_data) {
+                Err(err) if err.is_connection_error() => {
+                    // The next request reconnects if we run out of attempts.
+                    self.stream = None;
+                    if attempts == MAX_RECONNECT_ATTEMPTS || Instant::now() >= deadline {
+                        return Err(err);
+                    }
+                    warn!(
+                        "NBD request failed, retrying on a new connection: {:?}",
+                        err
+                    );
+                    attempts += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn try_execute(
+        &mut self,
+        timeout: Duration,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+        reply_data: &mut [u8],
+    ) -> Result<(), Error> {
+        match self.stream.as_ref() {
+            Some(stream) => stream.set_timeout(timeout).map_err(Error::Io)?,
+            None => self.stream = Some(self.reconnect(timeout)?),
+        }
+        self.next_handle = self.next_handle.wrapping_add(1);
+        let stream = self.stream.as_mut().expect("Missing NBD connection");
+        Self::transmit(
+            stream,
+            command,
+            flags,
+            self.next_handle,
+            offset,
+            len,
+            data,
+            reply_data,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn transmit(
+        stream: &mut NbdStream,
+        command: u16,
+        flags: u16,
+        handle: u64,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+        reply_data: &mut [u8],
+    ) -> Result<(), Error> {
+        let mut request = [0u8; REQUEST_LEN];
+        write_be_u32(&mut request[0..4], REQUEST_MAGIC);
+        write_be_u16(&mut request[4..6], flags);
+        write_be_u16(&mut request[6..8], command);
+        write_be_u64(&mut request[8..16], handle);
+        write_be_u64(&mut request[16..24], offset);
+        write_be_u32(&mut request[24..28], len);
+        stream.write_all(&request).map_err(Error::Io)?;
+        stream.write_all(data).map_err(Error::Io)?;
+
+        let mut reply = [0u8; REPLY_LEN];
+        stream.read_exact(&mut reply).map_err(Error::Io)?;
+        if read_be_u32(&reply[0..4]) != SIMPLE_REPLY_MAGIC {
+            return Err(Error::InvalidReply("invalid magic"));
+        }
+        if read_be_u64(&reply[8..16]) != handle {
+            return Err(Error::InvalidReply("unexpected handle"));
+        }
+        match read_be_u32(&reply[4..8]) {
+            // The data only follows successful replies.
+            0 => stream.read_exact(reply_data).map_err(Error::Io),
+            error => Err(Error::Command(error)),
+        }
+    }
+
+    /// Reads `buf.len()` bytes of the export, starting at `offset`.
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        self.execute(CMD_READ, 0, offset, buf.len() as u32, &[], buf)
+    }
+
+    /// Writes `buf` to the export, starting at `offset`.
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error> {
+        self.execute(CMD_WRITE, 0, offset, buf.len() as u32, buf, &mut [])
+    }
+
+    /// Makes the completed writes persistent, if the server supports it.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.transmission_flags & FLAG_SEND_FLUSH == 0 {
+            return Ok(());
+        }
+        self.execute(CMD_FLUSH, 0, 0, 0, &[], &mut [])
+    }
+
+    /// Zeroes `len` bytes starting at `offset`. If `unmap` is set, the server may punch a hole
+    /// instead. Servers that don't support the command are sent zeroed buffers instead.
+    pub fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> Result<(), Error> {
+        if self.transmission_flags & FLAG_SEND_WRITE_ZEROES != 0 {
+            let flags = match unmap {
+                true => 0,
+                false => CMD_FLAG_NO_HOLE,
+            };
+            let mut done = 0;
+            while done < len {
+                let chunk_len = cmp::min(len - done, MAX_ZEROES_LEN);
+                self.execute(
+                    CMD_WRITE_ZEROES,
+                    flags,
+                    offset + done,
+                    chunk_len as u32,
+                    &[],
+                    &mut [],
+                )?;
+                done += chunk_len;
+            }
+            return Ok(());
+        }
+
+        let zeroes = vec![0u8; cmp::min(len, u64::from(MAX_DATA_LEN)) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk_len = cmp::min(len - done, zeroes.len() as u64) as usize;
+            self.write_at(&zeroes[..chunk_len], offset + done)?;
+            done += chunk_len as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NbdClient {
+    fn drop(&mut self) {
+        // Let the server know we're done, it doesn't reply to this command.
+        if let Some(mut stream) = self.stream.take() {
+            let mut request = [0u8; REQUEST_LEN];
+            write_be_u32(&mut request[0..4], REQUEST_MAGIC);
+            write_be_u16(&mut request[6..8], CMD_DISC);
+            let _ = stream.write_all(&request);
+        }
+    }
+}
+
+pub struct NbdFileEngine {
+    client: Arc<Mutex<NbdClient>>,
+}
+
+impl NbdFileEngine {
+    pub fn new(client: Arc<Mutex<NbdClient>>) -> NbdFileEngine {
+        NbdFileEngine { client }
+    }
+
+    pub fn read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, Error> {
+        let mut client = self.client.lock().expect("Poisoned lock");
+        let mut buf = vec![0u8; cmp::min(count, MAX_DATA_LEN) as usize];
+        let mut done = 0;
+        while done < count {
+            let chunk = &mut buf[..cmp::min(count - done, MAX_DATA_LEN) as usize];
+            client.read_at(chunk, offset + u64::from(done))?;
+            let copied = mem
+                .write(chunk, addr.unchecked_add(u64::from(done)))
+                .map_err(Error::Transfer)? as u32;
+            done += copied;
+            // The buffer runs past the end of the guest memory.
+            if copied < chunk.len() as u32 {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    pub fn write(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, Error> {
+        let mut client = self.client.lock().expect("Poisoned lock");
+        let mut buf = vec![0u8; cmp::min(count, MAX_DATA_LEN) as usize];
+        let mut done = 0;
+        while done < count {
+            let chunk_len = cmp::min(count - done, MAX_DATA_LEN) as usize;
+            let copied = mem
+                .read(&mut buf[..chunk_len], addr.unchecked_add(u64::from(done)))
+                .map_err(Error::Transfer)?;
+            client.write_at(&buf[..copied], offset + u64::from(done))?;
+            done += copied as u32;
+            // The buffer runs past the end of the guest memory.
+            if copied < chunk_len {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.client.lock().expect("Poisoned lock").flush()
+    }
+
+    pub fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool) -> Result<(), Error> {
+        self.client
+            .lock()
+            .expect("Poisoned lock")
+            .write_zeroes(offset, len, unmap)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+
+    pub const TEST_EXPORT: &str = "test_export";
+    const ENOSPC: u32 = 28;
+
+    /// Minimal NBD server serving `disk` as `TEST_EXPORT`, on a unix socket.
+    pub struct NbdStubServer {
+        socket: TempFile,
+        pub disk: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl NbdStubServer {
+        /// Starts the server. It drops every connection after `requests_per_connection`
+        /// requests, without replying to the last one.
+        pub fn new(
+            disk_len: usize,
+            transmission_flags: u16,
+            requests_per_connection: Option<usize>,
+        ) -> NbdStubServer {
+            let mut socket = TempFile::new().unwrap();
+            socket.remove().unwrap();
+            let listener = UnixListener::bind(socket.as_path()).unwrap();
+            let disk = Arc::new(Mutex::new(vec![0u8; disk_len]));
+
+            let server_disk = disk.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    Self::serve(
+                        stream.unwrap(),
+                        &server_disk,
+                        transmission_flags,
+                        requests_per_connection,
+                    );
+                }
+            });
+            NbdStubServer { socket, disk }
+        }
+
+        pub fn uri(&self) -> String {
+            format!(
+                "nbd+unix:///{}?socket={}",
+                TEST_EXPORT,
+                self.socket.as_path().to_str().unwrap()
+            )
+        }
+
+        fn serve(
+            mut stream: UnixStream,
+            disk: &Mutex<Vec<u8>>,
+            transmission_flags: u16,
+            requests_per_connection: Option<usize>,
+        ) {
+            let mut greeting = [0u8; 18];
+            write_be_u64(&mut greeting[0..8], NBD_MAGIC);
+            write_be_u64(&mut greeting[8..16], IHAVEOPT);
+            write_be_u16(&mut greeting[16..18], FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES);
+            stream.write_all(&greeting).unwrap();
+
+            let mut option = [0u8; 20];
+            stream.read_exact(&mut option).unwrap();
+            assert_eq!(read_be_u64(&option[4..12]), IHAVEOPT);
+            assert_eq!(read_be_u32(&option[12..16]), OPT_EXPORT_NAME);
+            let mut name = vec![0u8; read_be_u32(&option[16..20]) as usize];
+            stream.read_exact(&mut name).unwrap();
+            if name != TEST_EXPORT.as_bytes() {
+                return;
+            }
+            // Only send the zeroes to clients which didn't acknowledge `NBD_FLAG_NO_ZEROES`.
+            let mut export = vec![0u8; EXPORT_REPLY_LEN];
+            write_be_u64(&mut export[0..8], disk.lock().unwrap().len() as u64);
+            write_be_u16(&mut export[8..10], transmission_flags | 1);
+            if read_be_u32(&option[0..4]) & FLAG_C_NO_ZEROES != 0 {
+                export.truncate(EXPORT_REPLY_NO_ZEROES_LEN);
+            }
+            stream.write_all(&export).unwrap();
+
+            let mut served = 0;
+            loop {
+                let mut request = [0u8; REQUEST_LEN];
+                if stream.read_exact(&mut request).is_err() {
+                    return;
+                }
+                assert_eq!(read_be_u32(&request[0..4]), REQUEST_MAGIC);
+                let command = read_be_u16(&request[6..8]);
+                let offset = read_be_u64(&request[16..24]) as usize;
+                let len = read_be_u32(&request[24..28]) as usize;
+                let mut data = vec![0u8; if command == CMD_WRITE { len } else { 0 }];
+                stream.read_exact(&mut data).unwrap();
+                if command == CMD_DISC {
+                    return;
+                }
+
+                served += 1;
+                if requests_per_connection == Some(served) {
+                    return;
+                }
+
+                let mut disk = disk.lock().unwrap();
+                let mut reply = vec![0u8; REPLY_LEN];
+                write_be_u32(&mut reply[0..4], SIMPLE_REPLY_MAGIC);
+                reply[8..16].copy_from_slice(&request[8..16]);
+                if offset + len > disk.len() {
+                    write_be_u32(&mut reply[4..8], ENOSPC);
+                } else {
+                    match command {
+                        CMD_READ => reply.extend_from_slice(&disk[offset..offset + len]),
+                        CMD_WRITE => disk[offset..offset + len].copy_from_slice(&data),
+                        CMD_WRITE_ZEROES => {
+                            disk[offset..offset + len].iter_mut().for_each(|b| *b = 0)
+                        }
+                        CMD_FLUSH => {}
+                        _ => panic!("Unexpected NBD command {}", command),
+                    }
+                }
+                stream.write_all(&reply).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_export_from_uri() {
+        assert_eq!(
+            NbdExport::from_uri("nbd+unix:///disk?socket=/tmp/nbd.sock").unwrap(),
+            NbdExport {
+                address: NbdAddress::Unix(PathBuf::from("/tmp/nbd.sock")),
+                name: "disk".to_string(),
+            }
+        );
+        assert_eq!(
+            NbdExport::from_uri("nbd+unix://?socket=/tmp/nbd.sock").unwrap(),
+            NbdExport {
+                address: NbdAddress::Unix(PathBuf::from("/tmp/nbd.sock")),
+                name: "".to_string(),
+            }
+        );
+        assert_eq!(
+            NbdExport::from_uri("nbd://10.0.0.1:1234/disk").unwrap(),
+            NbdExport {
+                address: NbdAddress::Tcp("10.0.0.1:1234".parse().unwrap()),
+                name: "disk".to_string(),
+            }
+        );
+        assert_eq!(
+            NbdExport::from_uri("nbd://[::1]").unwrap(),
+            NbdExport {
+                address: NbdAddress::Tcp(SocketAddr::new("::1".parse().unwrap(), DEFAULT_PORT)),
+                name: "".to_string(),
+            }
+        );
+
+        for uri in [
+            "/tmp/disk.img",
+            "nbd+unix:///disk",
+            "nbd+unix:///disk?socket=",
+            "nbd+unix://host/disk?socket=/tmp/nbd.sock",
+            "nbd://",
+            "nbd://localhost/disk",
+            "nbd://10.0.0.1:port",
+        ]
+        .iter()
+        {
+            assert!(matches!(
+                NbdExport::from_uri(uri),
+                Err(Error::InvalidUri(_))
+            ));
+        }
+        let long_name = format!("nbd://10.0.0.1/{}", "a".repeat(MAX_EXPORT_NAME_LEN + 1));
+        assert!(matches!(
+            NbdExport::from_uri(&long_name),
+            Err(Error::InvalidUri(_))
+        ));
+    }
+
+    #[test]
+    fn test_connect() {
+        let server = NbdStubServer::new(0x1000, FLAG_READ_ONLY, None);
+        let client = NbdClient::connect(&server.uri(), true).unwrap();
+        assert_eq!(client.size(), 0x1000);
+        assert_eq!(client.export_name(), TEST_EXPORT);
+
+        // The drive must be read only if the export is.
+        assert!(matches!(
+            NbdClient::connect(&server.uri(), false),
+            Err(Error::ReadOnlyExport)
+        ));
+
+        // The server drops the connection when asked for an unknown export.
+        let uri = server.uri().replace(TEST_EXPORT, "unknown");
+        assert!(matches!(
+            NbdClient::connect(&uri, true),
+            Err(Error::Handshake(_))
+        ));
+
+        let socket = TempFile::new().unwrap();
+        let uri = format!(
+            "nbd+unix:///{}?socket={}",
+            TEST_EXPORT,
+            socket.as_path().to_str().unwrap()
+        );
+        assert!(matches!(
+            NbdClient::connect(&uri, true),
+            Err(Error::Connect(_))
+        ));
+    }
+
+    #[test]
+    fn test_commands() {
+        let flags = FLAG_SEND_FLUSH | FLAG_SEND_WRITE_ZEROES;
+        let server = NbdStubServer::new(0x1000, flags, None);
+        let mut client = NbdClient::connect(&server.uri(), false).unwrap();
+
+        let data = utils::rand::rand_alphanumerics(0x100).as_bytes().to_vec();
+        client.write_at(&data, 0x200).unwrap();
+        assert_eq!(server.disk.lock().unwrap()[0x200..0x300], data[..]);
+        let mut buf = vec![0u8; 0x100];
+        client.read_at(&mut buf, 0x200).unwrap();
+        assert_eq!(buf, data);
+        client.flush().unwrap();
+
+        client.write_zeroes(0x200, 0x10, true).unwrap();
+        client.write_zeroes(0x2f0, 0x10, false).unwrap();
+        client.read_at(&mut buf, 0x200).unwrap();
+        assert_eq!(buf[..0x10], [0u8; 0x10]);
+        assert_eq!(buf[0x10..0xf0], data[0x10..0xf0]);
+        assert_eq!(buf[0xf0..], [0u8; 0x10]);
+
+        // Errors reported by the server don't break the connection.
+        assert!(matches!(
+            client.read_at(&mut buf, 0x1000),
+            Err(Error::Command(ENOSPC))
+        ));
+        client.read_at(&mut buf, 0x200).unwrap();
+
+        // Without server support, flushes are no-ops and zeroed buffers are written.
+        let server = NbdStubServer::new(0x1000, 0, None);
+        let mut client = NbdClient::connect(&server.uri(), false).unwrap();
+        server.disk.lock().unwrap().iter_mut().for_each(|b| *b = 1);
+        client.flush().unwrap();
+        client.write_zeroes(0x100, 0x100, true).unwrap();
+        let disk = server.disk.lock().unwrap();
+        assert!(disk[..0x100].iter().all(|b| *b == 1));
+        assert!(disk[0x100..0x200].iter().all(|b| *b == 0));
+        assert!(disk[0x200..].iter().all(|b| *b == 1));
+    }
+
+    #[test]
+    fn test_reconnect() {
+        // Every connection breaks on its second request, which has to be sent again.
+        let server = NbdStubServer::new(0x1000, 0, Some(2));
+        let mut client = NbdClient::connect(&server.uri(), false).unwrap();
+        for i in 0..8u8 {
+            client.write_at(&[i; 0x10], u64::from(i) * 0x10).unwrap();
+        }
+        let mut buf = vec![0u8; 0x80];
+        client.read_at(&mut buf, 0).unwrap();
+        for i in 0..8u8 {
+            assert_eq!(
+                buf[usize::from(i) * 0x10..(usize::from(i) + 1) * 0x10],
+                [i; 0x10]
+            );
+        }
+
+        // Requests fail once the attempts are exhausted.
+        let server = NbdStubServer::new(0x1000, 0, Some(1));
+        let mut client = NbdClient::connect(&server.uri(), false).unwrap();
+        assert!(matches!(client.read_at(&mut buf, 0), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        // The address isn't routable, so connecting either fails or times out.
+        let start = Instant::now();
+        assert!(matches!(
+            NbdClient::connect("nbd://10.255.255.1", true),
+            Err(Error::Connect(_))
+        ));
+        assert!(start.elapsed() < CONNECT_TIMEOUT + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_engine() {
+        let server = NbdStubServer::new(0x4000, FLAG_SEND_WRITE_ZEROES, None);
+        let client = NbdClient::connect(&server.uri(), false).unwrap();
+        let mut engine = NbdFileEngine::new(Arc::new(Mutex::new(client)));
+        let mem =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x2000)], false)
+                .unwrap();
+
+        let data = utils::rand::rand_alphanumerics(0x1000).as_bytes().to_vec();
+        mem.write_slice(&data, GuestAddress(0)).unwrap();
+        assert_eq!(
+            engine.write(0x100, &mem, GuestAddress(0), 0x1000).unwrap(),
+            0x1000
+        );
+        assert_eq!(server.disk.lock().unwrap()[0x100..0x1100], data[..]);
+
+        // Accesses are truncated at the end of the guest memory.
+        assert_eq!(
+            engine
+                .read(0x100, &mem, GuestAddress(0x1800), 0x1000)
+                .unwrap(),
+            0x800
+        );
+        let mut buf = vec![0u8; 0x800];
+        mem.read_slice(&mut buf, GuestAddress(0x1800)).unwrap();
+        assert_eq!(buf, data[..0x800]);
+
+        engine.write_zeroes(0x100, 0x10, true).unwrap();
+        engine.flush().unwrap();
+        assert!(server.disk.lock().unwrap()[0x100..0x110]
+            .iter()
+            .all(|b| *b == 0));
+        assert_eq!(server.disk.lock().unwrap()[0x110..0x120], data[0x10..0x20]);
+    }
+}
@@ -0,0 +1,1031 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reader/writer for qcow2 disk images.
+//!
+//! Only the subset of the format needed to serve guest IO is implemented: guest offsets are
+//! translated through the L1/L2 tables, clusters are allocated at the end of the image on their
+//! first write and clusters which were never written are read from the backing file, if any.
+//! Compressed clusters, encryption, clusters shared with internal snapshots and the v3
+//! incompatible features are not supported.
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::sync::{Arc, Mutex};
+
+use utils::byte_order::{read_be_u32, read_be_u64, write_be_u16, write_be_u64};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+/// The magic number at the start of every qcow2 image, "QFI\xfb".
+pub const QCOW_MAGIC: u32 = 0x5146_49fb;
+
+const V2_HEADER_LEN: usize = 72;
+const V3_HEADER_LEN: usize = 104;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+// Same limits as the ones enforced by QEMU.
+const MAX_L1_TABLE_LEN: u64 = 32 * 1024 * 1024;
+const MAX_REFCOUNT_TABLE_LEN: u64 = 8 * 1024 * 1024;
+const MAX_BACKING_FILE_NAME_LEN: u32 = 1023;
+const MAX_BACKING_CHAIN_DEPTH: usize = 16;
+// v2 images always use 16 bit refcounts, which is also the default for v3 images.
+const SUPPORTED_REFCOUNT_ORDER: u32 = 4;
+const REFCOUNT_LEN: u64 = 2;
+
+// Bits 9-55 of the L1 and L2 entries hold the host offset of the table or cluster.
+const TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+// Bits 9-63 of the refcount table entries hold the host offset of the refcount block.
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+// Set on L1 and L2 entries whose cluster has a refcount of exactly one, i.e. which can be
+// written in place.
+const COPIED_FLAG: u64 = 1 << 63;
+const COMPRESSED_FLAG: u64 = 1 << 62;
+// Set on the L2 entries of clusters which read as zeroes (v3 only).
+const ZERO_FLAG: u64 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The backing file chain is longer than `MAX_BACKING_CHAIN_DEPTH`.
+    BackingChainTooDeep,
+    /// The name of the backing file stored in the image is invalid.
+    BackingFileName,
+    /// Failed to open the backing file.
+    BackingFileOpen(PathBuf, io::Error),
+    /// The image contains compressed clusters.
+    CompressedCluster,
+    /// Failed to read or write guest data.
+    Data(io::Error),
+    /// The image is encrypted.
+    Encrypted,
+    /// Failed to flush the image to the host disk.
+    Flush(io::Error),
+    /// A field of the image header is invalid.
+    InvalidHeader(&'static str),
+    /// The file does not start with the qcow2 magic.
+    InvalidMagic,
+    /// Failed to read or write the image metadata.
+    Metadata(io::Error),
+    /// The refcount table is too small to describe newly allocated clusters.
+    RefcountTableFull,
+    /// Failed to seek within the image.
+    Seek(io::Error),
+    /// The cluster is shared with an internal snapshot, so it can't be written in place.
+    SharedCluster,
+    /// Failed to transfer data between the image and the guest memory.
+    Transfer(GuestMemoryError),
+    /// The image uses incompatible features we don't support.
+    UnsupportedFeatures(u64),
+    /// The image uses refcounts of a width we don't support.
+    UnsupportedRefcountOrder(u32),
+    /// The image version is neither 2 nor 3.
+    UnsupportedVersion(u32),
+}
+
+// Used by the `Read`, `Write` and `Seek` implementations, which have to return IO errors.
+fn to_io_error(err: Error) -> io::Error {
+    match err {
+        Error::Data(err) | Error::Metadata(err) => err,
+        other => io::Error::new(io::ErrorKind::Other, format!("{:?}", other)),
+    }
+}
+
+/// The fields of the qcow2 header we make use of.
+struct Header {
+    version: u32,
+    backing_file_offset: u64,
+    backing_file_size: u32,
+    cluster_bits: u32,
+    size: u64,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+}
+
+impl Header {
+    fn read_from(file: &File) -> Result<Header, Error> {
+        let mut buf = [0u8; V3_HEADER_LEN];
+        file.read_exact_at(&mut buf[..V2_HEADER_LEN], 0)
+            .map_err(Error::Metadata)?;
+        if read_be_u32(&buf[0..4]) != QCOW_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+
+        let version = read_be_u32(&buf[4..8]);
+        match version {
+            2 => {}
+            3 => {
+                file.read_exact_at(&mut buf[V2_HEADER_LEN..], V2_HEADER_LEN as u64)
+                    .map_err(Error::Metadata)?;
+                let incompatible_features = read_be_u64(&buf[72..80]);
+                if incompatible_features != 0 {
+                    return Err(Error::UnsupportedFeatures(incompatible_features));
+                }
+                let refcount_order = read_be_u32(&buf[96..100]);
+                if refcount_order != SUPPORTED_REFCOUNT_ORDER {
+                    return Err(Error::UnsupportedRefcountOrder(refcount_order));
+                }
+            }
+            _ => return Err(Error::UnsupportedVersion(version)),
+        }
+
+        if read_be_u32(&buf[32..36]) != 0 {
+            return Err(Error::Encrypted);
+        }
+
+        let header = Header {
+            version,
+            backing_file_offset: read_be_u64(&buf[8..16]),
+            backing_file_size: read_be_u32(&buf[16..20]),
+            cluster_bits: read_be_u32(&buf[20..24]),
+            size: read_be_u64(&buf[24..32]),
+            l1_size: read_be_u32(&buf[36..40]),
+            l1_table_offset: read_be_u64(&buf[40..48]),
+            refcount_table_offset: read_be_u64(&buf[48..56]),
+            refcount_table_clusters: read_be_u32(&buf[56..60]),
+        };
+        header.validate()?;
+        Ok(header)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.cluster_bits < MIN_CLUSTER_BITS || self.cluster_bits > MAX_CLUSTER_BITS {
+            return Err(Error::InvalidHeader("cluster_bits"));
+        }
+        let cluster_size = 1u64 << self.cluster_bits;
+        let l2_entries = cluster_size / 8;
+
+        // The L1 table has to cover the whole virtual disk.
+        let l1_entries_needed = div_round_up(self.size, cluster_size * l2_entries);
+        if u64::from(self.l1_size) < l1_entries_needed
+            || u64::from(self.l1_size) * 8 > MAX_L1_TABLE_LEN
+        {
+            return Err(Error::InvalidHeader("l1_size"));
+        }
+        if self.l1_table_offset % cluster_size != 0 {
+            return Err(Error::InvalidHeader("l1_table_offset"));
+        }
+
+        if self.refcount_table_clusters == 0
+            || u64::from(self.refcount_table_clusters) * cluster_size > MAX_REFCOUNT_TABLE_LEN
+        {
+            return Err(Error::InvalidHeader("refcount_table_clusters"));
+        }
+        if self.refcount_table_offset % cluster_size != 0 {
+            return Err(Error::InvalidHeader("refcount_table_offset"));
+        }
+
+        Ok(())
+    }
+}
+
+fn div_round_up(value: u64, divisor: u64) -> u64 {
+    value / divisor + u64::from(value % divisor != 0)
+}
+
+fn read_u64_at(file: &File, offset: u64) -> Result<u64, Error> {
+    let mut buf = [0u8; 8];
+    file.read_exact_at(&mut buf, offset)
+        .map_err(Error::Metadata)?;
+    Ok(read_be_u64(&buf))
+}
+
+fn write_u64_at(file: &File, offset: u64, value: u64) -> Result<(), Error> {
+    let mut buf = [0u8; 8];
+    write_be_u64(&mut buf, value);
+    file.write_all_at(&buf, offset).map_err(Error::Metadata)
+}
+
+fn read_table(file: &File, offset: u64, num_entries: usize) -> Result<Vec<u64>, Error> {
+    let mut buf = vec![0u8; num_entries * 8];
+    file.read_exact_at(&mut buf, offset)
+        .map_err(Error::Metadata)?;
+    Ok(buf.chunks_exact(8).map(read_be_u64).collect())
+}
+
+/// The image read for the guest clusters which were never written.
+enum BackingFile {
+    Raw(File),
+    Qcow2(Qcow2File),
+}
+
+impl BackingFile {
+    fn open(path: &Path, depth: usize) -> Result<BackingFile, Error> {
+        if depth > MAX_BACKING_CHAIN_DEPTH {
+            return Err(Error::BackingChainTooDeep);
+        }
+        // Backing files are never written to.
+        let file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(|err| Error::BackingFileOpen(path.to_path_buf(), err))?;
+
+        // The format of the backing file is not recorded in the image, so we probe it.
+        let mut magic = [0u8; 4];
+        match file.read_exact_at(&mut magic, 0) {
+            Ok(()) if read_be_u32(&magic) == QCOW_MAGIC => Ok(BackingFile::Qcow2(
+                Qcow2File::from_file_with_depth(file, path, depth)?,
+            )),
+            _ => Ok(BackingFile::Raw(file)),
+        }
+    }
+
+    // Fills `buf` with the data found at `offset`, the range past the end of the backing file
+    // reading as zeroes.
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        let count = match self {
+            BackingFile::Raw(file) => {
+                let mut count = 0;
+                while count < buf.len() {
+                    match file.read_at(&mut buf[count..], offset + count as u64) {
+                        Ok(0) => break,
+                        Ok(n) => count += n,
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                        Err(err) => return Err(Error::Data(err)),
+                    }
+                }
+                count
+            }
+            BackingFile::Qcow2(image) => image.read_at(buf, offset)?,
+        };
+        buf[count..].fill(0);
+        Ok(())
+    }
+}
+
+// Where the data of a guest cluster lives.
+enum Cluster {
+    Unallocated,
+    Zero,
+    Allocated(u64),
+}
+
+/// A qcow2 image, exposed as a seekable disk of `virtual_size()` bytes.
+pub struct Qcow2File {
+    file: File,
+    header: Header,
+    cluster_size: u64,
+    l1_table: Vec<u64>,
+    refcount_table: Vec<u64>,
+    backing_file: Option<Box<BackingFile>>,
+    // New clusters are always appended to the image.
+    next_cluster_offset: u64,
+    // Guest offset of the next read or write.
+    position: u64,
+}
+
+impl Qcow2File {
+    /// Parses the qcow2 image stored in `file`. `path` is used to find the backing file, when
+    /// it is given relatively to the image.
+    pub fn from_file(file: File, path: &Path) -> Result<Qcow2File, Error> {
+        Self::from_file_with_depth(file, path, 0)
+    }
+
+    fn from_file_with_depth(file: File, path: &Path, depth: usize) -> Result<Qcow2File, Error> {
+        let header = Header::read_from(&file)?;
+        let cluster_size = 1u64 << header.cluster_bits;
+
+        let l1_table = read_table(&file, header.l1_table_offset, header.l1_size as usize)?;
+        let refcount_table = read_table(
+            &file,
+            header.refcount_table_offset,
+            (u64::from(header.refcount_table_clusters) * cluster_size / 8) as usize,
+        )?;
+
+        let backing_file = match header.backing_file_offset {
+            0 => None,
+            offset => {
+                let backing_path = Self::read_backing_file_path(&file, &header, offset, path)?;
+                Some(Box::new(BackingFile::open(&backing_path, depth + 1)?))
+            }
+        };
+
+        let file_len = file.metadata().map_err(Error::Metadata)?.len();
+        Ok(Qcow2File {
+            file,
+            header,
+            cluster_size,
+            l1_table,
+            refcount_table,
+            backing_file,
+            next_cluster_offset: div_round_up(file_len, cluster_size) * cluster_size,
+            position: 0,
+        })
+    }
+
+    fn read_backing_file_path(
+        file: &File,
+        header: &Header,
+        offset: u64,
+        path: &Path,
+    ) -> Result<PathBuf, Error> {
+        if header.backing_file_size == 0 || header.backing_file_size > MAX_BACKING_FILE_NAME_LEN {
+            return Err(Error::BackingFileName);
+        }
+        let mut name = vec![0u8; header.backing_file_size as usize];
+        file.read_exact_at(&mut name, offset)
+            .map_err(Error::Metadata)?;
+        let name = PathBuf::from(String::from_utf8(name).map_err(|_| Error::BackingFileName)?);
+
+        // Relative backing file names are relative to the directory of the image.
+        match path.parent() {
+            Some(dir) if name.is_relative() => Ok(dir.join(name)),
+            _ => Ok(name),
+        }
+    }
+
+    /// The size of the disk exposed by the image.
+    pub fn virtual_size(&self) -> u64 {
+        self.header.size
+    }
+
+    /// Flushes the image to the host disk.
+    pub fn sync_all(&self) -> Result<(), Error> {
+        self.file.sync_all().map_err(Error::Flush)
+    }
+
+    #[cfg(test)]
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    fn l1_index(&self, guest_offset: u64) -> usize {
+        // Every L2 table holds `cluster_size / 8` entries.
+        (guest_offset >> (2 * self.header.cluster_bits - 3)) as usize
+    }
+
+    fn l2_index(&self, guest_offset: u64) -> u64 {
+        (guest_offset >> self.header.cluster_bits) & (self.cluster_size / 8 - 1)
+    }
+
+    fn cluster(&self, guest_offset: u64) -> Result<Cluster, Error> {
+        let l2_table = match self.l1_table.get(self.l1_index(guest_offset)) {
+            Some(l1_entry) => l1_entry & TABLE_OFFSET_MASK,
+            None => 0,
+        };
+        if l2_table == 0 {
+            return Ok(Cluster::Unallocated);
+        }
+
+        let l2_entry = read_u64_at(&self.file, l2_table + self.l2_index(guest_offset) * 8)?;
+        if l2_entry & COMPRESSED_FLAG != 0 {
+            return Err(Error::CompressedCluster);
+        }
+        if self.header.version >= 3 && l2_entry & ZERO_FLAG != 0 {
+            return Ok(Cluster::Zero);
+        }
+        match l2_entry & TABLE_OFFSET_MASK {
+            0 => Ok(Cluster::Unallocated),
+            host_offset => Ok(Cluster::Allocated(host_offset)),
+        }
+    }
+
+    /// Reads from the guest `offset` into `buf`, stopping at the end of the disk. Returns the
+    /// number of bytes read.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize, Error> {
+        let len = cmp::min(buf.len() as u64, self.virtual_size().saturating_sub(offset)) as usize;
+
+        let mut count = 0;
+        while count < len {
+            let guest_offset = offset + count as u64;
+            let offset_in_cluster = guest_offset % self.cluster_size;
+            let chunk_len = cmp::min(self.cluster_size - offset_in_cluster, (len - count) as u64);
+            let chunk = &mut buf[count..count + chunk_len as usize];
+
+            match self.cluster(guest_offset)? {
+                Cluster::Allocated(host_offset) => self
+                    .file
+                    .read_exact_at(chunk, host_offset + offset_in_cluster)
+                    .map_err(Error::Data)?,
+                Cluster::Zero => chunk.fill(0),
+                Cluster::Unallocated => self.read_backing_file(chunk, guest_offset)?,
+            }
+            count += chunk.len();
+        }
+        Ok(count)
+    }
+
+    fn read_backing_file(&self, buf: &mut [u8], guest_offset: u64) -> Result<(), Error> {
+        match &self.backing_file {
+            Some(backing_file) => backing_file.read_exact_at(buf, guest_offset),
+            None => {
+                buf.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes `buf` at the guest `offset`, allocating clusters as needed and stopping at the end
+    /// of the disk. Returns the number of bytes written.
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<usize, Error> {
+        let len = cmp::min(buf.len() as u64, self.virtual_size().saturating_sub(offset)) as usize;
+
+        let mut count = 0;
+        while count < len {
+            let guest_offset = offset + count as u64;
+            let offset_in_cluster = guest_offset % self.cluster_size;
+            let chunk_len = cmp::min(self.cluster_size - offset_in_cluster, (len - count) as u64);
+
+            self.write_cluster(guest_offset, &buf[count..count + chunk_len as usize])?;
+            count += chunk_len as usize;
+        }
+        Ok(count)
+    }
+
+    /// Zeroes `len` bytes starting at the guest `offset`. Clusters which already read as zeroes
+    /// are left untouched.
+    pub fn write_zeroes_at(&mut self, offset: u64, len: u64) -> Result<(), Error> {
+        let end = cmp::min(offset.saturating_add(len), self.virtual_size());
+        let zeroes = vec![0u8; self.cluster_size as usize];
+
+        let mut guest_offset = offset;
+        while guest_offset < end {
+            let offset_in_cluster = guest_offset % self.cluster_size;
+            let chunk_len = cmp::min(self.cluster_size - offset_in_cluster, end - guest_offset);
+
+            match self.cluster(guest_offset)? {
+                Cluster::Zero => {}
+                Cluster::Unallocated if self.backing_file.is_none() => {}
+                _ => self.write_cluster(guest_offset, &zeroes[..chunk_len as usize])?,
+            }
+            guest_offset += chunk_len;
+        }
+        Ok(())
+    }
+
+    // Writes `data`, which must not cross a cluster boundary, at the guest `offset`.
+    fn write_cluster(&mut self, guest_offset: u64, data: &[u8]) -> Result<(), Error> {
+        let offset_in_cluster = guest_offset % self.cluster_size;
+        let l2_entry_offset =
+            self.l2_table_for_write(guest_offset)? + self.l2_index(guest_offset) * 8;
+        let l2_entry = read_u64_at(&self.file, l2_entry_offset)?;
+        if l2_entry & COMPRESSED_FLAG != 0 {
+            return Err(Error::CompressedCluster);
+        }
+
+        let host_offset = l2_entry & TABLE_OFFSET_MASK;
+        let is_zero = self.header.version >= 3 && l2_entry & ZERO_FLAG != 0;
+        if host_offset != 0 && l2_entry & COPIED_FLAG == 0 {
+            return Err(Error::SharedCluster);
+        }
+        if host_offset != 0 && !is_zero {
+            return self
+                .file
+                .write_all_at(data, host_offset + offset_in_cluster)
+                .map_err(Error::Data);
+        }
+
+        // The cluster has to be filled in entirely before the L2 entry points at it, with the
+        // data of the backing file if the cluster was never written, or with zeroes otherwise.
+        let mut cluster = vec![0u8; self.cluster_size as usize];
+        if !is_zero && data.len() as u64 != self.cluster_size {
+            self.read_backing_file(&mut cluster, guest_offset - offset_in_cluster)?;
+        }
+        cluster[offset_in_cluster as usize..offset_in_cluster as usize + data.len()]
+            .copy_from_slice(data);
+
+        // Zero clusters may still have a preallocated host cluster, which we reuse.
+        let host_offset = match host_offset {
+            0 => self.allocate_cluster()?,
+            host_offset => host_offset,
+        };
+        self.file
+            .write_all_at(&cluster, host_offset)
+            .map_err(Error::Data)?;
+        write_u64_at(&self.file, l2_entry_offset, host_offset | COPIED_FLAG)
+    }
+
+    // Returns the host offset of the L2 table covering `guest_offset`, allocating it if needed.
+    fn l2_table_for_write(&mut self, guest_offset: u64) -> Result<u64, Error> {
+        let l1_index = self.l1_index(guest_offset);
+        let l1_entry = *self
+            .l1_table
+            .get(l1_index)
+            .ok_or(Error::InvalidHeader("l1_size"))?;
+
+        let l2_table = l1_entry & TABLE_OFFSET_MASK;
+        if l2_table != 0 {
+            if l1_entry & COPIED_FLAG == 0 {
+                return Err(Error::SharedCluster);
+            }
+            return Ok(l2_table);
+        }
+
+        // Freshly allocated clusters read as zeroes, which is an empty L2 table.
+        let l2_table = self.allocate_cluster()?;
+        let l1_entry = l2_table | COPIED_FLAG;
+        write_u64_at(
+            &self.file,
+            self.header.l1_table_offset + l1_index as u64 * 8,
+            l1_entry,
+        )?;
+        self.l1_table[l1_index] = l1_entry;
+        Ok(l2_table)
+    }
+
+    // Appends a zeroed cluster to the image and returns its host offset.
+    fn allocate_cluster(&mut self) -> Result<u64, Error> {
+        let host_offset = self.append_cluster()?;
+        self.set_refcount(host_offset, 1)?;
+        Ok(host_offset)
+    }
+
+    fn append_cluster(&mut self) -> Result<u64, Error> {
+        let host_offset = self.next_cluster_offset;
+        self.next_cluster_offset += self.cluster_size;
+        // Growing the file makes the new cluster read as zeroes.
+        self.file
+            .set_len(self.next_cluster_offset)
+            .map_err(Error::Metadata)?;
+        Ok(host_offset)
+    }
+
+    fn set_refcount(&mut self, host_offset: u64, refcount: u16) -> Result<(), Error> {
+        let refcounts_per_block = self.cluster_size / REFCOUNT_LEN;
+        let cluster_index = host_offset / self.cluster_size;
+        let table_index = (cluster_index / refcounts_per_block) as usize;
+
+        let table_entry = *self
+            .refcount_table
+            .get(table_index)
+            .ok_or(Error::RefcountTableFull)?;
+        let mut refcount_block = table_entry & REFCOUNT_TABLE_OFFSET_MASK;
+        if refcount_block == 0 {
+            refcount_block = self.append_cluster()?;
+            write_u64_at(
+                &self.file,
+                self.header.refcount_table_offset + table_index as u64 * 8,
+                refcount_block,
+            )?;
+            self.refcount_table[table_index] = refcount_block;
+            // The new refcount block accounts for itself, or in the worst case for a single
+            // other refcount block.
+            self.set_refcount(refcount_block, 1)?;
+        }
+
+        let mut buf = [0u8; REFCOUNT_LEN as usize];
+        write_be_u16(&mut buf, refcount);
+        self.file
+            .write_all_at(
+                &buf,
+                refcount_block + (cluster_index % refcounts_per_block) * REFCOUNT_LEN,
+            )
+            .map_err(Error::Metadata)
+    }
+}
+
+impl Read for Qcow2File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.read_at(buf, self.position).map_err(to_io_error)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Write for Qcow2File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.write_at(buf, self.position).map_err(to_io_error)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for Qcow2File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::End(delta) => (self.virtual_size(), delta),
+            SeekFrom::Current(delta) => (self.position, delta),
+        };
+        let position = if delta >= 0 {
+            base.checked_add(delta as u64)
+        } else {
+            base.checked_sub(delta.unsigned_abs())
+        };
+        self.position = position.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.position)
+    }
+}
+
+/// Serves guest requests from a qcow2 image. The image is shared by the engines of all the
+/// queues of a device, so that they agree on its metadata.
+pub struct Qcow2FileEngine {
+    image: Arc<Mutex<Qcow2File>>,
+}
+
+impl Qcow2FileEngine {
+    pub fn new(image: Arc<Mutex<Qcow2File>>) -> Qcow2FileEngine {
+        Qcow2FileEngine { image }
+    }
+
+    pub fn read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, Error> {
+        let mut image = self.image.lock().expect("Poisoned lock");
+        image.seek(SeekFrom::Start(offset)).map_err(Error::Seek)?;
+        mem.read_from(addr, &mut *image, count as usize)
+            .map(|count| count as u32)
+            .map_err(Error::Transfer)
+    }
+
+    pub fn write(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, Error> {
+        let mut image = self.image.lock().expect("Poisoned lock");
+        image.seek(SeekFrom::Start(offset)).map_err(Error::Seek)?;
+        mem.write_to(addr, &mut *image, count as usize)
+            .map(|count| count as u32)
+            .map_err(Error::Transfer)
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.image.lock().expect("Poisoned lock").sync_all()
+    }
+
+    pub fn write_zeroes(&mut self, offset: u64, len: u64) -> Result<(), Error> {
+        self.image
+            .lock()
+            .expect("Poisoned lock")
+            .write_zeroes_at(offset, len)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use utils::byte_order::write_be_u32;
+    use utils::tempfile::TempFile;
+
+    use super::*;
+
+    pub const TEST_CLUSTER_BITS: u32 = 16;
+    const TEST_CLUSTER_SIZE: u64 = 1 << TEST_CLUSTER_BITS;
+
+    /// Writes an empty v3 image of `size` bytes to `file`, on top of `backing_file` if given.
+    ///
+    /// The image is laid out like the ones created by `qemu-img`: the header is followed by the
+    /// refcount table, the first refcount block and the L1 table, each in its own cluster.
+    pub fn create_qcow2_image(file: &File, size: u64, backing_file: Option<&str>) {
+        let l2_coverage = TEST_CLUSTER_SIZE * (TEST_CLUSTER_SIZE / 8);
+        let l1_size = div_round_up(size, l2_coverage);
+        assert!(l1_size * 8 <= TEST_CLUSTER_SIZE);
+
+        let mut header = [0u8; V3_HEADER_LEN];
+        write_be_u32(&mut header[0..4], QCOW_MAGIC);
+        write_be_u32(&mut header[4..8], 3);
+        if let Some(name) = backing_file {
+            write_be_u64(&mut header[8..16], V3_HEADER_LEN as u64);
+            write_be_u32(&mut header[16..20], name.len() as u32);
+        }
+        write_be_u32(&mut header[20..24], TEST_CLUSTER_BITS);
+        write_be_u64(&mut header[24..32], size);
+        write_be_u32(&mut header[36..40], l1_size as u32);
+        write_be_u64(&mut header[40..48], 3 * TEST_CLUSTER_SIZE);
+        write_be_u64(&mut header[48..56], TEST_CLUSTER_SIZE);
+        write_be_u32(&mut header[56..60], 1);
+        write_be_u32(&mut header[96..100], SUPPORTED_REFCOUNT_ORDER);
+        write_be_u32(&mut header[100..104], V3_HEADER_LEN as u32);
+
+        file.set_len(4 * TEST_CLUSTER_SIZE).unwrap();
+        file.write_all_at(&header, 0).unwrap();
+        if let Some(name) = backing_file {
+            file.write_all_at(name.as_bytes(), V3_HEADER_LEN as u64)
+                .unwrap();
+        }
+        write_u64_at(file, TEST_CLUSTER_SIZE, 2 * TEST_CLUSTER_SIZE).unwrap();
+        for cluster_index in 0..4 {
+            let mut refcount = [0u8; 2];
+            write_be_u16(&mut refcount, 1);
+            file.write_all_at(&refcount, 2 * TEST_CLUSTER_SIZE + cluster_index * 2)
+                .unwrap();
+        }
+    }
+
+    fn new_image(size: u64, backing_file: Option<&str>) -> (TempFile, Qcow2File) {
+        let tmp = TempFile::new().unwrap();
+        create_qcow2_image(tmp.as_file(), size, backing_file);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(tmp.as_path())
+            .unwrap();
+        let image = Qcow2File::from_file(file, tmp.as_path()).unwrap();
+        (tmp, image)
+    }
+
+    fn refcount(image: &Qcow2File, host_offset: u64) -> u16 {
+        let cluster_index = host_offset / image.cluster_size;
+        let refcounts_per_block = image.cluster_size / REFCOUNT_LEN;
+        let block = image.refcount_table[(cluster_index / refcounts_per_block) as usize];
+        let mut buf = [0u8; 2];
+        image
+            .file
+            .read_exact_at(
+                &mut buf,
+                block + (cluster_index % refcounts_per_block) * REFCOUNT_LEN,
+            )
+            .unwrap();
+        u16::from_be_bytes(buf)
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let tmp = TempFile::new().unwrap();
+        tmp.as_file().set_len(4096).unwrap();
+        let file = tmp.as_file().try_clone().unwrap();
+        assert!(matches!(
+            Qcow2File::from_file(file, tmp.as_path()),
+            Err(Error::InvalidMagic)
+        ));
+
+        // Unsupported version.
+        create_qcow2_image(tmp.as_file(), 1 << 20, None);
+        let mut buf = [0u8; 4];
+        write_be_u32(&mut buf, 4);
+        tmp.as_file().write_all_at(&buf, 4).unwrap();
+        let file = tmp.as_file().try_clone().unwrap();
+        assert!(matches!(
+            Qcow2File::from_file(file, tmp.as_path()),
+            Err(Error::UnsupportedVersion(4))
+        ));
+
+        // Encryption.
+        create_qcow2_image(tmp.as_file(), 1 << 20, None);
+        write_be_u32(&mut buf, 1);
+        tmp.as_file().write_all_at(&buf, 32).unwrap();
+        let file = tmp.as_file().try_clone().unwrap();
+        assert!(matches!(
+            Qcow2File::from_file(file, tmp.as_path()),
+            Err(Error::Encrypted)
+        ));
+
+        // Dirty bit set.
+        create_qcow2_image(tmp.as_file(), 1 << 20, None);
+        tmp.as_file().write_all_at(&[1], 79).unwrap();
+        let file = tmp.as_file().try_clone().unwrap();
+        assert!(matches!(
+            Qcow2File::from_file(file, tmp.as_path()),
+            Err(Error::UnsupportedFeatures(1))
+        ));
+
+        // Invalid cluster size.
+        create_qcow2_image(tmp.as_file(), 1 << 20, None);
+        write_be_u32(&mut buf, 22);
+        tmp.as_file().write_all_at(&buf, 20).unwrap();
+        let file = tmp.as_file().try_clone().unwrap();
+        assert!(matches!(
+            Qcow2File::from_file(file, tmp.as_path()),
+            Err(Error::InvalidHeader("cluster_bits"))
+        ));
+
+        // The L1 table doesn't cover the whole disk.
+        create_qcow2_image(tmp.as_file(), 1 << 20, None);
+        write_be_u32(&mut buf, 0);
+        tmp.as_file().write_all_at(&buf, 36).unwrap();
+        let file = tmp.as_file().try_clone().unwrap();
+        assert!(matches!(
+            Qcow2File::from_file(file, tmp.as_path()),
+            Err(Error::InvalidHeader("l1_size"))
+        ));
+
+        // Missing backing file.
+        create_qcow2_image(tmp.as_file(), 1 << 20, Some("/nonexistent"));
+        let file = tmp.as_file().try_clone().unwrap();
+        assert!(matches!(
+            Qcow2File::from_file(file, tmp.as_path()),
+            Err(Error::BackingFileOpen(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_read_write() {
+        let size = 4 * TEST_CLUSTER_SIZE * (TEST_CLUSTER_SIZE / 8);
+        let (tmp, mut image) = new_image(size, None);
+        assert_eq!(image.virtual_size(), size);
+        let initial_len = image.file().metadata().unwrap().len();
+
+        // Unallocated clusters read as zeroes.
+        let mut buf = vec![0xffu8; 3 * TEST_CLUSTER_SIZE as usize];
+        assert_eq!(image.read_at(&mut buf, 100).unwrap(), buf.len());
+        assert!(buf.iter().all(|&b| b == 0));
+        assert_eq!(image.file().metadata().unwrap().len(), initial_len);
+
+        // A write crossing an L2 table boundary allocates two L2 tables and two data clusters.
+        let data = utils::rand::rand_alphanumerics(TEST_CLUSTER_SIZE as usize)
+            .as_bytes()
+            .to_vec();
+        let l2_coverage = TEST_CLUSTER_SIZE * (TEST_CLUSTER_SIZE / 8);
+        let offset = l2_coverage - 100;
+        assert_eq!(image.write_at(&data, offset).unwrap(), data.len());
+        assert_eq!(
+            image.file().metadata().unwrap().len(),
+            initial_len + 4 * TEST_CLUSTER_SIZE
+        );
+        assert_ne!(image.l1_table[0] & TABLE_OFFSET_MASK, 0);
+        assert_ne!(image.l1_table[1] & TABLE_OFFSET_MASK, 0);
+        assert_eq!(image.l1_table[2], 0);
+
+        let mut buf = vec![0u8; data.len() + 200];
+        assert_eq!(image.read_at(&mut buf, offset - 100).unwrap(), buf.len());
+        assert!(buf[..100].iter().all(|&b| b == 0));
+        assert_eq!(&buf[100..100 + data.len()], data.as_slice());
+        assert!(buf[100 + data.len()..].iter().all(|&b| b == 0));
+
+        // Rewriting allocated clusters doesn't allocate anything.
+        assert_eq!(image.write_at(&data[..10], offset).unwrap(), 10);
+        assert_eq!(
+            image.file().metadata().unwrap().len(),
+            initial_len + 4 * TEST_CLUSTER_SIZE
+        );
+
+        // Every allocated cluster is accounted for.
+        for cluster_index in 0..8 {
+            assert_eq!(refcount(&image, cluster_index * TEST_CLUSTER_SIZE), 1);
+        }
+
+        // Accesses are truncated at the end of the disk.
+        assert_eq!(image.write_at(&data, size - 10).unwrap(), 10);
+        assert_eq!(image.read_at(&mut buf, size - 10).unwrap(), 10);
+        assert_eq!(&buf[..10], &data[..10]);
+        assert_eq!(image.read_at(&mut buf, size).unwrap(), 0);
+
+        // The metadata is persisted in the image.
+        let file = image.file().try_clone().unwrap();
+        let reopened = Qcow2File::from_file(file, tmp.as_path()).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(reopened.read_at(&mut buf, offset).unwrap(), buf.len());
+        assert_eq!(buf, data);
+
+        // Through the IO traits.
+        image.seek(SeekFrom::Start(offset)).unwrap();
+        let mut buf = vec![0u8; 10];
+        image.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, &data[..10]);
+        assert_eq!(image.seek(SeekFrom::Current(0)).unwrap(), offset + 10);
+        image.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(image.seek(SeekFrom::End(-5)).unwrap(), size - 5);
+        assert!(image.seek(SeekFrom::Current(-(size as i64))).is_err());
+    }
+
+    #[test]
+    fn test_refcount_block_allocation() {
+        // Refcount blocks of 64 KiB clusters account for 2 GiB of host clusters, so we fake a
+        // larger image by moving the allocation point past the first refcount block.
+        let (_tmp, mut image) = new_image(1 << 30, None);
+        let refcounts_per_block = TEST_CLUSTER_SIZE / REFCOUNT_LEN;
+        image.next_cluster_offset = refcounts_per_block * TEST_CLUSTER_SIZE;
+
+        assert_eq!(image.write_at(&[1], 0).unwrap(), 1);
+        // Accounting for the L2 table required a new refcount block, which was allocated right
+        // after it, followed by the data cluster.
+        let l2_table = refcounts_per_block * TEST_CLUSTER_SIZE;
+        let refcount_block = l2_table + TEST_CLUSTER_SIZE;
+        assert_eq!(image.l1_table[0] & TABLE_OFFSET_MASK, l2_table);
+        assert_eq!(image.refcount_table[1], refcount_block);
+        assert_eq!(
+            read_u64_at(&image.file, TEST_CLUSTER_SIZE + 8).unwrap(),
+            refcount_block
+        );
+        assert_eq!(refcount(&image, l2_table), 1);
+        assert_eq!(refcount(&image, refcount_block), 1);
+        assert_eq!(refcount(&image, refcount_block + TEST_CLUSTER_SIZE), 1);
+    }
+
+    #[test]
+    fn test_backing_file() {
+        // Raw backing file, smaller than the image.
+        let base = TempFile::new().unwrap();
+        let base_data = utils::rand::rand_alphanumerics(3 * TEST_CLUSTER_SIZE as usize / 2)
+            .as_bytes()
+            .to_vec();
+        base.as_file().write_all_at(&base_data, 0).unwrap();
+
+        // The middle layer overrides a part of the base.
+        let middle = TempFile::new().unwrap();
+        create_qcow2_image(
+            middle.as_file(),
+            4 * TEST_CLUSTER_SIZE,
+            Some(base.as_path().to_str().unwrap()),
+        );
+        {
+            let file = middle.as_file().try_clone().unwrap();
+            let mut image = Qcow2File::from_file(file, middle.as_path()).unwrap();
+            image.write_at(&[0xaa; 100], 1000).unwrap();
+        }
+
+        // The top layer refers to the middle one relatively to its own directory.
+        let top = TempFile::new().unwrap();
+        assert_eq!(middle.as_path().parent(), top.as_path().parent());
+        create_qcow2_image(
+            top.as_file(),
+            4 * TEST_CLUSTER_SIZE,
+            Some(middle.as_path().file_name().unwrap().to_str().unwrap()),
+        );
+        let file = top.as_file().try_clone().unwrap();
+        let mut image = Qcow2File::from_file(file, top.as_path()).unwrap();
+
+        let mut expected = vec![0u8; 4 * TEST_CLUSTER_SIZE as usize];
+        expected[..base_data.len()].copy_from_slice(&base_data);
+        expected[1000..1100].fill(0xaa);
+        let mut buf = vec![0u8; expected.len()];
+        assert_eq!(image.read_at(&mut buf, 0).unwrap(), buf.len());
+        assert_eq!(buf, expected);
+
+        // Partial writes copy the rest of the cluster from the backing chain.
+        image.write_at(&[0xbb; 10], 2000).unwrap();
+        expected[2000..2010].fill(0xbb);
+        image.write_at(&[0xcc; 10], TEST_CLUSTER_SIZE + 10).unwrap();
+        expected[TEST_CLUSTER_SIZE as usize + 10..TEST_CLUSTER_SIZE as usize + 20].fill(0xcc);
+        assert_eq!(image.read_at(&mut buf, 0).unwrap(), buf.len());
+        assert_eq!(buf, expected);
+        assert!(matches!(image.cluster(0).unwrap(), Cluster::Allocated(_)));
+        assert!(matches!(
+            image.cluster(2 * TEST_CLUSTER_SIZE).unwrap(),
+            Cluster::Unallocated
+        ));
+
+        // Zeroing has to mask the backing chain, but skips the clusters already reading as zero.
+        let len = image.file().metadata().unwrap().len();
+        image.write_zeroes_at(500, 3 * TEST_CLUSTER_SIZE).unwrap();
+        expected[500..500 + 3 * TEST_CLUSTER_SIZE as usize].fill(0);
+        assert_eq!(image.read_at(&mut buf, 0).unwrap(), buf.len());
+        assert_eq!(buf, expected);
+        // Clusters 2 and 3 had to be allocated to mask the backing chain.
+        assert_eq!(
+            image.file().metadata().unwrap().len(),
+            len + 2 * TEST_CLUSTER_SIZE
+        );
+
+        // The chain can't be longer than `MAX_BACKING_CHAIN_DEPTH`.
+        let loop_image = TempFile::new().unwrap();
+        create_qcow2_image(
+            loop_image.as_file(),
+            TEST_CLUSTER_SIZE,
+            Some(loop_image.as_path().to_str().unwrap()),
+        );
+        let file = loop_image.as_file().try_clone().unwrap();
+        assert!(matches!(
+            Qcow2File::from_file(file, loop_image.as_path()),
+            Err(Error::BackingChainTooDeep)
+        ));
+    }
+
+    #[test]
+    fn test_unsupported_clusters() {
+        let (_tmp, mut image) = new_image(1 << 20, None);
+        image.write_at(&[1], 0).unwrap();
+        image.write_at(&[1], TEST_CLUSTER_SIZE).unwrap();
+        let l2_table = image.l1_table[0] & TABLE_OFFSET_MASK;
+
+        // Compressed clusters.
+        let l2_entry = read_u64_at(&image.file, l2_table).unwrap();
+        write_u64_at(&image.file, l2_table, l2_entry | COMPRESSED_FLAG).unwrap();
+        assert!(matches!(
+            image.read_at(&mut [0u8; 1], 0),
+            Err(Error::CompressedCluster)
+        ));
+        assert!(matches!(
+            image.write_at(&[0u8; 1], 0),
+            Err(Error::CompressedCluster)
+        ));
+
+        // Clusters shared with a snapshot.
+        let l2_entry = read_u64_at(&image.file, l2_table + 8).unwrap();
+        write_u64_at(&image.file, l2_table + 8, l2_entry & !COPIED_FLAG).unwrap();
+        assert_eq!(image.read_at(&mut [0u8; 1], TEST_CLUSTER_SIZE).unwrap(), 1);
+        assert!(matches!(
+            image.write_at(&[0u8; 1], TEST_CLUSTER_SIZE),
+            Err(Error::SharedCluster)
+        ));
+
+        // Zero clusters with a preallocated host cluster are reused.
+        write_u64_at(&image.file, l2_table + 8, l2_entry | ZERO_FLAG).unwrap();
+        let mut buf = [0xffu8; 2];
+        assert_eq!(image.read_at(&mut buf, TEST_CLUSTER_SIZE).unwrap(), 2);
+        assert_eq!(buf, [0, 0]);
+        let len = image.file().metadata().unwrap().len();
+        image.write_at(&[2], TEST_CLUSTER_SIZE + 1).unwrap();
+        assert_eq!(image.file().metadata().unwrap().len(), len);
+        assert_eq!(image.read_at(&mut buf, TEST_CLUSTER_SIZE).unwrap(), 2);
+        assert_eq!(buf, [0, 2]);
+        assert_eq!(
+            read_u64_at(&image.file, l2_table + 8).unwrap(),
+            l2_entry | COPIED_FLAG
+        );
+    }
+}
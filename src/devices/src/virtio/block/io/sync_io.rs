@@ -3,12 +3,15 @@
 
 use std::fs::File;
 use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 use std::result::Result;
 
+use utils::syscall::SyscallReturnCode;
 use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
 #[derive(Debug)]
 pub enum Error {
+    Fallocate(std::io::Error),
     Flush(std::io::Error),
     Seek(std::io::Error),
     SyncAll(std::io::Error),
@@ -67,4 +70,18 @@ impl SyncFileEngine {
         // Sync data out to physical media on host.
         self.file.sync_all().map_err(Error::SyncAll)
     }
+
+    pub fn fallocate(&mut self, mode: u32, offset: u64, len: u64) -> Result<(), Error> {
+        // Safe because the file descriptor is valid and we check the return value.
+        SyscallReturnCode(unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                mode as libc::c_int,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        })
+        .into_empty_result()
+        .map_err(Error::Fallocate)
+    }
 }
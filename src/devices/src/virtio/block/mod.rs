@@ -14,12 +14,13 @@ pub use self::device::{Block, CacheType};
 pub use self::event_handler::*;
 pub use self::request::*;
 
-pub const CONFIG_SPACE_SIZE: usize = 8;
+pub const CONFIG_SPACE_SIZE: usize = std::mem::size_of::<device::ConfigSpace>();
 pub const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01_u64) << SECTOR_SHIFT;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 1;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+pub const DEFAULT_NUM_QUEUES: usize = 1;
+// Every queue is backed by its own IO engine instance, so we keep the number of queues bounded.
+pub const MAX_NUM_QUEUES: usize = 16;
 // The virtio queue can hold up to 256 descriptors, but 1 request spreads across 2-3 descriptors.
 // So we can use 128 IO_URING entries without ever triggering a FullSq Error.
 pub const IO_URING_NUM_ENTRIES: u16 = 128;
@@ -36,16 +37,33 @@ pub enum Error {
     GuestMemory(GuestMemoryError),
     /// The data length is invalid.
     InvalidDataLength,
+    /// The number of queues is either zero or greater than `MAX_NUM_QUEUES`.
+    InvalidNumQueues(usize),
+    /// Guest gave us a discard or write zeroes segment with unsupported flags.
+    InvalidFlags,
     /// The requested operation would cause a seek beyond disk end.
     InvalidOffset,
     /// Guest gave us a read only descriptor that protocol says to write to.
     UnexpectedReadOnlyDescriptor,
     /// Guest gave us a write only descriptor that protocol says to read from.
     UnexpectedWriteOnlyDescriptor,
+    /// The block device doesn't have a copy-on-write overlay.
+    NoCowOverlay,
+    /// Copy-on-write overlays are only supported on top of read-only raw images, using the
+    /// Sync engine.
+    UnsupportedCowOverlay,
+    /// The IO engine can't serve images of the given format.
+    UnsupportedImageFormat(device::ImageFormat, device::FileEngineType),
     // Error coming from the IO engine.
     FileEngine(io::Error),
     // Error manipulating the backing file.
     BackingFile(std::io::Error),
+    // Error parsing the qcow2 backing file.
+    Qcow2Image(io::qcow2::Error),
+    // Error setting up the copy-on-write overlay.
+    CowOverlay(io::cow::Error),
+    // Error connecting to the NBD export.
+    NbdExport(io::nbd::Error),
     // Error opening eventfd.
     EventFd(std::io::Error),
     // Error creating an irqfd.
@@ -17,7 +17,7 @@ use virtio_gen::virtio_blk::VIRTIO_BLK_F_RO;
 use vm_memory::GuestMemoryMmap;
 
 use super::*;
-use crate::virtio::block::device::FileEngineType;
+use crate::virtio::block::device::{FileEngineType, ImageFormat};
 use crate::virtio::persist::VirtioDeviceState;
 use crate::virtio::{DeviceState, TYPE_BLOCK};
 
@@ -51,6 +51,17 @@ impl From<CacheTypeState> for CacheType {
 pub enum FileEngineTypeState {
     Sync,
     Async,
+    #[version(start = 2, default_fn = "default_nbd")]
+    Nbd,
+}
+
+impl FileEngineTypeState {
+    fn default_nbd(&self, _target_version: u16) -> VersionizeResult<FileEngineTypeState> {
+        // Older versions would open the URI of the export as a local file.
+        Err(VersionizeError::Semantic(
+            "Target version does not support NBD block devices.".to_owned(),
+        ))
+    }
 }
 
 impl From<FileEngineType> for FileEngineTypeState {
@@ -58,6 +69,7 @@ impl From<FileEngineType> for FileEngineTypeState {
         match file_engine_type {
             FileEngineType::Sync => FileEngineTypeState::Sync,
             FileEngineType::Async => FileEngineTypeState::Async,
+            FileEngineType::Nbd => FileEngineTypeState::Nbd,
         }
     }
 }
@@ -67,6 +79,7 @@ impl From<FileEngineTypeState> for FileEngineType {
         match file_engine_type_state {
             FileEngineTypeState::Sync => FileEngineType::Sync,
             FileEngineTypeState::Async => FileEngineType::Async,
+            FileEngineTypeState::Nbd => FileEngineType::Nbd,
         }
     }
 }
@@ -79,6 +92,45 @@ impl Default for FileEngineTypeState {
     }
 }
 
+#[derive(Clone, Copy, Debug, Versionize, PartialEq)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum ImageFormatState {
+    Raw,
+    Qcow2,
+}
+
+impl From<ImageFormat> for ImageFormatState {
+    fn from(image_format: ImageFormat) -> Self {
+        match image_format {
+            ImageFormat::Raw => ImageFormatState::Raw,
+            ImageFormat::Qcow2 => ImageFormatState::Qcow2,
+        }
+    }
+}
+
+impl From<ImageFormatState> for ImageFormat {
+    fn from(image_format_state: ImageFormatState) -> Self {
+        match image_format_state {
+            ImageFormatState::Raw => ImageFormat::Raw,
+            ImageFormatState::Qcow2 => ImageFormat::Qcow2,
+        }
+    }
+}
+
+impl Default for ImageFormatState {
+    fn default() -> Self {
+        // Snapshots which don't contain the image format were taken on raw images.
+        ImageFormatState::Raw
+    }
+}
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct CowOverlayState {
+    path: String,
+    bitmap: Vec<u64>,
+}
+
 #[derive(Clone, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct BlockState {
@@ -99,6 +151,14 @@ pub struct BlockState {
     // v1.0 are incompatible with older FC versions (due to incompatible notification suppression
     // feature).
     file_engine_type: FileEngineTypeState,
+    #[version(
+        start = 4,
+        ser_fn = "image_format_ser",
+        default_fn = "default_image_format"
+    )]
+    image_format: ImageFormatState,
+    #[version(start = 4, ser_fn = "cow_overlay_ser")]
+    cow_overlay: Option<CowOverlayState>,
 }
 
 impl BlockState {
@@ -116,6 +176,32 @@ impl BlockState {
     fn default_cache_type_flush(_source_version: u16) -> CacheTypeState {
         CacheTypeState::Unsafe
     }
+
+    fn image_format_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would open the image as a raw disk.
+        if target_version < 4 && self.image_format != ImageFormatState::Raw {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support qcow2 block devices.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_image_format(_source_version: u16) -> ImageFormatState {
+        ImageFormatState::Raw
+    }
+
+    fn cow_overlay_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would expose the backing file without the data written by the guest.
+        if target_version < 4 && self.cow_overlay.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support copy-on-write block devices.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 pub struct BlockConstructorArgs {
@@ -138,6 +224,13 @@ impl Persist<'_> for Block {
             virtio_state: VirtioDeviceState::from_device(self),
             rate_limiter_state: self.rate_limiter.save(),
             file_engine_type: FileEngineTypeState::from(self.file_engine_type()),
+            image_format: ImageFormatState::from(self.image_format()),
+            cow_overlay: self.cow_overlay_path().map(|path| CowOverlayState {
+                path,
+                // The bitmap is only saved once the device was drained, so it matches the
+                // contents of the overlay.
+                bitmap: self.disk.cow_bitmap().unwrap_or_default(),
+            }),
         }
     }
 
@@ -145,9 +238,20 @@ impl Persist<'_> for Block {
         constructor_args: Self::ConstructorArgs,
         state: &Self::State,
     ) -> Result<Self, Self::Error> {
-        let is_disk_read_only = state.virtio_state.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0;
+        // Disks with a copy-on-write overlay don't advertise `VIRTIO_BLK_F_RO`, but their backing
+        // file is still read only.
+        let is_disk_read_only = state.virtio_state.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0
+            || state.cow_overlay.is_some();
+        let cow_overlay_path = state
+            .cow_overlay
+            .as_ref()
+            .map(|cow_overlay| cow_overlay.path.clone());
         let rate_limiter =
             RateLimiter::restore((), &state.rate_limiter_state).map_err(Error::RateLimiter)?;
+        // Every queue is saved along with the virtio state, so there is no need to store
+        // their number separately. This also keeps single queue snapshots compatible with
+        // older versions.
+        let num_queues = state.virtio_state.queues.len();
 
         let mut block = Block::new(
             state.id.clone(),
@@ -158,6 +262,9 @@ impl Persist<'_> for Block {
             state.root_device,
             rate_limiter,
             state.file_engine_type.into(),
+            num_queues,
+            state.image_format.into(),
+            cow_overlay_path.clone(),
         )
         .or_else(|err| match err {
             Error::FileEngine(io::Error::UnsupportedEngine(FileEngineType::Async)) => {
@@ -179,14 +286,21 @@ impl Persist<'_> for Block {
                     state.root_device,
                     rate_limiter,
                     FileEngineType::Sync,
+                    num_queues,
+                    state.image_format.into(),
+                    cow_overlay_path,
                 )
             }
             other_err => Err(other_err),
         })?;
 
+        if let Some(cow_overlay) = &state.cow_overlay {
+            block.disk.set_cow_bitmap(cow_overlay.bitmap.clone())?;
+        }
+
         block.queues = state
             .virtio_state
-            .build_queues_checked(&constructor_args.mem, TYPE_BLOCK, NUM_QUEUES, QUEUE_SIZE)
+            .build_queues_checked(&constructor_args.mem, TYPE_BLOCK, num_queues, QUEUE_SIZE)
             .map_err(Error::Persist)?;
         block.irq_trigger.irq_status =
             Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
@@ -208,6 +322,8 @@ mod tests {
     use utils::tempfile::TempFile;
 
     use super::*;
+    use crate::virtio::block::io::nbd::tests::NbdStubServer;
+    use crate::virtio::block::io::qcow2::tests::create_qcow2_image;
     use crate::virtio::device::VirtioDevice;
     use crate::virtio::test_utils::default_mem;
 
@@ -257,6 +373,9 @@ mod tests {
             false,
             RateLimiter::default(),
             FileEngineType::default(),
+            DEFAULT_NUM_QUEUES,
+            ImageFormat::Raw,
+            None,
         )
         .unwrap();
 
@@ -311,6 +430,9 @@ mod tests {
                 // Need to use Sync because it will otherwise return an error.
                 // We'll overwrite the state instead.
                 FileEngineType::Sync,
+                DEFAULT_NUM_QUEUES,
+                ImageFormat::Raw,
+                None,
             )
             .unwrap();
 
@@ -354,6 +476,9 @@ mod tests {
             false,
             RateLimiter::default(),
             FileEngineType::default(),
+            DEFAULT_NUM_QUEUES,
+            ImageFormat::Raw,
+            None,
         )
         .unwrap();
         let guest_mem = default_mem();
@@ -387,4 +512,209 @@ mod tests {
         // Test that block specific fields are the same.
         assert_eq!(restored_block.disk.file_path(), block.disk.file_path());
     }
+
+    #[test]
+    fn test_persistence_multiple_queues() {
+        // We create the backing file here so that it exists for the whole lifetime of the test.
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::default(),
+            4,
+            ImageFormat::Raw,
+            None,
+        )
+        .unwrap();
+
+        // Save the block device.
+        let mut mem = vec![0; 4096];
+        let version_map = VersionMap::new();
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+
+        // Restore the block device.
+        let restored_block = Block::restore(
+            BlockConstructorArgs { mem: default_mem() },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+        )
+        .unwrap();
+
+        // Every queue gets its own IO engine and queue event after restore.
+        assert_eq!(restored_block.num_queues(), 4);
+        assert_eq!(restored_block.queues(), block.queues());
+        assert_eq!(restored_block.queue_events().len(), 4);
+        assert_eq!(restored_block.disk.file_engines().len(), 4);
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+    }
+
+    #[test]
+    fn test_persistence_qcow2() {
+        let f = TempFile::new().unwrap();
+        create_qcow2_image(f.as_file(), 0x10000, None);
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Sync,
+            DEFAULT_NUM_QUEUES,
+            ImageFormat::Qcow2,
+            None,
+        )
+        .unwrap();
+
+        // Test conversions between ImageFormat and ImageFormatState.
+        assert_eq!(
+            ImageFormatState::Qcow2,
+            ImageFormatState::from(ImageFormat::Qcow2)
+        );
+        assert_eq!(ImageFormat::Raw, ImageFormatState::Raw.into());
+        assert_eq!(BlockState::default_image_format(3), ImageFormatState::Raw);
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+
+        // Older versions can't tell qcow2 images apart from raw ones.
+        assert!(<Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .is_err());
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs { mem: default_mem() },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(restored_block.image_format(), ImageFormat::Qcow2);
+        assert_eq!(restored_block.disk.nsectors(), 0x10000 >> SECTOR_SHIFT);
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+    }
+
+    #[test]
+    fn test_persistence_cow() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x2000).unwrap();
+        let overlay = TempFile::new().unwrap();
+        let overlay_path = overlay.as_path().to_str().unwrap().to_string();
+
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            true,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Sync,
+            DEFAULT_NUM_QUEUES,
+            ImageFormat::Raw,
+            Some(overlay_path.clone()),
+        )
+        .unwrap();
+        // Pretend the guest wrote to the second chunk of the disk.
+        block.disk.set_cow_bitmap(vec![0b10]).unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+
+        // Older versions would lose the data written by the guest.
+        assert!(<Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .is_err());
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs { mem: default_mem() },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap(),
+        )
+        .unwrap();
+
+        assert!(restored_block.is_read_only());
+        assert_eq!(restored_block.cow_overlay_path(), Some(overlay_path));
+        assert_eq!(restored_block.disk.cow_bitmap(), Some(vec![0b10]));
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+    }
+
+    #[test]
+    fn test_persistence_nbd() {
+        let server = NbdStubServer::new(0x2000, 0, None);
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            server.uri(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Nbd,
+            DEFAULT_NUM_QUEUES,
+            ImageFormat::Raw,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            FileEngineTypeState::Nbd,
+            FileEngineTypeState::from(FileEngineType::Nbd)
+        );
+        assert_eq!(FileEngineType::Nbd, FileEngineTypeState::Nbd.into());
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4)
+            .new_version()
+            .set_type_version(FileEngineTypeState::type_id(), 2);
+
+        // Older versions would take the URI of the export for a file path.
+        assert!(matches!(
+            <Block as Persist>::save(&block).serialize(&mut mem.as_mut_slice(), &version_map, 2),
+            Err(VersionizeError::Semantic(_))
+        ));
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        // The restored device opens its own connection to the server.
+        let restored_block = Block::restore(
+            BlockConstructorArgs { mem: default_mem() },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(restored_block.file_engine_type(), FileEngineType::Nbd);
+        assert_eq!(restored_block.file_path(), &server.uri());
+        assert_eq!(restored_block.disk.nsectors(), 0x2000 >> SECTOR_SHIFT);
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+    }
 }
@@ -12,7 +12,8 @@ use logger::{error, IncMetric, METRICS};
 use rate_limiter::{RateLimiter, TokenType};
 pub use virtio_gen::virtio_blk::{
     VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
-    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+    VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN,
+    VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
 };
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
@@ -34,6 +35,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -44,6 +47,8 @@ impl From<u32> for RequestType {
             VIRTIO_BLK_T_OUT => RequestType::Out,
             VIRTIO_BLK_T_FLUSH => RequestType::Flush,
             VIRTIO_BLK_T_GET_ID => RequestType::GetDeviceID,
+            VIRTIO_BLK_T_DISCARD => RequestType::Discard,
+            VIRTIO_BLK_T_WRITE_ZEROES => RequestType::WriteZeroes,
             t => RequestType::Unsupported(t),
         }
     }
@@ -157,6 +162,18 @@ impl PendingRequest {
                     num_bytes_to_mem: 0,
                 }
             }
+            (Ok(_), RequestType::Discard) => {
+                METRICS.block.discard_count.inc();
+                Status::Ok {
+                    num_bytes_to_mem: 0,
+                }
+            }
+            (Ok(_), RequestType::WriteZeroes) => {
+                METRICS.block.write_zeroes_count.inc();
+                Status::Ok {
+                    num_bytes_to_mem: 0,
+                }
+            }
             (Ok(transferred_data_len), RequestType::GetDeviceID) => {
                 Status::from_data(self.data_len, transferred_data_len, true)
             }
@@ -213,6 +230,33 @@ impl RequestHeader {
     }
 }
 
+/// The segment carried in the data descriptor of discard and write zeroes requests.
+///
+/// A segment contains the following fields:
+///   * sector: an u64 value representing the first sector of the range.
+///   * num_sectors: an u32 value representing the number of sectors in the range.
+///   * flags: an u32 value; only the unmap flag is defined, and only for write zeroes.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct DiscardWriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// Safe because DiscardWriteZeroesSegment only contains plain data.
+unsafe impl ByteValued for DiscardWriteZeroesSegment {}
+
+impl DiscardWriteZeroesSegment {
+    pub fn new(sector: u64, num_sectors: u32, flags: u32) -> DiscardWriteZeroesSegment {
+        DiscardWriteZeroesSegment {
+            sector,
+            num_sectors,
+            flags,
+        }
+    }
+}
+
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub struct Request {
     pub r#type: RequestType,
@@ -220,6 +264,9 @@ pub struct Request {
     pub status_addr: GuestAddress,
     sector: u64,
     data_addr: GuestAddress,
+    // Only used by discard and write zeroes requests.
+    num_sectors: u32,
+    unmap: bool,
 }
 
 impl Request {
@@ -240,6 +287,8 @@ impl Request {
             data_addr: GuestAddress(0),
             data_len: 0,
             status_addr: GuestAddress(0),
+            num_sectors: 0,
+            unmap: false,
         };
 
         let data_desc;
@@ -260,7 +309,11 @@ impl Request {
                 .next_descriptor()
                 .ok_or(Error::DescriptorChainTooShort)?;
 
-            if data_desc.is_write_only() && req.r#type == RequestType::Out {
+            if data_desc.is_write_only()
+                && (req.r#type == RequestType::Out
+                    || req.r#type == RequestType::Discard
+                    || req.r#type == RequestType::WriteZeroes)
+            {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
             if !data_desc.is_write_only() && req.r#type == RequestType::In {
@@ -295,6 +348,33 @@ impl Request {
                     return Err(Error::InvalidDataLength);
                 }
             }
+            RequestType::Discard | RequestType::WriteZeroes => {
+                // We advertise a maximum of one segment per request.
+                if req.data_len as usize != std::mem::size_of::<DiscardWriteZeroesSegment>() {
+                    return Err(Error::InvalidDataLength);
+                }
+                let segment: DiscardWriteZeroesSegment =
+                    mem.read_obj(req.data_addr).map_err(Error::GuestMemory)?;
+                // The unmap flag is only defined for write zeroes requests.
+                let supported_flags = match req.r#type {
+                    RequestType::WriteZeroes => VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+                    _ => 0,
+                };
+                if segment.flags & !supported_flags != 0 {
+                    return Err(Error::InvalidFlags);
+                }
+                let top_sector = segment
+                    .sector
+                    .checked_add(u64::from(segment.num_sectors))
+                    .ok_or(Error::InvalidOffset)?;
+                if top_sector > num_disk_sectors {
+                    return Err(Error::InvalidOffset);
+                }
+
+                req.sector = segment.sector;
+                req.num_sectors = segment.num_sectors;
+                req.unmap = segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+            }
             _ => {}
         }
 
@@ -336,6 +416,11 @@ impl Request {
         self.sector << SECTOR_SHIFT
     }
 
+    // Length in bytes of the range targeted by a discard or write zeroes request.
+    fn range_len(&self) -> u64 {
+        u64::from(self.num_sectors) << SECTOR_SHIFT
+    }
+
     fn to_pending_request(&self, desc_idx: u16) -> PendingRequest {
         PendingRequest {
             r#type: self.r#type,
@@ -348,26 +433,37 @@ impl Request {
     pub(crate) fn process(
         self,
         disk: &mut DiskProperties,
+        queue_index: usize,
         desc_idx: u16,
         mem: &GuestMemoryMmap,
     ) -> ProcessingResult {
         let pending = self.to_pending_request(desc_idx);
         let res = match self.r#type {
-            RequestType::In => disk.file_engine_mut().read(
+            RequestType::In => disk.file_engine_mut(queue_index).read(
                 self.offset(),
                 mem,
                 self.data_addr,
                 self.data_len,
                 pending,
             ),
-            RequestType::Out => disk.file_engine_mut().write(
+            RequestType::Out => disk.file_engine_mut(queue_index).write(
                 self.offset(),
                 mem,
                 self.data_addr,
                 self.data_len,
                 pending,
             ),
-            RequestType::Flush => disk.file_engine_mut().flush(pending),
+            RequestType::Flush => disk.file_engine_mut(queue_index).flush(pending),
+            RequestType::Discard => {
+                disk.file_engine_mut(queue_index)
+                    .discard(self.offset(), self.range_len(), pending)
+            }
+            RequestType::WriteZeroes => disk.file_engine_mut(queue_index).write_zeroes(
+                self.offset(),
+                self.range_len(),
+                self.unmap,
+                pending,
+            ),
             RequestType::GetDeviceID => {
                 let res = mem
                     .write_slice(disk.image_id(), self.data_addr)
@@ -421,6 +517,8 @@ mod tests {
             VIRTIO_BLK_T_OUT,
             VIRTIO_BLK_T_FLUSH,
             VIRTIO_BLK_T_GET_ID,
+            VIRTIO_BLK_T_DISCARD,
+            VIRTIO_BLK_T_WRITE_ZEROES,
         ];
 
         for request_type in supported_request_types {
@@ -447,6 +545,14 @@ mod tests {
             RequestType::from(VIRTIO_BLK_T_GET_ID),
             RequestType::GetDeviceID
         );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_DISCARD),
+            RequestType::Discard
+        );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_WRITE_ZEROES),
+            RequestType::WriteZeroes
+        );
         assert_eq!(RequestType::from(42), RequestType::Unsupported(42));
     }
 
@@ -693,6 +799,78 @@ mod tests {
         queue.check_parse(true);
     }
 
+    #[test]
+    fn test_parse_discard_write_zeroes() {
+        let mem = &create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+        let segment_len = std::mem::size_of::<DiscardWriteZeroesSegment>() as u32;
+
+        for request_type in [VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_WRITE_ZEROES] {
+            let mut queue = RequestVirtQueue::new(GuestAddress(0), &mem);
+
+            let request_header = RequestHeader::new(request_type, 0);
+            queue.set_hdr_desc(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, request_header);
+            queue.set_status_desc(0x3000, 0x1000, VIRTQ_DESC_F_WRITE);
+
+            // Write only data descriptor.
+            queue.set_data_desc(0x2000, segment_len, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            queue.check_parse_err(Error::UnexpectedWriteOnlyDescriptor);
+
+            // More than one segment.
+            queue.mut_data_desc().flags.set(VIRTQ_DESC_F_NEXT);
+            queue.mut_data_desc().len.set(2 * segment_len);
+            queue.check_parse_err(Error::InvalidDataLength);
+
+            // Range goes beyond the end of the disk.
+            queue.mut_data_desc().len.set(segment_len);
+            mem.write_obj(
+                DiscardWriteZeroesSegment::new(NUM_DISK_SECTORS - 1, 2, 0),
+                GuestAddress(0x2000),
+            )
+            .unwrap();
+            queue.check_parse_err(Error::InvalidOffset);
+
+            // Unknown flags.
+            mem.write_obj(
+                DiscardWriteZeroesSegment::new(NUM_DISK_SECTORS - 2, 2, 0x2),
+                GuestAddress(0x2000),
+            )
+            .unwrap();
+            queue.check_parse_err(Error::InvalidFlags);
+
+            // Valid segment, with the unmap flag set for write zeroes only.
+            let flags = match request_type {
+                VIRTIO_BLK_T_WRITE_ZEROES => VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+                _ => 0,
+            };
+            mem.write_obj(
+                DiscardWriteZeroesSegment::new(NUM_DISK_SECTORS - 2, 2, flags),
+                GuestAddress(0x2000),
+            )
+            .unwrap();
+            let mut q = queue.vq.create_queue();
+            let request = Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS).unwrap();
+            assert_eq!(request.r#type, RequestType::from(request_type));
+            assert_eq!(request.sector, NUM_DISK_SECTORS - 2);
+            assert_eq!(request.num_sectors, 2);
+            assert_eq!(request.range_len(), 2 * SECTOR_SIZE);
+            assert_eq!(request.unmap, flags != 0);
+            assert_eq!(request.status_addr, GuestAddress(0x3000));
+        }
+
+        // The unmap flag is not supported for discard requests.
+        let mut queue = RequestVirtQueue::new(GuestAddress(0), &mem);
+        let request_header = RequestHeader::new(VIRTIO_BLK_T_DISCARD, 0);
+        queue.set_hdr_desc(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, request_header);
+        queue.set_data_desc(0x2000, segment_len, VIRTQ_DESC_F_NEXT);
+        queue.set_status_desc(0x3000, 0x1000, VIRTQ_DESC_F_WRITE);
+        mem.write_obj(
+            DiscardWriteZeroesSegment::new(0, 1, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+            GuestAddress(0x2000),
+        )
+        .unwrap();
+        queue.check_parse_err(Error::InvalidFlags);
+    }
+
     use std::convert::TryInto;
 
     /// -------------------------------------
@@ -735,9 +913,9 @@ mod tests {
                     1u32,
                     std::sync::Arc::new(Strategy::prop_map(any::<u32>(), |id| {
                         // Random unsupported requests for our implementation start at
-                        // VIRTIO_BLK_T_GET_ID + 1 = 9.
-                        // This can be further refined to include unsupported requests ids < 9.
-                        RequestType::Unsupported(id.checked_add(9).unwrap_or(9))
+                        // VIRTIO_BLK_T_WRITE_ZEROES + 1 = 14.
+                        // This can be further refined to include unsupported requests ids < 14.
+                        RequestType::Unsupported(id.checked_add(14).unwrap_or(14))
                     })),
                 ),
             ))
@@ -751,6 +929,8 @@ mod tests {
                 RequestType::Out => VIRTIO_BLK_T_OUT,
                 RequestType::Flush => VIRTIO_BLK_T_FLUSH,
                 RequestType::GetDeviceID => VIRTIO_BLK_T_GET_ID,
+                RequestType::Discard => VIRTIO_BLK_T_DISCARD,
+                RequestType::WriteZeroes => VIRTIO_BLK_T_WRITE_ZEROES,
                 RequestType::Unsupported(id) => id,
             }
         }
@@ -763,6 +943,7 @@ mod tests {
             RequestType::Out => VIRTQ_DESC_F_NEXT,
             RequestType::Flush => VIRTQ_DESC_F_NEXT,
             RequestType::GetDeviceID => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            RequestType::Discard | RequestType::WriteZeroes => VIRTQ_DESC_F_NEXT,
             RequestType::Unsupported(_) => VIRTQ_DESC_F_NEXT,
         }
     }
@@ -856,6 +1037,8 @@ mod tests {
             status_addr,
             sector: sector & (NUM_DISK_SECTORS - sectors_len),
             data_addr,
+            num_sectors: 0,
+            unmap: false,
         };
         let request_header = RequestHeader::new(virtio_request_id, request.sector);
 
@@ -12,9 +12,10 @@ use rate_limiter::RateLimiter;
 use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
 use utils::tempfile::TempFile;
 
-use crate::virtio::block::device::FileEngineType;
+use crate::virtio::block::device::{FileEngineType, ImageFormat};
 #[cfg(test)]
 use crate::virtio::block::io::FileEngine;
+use crate::virtio::block::DEFAULT_NUM_QUEUES;
 #[cfg(test)]
 use crate::virtio::IrqType;
 use crate::virtio::{Block, CacheType, Queue};
@@ -53,6 +54,9 @@ pub fn default_block_with_path(path: String, file_engine_type: FileEngineType) -
         false,
         rate_limiter,
         file_engine_type,
+        DEFAULT_NUM_QUEUES,
+        ImageFormat::Raw,
+        None,
     )
     .unwrap()
 }
@@ -74,7 +78,7 @@ pub fn simulate_queue_event(b: &mut Block, maybe_expected_irq: Option<bool>) {
     // Trigger the queue event.
     b.queue_evts[0].write(1).unwrap();
     // Handle event.
-    b.process_queue_event();
+    b.process_queue_event(0);
     // Validate the queue operation finished successfully.
     if let Some(expected_irq) = maybe_expected_irq {
         assert_eq!(b.irq_trigger.has_pending_irq(IrqType::Vring), expected_irq);
@@ -83,13 +87,13 @@ pub fn simulate_queue_event(b: &mut Block, maybe_expected_irq: Option<bool>) {
 
 #[cfg(test)]
 pub fn simulate_async_completion_event(b: &mut Block, expected_irq: bool) {
-    if let FileEngine::Async(engine) = b.disk.file_engine_mut() {
+    if let FileEngine::Async(engine) = b.disk.file_engine_mut(0) {
         // Wait for all the async operations to complete.
         engine.drain(false).unwrap();
         // Wait for the async completion event to be sent.
         thread::sleep(Duration::from_millis(150));
         // Handle event.
-        b.process_async_completion_event();
+        b.process_async_completion_event(0);
     }
 
     // Validate if there are pending IRQs.
@@ -98,12 +102,12 @@ pub fn simulate_async_completion_event(b: &mut Block, expected_irq: bool) {
 
 #[cfg(test)]
 pub fn simulate_queue_and_async_completion_events(b: &mut Block, expected_irq: bool) {
-    match b.disk.file_engine_mut() {
+    match b.disk.file_engine_mut(0) {
         FileEngine::Async(_) => {
             simulate_queue_event(b, None);
             simulate_async_completion_event(b, expected_irq);
         }
-        FileEngine::Sync(_) => {
+        FileEngine::Sync(_) | FileEngine::Qcow2(_) | FileEngine::Cow(_) | FileEngine::Nbd(_) => {
             simulate_queue_event(b, Some(expected_irq));
         }
     }
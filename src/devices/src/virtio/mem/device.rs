@@ -0,0 +1,838 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::cmp;
+use std::io::Write;
+use std::mem::size_of;
+use std::ops::Range;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+use logger::{error, IncMetric, METRICS};
+use utils::eventfd::EventFd;
+use virtio_gen::virtio_blk::VIRTIO_F_VERSION_1;
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_MEM};
+use super::{
+    Error as MemError, Result, MEM_DEV_ID, MEM_MIN_BLOCK_SIZE, MEM_NUM_QUEUES, MEM_QUEUE_SIZES,
+    VIRTIO_MEM_F_UNPLUGGED_INACCESSIBLE, VIRTIO_MEM_REQ_PLUG, VIRTIO_MEM_REQ_STATE,
+    VIRTIO_MEM_REQ_UNPLUG, VIRTIO_MEM_REQ_UNPLUG_ALL, VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_RESP_ERROR,
+    VIRTIO_MEM_RESP_NACK, VIRTIO_MEM_STATE_MIXED, VIRTIO_MEM_STATE_PLUGGED,
+    VIRTIO_MEM_STATE_UNPLUGGED,
+};
+use crate::virtio::balloon::utils::remove_range;
+use crate::virtio::{IrqTrigger, IrqType};
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct ConfigSpace {
+    pub block_size: u64,
+    pub node_id: u16,
+    pub padding: [u8; 6],
+    pub addr: u64,
+    pub region_size: u64,
+    pub usable_region_size: u64,
+    pub plugged_size: u64,
+    pub requested_size: u64,
+}
+
+// Safe because ConfigSpace only contains plain data.
+unsafe impl ByteValued for ConfigSpace {}
+
+// A request of the driver, for a range of `nb_blocks` memory blocks starting at `addr`.
+// The range is unused by unplug-all requests.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct MemRequest {
+    req_type: u16,
+    padding: [u16; 3],
+    addr: u64,
+    nb_blocks: u16,
+    padding_range: [u16; 3],
+}
+
+// Safe because MemRequest only contains plain data.
+unsafe impl ByteValued for MemRequest {}
+
+// The response to a request. The state is only set in response to state requests.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct MemResponse {
+    resp_type: u16,
+    padding: [u16; 3],
+    state: u16,
+}
+
+// Safe because MemResponse only contains plain data.
+unsafe impl ByteValued for MemResponse {}
+
+impl MemResponse {
+    fn new(resp_type: u16) -> Self {
+        MemResponse {
+            resp_type,
+            ..Default::default()
+        }
+    }
+
+    fn with_state(state: u16) -> Self {
+        MemResponse {
+            resp_type: VIRTIO_MEM_RESP_ACK,
+            state,
+            ..Default::default()
+        }
+    }
+}
+
+// Virtio-mem device.
+pub struct VirtioMem {
+    // Virtio fields.
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    pub(crate) config_space: ConfigSpace,
+    pub(crate) activate_evt: EventFd,
+
+    // Transport related fields.
+    pub(crate) queues: Vec<Queue>,
+    pub(crate) queue_evts: [EventFd; MEM_NUM_QUEUES],
+    pub(crate) device_state: DeviceState,
+    pub(crate) irq_trigger: IrqTrigger,
+
+    // Implementation specific fields.
+    pub(crate) restored: bool,
+    // Whether each memory block of the region is plugged.
+    pub(crate) plugged_blocks: Vec<bool>,
+}
+
+impl VirtioMem {
+    /// Creates a virtio-mem device for the memory region of `region_size` bytes at `addr`,
+    /// split in blocks of `block_size` bytes. None of the blocks is plugged initially.
+    pub fn new(
+        addr: GuestAddress,
+        region_size: u64,
+        block_size: u64,
+        requested_size: u64,
+        restored: bool,
+    ) -> Result<VirtioMem> {
+        if block_size < MEM_MIN_BLOCK_SIZE
+            || !block_size.is_power_of_two()
+            || addr.0 % block_size != 0
+            || region_size == 0
+            || region_size % block_size != 0
+        {
+            return Err(MemError::InvalidRegion);
+        }
+
+        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK).map_err(MemError::EventFd)?];
+        let queues: Vec<Queue> = MEM_QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+
+        let mut mem = VirtioMem {
+            avail_features: (1u64 << VIRTIO_F_VERSION_1)
+                | (1u64 << VIRTIO_MEM_F_UNPLUGGED_INACCESSIBLE),
+            acked_features: 0u64,
+            config_space: ConfigSpace {
+                block_size,
+                addr: addr.0,
+                region_size,
+                usable_region_size: region_size,
+                ..Default::default()
+            },
+            activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(MemError::EventFd)?,
+            queues,
+            queue_evts,
+            device_state: DeviceState::Inactive,
+            irq_trigger: IrqTrigger::new().map_err(MemError::EventFd)?,
+            restored,
+            plugged_blocks: vec![false; (region_size / block_size) as usize],
+        };
+        mem.check_requested_size(requested_size)?;
+        mem.config_space.requested_size = requested_size;
+
+        Ok(mem)
+    }
+
+    pub(crate) fn process_queue_event(&mut self) -> Result<()> {
+        self.queue_evts[0].read().map_err(MemError::EventFd)?;
+        self.process_queue()
+    }
+
+    pub(crate) fn process_queue(&mut self) -> Result<()> {
+        // This is safe since we checked in the event handler that the device is activated.
+        // The memory is cloned so that the requests can update the device state.
+        let mem = self.device_state.mem().unwrap().clone();
+        let mut needs_interrupt = false;
+
+        while let Some(head) = self.queues[0].pop(&mem) {
+            let len = match self.process_request(&mem, &head) {
+                Ok(len) => len,
+                Err(err) => {
+                    error!("virtio-mem: failed to process request: {:?}", err);
+                    METRICS.mem.event_fails.inc();
+                    0
+                }
+            };
+
+            self.queues[0]
+                .add_used(&mem, head.index, len)
+                .map_err(MemError::Queue)?;
+            needs_interrupt = true;
+        }
+
+        if needs_interrupt {
+            self.signal_used_queue()?;
+        }
+
+        Ok(())
+    }
+
+    // Handles the request in the descriptor chain starting at `head`, and returns the number of
+    // bytes written in its response.
+    fn process_request(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        head: &crate::virtio::DescriptorChain,
+    ) -> Result<u32> {
+        if head.is_write_only() || (head.len as usize) < size_of::<MemRequest>() {
+            return Err(MemError::MalformedDescriptor);
+        }
+        let request = mem
+            .read_obj::<MemRequest>(head.addr)
+            .map_err(MemError::GuestMemory)?;
+
+        let response_desc = head
+            .next_descriptor()
+            .ok_or(MemError::MalformedDescriptor)?;
+        if !response_desc.is_write_only() || (response_desc.len as usize) < size_of::<MemResponse>()
+        {
+            return Err(MemError::MalformedDescriptor);
+        }
+
+        let response = match request.req_type {
+            VIRTIO_MEM_REQ_PLUG => self.plug(request.addr, request.nb_blocks),
+            VIRTIO_MEM_REQ_UNPLUG => self.unplug(mem, request.addr, request.nb_blocks),
+            VIRTIO_MEM_REQ_UNPLUG_ALL => self.unplug_all(mem),
+            VIRTIO_MEM_REQ_STATE => self.state(request.addr, request.nb_blocks),
+            _ => {
+                error!("virtio-mem: unknown request type {}", request.req_type);
+                MemResponse::new(VIRTIO_MEM_RESP_ERROR)
+            }
+        };
+
+        mem.write_obj(response, response_desc.addr)
+            .map_err(MemError::GuestMemory)?;
+        Ok(size_of::<MemResponse>() as u32)
+    }
+
+    // Returns the indices of the `nb_blocks` blocks starting at `addr`, if they all are in the
+    // usable part of the region.
+    fn block_range(&self, addr: u64, nb_blocks: u16) -> Option<Range<usize>> {
+        let offset = addr.checked_sub(self.config_space.addr)?;
+        if nb_blocks == 0 || offset % self.config_space.block_size != 0 {
+            return None;
+        }
+
+        let first = offset / self.config_space.block_size;
+        let end = first + u64::from(nb_blocks);
+        if end > self.config_space.usable_region_size / self.config_space.block_size {
+            return None;
+        }
+        Some(first as usize..end as usize)
+    }
+
+    fn plug(&mut self, addr: u64, nb_blocks: u16) -> MemResponse {
+        let blocks = match self.block_range(addr, nb_blocks) {
+            Some(blocks) if !self.plugged_blocks[blocks.clone()].iter().any(|&p| p) => blocks,
+            _ => {
+                METRICS.mem.plug_fails.inc();
+                return MemResponse::new(VIRTIO_MEM_RESP_ERROR);
+            }
+        };
+
+        // The guest can't plug more memory than requested.
+        let size = u64::from(nb_blocks) * self.config_space.block_size;
+        if self.config_space.plugged_size + size > self.config_space.requested_size {
+            METRICS.mem.plug_fails.inc();
+            return MemResponse::new(VIRTIO_MEM_RESP_NACK);
+        }
+
+        // The memory of the region is already mapped, the blocks only need to be marked as
+        // plugged.
+        for plugged in &mut self.plugged_blocks[blocks] {
+            *plugged = true;
+        }
+        self.config_space.plugged_size += size;
+        METRICS.mem.plug_count.inc();
+        MemResponse::new(VIRTIO_MEM_RESP_ACK)
+    }
+
+    fn unplug(&mut self, mem: &GuestMemoryMmap, addr: u64, nb_blocks: u16) -> MemResponse {
+        let blocks = match self.block_range(addr, nb_blocks) {
+            Some(blocks) if self.plugged_blocks[blocks.clone()].iter().all(|&p| p) => blocks,
+            _ => {
+                METRICS.mem.unplug_fails.inc();
+                return MemResponse::new(VIRTIO_MEM_RESP_ERROR);
+            }
+        };
+
+        let size = u64::from(nb_blocks) * self.config_space.block_size;
+        if let Err(err) = remove_range(mem, (GuestAddress(addr), size), self.restored) {
+            error!("virtio-mem: failed to discard unplugged memory: {:?}", err);
+            METRICS.mem.unplug_fails.inc();
+            return MemResponse::new(VIRTIO_MEM_RESP_ERROR);
+        }
+
+        for plugged in &mut self.plugged_blocks[blocks] {
+            *plugged = false;
+        }
+        self.config_space.plugged_size -= size;
+        METRICS.mem.unplug_count.inc();
+        MemResponse::new(VIRTIO_MEM_RESP_ACK)
+    }
+
+    fn unplug_all(&mut self, mem: &GuestMemoryMmap) -> MemResponse {
+        // Discarding the memory of unplugged blocks is harmless, so the whole usable region
+        // is discarded at once.
+        if let Err(err) = remove_range(
+            mem,
+            (
+                GuestAddress(self.config_space.addr),
+                self.config_space.usable_region_size,
+            ),
+            self.restored,
+        ) {
+            error!("virtio-mem: failed to discard unplugged memory: {:?}", err);
+            METRICS.mem.unplug_fails.inc();
+            return MemResponse::new(VIRTIO_MEM_RESP_ERROR);
+        }
+
+        for plugged in self.plugged_blocks.iter_mut() {
+            *plugged = false;
+        }
+        self.config_space.plugged_size = 0;
+        METRICS.mem.unplug_count.inc();
+        MemResponse::new(VIRTIO_MEM_RESP_ACK)
+    }
+
+    fn state(&self, addr: u64, nb_blocks: u16) -> MemResponse {
+        let blocks = match self.block_range(addr, nb_blocks) {
+            Some(blocks) => &self.plugged_blocks[blocks],
+            None => return MemResponse::new(VIRTIO_MEM_RESP_ERROR),
+        };
+
+        if blocks.iter().all(|&p| p) {
+            MemResponse::with_state(VIRTIO_MEM_STATE_PLUGGED)
+        } else if blocks.iter().any(|&p| p) {
+            MemResponse::with_state(VIRTIO_MEM_STATE_MIXED)
+        } else {
+            MemResponse::with_state(VIRTIO_MEM_STATE_UNPLUGGED)
+        }
+    }
+
+    pub(crate) fn signal_used_queue(&self) -> Result<()> {
+        self.irq_trigger.trigger_irq(IrqType::Vring).map_err(|err| {
+            METRICS.mem.event_fails.inc();
+            MemError::InterruptError(err)
+        })
+    }
+
+    /// Process device virtio queue(s).
+    pub fn process_virtio_queues(&mut self) {
+        let _ = self.process_queue();
+    }
+
+    pub fn id(&self) -> &str {
+        MEM_DEV_ID
+    }
+
+    fn check_requested_size(&self, requested_size: u64) -> Result<()> {
+        if requested_size > self.config_space.usable_region_size
+            || requested_size % self.config_space.block_size != 0
+        {
+            return Err(MemError::InvalidRequestedSize(requested_size));
+        }
+        Ok(())
+    }
+
+    /// Requests the guest to plug or unplug memory blocks, until `requested_size` bytes of
+    /// the region are plugged.
+    pub fn update_requested_size(&mut self, requested_size: u64) -> Result<()> {
+        self.check_requested_size(requested_size)?;
+        self.config_space.requested_size = requested_size;
+
+        // The driver reads the requested size when probing the device, so it only needs to be
+        // notified of changes once activated.
+        if self.is_activated() {
+            self.irq_trigger
+                .trigger_irq(IrqType::Config)
+                .map_err(MemError::InterruptError)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the start address and the size of the memory region.
+    pub fn region(&self) -> (GuestAddress, u64) {
+        (
+            GuestAddress(self.config_space.addr),
+            self.config_space.region_size,
+        )
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.config_space.block_size
+    }
+
+    pub fn plugged_size(&self) -> u64 {
+        self.config_space.plugged_size
+    }
+
+    pub fn requested_size(&self) -> u64 {
+        self.config_space.requested_size
+    }
+}
+
+impl VirtioDevice for VirtioMem {
+    fn device_type(&self) -> u32 {
+        TYPE_MEM
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_evts
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.irq_trigger.irq_evt
+    }
+
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.irq_trigger.irq_status.clone()
+    }
+
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_space_bytes = self.config_space.as_slice();
+        let config_len = config_space_bytes.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(
+                &config_space_bytes[offset as usize..cmp::min(end, config_len) as usize],
+            )
+            .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {
+        // All the fields of the configuration space are read-only for the driver.
+        error!("virtio-mem: Guest attempted to write config");
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        self.device_state = DeviceState::Activated(mem);
+        if self.activate_evt.write(1).is_err() {
+            error!("virtio-mem: Cannot write to activate_evt");
+            METRICS.mem.activate_fails.inc();
+            self.device_state = DeviceState::Inactive;
+            return Err(super::super::ActivateError::BadActivate);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::check_metric_after_block;
+    use crate::virtio::test_utils::VirtQueue;
+    use crate::virtio::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const BLOCK_SIZE: u64 = MEM_MIN_BLOCK_SIZE;
+    // The hot-pluggable region follows 2 MiB of boot memory, which holds the queue.
+    const REGION_ADDR: u64 = BLOCK_SIZE;
+    const REGION_SIZE: u64 = 4 * BLOCK_SIZE;
+    const REQUEST_ADDR: u64 = 0x1000;
+    const RESPONSE_ADDR: u64 = 0x2000;
+
+    fn hotplug_mem() -> GuestMemoryMmap {
+        vm_memory::test_utils::create_anon_guest_memory(
+            &[
+                (GuestAddress(0), BLOCK_SIZE as usize),
+                (GuestAddress(REGION_ADDR), REGION_SIZE as usize),
+            ],
+            false,
+        )
+        .unwrap()
+    }
+
+    fn activated_device(mem: &GuestMemoryMmap, vq: &VirtQueue) -> VirtioMem {
+        let mut device = VirtioMem::new(
+            GuestAddress(REGION_ADDR),
+            REGION_SIZE,
+            BLOCK_SIZE,
+            2 * BLOCK_SIZE,
+            false,
+        )
+        .unwrap();
+        device.queues[0] = vq.create_queue();
+        device.activate(mem.clone()).unwrap();
+        device
+    }
+
+    // Submits a request through descriptors `idx` and `idx + 1`, processes it, and returns the
+    // response.
+    fn send_request(
+        device: &mut VirtioMem,
+        mem: &GuestMemoryMmap,
+        vq: &VirtQueue,
+        idx: u16,
+        request: MemRequest,
+    ) -> MemResponse {
+        mem.write_obj(request, GuestAddress(REQUEST_ADDR)).unwrap();
+        vq.dtable[idx as usize].set(
+            REQUEST_ADDR,
+            size_of::<MemRequest>() as u32,
+            VIRTQ_DESC_F_NEXT,
+            idx + 1,
+        );
+        vq.dtable[idx as usize + 1].set(
+            RESPONSE_ADDR,
+            size_of::<MemResponse>() as u32,
+            VIRTQ_DESC_F_WRITE,
+            0,
+        );
+        vq.avail.ring[(idx / 2) as usize].set(idx);
+        vq.avail.idx.set(idx / 2 + 1);
+
+        device.queue_evts[0].write(1).unwrap();
+        device.process_queue_event().unwrap();
+        assert_eq!(vq.used.idx.get(), idx / 2 + 1);
+        assert_eq!(
+            vq.used.ring[(idx / 2) as usize].get().len,
+            size_of::<MemResponse>() as u32
+        );
+        assert!(device.irq_trigger.has_pending_irq(IrqType::Vring));
+
+        mem.read_obj(GuestAddress(RESPONSE_ADDR)).unwrap()
+    }
+
+    fn request(req_type: u16, addr: u64, nb_blocks: u16) -> MemRequest {
+        MemRequest {
+            req_type,
+            addr,
+            nb_blocks,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_struct_sizes() {
+        assert_eq!(size_of::<ConfigSpace>(), 56);
+        assert_eq!(size_of::<MemRequest>(), 24);
+        assert_eq!(size_of::<MemResponse>(), 10);
+    }
+
+    #[test]
+    fn test_new() {
+        let addr = GuestAddress(1 << 32);
+        let device = VirtioMem::new(addr, 1 << 30, BLOCK_SIZE, 0, false).unwrap();
+        assert_eq!(device.device_type(), TYPE_MEM);
+        assert_eq!(device.id(), MEM_DEV_ID);
+        assert_eq!(device.region(), (addr, 1 << 30));
+        assert_eq!(device.block_size(), BLOCK_SIZE);
+        assert_eq!(device.plugged_size(), 0);
+        assert_eq!(device.requested_size(), 0);
+        assert_eq!(device.plugged_blocks.len(), 512);
+        assert_eq!(
+            device.avail_features(),
+            (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_MEM_F_UNPLUGGED_INACCESSIBLE)
+        );
+
+        // The region must be made of whole blocks.
+        assert!(matches!(
+            VirtioMem::new(addr, BLOCK_SIZE + 0x1000, BLOCK_SIZE, 0, false),
+            Err(MemError::InvalidRegion)
+        ));
+        assert!(matches!(
+            VirtioMem::new(GuestAddress(0x1000), BLOCK_SIZE, BLOCK_SIZE, 0, false),
+            Err(MemError::InvalidRegion)
+        ));
+        assert!(matches!(
+            VirtioMem::new(addr, 0, BLOCK_SIZE, 0, false),
+            Err(MemError::InvalidRegion)
+        ));
+        assert!(matches!(
+            VirtioMem::new(addr, 1 << 30, 0x1000, 0, false),
+            Err(MemError::InvalidRegion)
+        ));
+        assert!(matches!(
+            VirtioMem::new(addr, 1 << 30, 3 << 20, 0, false),
+            Err(MemError::InvalidRegion)
+        ));
+        // The requested size can't exceed the region.
+        assert!(matches!(
+            VirtioMem::new(addr, BLOCK_SIZE, BLOCK_SIZE, 2 * BLOCK_SIZE, false),
+            Err(MemError::InvalidRequestedSize(_))
+        ));
+    }
+
+    #[test]
+    fn test_config_space() {
+        let mut device = VirtioMem::new(
+            GuestAddress(REGION_ADDR),
+            REGION_SIZE,
+            BLOCK_SIZE,
+            BLOCK_SIZE,
+            false,
+        )
+        .unwrap();
+
+        let mut block_size = [0u8; 8];
+        device.read_config(0, &mut block_size);
+        assert_eq!(u64::from_le_bytes(block_size), BLOCK_SIZE);
+        let mut addr = [0u8; 8];
+        device.read_config(16, &mut addr);
+        assert_eq!(u64::from_le_bytes(addr), REGION_ADDR);
+        let mut requested_size = [0u8; 8];
+        device.read_config(48, &mut requested_size);
+        assert_eq!(u64::from_le_bytes(requested_size), BLOCK_SIZE);
+
+        // Reads past the end of the configuration space are ignored.
+        let mut data = [0xffu8; 8];
+        device.read_config(56, &mut data);
+        assert_eq!(data, [0xffu8; 8]);
+
+        // The configuration space is read-only.
+        device.write_config(48, &[0u8; 8]);
+        assert_eq!(device.requested_size(), BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_update_requested_size() {
+        let mem = hotplug_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut device =
+            VirtioMem::new(GuestAddress(REGION_ADDR), REGION_SIZE, BLOCK_SIZE, 0, false).unwrap();
+
+        // The driver isn't notified before activation.
+        device.update_requested_size(BLOCK_SIZE).unwrap();
+        assert_eq!(device.requested_size(), BLOCK_SIZE);
+        assert!(!device.irq_trigger.has_pending_irq(IrqType::Config));
+
+        device.queues[0] = vq.create_queue();
+        device.activate(mem.clone()).unwrap();
+        device.update_requested_size(REGION_SIZE).unwrap();
+        assert_eq!(device.requested_size(), REGION_SIZE);
+        assert!(device.irq_trigger.has_pending_irq(IrqType::Config));
+
+        assert!(matches!(
+            device.update_requested_size(REGION_SIZE + BLOCK_SIZE),
+            Err(MemError::InvalidRequestedSize(_))
+        ));
+        assert!(matches!(
+            device.update_requested_size(0x1000),
+            Err(MemError::InvalidRequestedSize(_))
+        ));
+        assert_eq!(device.requested_size(), REGION_SIZE);
+    }
+
+    #[test]
+    fn test_plug_unplug() {
+        let mem = hotplug_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut device = activated_device(&mem, &vq);
+
+        // Plug the first two blocks.
+        let mut response = MemResponse::default();
+        check_metric_after_block!(METRICS.mem.plug_count, 1, {
+            response = send_request(
+                &mut device,
+                &mem,
+                &vq,
+                0,
+                request(VIRTIO_MEM_REQ_PLUG, REGION_ADDR, 2),
+            );
+        });
+        assert_eq!(response, MemResponse::new(VIRTIO_MEM_RESP_ACK));
+        assert_eq!(device.plugged_size(), 2 * BLOCK_SIZE);
+        assert_eq!(device.plugged_blocks, vec![true, true, false, false]);
+
+        // The guest can't plug more than requested.
+        response = send_request(
+            &mut device,
+            &mem,
+            &vq,
+            2,
+            request(VIRTIO_MEM_REQ_PLUG, REGION_ADDR + 2 * BLOCK_SIZE, 1),
+        );
+        assert_eq!(response, MemResponse::new(VIRTIO_MEM_RESP_NACK));
+
+        // Plugging plugged blocks, or blocks out of the region, is an error.
+        response = send_request(
+            &mut device,
+            &mem,
+            &vq,
+            4,
+            request(VIRTIO_MEM_REQ_PLUG, REGION_ADDR + BLOCK_SIZE, 1),
+        );
+        assert_eq!(response, MemResponse::new(VIRTIO_MEM_RESP_ERROR));
+        response = send_request(
+            &mut device,
+            &mem,
+            &vq,
+            6,
+            request(VIRTIO_MEM_REQ_PLUG, REGION_ADDR + 3 * BLOCK_SIZE, 2),
+        );
+        assert_eq!(response, MemResponse::new(VIRTIO_MEM_RESP_ERROR));
+        response = send_request(
+            &mut device,
+            &mem,
+            &vq,
+            8,
+            request(VIRTIO_MEM_REQ_PLUG, REGION_ADDR + 0x1000, 1),
+        );
+        assert_eq!(response, MemResponse::new(VIRTIO_MEM_RESP_ERROR));
+        assert_eq!(device.plugged_size(), 2 * BLOCK_SIZE);
+
+        // Unplugging a block discards its memory.
+        mem.write_obj(0xaa_u8, GuestAddress(REGION_ADDR + BLOCK_SIZE))
+            .unwrap();
+        check_metric_after_block!(METRICS.mem.unplug_count, 1, {
+            response = send_request(
+                &mut device,
+                &mem,
+                &vq,
+                10,
+                request(VIRTIO_MEM_REQ_UNPLUG, REGION_ADDR + BLOCK_SIZE, 1),
+            );
+        });
+        assert_eq!(response, MemResponse::new(VIRTIO_MEM_RESP_ACK));
+        assert_eq!(device.plugged_size(), BLOCK_SIZE);
+        assert_eq!(device.plugged_blocks, vec![true, false, false, false]);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(REGION_ADDR + BLOCK_SIZE))
+                .unwrap(),
+            0
+        );
+
+        // Unplugging unplugged blocks is an error.
+        response = send_request(
+            &mut device,
+            &mem,
+            &vq,
+            12,
+            request(VIRTIO_MEM_REQ_UNPLUG, REGION_ADDR, 2),
+        );
+        assert_eq!(response, MemResponse::new(VIRTIO_MEM_RESP_ERROR));
+        assert_eq!(device.plugged_size(), BLOCK_SIZE);
+
+        response = send_request(
+            &mut device,
+            &mem,
+            &vq,
+            14,
+            request(VIRTIO_MEM_REQ_UNPLUG_ALL, 0, 0),
+        );
+        assert_eq!(response, MemResponse::new(VIRTIO_MEM_RESP_ACK));
+        assert_eq!(device.plugged_size(), 0);
+        assert_eq!(device.plugged_blocks, vec![false; 4]);
+    }
+
+    #[test]
+    fn test_state() {
+        let mem = hotplug_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut device = activated_device(&mem, &vq);
+        device.plugged_blocks = vec![true, false, false, false];
+
+        let response = send_request(
+            &mut device,
+            &mem,
+            &vq,
+            0,
+            request(VIRTIO_MEM_REQ_STATE, REGION_ADDR, 1),
+        );
+        assert_eq!(response, MemResponse::with_state(VIRTIO_MEM_STATE_PLUGGED));
+        let response = send_request(
+            &mut device,
+            &mem,
+            &vq,
+            2,
+            request(VIRTIO_MEM_REQ_STATE, REGION_ADDR, 2),
+        );
+        assert_eq!(response, MemResponse::with_state(VIRTIO_MEM_STATE_MIXED));
+        let response = send_request(
+            &mut device,
+            &mem,
+            &vq,
+            4,
+            request(VIRTIO_MEM_REQ_STATE, REGION_ADDR + BLOCK_SIZE, 3),
+        );
+        assert_eq!(
+            response,
+            MemResponse::with_state(VIRTIO_MEM_STATE_UNPLUGGED)
+        );
+        let response = send_request(
+            &mut device,
+            &mem,
+            &vq,
+            6,
+            request(VIRTIO_MEM_REQ_STATE, REGION_ADDR, 0),
+        );
+        assert_eq!(response, MemResponse::new(VIRTIO_MEM_RESP_ERROR));
+
+        // Unknown requests are errors.
+        let response = send_request(&mut device, &mem, &vq, 8, request(42, REGION_ADDR, 1));
+        assert_eq!(response, MemResponse::new(VIRTIO_MEM_RESP_ERROR));
+    }
+
+    #[test]
+    fn test_malformed_request() {
+        let mem = hotplug_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut device = activated_device(&mem, &vq);
+
+        // A request without a response descriptor is consumed, but not handled.
+        mem.write_obj(
+            request(VIRTIO_MEM_REQ_PLUG, REGION_ADDR, 1),
+            GuestAddress(REQUEST_ADDR),
+        )
+        .unwrap();
+        vq.dtable[0].set(REQUEST_ADDR, size_of::<MemRequest>() as u32, 0, 0);
+        vq.avail.ring[0].set(0);
+        vq.avail.idx.set(1);
+
+        check_metric_after_block!(METRICS.mem.event_fails, 1, device.process_queue().unwrap());
+        assert_eq!(vq.used.idx.get(), 1);
+        assert_eq!(vq.used.ring[0].get().len, 0);
+        assert_eq!(device.plugged_size(), 0);
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements a virtio-mem device, which lets the guest plug and unplug memory blocks of a
+//! reserved region of guest memory, as requested by the host.
+
+pub mod device;
+pub mod event_handler;
+pub mod persist;
+
+use vm_memory::GuestMemoryError;
+
+pub use self::device::VirtioMem;
+pub use self::event_handler::*;
+
+/// Device ID used in MMIO device identification.
+/// Because the virtio-mem device is unique per-vm, this ID can be hardcoded.
+pub const MEM_DEV_ID: &str = "mem";
+pub const MEM_QUEUE_SIZE: u16 = 128;
+pub const MEM_NUM_QUEUES: usize = 1;
+pub const MEM_QUEUE_SIZES: &[u16] = &[MEM_QUEUE_SIZE];
+/// The smallest block of memory that can be plugged or unplugged: 2 MiB.
+pub const MEM_MIN_BLOCK_SIZE: u64 = 2 << 20;
+
+// The feature bitmap for virtio-mem.
+// The driver won't access unplugged memory.
+const VIRTIO_MEM_F_UNPLUGGED_INACCESSIBLE: u32 = 1;
+
+// The request types.
+const VIRTIO_MEM_REQ_PLUG: u16 = 0;
+const VIRTIO_MEM_REQ_UNPLUG: u16 = 1;
+const VIRTIO_MEM_REQ_UNPLUG_ALL: u16 = 2;
+const VIRTIO_MEM_REQ_STATE: u16 = 3;
+
+// The response types.
+const VIRTIO_MEM_RESP_ACK: u16 = 0;
+const VIRTIO_MEM_RESP_NACK: u16 = 1;
+const VIRTIO_MEM_RESP_ERROR: u16 = 3;
+
+// The states of a range of memory blocks, in response to a state request.
+const VIRTIO_MEM_STATE_PLUGGED: u16 = 0;
+const VIRTIO_MEM_STATE_UNPLUGGED: u16 = 1;
+const VIRTIO_MEM_STATE_MIXED: u16 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Activation error.
+    Activate(super::ActivateError),
+    /// Device not activated yet.
+    DeviceNotActive,
+    /// EventFd error.
+    EventFd(std::io::Error),
+    /// Guest gave us bad memory addresses.
+    GuestMemory(GuestMemoryError),
+    /// Received error while sending an interrupt.
+    InterruptError(std::io::Error),
+    /// The region is not aligned to, or sized in, whole memory blocks.
+    InvalidRegion,
+    /// The requested size is larger than the region, or not a multiple of the block size.
+    InvalidRequestedSize(u64),
+    /// Guest gave us a malformed descriptor.
+    MalformedDescriptor,
+    /// Error restoring the virtio-mem device queues.
+    QueueRestoreError,
+    /// Error while processing the virt queues.
+    Queue(super::QueueError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
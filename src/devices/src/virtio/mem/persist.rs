@@ -0,0 +1,169 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines the structures needed for saving/restoring virtio-mem devices.
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+use snapshot::Persist;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+
+use super::*;
+use crate::virtio::mem::device::ConfigSpace;
+use crate::virtio::persist::VirtioDeviceState;
+use crate::virtio::{DeviceState, TYPE_MEM};
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct MemConfigSpaceState {
+    block_size: u64,
+    addr: u64,
+    region_size: u64,
+    plugged_size: u64,
+    requested_size: u64,
+}
+
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct MemState {
+    config_space: MemConfigSpaceState,
+    plugged_blocks: Vec<bool>,
+    virtio_state: VirtioDeviceState,
+}
+
+impl MemState {
+    /// Returns the size of the hot-pluggable memory region.
+    pub fn region_size(&self) -> u64 {
+        self.config_space.region_size
+    }
+
+    /// Returns the size of memory the guest was requested to plug.
+    pub fn requested_size(&self) -> u64 {
+        self.config_space.requested_size
+    }
+}
+
+pub struct MemConstructorArgs {
+    pub mem: GuestMemoryMmap,
+}
+
+impl Persist<'_> for VirtioMem {
+    type State = MemState;
+    type ConstructorArgs = MemConstructorArgs;
+    type Error = super::Error;
+
+    fn save(&self) -> Self::State {
+        MemState {
+            config_space: MemConfigSpaceState {
+                block_size: self.config_space.block_size,
+                addr: self.config_space.addr,
+                region_size: self.config_space.region_size,
+                plugged_size: self.config_space.plugged_size,
+                requested_size: self.config_space.requested_size,
+            },
+            plugged_blocks: self.plugged_blocks.clone(),
+            virtio_state: VirtioDeviceState::from_device(self),
+        }
+    }
+
+    fn restore(
+        constructor_args: Self::ConstructorArgs,
+        state: &Self::State,
+    ) -> std::result::Result<Self, Self::Error> {
+        let config_space = &state.config_space;
+        let mut mem = VirtioMem::new(
+            GuestAddress(config_space.addr),
+            config_space.region_size,
+            config_space.block_size,
+            config_space.requested_size,
+            true,
+        )?;
+        if state.plugged_blocks.len() != mem.plugged_blocks.len() {
+            return Err(Self::Error::InvalidRegion);
+        }
+
+        mem.queues = state
+            .virtio_state
+            .build_queues_checked(
+                &constructor_args.mem,
+                TYPE_MEM,
+                MEM_NUM_QUEUES,
+                MEM_QUEUE_SIZE,
+            )
+            .map_err(|_| Self::Error::QueueRestoreError)?;
+        mem.irq_trigger.irq_status =
+            Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
+        mem.avail_features = state.virtio_state.avail_features;
+        mem.acked_features = state.virtio_state.acked_features;
+        mem.config_space = ConfigSpace {
+            plugged_size: config_space.plugged_size,
+            ..mem.config_space
+        };
+        mem.plugged_blocks = state.plugged_blocks.clone();
+
+        if state.virtio_state.activated {
+            mem.device_state = DeviceState::Activated(constructor_args.mem);
+        }
+
+        Ok(mem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::virtio::device::VirtioDevice;
+    use crate::virtio::test_utils::default_mem;
+
+    #[test]
+    fn test_persistence() {
+        let guest_mem = default_mem();
+        let mut mem = vec![0; 4096];
+        let version_map = VersionMap::new();
+
+        // Create and save the virtio-mem device, with a block plugged.
+        let mut device = VirtioMem::new(
+            GuestAddress(1 << 32),
+            4 * MEM_MIN_BLOCK_SIZE,
+            MEM_MIN_BLOCK_SIZE,
+            2 * MEM_MIN_BLOCK_SIZE,
+            false,
+        )
+        .unwrap();
+        device.plugged_blocks[1] = true;
+        device.config_space.plugged_size = MEM_MIN_BLOCK_SIZE;
+
+        let state = <VirtioMem as Persist>::save(&device);
+        assert_eq!(state.region_size(), 4 * MEM_MIN_BLOCK_SIZE);
+        assert_eq!(state.requested_size(), 2 * MEM_MIN_BLOCK_SIZE);
+        state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+
+        // Deserialize and restore the virtio-mem device.
+        let restored_device = VirtioMem::restore(
+            MemConstructorArgs { mem: guest_mem },
+            &MemState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(restored_device.device_type(), TYPE_MEM);
+        assert!(restored_device.restored);
+
+        assert_eq!(restored_device.acked_features, device.acked_features);
+        assert_eq!(restored_device.avail_features, device.avail_features);
+        assert_eq!(restored_device.config_space, device.config_space);
+        assert_eq!(restored_device.plugged_blocks, device.plugged_blocks);
+        assert_eq!(restored_device.queues(), device.queues());
+        assert_eq!(
+            restored_device.interrupt_status().load(Ordering::Relaxed),
+            device.interrupt_status().load(Ordering::Relaxed)
+        );
+        assert_eq!(restored_device.is_activated(), device.is_activated());
+    }
+}
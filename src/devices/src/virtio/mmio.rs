@@ -153,6 +153,20 @@ impl MmioTransport {
         }
     }
 
+    /// Lets the driver know that the device is about to be unplugged, by flagging the device as
+    /// needing a reset and raising a configuration change interrupt.
+    ///
+    /// Nothing is signaled if no driver has finished initializing the device.
+    pub fn notify_removal(&mut self) -> std::io::Result<()> {
+        if !self.check_device_status(device_status::DRIVER_OK, 0) {
+            return Ok(());
+        }
+        self.device_status |= device_status::DEVICE_NEEDS_RESET;
+        self.interrupt_status
+            .fetch_or(VIRTIO_MMIO_INT_CONFIG as usize, Ordering::SeqCst);
+        self.locked_device().interrupt_evt().write(1)
+    }
+
     /// Update device status according to the state machine defined by VirtIO Spec 1.0.
     /// Please refer to VirtIO Spec 1.0, section 2.1.1 and 3.1.1.
     ///
@@ -325,6 +339,66 @@ impl BusDevice for MmioTransport {
     }
 }
 
+/// A virtio-mmio slot reserved at boot, in which a device can be plugged after the guest has
+/// started.
+///
+/// The vCPUs work on their own copies of the MMIO bus, which aren't updated once the vCPUs are
+/// running. The slot is registered on the bus at boot instead, and forwards all accesses to the
+/// transport currently plugged in. An empty slot reports a virtio device ID of 0, which guest
+/// drivers treat as a placeholder for a device that isn't there.
+#[derive(Debug, Default)]
+pub struct MmioHotplugSlot {
+    transport: Option<Arc<Mutex<MmioTransport>>>,
+}
+
+impl MmioHotplugSlot {
+    /// Constructs an empty hot-plug slot.
+    pub fn new() -> MmioHotplugSlot {
+        MmioHotplugSlot::default()
+    }
+
+    /// Gets the transport plugged in the slot, if any.
+    pub fn transport(&self) -> Option<&Arc<Mutex<MmioTransport>>> {
+        self.transport.as_ref()
+    }
+
+    /// Plugs `transport` in the slot, or empties the slot if `None`. Returns the previously
+    /// plugged transport.
+    pub fn set_transport(
+        &mut self,
+        transport: Option<Arc<Mutex<MmioTransport>>>,
+    ) -> Option<Arc<Mutex<MmioTransport>>> {
+        std::mem::replace(&mut self.transport, transport)
+    }
+}
+
+impl BusDevice for MmioHotplugSlot {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if let Some(transport) = self.transport.as_ref() {
+            transport.lock().expect("Poisoned lock").read(offset, data);
+            return;
+        }
+
+        if data.len() != 4 {
+            return;
+        }
+        let v = match offset {
+            0x0 => MMIO_MAGIC_VALUE,
+            0x04 => MMIO_VERSION,
+            // Device ID 0 marks the slot as empty.
+            _ => 0,
+        };
+        byte_order::write_le_u32(data, v);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        // Writes to an empty slot are dropped.
+        if let Some(transport) = self.transport.as_ref() {
+            transport.lock().expect("Poisoned lock").write(offset, data);
+        }
+    }
+}
+
 #[cfg(test)]
 pub(crate) mod tests {
     use utils::byte_order::{read_le_u32, write_le_u32};
@@ -850,6 +924,71 @@ pub(crate) mod tests {
         assert!(d.locked_device().is_activated());
     }
 
+    #[test]
+    fn test_notify_removal() {
+        let m =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x1000)], false)
+                .unwrap();
+        let mut d = MmioTransport::new(m, Arc::new(Mutex::new(DummyDevice::new())));
+
+        // Nothing is signaled before the driver is done with the device initialization.
+        d.notify_removal().unwrap();
+        assert_eq!(d.device_status, device_status::INIT);
+        assert_eq!(d.interrupt_status.load(Ordering::SeqCst), 0);
+
+        activate_device(&mut d);
+        d.notify_removal().unwrap();
+        assert_ne!(d.device_status & device_status::DEVICE_NEEDS_RESET, 0);
+        assert_eq!(
+            d.interrupt_status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_INT_CONFIG as usize
+        );
+        assert_eq!(d.locked_device().interrupt_evt().read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_hotplug_slot() {
+        let m =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x1000)], false)
+                .unwrap();
+        let mut slot = MmioHotplugSlot::new();
+        let mut buf = vec![0xff; 4];
+
+        // An empty slot only exposes the virtio-mmio identification registers.
+        assert!(slot.transport().is_none());
+        slot.read(0x0, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), MMIO_MAGIC_VALUE);
+        slot.read(0x04, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), MMIO_VERSION);
+        slot.read(0x08, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 0);
+        write_le_u32(&mut buf[..], device_status::ACKNOWLEDGE);
+        slot.write(0x70, &buf[..]);
+        slot.read(0x70, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 0);
+
+        // Accesses are forwarded to the plugged transport.
+        let transport = Arc::new(Mutex::new(MmioTransport::new(
+            m,
+            Arc::new(Mutex::new(DummyDevice::new())),
+        )));
+        assert!(slot.set_transport(Some(transport.clone())).is_none());
+        slot.read(0x08, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 123);
+        write_le_u32(&mut buf[..], device_status::ACKNOWLEDGE);
+        slot.write(0x70, &buf[..]);
+        assert_eq!(
+            transport.lock().unwrap().device_status,
+            device_status::ACKNOWLEDGE
+        );
+
+        // Unplugging the transport empties the slot.
+        assert!(Arc::ptr_eq(&slot.set_transport(None).unwrap(), &transport));
+        assert!(slot.transport().is_none());
+        slot.read(0x08, &mut buf[..]);
+        assert_eq!(read_le_u32(&buf[..]), 0);
+    }
+
     #[test]
     fn test_get_avail_features() {
         let dummy_dev = DummyDevice::new();
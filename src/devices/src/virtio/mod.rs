@@ -12,20 +12,24 @@ use std::io::Error as IOError;
 pub mod balloon;
 pub mod block;
 pub mod device;
+pub mod mem;
 mod mmio;
 pub mod net;
 pub mod persist;
 mod queue;
 pub mod test_utils;
+pub mod vhost_user;
 pub mod vsock;
 
 pub use self::balloon::*;
 pub use self::block::*;
 pub use self::device::*;
+pub use self::mem::*;
 pub use self::mmio::*;
 pub use self::net::*;
 pub use self::persist::*;
 pub use self::queue::*;
+pub use self::vhost_user::*;
 pub use self::vsock::*;
 
 /// When the driver initializes the device, it lets the device know about the
@@ -43,6 +47,7 @@ mod device_status {
     pub const FAILED: u32 = 128;
     pub const FEATURES_OK: u32 = 8;
     pub const DRIVER_OK: u32 = 4;
+    pub const DEVICE_NEEDS_RESET: u32 = 64;
 }
 
 /// Types taken from linux/virtio_ids.h.
@@ -50,6 +55,7 @@ mod device_status {
 pub const TYPE_NET: u32 = 1;
 pub const TYPE_BLOCK: u32 = 2;
 pub const TYPE_BALLOON: u32 = 5;
+pub const TYPE_MEM: u32 = 24;
 
 /// Offset from the base MMIO address of a virtio device used by the guest to notify the device of
 /// queue events.
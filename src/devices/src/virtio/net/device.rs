@@ -8,32 +8,44 @@
 #[cfg(not(test))]
 use std::io;
 use std::io::{Read, Write};
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::num::Wrapping;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
 use std::{cmp, mem, result};
 
 use dumbo::pdu::ethernet::EthernetFrame;
-use libc::EAGAIN;
+use libc::{EAGAIN, EINVAL};
 use logger::{error, warn, IncMetric, METRICS};
 use mmds::data_store::Mmds;
+use mmds::dhcp::DhcpServer;
 use mmds::ns::MmdsNetworkStack;
-use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
+use rate_limiter::{BucketUpdate, RateLimiter, TokenBucket, TokenType};
+use serde::{Deserialize, Serialize};
 use utils::eventfd::EventFd;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
 use virtio_gen::virtio_net::{
-    virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
+    virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+    VIRTIO_NET_ERR, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM,
     VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
-    VIRTIO_NET_F_MAC,
+    VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ, VIRTIO_NET_OK,
 };
 use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
-use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
+    GuestMemoryRegion,
+};
 
-use crate::virtio::net::tap::Tap;
+use crate::virtio::net::tap::{Error as TapError, Tap};
 #[cfg(test)]
 use crate::virtio::net::test_utils::Mocks;
+use crate::virtio::net::tx_filter::{TxFilter, Verdict};
+use crate::virtio::net::user::{UserNetwork, UserNetworkConfig};
+use crate::virtio::net::vhost::{VhostNet, VHOST_RX_INDEX, VHOST_TX_INDEX};
 use crate::virtio::net::{
-    Error, NetQueue, Result, MAX_BUFFER_SIZE, QUEUE_SIZE, QUEUE_SIZES, RX_INDEX, TX_INDEX,
+    ctrl_queue_index, rx_queue_index, tx_queue_index, Error, Result, MAX_BUFFER_SIZE,
+    MAX_CTRL_COMMAND_LEN, MAX_QUEUE_PAIRS, QUEUE_SIZE,
 };
 use crate::virtio::{
     ActivateResult, DescriptorChain, DeviceState, IrqTrigger, IrqType, Queue, VirtioDevice,
@@ -80,31 +92,167 @@ fn init_vnet_hdr(buf: &mut [u8]) {
     }
 }
 
+// Returns a new rate limiter configured with the same buckets as `rate_limiter`.
+fn rate_limiter_like(rate_limiter: &RateLimiter) -> Result<RateLimiter> {
+    let bucket_params = |bucket: Option<&TokenBucket>| {
+        bucket.map_or((0, 0, 0), |bucket| {
+            (
+                bucket.capacity(),
+                bucket.initial_one_time_burst(),
+                bucket.refill_time_ms(),
+            )
+        })
+    };
+    let (bytes_size, bytes_burst, bytes_refill_time) = bucket_params(rate_limiter.bandwidth());
+    let (ops_size, ops_burst, ops_refill_time) = bucket_params(rate_limiter.ops());
+
+    RateLimiter::new(
+        bytes_size,
+        bytes_burst,
+        bytes_refill_time,
+        ops_size,
+        ops_burst,
+        ops_refill_time,
+    )
+    .map_err(Error::IO)
+}
+
+// Attaches the tap queues of the first `new_active` pairs and detaches the others, given that
+// the first `old_active` ones are currently attached.
+pub(crate) fn set_active_tap_queues(
+    queue_pairs: &[NetQueuePair],
+    old_active: usize,
+    new_active: usize,
+) -> std::result::Result<(), TapError> {
+    let (start, end) = (
+        cmp::min(old_active, new_active),
+        cmp::max(old_active, new_active),
+    );
+    for tap in queue_pairs[start..end]
+        .iter()
+        .filter_map(|pair| pair.link.tap())
+    {
+        tap.set_queue_enabled(new_active > old_active)?;
+    }
+
+    Ok(())
+}
+
+/// The data path of the RX/TX queue pairs of a net device.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NetBackend {
+    /// Frames are copied between the tap device and the guest memory by the VMM thread.
+    Userspace,
+    /// Frames are moved by the vhost-net kernel module, unless the device needs the userspace
+    /// path for rate limiting, MMDS or dirty page tracking.
+    VhostNet,
+    /// There is no tap device. The guest traffic goes through the user-mode network stack of
+    /// the VMM, which proxies it through host sockets.
+    User,
+}
+
+impl Default for NetBackend {
+    fn default() -> Self {
+        Self::Userspace
+    }
+}
+
 #[derive(Clone, Copy)]
+#[repr(C, packed)]
 pub struct ConfigSpace {
     pub guest_mac: [u8; MAC_ADDR_LEN],
+    pub status: u16,
+    pub max_virtqueue_pairs: u16,
 }
 
 impl Default for ConfigSpace {
     fn default() -> ConfigSpace {
         ConfigSpace {
             guest_mac: [0; MAC_ADDR_LEN],
+            status: 0,
+            max_virtqueue_pairs: 0,
         }
     }
 }
 
 unsafe impl ByteValued for ConfigSpace {}
 
-pub struct Net {
-    pub(crate) id: String,
+/// The host end of an RX/TX virtqueue pair.
+pub enum NetLink {
+    /// A queue of a tap device.
+    Tap(Tap),
+    /// The user-mode network stack.
+    User(UserNetwork),
+}
 
-    pub tap: Tap,
+impl NetLink {
+    /// Returns the tap queue, unless the pair goes through the user-mode network stack.
+    pub fn tap(&self) -> Option<&Tap> {
+        match self {
+            NetLink::Tap(tap) => Some(tap),
+            NetLink::User(_) => None,
+        }
+    }
 
-    pub(crate) avail_features: u64,
-    pub(crate) acked_features: u64,
+    /// Returns the user-mode network stack, unless the pair goes through a tap.
+    pub fn user(&self) -> Option<&UserNetwork> {
+        match self {
+            NetLink::Tap(_) => None,
+            NetLink::User(user) => Some(user),
+        }
+    }
 
-    pub(crate) queues: Vec<Queue>,
-    pub(crate) queue_evts: Vec<EventFd>,
+    // Reads a frame, prefixed by a VNET header, into `buf`. Fails with EAGAIN if there is none.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            NetLink::Tap(tap) => tap.read(buf),
+            NetLink::User(user) => {
+                let frame_buf = frame_bytes_from_buf_mut(buf)
+                    .map_err(|_| io::Error::from_raw_os_error(EINVAL))?;
+                let len = match user.write_next_frame(frame_buf) {
+                    Some(len) => len,
+                    None => {
+                        user.process_host_events();
+                        user.write_next_frame(frame_buf)
+                            .ok_or_else(|| io::Error::from_raw_os_error(EAGAIN))?
+                    }
+                };
+                init_vnet_hdr(buf);
+                Ok(vnet_hdr_len() + len.get())
+            }
+        }
+    }
+
+    // Writes a frame, prefixed by a VNET header.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            NetLink::Tap(tap) => tap.write(buf),
+            NetLink::User(user) => {
+                user.handle_guest_frame(
+                    frame_bytes_from_buf(buf).map_err(|_| io::Error::from_raw_os_error(EINVAL))?,
+                );
+                Ok(buf.len())
+            }
+        }
+    }
+}
+
+impl AsRawFd for NetLink {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            NetLink::Tap(tap) => tap.as_raw_fd(),
+            NetLink::User(user) => user.as_raw_fd(),
+        }
+    }
+}
+
+/// The host side of an RX/TX virtqueue pair: a tap queue or the user-mode network stack, along
+/// with the rate limiters and buffers used to move frames between it and the guest.
+pub struct NetQueuePair {
+    pub link: NetLink,
+    // Opened along with the device when the backend is vhost-net.
+    pub(crate) vhost: Option<VhostNet>,
 
     pub(crate) rx_rate_limiter: RateLimiter,
     pub(crate) tx_rate_limiter: RateLimiter,
@@ -116,16 +264,58 @@ pub struct Net {
 
     tx_iovec: Vec<(GuestAddress, usize)>,
     tx_frame_buf: [u8; MAX_BUFFER_SIZE],
+}
+
+impl NetQueuePair {
+    fn new(
+        link: NetLink,
+        vhost: Option<VhostNet>,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+    ) -> Self {
+        NetQueuePair {
+            link,
+            vhost,
+            rx_rate_limiter,
+            tx_rate_limiter,
+            rx_deferred_frame: false,
+            rx_bytes_read: 0,
+            rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
+            tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
+            tx_iovec: Vec::with_capacity(QUEUE_SIZE as usize),
+        }
+    }
+}
+
+pub struct Net {
+    pub(crate) id: String,
+
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+
+    pub(crate) queues: Vec<Queue>,
+    pub(crate) queue_evts: Vec<EventFd>,
+
+    pub(crate) queue_pairs: Vec<NetQueuePair>,
+    // Number of queue pairs the driver is currently using. Only the tap queues of these pairs
+    // are attached to the host interface.
+    pub(crate) active_queue_pairs: usize,
+
+    pub(crate) backend: NetBackend,
+    // Whether the queue pairs are served by vhost-net rather than by the VMM thread.
+    pub(crate) vhost_active: bool,
 
     pub(crate) irq_trigger: IrqTrigger,
 
     pub(crate) config_space: ConfigSpace,
     pub(crate) guest_mac: Option<MacAddr>,
+    pub(crate) tx_filter: TxFilter,
 
     pub(crate) device_state: DeviceState,
     pub(crate) activate_evt: EventFd,
 
     pub mmds_ns: Option<MmdsNetworkStack>,
+    pub dhcp_server: Option<DhcpServer>,
 
     #[cfg(test)]
     pub(crate) mocks: Mocks,
@@ -133,33 +323,72 @@ pub struct Net {
 
 impl Net {
     /// Create a new virtio network device with the given TAP interface.
+    ///
+    /// When `num_queue_pairs` is greater than 1, the TAP interface is opened in multi-queue mode
+    /// and each RX/TX queue pair gets its own TAP queue. The given rate limiters are used by the
+    /// first pair, while each of the others gets its own rate limiter with the same configuration.
+    ///
+    /// With the `VhostNet` backend, a vhost-net instance is opened for each queue pair, to which
+    /// the pair is handed over on activation. With the `User` backend, no TAP interface is used:
+    /// the single queue pair goes through the user-mode network stack, and `tap_if_name` has to
+    /// be empty.
     pub fn new_with_tap(
         id: String,
         tap_if_name: String,
         guest_mac: Option<&MacAddr>,
         rx_rate_limiter: RateLimiter,
         tx_rate_limiter: RateLimiter,
+        num_queue_pairs: usize,
+        backend: NetBackend,
     ) -> Result<Self> {
-        let tap = Tap::open_named(&tap_if_name).map_err(Error::TapOpen)?;
-
-        // Set offload flags to match the virtio features below.
-        tap.set_offload(
-            net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6,
-        )
-        .map_err(Error::TapSetOffload)?;
+        if num_queue_pairs == 0 || num_queue_pairs > MAX_QUEUE_PAIRS {
+            return Err(Error::InvalidNumQueuePairs(num_queue_pairs));
+        }
 
-        let vnet_hdr_size = vnet_hdr_len() as i32;
-        tap.set_vnet_hdr_size(vnet_hdr_size)
-            .map_err(Error::TapSetVnetHdrSize)?;
+        let mut avail_features = 1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_RING_F_EVENT_IDX;
+        let links = if backend == NetBackend::User {
+            if num_queue_pairs > 1 {
+                return Err(Error::UserBackendMultiQueue);
+            }
+            if !tap_if_name.is_empty() {
+                return Err(Error::UserBackendWithTap);
+            }
+            // The user-mode network stack handles whole frames with full checksums, so no
+            // offloads are advertised.
+            vec![NetLink::User(
+                UserNetwork::new().map_err(Error::UserNetwork)?,
+            )]
+        } else {
+            let taps = if num_queue_pairs == 1 {
+                vec![Tap::open_named(&tap_if_name).map_err(Error::TapOpen)?]
+            } else {
+                Tap::open_named_multi_queue(&tap_if_name, num_queue_pairs)
+                    .map_err(Error::TapOpen)?
+            };
+
+            let vnet_hdr_size = vnet_hdr_len() as i32;
+            for tap in taps.iter() {
+                // Set offload flags to match the virtio features below.
+                tap.set_offload(
+                    net_gen::TUN_F_CSUM
+                        | net_gen::TUN_F_UFO
+                        | net_gen::TUN_F_TSO4
+                        | net_gen::TUN_F_TSO6,
+                )
+                .map_err(Error::TapSetOffload)?;
+
+                tap.set_vnet_hdr_size(vnet_hdr_size)
+                    .map_err(Error::TapSetVnetHdrSize)?;
+            }
 
-        let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
-            | 1 << VIRTIO_NET_F_CSUM
-            | 1 << VIRTIO_NET_F_GUEST_TSO4
-            | 1 << VIRTIO_NET_F_GUEST_UFO
-            | 1 << VIRTIO_NET_F_HOST_TSO4
-            | 1 << VIRTIO_NET_F_HOST_UFO
-            | 1 << VIRTIO_F_VERSION_1
-            | 1 << VIRTIO_RING_F_EVENT_IDX;
+            avail_features |= 1 << VIRTIO_NET_F_GUEST_CSUM
+                | 1 << VIRTIO_NET_F_CSUM
+                | 1 << VIRTIO_NET_F_GUEST_TSO4
+                | 1 << VIRTIO_NET_F_GUEST_UFO
+                | 1 << VIRTIO_NET_F_HOST_TSO4
+                | 1 << VIRTIO_NET_F_HOST_UFO;
+            taps.into_iter().map(NetLink::Tap).collect()
+        };
 
         let mut config_space = ConfigSpace::default();
         if let Some(mac) = guest_mac {
@@ -169,33 +398,67 @@ impl Net {
             avail_features |= 1 << VIRTIO_NET_F_MAC;
         }
 
+        // Each RX/TX pair takes two queues. Multiple pairs also need the control queue, through
+        // which the driver selects how many of them it uses.
+        let mut num_queues = 2 * num_queue_pairs;
+        if num_queue_pairs > 1 {
+            config_space.max_virtqueue_pairs = (num_queue_pairs as u16).to_le();
+            avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ;
+            num_queues += 1;
+        }
+
         let mut queue_evts = Vec::new();
         let mut queues = Vec::new();
-        for &size in QUEUE_SIZES {
+        for _ in 0..num_queues {
             queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
-            queues.push(Queue::new(size));
+            queues.push(Queue::new(QUEUE_SIZE));
+        }
+
+        let open_vhost = || match backend {
+            NetBackend::Userspace | NetBackend::User => Ok(None),
+            NetBackend::VhostNet => VhostNet::new().map(Some).map_err(Error::Vhost),
+        };
+        let mut links = links.into_iter();
+        let mut queue_pairs = Vec::with_capacity(num_queue_pairs);
+        // There is at least one link, since `num_queue_pairs` was validated above.
+        queue_pairs.push(NetQueuePair::new(
+            links.next().unwrap(),
+            open_vhost()?,
+            rx_rate_limiter,
+            tx_rate_limiter,
+        ));
+        for link in links {
+            let rx_rate_limiter = rate_limiter_like(&queue_pairs[0].rx_rate_limiter)?;
+            let tx_rate_limiter = rate_limiter_like(&queue_pairs[0].tx_rate_limiter)?;
+            queue_pairs.push(NetQueuePair::new(
+                link,
+                open_vhost()?,
+                rx_rate_limiter,
+                tx_rate_limiter,
+            ));
         }
 
+        // The driver starts by using only the first pair.
+        set_active_tap_queues(&queue_pairs, num_queue_pairs, 1).map_err(Error::TapSetQueue)?;
+
         Ok(Net {
             id,
-            tap,
             avail_features,
             acked_features: 0u64,
             queues,
             queue_evts,
-            rx_rate_limiter,
-            tx_rate_limiter,
-            rx_deferred_frame: false,
-            rx_bytes_read: 0,
-            rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
-            tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
-            tx_iovec: Vec::with_capacity(QUEUE_SIZE as usize),
+            queue_pairs,
+            active_queue_pairs: 1,
+            backend,
+            vhost_active: false,
             irq_trigger: IrqTrigger::new().map_err(Error::EventFd)?,
             device_state: DeviceState::Inactive,
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
             config_space,
             mmds_ns: None,
+            dhcp_server: None,
             guest_mac: guest_mac.copied(),
+            tx_filter: TxFilter::default(),
 
             #[cfg(test)]
             mocks: Mocks::default(),
@@ -212,9 +475,42 @@ impl Net {
         self.guest_mac.as_ref()
     }
 
-    /// Provides the host IFACE name of this net device.
+    /// Provides the rules applied to the frames sent by the guest.
+    pub fn tx_filter(&self) -> &TxFilter {
+        &self.tx_filter
+    }
+
+    /// Replaces the rules applied to the frames sent by the guest. The rules can't be enforced
+    /// by vhost-net, so they can only be enabled while the device uses the userspace data path;
+    /// enabling them before activation keeps the device on the userspace path. While the rules
+    /// check the guest MAC, the driver can't change it.
+    pub fn set_tx_filter(&mut self, tx_filter: TxFilter) -> Result<()> {
+        if tx_filter.needs_guest_mac() && self.guest_mac.is_none() {
+            return Err(Error::TxFilterWithoutGuestMac);
+        }
+        if tx_filter.is_enabled() && self.vhost_active {
+            return Err(Error::TxFilterWithVhost);
+        }
+        self.tx_filter = tx_filter;
+        Ok(())
+    }
+
+    /// Provides the host IFACE name of this net device, which is empty with the `User` backend.
     pub fn iface_name(&self) -> String {
-        self.tap.if_name_as_str().to_string()
+        self.queue_pairs[0]
+            .link
+            .tap()
+            .map_or_else(String::new, |tap| tap.if_name_as_str().to_string())
+    }
+
+    /// Provides the number of RX/TX queue pairs of this net device.
+    pub fn num_queue_pairs(&self) -> usize {
+        self.queue_pairs.len()
+    }
+
+    /// Provides the configured backend of this net device.
+    pub fn backend(&self) -> NetBackend {
+        self.backend
     }
 
     /// Provides the MmdsNetworkStack of this net device.
@@ -223,13 +519,20 @@ impl Net {
     }
 
     /// Configures the `MmdsNetworkStack` to allow device to forward MMDS requests.
-    /// If the device already supports MMDS, updates the IPv4 address.
-    pub fn configure_mmds_network_stack(&mut self, ipv4_addr: Ipv4Addr, mmds: Arc<Mutex<Mmds>>) {
+    /// If the device already supports MMDS, updates the IP addresses.
+    pub fn configure_mmds_network_stack(
+        &mut self,
+        ipv4_addr: Ipv4Addr,
+        ipv6_addr: Option<Ipv6Addr>,
+        mmds: Arc<Mutex<Mmds>>,
+    ) {
         if let Some(mmds_ns) = self.mmds_ns.as_mut() {
             mmds_ns.set_ipv4_addr(ipv4_addr);
         } else {
             self.mmds_ns = Some(MmdsNetworkStack::new_with_defaults(Some(ipv4_addr), mmds))
         }
+        // The unwrap is safe because the stack has just been set up.
+        self.mmds_ns.as_mut().unwrap().set_ipv6_addr(ipv6_addr);
     }
 
     /// Disables the `MmdsNetworkStack` to prevent device to forward MMDS requests.
@@ -237,24 +540,62 @@ impl Net {
         self.mmds_ns = None
     }
 
-    /// Provides a reference to the configured RX rate limiter.
+    /// Provides the DHCP server of this net device.
+    pub fn dhcp_server(&self) -> Option<&DhcpServer> {
+        self.dhcp_server.as_ref()
+    }
+
+    /// Sets or removes the DHCP server which answers the guest. The user-mode network stack
+    /// has its own server, and vhost-net would bypass it, so the server can only be set while the
+    /// device uses the userspace data path; setting it before activation keeps the device on the
+    /// userspace path.
+    pub fn set_dhcp_server(&mut self, dhcp_server: Option<DhcpServer>) -> Result<()> {
+        if dhcp_server.is_some() {
+            if self.backend == NetBackend::User {
+                return Err(Error::UserBackendWithDhcp);
+            }
+            if self.vhost_active {
+                return Err(Error::DhcpWithVhost);
+            }
+        }
+        self.dhcp_server = dhcp_server;
+        Ok(())
+    }
+
+    /// Provides the destinations the guest can reach through the user-mode network stack, unless
+    /// the device uses a tap.
+    pub fn user_network_config(&self) -> Option<&UserNetworkConfig> {
+        self.queue_pairs[0].link.user().map(UserNetwork::config)
+    }
+
+    /// Sets the destinations the guest can reach through the user-mode network stack.
+    pub fn set_user_network_config(&mut self, config: UserNetworkConfig) -> Result<()> {
+        match &mut self.queue_pairs[0].link {
+            NetLink::User(user) => {
+                user.set_config(config);
+                Ok(())
+            }
+            NetLink::Tap(_) => Err(Error::UserNetworkConfigWithTap),
+        }
+    }
+
+    /// Provides a reference to the configured RX rate limiter. All the queue pairs share the
+    /// same configuration, so this is the rate limiter of the first one.
     pub fn rx_rate_limiter(&self) -> &RateLimiter {
-        &self.rx_rate_limiter
+        &self.queue_pairs[0].rx_rate_limiter
     }
 
-    /// Provides a reference to the configured TX rate limiter.
+    /// Provides a reference to the configured TX rate limiter. All the queue pairs share the
+    /// same configuration, so this is the rate limiter of the first one.
     pub fn tx_rate_limiter(&self) -> &RateLimiter {
-        &self.tx_rate_limiter
+        &self.queue_pairs[0].tx_rate_limiter
     }
 
-    fn signal_used_queue(&mut self, queue_type: NetQueue) -> result::Result<(), DeviceError> {
+    fn signal_used_queue(&mut self, queue_index: usize) -> result::Result<(), DeviceError> {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
-        let queue = match queue_type {
-            NetQueue::Rx => &mut self.queues[RX_INDEX],
-            NetQueue::Tx => &mut self.queues[TX_INDEX],
-        };
+        let queue = &mut self.queues[queue_index];
 
         if queue.prepare_kick(mem) {
             self.irq_trigger
@@ -271,35 +612,37 @@ impl Net {
     // Attempts to copy a single frame into the guest if there is enough
     // rate limiting budget.
     // Returns true on successful frame delivery.
-    fn rate_limited_rx_single_frame(&mut self) -> bool {
+    fn rate_limited_rx_single_frame(&mut self, pair_index: usize) -> bool {
+        let pair = &mut self.queue_pairs[pair_index];
         // If limiter.consume() fails it means there is no more TokenType::Ops
         // budget and rate limiting is in effect.
-        if !self.rx_rate_limiter.consume(1, TokenType::Ops) {
+        if !pair.rx_rate_limiter.consume(1, TokenType::Ops) {
             METRICS.net.rx_rate_limiter_throttled.inc();
             return false;
         }
         // If limiter.consume() fails it means there is no more TokenType::Bytes
         // budget and rate limiting is in effect.
-        if !self
+        if !pair
             .rx_rate_limiter
-            .consume(self.rx_bytes_read as u64, TokenType::Bytes)
+            .consume(pair.rx_bytes_read as u64, TokenType::Bytes)
         {
             // revert the OPS consume()
-            self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
+            pair.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
             METRICS.net.rx_rate_limiter_throttled.inc();
             return false;
         }
 
         // Attempt frame delivery.
-        let success = self.write_frame_to_guest();
+        let success = self.write_frame_to_guest(pair_index);
 
         // Undo the tokens consumption if guest delivery failed.
         if !success {
+            let pair = &mut self.queue_pairs[pair_index];
             // revert the OPS consume()
-            self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
+            pair.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
             // revert the BYTES consume()
-            self.rx_rate_limiter
-                .manual_replenish(self.rx_bytes_read as u64, TokenType::Bytes);
+            pair.rx_rate_limiter
+                .manual_replenish(pair.rx_bytes_read as u64, TokenType::Bytes);
         }
         success
     }
@@ -352,12 +695,16 @@ impl Net {
         Err(FrontendError::DescriptorChainTooSmall)
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest.
-    fn do_write_frame_to_guest(&mut self) -> std::result::Result<(), FrontendError> {
+    // Copies a single frame from the `rx_frame_buf` of the given pair into the guest.
+    fn do_write_frame_to_guest(
+        &mut self,
+        pair_index: usize,
+    ) -> std::result::Result<(), FrontendError> {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
-        let queue = &mut self.queues[RX_INDEX];
+        let pair = &self.queue_pairs[pair_index];
+        let queue = &mut self.queues[rx_queue_index(pair_index)];
         let head_descriptor = queue.pop_or_enable_notification(mem).ok_or_else(|| {
             METRICS.net.no_rx_avail_buffer.inc();
             FrontendError::EmptyQueue
@@ -366,7 +713,7 @@ impl Net {
 
         let result = Self::write_to_descriptor_chain(
             mem,
-            &self.rx_frame_buf[..self.rx_bytes_read],
+            &pair.rx_frame_buf[..pair.rx_bytes_read],
             head_descriptor,
         );
         // Mark the descriptor chain as used. If an error occurred, skip the descriptor chain.
@@ -374,7 +721,7 @@ impl Net {
             METRICS.net.rx_fails.inc();
             0
         } else {
-            self.rx_bytes_read as u32
+            pair.rx_bytes_read as u32
         };
         queue.add_used(mem, head_index, used_len).map_err(|err| {
             error!("Failed to add available descriptor {}: {}", head_index, err);
@@ -384,12 +731,12 @@ impl Net {
         result
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest. In case of an error retries
-    // the operation if possible. Returns true if the operation was successfull.
-    fn write_frame_to_guest(&mut self) -> bool {
-        let max_iterations = self.queues[RX_INDEX].actual_size();
+    // Copies a single frame from the `rx_frame_buf` of the given pair into the guest. In case of
+    // an error retries the operation if possible. Returns true if the operation was successfull.
+    fn write_frame_to_guest(&mut self, pair_index: usize) -> bool {
+        let max_iterations = self.queues[rx_queue_index(pair_index)].actual_size();
         for _ in 0..max_iterations {
-            match self.do_write_frame_to_guest() {
+            match self.do_write_frame_to_guest(pair_index) {
                 Ok(()) => return true,
                 Err(FrontendError::EmptyQueue) | Err(FrontendError::AddUsed) => {
                     return false;
@@ -404,16 +751,20 @@ impl Net {
         false
     }
 
-    // Tries to detour the frame to MMDS and if MMDS doesn't accept it, sends it on the host TAP.
+    // Tries to detour the frame to MMDS or to the DHCP server and if neither accepts it, sends it
+    // on the host link.
     //
     // `frame_buf` should contain the frame bytes in a slice of exact length.
-    // Returns whether MMDS consumed the frame.
+    // Returns whether MMDS or the DHCP server consumed the frame.
+    #[allow(clippy::too_many_arguments)]
     fn write_to_mmds_or_tap(
         mmds_ns: Option<&mut MmdsNetworkStack>,
+        dhcp_server: Option<&mut DhcpServer>,
         rate_limiter: &mut RateLimiter,
         frame_buf: &[u8],
-        tap: &mut Tap,
+        link: &mut NetLink,
         guest_mac: Option<MacAddr>,
+        tx_filter: &TxFilter,
     ) -> Result<bool> {
         let checked_frame = |frame_buf| {
             frame_bytes_from_buf(frame_buf).map_err(|err| {
@@ -422,18 +773,24 @@ impl Net {
                 err
             })
         };
+        let mut detoured = false;
         if let Some(ns) = mmds_ns {
-            if ns.detour_frame(checked_frame(frame_buf)?) {
+            detoured = ns.detour_frame(checked_frame(frame_buf)?);
+            if detoured {
                 METRICS.mmds.rx_accepted.inc();
-
-                // MMDS frames are not accounted by the rate limiter.
-                rate_limiter.manual_replenish(frame_buf.len() as u64, TokenType::Bytes);
-                rate_limiter.manual_replenish(1, TokenType::Ops);
-
-                // MMDS consumed the frame.
-                return Ok(true);
             }
         }
+        if let (false, Some(server)) = (detoured, dhcp_server) {
+            detoured = server.detour_frame(checked_frame(frame_buf)?);
+        }
+        if detoured {
+            // MMDS and DHCP frames are not accounted by the rate limiter.
+            rate_limiter.manual_replenish(frame_buf.len() as u64, TokenType::Bytes);
+            rate_limiter.manual_replenish(1, TokenType::Ops);
+
+            // MMDS or the DHCP server consumed the frame.
+            return Ok(true);
+        }
 
         // This frame goes to the TAP.
 
@@ -446,7 +803,33 @@ impl Net {
             });
         }
 
-        match tap.write(frame_buf) {
+        // Dropped frames are consumed like the sent ones, so that the guest can reuse the
+        // descriptors.
+        match tx_filter.check(checked_frame(frame_buf)?, guest_mac) {
+            Verdict::Pass => (),
+            Verdict::SpoofedMac => {
+                METRICS.net.tx_filter_spoofed_mac_drops.inc();
+                return Ok(false);
+            }
+            Verdict::Ipv4Source => {
+                METRICS.net.tx_filter_ipv4_source_drops.inc();
+                return Ok(false);
+            }
+            Verdict::SpoofedArp => {
+                METRICS.net.tx_filter_spoofed_arp_drops.inc();
+                return Ok(false);
+            }
+            Verdict::VlanTagged => {
+                METRICS.net.tx_filter_vlan_drops.inc();
+                return Ok(false);
+            }
+            Verdict::Malformed => {
+                METRICS.net.tx_malformed_frames.inc();
+                return Ok(false);
+            }
+        }
+
+        match link.write(frame_buf) {
             Ok(_) => {
                 METRICS.net.tx_bytes_count.add(frame_buf.len());
                 METRICS.net.tx_packets_count.inc();
@@ -460,32 +843,39 @@ impl Net {
         Ok(false)
     }
 
-    // We currently prioritize packets from the MMDS over regular network packets.
-    fn read_from_mmds_or_tap(&mut self) -> Result<usize> {
+    // We currently prioritize packets from the MMDS and the DHCP server over regular network
+    // packets.
+    fn read_from_mmds_or_tap(&mut self, pair_index: usize) -> Result<usize> {
         if let Some(ns) = self.mmds_ns.as_mut() {
-            if let Some(len) =
-                ns.write_next_frame(frame_bytes_from_buf_mut(&mut self.rx_frame_buf)?)
-            {
+            let rx_frame_buf = &mut self.queue_pairs[pair_index].rx_frame_buf;
+            if let Some(len) = ns.write_next_frame(frame_bytes_from_buf_mut(rx_frame_buf)?) {
                 let len = len.get();
                 METRICS.mmds.tx_frames.inc();
                 METRICS.mmds.tx_bytes.add(len);
-                init_vnet_hdr(&mut self.rx_frame_buf);
+                init_vnet_hdr(rx_frame_buf);
                 return Ok(vnet_hdr_len() + len);
             }
         }
+        if let Some(server) = self.dhcp_server.as_mut() {
+            let rx_frame_buf = &mut self.queue_pairs[pair_index].rx_frame_buf;
+            if let Some(len) = server.write_next_frame(frame_bytes_from_buf_mut(rx_frame_buf)?) {
+                init_vnet_hdr(rx_frame_buf);
+                return Ok(vnet_hdr_len() + len.get());
+            }
+        }
 
-        self.read_tap().map_err(Error::IO)
+        self.read_tap(pair_index).map_err(Error::IO)
     }
 
-    fn process_rx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_rx(&mut self, pair_index: usize) -> result::Result<(), DeviceError> {
         // Read as many frames as possible.
         loop {
-            match self.read_from_mmds_or_tap() {
+            match self.read_from_mmds_or_tap(pair_index) {
                 Ok(count) => {
-                    self.rx_bytes_read = count;
+                    self.queue_pairs[pair_index].rx_bytes_read = count;
                     METRICS.net.rx_count.inc();
-                    if !self.rate_limited_rx_single_frame() {
-                        self.rx_deferred_frame = true;
+                    if !self.rate_limited_rx_single_frame(pair_index) {
+                        self.queue_pairs[pair_index].rx_deferred_frame = true;
                         break;
                     }
                 }
@@ -510,30 +900,30 @@ impl Net {
 
         // At this point we processed as many Rx frames as possible.
         // We have to wake the guest if at least one descriptor chain has been used.
-        self.signal_used_queue(NetQueue::Rx)
+        self.signal_used_queue(rx_queue_index(pair_index))
     }
 
     // Process the deferred frame first, then continue reading from tap.
-    fn handle_deferred_frame(&mut self) -> result::Result<(), DeviceError> {
-        if self.rate_limited_rx_single_frame() {
-            self.rx_deferred_frame = false;
+    fn handle_deferred_frame(&mut self, pair_index: usize) -> result::Result<(), DeviceError> {
+        if self.rate_limited_rx_single_frame(pair_index) {
+            self.queue_pairs[pair_index].rx_deferred_frame = false;
             // process_rx() was interrupted possibly before consuming all
             // packets in the tap; try continuing now.
-            return self.process_rx();
+            return self.process_rx(pair_index);
         }
 
-        self.signal_used_queue(NetQueue::Rx)
+        self.signal_used_queue(rx_queue_index(pair_index))
     }
 
-    fn resume_rx(&mut self) -> result::Result<(), DeviceError> {
-        if self.rx_deferred_frame {
-            self.handle_deferred_frame()
+    fn resume_rx(&mut self, pair_index: usize) -> result::Result<(), DeviceError> {
+        if self.queue_pairs[pair_index].rx_deferred_frame {
+            self.handle_deferred_frame(pair_index)
         } else {
             Ok(())
         }
     }
 
-    fn process_tx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_tx(&mut self, pair_index: usize) -> result::Result<(), DeviceError> {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
@@ -543,12 +933,13 @@ impl Net {
         // with the MMDS network stack.
         let mut process_rx_for_mmds = false;
         let mut used_any = false;
-        let tx_queue = &mut self.queues[TX_INDEX];
+        let tx_queue = &mut self.queues[tx_queue_index(pair_index)];
+        let pair = &mut self.queue_pairs[pair_index];
 
         while let Some(head) = tx_queue.pop_or_enable_notification(mem) {
             // If limiter.consume() fails it means there is no more TokenType::Ops
             // budget and rate limiting is in effect.
-            if !self.tx_rate_limiter.consume(1, TokenType::Ops) {
+            if !pair.tx_rate_limiter.consume(1, TokenType::Ops) {
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
                 tx_queue.undo_pop();
@@ -560,25 +951,25 @@ impl Net {
             let mut read_count = 0;
             let mut next_desc = Some(head);
 
-            self.tx_iovec.clear();
+            pair.tx_iovec.clear();
             while let Some(desc) = next_desc {
                 if desc.is_write_only() {
-                    self.tx_iovec.clear();
+                    pair.tx_iovec.clear();
                     break;
                 }
-                self.tx_iovec.push((desc.addr, desc.len as usize));
+                pair.tx_iovec.push((desc.addr, desc.len as usize));
                 read_count += desc.len as usize;
                 next_desc = desc.next_descriptor();
             }
 
             // If limiter.consume() fails it means there is no more TokenType::Bytes
             // budget and rate limiting is in effect.
-            if !self
+            if !pair
                 .tx_rate_limiter
                 .consume(read_count as u64, TokenType::Bytes)
             {
                 // revert the OPS consume()
-                self.tx_rate_limiter.manual_replenish(1, TokenType::Ops);
+                pair.tx_rate_limiter.manual_replenish(1, TokenType::Ops);
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
                 tx_queue.undo_pop();
@@ -590,11 +981,11 @@ impl Net {
             // Copy buffer from across multiple descriptors.
             // TODO(performance - Issue #420): change this to use `writev()` instead of `write()`
             // and get rid of the intermediate buffer.
-            for (desc_addr, desc_len) in self.tx_iovec.drain(..) {
-                let limit = cmp::min((read_count + desc_len) as usize, self.tx_frame_buf.len());
+            for (desc_addr, desc_len) in pair.tx_iovec.drain(..) {
+                let limit = cmp::min((read_count + desc_len) as usize, pair.tx_frame_buf.len());
 
                 let read_result = mem.read_slice(
-                    &mut self.tx_frame_buf[read_count..limit as usize],
+                    &mut pair.tx_frame_buf[read_count..limit as usize],
                     desc_addr,
                 );
                 match read_result {
@@ -617,13 +1008,15 @@ impl Net {
 
             let frame_consumed_by_mmds = Self::write_to_mmds_or_tap(
                 self.mmds_ns.as_mut(),
-                &mut self.tx_rate_limiter,
-                &self.tx_frame_buf[..read_count],
-                &mut self.tap,
+                self.dhcp_server.as_mut(),
+                &mut pair.tx_rate_limiter,
+                &pair.tx_frame_buf[..read_count],
+                &mut pair.link,
                 self.guest_mac,
+                &self.tx_filter,
             )
             .unwrap_or(false);
-            if frame_consumed_by_mmds && !self.rx_deferred_frame {
+            if frame_consumed_by_mmds && !pair.rx_deferred_frame {
                 // MMDS consumed this frame/request, let's also try to process the response.
                 process_rx_for_mmds = true;
             }
@@ -638,102 +1031,233 @@ impl Net {
             METRICS.net.no_tx_avail_buffer.inc();
         }
 
-        self.signal_used_queue(NetQueue::Tx)?;
+        self.signal_used_queue(tx_queue_index(pair_index))?;
+
+        // An incoming frame for the MMDS may trigger the transmission of a new message. The same
+        // goes for the user-mode network stack, which answers ARP and DHCP requests right away.
+        let pair = &self.queue_pairs[pair_index];
+        let process_rx_for_user =
+            used_any && !pair.rx_deferred_frame && matches!(pair.link, NetLink::User(_));
+        if process_rx_for_mmds || process_rx_for_user {
+            self.process_rx(pair_index)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Handles a control command and returns the ack to be written back to the driver.
+    //
+    // The only supported command sets the number of queue pairs used by the driver.
+    fn handle_ctrl_command(
+        queue_pairs: &[NetQueuePair],
+        active_queue_pairs: &mut usize,
+        command: &[u8],
+    ) -> u8 {
+        match *command {
+            [class, cmd, pairs_lo, pairs_hi]
+                if u32::from(class) == VIRTIO_NET_CTRL_MQ
+                    && u32::from(cmd) == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET =>
+            {
+                let pairs = usize::from(u16::from_le_bytes([pairs_lo, pairs_hi]));
+                if pairs == 0 || pairs > queue_pairs.len() {
+                    error!("Invalid number of queue pairs requested: {}", pairs);
+                    return VIRTIO_NET_ERR as u8;
+                }
+                if let Err(err) = set_active_tap_queues(queue_pairs, *active_queue_pairs, pairs) {
+                    error!("Failed to set the number of tap queues: {:?}", err);
+                    return VIRTIO_NET_ERR as u8;
+                }
+                *active_queue_pairs = pairs;
+                VIRTIO_NET_OK as u8
+            }
+            _ => {
+                warn!("Unsupported control command: {:?}", command);
+                VIRTIO_NET_ERR as u8
+            }
+        }
+    }
+
+    fn process_ctrl_queue(&mut self) -> result::Result<(), DeviceError> {
+        // This is safe since we checked in the event handler that the device is activated.
+        let mem = self.device_state.mem().unwrap();
 
-        // An incoming frame for the MMDS may trigger the transmission of a new message.
-        if process_rx_for_mmds {
-            self.process_rx()
+        let ctrl_queue = &mut self.queues[ctrl_queue_index(self.queue_pairs.len())];
+        let mut used_any = false;
+
+        while let Some(head) = ctrl_queue.pop_or_enable_notification(mem) {
+            let head_index = head.index;
+            let mut command = Vec::new();
+            let mut command_valid = true;
+            let mut ack_addr = None;
+            let mut next_desc = Some(head);
+
+            // The command is made of the driver readable descriptors, while the ack goes into
+            // the last device writable one.
+            while let Some(desc) = next_desc {
+                if desc.is_write_only() {
+                    ack_addr = Some(desc.addr);
+                } else if command_valid {
+                    let offset = command.len();
+                    let len = offset + desc.len as usize;
+                    if len > MAX_CTRL_COMMAND_LEN {
+                        error!("Control command too long: at least {} bytes", len);
+                        command_valid = false;
+                    } else {
+                        command.resize(len, 0);
+                        if let Err(err) = mem.read_slice(&mut command[offset..], desc.addr) {
+                            error!("Failed to read control command: {:?}", err);
+                            command_valid = false;
+                        }
+                    }
+                }
+                next_desc = desc.next_descriptor();
+            }
+
+            let ack = if command_valid {
+                Self::handle_ctrl_command(&self.queue_pairs, &mut self.active_queue_pairs, &command)
+            } else {
+                VIRTIO_NET_ERR as u8
+            };
+            if ack != VIRTIO_NET_OK as u8 {
+                METRICS.net.ctrl_fails.inc();
+            }
+            // The ack is a single byte.
+            let used_len = match ack_addr {
+                Some(addr) if mem.write_obj(ack, addr).is_ok() => 1,
+                _ => {
+                    error!("Failed to write the control command ack");
+                    0
+                }
+            };
+
+            ctrl_queue
+                .add_used(mem, head_index, used_len)
+                .map_err(DeviceError::QueueError)?;
+            used_any = true;
+        }
+
+        if used_any {
+            self.signal_used_queue(ctrl_queue_index(self.queue_pairs.len()))
         } else {
             Ok(())
         }
     }
 
-    /// Updates the parameters for the rate limiters
+    /// Updates the parameters for the rate limiters of all the queue pairs. The kernel doesn't
+    /// enforce them, so no bucket can be set while the queue pairs are served by vhost-net.
     pub fn patch_rate_limiters(
         &mut self,
         rx_bytes: BucketUpdate,
         rx_ops: BucketUpdate,
         tx_bytes: BucketUpdate,
         tx_ops: BucketUpdate,
-    ) {
-        self.rx_rate_limiter.update_buckets(rx_bytes, rx_ops);
-        self.tx_rate_limiter.update_buckets(tx_bytes, tx_ops);
+    ) -> Result<()> {
+        let sets_bucket = [&rx_bytes, &rx_ops, &tx_bytes, &tx_ops]
+            .iter()
+            .any(|update| matches!(update, BucketUpdate::Update(_)));
+        if sets_bucket && self.vhost_active {
+            return Err(Error::RateLimiterWithVhost);
+        }
+        for pair in self.queue_pairs.iter_mut() {
+            pair.rx_rate_limiter
+                .update_buckets(rx_bytes.clone(), rx_ops.clone());
+            pair.tx_rate_limiter
+                .update_buckets(tx_bytes.clone(), tx_ops.clone());
+        }
+        Ok(())
     }
 
     #[cfg(not(test))]
-    fn read_tap(&mut self) -> io::Result<usize> {
-        self.tap.read(&mut self.rx_frame_buf)
+    fn read_tap(&mut self, pair_index: usize) -> io::Result<usize> {
+        let pair = &mut self.queue_pairs[pair_index];
+        pair.link.read(&mut pair.rx_frame_buf)
     }
 
-    pub fn process_rx_queue_event(&mut self) {
+    pub fn process_rx_queue_event(&mut self, pair_index: usize) {
         METRICS.net.rx_queue_event_count.inc();
 
-        if let Err(err) = self.queue_evts[RX_INDEX].read() {
+        if let Err(err) = self.queue_evts[rx_queue_index(pair_index)].read() {
             // rate limiters present but with _very high_ allowed rate
             error!("Failed to get rx queue event: {:?}", err);
             METRICS.net.event_fails.inc();
-        } else if self.rx_rate_limiter.is_blocked() {
+        } else if self.queue_pairs[pair_index].rx_rate_limiter.is_blocked() {
             METRICS.net.rx_rate_limiter_throttled.inc();
         } else {
             // If the limiter is not blocked, resume the receiving of bytes.
-            self.resume_rx().unwrap_or_else(report_net_event_fail);
+            self.resume_rx(pair_index)
+                .unwrap_or_else(report_net_event_fail);
         }
     }
 
-    pub fn process_tap_rx_event(&mut self) {
+    pub fn process_tap_rx_event(&mut self, pair_index: usize) {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
         METRICS.net.rx_tap_event_count.inc();
 
+        let pair = &self.queue_pairs[pair_index];
         // While there are no available RX queue buffers and there's a deferred_frame
         // don't process any more incoming. Otherwise start processing a frame. In the
         // process the deferred_frame flag will be set in order to avoid freezing the
         // RX queue.
-        if self.queues[RX_INDEX].is_empty(mem) && self.rx_deferred_frame {
+        if self.queues[rx_queue_index(pair_index)].is_empty(mem) && pair.rx_deferred_frame {
             METRICS.net.no_rx_avail_buffer.inc();
             return;
         }
 
         // While limiter is blocked, don't process any more incoming.
-        if self.rx_rate_limiter.is_blocked() {
+        if pair.rx_rate_limiter.is_blocked() {
             METRICS.net.rx_rate_limiter_throttled.inc();
             return;
         }
 
-        if self.rx_deferred_frame
+        if pair.rx_deferred_frame
         // Process a deferred frame first if available. Don't read from tap again
         // until we manage to receive this deferred frame.
         {
-            self.handle_deferred_frame()
+            self.handle_deferred_frame(pair_index)
                 .unwrap_or_else(report_net_event_fail);
         } else {
-            self.process_rx().unwrap_or_else(report_net_event_fail);
+            self.process_rx(pair_index)
+                .unwrap_or_else(report_net_event_fail);
         }
     }
 
-    pub fn process_tx_queue_event(&mut self) {
+    pub fn process_tx_queue_event(&mut self, pair_index: usize) {
         METRICS.net.tx_queue_event_count.inc();
-        if let Err(err) = self.queue_evts[TX_INDEX].read() {
+        if let Err(err) = self.queue_evts[tx_queue_index(pair_index)].read() {
             error!("Failed to get tx queue event: {:?}", err);
             METRICS.net.event_fails.inc();
-        } else if !self.tx_rate_limiter.is_blocked()
+        } else if !self.queue_pairs[pair_index].tx_rate_limiter.is_blocked()
         // If the limiter is not blocked, continue transmitting bytes.
         {
-            self.process_tx().unwrap_or_else(report_net_event_fail);
+            self.process_tx(pair_index)
+                .unwrap_or_else(report_net_event_fail);
         } else {
             METRICS.net.tx_rate_limiter_throttled.inc();
         }
     }
 
-    pub fn process_rx_rate_limiter_event(&mut self) {
+    pub fn process_ctrl_queue_event(&mut self) {
+        METRICS.net.ctrl_queue_event_count.inc();
+        if let Err(err) = self.queue_evts[ctrl_queue_index(self.queue_pairs.len())].read() {
+            error!("Failed to get ctrl queue event: {:?}", err);
+            METRICS.net.event_fails.inc();
+        } else {
+            self.process_ctrl_queue()
+                .unwrap_or_else(report_net_event_fail);
+        }
+    }
+
+    pub fn process_rx_rate_limiter_event(&mut self, pair_index: usize) {
         METRICS.net.rx_event_rate_limiter_count.inc();
         // Upon rate limiter event, call the rate limiter handler
         // and restart processing the queue.
 
-        match self.rx_rate_limiter.event_handler() {
+        match self.queue_pairs[pair_index].rx_rate_limiter.event_handler() {
             Ok(_) => {
                 // There might be enough budget now to receive the frame.
-                self.resume_rx().unwrap_or_else(report_net_event_fail);
+                self.resume_rx(pair_index)
+                    .unwrap_or_else(report_net_event_fail);
             }
             Err(err) => {
                 error!("Failed to get rx rate-limiter event: {:?}", err);
@@ -742,14 +1266,15 @@ impl Net {
         }
     }
 
-    pub fn process_tx_rate_limiter_event(&mut self) {
+    pub fn process_tx_rate_limiter_event(&mut self, pair_index: usize) {
         METRICS.net.tx_rate_limiter_event_count.inc();
         // Upon rate limiter event, call the rate limiter handler
         // and restart processing the queue.
-        match self.tx_rate_limiter.event_handler() {
+        match self.queue_pairs[pair_index].tx_rate_limiter.event_handler() {
             Ok(_) => {
                 // There might be enough budget now to send the frame.
-                self.process_tx().unwrap_or_else(report_net_event_fail);
+                self.process_tx(pair_index)
+                    .unwrap_or_else(report_net_event_fail);
             }
             Err(err) => {
                 error!("Failed to get tx rate-limiter event: {:?}", err);
@@ -758,10 +1283,139 @@ impl Net {
         }
     }
 
+    pub fn process_vhost_call_event(&mut self, pair_index: usize, vhost_index: usize) {
+        METRICS.net.vhost_call_event_count.inc();
+        if let Some(vhost) = self.queue_pairs[pair_index].vhost.as_ref() {
+            if let Err(err) = vhost.call_evts[vhost_index].read() {
+                error!("Failed to get vhost call event: {:?}", err);
+                METRICS.net.event_fails.inc();
+                return;
+            }
+        }
+        // The kernel already applied the notification suppression rules.
+        self.irq_trigger
+            .trigger_irq(IrqType::Vring)
+            .unwrap_or_else(|err| {
+                METRICS.net.event_fails.inc();
+                error!("Failed to signal used queue: {:?}", err);
+            });
+    }
+
+    // Returns why the queue pairs can't be served by vhost-net, if they can't.
+    fn vhost_fallback_reason(&self, mem: &GuestMemoryMmap) -> Option<&'static str> {
+        let is_limited = |rate_limiter: &RateLimiter| {
+            rate_limiter.bandwidth().is_some() || rate_limiter.ops().is_some()
+        };
+        if self
+            .queue_pairs
+            .iter()
+            .any(|pair| is_limited(&pair.rx_rate_limiter) || is_limited(&pair.tx_rate_limiter))
+        {
+            Some("rate limiters are configured")
+        } else if self.mmds_ns.is_some() {
+            Some("MMDS requests have to be intercepted")
+        } else if self.dhcp_server.is_some() {
+            Some("DHCP requests have to be answered")
+        } else if self.tx_filter.is_enabled() {
+            Some("the TX filter has to inspect the frames")
+        } else if mem.iter().any(|region| region.bitmap().is_some()) {
+            Some("dirty page tracking doesn't record the writes of vhost-net")
+        } else {
+            None
+        }
+    }
+
+    /// Hands the queue pairs over to vhost-net, from their current state, unless the device
+    /// needs the userspace path.
+    pub(crate) fn activate_vhost(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
+        if let Some(reason) = self.vhost_fallback_reason(mem) {
+            warn!(
+                "Net {}: falling back to the userspace backend, since {}.",
+                self.id, reason
+            );
+            return Ok(());
+        }
+
+        for (pair_index, pair) in self.queue_pairs.iter().enumerate() {
+            // Only the pairs of tap devices have a vhost-net instance.
+            if let (Some(vhost), NetLink::Tap(tap)) = (pair.vhost.as_ref(), &pair.link) {
+                let (rx_index, tx_index) = (rx_queue_index(pair_index), tx_queue_index(pair_index));
+                vhost
+                    .setup(
+                        mem,
+                        self.acked_features,
+                        [&self.queues[rx_index], &self.queues[tx_index]],
+                        [&self.queue_evts[rx_index], &self.queue_evts[tx_index]],
+                        tap,
+                    )
+                    .map_err(Error::Vhost)?;
+            }
+        }
+        self.vhost_active = true;
+        Ok(())
+    }
+
+    /// Stops the vhost-net instances and returns the queues, updated with the progress the
+    /// kernel made on them. The instances are started again by `process_virtio_queues`.
+    pub(crate) fn stop_vhost(&self) -> Vec<Queue> {
+        let mut queues = self.queues.clone();
+        // This is safe since vhost-net is only used by activated devices.
+        let mem = self.device_state.mem().unwrap();
+
+        for (pair_index, pair) in self.queue_pairs.iter().enumerate() {
+            let vhost = match pair.vhost.as_ref() {
+                Some(vhost) => vhost,
+                None => continue,
+            };
+            if let Err(err) = vhost.stop() {
+                error!("Net {}: cannot stop vhost-net: {:?}", self.id, err);
+                continue;
+            }
+
+            for (vhost_index, queue_index) in &[
+                (VHOST_RX_INDEX, rx_queue_index(pair_index)),
+                (VHOST_TX_INDEX, tx_queue_index(pair_index)),
+            ] {
+                let queue = &mut queues[*queue_index];
+                match vhost.next_avail(*vhost_index) {
+                    Ok(next_avail) => queue.next_avail = Wrapping(next_avail),
+                    Err(err) => error!("Net {}: cannot get vring base: {:?}", self.id, err),
+                }
+                // The kernel keeps the index of the used ring up to date.
+                match mem.read_obj::<u16>(queue.used_ring.unchecked_add(2)) {
+                    Ok(next_used) => queue.next_used = Wrapping(next_used),
+                    Err(err) => error!("Net {}: cannot read used ring: {:?}", self.id, err),
+                }
+                queue.num_added = Wrapping(0);
+            }
+        }
+
+        queues
+    }
+
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        let _ = self.resume_rx();
-        let _ = self.process_tx();
+        if self.vhost_active {
+            // The queues may have been stopped to save the device. The kernel also has to look
+            // for buffers made available in the meantime.
+            for (pair_index, pair) in self.queue_pairs.iter().enumerate() {
+                if let (Some(vhost), NetLink::Tap(tap)) = (pair.vhost.as_ref(), &pair.link) {
+                    if let Err(err) = vhost.start(tap) {
+                        error!("Net {}: cannot start vhost-net: {:?}", self.id, err);
+                        METRICS.net.event_fails.inc();
+                    }
+                }
+                for queue_index in &[rx_queue_index(pair_index), tx_queue_index(pair_index)] {
+                    let _ = self.queue_evts[*queue_index].write(1);
+                }
+            }
+            return;
+        }
+
+        for pair_index in 0..self.active_queue_pairs {
+            let _ = self.resume_rx(pair_index);
+            let _ = self.process_tx(pair_index);
+        }
     }
 }
 
@@ -803,7 +1457,12 @@ impl VirtioDevice for Net {
     }
 
     fn read_config(&self, offset: u64, mut data: &mut [u8]) {
-        let config_space_bytes = self.config_space.as_slice();
+        // The max_virtqueue_pairs field is only valid when multiple queue pairs are offered.
+        let config_space_bytes = if self.avail_features & (1 << VIRTIO_NET_F_MQ) != 0 {
+            self.config_space.as_slice()
+        } else {
+            &self.config_space.as_slice()[..MAC_ADDR_LEN]
+        };
         let config_len = config_space_bytes.len() as u64;
         if offset >= config_len {
             error!("Failed to read config space");
@@ -821,13 +1480,20 @@ impl VirtioDevice for Net {
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
         let data_len = data.len() as u64;
-        let config_space_bytes = self.config_space.as_mut_slice();
+        // Only the MAC address can be written by the driver.
+        let config_space_bytes = &mut self.config_space.as_mut_slice()[..MAC_ADDR_LEN];
         let config_len = config_space_bytes.len() as u64;
         if offset + data_len > config_len {
             error!("Failed to write config space");
             METRICS.net.cfg_fails.inc();
             return;
         }
+        // Otherwise the guest could just take the MAC it wants to spoof.
+        if self.tx_filter.needs_guest_mac() {
+            error!("The guest MAC can't be changed while the TX filter checks it");
+            METRICS.net.cfg_fails.inc();
+            return;
+        }
 
         config_space_bytes[offset as usize..(offset + data_len) as usize].copy_from_slice(data);
         self.guest_mac = Some(MacAddr::from_bytes_unchecked(
@@ -848,6 +1514,13 @@ impl VirtioDevice for Net {
             }
         }
 
+        if self.backend == NetBackend::VhostNet {
+            if let Err(err) = self.activate_vhost(&mem) {
+                error!("Net: Cannot set up vhost-net: {:?}", err);
+                return Err(super::super::ActivateError::BadActivate);
+            }
+        }
+
         if self.activate_evt.write(1).is_err() {
             error!("Net: Cannot write to activate_evt");
             return Err(super::super::ActivateError::BadActivate);
@@ -861,12 +1534,17 @@ impl VirtioDevice for Net {
 #[macro_use]
 pub mod tests {
     use std::net::Ipv4Addr;
+    use std::os::unix::io::AsRawFd;
     use std::time::Duration;
     use std::{io, mem, thread};
 
     use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
-    use dumbo::pdu::ethernet::ETHERTYPE_ARP;
+    use dumbo::pdu::dhcp::{self, DhcpMessage, MessageType};
+    use dumbo::pdu::ethernet::{ETHERTYPE_ARP, ETHERTYPE_IPV4};
+    use dumbo::pdu::ipv4::{IPv4Packet, PROTOCOL_UDP};
+    use dumbo::pdu::udp::UdpDatagram;
     use logger::{IncMetric, METRICS};
+    use mmds::dhcp::DhcpConfig;
     use rate_limiter::{RateLimiter, TokenBucket, TokenType};
     use virtio_gen::virtio_net::{
         virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
@@ -882,26 +1560,29 @@ pub mod tests {
     };
     use crate::virtio::net::test_utils::test::TestHelper;
     use crate::virtio::net::test_utils::{
-        default_net, if_index, inject_tap_tx_frame, set_mac, NetEvent, NetQueue, ReadTapMock,
+        default_guest_mac, default_net, default_net_multi_queue, default_net_no_mmds,
+        default_net_user, if_index, inject_tap_tx_frame, set_mac, NetEvent, NetQueue, ReadTapMock,
         TapTrafficSimulator,
     };
-    use crate::virtio::net::QUEUE_SIZES;
+    use crate::virtio::test_utils::{default_mem, VirtQueue};
     use crate::virtio::{
-        Net, VirtioDevice, MAX_BUFFER_SIZE, RX_INDEX, TX_INDEX, TYPE_NET, VIRTQ_DESC_F_WRITE,
+        Net, VirtioDevice, MAX_BUFFER_SIZE, RX_INDEX, TX_INDEX, TYPE_NET, VIRTQ_DESC_F_NEXT,
+        VIRTQ_DESC_F_WRITE,
     };
 
     impl Net {
-        pub fn read_tap(&mut self) -> io::Result<usize> {
+        pub fn read_tap(&mut self, pair_index: usize) -> io::Result<usize> {
+            let pair = &mut self.queue_pairs[pair_index];
             match &self.mocks.read_tap {
                 ReadTapMock::MockFrame(frame) => {
-                    self.rx_frame_buf[..frame.len()].copy_from_slice(&frame);
+                    pair.rx_frame_buf[..frame.len()].copy_from_slice(&frame);
                     Ok(frame.len())
                 }
                 ReadTapMock::Failure => Err(io::Error::new(
                     io::ErrorKind::Other,
                     "Read tap synthetically failed.",
                 )),
-                ReadTapMock::TapFrame => self.tap.read(&mut self.rx_frame_buf),
+                ReadTapMock::TapFrame => pair.link.read(&mut pair.rx_frame_buf),
             }
         }
     }
@@ -1138,7 +1819,7 @@ pub mod tests {
         th.rxq.check_used_elem(1, 3, 0);
         th.rxq.check_used_elem(2, 4, 0);
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the frame has been written successfully to the valid Rx descriptor chain.
         th.rxq.check_used_elem(3, 5, frame.len() as u32);
         th.rxq.dtable[5].check_data(&frame);
@@ -1171,7 +1852,7 @@ pub mod tests {
         );
 
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 1);
         assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
@@ -1210,7 +1891,7 @@ pub mod tests {
         );
 
         // Check that the frames weren't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 2);
         assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
@@ -1228,7 +1909,8 @@ pub mod tests {
     fn test_tx_missing_queue_signal() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].link.tap().unwrap()));
 
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 4096, 0)]);
         th.net().queue_evts[TX_INDEX].read().unwrap();
@@ -1248,7 +1930,8 @@ pub mod tests {
     fn test_tx_writeable_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].link.tap().unwrap()));
 
         let desc_list = [(0, 100, 0), (1, 100, VIRTQ_DESC_F_WRITE), (2, 500, 0)];
         th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
@@ -1267,7 +1950,8 @@ pub mod tests {
     fn test_tx_short_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].link.tap().unwrap()));
 
         // Send an invalid frame (too small, VNET header missing).
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 1, 0)]);
@@ -1289,7 +1973,8 @@ pub mod tests {
     fn test_tx_partial_read() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].link.tap().unwrap()));
 
         // The descriptor chain is created so that the last descriptor doesn't fit in the
         // guest memory.
@@ -1317,7 +2002,8 @@ pub mod tests {
     fn test_tx_retry() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].link.tap().unwrap()));
 
         // Add invalid descriptor chain - writeable descriptor.
         th.add_desc_chain(
@@ -1357,7 +2043,8 @@ pub mod tests {
     fn test_tx_complex_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].link.tap().unwrap()));
 
         // Add gaps between the descriptor ids in order to ensure that we follow
         // the `next` field.
@@ -1385,7 +2072,8 @@ pub mod tests {
     fn test_tx_multiple_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator =
+            TapTrafficSimulator::new(if_index(th.net().queue_pairs[0].link.tap().unwrap()));
 
         // Write the first frame to the Tx queue
         let desc_list = [(0, 50, 0), (1, 100, 0), (2, 150, 0)];
@@ -1462,15 +2150,18 @@ pub mod tests {
 
         // Call the code which sends the packet to the host or MMDS.
         // Validate the frame was consumed by MMDS and that the metrics reflect that.
+        let pair = &mut net.queue_pairs[0];
         check_metric_after_block!(
             &METRICS.mmds.rx_accepted,
             1,
             assert!(Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
+                None,
+                &mut pair.tx_rate_limiter,
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                &mut pair.link,
                 Some(src_mac),
+                &TxFilter::default(),
             )
             .unwrap())
         );
@@ -1479,7 +2170,124 @@ pub mod tests {
         check_metric_after_block!(
             &METRICS.mmds.tx_frames,
             1,
-            net.read_from_mmds_or_tap().unwrap()
+            net.read_from_mmds_or_tap(0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_dhcp_server_detour_and_injection() {
+        let mut net = default_net_no_mmds();
+        let guest_mac = default_guest_mac();
+        let config = DhcpConfig {
+            ipv4_address: Ipv4Addr::new(172, 16, 0, 2),
+            netmask: Ipv4Addr::new(255, 255, 255, 252),
+            gateway: Some(Ipv4Addr::new(172, 16, 0, 1)),
+            dns_servers: vec![],
+            mtu: None,
+            lease_time: 3600,
+        };
+        net.set_dhcp_server(Some(DhcpServer::new(config.clone()).unwrap()))
+            .unwrap();
+
+        // A DISCOVER sent by the guest.
+        let mut request = [0u8; 300];
+        request[0] = dhcp::OP_BOOTREQUEST;
+        request[1] = 1;
+        request[2] = 6;
+        request[28..34].copy_from_slice(guest_mac.get_bytes());
+        request[236..244].copy_from_slice(&[99, 130, 83, 99, 53, 1, 1, 255]);
+        let mut frame_buf = [0u8; MAX_BUFFER_SIZE];
+        let frame_len = {
+            let mut eth = EthernetFrame::write_incomplete(
+                frame_bytes_from_buf_mut(&mut frame_buf).unwrap(),
+                MacAddr::parse_str("ff:ff:ff:ff:ff:ff").unwrap(),
+                guest_mac,
+                ETHERTYPE_IPV4,
+            )
+            .unwrap();
+            let mut packet = IPv4Packet::write_header(
+                eth.inner_mut().payload_mut(),
+                PROTOCOL_UDP,
+                Ipv4Addr::UNSPECIFIED,
+                Ipv4Addr::BROADCAST,
+            )
+            .unwrap();
+            let udp_len =
+                UdpDatagram::write_incomplete_datagram(packet.inner_mut().payload_mut(), &request)
+                    .unwrap()
+                    .finalize(dhcp::CLIENT_PORT, dhcp::SERVER_PORT, None)
+                    .len();
+            let packet_len = packet
+                .with_payload_len_unchecked(usize::from(udp_len), true)
+                .len();
+            vnet_hdr_len() + eth.with_payload_len_unchecked(packet_len).len()
+        };
+
+        // The request is consumed by the server, without reaching the tap.
+        let pair = &mut net.queue_pairs[0];
+        check_metric_after_block!(
+            &METRICS.net.dhcp_requests_count,
+            1,
+            assert!(Net::write_to_mmds_or_tap(
+                None,
+                net.dhcp_server.as_mut(),
+                &mut pair.tx_rate_limiter,
+                &frame_buf[..frame_len],
+                &mut pair.link,
+                Some(guest_mac),
+                &TxFilter::default(),
+            )
+            .unwrap())
+        );
+
+        // The OFFER comes before the frames of the tap.
+        let len;
+        check_metric_after_block!(
+            &METRICS.net.dhcp_replies_count,
+            1,
+            len = net.read_from_mmds_or_tap(0).unwrap()
+        );
+        let rx_frame_buf = &net.queue_pairs[0].rx_frame_buf;
+        let eth = EthernetFrame::from_bytes(&rx_frame_buf[vnet_hdr_len()..len]).unwrap();
+        assert_eq!(eth.dst_mac(), guest_mac);
+        let packet = IPv4Packet::from_bytes(eth.payload(), true).unwrap();
+        let datagram = UdpDatagram::from_bytes(packet.payload(), None).unwrap();
+        let reply = DhcpMessage::from_bytes(datagram.payload()).unwrap();
+        assert_eq!(reply.message_type(), Some(MessageType::Offer));
+        assert_eq!(reply.yiaddr(), config.ipv4_address);
+
+        // The user-mode network stack has its own server.
+        let mut net = default_net_user();
+        assert_eq!(
+            format!(
+                "{:?}",
+                net.set_dhcp_server(Some(DhcpServer::new(config).unwrap()))
+            ),
+            "Err(UserBackendWithDhcp)"
+        );
+    }
+
+    #[test]
+    fn test_user_network_config() {
+        let config = UserNetworkConfig {
+            host_loopback: true,
+            ..Default::default()
+        };
+
+        let mut net = default_net_user();
+        assert_eq!(
+            net.user_network_config(),
+            Some(&UserNetworkConfig::default())
+        );
+        net.set_user_network_config(config.clone()).unwrap();
+        assert_eq!(net.user_network_config(), Some(&config));
+
+        // Only the user-mode network stack has destinations to filter.
+        let mut net = default_net();
+        assert_eq!(net.user_network_config(), None);
+        assert_eq!(
+            format!("{:?}", net.set_user_network_config(config)),
+            "Err(UserNetworkConfigWithTap)"
         );
     }
 
@@ -1495,16 +2303,19 @@ pub mod tests {
 
         let (frame_buf, frame_len) = create_arp_request(guest_mac, guest_ip, dst_mac, dst_ip);
 
+        let pair = &mut net.queue_pairs[0];
         // Check that a legit MAC doesn't affect the spoofed MAC metric.
         check_metric_after_block!(
             &METRICS.net.tx_spoofed_mac_count,
             0,
             Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
+                None,
+                &mut pair.tx_rate_limiter,
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                &mut pair.link,
                 Some(guest_mac),
+                &TxFilter::default(),
             )
         );
 
@@ -1514,12 +2325,140 @@ pub mod tests {
             1,
             Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
+                None,
+                &mut pair.tx_rate_limiter,
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                &mut pair.link,
                 Some(not_guest_mac),
+                &TxFilter::default(),
+            )
+        );
+    }
+
+    #[test]
+    fn test_tx_filter() {
+        let mut net = default_net_no_mmds();
+
+        let guest_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
+        let not_guest_mac = MacAddr::parse_str("33:33:33:33:33:33").unwrap();
+        let guest_ip = Ipv4Addr::new(10, 1, 2, 3);
+        let dst_mac = MacAddr::parse_str("22:22:22:22:22:22").unwrap();
+        let dst_ip = Ipv4Addr::new(10, 1, 1, 1);
+
+        // The MAC rules need a guest MAC to compare with.
+        let mut no_mac_net = default_net_no_mmds();
+        no_mac_net.guest_mac = None;
+        let filter = TxFilter {
+            drop_spoofed_arp: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            format!("{:?}", no_mac_net.set_tx_filter(filter)),
+            "Err(TxFilterWithoutGuestMac)"
+        );
+        let filter = TxFilter {
+            allowed_ipv4_sources: Some(vec![guest_ip]),
+            ..Default::default()
+        };
+        no_mac_net.set_tx_filter(filter.clone()).unwrap();
+        assert_eq!(no_mac_net.tx_filter(), &filter);
+
+        // The rules can't be enforced by vhost-net.
+        net.vhost_active = true;
+        assert_eq!(
+            format!("{:?}", net.set_tx_filter(filter)),
+            "Err(TxFilterWithVhost)"
+        );
+        net.set_tx_filter(TxFilter::default()).unwrap();
+        net.vhost_active = false;
+
+        let filter = TxFilter {
+            drop_spoofed_mac: true,
+            allowed_ipv4_sources: Some(vec![guest_ip]),
+            drop_spoofed_arp: true,
+        };
+        net.guest_mac = Some(guest_mac);
+        net.set_tx_filter(filter).unwrap();
+        let write_frame = |net: &mut Net, frame_buf: &[u8]| {
+            let pair = &mut net.queue_pairs[0];
+            Net::write_to_mmds_or_tap(
+                net.mmds_ns.as_mut(),
+                None,
+                &mut pair.tx_rate_limiter,
+                frame_buf,
+                &mut pair.link,
+                net.guest_mac,
+                &net.tx_filter,
             )
+            .unwrap()
+        };
+
+        // A legit frame reaches the tap.
+        let (frame_buf, frame_len) = create_arp_request(guest_mac, guest_ip, dst_mac, dst_ip);
+        check_metric_after_block!(
+            &METRICS.net.tx_packets_count,
+            1,
+            write_frame(&mut net, &frame_buf[..frame_len])
         );
+
+        // A frame with a spoofed source MAC is dropped.
+        let (mut frame_buf, frame_len) = create_arp_request(guest_mac, guest_ip, dst_mac, dst_ip);
+        EthernetFrame::write_incomplete(
+            frame_bytes_from_buf_mut(&mut frame_buf).unwrap(),
+            dst_mac,
+            not_guest_mac,
+            ETHERTYPE_ARP,
+        )
+        .unwrap();
+        check_metric_after_block!(
+            &METRICS.net.tx_filter_spoofed_mac_drops,
+            1,
+            write_frame(&mut net, &frame_buf[..frame_len])
+        );
+
+        // An ARP request claiming another address is dropped.
+        let (frame_buf, frame_len) =
+            create_arp_request(guest_mac, Ipv4Addr::new(10, 1, 2, 4), dst_mac, dst_ip);
+        check_metric_after_block!(
+            &METRICS.net.tx_filter_spoofed_arp_drops,
+            1,
+            write_frame(&mut net, &frame_buf[..frame_len])
+        );
+        check_metric_after_block!(
+            &METRICS.net.tx_packets_count,
+            0,
+            write_frame(&mut net, &frame_buf[..frame_len])
+        );
+
+        // A VLAN tagged frame is dropped, since its payload can't be checked.
+        let (mut frame_buf, frame_len) = create_arp_request(guest_mac, guest_ip, dst_mac, dst_ip);
+        EthernetFrame::write_incomplete(
+            frame_bytes_from_buf_mut(&mut frame_buf).unwrap(),
+            dst_mac,
+            guest_mac,
+            0x8100,
+        )
+        .unwrap();
+        check_metric_after_block!(
+            &METRICS.net.tx_filter_vlan_drops,
+            1,
+            write_frame(&mut net, &frame_buf[..frame_len])
+        );
+
+        // A frame too short for an Ethernet header is dropped.
+        check_metric_after_block!(
+            &METRICS.net.tx_malformed_frames,
+            1,
+            write_frame(&mut net, &frame_buf[..vnet_hdr_len() + 2])
+        );
+
+        // The guest can't change its MAC while the rules check it.
+        check_metric_after_block!(
+            &METRICS.net.cfg_fails,
+            1,
+            net.write_config(0, not_guest_mac.get_bytes())
+        );
+        assert_eq!(net.guest_mac(), Some(&guest_mac));
     }
 
     #[test]
@@ -1554,7 +2493,7 @@ pub mod tests {
         th.net().mocks.set_read_tap(ReadTapMock::Failure);
 
         // The RX queue is empty and rx_deffered_frame is set.
-        th.net().rx_deferred_frame = true;
+        th.net().queue_pairs[0].rx_deferred_frame = true;
         check_metric_after_block!(
             &METRICS.net.no_rx_avail_buffer,
             1,
@@ -1564,7 +2503,7 @@ pub mod tests {
         // We need to set this here to false, otherwise the device will try to
         // handle a deferred frame, it will fail and will never try to read from
         // the tap.
-        th.net().rx_deferred_frame = false;
+        th.net().queue_pairs[0].rx_deferred_frame = false;
 
         // Fake an avail buffer; this time, tap reading should error out.
         th.rxq.avail.idx.set(1);
@@ -1592,7 +2531,7 @@ pub mod tests {
         );
         // The frame we read from the tap should be deferred now and
         // no frames should have been transmitted
-        assert!(th.net().rx_deferred_frame);
+        assert!(th.net().queue_pairs[0].rx_deferred_frame);
         assert_eq!(METRICS.net.rx_packets_count.count(), rx_packets_count);
 
         // Let's add a second frame, which should really have the same
@@ -1609,7 +2548,7 @@ pub mod tests {
             th.simulate_event(NetEvent::Tap)
         );
         // We should still have a deferred frame
-        assert!(th.net().rx_deferred_frame);
+        assert!(th.net().queue_pairs[0].rx_deferred_frame);
         // However, we should have delivered the first frame
         assert_eq!(METRICS.net.rx_packets_count.count(), rx_packets_count + 1);
 
@@ -1622,7 +2561,7 @@ pub mod tests {
         );
 
         // We should be done with any deferred frame
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().queue_pairs[0].rx_deferred_frame);
     }
 
     #[test]
@@ -1630,7 +2569,7 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().rx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
+        th.net().queue_pairs[0].rx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
         // There is no actual event on the rate limiter's timerfd.
         check_metric_after_block!(
             &METRICS.net.event_fails,
@@ -1644,7 +2583,7 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().tx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
+        th.net().queue_pairs[0].tx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
         th.simulate_event(NetEvent::TxRateLimiter);
         // There is no actual event on the rate limiter's timerfd.
         check_metric_after_block!(
@@ -1667,7 +2606,7 @@ pub mod tests {
             assert!(rl.consume(0x1000, TokenType::Bytes));
 
             // set this tx rate limiter to be used
-            th.net().tx_rate_limiter = rl;
+            th.net().queue_pairs[0].tx_rate_limiter = rl;
 
             // try doing TX
             // following TX procedure should fail because of bandwidth rate limiting
@@ -1677,7 +2616,7 @@ pub mod tests {
                 th.simulate_event(NetEvent::TxQueue);
 
                 // assert that limiter is blocked
-                assert!(th.net().tx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 assert_eq!(METRICS.net.tx_rate_limiter_throttled.count(), 1);
                 // make sure the data is still queued for processing
                 assert_eq!(th.txq.used.idx.get(), 0);
@@ -1706,7 +2645,7 @@ pub mod tests {
                 );
                 // This should be still blocked. We managed to send the first frame, but
                 // not enough budget for the second
-                assert!(th.net().tx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data queue advanced
                 assert_eq!(th.txq.used.idx.get(), 1);
             }
@@ -1722,7 +2661,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::TxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().tx_rate_limiter.is_blocked());
+                assert!(!th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data queue advance one more place
                 assert_eq!(th.txq.used.idx.get(), 2);
             }
@@ -1736,10 +2675,10 @@ pub mod tests {
             assert!(rl.consume(0x1000, TokenType::Bytes));
 
             // set this rx rate limiter to be used
-            th.net().rx_rate_limiter = rl;
+            th.net().queue_pairs[0].rx_rate_limiter = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().queue_pairs[0].rx_deferred_frame);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of bandwidth rate limiting
@@ -1748,9 +2687,9 @@ pub mod tests {
                 th.simulate_event(NetEvent::Tap);
 
                 // assert that limiter is blocked
-                assert!(th.net().rx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].rx_rate_limiter.is_blocked());
                 assert_eq!(METRICS.net.rx_rate_limiter_throttled.count(), 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().queue_pairs[0].rx_deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data is still queued for processing
@@ -1779,7 +2718,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::RxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().rx_rate_limiter.is_blocked());
+                assert!(!th.net().queue_pairs[0].rx_rate_limiter.is_blocked());
                 // make sure the virtio queue operation completed this time
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data queue advanced
@@ -1803,7 +2742,7 @@ pub mod tests {
             assert!(rl.consume(1, TokenType::Ops));
 
             // set this tx rate limiter to be used
-            th.net().tx_rate_limiter = rl;
+            th.net().queue_pairs[0].tx_rate_limiter = rl;
 
             // try doing TX
             // following TX procedure should fail because of ops rate limiting
@@ -1817,7 +2756,7 @@ pub mod tests {
                 );
 
                 // assert that limiter is blocked
-                assert!(th.net().tx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data is still queued for processing
                 assert_eq!(th.txq.used.idx.get(), 0);
             }
@@ -1835,7 +2774,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::TxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().tx_rate_limiter.is_blocked());
+                assert!(!th.net().queue_pairs[0].tx_rate_limiter.is_blocked());
                 // make sure the data queue advanced
                 assert_eq!(th.txq.used.idx.get(), 1);
             }
@@ -1849,10 +2788,10 @@ pub mod tests {
             assert!(rl.consume(1, TokenType::Ops));
 
             // set this rx rate limiter to be used
-            th.net().rx_rate_limiter = rl;
+            th.net().queue_pairs[0].rx_rate_limiter = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().queue_pairs[0].rx_deferred_frame);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of ops rate limiting
@@ -1865,9 +2804,9 @@ pub mod tests {
                 );
 
                 // assert that limiter is blocked
-                assert!(th.net().rx_rate_limiter.is_blocked());
+                assert!(th.net().queue_pairs[0].rx_rate_limiter.is_blocked());
                 assert!(METRICS.net.rx_rate_limiter_throttled.count() >= 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().queue_pairs[0].rx_deferred_frame);
                 // assert that no operation actually completed (limiter blocked it)
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data is still queued for processing
@@ -1904,40 +2843,87 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().rx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
-        th.net().tx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
+        th.net().queue_pairs[0].rx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
+        th.net().queue_pairs[0].tx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
 
         let rx_bytes = TokenBucket::new(1000, 1001, 1002).unwrap();
         let rx_ops = TokenBucket::new(1003, 1004, 1005).unwrap();
         let tx_bytes = TokenBucket::new(1006, 1007, 1008).unwrap();
         let tx_ops = TokenBucket::new(1009, 1010, 1011).unwrap();
 
-        th.net().patch_rate_limiters(
-            BucketUpdate::Update(rx_bytes.clone()),
-            BucketUpdate::Update(rx_ops.clone()),
-            BucketUpdate::Update(tx_bytes.clone()),
-            BucketUpdate::Update(tx_ops.clone()),
-        );
+        th.net()
+            .patch_rate_limiters(
+                BucketUpdate::Update(rx_bytes.clone()),
+                BucketUpdate::Update(rx_ops.clone()),
+                BucketUpdate::Update(tx_bytes.clone()),
+                BucketUpdate::Update(tx_ops.clone()),
+            )
+            .unwrap();
         let compare_buckets = |a: &TokenBucket, b: &TokenBucket| {
             assert_eq!(a.capacity(), b.capacity());
             assert_eq!(a.one_time_burst(), b.one_time_burst());
             assert_eq!(a.refill_time_ms(), b.refill_time_ms());
         };
-        compare_buckets(th.net().rx_rate_limiter.bandwidth().unwrap(), &rx_bytes);
-        compare_buckets(th.net().rx_rate_limiter.ops().unwrap(), &rx_ops);
-        compare_buckets(th.net().tx_rate_limiter.bandwidth().unwrap(), &tx_bytes);
-        compare_buckets(th.net().tx_rate_limiter.ops().unwrap(), &tx_ops);
-
-        th.net().patch_rate_limiters(
-            BucketUpdate::Disabled,
-            BucketUpdate::Disabled,
-            BucketUpdate::Disabled,
-            BucketUpdate::Disabled,
+        compare_buckets(
+            th.net().queue_pairs[0].rx_rate_limiter.bandwidth().unwrap(),
+            &rx_bytes,
+        );
+        compare_buckets(
+            th.net().queue_pairs[0].rx_rate_limiter.ops().unwrap(),
+            &rx_ops,
+        );
+        compare_buckets(
+            th.net().queue_pairs[0].tx_rate_limiter.bandwidth().unwrap(),
+            &tx_bytes,
         );
-        assert!(th.net().rx_rate_limiter.bandwidth().is_none());
-        assert!(th.net().rx_rate_limiter.ops().is_none());
-        assert!(th.net().tx_rate_limiter.bandwidth().is_none());
-        assert!(th.net().tx_rate_limiter.ops().is_none());
+        compare_buckets(
+            th.net().queue_pairs[0].tx_rate_limiter.ops().unwrap(),
+            &tx_ops,
+        );
+
+        th.net()
+            .patch_rate_limiters(
+                BucketUpdate::Disabled,
+                BucketUpdate::Disabled,
+                BucketUpdate::Disabled,
+                BucketUpdate::Disabled,
+            )
+            .unwrap();
+        assert!(th.net().queue_pairs[0]
+            .rx_rate_limiter
+            .bandwidth()
+            .is_none());
+        assert!(th.net().queue_pairs[0].rx_rate_limiter.ops().is_none());
+        assert!(th.net().queue_pairs[0]
+            .tx_rate_limiter
+            .bandwidth()
+            .is_none());
+        assert!(th.net().queue_pairs[0].tx_rate_limiter.ops().is_none());
+
+        // vhost-net doesn't enforce the rate limiters, but they can still be disabled.
+        th.net().vhost_active = true;
+        assert!(matches!(
+            th.net().patch_rate_limiters(
+                BucketUpdate::None,
+                BucketUpdate::None,
+                BucketUpdate::Update(tx_bytes),
+                BucketUpdate::None,
+            ),
+            Err(Error::RateLimiterWithVhost)
+        ));
+        assert!(th.net().queue_pairs[0]
+            .tx_rate_limiter
+            .bandwidth()
+            .is_none());
+        th.net()
+            .patch_rate_limiters(
+                BucketUpdate::Disabled,
+                BucketUpdate::None,
+                BucketUpdate::None,
+                BucketUpdate::None,
+            )
+            .unwrap();
+        th.net().vhost_active = false;
     }
 
     #[test]
@@ -1948,12 +2934,12 @@ pub mod tests {
 
         // Test queues count (TX and RX).
         let queues = net.queues();
-        assert_eq!(queues.len(), QUEUE_SIZES.len());
+        assert_eq!(queues.len(), 2);
         assert_eq!(queues[RX_INDEX].size, th.rxq.size());
         assert_eq!(queues[TX_INDEX].size, th.txq.size());
 
         // Test corresponding queues events.
-        assert_eq!(net.queue_events().len(), QUEUE_SIZES.len());
+        assert_eq!(net.queue_events().len(), 2);
 
         // Test interrupts.
         assert!(!&net.irq_trigger.has_pending_irq(IrqType::Vring));
@@ -1972,4 +2958,231 @@ pub mod tests {
         assert!(queues[RX_INDEX].uses_notif_suppression);
         assert!(queues[TX_INDEX].uses_notif_suppression);
     }
+
+    #[test]
+    fn test_multi_queue() {
+        let mut net = default_net_multi_queue(2);
+
+        let mq_features = 1 << VIRTIO_NET_F_MQ | 1 << VIRTIO_NET_F_CTRL_VQ;
+        assert_eq!(net.avail_features() & mq_features, mq_features);
+        assert_eq!(net.num_queue_pairs(), 2);
+        assert_eq!(net.active_queue_pairs, 1);
+        // Two queue pairs and the control queue.
+        assert_eq!(net.queues().len(), 5);
+        assert_eq!(net.queue_events().len(), 5);
+
+        // Both pairs share the same interface, through different tap queues.
+        let (pair0, pair1) = (&net.queue_pairs[0], &net.queue_pairs[1]);
+        let (tap0, tap1) = (pair0.link.tap().unwrap(), pair1.link.tap().unwrap());
+        assert_eq!(tap0.if_name, tap1.if_name);
+        assert_ne!(tap0.as_raw_fd(), tap1.as_raw_fd());
+        // The second pair starts detached.
+        tap1.set_queue_enabled(false).unwrap_err();
+
+        // The config space advertises the number of queue pairs.
+        let mut max_virtqueue_pairs = [0u8; 2];
+        net.read_config(MAC_ADDR_LEN as u64 + 2, &mut max_virtqueue_pairs);
+        assert_eq!(u16::from_le_bytes(max_virtqueue_pairs), 2);
+
+        // Each pair has its own rate limiters, all of them patched at once.
+        let bytes = TokenBucket::new(1000, 1001, 1002).unwrap();
+        net.patch_rate_limiters(
+            BucketUpdate::Update(bytes.clone()),
+            BucketUpdate::None,
+            BucketUpdate::None,
+            BucketUpdate::None,
+        )
+        .unwrap();
+        for pair in net.queue_pairs.iter() {
+            let bucket = pair.rx_rate_limiter.bandwidth().unwrap();
+            assert_eq!(bucket.capacity(), bytes.capacity());
+            assert_eq!(bucket.refill_time_ms(), bytes.refill_time_ms());
+            assert!(pair.tx_rate_limiter.bandwidth().is_none());
+        }
+        assert_ne!(
+            net.queue_pairs[0].rx_rate_limiter.as_raw_fd(),
+            net.queue_pairs[1].rx_rate_limiter.as_raw_fd()
+        );
+
+        // Invalid number of queue pairs.
+        assert!(matches!(
+            Net::new_with_tap(
+                "net-mq".to_string(),
+                "net-mq".to_string(),
+                None,
+                RateLimiter::default(),
+                RateLimiter::default(),
+                MAX_QUEUE_PAIRS + 1,
+                NetBackend::Userspace,
+            ),
+            Err(Error::InvalidNumQueuePairs(_))
+        ));
+    }
+
+    #[test]
+    fn test_rate_limiter_like() {
+        let compare_buckets = |a: &TokenBucket, b: &TokenBucket| {
+            assert_eq!(a.capacity(), b.capacity());
+            assert_eq!(a.initial_one_time_burst(), b.initial_one_time_burst());
+            assert_eq!(a.refill_time_ms(), b.refill_time_ms());
+        };
+
+        let mut rate_limiter = RateLimiter::new(10, 5, 10, 2, 1, 2).unwrap();
+        // The new rate limiter starts from the initial configuration, not the current budget.
+        assert!(rate_limiter.consume(5, TokenType::Bytes));
+        let new_rate_limiter = rate_limiter_like(&rate_limiter).unwrap();
+        compare_buckets(
+            new_rate_limiter.bandwidth().unwrap(),
+            rate_limiter.bandwidth().unwrap(),
+        );
+        compare_buckets(new_rate_limiter.ops().unwrap(), rate_limiter.ops().unwrap());
+        assert_eq!(new_rate_limiter.bandwidth().unwrap().one_time_burst(), 5);
+        assert_ne!(rate_limiter.as_raw_fd(), new_rate_limiter.as_raw_fd());
+
+        let new_rate_limiter = rate_limiter_like(&RateLimiter::default()).unwrap();
+        assert_eq!(new_rate_limiter, RateLimiter::default());
+    }
+
+    #[test]
+    fn test_ctrl_queue() {
+        let mut net = default_net_multi_queue(2);
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        net.queues[ctrl_queue_index(2)] = vq.create_queue();
+        net.activate(mem.clone()).unwrap();
+
+        let command_addr = GuestAddress(0x1000);
+        let ack_addr = GuestAddress(0x2000);
+        vq.dtable[0].set(command_addr.raw_value(), 4, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(ack_addr.raw_value(), 1, VIRTQ_DESC_F_WRITE, 0);
+
+        let send_command = |net: &mut Net, command: [u8; 4]| -> u8 {
+            mem.write_slice(&command, command_addr).unwrap();
+            let avail_idx = vq.avail.idx.get();
+            vq.avail.ring[avail_idx as usize].set(0);
+            vq.avail.idx.set(avail_idx + 1);
+            net.queue_evts[ctrl_queue_index(2)].write(1).unwrap();
+            net.process_ctrl_queue_event();
+
+            assert_eq!(vq.used.idx.get(), avail_idx + 1);
+            vq.check_used_elem(avail_idx, 0, 1);
+            mem.read_obj::<u8>(ack_addr).unwrap()
+        };
+        let set_pairs = |pairs: u16| {
+            let pairs = pairs.to_le_bytes();
+            [
+                VIRTIO_NET_CTRL_MQ as u8,
+                VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8,
+                pairs[0],
+                pairs[1],
+            ]
+        };
+
+        // Use both queue pairs.
+        assert_eq!(send_command(&mut net, set_pairs(2)), VIRTIO_NET_OK as u8);
+        assert_eq!(net.active_queue_pairs, 2);
+        assert!(net.irq_trigger.has_pending_irq(IrqType::Vring));
+        // The tap queue of the second pair is attached now.
+        net.queue_pairs[1]
+            .link
+            .tap()
+            .unwrap()
+            .set_queue_enabled(true)
+            .unwrap_err();
+
+        // Out of range number of queue pairs.
+        check_metric_after_block!(METRICS.net.ctrl_fails, 2, {
+            assert_eq!(send_command(&mut net, set_pairs(0)), VIRTIO_NET_ERR as u8);
+            assert_eq!(send_command(&mut net, set_pairs(3)), VIRTIO_NET_ERR as u8);
+        });
+        assert_eq!(net.active_queue_pairs, 2);
+
+        // Unsupported command class.
+        check_metric_after_block!(METRICS.net.ctrl_fails, 1, {
+            assert_eq!(send_command(&mut net, [0, 0, 1, 0]), VIRTIO_NET_ERR as u8);
+        });
+
+        // Oversized commands are rejected without being read.
+        vq.dtable[0].set(command_addr.raw_value(), u32::MAX, VIRTQ_DESC_F_NEXT, 1);
+        check_metric_after_block!(METRICS.net.ctrl_fails, 1, {
+            assert_eq!(send_command(&mut net, set_pairs(2)), VIRTIO_NET_ERR as u8);
+        });
+        assert_eq!(net.active_queue_pairs, 2);
+        vq.dtable[0].set(command_addr.raw_value(), 4, VIRTQ_DESC_F_NEXT, 1);
+
+        // Back to a single queue pair.
+        assert_eq!(send_command(&mut net, set_pairs(1)), VIRTIO_NET_OK as u8);
+        assert_eq!(net.active_queue_pairs, 1);
+        net.queue_pairs[1]
+            .link
+            .tap()
+            .unwrap()
+            .set_queue_enabled(false)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_user_backend() {
+        let new_user_net = |tap_if_name: &str, num_queue_pairs| {
+            Net::new_with_tap(
+                "user-net".to_string(),
+                tap_if_name.to_string(),
+                None,
+                RateLimiter::default(),
+                RateLimiter::default(),
+                num_queue_pairs,
+                NetBackend::User,
+            )
+        };
+        assert_eq!(
+            format!("{:?}", new_user_net("", 2).err()),
+            "Some(UserBackendMultiQueue)"
+        );
+        assert_eq!(
+            format!("{:?}", new_user_net("tap0", 1).err()),
+            "Some(UserBackendWithTap)"
+        );
+
+        let mut net = default_net_user();
+        assert_eq!(net.iface_name(), "");
+        assert!(net.queue_pairs[0].link.tap().is_none());
+        // No offloads are advertised.
+        assert_eq!(net.avail_features() & (1 << VIRTIO_NET_F_CSUM), 0);
+        assert_eq!(net.avail_features() & (1 << VIRTIO_NET_F_GUEST_CSUM), 0);
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_MAC), 0);
+
+        // The stack answers for the router of its network.
+        let guest_mac = default_guest_mac();
+        let (frame_buf, frame_len) = create_arp_request(
+            guest_mac,
+            Ipv4Addr::new(10, 0, 2, 15),
+            MacAddr::parse_str("ff:ff:ff:ff:ff:ff").unwrap(),
+            Ipv4Addr::new(10, 0, 2, 2),
+        );
+        let pair = &mut net.queue_pairs[0];
+        assert!(!Net::write_to_mmds_or_tap(
+            None,
+            None,
+            &mut pair.tx_rate_limiter,
+            &frame_buf[..frame_len],
+            &mut pair.link,
+            Some(guest_mac),
+            &TxFilter::default(),
+        )
+        .unwrap());
+
+        net.mocks.set_read_tap(ReadTapMock::TapFrame);
+        let len = net.read_from_mmds_or_tap(0).unwrap();
+        let frame = frame_bytes_from_buf(&net.queue_pairs[0].rx_frame_buf[..len]).unwrap();
+        let arp = EthIPv4ArpFrame::from_bytes(EthernetFrame::from_bytes(frame).unwrap().payload())
+            .unwrap();
+        assert_eq!(arp.spa(), Ipv4Addr::new(10, 0, 2, 2));
+        assert_eq!(arp.tha(), guest_mac);
+
+        // There is nothing else to read.
+        match net.read_from_mmds_or_tap(0) {
+            Err(Error::IO(err)) => assert_eq!(err.raw_os_error(), Some(EAGAIN)),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
 }
@@ -1,37 +1,107 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use event_manager::{EventOps, Events, MutEventSubscriber};
 use logger::{debug, error, warn, IncMetric, METRICS};
 use utils::epoll::EventSet;
 
 use crate::virtio::net::device::Net;
-use crate::virtio::{VirtioDevice, RX_INDEX, TX_INDEX};
+use crate::virtio::net::vhost::{VHOST_RX_INDEX, VHOST_TX_INDEX};
+use crate::virtio::net::{ctrl_queue_index, rx_queue_index, tx_queue_index};
+use crate::virtio::VirtioDevice;
+
+// The event sources of a queue pair.
+enum QueuePairEvent {
+    RxQueue,
+    TxQueue,
+    RxRateLimiter,
+    TxRateLimiter,
+    Tap,
+    // Used buffers signaled by vhost-net, with the index of the queue in the pair.
+    VhostCall(usize),
+}
 
 impl Net {
     fn register_runtime_events(&self, ops: &mut EventOps) {
-        if let Err(err) = ops.add(Events::new(&self.queue_evts[RX_INDEX], EventSet::IN)) {
-            error!("Failed to register rx queue event: {}", err);
-        }
-        if let Err(err) = ops.add(Events::new(&self.queue_evts[TX_INDEX], EventSet::IN)) {
-            error!("Failed to register tx queue event: {}", err);
-        }
-        if let Err(err) = ops.add(Events::new(&self.rx_rate_limiter, EventSet::IN)) {
-            error!("Failed to register rx queue event: {}", err);
-        }
-        if let Err(err) = ops.add(Events::new(&self.tx_rate_limiter, EventSet::IN)) {
-            error!("Failed to register tx queue event: {}", err);
+        for (pair_index, pair) in self.queue_pairs.iter().enumerate() {
+            // The queue events and the tap are handled by the kernel.
+            if self.vhost_active {
+                for call_evt in pair.vhost.iter().flat_map(|vhost| vhost.call_evts.iter()) {
+                    if let Err(err) = ops.add(Events::new(call_evt, EventSet::IN)) {
+                        error!("Failed to register vhost call event: {}", err);
+                    }
+                }
+                continue;
+            }
+
+            let rx_queue_evt = &self.queue_evts[rx_queue_index(pair_index)];
+            if let Err(err) = ops.add(Events::new(rx_queue_evt, EventSet::IN)) {
+                error!("Failed to register rx queue event: {}", err);
+            }
+            let tx_queue_evt = &self.queue_evts[tx_queue_index(pair_index)];
+            if let Err(err) = ops.add(Events::new(tx_queue_evt, EventSet::IN)) {
+                error!("Failed to register tx queue event: {}", err);
+            }
+            if let Err(err) = ops.add(Events::new(&pair.rx_rate_limiter, EventSet::IN)) {
+                error!("Failed to register rx queue event: {}", err);
+            }
+            if let Err(err) = ops.add(Events::new(&pair.tx_rate_limiter, EventSet::IN)) {
+                error!("Failed to register tx queue event: {}", err);
+            }
+            // The user-mode network stack is polled through its epoll FD, which stands in for the
+            // tap.
+            if let Err(err) = ops.add(Events::new(
+                &pair.link,
+                EventSet::IN | EventSet::EDGE_TRIGGERED,
+            )) {
+                error!("Failed to register tap event: {}", err);
+            }
         }
-        if let Err(err) = ops.add(Events::new(
-            &self.tap,
-            EventSet::IN | EventSet::EDGE_TRIGGERED,
-        )) {
-            error!("Failed to register tap event: {}", err);
+        if let Some(ctrl_queue_evt) = self
+            .queue_evts
+            .get(ctrl_queue_index(self.queue_pairs.len()))
+        {
+            if let Err(err) = ops.add(Events::new(ctrl_queue_evt, EventSet::IN)) {
+                error!("Failed to register ctrl queue event: {}", err);
+            }
         }
     }
 
+    // Returns the queue pair that `source` belongs to, along with the kind of event, if any.
+    fn queue_pair_event_of(&self, source: RawFd) -> Option<(usize, QueuePairEvent)> {
+        self.queue_pairs
+            .iter()
+            .enumerate()
+            .find_map(|(pair_index, pair)| {
+                let event = match source {
+                    _ if source == self.queue_evts[rx_queue_index(pair_index)].as_raw_fd() => {
+                        QueuePairEvent::RxQueue
+                    }
+                    _ if source == self.queue_evts[tx_queue_index(pair_index)].as_raw_fd() => {
+                        QueuePairEvent::TxQueue
+                    }
+                    _ if source == pair.rx_rate_limiter.as_raw_fd() => {
+                        QueuePairEvent::RxRateLimiter
+                    }
+                    _ if source == pair.tx_rate_limiter.as_raw_fd() => {
+                        QueuePairEvent::TxRateLimiter
+                    }
+                    _ if source == pair.link.as_raw_fd() => QueuePairEvent::Tap,
+                    _ => {
+                        let vhost = pair.vhost.as_ref()?;
+                        let vhost_index = [VHOST_RX_INDEX, VHOST_TX_INDEX]
+                            .iter()
+                            .copied()
+                            .find(|&index| source == vhost.call_evts[index].as_raw_fd())?;
+                        QueuePairEvent::VhostCall(vhost_index)
+                    }
+                };
+                Some((pair_index, event))
+            })
+    }
+
     fn register_activate_event(&self, ops: &mut EventOps) {
         if let Err(err) = ops.add(Events::new(&self.activate_evt, EventSet::IN)) {
             error!("Failed to register activate event: {}", err);
@@ -67,22 +137,33 @@ impl MutEventSubscriber for Net {
         }
 
         if self.is_activated() {
-            let virtq_rx_ev_fd = self.queue_evts[RX_INDEX].as_raw_fd();
-            let virtq_tx_ev_fd = self.queue_evts[TX_INDEX].as_raw_fd();
-            let rx_rate_limiter_fd = self.rx_rate_limiter.as_raw_fd();
-            let tx_rate_limiter_fd = self.tx_rate_limiter.as_raw_fd();
-            let tap_fd = self.tap.as_raw_fd();
+            let virtq_ctrl_ev_fd = self
+                .queue_evts
+                .get(ctrl_queue_index(self.queue_pairs.len()))
+                .map(AsRawFd::as_raw_fd);
             let activate_fd = self.activate_evt.as_raw_fd();
 
             // Looks better than C style if/else if/else.
-            match source {
-                _ if source == virtq_rx_ev_fd => self.process_rx_queue_event(),
-                _ if source == tap_fd => self.process_tap_rx_event(),
-                _ if source == virtq_tx_ev_fd => self.process_tx_queue_event(),
-                _ if source == rx_rate_limiter_fd => self.process_rx_rate_limiter_event(),
-                _ if source == tx_rate_limiter_fd => self.process_tx_rate_limiter_event(),
-                _ if activate_fd == source => self.process_activate_event(ops),
-                _ => {
+            match self.queue_pair_event_of(source) {
+                Some((pair_index, QueuePairEvent::RxQueue)) => {
+                    self.process_rx_queue_event(pair_index)
+                }
+                Some((pair_index, QueuePairEvent::Tap)) => self.process_tap_rx_event(pair_index),
+                Some((pair_index, QueuePairEvent::TxQueue)) => {
+                    self.process_tx_queue_event(pair_index)
+                }
+                Some((pair_index, QueuePairEvent::RxRateLimiter)) => {
+                    self.process_rx_rate_limiter_event(pair_index)
+                }
+                Some((pair_index, QueuePairEvent::TxRateLimiter)) => {
+                    self.process_tx_rate_limiter_event(pair_index)
+                }
+                Some((pair_index, QueuePairEvent::VhostCall(vhost_index))) => {
+                    self.process_vhost_call_event(pair_index, vhost_index)
+                }
+                None if virtq_ctrl_ev_fd == Some(source) => self.process_ctrl_queue_event(),
+                None if activate_fd == source => self.process_activate_event(ops),
+                None => {
                     warn!("Net: Spurious event received: {:?}", source);
                     METRICS.net.event_fails.inc();
                 }
@@ -5,31 +5,48 @@ use std::{io, result};
 
 pub const MAX_BUFFER_SIZE: usize = 65562;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 2;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
-// The index of the rx queue from Net device queues/queues_evts vector.
+pub const DEFAULT_NUM_QUEUE_PAIRS: usize = 1;
+// Each queue pair owns a tap queue and two frame buffers, so keep their number bounded.
+pub const MAX_QUEUE_PAIRS: usize = 16;
+// The control commands are read in full before being handled, so keep their length bounded. The
+// only supported one, which sets the number of queue pairs, is 4 bytes long.
+pub const MAX_CTRL_COMMAND_LEN: usize = 64;
+// The index of the rx queue of the first queue pair from Net device queues/queues_evts vector.
 pub const RX_INDEX: usize = 0;
-// The index of the tx queue from Net device queues/queues_evts vector.
+// The index of the tx queue of the first queue pair from Net device queues/queues_evts vector.
 pub const TX_INDEX: usize = 1;
 
+/// Returns the index of the rx queue of the given queue pair.
+pub fn rx_queue_index(pair_index: usize) -> usize {
+    2 * pair_index + RX_INDEX
+}
+
+/// Returns the index of the tx queue of the given queue pair.
+pub fn tx_queue_index(pair_index: usize) -> usize {
+    2 * pair_index + TX_INDEX
+}
+
+/// Returns the index of the control queue, which follows all the queue pairs.
+pub fn ctrl_queue_index(num_queue_pairs: usize) -> usize {
+    2 * num_queue_pairs
+}
+
 pub mod device;
 pub mod event_handler;
 pub mod persist;
 mod tap;
 pub mod test_utils;
+pub mod tx_filter;
+pub mod user;
+mod vhost;
 
 pub use tap::Error as TapError;
+pub use vhost::Error as VhostError;
 
-pub use self::device::Net;
+pub use self::device::{Net, NetBackend};
 pub use self::event_handler::*;
-
-/// Enum representing the Net device queue types
-pub enum NetQueue {
-    /// The RX queue
-    Rx,
-    /// The TX queue
-    Tx,
-}
+pub use self::tx_filter::TxFilter;
+pub use self::user::UserNetworkConfig;
 
 #[derive(Debug)]
 pub enum Error {
@@ -41,12 +58,38 @@ pub enum Error {
     TapSetVnetHdrSize(TapError),
     /// Enabling tap interface failed.
     TapEnable(TapError),
+    /// Attaching or detaching a tap queue failed.
+    TapSetQueue(TapError),
+    /// Setting up the vhost-net backend failed.
+    Vhost(VhostError),
+    /// The number of queue pairs is out of range.
+    InvalidNumQueuePairs(usize),
     /// EventFd error.
     EventFd(io::Error),
     /// IO error.
     IO(io::Error),
     /// The VNET header is missing from the frame.
     VnetHeaderMissing,
+    /// The TX filter compares addresses with the guest MAC, but the device has none.
+    TxFilterWithoutGuestMac,
+    /// The TX filter can't be enforced while the queue pairs are served by vhost-net.
+    TxFilterWithVhost,
+    /// The rate limiters can't be enforced while the queue pairs are served by vhost-net.
+    RateLimiterWithVhost,
+    /// The rate limiters can't be enforced while the queue pairs are served by vhost-net.
+    RateLimiterWithVhost,
+    /// The user-mode network stack serves a single queue pair.
+    UserBackendMultiQueue,
+    /// A tap device was given along with the user-mode network stack.
+    UserBackendWithTap,
+    /// Creating the user-mode network stack failed.
+    UserNetwork(io::Error),
+    /// The user-mode network stack has its own DHCP server.
+    UserBackendWithDhcp,
+    /// The DHCP server can't answer while the queue pairs are served by vhost-net.
+    DhcpWithVhost,
+    /// The reachable destinations only apply to the user-mode network stack.
+    UserNetworkConfigWithTap,
 }
 
 pub type Result<T> = result::Result<T, Error>;
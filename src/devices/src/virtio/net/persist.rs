@@ -8,18 +8,21 @@ use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
 
 use mmds::data_store::Mmds;
+use mmds::dhcp::{DhcpServer, Error as DhcpError};
 use mmds::ns::MmdsNetworkStack;
-use mmds::persist::MmdsNetworkStackState;
+use mmds::persist::{DhcpServerState, MmdsNetworkStackState};
 use rate_limiter::persist::RateLimiterState;
 use rate_limiter::RateLimiter;
 use snapshot::Persist;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::GuestMemoryMmap;
 
-use super::device::{ConfigSpace, Net};
-use super::{NUM_QUEUES, QUEUE_SIZE};
+use super::device::{set_active_tap_queues, Net, NetBackend};
+use super::tx_filter::{TxFilter, TxFilterState};
+use super::user::{UserNetworkConfig, UserNetworkConfigState};
+use super::QUEUE_SIZE;
 use crate::virtio::persist::{Error as VirtioStateError, VirtioDeviceState};
 use crate::virtio::{DeviceState, TYPE_NET};
 
@@ -29,16 +32,119 @@ pub struct NetConfigSpaceState {
     guest_mac: [u8; MAC_ADDR_LEN],
 }
 
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct NetQueuePairState {
+    rx_rate_limiter_state: RateLimiterState,
+    tx_rate_limiter_state: RateLimiterState,
+}
+
+#[derive(Clone, Copy, Debug, Versionize, PartialEq)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum NetBackendState {
+    Userspace,
+    VhostNet,
+    User,
+}
+
+impl From<NetBackend> for NetBackendState {
+    fn from(backend: NetBackend) -> Self {
+        match backend {
+            NetBackend::Userspace => NetBackendState::Userspace,
+            NetBackend::VhostNet => NetBackendState::VhostNet,
+            NetBackend::User => NetBackendState::User,
+        }
+    }
+}
+
+impl From<NetBackendState> for NetBackend {
+    fn from(backend_state: NetBackendState) -> Self {
+        match backend_state {
+            NetBackendState::Userspace => NetBackend::Userspace,
+            NetBackendState::VhostNet => NetBackend::VhostNet,
+            NetBackendState::User => NetBackend::User,
+        }
+    }
+}
+
+impl Default for NetBackendState {
+    fn default() -> Self {
+        // Older versions only had the userspace backend.
+        NetBackendState::Userspace
+    }
+}
+
 #[derive(Clone, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct NetState {
     id: String,
     tap_if_name: String,
+    // Rate limiters of the first queue pair.
     rx_rate_limiter_state: RateLimiterState,
     tx_rate_limiter_state: RateLimiterState,
     pub mmds_ns: Option<MmdsNetworkStackState>,
     config_space: NetConfigSpaceState,
     virtio_state: VirtioDeviceState,
+    // We don't need to specify a `ser_fn` for the following fields since snapshots of multi-queue
+    // devices can't be restored by older FC versions anyway. Their number of queue pairs is
+    // derived from the saved virtio queues.
+    #[version(start = 2)]
+    // Rate limiters of the queue pairs following the first one.
+    extra_queue_pairs: Vec<NetQueuePairState>,
+    #[version(start = 2, default_fn = "default_active_queue_pairs")]
+    active_queue_pairs: u16,
+    #[version(start = 2, default_fn = "default_backend", ser_fn = "backend_ser")]
+    backend: NetBackendState,
+    #[version(start = 2, ser_fn = "tx_filter_ser")]
+    tx_filter: TxFilterState,
+    #[version(start = 2, ser_fn = "dhcp_server_ser")]
+    dhcp_server: Option<DhcpServerState>,
+    // Older versions can't restore the user-mode network stack, as checked by `backend_ser`.
+    #[version(start = 2)]
+    user_network: Option<UserNetworkConfigState>,
+}
+
+impl NetState {
+    fn default_active_queue_pairs(_source_version: u16) -> u16 {
+        1
+    }
+
+    fn default_backend(_source_version: u16) -> NetBackendState {
+        NetBackendState::Userspace
+    }
+
+    fn backend_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would look for a tap device.
+        if target_version < 2 && self.backend == NetBackendState::User {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support the user-mode network backend.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn tx_filter_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would let the guest send any frame.
+        if target_version < 2 && TxFilter::from(&self.tx_filter).is_enabled() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support TX filtering.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn dhcp_server_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would leave the guest without an answer when its lease runs out.
+        if target_version < 2 && self.dhcp_server.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support the DHCP server.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 pub struct NetConstructorArgs {
@@ -52,6 +158,7 @@ pub enum Error {
     CreateRateLimiter(io::Error),
     VirtioState(VirtioStateError),
     NoMmdsDataStore,
+    Dhcp(DhcpError),
 }
 
 impl Persist<'_> for Net {
@@ -60,16 +167,34 @@ impl Persist<'_> for Net {
     type Error = Error;
 
     fn save(&self) -> Self::State {
+        let mut virtio_state = VirtioDeviceState::from_device(self);
+        if self.vhost_active {
+            // The kernel owns the rings, so their indexes have to be fetched from it.
+            virtio_state.queues = self.stop_vhost().iter().map(Persist::save).collect();
+        }
+
         NetState {
             id: self.id().clone(),
             tap_if_name: self.iface_name(),
-            rx_rate_limiter_state: self.rx_rate_limiter.save(),
-            tx_rate_limiter_state: self.tx_rate_limiter.save(),
+            rx_rate_limiter_state: self.queue_pairs[0].rx_rate_limiter.save(),
+            tx_rate_limiter_state: self.queue_pairs[0].tx_rate_limiter.save(),
             mmds_ns: self.mmds_ns.as_ref().map(|mmds| mmds.save()),
             config_space: NetConfigSpaceState {
                 guest_mac: self.config_space.guest_mac,
             },
-            virtio_state: VirtioDeviceState::from_device(self),
+            virtio_state,
+            extra_queue_pairs: self.queue_pairs[1..]
+                .iter()
+                .map(|pair| NetQueuePairState {
+                    rx_rate_limiter_state: pair.rx_rate_limiter.save(),
+                    tx_rate_limiter_state: pair.tx_rate_limiter.save(),
+                })
+                .collect(),
+            active_queue_pairs: self.active_queue_pairs as u16,
+            backend: self.backend().into(),
+            tx_filter: TxFilterState::from(self.tx_filter()),
+            dhcp_server: self.dhcp_server.as_ref().map(Persist::save),
+            user_network: self.user_network_config().map(UserNetworkConfigState::from),
         }
     }
 
@@ -80,13 +205,34 @@ impl Persist<'_> for Net {
         // RateLimiter::restore() can fail at creating a timerfd.
         let rx_rate_limiter = RateLimiter::restore((), &state.rx_rate_limiter_state)?;
         let tx_rate_limiter = RateLimiter::restore((), &state.tx_rate_limiter_state)?;
+        // Each queue pair takes two virtio queues, while the control queue, if any, is last.
+        let num_queues = state.virtio_state.queues.len();
         let mut net = Net::new_with_tap(
             state.id.clone(),
             state.tap_if_name.clone(),
             None,
             rx_rate_limiter,
             tx_rate_limiter,
+            num_queues / 2,
+            state.backend.into(),
         )?;
+        for (pair, pair_state) in net.queue_pairs[1..]
+            .iter_mut()
+            .zip(state.extra_queue_pairs.iter())
+        {
+            pair.rx_rate_limiter = RateLimiter::restore((), &pair_state.rx_rate_limiter_state)?;
+            pair.tx_rate_limiter = RateLimiter::restore((), &pair_state.tx_rate_limiter_state)?;
+        }
+
+        let active_queue_pairs = usize::from(state.active_queue_pairs);
+        if active_queue_pairs == 0 || active_queue_pairs > net.queue_pairs.len() {
+            return Err(Error::CreateNet(super::Error::InvalidNumQueuePairs(
+                active_queue_pairs,
+            )));
+        }
+        set_active_tap_queues(&net.queue_pairs, net.active_queue_pairs, active_queue_pairs)
+            .map_err(super::Error::TapSetQueue)?;
+        net.active_queue_pairs = active_queue_pairs;
 
         // We trust the MMIODeviceManager::restore to pass us an MMDS data store reference if
         // there is at least one net device having the MMDS NS present and/or the mmds version was
@@ -108,22 +254,33 @@ impl Persist<'_> for Net {
         net.queues = state.virtio_state.build_queues_checked(
             &constructor_args.mem,
             TYPE_NET,
-            NUM_QUEUES,
+            num_queues,
             QUEUE_SIZE,
         )?;
         net.irq_trigger.irq_status =
             Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
         net.avail_features = state.virtio_state.avail_features;
         net.acked_features = state.virtio_state.acked_features;
-        net.config_space = ConfigSpace {
-            guest_mac: state.config_space.guest_mac,
-        };
+        net.config_space.guest_mac = state.config_space.guest_mac;
 
         net.guest_mac = Some(MacAddr::from_bytes_unchecked(
             &state.config_space.guest_mac[..MAC_ADDR_LEN],
         ));
+        // The filter and the DHCP server have to be in place before picking the data path.
+        net.tx_filter = TxFilter::from(&state.tx_filter);
+        net.dhcp_server = state
+            .dhcp_server
+            .as_ref()
+            .map(|dhcp_server| DhcpServer::restore((), dhcp_server))
+            .transpose()?;
+        if let Some(config) = &state.user_network {
+            net.set_user_network_config(UserNetworkConfig::from(config))?;
+        }
 
         if state.virtio_state.activated {
+            if net.backend() == NetBackend::VhostNet {
+                net.activate_vhost(&constructor_args.mem)?;
+            }
             net.device_state = DeviceState::Activated(constructor_args.mem);
         }
 
@@ -133,11 +290,17 @@ impl Persist<'_> for Net {
 
 #[cfg(test)]
 mod tests {
+    use std::net::Ipv4Addr;
     use std::sync::atomic::Ordering;
 
+    use mmds::dhcp::DhcpConfig;
+
     use super::*;
     use crate::virtio::device::VirtioDevice;
-    use crate::virtio::net::test_utils::{default_guest_memory, default_net, default_net_no_mmds};
+    use crate::virtio::net::test_utils::{
+        default_guest_memory, default_net, default_net_multi_queue, default_net_no_mmds,
+        default_net_user,
+    };
 
     fn validate_save_and_restore(net: Net, mmds_ds: Option<Arc<Mutex<Mmds>>>) {
         let guest_mem = default_guest_memory();
@@ -191,8 +354,9 @@ mod tests {
                     assert_eq!(&restored_net.id, &id);
                     assert_eq!(&restored_net.iface_name(), &tap_if_name);
                     assert_eq!(restored_net.mmds_ns.is_some(), allow_mmds_requests);
-                    assert_eq!(restored_net.rx_rate_limiter, RateLimiter::default());
-                    assert_eq!(restored_net.tx_rate_limiter, RateLimiter::default());
+                    assert_eq!(restored_net.rx_rate_limiter(), &RateLimiter::default());
+                    assert_eq!(restored_net.tx_rate_limiter(), &RateLimiter::default());
+                    assert_eq!(restored_net.num_queue_pairs(), 1);
                 }
                 Err(Error::NoMmdsDataStore) => assert!(has_mmds_ns && !allow_mmds_requests),
                 _ => unreachable!(),
@@ -215,4 +379,180 @@ mod tests {
         // data store. This will return an error.
         validate_save_and_restore(default_net(), None);
     }
+
+    #[test]
+    fn test_persistence_multi_queue() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+
+        let mut net = default_net_multi_queue(3);
+        set_active_tap_queues(&net.queue_pairs, 1, 2).unwrap();
+        net.active_queue_pairs = 2;
+        net.queue_pairs[2].tx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        let state = NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap();
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: None,
+            },
+            &state,
+        )
+        .unwrap();
+
+        // Three queue pairs and the control queue.
+        assert_eq!(restored_net.queues().len(), 7);
+        assert_eq!(restored_net.num_queue_pairs(), 3);
+        assert_eq!(restored_net.active_queue_pairs, 2);
+        assert_eq!(restored_net.backend(), NetBackend::Userspace);
+        // The tap queue of the second pair was attached again, unlike the third one.
+        restored_net.queue_pairs[1]
+            .link
+            .tap()
+            .unwrap()
+            .set_queue_enabled(true)
+            .unwrap_err();
+        restored_net.queue_pairs[2]
+            .link
+            .tap()
+            .unwrap()
+            .set_queue_enabled(false)
+            .unwrap_err();
+        assert_eq!(
+            restored_net.queue_pairs[1].tx_rate_limiter,
+            RateLimiter::default()
+        );
+        let tx_rate_limiter = &restored_net.queue_pairs[2].tx_rate_limiter;
+        assert_eq!(tx_rate_limiter.bandwidth().unwrap().capacity(), 10);
+        assert_eq!(tx_rate_limiter.ops().unwrap().capacity(), 2);
+    }
+
+    #[test]
+    fn test_persistence_tx_filter() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+
+        let mut net = default_net_no_mmds();
+        let filter = TxFilter {
+            drop_spoofed_mac: true,
+            allowed_ipv4_sources: Some(vec![std::net::Ipv4Addr::new(192, 168, 0, 2)]),
+            drop_spoofed_arp: false,
+        };
+        net.set_tx_filter(filter.clone()).unwrap();
+
+        // Older versions can't enforce the filter.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        let state = NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap();
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: None,
+            },
+            &state,
+        )
+        .unwrap();
+        assert_eq!(restored_net.tx_filter(), &filter);
+    }
+
+    #[test]
+    fn test_persistence_user_backend() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+
+        let mut net = default_net_user();
+        let config = UserNetworkConfig {
+            host_loopback: true,
+            ..Default::default()
+        };
+        net.set_user_network_config(config.clone()).unwrap();
+
+        // Older versions would look for a tap device.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        // The user-mode network stack starts over, without any flow.
+        let state = NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap();
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: None,
+            },
+            &state,
+        )
+        .unwrap();
+        assert_eq!(restored_net.backend(), NetBackend::User);
+        assert_eq!(restored_net.iface_name(), "");
+        assert!(restored_net.queue_pairs[0].link.tap().is_none());
+        // The guest can still only reach the same destinations.
+        assert_eq!(restored_net.user_network_config(), Some(&config));
+    }
+    #[test]
+    fn test_persistence_dhcp_server() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+
+        let mut net = default_net_no_mmds();
+        let config = DhcpConfig {
+            ipv4_address: Ipv4Addr::new(172, 16, 0, 2),
+            netmask: Ipv4Addr::new(255, 255, 255, 252),
+            gateway: Some(Ipv4Addr::new(172, 16, 0, 1)),
+            dns_servers: vec![],
+            mtu: None,
+            lease_time: 3600,
+        };
+        net.set_dhcp_server(Some(DhcpServer::new(config.clone()).unwrap()))
+            .unwrap();
+
+        // Older versions don't have a DHCP server.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        let state = NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap();
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: None,
+            },
+            &state,
+        )
+        .unwrap();
+        assert_eq!(restored_net.dhcp_server().unwrap().config(), &config);
+    }
 }
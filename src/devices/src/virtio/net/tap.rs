@@ -37,6 +37,7 @@ const TUNTAP: ::std::os::raw::c_uint = 84;
 ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, ::std::os::raw::c_int);
 ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 208, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, ::std::os::raw::c_int);
+ioctl_iow_nr!(TUNSETQUEUE, TUNTAP, 217, ::std::os::raw::c_int);
 
 /// Handle for a network tap interface.
 ///
@@ -106,8 +107,34 @@ impl Tap {
     ///
     /// * `if_name` - the name of the interface.
     pub fn open_named(if_name: &str) -> Result<Tap> {
-        let terminated_if_name = build_terminated_if_name(if_name)?;
+        Self::open_with_flags(&build_terminated_if_name(if_name)?, 0)
+    }
 
+    /// Create a multi-queue TUN/TAP device given the interface name, and open one
+    /// file descriptor for each of its queues.
+    /// # Arguments
+    ///
+    /// * `if_name` - the name of the interface.
+    /// * `num_queues` - the number of queues to open.
+    pub fn open_named_multi_queue(if_name: &str, num_queues: usize) -> Result<Vec<Tap>> {
+        let mut terminated_if_name = build_terminated_if_name(if_name)?;
+        let mut taps = Vec::with_capacity(num_queues);
+
+        for _ in 0..num_queues {
+            let tap = Self::open_with_flags(&terminated_if_name, net_gen::IFF_MULTI_QUEUE)?;
+            // The following queues have to be attached to the interface created by the
+            // first open, whose name may have been chosen by the kernel.
+            terminated_if_name = tap.if_name;
+            taps.push(tap);
+        }
+
+        Ok(taps)
+    }
+
+    fn open_with_flags(
+        terminated_if_name: &[u8; IFACE_NAME_MAX_LEN],
+        flags: c_uint,
+    ) -> Result<Tap> {
         let fd = unsafe {
             // Open calls are safe because we give a constant null-terminated
             // string and verify the result.
@@ -123,8 +150,8 @@ impl Tap {
         let tuntap = unsafe { File::from_raw_fd(fd) };
 
         let ifreq = IfReqBuilder::new()
-            .if_name(&terminated_if_name)
-            .flags((net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR) as i16)
+            .if_name(terminated_if_name)
+            .flags((net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR | flags) as i16)
             .execute(&tuntap, TUNSETIFF())?;
 
         // Safe since only the name is accessed, and it's cloned out.
@@ -154,6 +181,22 @@ impl Tap {
         Ok(())
     }
 
+    /// Attach this queue to, or detach it from, a multi-queue tap interface.
+    ///
+    /// A detached queue neither receives nor transmits frames until it is attached again.
+    pub fn set_queue_enabled(&self, enabled: bool) -> Result<()> {
+        let flags = if enabled {
+            net_gen::IFF_ATTACH_QUEUE
+        } else {
+            net_gen::IFF_DETACH_QUEUE
+        };
+        IfReqBuilder::new()
+            .flags(flags as i16)
+            .execute(&self.tap_file, TUNSETQUEUE())?;
+
+        Ok(())
+    }
+
     /// Set the size of the vnet hdr.
     pub fn set_vnet_hdr_size(&self, size: c_int) -> Result<()> {
         // ioctl is safe. Called with a valid tap fd, and we check the return.
@@ -239,6 +282,32 @@ pub mod tests {
         Tap::open_named("exclusivetap").unwrap_err();
     }
 
+    #[test]
+    fn test_tap_multi_queue() {
+        let taps = Tap::open_named_multi_queue("mqtap", 3).unwrap();
+        assert_eq!(taps.len(), 3);
+        for tap in taps.iter() {
+            assert_eq!("mqtap", tap.if_name_as_str());
+        }
+        // A multi-queue interface can't be opened as a single queue one.
+        Tap::open_named("mqtap").unwrap_err();
+
+        taps[2].set_queue_enabled(false).unwrap();
+        // Detaching an already detached queue is not permitted.
+        taps[2].set_queue_enabled(false).unwrap_err();
+        taps[2].set_queue_enabled(true).unwrap();
+
+        // Empty name - all the queues should belong to the interface named by the kernel.
+        let taps = Tap::open_named_multi_queue("", 2).unwrap();
+        assert_eq!(taps[0].if_name, taps[1].if_name);
+
+        // 16 characters - too long.
+        match Tap::open_named_multi_queue("a123456789abcdef", 2) {
+            Err(Error::InvalidIfname) => (),
+            _ => panic!("Expected Error::InvalidIfname"),
+        };
+    }
+
     #[test]
     fn test_set_options() {
         // This line will fail to provide an initialized FD if the test is not run as root.
@@ -252,6 +321,7 @@ pub mod tests {
         };
         assert!(faulty_tap.set_vnet_hdr_size(16).is_err());
         assert!(faulty_tap.set_offload(0).is_err());
+        assert!(faulty_tap.set_queue_enabled(true).is_err());
     }
 
     #[test]
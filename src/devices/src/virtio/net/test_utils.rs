@@ -21,6 +21,7 @@ use vm_memory::{GuestAddress, GuestMemoryMmap};
 #[cfg(test)]
 use crate::virtio::net::device::vnet_hdr_len;
 use crate::virtio::net::tap::{Error, IfReqBuilder, Tap};
+use crate::virtio::net::{NetBackend, DEFAULT_NUM_QUEUE_PAIRS};
 use crate::virtio::test_utils::VirtQueue;
 use crate::virtio::{Net, Queue, QueueError};
 use crate::Error as DeviceError;
@@ -41,13 +42,16 @@ pub fn default_net() -> Net {
         Some(&guest_mac),
         RateLimiter::default(),
         RateLimiter::default(),
+        DEFAULT_NUM_QUEUE_PAIRS,
+        NetBackend::Userspace,
     )
     .unwrap();
     net.configure_mmds_network_stack(
         MmdsNetworkStack::default_ipv4_addr(),
+        None,
         Arc::new(Mutex::new(Mmds::default())),
     );
-    enable(&net.tap);
+    enable(net.queue_pairs[0].link.tap().unwrap());
 
     net
 }
@@ -64,13 +68,51 @@ pub fn default_net_no_mmds() -> Net {
         Some(&guest_mac),
         RateLimiter::default(),
         RateLimiter::default(),
+        DEFAULT_NUM_QUEUE_PAIRS,
+        NetBackend::Userspace,
     )
     .unwrap();
-    enable(&net.tap);
+    enable(net.queue_pairs[0].link.tap().unwrap());
 
     net
 }
 
+pub fn default_net_multi_queue(num_queue_pairs: usize) -> Net {
+    let next_tap = NEXT_INDEX.fetch_add(1, Ordering::SeqCst);
+    let tap_dev_name = format!("net-device{}", next_tap);
+
+    let guest_mac = default_guest_mac();
+
+    let net = Net::new_with_tap(
+        format!("net-device{}", next_tap),
+        tap_dev_name,
+        Some(&guest_mac),
+        RateLimiter::default(),
+        RateLimiter::default(),
+        num_queue_pairs,
+        NetBackend::Userspace,
+    )
+    .unwrap();
+    enable(net.queue_pairs[0].link.tap().unwrap());
+
+    net
+}
+
+pub fn default_net_user() -> Net {
+    let next_tap = NEXT_INDEX.fetch_add(1, Ordering::SeqCst);
+
+    Net::new_with_tap(
+        format!("net-device{}", next_tap),
+        String::new(),
+        Some(&default_guest_mac()),
+        RateLimiter::default(),
+        RateLimiter::default(),
+        DEFAULT_NUM_QUEUE_PAIRS,
+        NetBackend::User,
+    )
+    .unwrap()
+}
+
 pub enum ReadTapMock {
     Failure,
     MockFrame(Vec<u8>),
@@ -259,7 +301,8 @@ pub fn enable(tap: &Tap) {
 #[cfg(test)]
 pub(crate) fn inject_tap_tx_frame(net: &Net, len: usize) -> Vec<u8> {
     assert!(len >= vnet_hdr_len());
-    let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&net.tap));
+    let tap_traffic_simulator =
+        TapTrafficSimulator::new(if_index(net.queue_pairs[0].link.tap().unwrap()));
     let mut frame = utils::rand::rand_alphanumerics(len - vnet_hdr_len())
         .as_bytes()
         .to_vec();
@@ -388,11 +431,11 @@ pub mod test {
 
         pub fn simulate_event(&mut self, event: NetEvent) {
             match event {
-                NetEvent::RxQueue => self.net().process_rx_queue_event(),
-                NetEvent::RxRateLimiter => self.net().process_rx_rate_limiter_event(),
-                NetEvent::Tap => self.net().process_tap_rx_event(),
-                NetEvent::TxQueue => self.net().process_tx_queue_event(),
-                NetEvent::TxRateLimiter => self.net().process_tx_rate_limiter_event(),
+                NetEvent::RxQueue => self.net().process_rx_queue_event(0),
+                NetEvent::RxRateLimiter => self.net().process_rx_rate_limiter_event(0),
+                NetEvent::Tap => self.net().process_tap_rx_event(0),
+                NetEvent::TxQueue => self.net().process_tx_queue_event(0),
+                NetEvent::TxRateLimiter => self.net().process_tx_rate_limiter_event(0),
             };
         }
 
@@ -452,7 +495,7 @@ pub mod test {
                 self.event_manager.run_with_timeout(100).unwrap()
             );
             // Check that the frame has been deferred.
-            assert!(self.net().rx_deferred_frame);
+            assert!(self.net().queue_pairs[0].rx_deferred_frame);
             // Check that the descriptor chain has been discarded.
             assert_eq!(self.rxq.used.idx.get(), used_idx + 1);
             assert!(&self.net().irq_trigger.has_pending_irq(IrqType::Vring));
@@ -0,0 +1,422 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Filtering of the frames sent by the guest, applied before they reach the TAP interface.
+
+use std::net::Ipv4Addr;
+
+use dumbo::pdu::arp::EthIPv4ArpFrame;
+use dumbo::pdu::ethernet::{EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use dumbo::pdu::ipv4::{IPv4Packet, IPV4_VERSION};
+use dumbo::ETH_IPV4_FRAME_LEN;
+use serde::{Deserialize, Serialize};
+use utils::net::mac::MacAddr;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
+// The length of an IPv4 header without options.
+const IPV4_MIN_HEADER_LEN: usize = 20;
+// The ethertypes of the 802.1Q and 802.1ad tags, which hide the ethertype of the payload.
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+/// The rules applied to the frames sent by the guest. All of them are disabled by default.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TxFilter {
+    /// Drop the frames whose source MAC isn't the guest MAC of the interface.
+    #[serde(default)]
+    pub drop_spoofed_mac: bool,
+    /// When present, drop the IPv4 packets whose source address isn't in the list. Packets sent
+    /// from the unspecified address are let through, so that DHCP clients keep working.
+    #[serde(default)]
+    pub allowed_ipv4_sources: Option<Vec<Ipv4Addr>>,
+    /// Drop the ARP frames whose sender hardware address isn't the guest MAC or, when
+    /// `allowed_ipv4_sources` is present, whose sender protocol address isn't in the list.
+    #[serde(default)]
+    pub drop_spoofed_arp: bool,
+}
+
+/// The outcome of checking a frame against a `TxFilter`.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// The frame can be sent.
+    Pass,
+    /// The source MAC of the frame isn't the guest MAC.
+    SpoofedMac,
+    /// The source address of the IPv4 packet isn't allowed.
+    Ipv4Source,
+    /// The sender addresses of the ARP frame aren't the ones of the guest.
+    SpoofedArp,
+    /// The frame is VLAN tagged, so the address rules can't inspect it.
+    VlanTagged,
+    /// The frame is too short for the headers the rules have to look at.
+    Malformed,
+}
+
+impl TxFilter {
+    /// Returns whether any rule is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.drop_spoofed_mac || self.allowed_ipv4_sources.is_some() || self.drop_spoofed_arp
+    }
+
+    /// Returns whether the rules compare addresses with the guest MAC.
+    pub fn needs_guest_mac(&self) -> bool {
+        self.drop_spoofed_mac || self.drop_spoofed_arp
+    }
+
+    fn is_allowed_ipv4(&self, addr: Ipv4Addr) -> bool {
+        match self.allowed_ipv4_sources {
+            Some(ref allowed) => addr.is_unspecified() || allowed.contains(&addr),
+            None => true,
+        }
+    }
+
+    /// Checks `frame`, which doesn't include the VNET header, against the rules.
+    ///
+    /// Only the IPv4 and ARP ethertypes are inspected by the address rules; frames carrying
+    /// other protocols, such as IPv6, are only subject to the MAC rule. VLAN tagged frames are
+    /// dropped while an address rule is enabled, since they would otherwise get around it.
+    pub fn check(&self, frame: &[u8], guest_mac: Option<MacAddr>) -> Verdict {
+        if !self.is_enabled() {
+            return Verdict::Pass;
+        }
+
+        let eth_frame = match EthernetFrame::from_bytes(frame) {
+            Ok(eth_frame) => eth_frame,
+            Err(_) => return Verdict::Malformed,
+        };
+
+        if self.drop_spoofed_mac && Some(eth_frame.src_mac()) != guest_mac {
+            return Verdict::SpoofedMac;
+        }
+
+        match eth_frame.ethertype() {
+            ETHERTYPE_IPV4 if self.allowed_ipv4_sources.is_some() => {
+                // The packet may be followed by the padding of the Ethernet frame, so only the
+                // header fields we need are validated.
+                let payload = eth_frame.payload();
+                if payload.len() < IPV4_MIN_HEADER_LEN {
+                    return Verdict::Malformed;
+                }
+                let packet = IPv4Packet::from_bytes_unchecked(payload);
+                if packet.version_and_header_len().0 != IPV4_VERSION {
+                    return Verdict::Malformed;
+                }
+                if !self.is_allowed_ipv4(packet.source_address()) {
+                    return Verdict::Ipv4Source;
+                }
+            }
+            ETHERTYPE_ARP if self.drop_spoofed_arp => {
+                // Replies and gratuitous requests can both poison the caches of the neighbours.
+                let payload = eth_frame.payload();
+                if payload.len() < ETH_IPV4_FRAME_LEN {
+                    return Verdict::Malformed;
+                }
+                let arp_frame = match EthIPv4ArpFrame::from_bytes(&payload[..ETH_IPV4_FRAME_LEN]) {
+                    Ok(arp_frame) => arp_frame,
+                    Err(_) => return Verdict::Malformed,
+                };
+                if Some(arp_frame.sha()) != guest_mac || !self.is_allowed_ipv4(arp_frame.spa()) {
+                    return Verdict::SpoofedArp;
+                }
+            }
+            ETHERTYPE_VLAN | ETHERTYPE_QINQ
+                if self.allowed_ipv4_sources.is_some() || self.drop_spoofed_arp =>
+            {
+                return Verdict::VlanTagged;
+            }
+            _ => (),
+        }
+
+        Verdict::Pass
+    }
+}
+
+/// The saved state of a `TxFilter`.
+#[derive(Clone, Debug, Default, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct TxFilterState {
+    drop_spoofed_mac: bool,
+    allowed_ipv4_sources: Option<Vec<u32>>,
+    drop_spoofed_arp: bool,
+}
+
+impl From<&TxFilter> for TxFilterState {
+    fn from(filter: &TxFilter) -> Self {
+        TxFilterState {
+            drop_spoofed_mac: filter.drop_spoofed_mac,
+            allowed_ipv4_sources: filter
+                .allowed_ipv4_sources
+                .as_ref()
+                .map(|allowed| allowed.iter().map(|addr| u32::from(*addr)).collect()),
+            drop_spoofed_arp: filter.drop_spoofed_arp,
+        }
+    }
+}
+
+impl From<&TxFilterState> for TxFilter {
+    fn from(state: &TxFilterState) -> Self {
+        TxFilter {
+            drop_spoofed_mac: state.drop_spoofed_mac,
+            allowed_ipv4_sources: state
+                .allowed_ipv4_sources
+                .as_ref()
+                .map(|allowed| allowed.iter().map(|addr| Ipv4Addr::from(*addr)).collect()),
+            drop_spoofed_arp: state.drop_spoofed_arp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dumbo::pdu::arp::{OPER_REPLY, OPER_REQUEST};
+    use dumbo::pdu::ethernet::PAYLOAD_OFFSET;
+    use dumbo::pdu::ipv4::PROTOCOL_UDP;
+
+    use super::*;
+
+    const ETHERTYPE_IPV6: u16 = 0x86dd;
+    const ARP_OPER_OFFSET: usize = 6;
+
+    fn guest_mac() -> MacAddr {
+        MacAddr::parse_str("12:34:56:78:9a:bc").unwrap()
+    }
+
+    fn other_mac() -> MacAddr {
+        MacAddr::parse_str("12:34:56:78:9a:bd").unwrap()
+    }
+
+    fn write_eth(buf: &mut [u8], src_mac: MacAddr, ethertype: u16) {
+        let dst_mac = MacAddr::from_bytes_unchecked(&[0xff; 6]);
+        EthernetFrame::write_incomplete(buf, dst_mac, src_mac, ethertype).unwrap();
+    }
+
+    fn ipv4_frame(src_mac: MacAddr, src_addr: Ipv4Addr) -> Vec<u8> {
+        // Leave room for the padding of a minimum size Ethernet frame.
+        let mut buf = vec![0u8; 64];
+        write_eth(&mut buf, src_mac, ETHERTYPE_IPV4);
+        IPv4Packet::write_header(
+            &mut buf[PAYLOAD_OFFSET..],
+            PROTOCOL_UDP,
+            src_addr,
+            Ipv4Addr::new(10, 0, 0, 1),
+        )
+        .unwrap()
+        .with_payload_len_unchecked(8, true);
+        buf
+    }
+
+    fn arp_frame(operation: u16, sha: MacAddr, spa: Ipv4Addr) -> Vec<u8> {
+        let mut buf = vec![0u8; PAYLOAD_OFFSET + ETH_IPV4_FRAME_LEN];
+        write_eth(&mut buf, sha, ETHERTYPE_ARP);
+        EthIPv4ArpFrame::write_reply(
+            &mut buf[PAYLOAD_OFFSET..],
+            sha,
+            spa,
+            other_mac(),
+            Ipv4Addr::new(10, 0, 0, 1),
+        )
+        .unwrap();
+        // Only replies can be written, so patch the operation field.
+        buf[PAYLOAD_OFFSET + ARP_OPER_OFFSET..PAYLOAD_OFFSET + ARP_OPER_OFFSET + 2]
+            .copy_from_slice(&operation.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_disabled_filter() {
+        let filter = TxFilter::default();
+        assert!(!filter.is_enabled());
+        assert!(!filter.needs_guest_mac());
+
+        // Nothing is inspected, not even the length of the frame.
+        assert_eq!(filter.check(&[0u8; 2], Some(guest_mac())), Verdict::Pass);
+        let frame = ipv4_frame(other_mac(), Ipv4Addr::new(192, 168, 0, 2));
+        assert_eq!(filter.check(&frame, Some(guest_mac())), Verdict::Pass);
+    }
+
+    #[test]
+    fn test_spoofed_mac() {
+        let filter = TxFilter {
+            drop_spoofed_mac: true,
+            ..Default::default()
+        };
+        assert!(filter.is_enabled());
+        assert!(filter.needs_guest_mac());
+
+        let addr = Ipv4Addr::new(192, 168, 0, 2);
+        assert_eq!(
+            filter.check(&ipv4_frame(guest_mac(), addr), Some(guest_mac())),
+            Verdict::Pass
+        );
+        assert_eq!(
+            filter.check(&ipv4_frame(other_mac(), addr), Some(guest_mac())),
+            Verdict::SpoofedMac
+        );
+        assert_eq!(
+            filter.check(&[0u8; 2], Some(guest_mac())),
+            Verdict::Malformed
+        );
+
+        // The rule applies to all the ethertypes.
+        let mut buf = [0u8; 64];
+        write_eth(&mut buf, other_mac(), ETHERTYPE_IPV6);
+        assert_eq!(filter.check(&buf, Some(guest_mac())), Verdict::SpoofedMac);
+    }
+
+    #[test]
+    fn test_ipv4_source() {
+        let allowed = Ipv4Addr::new(192, 168, 0, 2);
+        let filter = TxFilter {
+            allowed_ipv4_sources: Some(vec![allowed]),
+            ..Default::default()
+        };
+        assert!(filter.is_enabled());
+        assert!(!filter.needs_guest_mac());
+
+        assert_eq!(
+            filter.check(&ipv4_frame(guest_mac(), allowed), None),
+            Verdict::Pass
+        );
+        assert_eq!(
+            filter.check(&ipv4_frame(guest_mac(), Ipv4Addr::UNSPECIFIED), None),
+            Verdict::Pass
+        );
+        assert_eq!(
+            filter.check(
+                &ipv4_frame(guest_mac(), Ipv4Addr::new(192, 168, 0, 3)),
+                None
+            ),
+            Verdict::Ipv4Source
+        );
+
+        // A truncated IPv4 header.
+        let frame = ipv4_frame(guest_mac(), allowed);
+        assert_eq!(
+            filter.check(&frame[..PAYLOAD_OFFSET + 10], None),
+            Verdict::Malformed
+        );
+
+        // A header with the wrong version.
+        let mut frame = ipv4_frame(guest_mac(), allowed);
+        frame[PAYLOAD_OFFSET] = 0x65;
+        assert_eq!(filter.check(&frame, None), Verdict::Malformed);
+
+        // Other ethertypes aren't inspected.
+        let mut buf = [0u8; 64];
+        write_eth(&mut buf, guest_mac(), ETHERTYPE_IPV6);
+        assert_eq!(filter.check(&buf, None), Verdict::Pass);
+
+        // An empty list drops all the IPv4 traffic.
+        let filter = TxFilter {
+            allowed_ipv4_sources: Some(vec![]),
+            ..Default::default()
+        };
+        assert_eq!(
+            filter.check(&ipv4_frame(guest_mac(), allowed), None),
+            Verdict::Ipv4Source
+        );
+    }
+
+    #[test]
+    fn test_spoofed_arp() {
+        let allowed = Ipv4Addr::new(192, 168, 0, 2);
+        let filter = TxFilter {
+            drop_spoofed_arp: true,
+            ..Default::default()
+        };
+        assert!(filter.needs_guest_mac());
+
+        // Without an allowed list, only the sender hardware address is checked. The frame
+        // source MAC isn't, since `drop_spoofed_mac` is disabled.
+        for operation in [OPER_REQUEST, OPER_REPLY].iter() {
+            let mut frame = arp_frame(*operation, guest_mac(), Ipv4Addr::new(10, 0, 0, 7));
+            assert_eq!(filter.check(&frame, Some(guest_mac())), Verdict::Pass);
+            write_eth(&mut frame, other_mac(), ETHERTYPE_ARP);
+            assert_eq!(filter.check(&frame, Some(guest_mac())), Verdict::Pass);
+
+            let frame = arp_frame(*operation, other_mac(), allowed);
+            assert_eq!(filter.check(&frame, Some(guest_mac())), Verdict::SpoofedArp);
+        }
+
+        let filter = TxFilter {
+            allowed_ipv4_sources: Some(vec![allowed]),
+            drop_spoofed_arp: true,
+            ..Default::default()
+        };
+        let frame = arp_frame(OPER_REPLY, guest_mac(), allowed);
+        assert_eq!(filter.check(&frame, Some(guest_mac())), Verdict::Pass);
+        // Address probes are sent from the unspecified address.
+        let frame = arp_frame(OPER_REQUEST, guest_mac(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(filter.check(&frame, Some(guest_mac())), Verdict::Pass);
+        let frame = arp_frame(OPER_REPLY, guest_mac(), Ipv4Addr::new(192, 168, 0, 1));
+        assert_eq!(filter.check(&frame, Some(guest_mac())), Verdict::SpoofedArp);
+
+        // Frames padded to the minimum Ethernet size are fine.
+        let mut frame = arp_frame(OPER_REPLY, guest_mac(), allowed);
+        frame.resize(60, 0);
+        assert_eq!(filter.check(&frame, Some(guest_mac())), Verdict::Pass);
+
+        // A truncated ARP frame.
+        let frame = arp_frame(OPER_REPLY, guest_mac(), allowed);
+        assert_eq!(
+            filter.check(&frame[..frame.len() - 1], Some(guest_mac())),
+            Verdict::Malformed
+        );
+
+        // ARP frames which aren't about IPv4 over Ethernet.
+        let mut frame = arp_frame(OPER_REPLY, guest_mac(), allowed);
+        frame[PAYLOAD_OFFSET + 1] = 0x06;
+        assert_eq!(filter.check(&frame, Some(guest_mac())), Verdict::Malformed);
+    }
+
+    #[test]
+    fn test_vlan_tagged() {
+        let allowed = Ipv4Addr::new(192, 168, 0, 2);
+        // A tagged frame hiding a spoofed IPv4 packet behind the 4 bytes of the tag.
+        let untagged = ipv4_frame(guest_mac(), Ipv4Addr::new(192, 168, 0, 3));
+        for ethertype in [ETHERTYPE_VLAN, ETHERTYPE_QINQ].iter() {
+            let mut frame = untagged[..PAYLOAD_OFFSET].to_vec();
+            frame.extend_from_slice(&[0x00, 0x05]);
+            frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+            frame.extend_from_slice(&untagged[PAYLOAD_OFFSET..]);
+            write_eth(&mut frame, guest_mac(), *ethertype);
+
+            let filter = TxFilter {
+                allowed_ipv4_sources: Some(vec![allowed]),
+                ..Default::default()
+            };
+            assert_eq!(filter.check(&frame, None), Verdict::VlanTagged);
+
+            let filter = TxFilter {
+                drop_spoofed_arp: true,
+                ..Default::default()
+            };
+            assert_eq!(filter.check(&frame, Some(guest_mac())), Verdict::VlanTagged);
+
+            // Only the address rules need to look past the Ethernet header.
+            let filter = TxFilter {
+                drop_spoofed_mac: true,
+                ..Default::default()
+            };
+            assert_eq!(filter.check(&frame, Some(guest_mac())), Verdict::Pass);
+        }
+    }
+
+    #[test]
+    fn test_persistence() {
+        let filter = TxFilter {
+            drop_spoofed_mac: true,
+            allowed_ipv4_sources: Some(vec![Ipv4Addr::new(192, 168, 0, 2)]),
+            drop_spoofed_arp: true,
+        };
+        let mut mem = vec![0u8; 1024];
+        let version_map = VersionMap::new();
+        TxFilterState::from(&filter)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+        let state = TxFilterState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap();
+        assert_eq!(TxFilter::from(&state), filter);
+    }
+}
@@ -0,0 +1,1718 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A user-mode network stack, which connects a net device to the host network without a tap
+//! device.
+//!
+//! The stack plays the part of a NAT router on a private network, similar to the one of slirp:
+//! the guest gets `10.0.2.15` through DHCP, the router answers at `10.0.2.2`, and `10.0.2.3`
+//! forwards to the first name server of the host. The TCP connections and UDP flows of the guest
+//! are terminated in the VMM and proxied through ordinary host sockets, so they appear to come
+//! from the VMM process. Other protocols, such as ICMP, are dropped.
+//!
+//! The guest can reach whatever the VMM process can, except for the destinations ruled out by
+//! the `UserNetworkConfig`: by default, the link-local network, which holds the instance metadata
+//! services of cloud providers, and the host loopback interface. The router address only maps to
+//! the host loopback interface when `host_loopback` is set, and the name server address only
+//! forwards DNS traffic.
+//!
+//! All the host sockets are registered under a nested epoll FD, which the device polls in place
+//! of the tap.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream, UdpSocket};
+use std::num::{NonZeroU16, NonZeroU64, NonZeroUsize, Wrapping};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::time::{Duration, Instant};
+use std::{fmt, fs, mem};
+
+use dumbo::pdu::arp::{Error as ArpFrameError, EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
+use dumbo::pdu::dhcp::{self, DhcpMessage, DhcpOption, MessageType};
+use dumbo::pdu::ethernet::{
+    Error as EthernetFrameError, EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4,
+};
+use dumbo::pdu::ipv4::{Error as IPv4PacketError, IPv4Packet, PROTOCOL_TCP, PROTOCOL_UDP};
+use dumbo::pdu::tcp::{Error as TcpSegmentError, Flags as TcpFlags, TcpSegment};
+use dumbo::pdu::udp::{Error as UdpDatagramError, UdpDatagram, UDP_HEADER_SIZE};
+use dumbo::tcp::connection::Connection;
+use dumbo::tcp::{seq_after, NextSegmentStatus, RstConfig};
+use logger::{debug, error, warn, IncMetric, METRICS};
+use serde::de::Error as DeserializeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use utils::net::mac::MacAddr;
+use utils::time::timestamp_cycles;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
+const GATEWAY_MAC_ADDR: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const GATEWAY_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+const DNS_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+const GUEST_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+const DNS_PORT: u16 = 53;
+// Holds the instance metadata services of cloud providers, such as `169.254.169.254`.
+const LINK_LOCAL_NET: Ipv4Net = Ipv4Net {
+    addr: Ipv4Addr::new(169, 254, 0, 0),
+    prefix_len: 16,
+};
+
+// The frames of the stack fit in a regular Ethernet MTU, since no offloads are negotiated.
+const MTU: u16 = 1500;
+const IPV4_HEADER_LEN: usize = 20;
+const MAX_UDP_PAYLOAD: usize = MTU as usize - IPV4_HEADER_LEN - UDP_HEADER_SIZE;
+const DHCP_LEASE_TIME: u32 = 86400;
+// Large enough for the replies of the DHCP server.
+const DHCP_REPLY_MAX_LEN: usize = 576;
+
+const MAX_TCP_FLOWS: usize = 256;
+const MAX_UDP_FLOWS: usize = 256;
+const MAX_PENDING_DATAGRAMS: usize = 64;
+const MAX_PENDING_RESETS: usize = 64;
+// Both the receive window and the send buffer of the TCP flows. The window scaling option isn't
+// supported, so it can't be larger.
+const TCP_BUF_SIZE: usize = u16::MAX as usize;
+const CONNECTION_RTO_PERIOD: u64 = 1_200_000_000;
+const CONNECTION_RTO_COUNT_MAX: u16 = 15;
+const UDP_FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+// The period of the timer which expires the UDP flows, armed while there are flows.
+const TIMER_PERIOD: Duration = Duration::from_secs(1);
+const EPOLL_EVENTS_LEN: usize = 32;
+
+const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+
+/// An IPv4 network, written as `<address>/<prefix length>`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ipv4Net {
+    addr: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Net {
+    /// Creates the network of `addr` with the given prefix length, or returns `None` if the
+    /// prefix length is larger than 32. The host bits of `addr` are ignored.
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> Option<Self> {
+        if prefix_len > 32 {
+            return None;
+        }
+        Some(Ipv4Net {
+            addr: Ipv4Addr::from(u32::from(addr) & Self::mask(prefix_len)),
+            prefix_len,
+        })
+    }
+
+    /// Parses a network written as `<address>/<prefix length>`.
+    pub fn parse_str(s: &str) -> Option<Self> {
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next()?.parse().ok()?;
+        let prefix_len = parts.next()?.parse().ok()?;
+        Self::new(addr, prefix_len)
+    }
+
+    fn mask(prefix_len: u8) -> u32 {
+        u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0)
+    }
+
+    /// Returns whether `addr` belongs to the network.
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & Self::mask(self.prefix_len) == u32::from(self.addr)
+    }
+}
+
+impl fmt::Display for Ipv4Net {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Serialize for Ipv4Net {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Ipv4Net {
+    fn deserialize<D>(deserializer: D) -> Result<Ipv4Net, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ipv4Net::parse_str(&s)
+            .ok_or_else(|| D::Error::custom("The provided IPv4 network is invalid."))
+    }
+}
+
+/// The destinations the guest can reach through the user-mode network stack.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserNetworkConfig {
+    /// Forward the traffic sent to the router address to the host loopback interface, which
+    /// exposes the services only listening there to the guest.
+    #[serde(default)]
+    pub host_loopback: bool,
+    /// When present, only the destinations in these networks can be reached.
+    #[serde(default)]
+    pub allowed_networks: Option<Vec<Ipv4Net>>,
+    /// The destinations in these networks can't be reached. Defaults to the link-local network.
+    #[serde(default = "default_denied_networks")]
+    pub denied_networks: Vec<Ipv4Net>,
+}
+
+fn default_denied_networks() -> Vec<Ipv4Net> {
+    vec![LINK_LOCAL_NET]
+}
+
+impl Default for UserNetworkConfig {
+    fn default() -> Self {
+        UserNetworkConfig {
+            host_loopback: false,
+            allowed_networks: None,
+            denied_networks: default_denied_networks(),
+        }
+    }
+}
+
+impl UserNetworkConfig {
+    fn allows(&self, addr: Ipv4Addr) -> bool {
+        self.allowed_networks
+            .as_ref()
+            .map_or(true, |allowed| allowed.iter().any(|net| net.contains(addr)))
+            && !self.denied_networks.iter().any(|net| net.contains(addr))
+    }
+}
+
+/// The saved state of an `Ipv4Net`.
+#[derive(Clone, Debug, Default, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct Ipv4NetState {
+    addr: u32,
+    prefix_len: u8,
+}
+
+/// The saved state of a `UserNetworkConfig`.
+#[derive(Clone, Debug, Default, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct UserNetworkConfigState {
+    host_loopback: bool,
+    allowed_networks: Option<Vec<Ipv4NetState>>,
+    denied_networks: Vec<Ipv4NetState>,
+}
+
+impl From<&UserNetworkConfig> for UserNetworkConfigState {
+    fn from(config: &UserNetworkConfig) -> Self {
+        let save = |nets: &Vec<Ipv4Net>| {
+            nets.iter()
+                .map(|net| Ipv4NetState {
+                    addr: u32::from(net.addr),
+                    prefix_len: net.prefix_len,
+                })
+                .collect()
+        };
+        UserNetworkConfigState {
+            host_loopback: config.host_loopback,
+            allowed_networks: config.allowed_networks.as_ref().map(save),
+            denied_networks: save(&config.denied_networks),
+        }
+    }
+}
+
+impl From<&UserNetworkConfigState> for UserNetworkConfig {
+    fn from(state: &UserNetworkConfigState) -> Self {
+        let restore = |nets: &Vec<Ipv4NetState>| {
+            nets.iter()
+                .map(|net| Ipv4Net {
+                    addr: Ipv4Addr::from(net.addr),
+                    prefix_len: net.prefix_len.min(32),
+                })
+                .collect()
+        };
+        UserNetworkConfig {
+            host_loopback: state.host_loopback,
+            allowed_networks: state.allowed_networks.as_ref().map(restore),
+            denied_networks: restore(&state.denied_networks),
+        }
+    }
+}
+
+#[derive(Debug, derive_more::From)]
+enum WriteFrameError {
+    Arp(ArpFrameError),
+    Ethernet(EthernetFrameError),
+    IPv4Packet(IPv4PacketError),
+    TcpSegment(TcpSegmentError),
+    UdpDatagram(UdpDatagramError),
+}
+
+// A TCP connection of the guest, identified by the guest port and the remote end, as seen by the
+// guest. The guest always uses the same address.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct TcpTuple {
+    guest_port: u16,
+    remote_addr: Ipv4Addr,
+    remote_port: u16,
+}
+
+// The host sockets registered under the nested epoll FD.
+#[derive(Clone, Copy, Debug)]
+enum HostSocket {
+    Tcp(TcpTuple),
+    // The UDP flows are identified by the guest port.
+    Udp(u16),
+}
+
+// A datagram waiting to be sent to the guest.
+struct Datagram {
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    payload: Vec<u8>,
+}
+
+enum TcpFlowState {
+    // The host connection is in progress. The guest gets the SYNACK once it's established, so
+    // the SYN is kept until then.
+    Connecting(Vec<u8>),
+    Open(Connection),
+}
+
+// Proxies a TCP connection of the guest through a host socket.
+struct TcpFlow {
+    stream: TcpStream,
+    state: TcpFlowState,
+    // The data received from the guest, which has yet to be written to the host socket.
+    to_host: Box<[u8]>,
+    to_host_len: usize,
+    // The data read from the host socket, which has yet to be acknowledged by the guest.
+    to_guest: Vec<u8>,
+    // The sequence number of the first byte of `to_guest`.
+    to_guest_seq: Wrapping<u32>,
+    // The host closed its end of the connection.
+    host_eof: bool,
+    // The FIN of the guest has been passed on to the host.
+    host_shutdown: bool,
+}
+
+impl TcpFlow {
+    fn new(stream: TcpStream, syn: &[u8]) -> Self {
+        TcpFlow {
+            stream,
+            state: TcpFlowState::Connecting(syn.to_vec()),
+            to_host: vec![0u8; TCP_BUF_SIZE].into_boxed_slice(),
+            to_host_len: 0,
+            to_guest: Vec::new(),
+            to_guest_seq: Wrapping(0),
+            host_eof: false,
+            host_shutdown: false,
+        }
+    }
+
+    // Accepts the connection of the guest, once the host one is established. Returns false if
+    // the SYN can't be accepted.
+    fn open(&mut self) -> bool {
+        let connection = match &self.state {
+            TcpFlowState::Connecting(syn) => Connection::passive_open(
+                &TcpSegment::from_bytes_unchecked(syn.as_slice()),
+                TCP_BUF_SIZE as u32,
+                // The unwraps are safe because the constants are greater than 0.
+                NonZeroU64::new(CONNECTION_RTO_PERIOD).unwrap(),
+                NonZeroU16::new(CONNECTION_RTO_COUNT_MAX).unwrap(),
+            ),
+            TcpFlowState::Open(_) => return true,
+        };
+        match connection {
+            Ok(connection) => {
+                // The connection was created via passive open, so this is the sequence number
+                // right after the SYNACK.
+                self.to_guest_seq = connection.first_not_sent();
+                self.state = TcpFlowState::Open(connection);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // The flow can go once both ends are closed, and the last segments have been sent.
+    fn is_done(&self, now: u64) -> bool {
+        match &self.state {
+            TcpFlowState::Connecting(_) => false,
+            TcpFlowState::Open(connection) => {
+                connection.is_done() && self.to_host_len == 0 && !self.has_segment_to_send(now)
+            }
+        }
+    }
+
+    fn receive_segment(&mut self, segment: &TcpSegment<&[u8]>, now: u64) {
+        let connection = match &mut self.state {
+            TcpFlowState::Open(connection) => connection,
+            // The guest can only retransmit its SYN at this point.
+            TcpFlowState::Connecting(_) => return,
+        };
+
+        // The receive window matches the free space of the buffer, so the data always fits.
+        match connection.receive_segment(segment, &mut self.to_host[self.to_host_len..], now) {
+            Ok((len, _)) => self.to_host_len += len.map_or(0, NonZeroUsize::get),
+            Err(_) => return,
+        }
+
+        // Drop the data acknowledged by the guest.
+        let highest_ack = connection.highest_ack_received();
+        if seq_after(highest_ack, self.to_guest_seq) {
+            let acked = std::cmp::min(
+                (highest_ack - self.to_guest_seq).0 as usize,
+                self.to_guest.len(),
+            );
+            self.to_guest.drain(..acked);
+            self.to_guest_seq += Wrapping(acked as u32);
+        }
+
+        self.flush_to_host();
+        self.read_host();
+    }
+
+    // Writes as much of the guest data as possible to the host socket.
+    fn flush_to_host(&mut self) {
+        let connection = match &mut self.state {
+            TcpFlowState::Open(connection) => connection,
+            TcpFlowState::Connecting(_) => return,
+        };
+
+        while self.to_host_len > 0 {
+            match self.stream.write(&self.to_host[..self.to_host_len]) {
+                Ok(len) => {
+                    self.to_host.copy_within(len..self.to_host_len, 0);
+                    self.to_host_len -= len;
+                    connection.advance_local_rwnd_edge(len as u32);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    debug!("user net: cannot write to the host socket: {:?}", err);
+                    connection.reset();
+                    return;
+                }
+            }
+        }
+
+        if connection.fin_received() && !self.host_shutdown {
+            self.host_shutdown = true;
+            let _ = self.stream.shutdown(Shutdown::Write);
+        }
+    }
+
+    // Reads as much data from the host socket as the send buffer can hold.
+    fn read_host(&mut self) {
+        if let TcpFlowState::Connecting(_) = self.state {
+            return;
+        }
+
+        while !self.host_eof && self.to_guest.len() < TCP_BUF_SIZE {
+            let len = self.to_guest.len();
+            self.to_guest.resize(TCP_BUF_SIZE, 0);
+            let result = self.stream.read(&mut self.to_guest[len..]);
+            self.to_guest
+                .truncate(len + result.as_ref().map_or(0, |read_len| *read_len));
+            match result {
+                Ok(0) => self.host_eof = true,
+                Ok(_) => (),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    debug!("user net: cannot read from the host socket: {:?}", err);
+                    if let TcpFlowState::Open(connection) = &mut self.state {
+                        connection.reset();
+                    }
+                    return;
+                }
+            }
+        }
+
+        self.close_if_sent();
+    }
+
+    // Closes the connection of the guest after the host did, once all the data has been sent.
+    fn close_if_sent(&mut self) {
+        if let TcpFlowState::Open(connection) = &mut self.state {
+            let end_seq = self.to_guest_seq + Wrapping(self.to_guest.len() as u32);
+            if self.host_eof && connection.first_not_sent() == end_seq {
+                connection.close();
+            }
+        }
+    }
+
+    fn has_segment_to_send(&self, now: u64) -> bool {
+        let connection = match &self.state {
+            TcpFlowState::Open(connection) => connection,
+            TcpFlowState::Connecting(_) => return false,
+        };
+
+        let end_seq = self.to_guest_seq + Wrapping(self.to_guest.len() as u32);
+        let can_send_new_data = seq_after(end_seq, connection.first_not_sent())
+            && seq_after(connection.remote_rwnd_edge(), connection.first_not_sent());
+
+        if can_send_new_data || connection.dup_ack_pending() {
+            return true;
+        }
+        match connection.control_segment_or_timeout_status() {
+            NextSegmentStatus::Available => true,
+            NextSegmentStatus::Timeout(value) => now >= value,
+            NextSegmentStatus::Nothing => false,
+        }
+    }
+
+    // Writes the next segment, without the ports and the checksum.
+    fn write_next_segment<'a>(
+        &mut self,
+        buf: &'a mut [u8],
+        now: u64,
+    ) -> Option<dumbo::pdu::Incomplete<TcpSegment<'a, &'a mut [u8]>>> {
+        let connection = match &mut self.state {
+            TcpFlowState::Open(connection) => connection,
+            TcpFlowState::Connecting(_) => return None,
+        };
+
+        let payload_src = if self.to_guest.is_empty() {
+            None
+        } else {
+            Some((self.to_guest.as_slice(), self.to_guest_seq))
+        };
+        // We don't add any IP options, so none of the MSS is used at the lower layers.
+        let result = connection.write_next_segment(buf, 0, payload_src, now);
+        let segment = match result {
+            Ok(segment) => segment,
+            Err(_) => {
+                METRICS.net.user_rx_drops.inc();
+                None
+            }
+        };
+        if segment.is_some() {
+            // The segment may have carried the last of the data.
+            self.close_if_sent();
+        }
+        segment
+    }
+}
+
+// Relays the datagrams of a guest port through a host socket.
+struct UdpFlow {
+    socket: UdpSocket,
+    last_active: Instant,
+}
+
+// Opens a TCP connection to `addr`, without waiting for it to be established.
+fn connect_nonblocking(addr: SocketAddrV4) -> io::Result<TcpStream> {
+    // This is safe because the return value is checked.
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // This is safe because the socket was just created, and nothing else owns it.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    stream.set_nonblocking(true)?;
+
+    let sockaddr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // This is safe because `sockaddr` is a valid address of the given length.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+
+    Ok(stream)
+}
+
+// Returns the first IPv4 name server of the host, if any.
+fn host_name_server(resolv_conf: &str) -> Option<Ipv4Addr> {
+    resolv_conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("nameserver"), Some(addr)) => addr.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+fn in_guest_network(addr: Ipv4Addr) -> bool {
+    u32::from(addr) & u32::from(NETMASK) == u32::from(GATEWAY_ADDR) & u32::from(NETMASK)
+}
+
+/// The user-mode network stack of a net device.
+pub struct UserNetwork {
+    // The nested epoll FD, under which the host sockets and the timer are registered.
+    epoll: Epoll,
+    // Expires the UDP flows, and wakes up the device for the TCP retransmissions.
+    timer: TimerFd,
+    timer_armed: bool,
+    // The name server behind `DNS_ADDR`.
+    host_dns: Option<Ipv4Addr>,
+    config: UserNetworkConfig,
+    // Learned from the frames of the guest, which always starts the conversation.
+    guest_mac: Option<MacAddr>,
+    // The sender and the target of the last ARP request of the guest.
+    pending_arp_reply: Option<(MacAddr, Ipv4Addr, Ipv4Addr)>,
+    pending_datagrams: VecDeque<Datagram>,
+    pending_resets: Vec<(TcpTuple, RstConfig)>,
+    host_sockets: HashMap<RawFd, HostSocket>,
+    tcp_flows: HashMap<TcpTuple, TcpFlow>,
+    udp_flows: HashMap<u16, UdpFlow>,
+    // Holds the datagrams read from the host sockets.
+    udp_buf: Box<[u8]>,
+}
+
+impl UserNetwork {
+    /// Creates a user-mode network stack, which resolves names through the first name server of
+    /// the host, if there is one.
+    pub fn new() -> io::Result<Self> {
+        let host_dns = fs::read_to_string(RESOLV_CONF_PATH)
+            .ok()
+            .and_then(|resolv_conf| host_name_server(&resolv_conf));
+        if host_dns.is_none() {
+            warn!("user net: no name server found in {}", RESOLV_CONF_PATH);
+        }
+
+        let epoll = Epoll::new()?;
+        let timer = TimerFd::new_custom(ClockId::Monotonic, true, true)?;
+        epoll.ctl(
+            ControlOperation::Add,
+            timer.as_raw_fd(),
+            EpollEvent::new(
+                EventSet::IN | EventSet::EDGE_TRIGGERED,
+                timer.as_raw_fd() as u64,
+            ),
+        )?;
+
+        Ok(UserNetwork {
+            epoll,
+            timer,
+            timer_armed: false,
+            host_dns,
+            config: UserNetworkConfig::default(),
+            guest_mac: None,
+            pending_arp_reply: None,
+            pending_datagrams: VecDeque::new(),
+            pending_resets: Vec::new(),
+            host_sockets: HashMap::new(),
+            tcp_flows: HashMap::new(),
+            udp_flows: HashMap::new(),
+            udp_buf: vec![0u8; u16::MAX as usize].into_boxed_slice(),
+        })
+    }
+
+    /// Provides the destinations the guest can reach.
+    pub fn config(&self) -> &UserNetworkConfig {
+        &self.config
+    }
+
+    /// Replaces the destinations the guest can reach. The flows already open are kept.
+    pub fn set_config(&mut self, config: UserNetworkConfig) {
+        self.config = config;
+    }
+
+    /// Handles a frame sent by the guest. The `frame` slice should hold the contents of an
+    /// Ethernet frame, of that exact size.
+    pub fn handle_guest_frame(&mut self, frame: &[u8]) {
+        let handled = match EthernetFrame::from_bytes(frame) {
+            Ok(eth) => {
+                self.guest_mac = Some(eth.src_mac());
+                match eth.ethertype() {
+                    ETHERTYPE_ARP => self.handle_arp(eth.payload()),
+                    ETHERTYPE_IPV4 => self.handle_ipv4(eth.payload()),
+                    _ => false,
+                }
+            }
+            Err(_) => false,
+        };
+        if !handled {
+            METRICS.net.user_tx_drops.inc();
+        }
+    }
+
+    fn handle_arp(&mut self, payload: &[u8]) -> bool {
+        let arp = match payload
+            .get(..ETH_IPV4_FRAME_LEN)
+            .map(EthIPv4ArpFrame::request_from_bytes)
+        {
+            Some(Ok(arp)) => arp,
+            _ => return false,
+        };
+        // Only the router and the name server live next to the guest.
+        let tpa = arp.tpa();
+        if tpa == GATEWAY_ADDR || (tpa == DNS_ADDR && self.host_dns.is_some()) {
+            self.pending_arp_reply = Some((arp.sha(), arp.spa(), tpa));
+            return true;
+        }
+        false
+    }
+
+    fn handle_ipv4(&mut self, payload: &[u8]) -> bool {
+        let packet = match IPv4Packet::from_bytes(payload, true) {
+            Ok(packet) => packet,
+            Err(_) => return false,
+        };
+        // Fragments are not reassembled.
+        let (flags, fragment_offset) = packet.flags_and_fragment_offset();
+        if flags & 0x1 != 0 || fragment_offset != 0 {
+            return false;
+        }
+
+        match packet.protocol() {
+            PROTOCOL_TCP => self.handle_tcp(&packet),
+            PROTOCOL_UDP => self.handle_udp(&packet),
+            _ => false,
+        }
+    }
+
+    // Returns the host address behind an address and port the guest sends to, if they can be
+    // reached.
+    fn host_addr(
+        config: &UserNetworkConfig,
+        host_dns: Option<Ipv4Addr>,
+        addr: Ipv4Addr,
+        port: u16,
+    ) -> Option<Ipv4Addr> {
+        if addr == GATEWAY_ADDR {
+            Some(Ipv4Addr::LOCALHOST).filter(|_| config.host_loopback)
+        } else if addr == DNS_ADDR {
+            // The name server may listen on the host loopback interface.
+            host_dns.filter(|_| port == DNS_PORT)
+        } else if in_guest_network(addr)
+            || addr.is_broadcast()
+            || addr.is_multicast()
+            || addr.is_unspecified()
+            || addr.is_loopback()
+            || !config.allows(addr)
+        {
+            None
+        } else {
+            Some(addr)
+        }
+    }
+
+    // Returns the address the guest sees in place of a host address.
+    fn guest_visible_addr(host_dns: Option<Ipv4Addr>, addr: Ipv4Addr) -> Ipv4Addr {
+        if Some(addr) == host_dns {
+            DNS_ADDR
+        } else if addr.is_loopback() {
+            GATEWAY_ADDR
+        } else {
+            addr
+        }
+    }
+
+    fn handle_tcp(&mut self, packet: &IPv4Packet<&[u8]>) -> bool {
+        let (src_addr, dst_addr) = (packet.source_address(), packet.destination_address());
+        let segment = match TcpSegment::from_bytes(packet.payload(), Some((src_addr, dst_addr))) {
+            Ok(segment) => segment,
+            Err(_) => return false,
+        };
+        let tuple = TcpTuple {
+            guest_port: segment.source_port(),
+            remote_addr: dst_addr,
+            remote_port: segment.destination_port(),
+        };
+
+        if let Some(flow) = self.tcp_flows.get_mut(&tuple) {
+            let now = timestamp_cycles();
+            flow.receive_segment(&segment, now);
+            if flow.is_done(now) {
+                self.remove_tcp_flow(tuple);
+            }
+            return true;
+        }
+
+        let flags = segment.flags_after_ns();
+        if flags != TcpFlags::SYN {
+            // Reply to unexpected segments with RSTs, except for other RSTs.
+            if !flags.intersects(TcpFlags::RST) {
+                self.enqueue_rst(tuple, RstConfig::new(&segment));
+            }
+            return true;
+        }
+
+        // The RST refusing a SYN has to acknowledge it.
+        let refusal = RstConfig::Ack(segment.sequence_number().wrapping_add(1));
+        let host_addr =
+            match Self::host_addr(&self.config, self.host_dns, dst_addr, tuple.remote_port) {
+                Some(addr) if self.tcp_flows.len() < MAX_TCP_FLOWS => addr,
+                _ => {
+                    self.enqueue_rst(tuple, refusal);
+                    return true;
+                }
+            };
+        match connect_nonblocking(SocketAddrV4::new(host_addr, tuple.remote_port)) {
+            Ok(stream) => {
+                let fd = stream.as_raw_fd();
+                if let Err(err) = self.register_host_socket(fd, HostSocket::Tcp(tuple)) {
+                    error!("user net: cannot register the host socket: {:?}", err);
+                    self.enqueue_rst(tuple, refusal);
+                    return true;
+                }
+                self.tcp_flows
+                    .insert(tuple, TcpFlow::new(stream, packet.payload()));
+                METRICS.net.user_flows_created.inc();
+                self.update_timer();
+            }
+            Err(err) => {
+                debug!("user net: cannot connect to {}: {:?}", host_addr, err);
+                METRICS.net.user_connect_fails.inc();
+                self.enqueue_rst(tuple, refusal);
+            }
+        }
+        true
+    }
+
+    fn handle_udp(&mut self, packet: &IPv4Packet<&[u8]>) -> bool {
+        let (src_addr, dst_addr) = (packet.source_address(), packet.destination_address());
+        let datagram = match UdpDatagram::from_bytes(packet.payload(), Some((src_addr, dst_addr))) {
+            Ok(datagram) => datagram,
+            Err(_) => return false,
+        };
+        if datagram.destination_port() == dhcp::SERVER_PORT {
+            return self.handle_dhcp(datagram.payload());
+        }
+
+        let host_addr = match Self::host_addr(
+            &self.config,
+            self.host_dns,
+            dst_addr,
+            datagram.destination_port(),
+        ) {
+            Some(addr) => addr,
+            None => return false,
+        };
+        let guest_port = datagram.source_port();
+        if !self.udp_flows.contains_key(&guest_port) {
+            if self.udp_flows.len() >= MAX_UDP_FLOWS {
+                return false;
+            }
+            let socket = match UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+                .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+            {
+                Ok(socket) => socket,
+                Err(err) => {
+                    error!("user net: cannot open a UDP socket: {:?}", err);
+                    return false;
+                }
+            };
+            if let Err(err) =
+                self.register_host_socket(socket.as_raw_fd(), HostSocket::Udp(guest_port))
+            {
+                error!("user net: cannot register the host socket: {:?}", err);
+                return false;
+            }
+            self.udp_flows.insert(
+                guest_port,
+                UdpFlow {
+                    socket,
+                    last_active: Instant::now(),
+                },
+            );
+            METRICS.net.user_flows_created.inc();
+            self.update_timer();
+        }
+
+        // The unwrap is safe because the flow was added above if missing.
+        let flow = self.udp_flows.get_mut(&guest_port).unwrap();
+        flow.last_active = Instant::now();
+        let dst = SocketAddrV4::new(host_addr, datagram.destination_port());
+        if let Err(err) = flow.socket.send_to(datagram.payload(), dst) {
+            debug!("user net: cannot send a datagram to {}: {:?}", dst, err);
+        }
+        true
+    }
+
+    fn handle_dhcp(&mut self, payload: &[u8]) -> bool {
+        let request = match DhcpMessage::from_bytes(payload) {
+            Ok(request) if request.op() == dhcp::OP_BOOTREQUEST => request,
+            _ => return false,
+        };
+        let message_type = match request.message_type() {
+            Some(MessageType::Discover) => MessageType::Offer,
+            Some(MessageType::Request) => {
+                let requested_addr = request
+                    .requested_ip_addr()
+                    .unwrap_or_else(|| request.ciaddr());
+                if requested_addr == GUEST_ADDR {
+                    MessageType::Ack
+                } else {
+                    MessageType::Nak
+                }
+            }
+            // There is a single address, so declines and releases don't change anything.
+            _ => return true,
+        };
+
+        let dns_servers = [DNS_ADDR];
+        let mut options = vec![
+            DhcpOption::MessageType(message_type),
+            DhcpOption::ServerId(GATEWAY_ADDR),
+        ];
+        let yiaddr = if message_type == MessageType::Nak {
+            Ipv4Addr::UNSPECIFIED
+        } else {
+            options.extend_from_slice(&[
+                DhcpOption::LeaseTime(DHCP_LEASE_TIME),
+                DhcpOption::SubnetMask(NETMASK),
+                DhcpOption::Router(GATEWAY_ADDR),
+                DhcpOption::InterfaceMtu(MTU),
+            ]);
+            if self.host_dns.is_some() {
+                options.push(DhcpOption::DnsServers(&dns_servers));
+            }
+            GUEST_ADDR
+        };
+
+        let mut reply = vec![0u8; DHCP_REPLY_MAX_LEN];
+        let len = match DhcpMessage::write_reply(
+            reply.as_mut_slice(),
+            &request,
+            yiaddr,
+            GATEWAY_ADDR,
+            &options,
+        ) {
+            Ok(reply) => reply.len(),
+            Err(err) => {
+                error!("user net: cannot write the DHCP reply: {:?}", err);
+                return false;
+            }
+        };
+        reply.truncate(len);
+
+        // The client can't receive unicast datagrams before it's configured.
+        let dst_addr =
+            if message_type == MessageType::Nak || request.ciaddr() == Ipv4Addr::UNSPECIFIED {
+                Ipv4Addr::BROADCAST
+            } else {
+                request.ciaddr()
+            };
+        self.enqueue_datagram(Datagram {
+            src: SocketAddrV4::new(GATEWAY_ADDR, dhcp::SERVER_PORT),
+            dst: SocketAddrV4::new(dst_addr, dhcp::CLIENT_PORT),
+            payload: reply,
+        });
+        true
+    }
+
+    fn enqueue_datagram(&mut self, datagram: Datagram) {
+        if self.pending_datagrams.len() < MAX_PENDING_DATAGRAMS {
+            self.pending_datagrams.push_back(datagram);
+        } else {
+            METRICS.net.user_rx_drops.inc();
+        }
+    }
+
+    fn enqueue_rst(&mut self, tuple: TcpTuple, rst_config: RstConfig) {
+        if self.pending_resets.len() < MAX_PENDING_RESETS {
+            self.pending_resets.push((tuple, rst_config));
+        } else {
+            METRICS.net.user_rx_drops.inc();
+        }
+    }
+
+    fn register_host_socket(&mut self, fd: RawFd, socket: HostSocket) -> io::Result<()> {
+        // The sockets are drained whenever they are signaled, and whenever their buffers free
+        // up, so edge triggered notifications are enough.
+        self.epoll.ctl(
+            ControlOperation::Add,
+            fd,
+            EpollEvent::new(
+                EventSet::IN | EventSet::OUT | EventSet::EDGE_TRIGGERED,
+                fd as u64,
+            ),
+        )?;
+        self.host_sockets.insert(fd, socket);
+        Ok(())
+    }
+
+    // Closing the socket also removes it from the nested epoll FD.
+    fn remove_tcp_flow(&mut self, tuple: TcpTuple) {
+        if let Some(flow) = self.tcp_flows.remove(&tuple) {
+            self.host_sockets.remove(&flow.stream.as_raw_fd());
+        }
+    }
+
+    fn update_timer(&mut self) {
+        let needed = !self.tcp_flows.is_empty() || !self.udp_flows.is_empty();
+        if needed == self.timer_armed {
+            return;
+        }
+        let timer_state = if needed {
+            TimerState::Periodic {
+                current: TIMER_PERIOD,
+                interval: TIMER_PERIOD,
+            }
+        } else {
+            TimerState::Disarmed
+        };
+        self.timer.set_state(timer_state, SetTimeFlags::Default);
+        self.timer_armed = needed;
+    }
+
+    /// Handles the pending events of the host sockets.
+    pub fn process_host_events(&mut self) {
+        let mut events = vec![EpollEvent::default(); EPOLL_EVENTS_LEN];
+        loop {
+            let count = match self.epoll.wait(0, events.as_mut_slice()) {
+                Ok(count) => count,
+                Err(err) => {
+                    error!("user net: failed to consume the epoll events: {}", err);
+                    METRICS.net.event_fails.inc();
+                    return;
+                }
+            };
+            for event in &events[..count] {
+                self.handle_host_event(event.fd(), event.event_set());
+            }
+            if count < events.len() {
+                return;
+            }
+        }
+    }
+
+    fn handle_host_event(&mut self, fd: RawFd, event_set: EventSet) {
+        match self.host_sockets.get(&fd).copied() {
+            Some(HostSocket::Tcp(tuple)) => self.handle_tcp_event(tuple, event_set),
+            Some(HostSocket::Udp(guest_port)) => self.read_udp_flow(guest_port),
+            None if fd == self.timer.as_raw_fd() => {
+                self.timer.read();
+                let now = Instant::now();
+                let host_sockets = &mut self.host_sockets;
+                self.udp_flows.retain(|_, flow| {
+                    let active = now.duration_since(flow.last_active) < UDP_FLOW_TIMEOUT;
+                    if !active {
+                        host_sockets.remove(&flow.socket.as_raw_fd());
+                    }
+                    active
+                });
+                self.update_timer();
+            }
+            // The socket was closed after the event was queued.
+            None => (),
+        }
+    }
+
+    fn handle_tcp_event(&mut self, tuple: TcpTuple, event_set: EventSet) {
+        let flow = match self.tcp_flows.get_mut(&tuple) {
+            Some(flow) => flow,
+            None => return,
+        };
+
+        if let TcpFlowState::Connecting(syn) = &flow.state {
+            let seq = TcpSegment::from_bytes_unchecked(syn.as_slice()).sequence_number();
+            let failed = event_set.intersects(EventSet::ERROR | EventSet::HANG_UP)
+                || !matches!(flow.stream.take_error(), Ok(None));
+            if failed || (event_set.contains(EventSet::OUT) && !flow.open()) {
+                METRICS.net.user_connect_fails.inc();
+                self.remove_tcp_flow(tuple);
+                self.enqueue_rst(tuple, RstConfig::Ack(seq.wrapping_add(1)));
+                return;
+            }
+            // The host may have sent data along with the connection, so the socket is drained
+            // right away.
+            if !event_set.contains(EventSet::OUT) {
+                return;
+            }
+        }
+
+        flow.flush_to_host();
+        flow.read_host();
+        if flow.is_done(timestamp_cycles()) {
+            self.remove_tcp_flow(tuple);
+        }
+    }
+
+    fn read_udp_flow(&mut self, guest_port: u16) {
+        let flow = match self.udp_flows.get_mut(&guest_port) {
+            Some(flow) => flow,
+            None => return,
+        };
+
+        loop {
+            let (len, src) = match flow.socket.recv_from(&mut self.udp_buf) {
+                Ok((len, SocketAddr::V4(src))) => (len, src),
+                Ok(_) => continue,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    debug!("user net: cannot receive a datagram: {:?}", err);
+                    return;
+                }
+            };
+            // The host socket receives from anyone, while the guest may only hear back from
+            // the destinations it can send to.
+            let guest_visible_src = Self::guest_visible_addr(self.host_dns, *src.ip());
+            if Self::host_addr(&self.config, self.host_dns, guest_visible_src, src.port())
+                != Some(*src.ip())
+            {
+                METRICS.net.user_rx_drops.inc();
+                continue;
+            }
+            flow.last_active = Instant::now();
+
+            // Datagrams which would have to be fragmented are dropped.
+            if len > MAX_UDP_PAYLOAD || self.pending_datagrams.len() >= MAX_PENDING_DATAGRAMS {
+                METRICS.net.user_rx_drops.inc();
+                continue;
+            }
+            self.pending_datagrams.push_back(Datagram {
+                src: SocketAddrV4::new(guest_visible_src, src.port()),
+                dst: SocketAddrV4::new(GUEST_ADDR, guest_port),
+                payload: self.udp_buf[..len].to_vec(),
+            });
+        }
+    }
+
+    /// Writes the next frame for the guest to `buf`, if there is one, and returns its length.
+    pub fn write_next_frame(&mut self, buf: &mut [u8]) -> Option<NonZeroUsize> {
+        // The guest always speaks first.
+        let guest_mac = self.guest_mac?;
+
+        loop {
+            let result = if let Some((tha, tpa, spa)) = self.pending_arp_reply.take() {
+                Self::write_arp_reply(buf, tha, tpa, spa)
+            } else if let Some(datagram) = self.pending_datagrams.pop_front() {
+                Self::write_datagram(buf, guest_mac, &datagram)
+            } else if let Some((tuple, rst_config)) = self.pending_resets.pop() {
+                Self::write_rst(buf, guest_mac, tuple, rst_config)
+            } else {
+                return self.write_tcp_segment(buf, guest_mac);
+            };
+
+            match result {
+                // The unwrap is safe because frames are never empty.
+                Ok(len) => return Some(NonZeroUsize::new(len).unwrap()),
+                Err(err) => {
+                    error!("user net: cannot write frame: {:?}", err);
+                    METRICS.net.user_rx_drops.inc();
+                }
+            }
+        }
+    }
+
+    fn write_arp_reply(
+        buf: &mut [u8],
+        tha: MacAddr,
+        tpa: Ipv4Addr,
+        spa: Ipv4Addr,
+    ) -> Result<usize, WriteFrameError> {
+        let gateway_mac = MacAddr::from_bytes_unchecked(&GATEWAY_MAC_ADDR);
+        let mut eth = EthernetFrame::write_incomplete(buf, tha, gateway_mac, ETHERTYPE_ARP)?;
+        let arp_len = EthIPv4ArpFrame::write_reply(
+            eth.inner_mut()
+                .payload_mut()
+                .split_at_mut(ETH_IPV4_FRAME_LEN)
+                .0,
+            gateway_mac,
+            spa,
+            tha,
+            tpa,
+        )?
+        .len();
+        Ok(eth.with_payload_len_unchecked(arp_len).len())
+    }
+
+    fn write_datagram(
+        buf: &mut [u8],
+        guest_mac: MacAddr,
+        datagram: &Datagram,
+    ) -> Result<usize, WriteFrameError> {
+        let gateway_mac = MacAddr::from_bytes_unchecked(&GATEWAY_MAC_ADDR);
+        let (src_addr, dst_addr) = (*datagram.src.ip(), *datagram.dst.ip());
+        let mut eth = EthernetFrame::write_incomplete(buf, guest_mac, gateway_mac, ETHERTYPE_IPV4)?;
+        let mut packet = IPv4Packet::write_header(
+            eth.inner_mut().payload_mut(),
+            PROTOCOL_UDP,
+            src_addr,
+            dst_addr,
+        )?;
+        let udp_len = UdpDatagram::write_incomplete_datagram(
+            packet.inner_mut().payload_mut(),
+            &datagram.payload,
+        )?
+        .finalize(
+            datagram.src.port(),
+            datagram.dst.port(),
+            Some((src_addr, dst_addr)),
+        )
+        .len();
+        let packet_len = packet
+            .with_payload_len_unchecked(usize::from(udp_len), true)
+            .len();
+        Ok(eth.with_payload_len_unchecked(packet_len).len())
+    }
+
+    fn write_rst(
+        buf: &mut [u8],
+        guest_mac: MacAddr,
+        tuple: TcpTuple,
+        rst_config: RstConfig,
+    ) -> Result<usize, WriteFrameError> {
+        let gateway_mac = MacAddr::from_bytes_unchecked(&GATEWAY_MAC_ADDR);
+        let mut eth = EthernetFrame::write_incomplete(buf, guest_mac, gateway_mac, ETHERTYPE_IPV4)?;
+        let mut packet = IPv4Packet::write_header(
+            eth.inner_mut().payload_mut(),
+            PROTOCOL_TCP,
+            tuple.remote_addr,
+            GUEST_ADDR,
+        )?;
+        let (seq, ack, flags_after_ns) = rst_config.seq_ack_tcp_flags();
+        let segment_len = TcpSegment::write_incomplete_segment::<[u8]>(
+            packet.inner_mut().payload_mut(),
+            seq,
+            ack,
+            flags_after_ns,
+            0,
+            None,
+            0,
+            None,
+        )?
+        .finalize(
+            tuple.remote_port,
+            tuple.guest_port,
+            Some((tuple.remote_addr, GUEST_ADDR)),
+        )
+        .len();
+        let packet_len = packet.with_payload_len_unchecked(segment_len, true).len();
+        Ok(eth.with_payload_len_unchecked(packet_len).len())
+    }
+
+    fn write_tcp_segment(&mut self, buf: &mut [u8], guest_mac: MacAddr) -> Option<NonZeroUsize> {
+        let gateway_mac = MacAddr::from_bytes_unchecked(&GATEWAY_MAC_ADDR);
+        let now = timestamp_cycles();
+        let mut written = None;
+
+        for (tuple, flow) in self.tcp_flows.iter_mut() {
+            if !flow.has_segment_to_send(now) {
+                continue;
+            }
+
+            // The buffer of the device always fits the headers.
+            let mut eth = EthernetFrame::write_incomplete(
+                &mut buf[..],
+                guest_mac,
+                gateway_mac,
+                ETHERTYPE_IPV4,
+            )
+            .ok()?;
+            let mut packet = IPv4Packet::write_header(
+                eth.inner_mut().payload_mut(),
+                PROTOCOL_TCP,
+                tuple.remote_addr,
+                GUEST_ADDR,
+            )
+            .ok()?;
+            let segment_len = match flow.write_next_segment(packet.inner_mut().payload_mut(), now) {
+                Some(segment) => segment
+                    .finalize(
+                        tuple.remote_port,
+                        tuple.guest_port,
+                        Some((tuple.remote_addr, GUEST_ADDR)),
+                    )
+                    .len(),
+                None => continue,
+            };
+            let packet_len = packet.with_payload_len_unchecked(segment_len, true).len();
+            let len = eth.with_payload_len_unchecked(packet_len).len();
+
+            written = Some((*tuple, flow.is_done(now), len));
+            break;
+        }
+
+        let (tuple, done, len) = written?;
+        if done {
+            self.remove_tcp_flow(tuple);
+        }
+        NonZeroUsize::new(len)
+    }
+}
+
+impl AsRawFd for UserNetwork {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    const GUEST_MAC_STR: &str = "12:34:56:78:9a:bc";
+    const GUEST_PORT: u16 = 42000;
+
+    fn guest_mac() -> MacAddr {
+        MacAddr::parse_str(GUEST_MAC_STR).unwrap()
+    }
+
+    // Writes an IPv4 frame from the guest, carrying the given transport payload.
+    fn write_ipv4_frame(
+        buf: &mut [u8],
+        protocol: u8,
+        dst_addr: Ipv4Addr,
+        write_payload: impl FnOnce(&mut [u8]) -> usize,
+    ) -> usize {
+        let gateway_mac = MacAddr::from_bytes_unchecked(&GATEWAY_MAC_ADDR);
+        let mut eth =
+            EthernetFrame::write_incomplete(buf, gateway_mac, guest_mac(), ETHERTYPE_IPV4).unwrap();
+        let mut packet = IPv4Packet::write_header(
+            eth.inner_mut().payload_mut(),
+            protocol,
+            GUEST_ADDR,
+            dst_addr,
+        )
+        .unwrap();
+        let payload_len = write_payload(packet.inner_mut().payload_mut());
+        let packet_len = packet.with_payload_len_unchecked(payload_len, true).len();
+        eth.with_payload_len_unchecked(packet_len).len()
+    }
+
+    fn write_tcp_frame(
+        buf: &mut [u8],
+        dst: SocketAddrV4,
+        seq: u32,
+        ack: u32,
+        flags: TcpFlags,
+        payload: &[u8],
+    ) -> usize {
+        write_ipv4_frame(buf, PROTOCOL_TCP, *dst.ip(), |buf| {
+            let payload = if payload.is_empty() {
+                None
+            } else {
+                Some((payload, payload.len()))
+            };
+            TcpSegment::write_segment(
+                buf,
+                GUEST_PORT,
+                dst.port(),
+                seq,
+                ack,
+                flags,
+                10000,
+                None,
+                1460,
+                payload,
+                Some((GUEST_ADDR, *dst.ip())),
+            )
+            .unwrap()
+            .len()
+        })
+    }
+
+    fn write_udp_frame(buf: &mut [u8], src_port: u16, dst: SocketAddrV4, payload: &[u8]) -> usize {
+        write_ipv4_frame(buf, PROTOCOL_UDP, *dst.ip(), |buf| {
+            UdpDatagram::write_incomplete_datagram(buf, payload)
+                .unwrap()
+                .finalize(src_port, dst.port(), Some((GUEST_ADDR, *dst.ip())))
+                .len()
+                .into()
+        })
+    }
+
+    // Returns the TCP segment of a frame written by the stack.
+    fn tcp_segment(frame: &[u8]) -> TcpSegment<&[u8]> {
+        let eth = EthernetFrame::from_bytes(frame).unwrap();
+        assert_eq!(eth.dst_mac(), guest_mac());
+        let packet = IPv4Packet::from_bytes(eth.payload(), true).unwrap();
+        assert_eq!(packet.protocol(), PROTOCOL_TCP);
+        assert_eq!(packet.destination_address(), GUEST_ADDR);
+        let (src, dst) = (packet.source_address(), packet.destination_address());
+        let len = packet.payload().len();
+        let offset = frame.len() - len;
+        TcpSegment::from_bytes(&frame[offset..], Some((src, dst))).unwrap()
+    }
+
+    // Polls the stack until it has a frame for the guest.
+    fn wait_frame(net: &mut UserNetwork, buf: &mut [u8]) -> usize {
+        for _ in 0..200 {
+            net.process_host_events();
+            if let Some(len) = net.write_next_frame(buf) {
+                return len.get();
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        panic!("The stack didn't write any frame");
+    }
+
+    #[test]
+    fn test_host_name_server() {
+        assert_eq!(host_name_server(""), None);
+        assert_eq!(
+            host_name_server("# comment\nsearch example.com\nnameserver ::1\nnameserver 1.1.1.1\n"),
+            Some(Ipv4Addr::new(1, 1, 1, 1))
+        );
+    }
+
+    #[test]
+    fn test_ipv4_net() {
+        let net = Ipv4Net::parse_str("169.254.1.2/16").unwrap();
+        assert_eq!(net, LINK_LOCAL_NET);
+        assert_eq!(net.to_string(), "169.254.0.0/16");
+        assert!(net.contains(Ipv4Addr::new(169, 254, 169, 254)));
+        assert!(!net.contains(Ipv4Addr::new(169, 255, 0, 0)));
+
+        let any = Ipv4Net::parse_str("0.0.0.0/0").unwrap();
+        assert!(any.contains(Ipv4Addr::BROADCAST));
+        let host = Ipv4Net::parse_str("1.2.3.4/32").unwrap();
+        assert!(host.contains(Ipv4Addr::new(1, 2, 3, 4)));
+        assert!(!host.contains(Ipv4Addr::new(1, 2, 3, 5)));
+
+        for invalid in &["1.2.3.4", "1.2.3.4/33", "1.2.3/8", "1.2.3.4/a", ""] {
+            assert_eq!(Ipv4Net::parse_str(invalid), None);
+        }
+
+        let config = UserNetworkConfig {
+            host_loopback: true,
+            allowed_networks: Some(vec![Ipv4Net::new(Ipv4Addr::new(10, 1, 0, 0), 16).unwrap()]),
+            denied_networks: vec![],
+        };
+        assert_eq!(
+            UserNetworkConfig::from(&UserNetworkConfigState::from(&config)),
+            config
+        );
+    }
+
+    #[test]
+    fn test_host_addr() {
+        let host_dns = Some(Ipv4Addr::new(192, 168, 0, 1));
+        let mut config = UserNetworkConfig::default();
+        let host_addr = |config: &UserNetworkConfig, addr| {
+            UserNetwork::host_addr(config, host_dns, addr, DNS_PORT)
+        };
+
+        // The host loopback interface is only reachable when enabled.
+        assert_eq!(host_addr(&config, GATEWAY_ADDR), None);
+        assert_eq!(host_addr(&config, DNS_ADDR), host_dns);
+        assert_eq!(
+            UserNetwork::host_addr(&config, host_dns, DNS_ADDR, 80),
+            None
+        );
+        assert_eq!(host_addr(&config, Ipv4Addr::new(10, 0, 2, 100)), None);
+        assert_eq!(host_addr(&config, Ipv4Addr::BROADCAST), None);
+        assert_eq!(host_addr(&config, Ipv4Addr::LOCALHOST), None);
+        assert_eq!(host_addr(&config, Ipv4Addr::new(169, 254, 169, 254)), None);
+        assert_eq!(
+            host_addr(&config, Ipv4Addr::new(1, 2, 3, 4)),
+            Some(Ipv4Addr::new(1, 2, 3, 4))
+        );
+
+        config.host_loopback = true;
+        assert_eq!(host_addr(&config, GATEWAY_ADDR), Some(Ipv4Addr::LOCALHOST));
+
+        config.allowed_networks = Some(vec![Ipv4Net::parse_str("1.2.0.0/16").unwrap()]);
+        config.denied_networks = vec![Ipv4Net::parse_str("1.2.3.0/24").unwrap()];
+        assert_eq!(
+            host_addr(&config, Ipv4Addr::new(1, 2, 4, 4)),
+            Some(Ipv4Addr::new(1, 2, 4, 4))
+        );
+        assert_eq!(host_addr(&config, Ipv4Addr::new(1, 2, 3, 4)), None);
+        assert_eq!(host_addr(&config, Ipv4Addr::new(1, 3, 0, 1)), None);
+        // The lists don't apply to the name server.
+        assert_eq!(host_addr(&config, DNS_ADDR), host_dns);
+
+        assert_eq!(
+            UserNetwork::guest_visible_addr(host_dns, Ipv4Addr::LOCALHOST),
+            GATEWAY_ADDR
+        );
+        assert_eq!(
+            UserNetwork::guest_visible_addr(host_dns, Ipv4Addr::new(192, 168, 0, 1)),
+            DNS_ADDR
+        );
+        assert_eq!(
+            UserNetwork::guest_visible_addr(host_dns, Ipv4Addr::new(1, 2, 3, 4)),
+            Ipv4Addr::new(1, 2, 3, 4)
+        );
+    }
+
+    #[test]
+    fn test_arp() {
+        let mut net = UserNetwork::new().unwrap();
+        let mut buf = [0u8; 2000];
+
+        // Nothing is sent before the guest shows up.
+        assert!(net.write_next_frame(&mut buf).is_none());
+
+        let mut request = [0u8; 100];
+        let len = {
+            let mut eth = EthernetFrame::write_incomplete(
+                request.as_mut(),
+                MacAddr::parse_str("ff:ff:ff:ff:ff:ff").unwrap(),
+                guest_mac(),
+                ETHERTYPE_ARP,
+            )
+            .unwrap();
+            EthIPv4ArpFrame::write_request(
+                eth.inner_mut()
+                    .payload_mut()
+                    .split_at_mut(ETH_IPV4_FRAME_LEN)
+                    .0,
+                guest_mac(),
+                GUEST_ADDR,
+                MacAddr::parse_str("00:00:00:00:00:00").unwrap(),
+                GATEWAY_ADDR,
+            )
+            .unwrap();
+            eth.with_payload_len_unchecked(ETH_IPV4_FRAME_LEN).len()
+        };
+        net.handle_guest_frame(&request[..len]);
+
+        let len = net.write_next_frame(&mut buf).unwrap().get();
+        let eth = EthernetFrame::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(eth.dst_mac(), guest_mac());
+        let arp = EthIPv4ArpFrame::from_bytes(eth.payload()).unwrap();
+        assert_eq!(arp.sha(), MacAddr::from_bytes_unchecked(&GATEWAY_MAC_ADDR));
+        assert_eq!(arp.spa(), GATEWAY_ADDR);
+        assert_eq!(arp.tpa(), GUEST_ADDR);
+        assert!(net.write_next_frame(&mut buf).is_none());
+
+        // Other addresses of the network are not answered.
+        {
+            let mut eth = EthernetFrame::from_bytes_unchecked(&mut request[..len]);
+            let mut arp = EthIPv4ArpFrame::from_bytes_unchecked(eth.payload_mut());
+            arp.set_tpa(Ipv4Addr::new(10, 0, 2, 100));
+        }
+        net.handle_guest_frame(&request[..len]);
+        assert!(net.write_next_frame(&mut buf).is_none());
+    }
+
+    #[test]
+    fn test_dhcp() {
+        let mut net = UserNetwork::new().unwrap();
+        let mut frame = [0u8; 1000];
+        let mut buf = [0u8; 2000];
+
+        let mut request = [0u8; 300];
+        {
+            request[0] = dhcp::OP_BOOTREQUEST;
+            request[1] = 1;
+            request[2] = 6;
+            request[4..8].copy_from_slice(&[1, 2, 3, 4]);
+            request[28..34].copy_from_slice(guest_mac().get_bytes());
+            request[236..240].copy_from_slice(&[99, 130, 83, 99]);
+            // A DISCOVER.
+            request[240..244].copy_from_slice(&[53, 1, 1, 255]);
+        }
+        let dhcp_server = SocketAddrV4::new(Ipv4Addr::BROADCAST, dhcp::SERVER_PORT);
+
+        let check_reply = |frame: &[u8], message_type: MessageType| {
+            let eth = EthernetFrame::from_bytes(frame).unwrap();
+            let packet = IPv4Packet::from_bytes(eth.payload(), true).unwrap();
+            assert_eq!(packet.destination_address(), Ipv4Addr::BROADCAST);
+            let datagram = UdpDatagram::from_bytes(packet.payload(), None).unwrap();
+            assert_eq!(datagram.source_port(), dhcp::SERVER_PORT);
+            assert_eq!(datagram.destination_port(), dhcp::CLIENT_PORT);
+            let reply = DhcpMessage::from_bytes(datagram.payload()).unwrap();
+            assert_eq!(reply.xid(), 0x0102_0304);
+            assert_eq!(reply.message_type(), Some(message_type));
+            assert_eq!(reply.server_id(), Some(GATEWAY_ADDR));
+            reply.yiaddr()
+        };
+
+        let len = write_udp_frame(&mut frame, dhcp::CLIENT_PORT, dhcp_server, &request);
+        net.handle_guest_frame(&frame[..len]);
+        let len = net.write_next_frame(&mut buf).unwrap().get();
+        assert_eq!(check_reply(&buf[..len], MessageType::Offer), GUEST_ADDR);
+
+        // A REQUEST for the offered address.
+        request[240..250].copy_from_slice(&[53, 1, 3, 50, 4, 10, 0, 2, 15, 255]);
+        let len = write_udp_frame(&mut frame, dhcp::CLIENT_PORT, dhcp_server, &request);
+        net.handle_guest_frame(&frame[..len]);
+        let len = net.write_next_frame(&mut buf).unwrap().get();
+        assert_eq!(check_reply(&buf[..len], MessageType::Ack), GUEST_ADDR);
+
+        // A REQUEST for another address.
+        request[240..250].copy_from_slice(&[53, 1, 3, 50, 4, 10, 0, 2, 16, 255]);
+        let len = write_udp_frame(&mut frame, dhcp::CLIENT_PORT, dhcp_server, &request);
+        net.handle_guest_frame(&frame[..len]);
+        let len = net.write_next_frame(&mut buf).unwrap().get();
+        assert_eq!(
+            check_reply(&buf[..len], MessageType::Nak),
+            Ipv4Addr::UNSPECIFIED
+        );
+    }
+
+    #[test]
+    fn test_udp_flow() {
+        let mut net = UserNetwork::new().unwrap();
+        // The name server of the host may live on the loopback interface.
+        net.host_dns = None;
+        net.config.host_loopback = true;
+        let mut frame = [0u8; 1000];
+        let mut buf = [0u8; 2000];
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        // The router address maps to the host loopback interface.
+        let dst = SocketAddrV4::new(GATEWAY_ADDR, server_port);
+        let len = write_udp_frame(&mut frame, GUEST_PORT, dst, b"ping");
+        net.handle_guest_frame(&frame[..len]);
+        assert_eq!(net.udp_flows.len(), 1);
+
+        let mut payload = [0u8; 10];
+        let (len, src) = server.recv_from(&mut payload).unwrap();
+        assert_eq!(&payload[..len], b"ping");
+        server.send_to(b"pong", src).unwrap();
+
+        let len = wait_frame(&mut net, &mut buf);
+        let eth = EthernetFrame::from_bytes(&buf[..len]).unwrap();
+        let packet = IPv4Packet::from_bytes(eth.payload(), true).unwrap();
+        assert_eq!(packet.source_address(), GATEWAY_ADDR);
+        assert_eq!(packet.destination_address(), GUEST_ADDR);
+        let datagram =
+            UdpDatagram::from_bytes(packet.payload(), Some((GATEWAY_ADDR, GUEST_ADDR))).unwrap();
+        assert_eq!(datagram.source_port(), server_port);
+        assert_eq!(datagram.destination_port(), GUEST_PORT);
+        assert_eq!(datagram.payload(), b"pong");
+
+        // The replies of destinations which are off limits are dropped.
+        net.config.host_loopback = false;
+        server.send_to(b"pong", src).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        net.process_host_events();
+        assert!(net.write_next_frame(&mut buf).is_none());
+        net.config.host_loopback = true;
+
+        // Datagrams to the other addresses of the network are dropped.
+        let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 100), server_port);
+        let len = write_udp_frame(&mut frame, GUEST_PORT + 1, dst, b"ping");
+        net.handle_guest_frame(&frame[..len]);
+        assert_eq!(net.udp_flows.len(), 1);
+    }
+
+    #[test]
+    fn test_tcp_flow() {
+        let mut net = UserNetwork::new().unwrap();
+        net.config.host_loopback = true;
+        let mut frame = [0u8; 2000];
+        let mut buf = [0u8; 2000];
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst = SocketAddrV4::new(GATEWAY_ADDR, listener.local_addr().unwrap().port());
+
+        let guest_isn = 1000;
+        let len = write_tcp_frame(&mut frame, dst, guest_isn, 0, TcpFlags::SYN, &[]);
+        net.handle_guest_frame(&frame[..len]);
+        let (mut server, _) = listener.accept().unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        // The SYNACK follows the host connection.
+        let len = wait_frame(&mut net, &mut buf);
+        let synack = tcp_segment(&buf[..len]);
+        assert_eq!(synack.flags_after_ns(), TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(synack.source_port(), dst.port());
+        assert_eq!(synack.destination_port(), GUEST_PORT);
+        assert_eq!(synack.ack_number(), guest_isn + 1);
+        let host_isn = synack.sequence_number();
+
+        // Guest to host.
+        let len = write_tcp_frame(
+            &mut frame,
+            dst,
+            guest_isn + 1,
+            host_isn + 1,
+            TcpFlags::ACK,
+            b"hello",
+        );
+        net.handle_guest_frame(&frame[..len]);
+        let mut data = [0u8; 10];
+        let len = server.read(&mut data).unwrap();
+        assert_eq!(&data[..len], b"hello");
+        // Consume the ACK of the data.
+        while net.write_next_frame(&mut buf).is_some() {}
+
+        // Host to guest.
+        server.write_all(b"world").unwrap();
+        let len = wait_frame(&mut net, &mut buf);
+        let segment = tcp_segment(&buf[..len]);
+        assert_eq!(segment.sequence_number(), host_isn + 1);
+        assert_eq!(segment.ack_number(), guest_isn + 6);
+        assert_eq!(segment.payload(), b"world");
+
+        // The host closes the connection, once the data is acknowledged.
+        drop(server);
+        let len = write_tcp_frame(
+            &mut frame,
+            dst,
+            guest_isn + 6,
+            host_isn + 6,
+            TcpFlags::ACK,
+            &[],
+        );
+        net.handle_guest_frame(&frame[..len]);
+        let len = wait_frame(&mut net, &mut buf);
+        let fin = tcp_segment(&buf[..len]);
+        assert!(fin.flags_after_ns().intersects(TcpFlags::FIN));
+        assert_eq!(fin.sequence_number(), host_isn + 6);
+
+        // The guest closes its end as well.
+        let len = write_tcp_frame(
+            &mut frame,
+            dst,
+            guest_isn + 6,
+            host_isn + 7,
+            TcpFlags::FIN | TcpFlags::ACK,
+            &[],
+        );
+        net.handle_guest_frame(&frame[..len]);
+        let len = net.write_next_frame(&mut buf).unwrap().get();
+        assert!(tcp_segment(&buf[..len])
+            .flags_after_ns()
+            .intersects(TcpFlags::ACK));
+        assert!(net.tcp_flows.is_empty());
+    }
+
+    #[test]
+    fn test_tcp_refused() {
+        let mut net = UserNetwork::new().unwrap();
+        net.config.host_loopback = true;
+        let mut frame = [0u8; 2000];
+        let mut buf = [0u8; 2000];
+
+        // Nothing listens on the port of a closed listener.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let dst = SocketAddrV4::new(GATEWAY_ADDR, port);
+        let len = write_tcp_frame(&mut frame, dst, 1000, 0, TcpFlags::SYN, &[]);
+        net.handle_guest_frame(&frame[..len]);
+
+        let len = wait_frame(&mut net, &mut buf);
+        let rst = tcp_segment(&buf[..len]);
+        assert_eq!(rst.flags_after_ns(), TcpFlags::RST | TcpFlags::ACK);
+        assert_eq!(rst.ack_number(), 1001);
+        assert!(net.tcp_flows.is_empty());
+
+        // Segments of unknown connections are reset as well.
+        let len = write_tcp_frame(&mut frame, dst, 1001, 5000, TcpFlags::ACK, &[]);
+        net.handle_guest_frame(&frame[..len]);
+        let len = net.write_next_frame(&mut buf).unwrap().get();
+        let rst = tcp_segment(&buf[..len]);
+        assert_eq!(rst.flags_after_ns(), TcpFlags::RST);
+        assert_eq!(rst.sequence_number(), 5000);
+    }
+}
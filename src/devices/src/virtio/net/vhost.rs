@@ -0,0 +1,400 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Offloading of the RX/TX queue pairs of a net device to the vhost-net kernel module.
+//!
+//! Each queue pair gets its own `/dev/vhost-net` instance, which moves frames between the
+//! virtqueues and the tap queue of the pair from a kernel thread. The guest notifications reach
+//! the kernel directly through the ioeventfds of the queues, while the kernel signals used
+//! buffers through eventfds which the device relays to the guest as interrupts.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::raw::{c_int, c_uint};
+use std::os::unix::io::AsRawFd;
+
+use utils::eventfd::EventFd;
+use utils::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref};
+use utils::{ioctl_expr, ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr};
+use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use crate::virtio::net::tap::Tap;
+use crate::virtio::Queue;
+
+const VHOST_NET_PATH: &str = "/dev/vhost-net";
+/// Index of the RX queue of a pair, as seen by vhost-net.
+pub(crate) const VHOST_RX_INDEX: usize = 0;
+/// Index of the TX queue of a pair, as seen by vhost-net.
+pub(crate) const VHOST_TX_INDEX: usize = 1;
+/// Upper bound of the number of guest memory regions handed to the kernel.
+const MAX_MEMORY_REGIONS: usize = 8;
+
+const VHOST_VIRTIO: c_uint = 0xAF;
+ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_io_nr!(VHOST_SET_OWNER, VHOST_VIRTIO, 0x01);
+// The size of `struct vhost_memory`, without its flexible array of regions.
+ioctl_iow_nr!(VHOST_SET_MEM_TABLE, VHOST_VIRTIO, 0x03, u64);
+ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST_VIRTIO, 0x10, VhostVringState);
+ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST_VIRTIO, 0x11, VhostVringAddr);
+ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST_VIRTIO, 0x12, VhostVringState);
+ioctl_iowr_nr!(VHOST_GET_VRING_BASE, VHOST_VIRTIO, 0x12, VhostVringState);
+ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST_VIRTIO, 0x20, VhostVringFile);
+ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST_VIRTIO, 0x21, VhostVringFile);
+ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST_VIRTIO, 0x30, VhostVringFile);
+
+#[derive(Debug, Default)]
+#[repr(C)]
+struct VhostMemoryRegion {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    flags_padding: u64,
+}
+
+#[derive(Debug, Default)]
+#[repr(C)]
+struct VhostMemory {
+    nregions: u32,
+    padding: u32,
+    regions: [VhostMemoryRegion; MAX_MEMORY_REGIONS],
+}
+
+#[derive(Debug, Default)]
+#[repr(C)]
+struct VhostVringState {
+    index: c_uint,
+    num: c_uint,
+}
+
+#[derive(Debug, Default)]
+#[repr(C)]
+struct VhostVringAddr {
+    index: c_uint,
+    flags: c_uint,
+    desc_user_addr: u64,
+    used_user_addr: u64,
+    avail_user_addr: u64,
+    log_guest_addr: u64,
+}
+
+#[derive(Debug, Default)]
+#[repr(C)]
+struct VhostVringFile {
+    index: c_uint,
+    fd: c_int,
+}
+
+/// Errors associated with the vhost-net backend.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create an eventfd.
+    EventFd(io::Error),
+    /// A vring isn't backed by the guest memory.
+    InvalidVringAddress(GuestAddress),
+    /// A vhost ioctl failed.
+    Ioctl(&'static str, io::Error),
+    /// Failed to open `/dev/vhost-net`.
+    Open(io::Error),
+    /// The guest memory has more regions than handed to the kernel.
+    TooManyMemoryRegions(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A vhost-net instance serving one RX/TX queue pair.
+#[derive(Debug)]
+pub struct VhostNet {
+    file: File,
+    // Features supported by the kernel.
+    features: u64,
+    /// Signaled by the kernel when it adds used buffers to the RX and TX queues.
+    pub(crate) call_evts: [EventFd; 2],
+}
+
+impl VhostNet {
+    /// Opens a vhost-net instance owned by this process.
+    pub fn new() -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(VHOST_NET_PATH)
+            .map_err(Error::Open)?;
+        // Safe because the fd is valid and we check the return value.
+        let ret = unsafe { ioctl(&file, VHOST_SET_OWNER()) };
+        if ret < 0 {
+            return Err(Error::Ioctl("VHOST_SET_OWNER", io::Error::last_os_error()));
+        }
+
+        let mut features = 0u64;
+        // Safe because the fd is valid, the kernel writes a u64, and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(&file, VHOST_GET_FEATURES(), &mut features) };
+        if ret < 0 {
+            return Err(Error::Ioctl(
+                "VHOST_GET_FEATURES",
+                io::Error::last_os_error(),
+            ));
+        }
+
+        Ok(VhostNet {
+            file,
+            features,
+            call_evts: [
+                EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
+                EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
+            ],
+        })
+    }
+
+    fn vhost_ioctl<T>(&self, name: &'static str, request: u64, arg: &T) -> Result<()> {
+        // Safe because the fd is valid, `arg` is the structure expected by the request, and we
+        // check the return value.
+        let ret = unsafe { ioctl_with_ref(&self.file, request, arg) };
+        if ret < 0 {
+            return Err(Error::Ioctl(name, io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Hands the RX and TX `queues`, notified through `kick_evts`, over to the kernel, starting
+    /// from their current state. Only the transport `acked_features` known to vhost-net are
+    /// passed along, the offloads being handled by the tap device.
+    pub fn setup(
+        &self,
+        mem: &GuestMemoryMmap,
+        acked_features: u64,
+        queues: [&Queue; 2],
+        kick_evts: [&EventFd; 2],
+        tap: &Tap,
+    ) -> Result<()> {
+        self.vhost_ioctl(
+            "VHOST_SET_FEATURES",
+            VHOST_SET_FEATURES(),
+            &(acked_features & self.features),
+        )?;
+        self.vhost_ioctl(
+            "VHOST_SET_MEM_TABLE",
+            VHOST_SET_MEM_TABLE(),
+            &memory_table(mem)?,
+        )?;
+
+        for (index, (queue, kick_evt)) in queues.iter().zip(kick_evts.iter()).enumerate() {
+            let index = index as c_uint;
+            self.vhost_ioctl(
+                "VHOST_SET_VRING_NUM",
+                VHOST_SET_VRING_NUM(),
+                &VhostVringState {
+                    index,
+                    num: c_uint::from(queue.actual_size()),
+                },
+            )?;
+            self.vhost_ioctl(
+                "VHOST_SET_VRING_ADDR",
+                VHOST_SET_VRING_ADDR(),
+                &VhostVringAddr {
+                    index,
+                    desc_user_addr: host_address(mem, queue.desc_table)?,
+                    used_user_addr: host_address(mem, queue.used_ring)?,
+                    avail_user_addr: host_address(mem, queue.avail_ring)?,
+                    ..Default::default()
+                },
+            )?;
+            self.vhost_ioctl(
+                "VHOST_SET_VRING_BASE",
+                VHOST_SET_VRING_BASE(),
+                &VhostVringState {
+                    index,
+                    num: c_uint::from(queue.next_avail.0),
+                },
+            )?;
+            self.vhost_ioctl(
+                "VHOST_SET_VRING_KICK",
+                VHOST_SET_VRING_KICK(),
+                &VhostVringFile {
+                    index,
+                    fd: kick_evt.as_raw_fd(),
+                },
+            )?;
+            self.vhost_ioctl(
+                "VHOST_SET_VRING_CALL",
+                VHOST_SET_VRING_CALL(),
+                &VhostVringFile {
+                    index,
+                    fd: self.call_evts[index as usize].as_raw_fd(),
+                },
+            )?;
+        }
+
+        self.set_backend(tap.as_raw_fd())
+    }
+
+    /// Makes the kernel process the queues again, after `stop`.
+    pub fn start(&self, tap: &Tap) -> Result<()> {
+        self.set_backend(tap.as_raw_fd())
+    }
+
+    /// Makes the kernel stop processing the queues, and wait for the frames in flight.
+    pub fn stop(&self) -> Result<()> {
+        self.set_backend(-1)
+    }
+
+    fn set_backend(&self, fd: c_int) -> Result<()> {
+        for index in &[VHOST_RX_INDEX, VHOST_TX_INDEX] {
+            self.vhost_ioctl(
+                "VHOST_NET_SET_BACKEND",
+                VHOST_NET_SET_BACKEND(),
+                &VhostVringFile {
+                    index: *index as c_uint,
+                    fd,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Returns the index of the next available descriptor the kernel would process in the
+    /// `index` queue.
+    pub fn next_avail(&self, index: usize) -> Result<u16> {
+        let mut state = VhostVringState {
+            index: index as c_uint,
+            num: 0,
+        };
+        // Safe because the fd is valid, the kernel writes a `vhost_vring_state`, and we check
+        // the return value.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, VHOST_GET_VRING_BASE(), &mut state) };
+        if ret < 0 {
+            return Err(Error::Ioctl(
+                "VHOST_GET_VRING_BASE",
+                io::Error::last_os_error(),
+            ));
+        }
+        Ok(state.num as u16)
+    }
+}
+
+fn host_address(mem: &GuestMemoryMmap, addr: GuestAddress) -> Result<u64> {
+    mem.get_host_address(addr)
+        .map(|host_addr| host_addr as u64)
+        .map_err(|_| Error::InvalidVringAddress(addr))
+}
+
+fn memory_table(mem: &GuestMemoryMmap) -> Result<VhostMemory> {
+    let mut table = VhostMemory::default();
+    for (index, region) in mem.iter().enumerate() {
+        let table_region = table
+            .regions
+            .get_mut(index)
+            .ok_or_else(|| Error::TooManyMemoryRegions(mem.num_regions()))?;
+        *table_region = VhostMemoryRegion {
+            guest_phys_addr: region.start_addr().raw_value(),
+            memory_size: region.len(),
+            userspace_addr: host_address(mem, region.start_addr())?,
+            flags_padding: 0,
+        };
+        table.nregions += 1;
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use vm_memory::test_utils::create_anon_guest_memory;
+
+    use super::*;
+    use crate::virtio::net::test_utils::default_guest_memory;
+
+    #[test]
+    fn test_ioctl_numbers() {
+        // As defined in include/uapi/linux/vhost.h.
+        assert_eq!(VHOST_GET_FEATURES(), 0x8008_af00);
+        assert_eq!(VHOST_SET_FEATURES(), 0x4008_af00);
+        assert_eq!(VHOST_SET_OWNER(), 0xaf01);
+        assert_eq!(VHOST_SET_MEM_TABLE(), 0x4008_af03);
+        assert_eq!(VHOST_SET_VRING_NUM(), 0x4008_af10);
+        assert_eq!(VHOST_SET_VRING_ADDR(), 0x4028_af11);
+        assert_eq!(VHOST_SET_VRING_BASE(), 0x4008_af12);
+        assert_eq!(VHOST_GET_VRING_BASE(), 0xc008_af12);
+        assert_eq!(VHOST_SET_VRING_KICK(), 0x4008_af20);
+        assert_eq!(VHOST_SET_VRING_CALL(), 0x4008_af21);
+        assert_eq!(VHOST_NET_SET_BACKEND(), 0x4008_af30);
+    }
+
+    #[test]
+    fn test_memory_table() {
+        let mem = create_anon_guest_memory(
+            &[(GuestAddress(0), 0x1000), (GuestAddress(0x10000), 0x2000)],
+            false,
+        )
+        .unwrap();
+        let table = memory_table(&mem).unwrap();
+        assert_eq!(table.nregions, 2);
+        assert_eq!(table.regions[1].guest_phys_addr, 0x10000);
+        assert_eq!(table.regions[1].memory_size, 0x2000);
+        assert_eq!(
+            table.regions[1].userspace_addr,
+            mem.get_host_address(GuestAddress(0x10000)).unwrap() as u64
+        );
+        assert_eq!(table.regions[2].memory_size, 0);
+
+        let ranges: Vec<_> = (0..=MAX_MEMORY_REGIONS as u64)
+            .map(|i| (GuestAddress(i * 0x2000), 0x1000))
+            .collect();
+        let mem = create_anon_guest_memory(&ranges, false).unwrap();
+        match memory_table(&mem) {
+            Err(Error::TooManyMemoryRegions(9)) => (),
+            _ => panic!("Expected Error::TooManyMemoryRegions"),
+        }
+    }
+
+    #[test]
+    fn test_vhost_net() {
+        // The vhost-net module isn't available on every host.
+        if !Path::new(VHOST_NET_PATH).exists() {
+            return;
+        }
+
+        let vhost = VhostNet::new().unwrap();
+        let mem = default_guest_memory();
+        let tap = Tap::open_named("").unwrap();
+        let mut queue = Queue::new(16);
+        queue.size = 16;
+        queue.ready = true;
+        queue.desc_table = GuestAddress(0);
+        queue.avail_ring = GuestAddress(0x1000);
+        queue.used_ring = GuestAddress(0x2000);
+        queue.next_avail = std::num::Wrapping(3);
+        let kick_evts = [
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+        ];
+
+        vhost
+            .setup(
+                &mem,
+                0,
+                [&queue, &queue],
+                [&kick_evts[0], &kick_evts[1]],
+                &tap,
+            )
+            .unwrap();
+        vhost.stop().unwrap();
+        assert_eq!(vhost.next_avail(VHOST_TX_INDEX).unwrap(), 3);
+        vhost.start(&tap).unwrap();
+
+        // The queues must be backed by the guest memory.
+        let vhost = VhostNet::new().unwrap();
+        queue.used_ring = GuestAddress(0x10000);
+        match vhost.setup(
+            &mem,
+            0,
+            [&queue, &queue],
+            [&kick_evts[0], &kick_evts[1]],
+            &tap,
+        ) {
+            Err(Error::InvalidVringAddress(_)) => (),
+            _ => panic!("Expected Error::InvalidVringAddress"),
+        }
+    }
+}
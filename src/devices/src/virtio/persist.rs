@@ -220,7 +220,9 @@ mod tests {
     use crate::virtio::block::test_utils::default_block_with_path;
     use crate::virtio::mmio::tests::DummyDevice;
     use crate::virtio::test_utils::default_mem;
-    use crate::virtio::{net, Block, Net, Vsock, VsockUnixBackend};
+    use crate::virtio::{
+        net, Block, Net, Vsock, VsockForwarding, VsockPortFilter, VsockUnixBackend,
+    };
 
     const DEFAULT_QUEUE_MAX_SIZE: u16 = 256;
     impl Default for QueueState {
@@ -422,7 +424,15 @@ mod tests {
         // Remove the file so the path can be used by the socket.
         temp_uds_path.remove().unwrap();
         let uds_path = String::from(temp_uds_path.as_path().to_str().unwrap());
-        let backend = VsockUnixBackend::new(guest_cid, uds_path).unwrap();
+        let backend = VsockUnixBackend::new(
+            guest_cid,
+            uds_path,
+            VsockForwarding::Unix,
+            VsockPortFilter::default(),
+            None,
+            false,
+        )
+        .unwrap();
         let vsock = Vsock::new(guest_cid, backend).unwrap();
         let vsock = Arc::new(Mutex::new(vsock));
         let mmio_transport = MmioTransport::new(mem.clone(), vsock.clone());
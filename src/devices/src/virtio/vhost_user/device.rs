@@ -0,0 +1,715 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::cmp;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+use logger::{error, IncMetric, METRICS};
+use utils::byte_order::write_le_u16;
+use utils::eventfd::EventFd;
+use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
+use virtio_gen::virtio_blk::{VIRTIO_BLK_F_CONFIG_WCE, VIRTIO_BLK_F_MQ, VIRTIO_F_VERSION_1};
+use virtio_gen::virtio_net::{
+    VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_ECN, VIRTIO_NET_F_GUEST_TSO4,
+    VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_ECN, VIRTIO_NET_F_HOST_TSO4,
+    VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MRG_RXBUF,
+};
+use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use super::master::{
+    Master, MemoryRegion, VringAddresses, PROTOCOL_F_CONFIG, PROTOCOL_F_MQ, PROTOCOL_F_REPLY_ACK,
+    VHOST_USER_F_PROTOCOL_FEATURES,
+};
+use super::{Result, VhostUserError, VHOST_USER_MAX_BLOCK_QUEUES};
+use crate::virtio::{
+    ActivateError, ActivateResult, DeviceState, IrqTrigger, IrqType, Queue, VirtioDevice,
+    TYPE_BLOCK, TYPE_NET,
+};
+
+// Not exposed by the virtio bindings.
+const VIRTIO_RING_F_INDIRECT_DESC: u32 = 28;
+// The device specific feature bits.
+const DEVICE_FEATURES_MASK: u64 = (1 << 24) - 1;
+
+// Size of the block config space, up to the write zeroes fields.
+const BLOCK_CONFIG_LEN: u32 = 60;
+// Offset of `num_queues` in the block config space.
+const BLOCK_CONFIG_NUM_QUEUES_OFFSET: usize = 34;
+// The vhost-user net device has a single queue pair.
+const NET_NUM_QUEUES: usize = 2;
+
+/// Virtio device whose queues are processed by a vhost-user backend. The device only
+/// negotiates the features and the config space with the backend, and relays the backend
+/// used buffer notifications to the guest.
+pub struct VhostUser {
+    // Virtio fields.
+    pub(crate) device_type: u32,
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    pub(crate) config_space: Vec<u8>,
+    pub(crate) activate_evt: EventFd,
+
+    // Transport related fields.
+    pub(crate) queues: Vec<Queue>,
+    pub(crate) queue_evts: Vec<EventFd>,
+    pub(crate) device_state: DeviceState,
+    pub(crate) irq_trigger: IrqTrigger,
+
+    // Implementation specific fields.
+    pub(crate) id: String,
+    socket_path: String,
+    master: Master,
+    backend_features: u64,
+    protocol_features: u64,
+    // Signaled by the backend when it used buffers of the queue with the same index.
+    pub(crate) call_evts: Vec<EventFd>,
+}
+
+impl VhostUser {
+    /// Create a new vhost-user block device, served by the backend listening on `socket_path`.
+    ///
+    /// The config space is read from the backend, which has to support the config messages.
+    pub fn new_block(
+        id: String,
+        socket_path: String,
+        num_queues: usize,
+        queue_size: u16,
+    ) -> Result<VhostUser> {
+        if num_queues == 0 || num_queues > VHOST_USER_MAX_BLOCK_QUEUES {
+            return Err(VhostUserError::InvalidNumQueues(num_queues));
+        }
+        let mut device = Self::new(TYPE_BLOCK, id, socket_path, num_queues, queue_size)?;
+        if device.protocol_features & PROTOCOL_F_CONFIG == 0 {
+            return Err(VhostUserError::MissingFeature(
+                "VHOST_USER_PROTOCOL_F_CONFIG",
+            ));
+        }
+
+        // The guest can't change the cache mode, since the config space is read-only.
+        let mut features =
+            device.backend_features & DEVICE_FEATURES_MASK & !(1u64 << VIRTIO_BLK_F_CONFIG_WCE);
+        let mut config_space = device.master.get_config(0, BLOCK_CONFIG_LEN)?;
+        if num_queues > 1 {
+            if features & (1u64 << VIRTIO_BLK_F_MQ) == 0 {
+                return Err(VhostUserError::MissingFeature("VIRTIO_BLK_F_MQ"));
+            }
+            if device.protocol_features & PROTOCOL_F_MQ == 0 {
+                return Err(VhostUserError::MissingFeature("VHOST_USER_PROTOCOL_F_MQ"));
+            }
+            if device.master.get_queue_num()? < num_queues as u64 {
+                return Err(VhostUserError::InvalidNumQueues(num_queues));
+            }
+            write_le_u16(
+                &mut config_space
+                    [BLOCK_CONFIG_NUM_QUEUES_OFFSET..BLOCK_CONFIG_NUM_QUEUES_OFFSET + 2],
+                num_queues as u16,
+            );
+        } else {
+            features &= !(1u64 << VIRTIO_BLK_F_MQ);
+        }
+
+        device.avail_features |= features;
+        device.config_space = config_space;
+        Ok(device)
+    }
+
+    /// Create a new vhost-user network device with a single queue pair, served by the backend
+    /// listening on `socket_path`.
+    pub fn new_net(
+        id: String,
+        socket_path: String,
+        guest_mac: Option<&MacAddr>,
+        queue_size: u16,
+    ) -> Result<VhostUser> {
+        let mut device = Self::new(TYPE_NET, id, socket_path, NET_NUM_QUEUES, queue_size)?;
+
+        let offloads = [
+            VIRTIO_NET_F_CSUM,
+            VIRTIO_NET_F_GUEST_CSUM,
+            VIRTIO_NET_F_GUEST_TSO4,
+            VIRTIO_NET_F_GUEST_TSO6,
+            VIRTIO_NET_F_GUEST_ECN,
+            VIRTIO_NET_F_GUEST_UFO,
+            VIRTIO_NET_F_HOST_TSO4,
+            VIRTIO_NET_F_HOST_TSO6,
+            VIRTIO_NET_F_HOST_ECN,
+            VIRTIO_NET_F_HOST_UFO,
+            VIRTIO_NET_F_MRG_RXBUF,
+        ];
+        let features_mask = offloads
+            .iter()
+            .fold(0u64, |mask, feature| mask | (1u64 << feature));
+        device.avail_features |= device.backend_features & features_mask;
+
+        // The MAC address is handled by the VMM, the backend doesn't need to know about it.
+        if let Some(mac) = guest_mac {
+            device.avail_features |= 1u64 << VIRTIO_NET_F_MAC;
+            device.config_space = mac.get_bytes().to_vec();
+        }
+        Ok(device)
+    }
+
+    // Connects to the backend and negotiates the protocol features.
+    fn new(
+        device_type: u32,
+        id: String,
+        socket_path: String,
+        num_queues: usize,
+        queue_size: u16,
+    ) -> Result<VhostUser> {
+        if queue_size == 0 || !queue_size.is_power_of_two() {
+            return Err(VhostUserError::InvalidQueueSize(queue_size));
+        }
+
+        let mut master = Master::connect(&socket_path)?;
+        master.set_owner()?;
+        let backend_features = master.get_features()?;
+        if backend_features & (1u64 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(VhostUserError::MissingFeature("VIRTIO_F_VERSION_1"));
+        }
+
+        let mut protocol_features = 0;
+        if backend_features & VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+            protocol_features = master.get_protocol_features()?
+                & (PROTOCOL_F_MQ | PROTOCOL_F_REPLY_ACK | PROTOCOL_F_CONFIG);
+            master.set_protocol_features(protocol_features)?;
+            master.set_reply_ack(protocol_features & PROTOCOL_F_REPLY_ACK != 0);
+        }
+
+        let transport_features = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_RING_F_INDIRECT_DESC);
+
+        let mut queue_evts = Vec::with_capacity(num_queues);
+        let mut call_evts = Vec::with_capacity(num_queues);
+        for _ in 0..num_queues {
+            queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(VhostUserError::EventFd)?);
+            call_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(VhostUserError::EventFd)?);
+        }
+
+        Ok(VhostUser {
+            device_type,
+            avail_features: backend_features & transport_features,
+            acked_features: 0u64,
+            config_space: Vec::new(),
+            activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(VhostUserError::EventFd)?,
+            queues: (0..num_queues).map(|_| Queue::new(queue_size)).collect(),
+            queue_evts,
+            device_state: DeviceState::Inactive,
+            irq_trigger: IrqTrigger::new().map_err(VhostUserError::EventFd)?,
+            id,
+            socket_path,
+            master,
+            backend_features,
+            protocol_features,
+            call_evts,
+        })
+    }
+
+    /// Provides the ID of this device.
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    /// Provides the path of the backend socket.
+    pub fn socket_path(&self) -> &String {
+        &self.socket_path
+    }
+
+    /// Provides the number of queues of this device.
+    pub fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
+    /// Provides the size of the queues of this device.
+    pub fn queue_size(&self) -> u16 {
+        self.queues[0].get_max_size()
+    }
+
+    /// Provides the MAC address exposed to the guest, for network devices.
+    pub fn guest_mac(&self) -> Option<MacAddr> {
+        if self.device_type == TYPE_NET && self.has_feature(u64::from(VIRTIO_NET_F_MAC)) {
+            Some(MacAddr::from_bytes_unchecked(
+                &self.config_space[..MAC_ADDR_LEN],
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Hands the guest memory and the ready queues over to the backend.
+    pub(crate) fn setup_backend(&mut self) -> Result<()> {
+        // This is safe since the backend is only set up once the device is activated.
+        let mem = self.device_state.mem().unwrap();
+
+        let mut features = self.acked_features;
+        if self.backend_features & VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+            features |= VHOST_USER_F_PROTOCOL_FEATURES;
+        }
+        self.master.set_features(features)?;
+        self.master.set_mem_table(&memory_regions(mem)?)?;
+
+        for (index, queue) in self.queues.iter().enumerate() {
+            if !queue.ready {
+                continue;
+            }
+            self.master.set_vring_num(index, queue.actual_size())?;
+            self.master.set_vring_addr(
+                index,
+                &VringAddresses {
+                    desc: host_address(mem, queue.desc_table)?,
+                    used: host_address(mem, queue.used_ring)?,
+                    avail: host_address(mem, queue.avail_ring)?,
+                },
+            )?;
+            self.master.set_vring_base(index, queue.next_avail.0)?;
+            self.master
+                .set_vring_call(index, self.call_evts[index].as_raw_fd())?;
+            self.master
+                .set_vring_kick(index, self.queue_evts[index].as_raw_fd())?;
+            // The rings start disabled when the protocol features are negotiated.
+            if features & VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+                self.master.set_vring_enable(index, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn process_call_event(&mut self, queue_index: usize) {
+        METRICS.vhost_user.call_event_count.inc();
+        if let Err(err) = self.call_evts[queue_index].read() {
+            error!("Failed to get vhost-user call event: {:?}", err);
+            METRICS.vhost_user.event_fails.inc();
+            return;
+        }
+        // The backend already applied the notification suppression rules.
+        self.irq_trigger
+            .trigger_irq(IrqType::Vring)
+            .unwrap_or_else(|err| {
+                METRICS.vhost_user.event_fails.inc();
+                error!("Failed to signal used queue: {:?}", err);
+            });
+    }
+}
+
+fn host_address(mem: &GuestMemoryMmap, addr: GuestAddress) -> Result<u64> {
+    mem.get_host_address(addr)
+        .map(|host_addr| host_addr as u64)
+        .map_err(|_| VhostUserError::InvalidVringAddress(addr))
+}
+
+// Describes the guest memory regions to the backend, which maps them from their file.
+fn memory_regions(mem: &GuestMemoryMmap) -> Result<Vec<MemoryRegion>> {
+    mem.iter()
+        .map(|region| match region.file_offset() {
+            Some(file_offset) if region.flags() & libc::MAP_SHARED != 0 => Ok(MemoryRegion {
+                guest_addr: region.start_addr().raw_value(),
+                size: region.len(),
+                user_addr: region.as_ptr() as u64,
+                mmap_offset: file_offset.start(),
+                fd: file_offset.file().as_raw_fd(),
+            }),
+            _ => Err(VhostUserError::MemoryNotShareable(region.start_addr())),
+        })
+        .collect()
+}
+
+impl VirtioDevice for VhostUser {
+    fn device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_evts
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.irq_trigger.irq_evt
+    }
+
+    /// Returns the current device interrupt status.
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.irq_trigger.irq_status.clone()
+    }
+
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_len = self.config_space.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            METRICS.vhost_user.cfg_fails.inc();
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&self.config_space[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {
+        error!("vhost-user: Guest attempted to write config");
+        METRICS.vhost_user.cfg_fails.inc();
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        if self.activate_evt.write(1).is_err() {
+            error!("vhost-user: Cannot write to activate_evt");
+            METRICS.vhost_user.activate_fails.inc();
+            return Err(ActivateError::BadActivate);
+        }
+        self.device_state = DeviceState::Activated(mem);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use utils::byte_order::{read_le_u16, read_le_u32, read_le_u64};
+    use vm_memory::test_utils::create_anon_guest_memory;
+
+    use super::*;
+    use crate::virtio::test_utils::VirtQueue;
+    use crate::virtio::vhost_user::test_utils::{default_block_backend, BackendStub};
+    use crate::virtio::vhost_user::{VhostUserRequest, VHOST_USER_QUEUE_SIZE};
+
+    const ALL_PROTOCOL_FEATURES: u64 = PROTOCOL_F_MQ | PROTOCOL_F_REPLY_ACK | PROTOCOL_F_CONFIG;
+
+    fn backend_features(device_features: &[u32]) -> u64 {
+        device_features.iter().fold(
+            (1u64 << VIRTIO_F_VERSION_1)
+                | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+                | VHOST_USER_F_PROTOCOL_FEATURES,
+            |features, feature| features | (1u64 << feature),
+        )
+    }
+
+    pub(crate) fn default_vhost_user_block(backend: &BackendStub) -> VhostUser {
+        VhostUser::new_block(
+            "block".to_string(),
+            backend.socket_path().to_string(),
+            1,
+            VHOST_USER_QUEUE_SIZE,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_block_features() {
+        let backend = default_block_backend();
+        let device = default_vhost_user_block(&backend);
+
+        assert_eq!(device.device_type(), TYPE_BLOCK);
+        let mut capacity = [0u8; 8];
+        device.read_config(0, &mut capacity);
+        assert_eq!(read_le_u64(&capacity), 0x800);
+        assert_eq!(device.num_queues(), 1);
+        assert_eq!(device.queue_size(), VHOST_USER_QUEUE_SIZE);
+        assert_eq!(device.guest_mac(), None);
+        assert_eq!(device.socket_path(), backend.socket_path());
+        // The write cache and multiqueue features are not passed along, nor the vhost-user
+        // specific ones.
+        assert_eq!(
+            device.avail_features(),
+            (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+        );
+        assert_eq!(
+            backend.requests(),
+            vec![
+                VhostUserRequest::SetOwner,
+                VhostUserRequest::GetFeatures,
+                VhostUserRequest::GetProtocolFeatures,
+                VhostUserRequest::SetProtocolFeatures,
+                VhostUserRequest::GetConfig,
+            ]
+        );
+        let message = backend
+            .message(VhostUserRequest::SetProtocolFeatures)
+            .unwrap();
+        assert_eq!(read_le_u64(&message.body), ALL_PROTOCOL_FEATURES);
+
+        // Reads past the end of the config space are ignored.
+        let mut data = [0xFFu8; 4];
+        device.read_config(u64::from(BLOCK_CONFIG_LEN), &mut data);
+        assert_eq!(data, [0xFF; 4]);
+    }
+
+    #[test]
+    fn test_block_multiqueue() {
+        let backend = default_block_backend();
+        let device = VhostUser::new_block(
+            "block".to_string(),
+            backend.socket_path().to_string(),
+            4,
+            VHOST_USER_QUEUE_SIZE,
+        )
+        .unwrap();
+        assert_eq!(device.num_queues(), 4);
+        assert_eq!(device.queue_events().len(), 4);
+        assert!(device.has_feature(u64::from(VIRTIO_BLK_F_MQ)));
+        let mut num_queues = [0u8; 2];
+        device.read_config(BLOCK_CONFIG_NUM_QUEUES_OFFSET as u64, &mut num_queues);
+        assert_eq!(read_le_u16(&num_queues), 4);
+
+        // More queues than the backend supports.
+        let backend = default_block_backend();
+        assert!(matches!(
+            VhostUser::new_block(
+                "block".to_string(),
+                backend.socket_path().to_string(),
+                5,
+                VHOST_USER_QUEUE_SIZE,
+            ),
+            Err(VhostUserError::InvalidNumQueues(5))
+        ));
+
+        // A backend without multiqueue support.
+        let backend = BackendStub::new(
+            backend_features(&[]),
+            ALL_PROTOCOL_FEATURES,
+            4,
+            vec![0u8; BLOCK_CONFIG_LEN as usize],
+        );
+        assert!(matches!(
+            VhostUser::new_block(
+                "block".to_string(),
+                backend.socket_path().to_string(),
+                2,
+                VHOST_USER_QUEUE_SIZE,
+            ),
+            Err(VhostUserError::MissingFeature("VIRTIO_BLK_F_MQ"))
+        ));
+
+        assert!(matches!(
+            VhostUser::new_block(
+                "block".to_string(),
+                "/invalid/socket".to_string(),
+                VHOST_USER_MAX_BLOCK_QUEUES + 1,
+                VHOST_USER_QUEUE_SIZE,
+            ),
+            Err(VhostUserError::InvalidNumQueues(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_backends() {
+        // The block config space can't be read without the config messages.
+        let backend = BackendStub::new(
+            backend_features(&[]),
+            PROTOCOL_F_REPLY_ACK,
+            1,
+            vec![0u8; BLOCK_CONFIG_LEN as usize],
+        );
+        assert!(matches!(
+            VhostUser::new_block(
+                "block".to_string(),
+                backend.socket_path().to_string(),
+                1,
+                VHOST_USER_QUEUE_SIZE,
+            ),
+            Err(VhostUserError::MissingFeature(
+                "VHOST_USER_PROTOCOL_F_CONFIG"
+            ))
+        ));
+
+        // Legacy virtio devices are not supported.
+        let backend = BackendStub::new(VHOST_USER_F_PROTOCOL_FEATURES, 0, 1, vec![]);
+        assert!(matches!(
+            VhostUser::new_net(
+                "net".to_string(),
+                backend.socket_path().to_string(),
+                None,
+                VHOST_USER_QUEUE_SIZE,
+            ),
+            Err(VhostUserError::MissingFeature("VIRTIO_F_VERSION_1"))
+        ));
+
+        assert!(matches!(
+            VhostUser::new_net("net".to_string(), "/invalid/socket".to_string(), None, 100),
+            Err(VhostUserError::InvalidQueueSize(100))
+        ));
+        assert!(matches!(
+            VhostUser::new_net(
+                "net".to_string(),
+                "/invalid/socket".to_string(),
+                None,
+                VHOST_USER_QUEUE_SIZE,
+            ),
+            Err(VhostUserError::Connect(_))
+        ));
+    }
+
+    #[test]
+    fn test_net_features() {
+        // The backend doesn't support the protocol features.
+        let backend = BackendStub::new(
+            (1u64 << VIRTIO_F_VERSION_1)
+                | (1u64 << VIRTIO_NET_F_CSUM)
+                | (1u64 << VIRTIO_NET_F_HOST_TSO4)
+                | (1u64 << VIRTIO_NET_F_MAC),
+            0,
+            1,
+            vec![],
+        );
+        let mac = MacAddr::parse_str("11:22:33:44:55:66").unwrap();
+        let mut device = VhostUser::new_net(
+            "net".to_string(),
+            backend.socket_path().to_string(),
+            Some(&mac),
+            VHOST_USER_QUEUE_SIZE,
+        )
+        .unwrap();
+
+        assert_eq!(device.device_type(), TYPE_NET);
+        assert_eq!(device.num_queues(), 2);
+        assert_eq!(
+            device.avail_features(),
+            (1u64 << VIRTIO_F_VERSION_1)
+                | (1u64 << VIRTIO_NET_F_CSUM)
+                | (1u64 << VIRTIO_NET_F_HOST_TSO4)
+                | (1u64 << VIRTIO_NET_F_MAC)
+        );
+        assert_eq!(device.guest_mac(), Some(mac));
+        let mut config = [0u8; 6];
+        device.read_config(0, &mut config);
+        assert_eq!(&config, mac.get_bytes());
+
+        // The config space is read-only.
+        device.write_config(0, &[0u8; 6]);
+        device.read_config(0, &mut config);
+        assert_eq!(&config, mac.get_bytes());
+    }
+
+    fn activate(device: &mut VhostUser, mem: &GuestMemoryMmap) {
+        let vq = VirtQueue::new(GuestAddress(0), mem, 16);
+        let mut queue = vq.create_queue();
+        queue.next_avail = std::num::Wrapping(3);
+        device.queues[0] = queue;
+        device.set_acked_features(device.avail_features());
+        device.activate(mem.clone()).unwrap();
+        assert_eq!(device.activate_evt.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_setup_backend() {
+        let backend = default_block_backend();
+        let mut device = default_vhost_user_block(&backend);
+        let mem = vm_memory::create_shared_guest_memory(&[(GuestAddress(0), 0x10000)], None, false)
+            .unwrap();
+        activate(&mut device, &mem);
+        device.setup_backend().unwrap();
+
+        let requests = backend.requests();
+        assert_eq!(
+            &requests[5..],
+            &[
+                VhostUserRequest::SetFeatures,
+                VhostUserRequest::SetMemTable,
+                VhostUserRequest::SetVringNum,
+                VhostUserRequest::SetVringAddr,
+                VhostUserRequest::SetVringBase,
+                VhostUserRequest::SetVringCall,
+                VhostUserRequest::SetVringKick,
+                VhostUserRequest::SetVringEnable,
+            ]
+        );
+
+        let message = backend.message(VhostUserRequest::SetFeatures).unwrap();
+        assert_eq!(
+            read_le_u64(&message.body),
+            device.avail_features() | VHOST_USER_F_PROTOCOL_FEATURES
+        );
+
+        // The region is shared through its memfd.
+        let message = backend.message(VhostUserRequest::SetMemTable).unwrap();
+        assert_eq!(read_le_u32(&message.body[0..4]), 1);
+        assert_eq!(read_le_u64(&message.body[8..16]), 0);
+        assert_eq!(read_le_u64(&message.body[16..24]), 0x10000);
+        assert_eq!(
+            read_le_u64(&message.body[24..32]),
+            mem.get_host_address(GuestAddress(0)).unwrap() as u64
+        );
+        assert_eq!(message.files.len(), 1);
+        assert_eq!(message.files[0].metadata().unwrap().len(), 0x10000);
+
+        let message = backend.message(VhostUserRequest::SetVringNum).unwrap();
+        assert_eq!(read_le_u32(&message.body[4..8]), 16);
+        let message = backend.message(VhostUserRequest::SetVringBase).unwrap();
+        assert_eq!(read_le_u32(&message.body[4..8]), 3);
+        let message = backend.message(VhostUserRequest::SetVringAddr).unwrap();
+        assert_eq!(
+            read_le_u64(&message.body[8..16]),
+            mem.get_host_address(device.queues[0].desc_table).unwrap() as u64
+        );
+        let message = backend.message(VhostUserRequest::SetVringKick).unwrap();
+        assert_eq!(message.files.len(), 1);
+        let message = backend.message(VhostUserRequest::SetVringCall).unwrap();
+        assert_eq!(message.files.len(), 1);
+    }
+
+    #[test]
+    fn test_setup_backend_failures() {
+        // Anonymous private memory can't be shared with the backend.
+        let backend = default_block_backend();
+        let mut device = default_vhost_user_block(&backend);
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+        activate(&mut device, &mem);
+        assert!(matches!(
+            device.setup_backend(),
+            Err(VhostUserError::MemoryNotShareable(GuestAddress(0)))
+        ));
+
+        let backend = default_block_backend();
+        let mut device = default_vhost_user_block(&backend);
+        backend.fail_request(VhostUserRequest::SetVringKick);
+        let mem = vm_memory::create_shared_guest_memory(&[(GuestAddress(0), 0x10000)], None, false)
+            .unwrap();
+        activate(&mut device, &mem);
+        assert!(matches!(
+            device.setup_backend(),
+            Err(VhostUserError::BackendFailure(
+                VhostUserRequest::SetVringKick,
+                1
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_call_event() {
+        let backend = default_block_backend();
+        let mut device = default_vhost_user_block(&backend);
+
+        device.call_evts[0].write(1).unwrap();
+        device.process_call_event(0);
+        assert!(device.irq_trigger.has_pending_irq(IrqType::Vring));
+        // The call event was consumed.
+        assert!(device.call_evts[0].read().is_err());
+    }
+}
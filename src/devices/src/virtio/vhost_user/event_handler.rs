@@ -0,0 +1,135 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::os::unix::io::AsRawFd;
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use logger::{debug, error, warn, IncMetric, METRICS};
+use utils::epoll::EventSet;
+
+use crate::virtio::vhost_user::device::VhostUser;
+use crate::virtio::VirtioDevice;
+
+impl VhostUser {
+    // The queue events are consumed by the backend, only the call events are handled here.
+    fn register_runtime_events(&self, ops: &mut EventOps) {
+        for call_evt in &self.call_evts {
+            if let Err(err) = ops.add(Events::new(call_evt, EventSet::IN)) {
+                error!("Failed to register vhost-user call event: {}", err);
+            }
+        }
+    }
+
+    fn register_activate_event(&self, ops: &mut EventOps) {
+        if let Err(err) = ops.add(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to register activate event: {}", err);
+        }
+    }
+
+    fn process_activate_event(&mut self, ops: &mut EventOps) {
+        debug!("vhost-user: activate event");
+        if let Err(err) = self.activate_evt.read() {
+            error!("Failed to consume vhost-user activate event: {:?}", err);
+        }
+        // The backend is set up from the VMM thread, since the vCPU threads can't block on it.
+        if let Err(err) = self.setup_backend() {
+            error!(
+                "Failed to set up the vhost-user backend of {}: {:?}",
+                self.id, err
+            );
+            METRICS.vhost_user.activate_fails.inc();
+        }
+        self.register_runtime_events(ops);
+        if let Err(err) = ops.remove(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to un-register activate event: {}", err);
+        }
+    }
+}
+
+impl MutEventSubscriber for VhostUser {
+    fn process(&mut self, event: Events, ops: &mut EventOps) {
+        let source = event.fd();
+        let event_set = event.event_set();
+
+        let supported_events = EventSet::IN;
+        if !supported_events.contains(event_set) {
+            warn!(
+                "vhost-user: Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        if self.is_activated() {
+            let maybe_queue_index = self
+                .call_evts
+                .iter()
+                .position(|call_evt| call_evt.as_raw_fd() == source);
+            match maybe_queue_index {
+                Some(queue_index) => self.process_call_event(queue_index),
+                None if self.activate_evt.as_raw_fd() == source => self.process_activate_event(ops),
+                None => warn!("vhost-user: Spurious event received: {:?}", source),
+            }
+        } else {
+            warn!(
+                "vhost-user: The device is not yet activated. Spurious event received: {:?}",
+                source
+            );
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        if self.is_activated() {
+            self.register_runtime_events(ops);
+        } else {
+            self.register_activate_event(ops);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use event_manager::{EventManager, SubscriberOps};
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::virtio::test_utils::VirtQueue;
+    use crate::virtio::vhost_user::device::tests::default_vhost_user_block;
+    use crate::virtio::vhost_user::test_utils::default_block_backend;
+    use crate::virtio::vhost_user::VhostUserRequest;
+    use crate::virtio::IrqType;
+
+    #[test]
+    fn test_event_handler() {
+        let mut event_manager = EventManager::new().unwrap();
+        let backend = default_block_backend();
+        let device = Arc::new(Mutex::new(default_vhost_user_block(&backend)));
+        let _id = event_manager.add_subscriber(device.clone());
+
+        let mem = vm_memory::create_shared_guest_memory(&[(GuestAddress(0), 0x10000)], None, false)
+            .unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        device.lock().unwrap().queues[0] = vq.create_queue();
+
+        // Nothing happens before the device is activated.
+        device.lock().unwrap().call_evts[0].write(1).unwrap();
+        assert_eq!(event_manager.run_with_timeout(50).unwrap(), 0);
+
+        // The backend is set up upon activation.
+        device.lock().unwrap().activate(mem.clone()).unwrap();
+        assert_eq!(event_manager.run_with_timeout(50).unwrap(), 1);
+        assert!(backend
+            .requests()
+            .contains(&VhostUserRequest::SetVringEnable));
+
+        // The pending call event is relayed to the guest.
+        assert_eq!(event_manager.run_with_timeout(50).unwrap(), 1);
+        assert!(device
+            .lock()
+            .unwrap()
+            .irq_trigger
+            .has_pending_irq(IrqType::Vring));
+    }
+}
@@ -0,0 +1,449 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Master side of the vhost-user protocol.
+//!
+//! Messages are made of a 12 bytes header (request, flags and body size) followed by a little
+//! endian body. File descriptors, such as the guest memory regions and the vring eventfds, are
+//! passed along with the header as `SCM_RIGHTS` ancillary data. Only the requests needed to hand
+//! split virtqueues over to a backend are implemented.
+
+use std::io::{self, Read, Write};
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+
+use utils::byte_order::{read_le_u32, read_le_u64, write_le_u32, write_le_u64};
+use utils::sock_ctrl_msg::ScmSocket;
+
+use super::{Result, VhostUserError};
+
+pub(crate) const HEADER_LEN: usize = 12;
+// The version of the protocol, in the lowest bits of the header flags.
+pub(crate) const VERSION: u32 = 0x1;
+pub(crate) const FLAG_REPLY: u32 = 0x4;
+pub(crate) const FLAG_NEED_REPLY: u32 = 0x8;
+
+/// Largest number of memory regions a `SET_MEM_TABLE` message can describe.
+pub(crate) const MAX_MEMORY_REGIONS: usize = 8;
+const MEMORY_REGION_LEN: usize = 32;
+const CONFIG_HEADER_LEN: usize = 12;
+/// Largest config space the backend is asked for.
+pub(crate) const MAX_CONFIG_LEN: u32 = 256;
+
+/// Virtio feature bit signaling that the backend supports the protocol feature negotiation.
+pub(crate) const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+
+// Protocol features.
+pub(crate) const PROTOCOL_F_MQ: u64 = 1 << 0;
+pub(crate) const PROTOCOL_F_REPLY_ACK: u64 = 1 << 3;
+pub(crate) const PROTOCOL_F_CONFIG: u64 = 1 << 9;
+
+/// Requests sent by the master, numbered as in the vhost-user specification.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Request {
+    GetFeatures = 1,
+    SetFeatures = 2,
+    SetOwner = 3,
+    SetMemTable = 5,
+    SetVringNum = 8,
+    SetVringAddr = 9,
+    SetVringBase = 10,
+    SetVringKick = 12,
+    SetVringCall = 13,
+    GetProtocolFeatures = 15,
+    SetProtocolFeatures = 16,
+    GetQueueNum = 17,
+    SetVringEnable = 18,
+    GetConfig = 24,
+}
+
+/// A guest memory region shared with the backend.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct MemoryRegion {
+    pub guest_addr: u64,
+    pub size: u64,
+    pub user_addr: u64,
+    pub mmap_offset: u64,
+    pub fd: RawFd,
+}
+
+/// Addresses of the parts of a vring, in the address space of the VMM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct VringAddresses {
+    pub desc: u64,
+    pub used: u64,
+    pub avail: u64,
+}
+
+/// Connection to a vhost-user backend.
+pub(crate) struct Master {
+    stream: UnixStream,
+    // Whether the backend acknowledges the requests which don't have a reply.
+    reply_ack: bool,
+}
+
+impl Master {
+    /// Connects to the backend listening on `socket_path`.
+    pub fn connect(socket_path: &str) -> Result<Master> {
+        let stream = UnixStream::connect(socket_path).map_err(VhostUserError::Connect)?;
+        Ok(Master {
+            stream,
+            reply_ack: false,
+        })
+    }
+
+    /// Makes the requests without a reply wait for the backend acknowledgement. Only valid once
+    /// `PROTOCOL_F_REPLY_ACK` was negotiated.
+    pub fn set_reply_ack(&mut self, reply_ack: bool) {
+        self.reply_ack = reply_ack;
+    }
+
+    fn send(
+        &mut self,
+        request: Request,
+        body: &[u8],
+        fds: &[RawFd],
+        need_reply: bool,
+    ) -> Result<()> {
+        let mut message = vec![0u8; HEADER_LEN + body.len()];
+        let flags = if need_reply {
+            VERSION | FLAG_NEED_REPLY
+        } else {
+            VERSION
+        };
+        write_le_u32(&mut message[0..4], request as u32);
+        write_le_u32(&mut message[4..8], flags);
+        write_le_u32(&mut message[8..12], body.len() as u32);
+        message[HEADER_LEN..].copy_from_slice(body);
+
+        if fds.is_empty() {
+            return self
+                .stream
+                .write_all(&message)
+                .map_err(VhostUserError::Send);
+        }
+        // The file descriptors have to go along with the first byte of the message.
+        let sent = self
+            .stream
+            .send_with_fds(&[&message[..]], fds)
+            .map_err(|err| VhostUserError::Send(io::Error::from_raw_os_error(err.errno())))?;
+        self.stream
+            .write_all(&message[sent..])
+            .map_err(VhostUserError::Send)
+    }
+
+    // Reads the reply to `request` into `body`, and returns its length.
+    fn recv(&mut self, request: Request, body: &mut [u8]) -> Result<usize> {
+        let mut header = [0u8; HEADER_LEN];
+        self.stream
+            .read_exact(&mut header)
+            .map_err(VhostUserError::Recv)?;
+        let size = read_le_u32(&header[8..12]) as usize;
+        if read_le_u32(&header[0..4]) != request as u32
+            || read_le_u32(&header[4..8]) & FLAG_REPLY == 0
+            || size > body.len()
+        {
+            return Err(VhostUserError::InvalidReply(request));
+        }
+        self.stream
+            .read_exact(&mut body[..size])
+            .map_err(VhostUserError::Recv)?;
+        Ok(size)
+    }
+
+    // Sends a request without a reply, and waits for its acknowledgement if needed.
+    fn set(&mut self, request: Request, body: &[u8], fds: &[RawFd]) -> Result<()> {
+        self.send(request, body, fds, self.reply_ack)?;
+        if self.reply_ack {
+            let status = self.recv_u64(request)?;
+            if status != 0 {
+                return Err(VhostUserError::BackendFailure(request, status));
+            }
+        }
+        Ok(())
+    }
+
+    fn recv_u64(&mut self, request: Request) -> Result<u64> {
+        let mut body = [0u8; 8];
+        if self.recv(request, &mut body)? != body.len() {
+            return Err(VhostUserError::InvalidReply(request));
+        }
+        Ok(read_le_u64(&body))
+    }
+
+    fn get_u64(&mut self, request: Request) -> Result<u64> {
+        self.send(request, &[], &[], false)?;
+        self.recv_u64(request)
+    }
+
+    fn set_u64(&mut self, request: Request, value: u64, fds: &[RawFd]) -> Result<()> {
+        let mut body = [0u8; 8];
+        write_le_u64(&mut body, value);
+        self.set(request, &body, fds)
+    }
+
+    fn set_vring_state(&mut self, request: Request, index: usize, num: u32) -> Result<()> {
+        let mut body = [0u8; 8];
+        write_le_u32(&mut body[0..4], index as u32);
+        write_le_u32(&mut body[4..8], num);
+        self.set(request, &body, &[])
+    }
+
+    pub fn get_features(&mut self) -> Result<u64> {
+        self.get_u64(Request::GetFeatures)
+    }
+
+    pub fn set_features(&mut self, features: u64) -> Result<()> {
+        self.set_u64(Request::SetFeatures, features, &[])
+    }
+
+    pub fn set_owner(&mut self) -> Result<()> {
+        self.set(Request::SetOwner, &[], &[])
+    }
+
+    pub fn get_protocol_features(&mut self) -> Result<u64> {
+        self.get_u64(Request::GetProtocolFeatures)
+    }
+
+    pub fn set_protocol_features(&mut self, features: u64) -> Result<()> {
+        self.set_u64(Request::SetProtocolFeatures, features, &[])
+    }
+
+    pub fn get_queue_num(&mut self) -> Result<u64> {
+        self.get_u64(Request::GetQueueNum)
+    }
+
+    pub fn set_mem_table(&mut self, regions: &[MemoryRegion]) -> Result<()> {
+        if regions.len() > MAX_MEMORY_REGIONS {
+            return Err(VhostUserError::TooManyMemoryRegions(regions.len()));
+        }
+        let mut body = vec![0u8; 8 + regions.len() * MEMORY_REGION_LEN];
+        write_le_u32(&mut body[0..4], regions.len() as u32);
+        for (region, buf) in regions
+            .iter()
+            .zip(body[8..].chunks_exact_mut(MEMORY_REGION_LEN))
+        {
+            write_le_u64(&mut buf[0..8], region.guest_addr);
+            write_le_u64(&mut buf[8..16], region.size);
+            write_le_u64(&mut buf[16..24], region.user_addr);
+            write_le_u64(&mut buf[24..32], region.mmap_offset);
+        }
+        let fds: Vec<RawFd> = regions.iter().map(|region| region.fd).collect();
+        self.set(Request::SetMemTable, &body, &fds)
+    }
+
+    pub fn set_vring_num(&mut self, index: usize, num: u16) -> Result<()> {
+        self.set_vring_state(Request::SetVringNum, index, u32::from(num))
+    }
+
+    pub fn set_vring_addr(&mut self, index: usize, addresses: &VringAddresses) -> Result<()> {
+        let mut body = [0u8; 40];
+        write_le_u32(&mut body[0..4], index as u32);
+        // No flags, since dirty page logging is not negotiated.
+        write_le_u64(&mut body[8..16], addresses.desc);
+        write_le_u64(&mut body[16..24], addresses.used);
+        write_le_u64(&mut body[24..32], addresses.avail);
+        self.set(Request::SetVringAddr, &body, &[])
+    }
+
+    pub fn set_vring_base(&mut self, index: usize, base: u16) -> Result<()> {
+        self.set_vring_state(Request::SetVringBase, index, u32::from(base))
+    }
+
+    pub fn set_vring_kick(&mut self, index: usize, fd: RawFd) -> Result<()> {
+        self.set_u64(Request::SetVringKick, index as u64, &[fd])
+    }
+
+    pub fn set_vring_call(&mut self, index: usize, fd: RawFd) -> Result<()> {
+        self.set_u64(Request::SetVringCall, index as u64, &[fd])
+    }
+
+    pub fn set_vring_enable(&mut self, index: usize, enable: bool) -> Result<()> {
+        self.set_vring_state(Request::SetVringEnable, index, enable as u32)
+    }
+
+    /// Reads `len` bytes of the device config space, from `offset`.
+    pub fn get_config(&mut self, offset: u32, len: u32) -> Result<Vec<u8>> {
+        if len > MAX_CONFIG_LEN {
+            return Err(VhostUserError::InvalidConfigRange(offset, len));
+        }
+        let mut body = vec![0u8; CONFIG_HEADER_LEN + len as usize];
+        write_le_u32(&mut body[0..4], offset);
+        write_le_u32(&mut body[4..8], len);
+        self.send(Request::GetConfig, &body, &[], false)?;
+        // The reply has the same layout, with the payload filled in.
+        if self.recv(Request::GetConfig, &mut body)? != body.len() {
+            return Err(VhostUserError::InvalidReply(Request::GetConfig));
+        }
+        Ok(body.split_off(CONFIG_HEADER_LEN))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Seek;
+    use std::os::unix::io::AsRawFd;
+
+    use utils::eventfd::EventFd;
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::virtio::vhost_user::test_utils::BackendStub;
+
+    const FEATURES: u64 = (1 << 32) | VHOST_USER_F_PROTOCOL_FEATURES | 0x3;
+
+    fn read_fd_contents(file: &File) -> Vec<u8> {
+        let mut file = file.try_clone().unwrap();
+        file.seek(std::io::SeekFrom::Start(0)).unwrap();
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let backend = BackendStub::new(
+            FEATURES,
+            PROTOCOL_F_MQ | PROTOCOL_F_CONFIG,
+            4,
+            vec![1, 2, 3, 4],
+        );
+        let mut master = Master::connect(backend.socket_path()).unwrap();
+
+        assert_eq!(master.get_features().unwrap(), FEATURES);
+        master.set_owner().unwrap();
+        assert_eq!(
+            master.get_protocol_features().unwrap(),
+            PROTOCOL_F_MQ | PROTOCOL_F_CONFIG
+        );
+        master.set_protocol_features(PROTOCOL_F_CONFIG).unwrap();
+        master.set_features(FEATURES & !0x2).unwrap();
+        assert_eq!(master.get_queue_num().unwrap(), 4);
+        assert_eq!(master.get_config(1, 2).unwrap(), vec![2, 3]);
+        // Reading past the end of the config space of the stub returns zeroes.
+        assert_eq!(master.get_config(2, 4).unwrap(), vec![3, 4, 0, 0]);
+
+        // The config space can't be read past its end.
+        assert!(matches!(
+            master.get_config(0, MAX_CONFIG_LEN + 1),
+            Err(VhostUserError::InvalidConfigRange(0, _))
+        ));
+
+        // The requests without a reply were processed before the ones which followed.
+        assert_eq!(
+            backend.requests(),
+            vec![
+                Request::GetFeatures,
+                Request::SetOwner,
+                Request::GetProtocolFeatures,
+                Request::SetProtocolFeatures,
+                Request::SetFeatures,
+                Request::GetQueueNum,
+                Request::GetConfig,
+                Request::GetConfig,
+            ]
+        );
+        assert_eq!(
+            read_le_u64(&backend.message(Request::SetFeatures).unwrap().body),
+            FEATURES & !0x2
+        );
+    }
+
+    #[test]
+    fn test_vrings() {
+        let backend = BackendStub::new(FEATURES, PROTOCOL_F_REPLY_ACK, 2, vec![]);
+        let mut master = Master::connect(backend.socket_path()).unwrap();
+        master.set_reply_ack(true);
+
+        let region = TempFile::new().unwrap();
+        let mut region_file = region.as_file();
+        region_file.write_all(b"guest memory").unwrap();
+        master
+            .set_mem_table(&[MemoryRegion {
+                guest_addr: 0x1000,
+                size: 0x2000,
+                user_addr: 0x7f00_0000_0000,
+                mmap_offset: 0x10,
+                fd: region_file.as_raw_fd(),
+            }])
+            .unwrap();
+        let message = backend.message(Request::SetMemTable).unwrap();
+        assert_eq!(read_le_u32(&message.body[0..4]), 1);
+        assert_eq!(read_le_u64(&message.body[8..16]), 0x1000);
+        assert_eq!(read_le_u64(&message.body[16..24]), 0x2000);
+        assert_eq!(read_le_u64(&message.body[24..32]), 0x7f00_0000_0000);
+        assert_eq!(read_le_u64(&message.body[32..40]), 0x10);
+        // The backend got its own descriptor of the same file.
+        assert_eq!(message.files.len(), 1);
+        assert_eq!(read_fd_contents(&message.files[0]), b"guest memory");
+
+        master.set_vring_num(1, 256).unwrap();
+        assert_eq!(
+            backend.message(Request::SetVringNum).unwrap().body,
+            vec![1, 0, 0, 0, 0, 1, 0, 0]
+        );
+        let addresses = VringAddresses {
+            desc: 0x1000,
+            used: 0x3000,
+            avail: 0x2000,
+        };
+        master.set_vring_addr(1, &addresses).unwrap();
+        let body = backend.message(Request::SetVringAddr).unwrap().body;
+        assert_eq!(read_le_u32(&body[0..4]), 1);
+        assert_eq!(read_le_u64(&body[8..16]), 0x1000);
+        assert_eq!(read_le_u64(&body[16..24]), 0x3000);
+        assert_eq!(read_le_u64(&body[24..32]), 0x2000);
+        master.set_vring_base(1, 5).unwrap();
+
+        let kick_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        master.set_vring_kick(1, kick_evt.as_raw_fd()).unwrap();
+        let message = backend.message(Request::SetVringKick).unwrap();
+        assert_eq!(read_le_u64(&message.body), 1);
+        assert_eq!(message.files.len(), 1);
+        let call_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        master.set_vring_call(1, call_evt.as_raw_fd()).unwrap();
+        master.set_vring_enable(1, true).unwrap();
+        assert_eq!(
+            backend.message(Request::SetVringEnable).unwrap().body,
+            vec![1, 0, 0, 0, 1, 0, 0, 0]
+        );
+
+        // Too many memory regions.
+        let regions = vec![
+            MemoryRegion {
+                guest_addr: 0,
+                size: 0x1000,
+                user_addr: 0,
+                mmap_offset: 0,
+                fd: region_file.as_raw_fd(),
+            };
+            MAX_MEMORY_REGIONS + 1
+        ];
+        assert!(matches!(
+            master.set_mem_table(&regions),
+            Err(VhostUserError::TooManyMemoryRegions(9))
+        ));
+    }
+
+    #[test]
+    fn test_backend_failure() {
+        let backend = BackendStub::new(FEATURES, PROTOCOL_F_REPLY_ACK, 1, vec![]);
+        let mut master = Master::connect(backend.socket_path()).unwrap();
+        master.set_reply_ack(true);
+        backend.fail_request(Request::SetVringNum);
+
+        assert!(matches!(
+            master.set_vring_num(0, 256),
+            Err(VhostUserError::BackendFailure(Request::SetVringNum, 1))
+        ));
+        // The connection is still usable.
+        master.set_vring_base(0, 0).unwrap();
+
+        drop(backend);
+        assert!(matches!(
+            Master::connect("/invalid/socket"),
+            Err(VhostUserError::Connect(_))
+        ));
+    }
+}
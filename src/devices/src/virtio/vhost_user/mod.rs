@@ -0,0 +1,58 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Virtio devices whose virtqueues are processed by an external vhost-user backend.
+//!
+//! The VMM connects to the backend over a unix socket, negotiates the features and shares the
+//! guest memory, which has to be backed by file descriptors, along with the eventfds the guest
+//! kicks the queues through. The used buffer notifications of the backend are relayed to the
+//! guest by the VMM thread.
+
+mod device;
+mod event_handler;
+mod master;
+pub mod test_utils;
+
+use std::io;
+
+use vm_memory::GuestAddress;
+
+pub use self::device::VhostUser;
+pub use self::master::Request as VhostUserRequest;
+
+/// Default size of the virtqueues handed to a backend.
+pub const VHOST_USER_QUEUE_SIZE: u16 = 256;
+/// Upper bound of the number of queues of a vhost-user block device.
+pub const VHOST_USER_MAX_BLOCK_QUEUES: usize = 16;
+
+#[derive(Debug)]
+pub enum VhostUserError {
+    /// The backend reported a failure for the request.
+    BackendFailure(VhostUserRequest, u64),
+    /// Failed to connect to the backend socket.
+    Connect(io::Error),
+    /// Failed to create an eventfd.
+    EventFd(io::Error),
+    /// The config space range can't be transferred.
+    InvalidConfigRange(u32, u32),
+    /// The number of queues is out of the range supported by the device or the backend.
+    InvalidNumQueues(usize),
+    /// The queue size is zero or not a power of two.
+    InvalidQueueSize(u16),
+    /// The backend sent an unexpected reply to the request.
+    InvalidReply(VhostUserRequest),
+    /// A virtqueue address isn't backed by the guest memory.
+    InvalidVringAddress(GuestAddress),
+    /// The guest memory region at the given address can't be shared with the backend.
+    MemoryNotShareable(GuestAddress),
+    /// The backend doesn't support a feature the device depends on.
+    MissingFeature(&'static str),
+    /// Failed to receive a reply from the backend.
+    Recv(io::Error),
+    /// Failed to send a request to the backend.
+    Send(io::Error),
+    /// The guest memory has more regions than a vhost-user message can describe.
+    TooManyMemoryRegions(usize),
+}
+
+type Result<T> = std::result::Result<T, VhostUserError>;
@@ -0,0 +1,249 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![doc(hidden)]
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use utils::byte_order::{read_le_u32, write_le_u32, write_le_u64};
+use utils::sock_ctrl_msg::ScmSocket;
+use utils::tempfile::TempFile;
+use virtio_gen::virtio_blk::{VIRTIO_BLK_F_CONFIG_WCE, VIRTIO_BLK_F_MQ, VIRTIO_F_VERSION_1};
+use virtio_gen::virtio_net::VIRTIO_NET_F_CSUM;
+use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+
+use super::master::{
+    Request, FLAG_NEED_REPLY, FLAG_REPLY, HEADER_LEN, MAX_MEMORY_REGIONS, PROTOCOL_F_CONFIG,
+    PROTOCOL_F_MQ, PROTOCOL_F_REPLY_ACK, VERSION, VHOST_USER_F_PROTOCOL_FEATURES,
+};
+
+/// Returns a block backend supporting all the protocol features and up to 4 queues, for a disk
+/// of 0x800 sectors.
+pub fn default_block_backend() -> BackendStub {
+    let mut config = vec![0u8; 60];
+    write_le_u64(&mut config[0..8], 0x800);
+    BackendStub::new(
+        (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_BLK_F_CONFIG_WCE)
+            | (1u64 << VIRTIO_BLK_F_MQ)
+            | VHOST_USER_F_PROTOCOL_FEATURES,
+        PROTOCOL_F_MQ | PROTOCOL_F_REPLY_ACK | PROTOCOL_F_CONFIG,
+        4,
+        config,
+    )
+}
+
+/// Returns a network backend supporting checksum offloading and acknowledging the requests.
+pub fn default_net_backend() -> BackendStub {
+    BackendStub::new(
+        (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_NET_F_CSUM) | VHOST_USER_F_PROTOCOL_FEATURES,
+        PROTOCOL_F_REPLY_ACK,
+        1,
+        vec![],
+    )
+}
+
+/// A message received by the backend stub.
+pub struct StubMessage {
+    pub request: Request,
+    pub body: Vec<u8>,
+    /// The file descriptors passed along with the message.
+    pub files: Vec<File>,
+}
+
+#[derive(Default)]
+struct StubState {
+    messages: Vec<StubMessage>,
+    config: Vec<u8>,
+    failing_requests: HashSet<u32>,
+}
+
+/// Minimal vhost-user backend, serving a single connection on a unix socket. It records the
+/// messages it receives and replies to the requests which expect a reply, without processing
+/// any vring.
+pub struct BackendStub {
+    socket: TempFile,
+    state: Arc<Mutex<StubState>>,
+}
+
+impl BackendStub {
+    pub fn new(features: u64, protocol_features: u64, queue_num: u64, config: Vec<u8>) -> Self {
+        let mut socket = TempFile::new().unwrap();
+        socket.remove().unwrap();
+        let listener = UnixListener::bind(socket.as_path()).unwrap();
+        let state = Arc::new(Mutex::new(StubState {
+            config,
+            ..Default::default()
+        }));
+
+        let backend_state = state.clone();
+        thread::spawn(move || {
+            if let Ok((stream, _)) = listener.accept() {
+                Self::serve(
+                    stream,
+                    &backend_state,
+                    features,
+                    protocol_features,
+                    queue_num,
+                );
+            }
+        });
+        BackendStub { socket, state }
+    }
+
+    pub fn socket_path(&self) -> &str {
+        self.socket.as_path().to_str().unwrap()
+    }
+
+    /// Returns the requests received so far, in order.
+    pub fn requests(&self) -> Vec<Request> {
+        let state = self.state.lock().unwrap();
+        state
+            .messages
+            .iter()
+            .map(|message| message.request)
+            .collect()
+    }
+
+    /// Returns a copy of the last message received for `request`.
+    pub fn message(&self, request: Request) -> Option<StubMessage> {
+        let state = self.state.lock().unwrap();
+        state
+            .messages
+            .iter()
+            .rev()
+            .find(|message| message.request == request)
+            .map(|message| StubMessage {
+                request: message.request,
+                body: message.body.clone(),
+                files: message
+                    .files
+                    .iter()
+                    .map(|file| file.try_clone().unwrap())
+                    .collect(),
+            })
+    }
+
+    /// Makes the acknowledgements of `request` report a failure.
+    pub fn fail_request(&self, request: Request) {
+        self.state
+            .lock()
+            .unwrap()
+            .failing_requests
+            .insert(request as u32);
+    }
+
+    fn request_from_u32(value: u32) -> Request {
+        use self::Request::*;
+        [
+            GetFeatures,
+            SetFeatures,
+            SetOwner,
+            SetMemTable,
+            SetVringNum,
+            SetVringAddr,
+            SetVringBase,
+            SetVringKick,
+            SetVringCall,
+            GetProtocolFeatures,
+            SetProtocolFeatures,
+            GetQueueNum,
+            SetVringEnable,
+            GetConfig,
+        ]
+        .iter()
+        .copied()
+        .find(|request| *request as u32 == value)
+        .unwrap_or_else(|| panic!("Unexpected vhost-user request {}", value))
+    }
+
+    fn reply(stream: &mut UnixStream, request: u32, body: &[u8]) {
+        let mut message = vec![0u8; HEADER_LEN + body.len()];
+        write_le_u32(&mut message[0..4], request);
+        write_le_u32(&mut message[4..8], VERSION | FLAG_REPLY);
+        write_le_u32(&mut message[8..12], body.len() as u32);
+        message[HEADER_LEN..].copy_from_slice(body);
+        stream.write_all(&message).unwrap();
+    }
+
+    fn reply_u64(stream: &mut UnixStream, request: u32, value: u64) {
+        let mut body = [0u8; 8];
+        write_le_u64(&mut body, value);
+        Self::reply(stream, request, &body);
+    }
+
+    fn serve(
+        mut stream: UnixStream,
+        state: &Mutex<StubState>,
+        features: u64,
+        protocol_features: u64,
+        queue_num: u64,
+    ) {
+        loop {
+            let mut header = [0u8; HEADER_LEN];
+            let mut fds = [-1 as RawFd; MAX_MEMORY_REGIONS];
+            let mut iovecs = [libc::iovec {
+                iov_base: header.as_mut_ptr() as *mut libc::c_void,
+                iov_len: header.len(),
+            }];
+            // Safe because the iovec points to the header buffer.
+            let (read_count, fd_count) =
+                match unsafe { stream.recv_with_fds(&mut iovecs, &mut fds) } {
+                    Ok(counts) => counts,
+                    Err(_) => return,
+                };
+            if read_count == 0 {
+                // The master closed the connection.
+                return;
+            }
+            if read_count < HEADER_LEN {
+                stream.read_exact(&mut header[read_count..]).unwrap();
+            }
+            // Safe because the descriptors were just received, and are owned by nobody else.
+            let files = fds[..fd_count]
+                .iter()
+                .map(|fd| unsafe { File::from_raw_fd(*fd) })
+                .collect();
+
+            let request_value = read_le_u32(&header[0..4]);
+            let request = Self::request_from_u32(request_value);
+            let flags = read_le_u32(&header[4..8]);
+            let mut body = vec![0u8; read_le_u32(&header[8..12]) as usize];
+            stream.read_exact(&mut body).unwrap();
+
+            let mut state = state.lock().unwrap();
+            match request {
+                Request::GetFeatures => Self::reply_u64(&mut stream, request_value, features),
+                Request::GetProtocolFeatures => {
+                    Self::reply_u64(&mut stream, request_value, protocol_features)
+                }
+                Request::GetQueueNum => Self::reply_u64(&mut stream, request_value, queue_num),
+                Request::GetConfig => {
+                    let offset = read_le_u32(&body[0..4]) as usize;
+                    for (index, byte) in body[12..].iter_mut().enumerate() {
+                        *byte = state.config.get(offset + index).copied().unwrap_or(0);
+                    }
+                    Self::reply(&mut stream, request_value, &body);
+                }
+                _ => (),
+            }
+            if flags & FLAG_NEED_REPLY != 0 {
+                let status = state.failing_requests.contains(&request_value) as u64;
+                Self::reply_u64(&mut stream, request_value, status);
+            }
+
+            state.messages.push(StubMessage {
+                request,
+                body,
+                files,
+            });
+        }
+    }
+}
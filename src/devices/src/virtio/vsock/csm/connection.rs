@@ -84,7 +84,10 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::time::{Duration, Instant};
 
 use logger::{debug, error, info, warn, IncMetric, METRICS};
+use snapshot::Persist;
 use utils::epoll::EventSet;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
 use vm_memory::{GuestMemoryError, GuestMemoryMmap};
 
 use super::super::defs::uapi;
@@ -98,6 +101,8 @@ use super::{defs, ConnState, Error, PendingRx, PendingRxSet, Result};
 pub struct VsockConnection<S: Read + Write + AsRawFd> {
     /// The current connection state.
     state: ConnState,
+    /// Whether the connection was initiated by the host end.
+    local_init: bool,
     /// The local CID. Most of the time this will be the constant `2` (the vsock host CID).
     local_cid: u64,
     /// The peer (guest) CID.
@@ -502,6 +507,7 @@ where
             peer_port,
             stream,
             state: ConnState::PeerInit,
+            local_init: false,
             tx_buf: TxBuf::new(),
             fwd_cnt: Wrapping(0),
             peer_buf_alloc,
@@ -528,6 +534,7 @@ where
             peer_port,
             stream,
             state: ConnState::LocalInit,
+            local_init: true,
             tx_buf: TxBuf::new(),
             fwd_cnt: Wrapping(0),
             peer_buf_alloc: 0,
@@ -574,6 +581,17 @@ where
         self.state
     }
 
+    /// Replace the host-side stream, returning the previous one. This is used to hand a new
+    /// stream to a restored connection, once the host resumes it.
+    pub fn replace_stream(&mut self, stream: S) -> S {
+        // Disarm the resume timer, unless the peer is only waiting for the TX buffer to be
+        // drained, in which case the connection still has to be terminated in due time.
+        if self.state != ConnState::PeerClosed(true, true) {
+            self.expiry = None;
+        }
+        std::mem::replace(&mut self.stream, stream)
+    }
+
     /// Send some raw, untracked, data straight to the underlying connected stream.
     /// Returns: number of bytes written, or the error describing the write failure.
     ///
@@ -665,6 +683,121 @@ where
     }
 }
 
+/// The serializable state of an established `VsockConnection`. The host-side stream can't be
+/// saved, so a new one has to be provided when restoring the connection.
+#[derive(Clone, Debug, PartialEq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VsockConnectionState {
+    /// Whether the connection was initiated by the host end.
+    pub local_init: bool,
+    /// The local (host) port.
+    pub local_port: u32,
+    /// The peer (guest) port.
+    pub peer_port: u32,
+    /// The peer has promised it won't receive any more data.
+    peer_recv_off: bool,
+    /// The peer has promised it won't send any more data.
+    peer_send_off: bool,
+    /// The data sent by the peer, which is yet to be written to the host-side stream.
+    tx_buf: Vec<u8>,
+    fwd_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    rx_cnt: u32,
+    last_fwd_cnt_to_peer: u32,
+    /// The pending RX indications, as a `PendingRxSet` bitmask.
+    pending_rx: u16,
+}
+
+/// The arguments needed to restore a `VsockConnection`.
+pub struct VsockConnectionConstructorArgs<S> {
+    /// The host-side stream.
+    pub stream: S,
+    /// The local CID.
+    pub local_cid: u64,
+    /// The peer (guest) CID.
+    pub peer_cid: u64,
+    /// Whether `stream` is only a placeholder, until the host resumes the connection, which is
+    /// killed if that doesn't happen in time.
+    pub detached: bool,
+}
+
+impl<S> Persist<'_> for VsockConnection<S>
+where
+    S: Read + Write + AsRawFd,
+{
+    type State = VsockConnectionState;
+    type ConstructorArgs = VsockConnectionConstructorArgs<S>;
+    type Error = Error;
+
+    /// Save the state of the connection, which is expected to be either established, or shut
+    /// down by the peer.
+    fn save(&self) -> Self::State {
+        let (peer_recv_off, peer_send_off) = match self.state {
+            ConnState::PeerClosed(recv_off, send_off) => (recv_off, send_off),
+            _ => (false, false),
+        };
+        VsockConnectionState {
+            local_init: self.local_init,
+            local_port: self.local_port,
+            peer_port: self.peer_port,
+            peer_recv_off,
+            peer_send_off,
+            tx_buf: self.tx_buf.to_vec(),
+            fwd_cnt: self.fwd_cnt.0,
+            peer_buf_alloc: self.peer_buf_alloc,
+            peer_fwd_cnt: self.peer_fwd_cnt.0,
+            rx_cnt: self.rx_cnt.0,
+            last_fwd_cnt_to_peer: self.last_fwd_cnt_to_peer.0,
+            pending_rx: self.pending_rx.data,
+        }
+    }
+
+    fn restore(
+        constructor_args: Self::ConstructorArgs,
+        state: &Self::State,
+    ) -> std::result::Result<Self, Self::Error> {
+        let mut tx_buf = TxBuf::new();
+        if !state.tx_buf.is_empty() {
+            tx_buf.push(&state.tx_buf)?;
+        }
+
+        let conn_state = if state.peer_recv_off || state.peer_send_off {
+            ConnState::PeerClosed(state.peer_recv_off, state.peer_send_off)
+        } else {
+            ConnState::Established
+        };
+        let expiry = if constructor_args.detached {
+            Some(Instant::now() + Duration::from_millis(defs::CONN_RESUME_TIMEOUT_MS))
+        } else if conn_state == ConnState::PeerClosed(true, true) && !tx_buf.is_empty() {
+            Some(Instant::now() + Duration::from_millis(defs::CONN_SHUTDOWN_TIMEOUT_MS))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            state: conn_state,
+            local_init: state.local_init,
+            local_cid: constructor_args.local_cid,
+            peer_cid: constructor_args.peer_cid,
+            local_port: state.local_port,
+            peer_port: state.peer_port,
+            stream: constructor_args.stream,
+            tx_buf,
+            fwd_cnt: Wrapping(state.fwd_cnt),
+            peer_buf_alloc: state.peer_buf_alloc,
+            peer_fwd_cnt: Wrapping(state.peer_fwd_cnt),
+            rx_cnt: Wrapping(state.rx_cnt),
+            last_fwd_cnt_to_peer: Wrapping(state.last_fwd_cnt_to_peer),
+            // Whatever data the previous host-side stream had for us is gone.
+            pending_rx: PendingRxSet {
+                data: state.pending_rx & !PendingRx::Rw.into_mask(),
+            },
+            expiry,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
@@ -1262,4 +1395,103 @@ mod tests {
         ctx.recv();
         assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
     }
+
+    #[test]
+    fn test_persist() {
+        let mut ctx = CsmTestContext::new_established();
+
+        let mut stream = TestStream::new();
+        stream.write_state = StreamState::WouldBlock;
+        ctx.set_stream(stream);
+
+        // Leave some data in the TX buffer, and a credit update to be sent to the peer.
+        let data = &[1, 2, 3, 4];
+        ctx.init_data_pkt(data);
+        ctx.send();
+        ctx.conn.insert_credit_update();
+        ctx.conn.pending_rx.insert(PendingRx::Rw);
+
+        let state = ctx.conn.save();
+        assert!(!state.local_init);
+        assert_eq!(state.tx_buf, data);
+
+        // Test case: the connection is restored along with its host-side stream.
+        {
+            let restored_conn = VsockConnection::restore(
+                VsockConnectionConstructorArgs {
+                    stream: TestStream::new(),
+                    local_cid: LOCAL_CID,
+                    peer_cid: PEER_CID,
+                    detached: false,
+                },
+                &state,
+            )
+            .unwrap();
+            assert_eq!(restored_conn.save(), {
+                let mut expected = state.clone();
+                expected.pending_rx &= !PendingRx::Rw.into_mask();
+                expected
+            });
+            ctx.conn = restored_conn;
+            assert_eq!(ctx.conn.state, ConnState::Established);
+            assert_eq!(ctx.conn.local_port, LOCAL_PORT);
+            assert_eq!(ctx.conn.peer_port, PEER_PORT);
+            assert!(!ctx.conn.will_expire());
+
+            // The pending credit update survives the snapshot, but the data read from the
+            // previous stream doesn't.
+            assert!(ctx.conn.has_pending_rx());
+            ctx.recv();
+            assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_CREDIT_UPDATE);
+            assert!(!ctx.conn.has_pending_rx());
+
+            // The buffered data is flushed to the new stream.
+            assert!(ctx.conn.get_polled_evset().contains(EventSet::OUT));
+            ctx.notify_epollout();
+            assert!(ctx.conn.tx_buf.is_empty());
+            assert_eq!(ctx.conn.stream.write_buf, data);
+        }
+
+        // Test case: the connection is restored without its host-side stream, which is later
+        // replaced by the host.
+        {
+            let mut restored_conn = VsockConnection::restore(
+                VsockConnectionConstructorArgs {
+                    stream: TestStream::new(),
+                    local_cid: LOCAL_CID,
+                    peer_cid: PEER_CID,
+                    detached: true,
+                },
+                &state,
+            )
+            .unwrap();
+            assert!(restored_conn.will_expire());
+            assert!(!restored_conn.has_expired());
+
+            let mut stream = TestStream::new();
+            stream.read_buf = data.to_vec();
+            restored_conn.replace_stream(stream);
+            assert!(!restored_conn.will_expire());
+            assert_eq!(restored_conn.stream.read_buf, data);
+        }
+
+        // Test case: a connection shut down by the peer is restored in the same state.
+        {
+            ctx.init_pkt(uapi::VSOCK_OP_SHUTDOWN, 0)
+                .set_flags(uapi::VSOCK_FLAGS_SHUTDOWN_RCV);
+            ctx.send();
+            let state = ctx.conn.save();
+            let restored_conn = VsockConnection::restore(
+                VsockConnectionConstructorArgs {
+                    stream: TestStream::new(),
+                    local_cid: LOCAL_CID,
+                    peer_cid: PEER_CID,
+                    detached: false,
+                },
+                &state,
+            )
+            .unwrap();
+            assert_eq!(restored_conn.state, ConnState::PeerClosed(true, false));
+        }
+    }
 }
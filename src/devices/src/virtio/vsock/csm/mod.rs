@@ -8,7 +8,7 @@ mod txbuf;
 
 use std::fmt;
 
-pub use connection::VsockConnection;
+pub use connection::{VsockConnection, VsockConnectionConstructorArgs, VsockConnectionState};
 
 pub mod defs {
     /// Vsock connection TX buffer capacity.
@@ -23,6 +23,9 @@ pub mod defs {
 
     /// Connection graceful shutdown timeout, in millis.
     pub const CONN_SHUTDOWN_TIMEOUT_MS: u64 = 2000;
+
+    /// Timeout for the host to resume a restored host-initiated connection, in millis.
+    pub const CONN_RESUME_TIMEOUT_MS: u64 = 10000;
 }
 
 #[derive(Debug)]
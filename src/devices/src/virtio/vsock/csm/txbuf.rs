@@ -131,6 +131,18 @@ impl TxBuf {
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// Copy out the data that hasn't yet been flushed out, without consuming it.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut vec = Vec::with_capacity(self.len());
+        if let Some(data) = self.data.as_ref() {
+            let tail_ofs = self.tail.0 as usize % Self::SIZE;
+            let len = std::cmp::min(Self::SIZE - tail_ofs, self.len());
+            vec.extend_from_slice(&data[tail_ofs..(tail_ofs + len)]);
+            vec.extend_from_slice(&data[..(self.len() - len)]);
+        }
+        vec
+    }
 }
 
 impl Write for TxBuf {
@@ -223,6 +235,7 @@ mod tests {
         let mut txbuf = TxBuf::new();
         let mut sink = TestSink::new();
         let mut tmp: Vec<u8> = Vec::new();
+        assert!(txbuf.to_vec().is_empty());
 
         tmp.resize(TxBuf::SIZE - 2, 0);
         txbuf.push(tmp.as_slice()).unwrap();
@@ -230,6 +243,7 @@ mod tests {
         sink.clear();
 
         txbuf.push(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(txbuf.to_vec(), [1, 2, 3, 4]);
         assert_eq!(txbuf.flush_to(&mut sink).unwrap(), 4);
         assert_eq!(sink.data, [1, 2, 3, 4]);
         sink.clear();
@@ -47,6 +47,8 @@ pub(crate) const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
 /// - VIRTIO_F_VERSION_1: the device conforms to at least version 1.0 of the VirtIO spec.
 /// - VIRTIO_F_IN_ORDER: the device returns used buffers in the same order that the driver makes
 ///   them available.
+///
+/// VIRTIO_VSOCK_F_DGRAM is also offered, whenever the backend supports datagrams.
 pub(crate) const AVAIL_FEATURES: u64 =
     1 << uapi::VIRTIO_F_VERSION_1 as u64 | 1 << uapi::VIRTIO_F_IN_ORDER as u64;
 
@@ -82,12 +84,17 @@ where
             queue_events.push(EventFd::new(libc::EFD_NONBLOCK).map_err(VsockError::EventFd)?);
         }
 
+        let mut avail_features = AVAIL_FEATURES;
+        if backend.supports_dgram() {
+            avail_features |= 1 << uapi::VIRTIO_VSOCK_F_DGRAM as u64;
+        }
+
         Ok(Vsock {
             cid,
             queues,
             queue_events,
             backend,
-            avail_features: AVAIL_FEATURES,
+            avail_features,
             acked_features: 0,
             irq_trigger: IrqTrigger::new().map_err(VsockError::EventFd)?,
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(VsockError::EventFd)?,
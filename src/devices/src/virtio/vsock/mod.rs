@@ -22,7 +22,10 @@ use vm_memory::{GuestMemoryError, GuestMemoryMmap};
 pub use self::defs::uapi::VIRTIO_ID_VSOCK as TYPE_VSOCK;
 pub use self::defs::VSOCK_DEV_ID;
 pub use self::device::Vsock;
-pub use self::unix::{Error as VsockUnixBackendError, VsockUnixBackend};
+pub use self::unix::{
+    Error as VsockUnixBackendError, VsockForwarding, VsockPortAction, VsockPortFilter,
+    VsockPortRule, VsockTcpPortMapping, VsockUnixBackend,
+};
 use crate::virtio::persist::Error as VirtioStateError;
 
 mod defs {
@@ -52,6 +55,12 @@ mod defs {
         /// The device conforms to the virtio spec version 1.0.
         pub const VIRTIO_F_VERSION_1: u32 = 32;
 
+        /// Vsock feature flags.
+        /// Defined in `/include/uapi/linux/virtio_vsock.h`.
+        ///
+        /// The device supports datagram sockets.
+        pub const VIRTIO_VSOCK_F_DGRAM: usize = 3;
+
         /// Virtio vsock device ID.
         /// Defined in `include/uapi/linux/virtio_ids.h`.
         pub const VIRTIO_ID_VSOCK: u32 = 19;
@@ -85,8 +94,10 @@ mod defs {
         /// Vsock packet type.
         /// Defined in `/include/uapi/linux/virtio_vsock.h`.
         ///
-        /// Stream / connection-oriented packet (the only currently valid type).
+        /// Stream / connection-oriented packet.
         pub const VSOCK_TYPE_STREAM: u16 = 1;
+        /// Datagram / connectionless packet. Only valid if `VIRTIO_VSOCK_F_DGRAM` was negotiated.
+        pub const VSOCK_TYPE_DGRAM: u16 = 3;
 
         pub const VSOCK_HOST_CID: u64 = 2;
     }
@@ -161,4 +172,10 @@ pub trait VsockChannel {
 /// The vsock backend, which is basically an epoll-event-driven vsock channel.
 /// Currently, the only implementation we have is `crate::virtio::unix::muxer::VsockMuxer`, which
 /// translates guest-side vsock connections to host-side Unix domain socket connections.
-pub trait VsockBackend: VsockChannel + VsockEpollListener + Send {}
+pub trait VsockBackend: VsockChannel + VsockEpollListener + Send {
+    /// Whether the backend can handle `VSOCK_TYPE_DGRAM` packets, in which case the device will
+    /// offer `VIRTIO_VSOCK_F_DGRAM` to the driver.
+    fn supports_dgram(&self) -> bool {
+        false
+    }
+}
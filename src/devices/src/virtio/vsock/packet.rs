@@ -22,7 +22,8 @@ use vm_memory::{
 };
 
 use super::super::DescriptorChain;
-use super::{defs, Result, VsockError};
+use super::defs::{self, uapi};
+use super::{Result, VsockError};
 
 // The vsock packet header is defined by the C struct:
 //
@@ -359,6 +360,11 @@ impl VsockPacket {
         self
     }
 
+    /// Checks whether this is a datagram packet, which doesn't belong to any connection.
+    pub fn is_dgram(&self) -> bool {
+        self.type_() == uapi::VSOCK_TYPE_DGRAM
+    }
+
     pub fn op(&self) -> u16 {
         u16::from_le(self.hdr.op)
     }
@@ -641,6 +647,9 @@ mod tests {
         assert_eq!(pkt.flags(), FLAGS);
         assert_eq!(pkt.buf_alloc(), BUF_ALLOC);
         assert_eq!(pkt.fwd_cnt(), FWD_CNT);
+        assert!(!pkt.is_dgram());
+        pkt.set_type(uapi::VSOCK_TYPE_DGRAM);
+        assert!(pkt.is_dgram());
 
         // Test individual flag setting.
         let flags = pkt.flags() | 0b1000;
@@ -6,11 +6,15 @@
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
+use rate_limiter::persist::RateLimiterState;
+use rate_limiter::RateLimiter;
 use snapshot::Persist;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::GuestMemoryMmap;
 
+use super::csm::VsockConnectionState;
+use super::unix::{VsockForwardingState, VsockPortFilterState};
 use super::*;
 use crate::virtio::persist::VirtioDeviceState;
 use crate::virtio::{DeviceState, TYPE_VSOCK};
@@ -43,6 +47,64 @@ pub enum VsockBackendState {
 pub struct VsockUdsState {
     /// The path for the UDS socket.
     pub(crate) path: String,
+    /// Where the connections initiated by the guest are forwarded to.
+    #[version(start = 2, ser_fn = "forwarding_ser")]
+    pub(crate) forwarding: VsockForwardingState,
+    /// The rules deciding which ports the guest can connect to.
+    #[version(start = 2, ser_fn = "port_filter_ser")]
+    pub(crate) port_filter: VsockPortFilterState,
+    /// The datagram rate limiter, if datagrams are enabled.
+    #[version(start = 2, ser_fn = "dgram_ser")]
+    pub(crate) dgram_rate_limiter: Option<RateLimiterState>,
+    /// Whether the established connections are preserved across snapshot/restore.
+    #[version(start = 2, ser_fn = "preserve_connections_ser")]
+    pub(crate) preserve_connections: bool,
+    /// The preserved connections.
+    #[version(start = 2)]
+    pub(crate) connections: Vec<VsockConnectionState>,
+}
+
+impl VsockUdsState {
+    fn forwarding_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would forward all the connections to Unix sockets.
+        if target_version < 2 && VsockForwarding::from(&self.forwarding) != VsockForwarding::Unix {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support vsock forwarding to TCP or AF_VSOCK.".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn port_filter_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would let the guest connect to any port.
+        if target_version < 2
+            && VsockPortFilter::from(&self.port_filter) != VsockPortFilter::default()
+        {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support vsock port filtering.".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn dgram_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && self.dgram_rate_limiter.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support vsock datagrams.".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn preserve_connections_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // The guest connections wouldn't be reset, nor restored, by older versions.
+        if target_version < 2 && self.preserve_connections {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support preserving vsock connections.".to_owned(),
+            ));
+        }
+        Ok(())
+    }
 }
 
 /// A helper structure that holds the constructor arguments for VsockUnixBackend
@@ -65,6 +127,11 @@ impl Persist<'_> for VsockUnixBackend {
     fn save(&self) -> Self::State {
         VsockBackendState::Uds(VsockUdsState {
             path: self.host_sock_path.clone(),
+            forwarding: self.forwarding().into(),
+            port_filter: self.port_filter().into(),
+            dgram_rate_limiter: self.dgram_rate_limiter().map(RateLimiter::save),
+            preserve_connections: self.preserve_connections(),
+            connections: self.save_connections(),
         })
     }
 
@@ -73,10 +140,24 @@ impl Persist<'_> for VsockUnixBackend {
         state: &Self::State,
     ) -> std::result::Result<Self, Self::Error> {
         match state {
-            VsockBackendState::Uds(uds_state) => Ok(VsockUnixBackend::new(
-                constructor_args.cid,
-                uds_state.path.clone(),
-            )?),
+            VsockBackendState::Uds(uds_state) => {
+                let dgram_rate_limiter = uds_state
+                    .dgram_rate_limiter
+                    .as_ref()
+                    .map(|state| RateLimiter::restore((), state))
+                    .transpose()
+                    .map_err(VsockUnixBackendError::CreateRateLimiter)?;
+                let mut backend = VsockUnixBackend::new(
+                    constructor_args.cid,
+                    uds_state.path.clone(),
+                    (&uds_state.forwarding).into(),
+                    (&uds_state.port_filter).into(),
+                    dgram_rate_limiter,
+                    uds_state.preserve_connections,
+                )?;
+                backend.restore_connections(&uds_state.connections);
+                Ok(backend)
+            }
         }
     }
 }
@@ -143,6 +224,11 @@ pub(crate) mod tests {
         fn save(&self) -> Self::State {
             VsockBackendState::Uds(VsockUdsState {
                 path: "test".to_owned(),
+                forwarding: VsockForwardingState::default(),
+                port_filter: VsockPortFilterState::default(),
+                dgram_rate_limiter: None,
+                preserve_connections: false,
+                connections: Vec::new(),
             })
         }
 
@@ -156,6 +242,74 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_persist_forwarding() {
+        let mut tmp_sock_file = utils::tempfile::TempFile::new().unwrap();
+        tmp_sock_file.remove().unwrap();
+        let path = tmp_sock_file.as_path().to_str().unwrap().to_owned();
+        let forwarding = VsockForwarding::Tcp {
+            port_map: vec![VsockTcpPortMapping {
+                port: 52,
+                host_address: "127.0.0.1:8080".parse().unwrap(),
+            }],
+        };
+        let port_filter = VsockPortFilter {
+            rules: vec![VsockPortRule {
+                action: VsockPortAction::Allow,
+                first_port: 52,
+                last_port: None,
+            }],
+            default_action: VsockPortAction::Deny,
+        };
+        let dgram_rate_limiter = RateLimiter::new(0, 0, 0, 10, 0, 100).unwrap();
+        let backend = VsockUnixBackend::new(
+            3,
+            path.clone(),
+            forwarding.clone(),
+            port_filter.clone(),
+            Some(dgram_rate_limiter),
+            true,
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(VsockUdsState::type_id(), 2);
+
+        // Older versions can't hold the forwarding, the port filter, the datagram settings, nor
+        // preserve connections.
+        let state = backend.save();
+        assert!(state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        state
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        // The restored backend binds the same socket.
+        drop(backend);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{}_dgram", path)).unwrap();
+
+        let restored_state =
+            VsockBackendState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap();
+        let restored_backend =
+            VsockUnixBackend::restore(VsockUdsConstructorArgs { cid: 3 }, &restored_state).unwrap();
+        assert_eq!(restored_backend.forwarding(), &forwarding);
+        assert_eq!(restored_backend.port_filter(), &port_filter);
+        assert!(restored_backend.supports_dgram());
+        assert!(restored_backend
+            .dgram_rate_limiter()
+            .unwrap()
+            .ops()
+            .is_some());
+        assert!(restored_backend.preserve_connections());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{}_dgram", path)).unwrap();
+    }
+
     #[test]
     fn test_persist_uds_backend() {
         let ctx = TestContext::new();
@@ -0,0 +1,674 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Forwarding of the connections initiated by the guest to host sockets, and the port rules
+//! deciding which of those connections are let through.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use serde::{Deserialize, Serialize};
+use timerfd::{ClockId, TimerFd};
+use utils::syscall::SyscallReturnCode;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
+use super::{Error, Result};
+
+/// Where the connections initiated by the guest are forwarded to. Host-initiated connections
+/// always go through the Unix socket of the device, whichever the forwarding.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum VsockForwarding {
+    /// A connection to port `P` goes to the Unix socket listening at `<uds_path>_<P>`.
+    Unix,
+    /// A connection goes to the host TCP address mapped to its destination port. Connections
+    /// to ports without a mapping are refused.
+    Tcp { port_map: Vec<VsockTcpPortMapping> },
+    /// A connection to port `P` goes to port `P` of the host AF_VSOCK address `cid`, such as
+    /// the host of a nested VM or a sibling VM.
+    Vsock { cid: u32 },
+}
+
+impl Default for VsockForwarding {
+    fn default() -> Self {
+        VsockForwarding::Unix
+    }
+}
+
+/// Maps a port the guest connects to onto a host TCP address.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockTcpPortMapping {
+    /// The port the guest connects to, on the host CID.
+    pub port: u32,
+    /// The host TCP address the connection is forwarded to.
+    pub host_address: SocketAddr,
+}
+
+impl VsockForwarding {
+    /// Checks that the forwarding can be used by the device of guest `guest_cid`.
+    pub(crate) fn validate(&self, guest_cid: u64) -> Result<()> {
+        match self {
+            VsockForwarding::Unix => Ok(()),
+            VsockForwarding::Tcp { port_map } => {
+                let mut ports = HashSet::with_capacity(port_map.len());
+                match port_map.iter().find(|mapping| !ports.insert(mapping.port)) {
+                    Some(mapping) => Err(Error::DuplicatePortMapping(mapping.port)),
+                    None => Ok(()),
+                }
+            }
+            // The guest would end up connecting to itself, through the host.
+            VsockForwarding::Vsock { cid } if u64::from(*cid) == guest_cid => {
+                Err(Error::InvalidForwardingCid(*cid))
+            }
+            VsockForwarding::Vsock { cid } if *cid == libc::VMADDR_CID_ANY => {
+                Err(Error::InvalidForwardingCid(*cid))
+            }
+            VsockForwarding::Vsock { .. } => Ok(()),
+        }
+    }
+
+    /// Starts connecting to the host end of a connection made by the guest to `port`.
+    /// `host_sock_path` is the path of the Unix socket of the device.
+    ///
+    /// The Unix sockets are local, so they are connected right away. The TCP and AF_VSOCK
+    /// connections may still be in progress on return, so that unreachable addresses don't
+    /// stall the device.
+    pub(crate) fn connect(&self, host_sock_path: &str, port: u32) -> Result<HostConnect> {
+        match self {
+            VsockForwarding::Unix => UnixStream::connect(format!("{}_{}", host_sock_path, port))
+                .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
+                .map(|stream| HostConnect::Done(HostStream::Unix(stream)))
+                .map_err(Error::UnixConnect),
+            VsockForwarding::Tcp { port_map } => {
+                let mapping = port_map
+                    .iter()
+                    .find(|mapping| mapping.port == port)
+                    .ok_or(Error::PortNotMapped(port))?;
+                connect_tcp(mapping.host_address)
+                    .map(|(stream, in_progress)| {
+                        HostConnect::new(HostStream::Tcp(stream), in_progress)
+                    })
+                    .map_err(Error::TcpConnect)
+            }
+            VsockForwarding::Vsock { cid } => VsockStream::connect(*cid, port)
+                .map(|(stream, in_progress)| {
+                    HostConnect::new(HostStream::Vsock(stream), in_progress)
+                })
+                .map_err(Error::VsockConnect),
+        }
+    }
+}
+
+/// The host end of a guest-initiated connection, as returned by `VsockForwarding::connect()`.
+pub(crate) enum HostConnect {
+    /// The stream is connected.
+    Done(HostStream),
+    /// The stream is still connecting. The outcome is known once the stream becomes writable.
+    InProgress(HostStream),
+}
+
+impl HostConnect {
+    fn new(stream: HostStream, in_progress: bool) -> Self {
+        if in_progress {
+            HostConnect::InProgress(stream)
+        } else {
+            HostConnect::Done(stream)
+        }
+    }
+}
+
+// Creates a non-blocking stream socket of the given address family.
+fn nonblocking_socket(domain: libc::c_int) -> io::Result<File> {
+    // Safe because we check the return value.
+    let fd = SyscallReturnCode(unsafe {
+        libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0)
+    })
+    .into_result()?;
+    // Safe because the fd is valid and nothing else owns it.
+    let file = unsafe { File::from_raw_fd(fd) };
+
+    let mut nonblocking: libc::c_int = 1;
+    // Safe because the fd is valid, the kernel reads an int, and we check the return value.
+    SyscallReturnCode(unsafe { libc::ioctl(fd, libc::FIONBIO, &mut nonblocking) })
+        .into_empty_result()?;
+    Ok(file)
+}
+
+// Starts connecting the non-blocking socket `fd` to `addr`, a `sockaddr_*` struct. Returns
+// whether the connection is still in progress.
+fn connect_nonblocking<T>(fd: RawFd, addr: &T) -> io::Result<bool> {
+    // Safe because `addr` is a socket address of the given length, and we check the return
+    // value.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            addr as *const T as *const libc::sockaddr,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(false);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EINPROGRESS) {
+        Ok(true)
+    } else {
+        Err(err)
+    }
+}
+
+// Starts connecting a TCP socket to `addr`. Returns the stream, and whether the connection is
+// still in progress.
+fn connect_tcp(addr: SocketAddr) -> io::Result<(TcpStream, bool)> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = nonblocking_socket(domain)?.into_raw_fd();
+    // Safe because the fd is valid and its ownership was just released.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    stream.set_nodelay(true)?;
+
+    let in_progress = match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from(*addr.ip()).to_be(),
+                },
+                sin_zero: [0; 8],
+            };
+            connect_nonblocking(fd, &sockaddr)?
+        }
+        SocketAddr::V6(addr) => {
+            let sockaddr = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            connect_nonblocking(fd, &sockaddr)?
+        }
+    };
+    Ok((stream, in_progress))
+}
+
+/// What happens to the connections matched by a port rule.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VsockPortAction {
+    /// The connection is forwarded.
+    Allow,
+    /// The connection is refused.
+    Deny,
+}
+
+impl Default for VsockPortAction {
+    fn default() -> Self {
+        VsockPortAction::Allow
+    }
+}
+
+/// Applies an action to the connections made by the guest to a range of ports.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockPortRule {
+    /// What happens to the connections to the ports of the range.
+    pub action: VsockPortAction,
+    /// The first port of the range.
+    pub first_port: u32,
+    /// The last port of the range, included. The range only holds `first_port` when missing.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_port: Option<u32>,
+}
+
+impl VsockPortRule {
+    fn matches(&self, port: u32) -> bool {
+        port >= self.first_port && port <= self.last_port.unwrap_or(self.first_port)
+    }
+}
+
+/// The rules deciding which host ports the guest can connect to. The first rule matching the
+/// destination port of a connection applies, and the connections matched by no rule get the
+/// default action. Everything is allowed by default.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockPortFilter {
+    /// The rules, in the order in which they are checked.
+    #[serde(default)]
+    pub rules: Vec<VsockPortRule>,
+    /// The action applied to the connections matched by no rule.
+    #[serde(default)]
+    pub default_action: VsockPortAction,
+}
+
+impl VsockPortFilter {
+    /// Returns whether the guest can connect to `port`.
+    pub fn is_allowed(&self, port: u32) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(port))
+            .map_or(self.default_action, |rule| rule.action)
+            == VsockPortAction::Allow
+    }
+
+    /// Checks that the ranges of the rules aren't empty.
+    pub(crate) fn validate(&self) -> Result<()> {
+        match self
+            .rules
+            .iter()
+            .find(|rule| rule.last_port.unwrap_or(rule.first_port) < rule.first_port)
+        {
+            Some(rule) => Err(Error::InvalidPortRule(rule.first_port)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The saved state of a `VsockTcpPortMapping`.
+#[derive(Clone, Debug, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VsockTcpPortMappingState {
+    port: u32,
+    // IPv4 addresses are saved as IPv4-mapped IPv6 addresses.
+    host_ip: [u8; 16],
+    host_ipv4: bool,
+    host_port: u16,
+    host_scope_id: u32,
+}
+
+impl From<&VsockTcpPortMapping> for VsockTcpPortMappingState {
+    fn from(mapping: &VsockTcpPortMapping) -> Self {
+        let (host_ip, host_ipv4, host_scope_id) = match mapping.host_address {
+            SocketAddr::V4(addr) => (addr.ip().to_ipv6_mapped().octets(), true, 0),
+            SocketAddr::V6(addr) => (addr.ip().octets(), false, addr.scope_id()),
+        };
+        VsockTcpPortMappingState {
+            port: mapping.port,
+            host_ip,
+            host_ipv4,
+            host_port: mapping.host_address.port(),
+            host_scope_id,
+        }
+    }
+}
+
+impl From<&VsockTcpPortMappingState> for VsockTcpPortMapping {
+    fn from(state: &VsockTcpPortMappingState) -> Self {
+        let ip = state.host_ip;
+        let host_address = if state.host_ipv4 {
+            SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]),
+                state.host_port,
+            ))
+        } else {
+            SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(ip),
+                state.host_port,
+                0,
+                state.host_scope_id,
+            ))
+        };
+        VsockTcpPortMapping {
+            port: state.port,
+            host_address,
+        }
+    }
+}
+
+/// The saved state of a `VsockForwarding`.
+#[derive(Clone, Debug, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum VsockForwardingState {
+    Unix,
+    Tcp(Vec<VsockTcpPortMappingState>),
+    Vsock(u32),
+}
+
+impl Default for VsockForwardingState {
+    fn default() -> Self {
+        VsockForwardingState::Unix
+    }
+}
+
+impl From<&VsockForwarding> for VsockForwardingState {
+    fn from(forwarding: &VsockForwarding) -> Self {
+        match forwarding {
+            VsockForwarding::Unix => VsockForwardingState::Unix,
+            VsockForwarding::Tcp { port_map } => {
+                VsockForwardingState::Tcp(port_map.iter().map(Into::into).collect())
+            }
+            VsockForwarding::Vsock { cid } => VsockForwardingState::Vsock(*cid),
+        }
+    }
+}
+
+impl From<&VsockForwardingState> for VsockForwarding {
+    fn from(state: &VsockForwardingState) -> Self {
+        match state {
+            VsockForwardingState::Unix => VsockForwarding::Unix,
+            VsockForwardingState::Tcp(port_map) => VsockForwarding::Tcp {
+                port_map: port_map.iter().map(Into::into).collect(),
+            },
+            VsockForwardingState::Vsock(cid) => VsockForwarding::Vsock { cid: *cid },
+        }
+    }
+}
+
+/// The saved state of a `VsockPortRule`.
+#[derive(Clone, Debug, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VsockPortRuleState {
+    deny: bool,
+    first_port: u32,
+    last_port: Option<u32>,
+}
+
+/// The saved state of a `VsockPortFilter`.
+#[derive(Clone, Debug, Default, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct VsockPortFilterState {
+    rules: Vec<VsockPortRuleState>,
+    default_deny: bool,
+}
+
+impl From<&VsockPortFilter> for VsockPortFilterState {
+    fn from(filter: &VsockPortFilter) -> Self {
+        VsockPortFilterState {
+            rules: filter
+                .rules
+                .iter()
+                .map(|rule| VsockPortRuleState {
+                    deny: rule.action == VsockPortAction::Deny,
+                    first_port: rule.first_port,
+                    last_port: rule.last_port,
+                })
+                .collect(),
+            default_deny: filter.default_action == VsockPortAction::Deny,
+        }
+    }
+}
+
+impl From<&VsockPortFilterState> for VsockPortFilter {
+    fn from(state: &VsockPortFilterState) -> Self {
+        let action = |deny: bool| {
+            if deny {
+                VsockPortAction::Deny
+            } else {
+                VsockPortAction::Allow
+            }
+        };
+        VsockPortFilter {
+            rules: state
+                .rules
+                .iter()
+                .map(|rule| VsockPortRule {
+                    action: action(rule.deny),
+                    first_port: rule.first_port,
+                    last_port: rule.last_port,
+                })
+                .collect(),
+            default_action: action(state.default_deny),
+        }
+    }
+}
+
+/// A connected host AF_VSOCK stream socket.
+pub struct VsockStream {
+    file: File,
+}
+
+impl VsockStream {
+    /// Starts connecting to `port` of the host AF_VSOCK address `cid`. Returns the stream, and
+    /// whether the connection is still in progress.
+    fn connect(cid: u32, port: u32) -> io::Result<(Self, bool)> {
+        let stream = VsockStream {
+            file: nonblocking_socket(libc::AF_VSOCK)?,
+        };
+
+        // Safe because `sockaddr_vm` is a plain C struct, for which all zeroes is a valid value.
+        let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        addr.svm_cid = cid;
+        addr.svm_port = port;
+        let in_progress = connect_nonblocking(stream.file.as_raw_fd(), &addr)?;
+
+        Ok((stream, in_progress))
+    }
+
+    fn take_error(&self) -> io::Result<Option<io::Error>> {
+        let mut err: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // Safe because the fd is valid, the kernel writes an int of the given length, and we
+        // check the return value.
+        SyscallReturnCode(unsafe {
+            libc::getsockopt(
+                self.file.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut err as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        })
+        .into_empty_result()?;
+        Ok(match err {
+            0 => None,
+            err => Some(io::Error::from_raw_os_error(err)),
+        })
+    }
+}
+
+/// The host end of a vsock connection.
+pub enum HostStream {
+    /// A Unix socket, for host-initiated connections and for the `Unix` forwarding.
+    Unix(UnixStream),
+    /// A TCP socket, for the `Tcp` forwarding.
+    Tcp(TcpStream),
+    /// An AF_VSOCK socket, for the `Vsock` forwarding.
+    Vsock(VsockStream),
+    /// A placeholder for the stream of a restored host-initiated connection, until the host
+    /// resumes it. It is never ready for I/O, as its timer is never armed.
+    Detached(TimerFd),
+}
+
+impl HostStream {
+    /// Creates a placeholder stream for a restored connection.
+    pub(crate) fn detached() -> Result<Self> {
+        TimerFd::new_custom(ClockId::Monotonic, true, true)
+            .map(HostStream::Detached)
+            .map_err(Error::CreateDetachedStream)
+    }
+
+    /// Returns the error of a connection which failed to complete, if any.
+    pub(crate) fn take_error(&self) -> io::Result<Option<io::Error>> {
+        match self {
+            HostStream::Unix(stream) => stream.take_error(),
+            HostStream::Tcp(stream) => stream.take_error(),
+            HostStream::Vsock(stream) => stream.take_error(),
+            HostStream::Detached(_) => Ok(None),
+        }
+    }
+}
+
+impl Read for HostStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            HostStream::Unix(stream) => stream.read(buf),
+            HostStream::Tcp(stream) => stream.read(buf),
+            HostStream::Vsock(stream) => stream.file.read(buf),
+            HostStream::Detached(_) => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+        }
+    }
+}
+
+impl Write for HostStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            HostStream::Unix(stream) => stream.write(buf),
+            HostStream::Tcp(stream) => stream.write(buf),
+            HostStream::Vsock(stream) => stream.file.write(buf),
+            HostStream::Detached(_) => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            HostStream::Unix(stream) => stream.flush(),
+            HostStream::Tcp(stream) => stream.flush(),
+            HostStream::Vsock(stream) => stream.file.flush(),
+            HostStream::Detached(_) => Ok(()),
+        }
+    }
+}
+
+impl AsRawFd for HostStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            HostStream::Unix(stream) => stream.as_raw_fd(),
+            HostStream::Tcp(stream) => stream.as_raw_fd(),
+            HostStream::Vsock(stream) => stream.file.as_raw_fd(),
+            HostStream::Detached(timer) => timer.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn rule(action: VsockPortAction, first_port: u32, last_port: Option<u32>) -> VsockPortRule {
+        VsockPortRule {
+            action,
+            first_port,
+            last_port,
+        }
+    }
+
+    #[test]
+    fn test_port_filter() {
+        let mut filter = VsockPortFilter::default();
+        assert!(filter.is_allowed(0));
+        assert!(filter.is_allowed(u32::MAX));
+
+        filter.default_action = VsockPortAction::Deny;
+        filter.rules = vec![
+            rule(VsockPortAction::Deny, 1030, None),
+            rule(VsockPortAction::Allow, 1024, Some(2047)),
+            rule(VsockPortAction::Allow, 52, None),
+        ];
+        filter.validate().unwrap();
+        assert!(filter.is_allowed(52));
+        assert!(!filter.is_allowed(53));
+        assert!(filter.is_allowed(1024));
+        assert!(filter.is_allowed(2047));
+        assert!(!filter.is_allowed(2048));
+        // The first matching rule wins.
+        assert!(!filter.is_allowed(1030));
+
+        filter.rules.push(rule(VsockPortAction::Allow, 10, Some(9)));
+        assert!(matches!(filter.validate(), Err(Error::InvalidPortRule(10))));
+    }
+
+    #[test]
+    fn test_forwarding_validate() {
+        assert!(VsockForwarding::Unix.validate(3).is_ok());
+
+        let mapping = VsockTcpPortMapping {
+            port: 1024,
+            host_address: "127.0.0.1:8080".parse().unwrap(),
+        };
+        let mut port_map = vec![mapping.clone()];
+        assert!(VsockForwarding::Tcp {
+            port_map: port_map.clone()
+        }
+        .validate(3)
+        .is_ok());
+        port_map.push(mapping);
+        assert!(matches!(
+            VsockForwarding::Tcp { port_map }.validate(3),
+            Err(Error::DuplicatePortMapping(1024))
+        ));
+
+        assert!(VsockForwarding::Vsock { cid: 2 }.validate(3).is_ok());
+        assert!(matches!(
+            VsockForwarding::Vsock { cid: 3 }.validate(3),
+            Err(Error::InvalidForwardingCid(3))
+        ));
+        assert!(matches!(
+            VsockForwarding::Vsock { cid: u32::MAX }.validate(3),
+            Err(Error::InvalidForwardingCid(u32::MAX))
+        ));
+    }
+
+    #[test]
+    fn test_state_conversions() {
+        let forwarding = VsockForwarding::Tcp {
+            port_map: vec![
+                VsockTcpPortMapping {
+                    port: 52,
+                    host_address: "10.0.0.1:8080".parse().unwrap(),
+                },
+                VsockTcpPortMapping {
+                    port: 53,
+                    host_address: "[fe80::1%2]:8081".parse().unwrap(),
+                },
+            ],
+        };
+        let state = VsockForwardingState::from(&forwarding);
+        assert_eq!(VsockForwarding::from(&state), forwarding);
+        let forwarding = VsockForwarding::Vsock { cid: 2 };
+        let state = VsockForwardingState::from(&forwarding);
+        assert_eq!(VsockForwarding::from(&state), forwarding);
+
+        let filter = VsockPortFilter {
+            rules: vec![
+                rule(VsockPortAction::Allow, 52, None),
+                rule(VsockPortAction::Deny, 1024, Some(2047)),
+            ],
+            default_action: VsockPortAction::Deny,
+        };
+        let state = VsockPortFilterState::from(&filter);
+        assert_eq!(VsockPortFilter::from(&state), filter);
+    }
+
+    #[test]
+    fn test_tcp_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let forwarding = VsockForwarding::Tcp {
+            port_map: vec![VsockTcpPortMapping {
+                port: 1024,
+                host_address: listener.local_addr().unwrap(),
+            }],
+        };
+
+        assert!(matches!(
+            forwarding.connect("unused", 1025),
+            Err(Error::PortNotMapped(1025))
+        ));
+
+        let mut stream = match forwarding.connect("unused", 1024).unwrap() {
+            HostConnect::Done(stream) | HostConnect::InProgress(stream) => stream,
+        };
+        let (mut peer, _) = listener.accept().unwrap();
+        // The connection is complete once the listener accepts it.
+        assert!(stream.take_error().unwrap().is_none());
+        stream.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
@@ -7,10 +7,19 @@
 /// `muxer::VsockMuxer`, a connection multiplexer that uses `super::csm::VsockConnection` for
 /// handling vsock connection states.
 /// Check out `muxer.rs` for a more detailed explanation of the inner workings of this backend.
+/// Connections initiated by the guest can also be forwarded to host TCP or AF_VSOCK sockets,
+/// as described in `forward.rs`. Datagrams are bridged to host-side `SOCK_DGRAM` Unix sockets
+/// by `muxer_dgram::MuxerDgram`.
+mod forward;
 mod muxer;
+mod muxer_dgram;
 mod muxer_killq;
 mod muxer_rxq;
 
+pub use forward::{
+    VsockForwarding, VsockPortAction, VsockPortFilter, VsockPortRule, VsockTcpPortMapping,
+};
+pub(crate) use forward::{VsockForwardingState, VsockPortFilterState};
 pub use muxer::VsockMuxer as VsockUnixBackend;
 
 mod defs {
@@ -22,16 +31,35 @@ mod defs {
 
     /// Size of the muxer connection kill queue.
     pub const MUXER_KILLQ_SIZE: usize = 128;
+
+    /// Size of the muxer queue of host datagrams, waiting to be delivered to the guest.
+    pub const MUXER_DGRAM_RXQ_SIZE: usize = 64;
 }
 
 #[derive(Debug)]
 pub enum Error {
+    /// Error creating the placeholder stream of a restored connection.
+    CreateDetachedStream(std::io::Error),
+    /// Error creating the datagram rate limiter.
+    CreateRateLimiter(std::io::Error),
+    /// The TCP forwarding maps the same port more than once.
+    DuplicatePortMapping(u32),
     /// Error registering a new epoll-listening FD.
     EpollAdd(std::io::Error),
     /// Error creating an epoll FD.
     EpollFdCreate(std::io::Error),
+    /// The AF_VSOCK forwarding targets an unusable CID.
+    InvalidForwardingCid(u32),
     /// The host made an invalid vsock port connection request.
     InvalidPortRequest,
+    /// The port rule starting at the given port has an empty range.
+    InvalidPortRule(u32),
+    /// The TCP forwarding doesn't map the port the guest connected to.
+    PortNotMapped(u32),
+    /// Error restoring a connection from a snapshot.
+    RestoreConnection(super::csm::Error),
+    /// Error connecting to a host TCP address.
+    TcpConnect(std::io::Error),
     /// Error accepting a new connection from the host-side Unix socket.
     UnixAccept(std::io::Error),
     /// Error binding to the host-side Unix socket.
@@ -42,7 +70,9 @@ pub enum Error {
     UnixRead(std::io::Error),
     /// Muxer connection limit reached.
     TooManyConnections,
+    /// Error connecting to a host AF_VSOCK address.
+    VsockConnect(std::io::Error),
 }
 
 type Result<T> = std::result::Result<T, Error>;
-type MuxerConnection = super::csm::VsockConnection<std::os::unix::net::UnixStream>;
+type MuxerConnection = super::csm::VsockConnection<forward::HostStream>;
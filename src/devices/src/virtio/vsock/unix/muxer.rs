@@ -30,21 +30,42 @@
 ///    other pollable FDs are then registered under this nested epoll FD.
 ///    To route all these events to their handlers, the muxer uses another `HashMap` object,
 ///    mapping `RawFd`s to `EpollListener`s.
+///
+/// Connection requests coming from the guest are checked against the port filter of the muxer,
+/// and the allowed ones are forwarded to a host socket, as chosen by the `VsockForwarding`.
+///
+/// When enabled, datagram packets bypass the connection machinery altogether, and are handed
+/// over to `MuxerDgram` (see `muxer_dgram.rs`), which also registers its FDs under the muxer's
+/// nested epoll FD.
+///
+/// When preserving connections is enabled, the established connections are saved along with
+/// the muxer, instead of having the guest reset all of them. Once restored, the connections
+/// initiated by the guest are forwarded again, and the host-side stream gets a
+/// `RESUME <guest port>\n` line, before any data. The host end of a host-initiated connection
+/// has to resume it, by connecting to the host socket and sending `RESUME <local port>\n`,
+/// where `<local port>` is the port it was acknowledged with. The muxer replies with
+/// `OK <local port>\n`, and data flows again.
 use std::collections::{HashMap, HashSet};
-use std::io::Read;
+use std::io::{Read, Write};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 
 use logger::{debug, error, info, warn, IncMetric, METRICS};
+use rate_limiter::RateLimiter;
+use snapshot::Persist;
 use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
 use vm_memory::GuestMemoryMmap;
 
-use super::super::csm::ConnState;
+use super::super::csm::{
+    ConnState, Error as CsmError, VsockConnectionConstructorArgs, VsockConnectionState,
+};
 use super::super::defs::uapi;
 use super::super::packet::VsockPacket;
 use super::super::{
     Result as VsockResult, VsockBackend, VsockChannel, VsockEpollListener, VsockError,
 };
+use super::forward::{HostConnect, HostStream, VsockForwarding, VsockPortFilter};
+use super::muxer_dgram::MuxerDgram;
 use super::muxer_killq::MuxerKillQ;
 use super::muxer_rxq::MuxerRxQ;
 use super::{defs, Error, MuxerConnection, Result};
@@ -74,9 +95,33 @@ enum EpollListener {
     Connection { key: ConnMapKey, evset: EventSet },
     /// A listener interested in new host-initiated connections.
     HostSock,
-    /// A listener interested in reading host "connect <port>" commands from a freshly
-    /// connected host socket.
+    /// A listener interested in reading host "connect <port>" or "resume <port>" commands from
+    /// a freshly connected host socket.
     LocalStream(UnixStream),
+    /// A listener interested in datagrams sent by the host.
+    DgramSock,
+    /// A listener interested in the replenishment of the datagram rate limiter.
+    DgramRateLimiter,
+    /// A listener interested in the completion of the host-side stream of the guest-initiated
+    /// connection identified by `key`, which is held in the pending connections.
+    PendingConnect(ConnMapKey),
+}
+
+/// A guest-initiated connection, whose host-side stream is still connecting.
+struct PendingConnect {
+    stream: HostStream,
+    /// The buffer space of the guest for a new connection, or `None` for a restored one, which
+    /// waits for the stream on a placeholder.
+    peer_buf_alloc: Option<u32>,
+}
+
+/// A command read from a freshly connected host socket.
+#[derive(Debug, PartialEq)]
+enum LocalStreamCmd {
+    /// Connect to the given guest port.
+    Connect(u32),
+    /// Resume the restored connection with the given local port.
+    Resume(u32),
 }
 
 /// The vsock connection multiplexer.
@@ -106,6 +151,19 @@ pub struct VsockMuxer {
     local_port_set: HashSet<u32>,
     /// The last used host-side port.
     local_port_last: u32,
+    /// Where the connections initiated by the guest are forwarded to.
+    forwarding: VsockForwarding,
+    /// The rules deciding which ports the guest can connect to.
+    port_filter: VsockPortFilter,
+    /// The datagram side of the muxer, if datagrams are enabled.
+    dgram: Option<MuxerDgram>,
+    /// Whether the established connections are preserved across snapshot/restore.
+    preserve_connections: bool,
+    /// The restored host-initiated connections, which the host is yet to resume, keyed by
+    /// local port.
+    detached: HashMap<u32, ConnMapKey>,
+    /// The guest-initiated connections whose host-side stream is still connecting.
+    pending_connects: HashMap<ConnMapKey, PendingConnect>,
 }
 
 impl VsockChannel for VsockMuxer {
@@ -176,7 +234,15 @@ impl VsockChannel for VsockMuxer {
             }
         }
 
-        Err(VsockError::NoData)
+        // Connection traffic takes precedence over datagrams, which can be dropped anyway.
+        match self.dgram.as_mut() {
+            Some(dgram) => {
+                dgram.recv_pkt(pkt, mem, self.cid)?;
+                debug!("vsock muxer: RX dgram pkt: {:?}", pkt.hdr());
+                Ok(())
+            }
+            None => Err(VsockError::NoData),
+        }
     }
 
     /// Deliver a guest-generated packet to its destination in the vsock backend.
@@ -199,6 +265,29 @@ impl VsockChannel for VsockMuxer {
             pkt.hdr()
         );
 
+        // Datagrams don't belong to any connection, so they get routed to the datagram side of
+        // the muxer. Those it can't handle, or that the port filter denies, are silently
+        // dropped, since there's no RST for them.
+        if pkt.is_dgram() {
+            match self.dgram.as_mut() {
+                Some(dgram)
+                    if pkt.dst_cid() == uapi::VSOCK_HOST_CID && pkt.op() == uapi::VSOCK_OP_RW =>
+                {
+                    if self.port_filter.is_allowed(pkt.dst_port()) {
+                        dgram.send_pkt(pkt, mem)
+                    } else {
+                        debug!(
+                            "vsock: dropping guest datagram to denied port {}",
+                            pkt.dst_port()
+                        );
+                        METRICS.vsock.dgram_tx_denied.inc();
+                    }
+                }
+                _ => debug!("vsock: dropping guest datagram: {:?}", pkt.hdr()),
+            }
+            return Ok(());
+        }
+
         // If this packet has an unsupported type (!=stream), we must send back an RST.
         //
         if pkt.type_() != uapi::VSOCK_TYPE_STREAM {
@@ -217,6 +306,16 @@ impl VsockChannel for VsockMuxer {
         }
 
         if !self.conn_map.contains_key(&conn_key) {
+            if self.pending_connects.contains_key(&conn_key) {
+                // The guest isn't supposed to send anything but an RST before the connection
+                // is confirmed, so the connection attempt is over either way.
+                self.remove_pending_connect(conn_key);
+                if pkt.op() != uapi::VSOCK_OP_RST {
+                    self.enq_rst(pkt.dst_port(), pkt.src_port());
+                }
+                return Ok(());
+            }
+
             // This packet can't be routed to any active connection (based on its src and dst
             // ports).  The only orphan / unroutable packets we know how to handle are
             // connection requests.
@@ -250,7 +349,12 @@ impl VsockChannel for VsockMuxer {
     /// Check if the muxer has any pending RX data, with which to fill a guest-provided RX
     /// buffer.
     fn has_pending_rx(&self) -> bool {
-        !self.rxq.is_empty() || !self.rxq.is_synced()
+        !self.rxq.is_empty()
+            || !self.rxq.is_synced()
+            || self
+                .dgram
+                .as_ref()
+                .map_or(false, MuxerDgram::has_pending_rx)
     }
 }
 
@@ -298,16 +402,36 @@ impl VsockEpollListener for VsockMuxer {
     }
 }
 
-impl VsockBackend for VsockMuxer {}
+impl VsockBackend for VsockMuxer {
+    fn supports_dgram(&self) -> bool {
+        self.dgram.is_some()
+    }
+}
 
 impl VsockMuxer {
     /// Muxer constructor.
-    pub fn new(cid: u64, host_sock_path: String) -> Result<Self> {
+    ///
+    /// Datagrams are only enabled if `dgram_rate_limiter` is provided. Use
+    /// `RateLimiter::default()` for unlimited datagram traffic.
+    pub fn new(
+        cid: u64,
+        host_sock_path: String,
+        forwarding: VsockForwarding,
+        port_filter: VsockPortFilter,
+        dgram_rate_limiter: Option<RateLimiter>,
+        preserve_connections: bool,
+    ) -> Result<Self> {
+        forwarding.validate(cid)?;
+        port_filter.validate()?;
+
         // Open/bind on the host Unix socket, so we can accept host-initiated
         // connections.
         let host_sock = UnixListener::bind(&host_sock_path)
             .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
             .map_err(Error::UnixBind)?;
+        let dgram = dgram_rate_limiter
+            .map(|rate_limiter| MuxerDgram::new(&host_sock_path, rate_limiter))
+            .transpose()?;
 
         let mut muxer = Self {
             cid,
@@ -320,10 +444,24 @@ impl VsockMuxer {
             killq: MuxerKillQ::new(),
             local_port_last: (1u32 << 30) - 1,
             local_port_set: HashSet::with_capacity(defs::MAX_CONNECTIONS),
+            forwarding,
+            port_filter,
+            dgram,
+            preserve_connections,
+            detached: HashMap::new(),
+            pending_connects: HashMap::new(),
         };
 
         // Listen on the host initiated socket, for incoming connections.
         muxer.add_listener(muxer.host_sock.as_raw_fd(), EpollListener::HostSock)?;
+        if let Some((sock_fd, rate_limiter_fd)) = muxer
+            .dgram
+            .as_ref()
+            .map(|dgram| (dgram.sock_fd(), dgram.rate_limiter_fd()))
+        {
+            muxer.add_listener(sock_fd, EpollListener::DgramSock)?;
+            muxer.add_listener(rate_limiter_fd, EpollListener::DgramRateLimiter)?;
+        }
         Ok(muxer)
     }
 
@@ -331,6 +469,187 @@ impl VsockMuxer {
         &self.host_sock_path
     }
 
+    /// Returns where the connections initiated by the guest are forwarded to.
+    pub fn forwarding(&self) -> &VsockForwarding {
+        &self.forwarding
+    }
+
+    /// Returns the rules deciding which ports the guest can connect to.
+    pub fn port_filter(&self) -> &VsockPortFilter {
+        &self.port_filter
+    }
+
+    /// Returns the rate limiter applied to guest datagrams, if datagrams are enabled.
+    pub fn dgram_rate_limiter(&self) -> Option<&RateLimiter> {
+        self.dgram.as_ref().map(MuxerDgram::rate_limiter)
+    }
+
+    /// Returns the path of the Unix socket receiving host datagrams, if datagrams are enabled.
+    pub fn dgram_sock_path(&self) -> Option<String> {
+        self.dgram
+            .as_ref()
+            .map(|_| MuxerDgram::sock_path(&self.host_sock_path))
+    }
+
+    /// Returns whether the established connections are preserved across snapshot/restore.
+    pub fn preserve_connections(&self) -> bool {
+        self.preserve_connections
+    }
+
+    /// Returns the state of the connections to preserve across snapshot/restore, i.e. the
+    /// established ones, if preserving connections is enabled.
+    pub(crate) fn save_connections(&self) -> Vec<VsockConnectionState> {
+        if !self.preserve_connections {
+            return Vec::new();
+        }
+        self.conn_map
+            .values()
+            .filter(|conn| {
+                matches!(
+                    conn.state(),
+                    ConnState::Established | ConnState::PeerClosed(_, _)
+                )
+            })
+            .map(|conn| conn.save())
+            .collect()
+    }
+
+    /// Restore the connections saved by `save_connections()`. The guest gets an RST for each
+    /// connection that can't be restored.
+    pub(crate) fn restore_connections(&mut self, states: &[VsockConnectionState]) {
+        for state in states {
+            let key = ConnMapKey {
+                local_port: state.local_port,
+                peer_port: state.peer_port,
+            };
+            self.restore_connection(key, state).unwrap_or_else(|err| {
+                warn!(
+                    "vsock: unable to restore connection (lp={}, pp={}): {:?}",
+                    key.local_port, key.peer_port, err
+                );
+                self.enq_rst(key.local_port, key.peer_port);
+            });
+        }
+    }
+
+    fn restore_connection(&mut self, key: ConnMapKey, state: &VsockConnectionState) -> Result<()> {
+        let (stream, connecting) = if state.local_init {
+            // The host has to resume the connection, until then there's no stream to use.
+            (HostStream::detached()?, None)
+        } else {
+            match self
+                .forwarding
+                .connect(&self.host_sock_path, state.local_port)?
+            {
+                HostConnect::Done(mut stream) => {
+                    Self::write_resume_cmd(&mut stream, state.peer_port)?;
+                    (stream, None)
+                }
+                // The connection waits on a placeholder until the new stream is connected.
+                HostConnect::InProgress(stream) => (HostStream::detached()?, Some(stream)),
+            }
+        };
+        let conn = MuxerConnection::restore(
+            VsockConnectionConstructorArgs {
+                stream,
+                local_cid: uapi::VSOCK_HOST_CID,
+                peer_cid: self.cid,
+                detached: state.local_init || connecting.is_some(),
+            },
+            state,
+        )
+        .map_err(Error::RestoreConnection)?;
+        let expiry = conn.expiry();
+
+        self.add_connection(key, conn)?;
+        if state.local_init {
+            self.local_port_set.insert(key.local_port);
+            self.detached.insert(key.local_port, key);
+        }
+        if let Some(stream) = connecting {
+            let pending = PendingConnect {
+                stream,
+                peer_buf_alloc: None,
+            };
+            if let Err(err) = self.add_pending_connect(key, pending) {
+                self.remove_connection(key);
+                return Err(err);
+            }
+        }
+        if let Some(expiry) = expiry {
+            self.killq.push(key, expiry);
+        }
+        Ok(())
+    }
+
+    /// Let the host end of a restored guest-initiated connection know which connection it
+    /// resumes. The stream is freshly connected, so the line is expected to fit in it.
+    fn write_resume_cmd(stream: &mut HostStream, peer_port: u32) -> Result<()> {
+        stream
+            .write_all(format!("RESUME {}\n", peer_port).as_bytes())
+            .map_err(|err| Error::RestoreConnection(CsmError::StreamWrite(err)))
+    }
+
+    /// Hand over the newly connected stream to a restored guest-initiated connection, which
+    /// was waiting for it on a placeholder.
+    fn resume_peer_connection(&mut self, key: ConnMapKey, mut stream: HostStream) -> Result<()> {
+        let fd = match self.conn_map.get(&key) {
+            Some(conn) if conn.state() != ConnState::Killed => conn.as_raw_fd(),
+            // The connection was killed in the meantime, and is about to be reset.
+            _ => return Ok(()),
+        };
+        Self::write_resume_cmd(&mut stream, key.peer_port)?;
+        // The listener of the placeholder stream goes away with it. The one of the new stream
+        // is registered by `apply_conn_mutation()`.
+        self.remove_listener(fd);
+        self.apply_conn_mutation(key, |conn| {
+            conn.replace_stream(stream);
+        });
+        Ok(())
+    }
+
+    /// Hand over the stream of a host socket to the restored host-initiated connection with
+    /// the given local port.
+    fn resume_local_connection(&mut self, stream: UnixStream, local_port: u32) -> Result<()> {
+        // Connections which have waited for too long can't be resumed anymore.
+        self.sweep_killq();
+
+        let key = self
+            .detached
+            .remove(&local_port)
+            .ok_or(Error::InvalidPortRequest)?;
+        let fd = match self.conn_map.get(&key) {
+            Some(conn) if conn.state() != ConnState::Killed => conn.as_raw_fd(),
+            _ => return Err(Error::InvalidPortRequest),
+        };
+        // The listener of the placeholder stream goes away with it. The one of the new stream
+        // is registered by `apply_conn_mutation()`.
+        self.remove_listener(fd);
+        self.apply_conn_mutation(key, |conn| {
+            conn.replace_stream(HostStream::Unix(stream));
+            Self::ack_local_connection(conn, local_port);
+        });
+        Ok(())
+    }
+
+    /// Let the host end of a host-initiated connection know that data can now flow.
+    fn ack_local_connection(conn: &mut MuxerConnection, local_port: u32) {
+        let msg = format!("OK {}\n", local_port);
+        match conn.send_bytes_raw(msg.as_bytes()) {
+            Ok(written) if written == msg.len() => (),
+            Ok(_) => {
+                // If we can't write a dozen bytes to a pristine connection something
+                // must be really wrong. Killing it.
+                conn.kill();
+                warn!("vsock: unable to fully write connection ack msg.");
+            }
+            Err(err) => {
+                conn.kill();
+                warn!("vsock: unable to ack host connection: {:?}", err);
+            }
+        };
+    }
+
     /// Handle/dispatch an epoll event to its listener.
     fn handle_event(&mut self, fd: RawFd, event_set: EventSet) {
         debug!(
@@ -383,29 +702,58 @@ impl VsockMuxer {
             }
 
             // Data is ready to be read from a host-initiated connection. That would be the
-            // "connect" command that we're expecting.
+            // "connect" or "resume" command that we're expecting.
             Some(EpollListener::LocalStream(_)) => {
                 if let Some(EpollListener::LocalStream(mut stream)) = self.remove_listener(fd) {
-                    Self::read_local_stream_port(&mut stream)
-                        .map(|peer_port| (self.allocate_local_port(), peer_port))
-                        .and_then(|(local_port, peer_port)| {
+                    match Self::read_local_stream_cmd(&mut stream) {
+                        Ok(LocalStreamCmd::Connect(peer_port)) => {
+                            let local_port = self.allocate_local_port();
                             self.add_connection(
                                 ConnMapKey {
                                     local_port,
                                     peer_port,
                                 },
                                 MuxerConnection::new_local_init(
-                                    stream,
+                                    HostStream::Unix(stream),
                                     uapi::VSOCK_HOST_CID,
                                     self.cid,
                                     local_port,
                                     peer_port,
                                 ),
                             )
-                        })
-                        .unwrap_or_else(|err| {
-                            info!("vsock: error adding local-init connection: {:?}", err);
-                        })
+                        }
+                        Ok(LocalStreamCmd::Resume(local_port)) => {
+                            self.resume_local_connection(stream, local_port)
+                        }
+                        Err(err) => Err(err),
+                    }
+                    .unwrap_or_else(|err| {
+                        info!("vsock: error adding local-init connection: {:?}", err);
+                    })
+                }
+            }
+
+            // Datagrams were sent by the host.
+            Some(EpollListener::DgramSock) => {
+                if let Some(dgram) = self.dgram.as_mut() {
+                    dgram.read_host_dgrams();
+                }
+            }
+
+            // The datagram rate limiter has been replenished.
+            Some(EpollListener::DgramRateLimiter) => {
+                if let Some(dgram) = self.dgram.as_mut() {
+                    dgram.process_rate_limiter_event();
+                }
+            }
+
+            // The host-side stream of a guest-initiated connection is done connecting, whether
+            // it succeeded or not.
+            Some(EpollListener::PendingConnect(key)) => {
+                let key = *key;
+                self.remove_listener(fd);
+                if let Some(pending) = self.pending_connects.remove(&key) {
+                    self.complete_connect(key, pending);
                 }
             }
 
@@ -419,13 +767,13 @@ impl VsockMuxer {
         }
     }
 
-    /// Parse a host "connect" command, and extract the destination vsock port.
-    fn read_local_stream_port(stream: &mut UnixStream) -> Result<u32> {
+    /// Parse a host "connect" or "resume" command, along with its vsock port.
+    fn read_local_stream_cmd(stream: &mut UnixStream) -> Result<LocalStreamCmd> {
         let mut buf = [0u8; 32];
 
         // This is the minimum number of bytes that we should be able to read, when parsing a
-        // valid connection request. I.e. `b"connect 0\n".len()`.
-        const MIN_READ_LEN: usize = 10;
+        // valid command. I.e. `b"resume 0\n".len()`.
+        const MIN_READ_LEN: usize = 9;
 
         // Bring in the minimum number of bytes that we should be able to read.
         stream
@@ -447,19 +795,20 @@ impl VsockMuxer {
             .map_err(|_| Error::InvalidPortRequest)?
             .split_whitespace();
 
-        word_iter
+        let cmd = word_iter
+            .next()
+            .ok_or(Error::InvalidPortRequest)?
+            .to_lowercase();
+        let port = word_iter
             .next()
             .ok_or(Error::InvalidPortRequest)
-            .and_then(|word| {
-                if word.to_lowercase() == "connect" {
-                    Ok(())
-                } else {
-                    Err(Error::InvalidPortRequest)
-                }
-            })
-            .and_then(|_| word_iter.next().ok_or(Error::InvalidPortRequest))
-            .and_then(|word| word.parse::<u32>().map_err(|_| Error::InvalidPortRequest))
-            .map_err(|_| Error::InvalidPortRequest)
+            .and_then(|word| word.parse::<u32>().map_err(|_| Error::InvalidPortRequest))?;
+
+        match cmd.as_str() {
+            "connect" => Ok(LocalStreamCmd::Connect(port)),
+            "resume" => Ok(LocalStreamCmd::Resume(port)),
+            _ => Err(Error::InvalidPortRequest),
+        }
     }
 
     /// Add a new connection to the active connection pool.
@@ -503,9 +852,66 @@ impl VsockMuxer {
             self.remove_listener(conn.as_raw_fd());
             METRICS.vsock.conns_removed.inc();
         }
+        if self.detached.get(&key.local_port) == Some(&key) {
+            self.detached.remove(&key.local_port);
+        }
+        self.remove_pending_connect(key);
         self.free_local_port(key.local_port);
     }
 
+    /// Wait for the host-side stream of a guest-initiated connection to be connected.
+    fn add_pending_connect(&mut self, key: ConnMapKey, pending: PendingConnect) -> Result<()> {
+        if self.pending_connects.len() >= defs::MAX_CONNECTIONS {
+            return Err(Error::TooManyConnections);
+        }
+        self.add_listener(
+            pending.stream.as_raw_fd(),
+            EpollListener::PendingConnect(key),
+        )?;
+        self.pending_connects.insert(key, pending);
+        Ok(())
+    }
+
+    /// Give up on connecting the host-side stream of a guest-initiated connection.
+    fn remove_pending_connect(&mut self, key: ConnMapKey) {
+        if let Some(pending) = self.pending_connects.remove(&key) {
+            self.remove_listener(pending.stream.as_raw_fd());
+        }
+    }
+
+    /// Handle the outcome of connecting the host-side stream of a guest-initiated connection.
+    /// A new connection is confirmed to the guest, or reset if the stream couldn't connect. A
+    /// restored connection gets its new stream, or is killed.
+    fn complete_connect(&mut self, key: ConnMapKey, pending: PendingConnect) {
+        let res = match pending.stream.take_error() {
+            Ok(None) => Ok(()),
+            Ok(Some(err)) | Err(err) => Err(err),
+        };
+        if let Err(ref err) = res {
+            info!(
+                "vsock: unable to connect host stream (lp={}, pp={}): {:?}",
+                key.local_port, key.peer_port, err
+            );
+        }
+
+        match (res, pending.peer_buf_alloc) {
+            (Ok(()), Some(peer_buf_alloc)) => self
+                .add_peer_connection(key, pending.stream, peer_buf_alloc)
+                .unwrap_or_else(|_| self.enq_rst(key.local_port, key.peer_port)),
+            (Err(_), Some(_)) => self.enq_rst(key.local_port, key.peer_port),
+            (Ok(()), None) => self
+                .resume_peer_connection(key, pending.stream)
+                .unwrap_or_else(|err| {
+                    warn!(
+                        "vsock: unable to resume connection (lp={}, pp={}): {:?}",
+                        key.local_port, key.peer_port, err
+                    );
+                    self.kill_connection(key);
+                }),
+            (Err(_), None) => self.kill_connection(key),
+        }
+    }
+
     /// Schedule a connection for immediate termination.
     /// I.e. as soon as we can also let our peer know we're dropping the connection, by sending
     /// it an RST packet.
@@ -533,6 +939,9 @@ impl VsockMuxer {
             EpollListener::Connection { evset, .. } => evset,
             EpollListener::LocalStream(_) => EventSet::IN,
             EpollListener::HostSock => EventSet::IN,
+            EpollListener::DgramSock => EventSet::IN,
+            EpollListener::DgramRateLimiter => EventSet::IN,
+            EpollListener::PendingConnect(_) => EventSet::OUT,
         };
 
         self.epoll
@@ -585,33 +994,64 @@ impl VsockMuxer {
 
     /// Handle a new connection request comming from our peer (the guest vsock driver).
     ///
-    /// This will attempt to connect to a host-side Unix socket, expected to be listening at
-    /// the file system path corresponing to the destination port. If successful, a new
-    /// connection object will be created and added to the connection pool. On failure, a new
-    /// RST packet will be scheduled for delivery to the guest.
+    /// If the port filter allows the destination port, this will attempt to connect to the
+    /// host-side socket the port is forwarded to (by default, a Unix socket expected to be
+    /// listening at the file system path corresponing to the destination port). If successful,
+    /// a new connection object will be created and added to the connection pool. Streams that
+    /// are still connecting are kept aside until they become writable, and the connection is
+    /// only confirmed to the guest then. On failure, a new RST packet will be scheduled for
+    /// delivery to the guest.
     fn handle_peer_request_pkt(&mut self, pkt: &VsockPacket) {
-        let port_path = format!("{}_{}", self.host_sock_path, pkt.dst_port());
-
-        UnixStream::connect(port_path)
-            .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
-            .map_err(Error::UnixConnect)
-            .and_then(|stream| {
-                self.add_connection(
-                    ConnMapKey {
-                        local_port: pkt.dst_port(),
-                        peer_port: pkt.src_port(),
-                    },
-                    MuxerConnection::new_peer_init(
-                        stream,
-                        uapi::VSOCK_HOST_CID,
-                        self.cid,
-                        pkt.dst_port(),
-                        pkt.src_port(),
-                        pkt.buf_alloc(),
-                    ),
-                )
-            })
-            .unwrap_or_else(|_| self.enq_rst(pkt.dst_port(), pkt.src_port()));
+        if !self.port_filter.is_allowed(pkt.dst_port()) {
+            debug!(
+                "vsock: refusing guest connection to denied port {}",
+                pkt.dst_port()
+            );
+            METRICS.vsock.conns_denied.inc();
+            self.enq_rst(pkt.dst_port(), pkt.src_port());
+            return;
+        }
+
+        let key = ConnMapKey {
+            local_port: pkt.dst_port(),
+            peer_port: pkt.src_port(),
+        };
+        match self
+            .forwarding
+            .connect(&self.host_sock_path, pkt.dst_port())
+        {
+            Ok(HostConnect::Done(stream)) => self.add_peer_connection(key, stream, pkt.buf_alloc()),
+            Ok(HostConnect::InProgress(stream)) => self.add_pending_connect(
+                key,
+                PendingConnect {
+                    stream,
+                    peer_buf_alloc: Some(pkt.buf_alloc()),
+                },
+            ),
+            Err(err) => Err(err),
+        }
+        .unwrap_or_else(|_| self.enq_rst(pkt.dst_port(), pkt.src_port()));
+    }
+
+    /// Add a guest-initiated connection, whose host-side stream is connected, to the
+    /// connection pool. The connection will confirm itself to the guest.
+    fn add_peer_connection(
+        &mut self,
+        key: ConnMapKey,
+        stream: HostStream,
+        peer_buf_alloc: u32,
+    ) -> Result<()> {
+        self.add_connection(
+            key,
+            MuxerConnection::new_peer_init(
+                stream,
+                uapi::VSOCK_HOST_CID,
+                self.cid,
+                key.local_port,
+                key.peer_port,
+                peer_buf_alloc,
+            ),
+        )
     }
 
     /// Perform an action that might mutate a connection's state.
@@ -635,20 +1075,7 @@ impl VsockMuxer {
             // If this is a host-initiated connection that has just become established, we'll have
             // to send an ack message to the host end.
             if prev_state == ConnState::LocalInit && conn.state() == ConnState::Established {
-                let msg = format!("OK {}\n", key.local_port);
-                match conn.send_bytes_raw(msg.as_bytes()) {
-                    Ok(written) if written == msg.len() => (),
-                    Ok(_) => {
-                        // If we can't write a dozen bytes to a pristine connection something
-                        // must be really wrong. Killing it.
-                        conn.kill();
-                        warn!("vsock: unable to fully write connection ack msg.");
-                    }
-                    Err(err) => {
-                        conn.kill();
-                        warn!("vsock: unable to ack host connection: {:?}", err);
-                    }
-                };
+                Self::ack_local_connection(conn, key.local_port);
             }
 
             // If the connection wasn't previously scheduled for RX, add it to our RX queue.
@@ -768,13 +1195,15 @@ impl VsockMuxer {
 #[cfg(test)]
 mod tests {
     use std::io::{Read, Write};
+    use std::net::TcpListener;
     use std::ops::Drop;
-    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
     use std::path::{Path, PathBuf};
 
     use utils::tempfile::TempFile;
 
     use super::super::super::csm::defs as csm_defs;
+    use super::super::forward::{VsockPortAction, VsockPortRule, VsockTcpPortMapping};
     use super::*;
     use crate::virtio::vsock::device::RXQ_INDEX;
     use crate::virtio::vsock::test_utils::TestContext as VsockTestContext;
@@ -791,6 +1220,9 @@ mod tests {
     impl Drop for MuxerTestContext {
         fn drop(&mut self) {
             std::fs::remove_file(self.muxer.host_sock_path.as_str()).unwrap();
+            if let Some(dgram_sock_path) = self.muxer.dgram_sock_path() {
+                std::fs::remove_file(dgram_sock_path).unwrap();
+            }
         }
     }
 
@@ -807,6 +1239,44 @@ mod tests {
 
     impl MuxerTestContext {
         fn new(name: &str) -> Self {
+            Self::with_forwarding(name, VsockForwarding::Unix, VsockPortFilter::default())
+        }
+
+        fn with_forwarding(
+            name: &str,
+            forwarding: VsockForwarding,
+            port_filter: VsockPortFilter,
+        ) -> Self {
+            Self::with_config(name, forwarding, port_filter, None, false)
+        }
+
+        fn with_dgram(name: &str, rate_limiter: RateLimiter) -> Self {
+            Self::with_config(
+                name,
+                VsockForwarding::Unix,
+                VsockPortFilter::default(),
+                Some(rate_limiter),
+                false,
+            )
+        }
+
+        fn with_preserved_connections(name: &str) -> Self {
+            Self::with_config(
+                name,
+                VsockForwarding::Unix,
+                VsockPortFilter::default(),
+                None,
+                true,
+            )
+        }
+
+        fn with_config(
+            name: &str,
+            forwarding: VsockForwarding,
+            port_filter: VsockPortFilter,
+            dgram_rate_limiter: Option<RateLimiter>,
+            preserve_connections: bool,
+        ) -> Self {
             let vsock_test_ctx = VsockTestContext::new();
             let mut handler_ctx = vsock_test_ctx.create_event_handler_context();
             let pkt = VsockPacket::from_rx_virtq_head(
@@ -816,7 +1286,15 @@ mod tests {
             )
             .unwrap();
 
-            let muxer = VsockMuxer::new(PEER_CID, get_file(name)).unwrap();
+            let muxer = VsockMuxer::new(
+                PEER_CID,
+                get_file(name),
+                forwarding,
+                port_filter,
+                dgram_rate_limiter,
+                preserve_connections,
+            )
+            .unwrap();
             Self {
                 _vsock_test_ctx: vsock_test_ctx,
                 pkt,
@@ -888,6 +1366,21 @@ mod tests {
             LocalListener::new(format!("{}_{}", self.muxer.host_sock_path, port))
         }
 
+        fn init_dgram_pkt(
+            &mut self,
+            local_port: u32,
+            peer_port: u32,
+            data: &[u8],
+        ) -> &mut VsockPacket {
+            self.init_data_pkt(local_port, peer_port, data)
+                .set_type(uapi::VSOCK_TYPE_DGRAM)
+                .set_buf_alloc(0)
+        }
+
+        fn create_local_dgram_sock(&self, port: u32) -> LocalDgramSock {
+            LocalDgramSock::new(format!("{}_dgram_{}", self.muxer.host_sock_path, port))
+        }
+
         fn local_connect(&mut self, peer_port: u32) -> (UnixStream, u32) {
             let (init_local_lsn_count, init_conn_lsn_count) = self.count_epoll_listeners();
 
@@ -968,6 +1461,27 @@ mod tests {
         }
     }
 
+    struct LocalDgramSock {
+        path: PathBuf,
+        sock: UnixDatagram,
+    }
+    impl LocalDgramSock {
+        fn new<P: AsRef<Path>>(path: P) -> Self {
+            let path_buf = path.as_ref().to_path_buf();
+            let sock = UnixDatagram::bind(path).unwrap();
+            sock.set_nonblocking(true).unwrap();
+            Self {
+                path: path_buf,
+                sock,
+            }
+        }
+    }
+    impl Drop for LocalDgramSock {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.path).unwrap();
+        }
+    }
+
     #[test]
     fn test_muxer_epoll_listener() {
         let ctx = MuxerTestContext::new("muxer_epoll_listener");
@@ -1116,6 +1630,378 @@ mod tests {
         assert!(!ctx.muxer.has_pending_rx());
     }
 
+    #[test]
+    fn test_peer_connection_denied() {
+        const ALLOWED_PORT: u32 = 1026;
+        const DENIED_PORT: u32 = 1027;
+        const PEER_PORT: u32 = 1025;
+
+        let port_filter = VsockPortFilter {
+            rules: vec![VsockPortRule {
+                action: VsockPortAction::Allow,
+                first_port: ALLOWED_PORT,
+                last_port: None,
+            }],
+            default_action: VsockPortAction::Deny,
+        };
+        let mut ctx = MuxerTestContext::with_forwarding(
+            "peer_connection_denied",
+            VsockForwarding::Unix,
+            port_filter,
+        );
+        let _allowed_listener = ctx.create_local_listener(ALLOWED_PORT);
+        let _denied_listener = ctx.create_local_listener(DENIED_PORT);
+
+        // The connection to a denied port gets reset, even though a host socket is listening.
+        let denied_count = METRICS.vsock.conns_denied.count();
+        ctx.init_pkt(DENIED_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        assert!(ctx.muxer.conn_map.is_empty());
+        assert_eq!(METRICS.vsock.conns_denied.count(), denied_count + 1);
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), DENIED_PORT);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+
+        ctx.init_pkt(ALLOWED_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        assert_eq!(ctx.muxer.conn_map.len(), 1);
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        assert_eq!(ctx.pkt.src_port(), ALLOWED_PORT);
+    }
+
+    #[test]
+    fn test_peer_connection_tcp() {
+        const LOCAL_PORT: u32 = 1026;
+        const UNMAPPED_PORT: u32 = 1027;
+        const PEER_PORT: u32 = 1025;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let forwarding = VsockForwarding::Tcp {
+            port_map: vec![VsockTcpPortMapping {
+                port: LOCAL_PORT,
+                host_address: listener.local_addr().unwrap(),
+            }],
+        };
+        let mut ctx = MuxerTestContext::with_forwarding(
+            "peer_connection_tcp",
+            forwarding,
+            VsockPortFilter::default(),
+        );
+
+        // Ports without a mapping are refused.
+        ctx.init_pkt(UNMAPPED_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), UNMAPPED_PORT);
+
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        let (mut stream, _) = listener.accept().unwrap();
+        // The connection is only confirmed once the host stream is connected.
+        if ctx.muxer.conn_map.is_empty() {
+            assert_eq!(ctx.muxer.pending_connects.len(), 1);
+            assert!(!ctx.muxer.has_pending_rx());
+            ctx.notify_muxer();
+        }
+        assert!(ctx.muxer.pending_connects.is_empty());
+        assert_eq!(ctx.muxer.conn_map.len(), 1);
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+
+        // Test guest -> host data flow.
+        let data = [1, 2, 3, 4];
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &data);
+        ctx.send();
+        let mut buf = vec![0; data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+        assert_eq!(buf.as_slice(), data);
+
+        // Test host -> guest data flow.
+        let data = [5u8, 6, 7, 8];
+        stream.write_all(&data).unwrap();
+        ctx.notify_muxer();
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        let mut buf = vec![];
+        ctx.pkt
+            .write_from_offset_to(
+                &ctx._vsock_test_ctx.mem,
+                0,
+                &mut buf,
+                ctx.pkt.len() as usize,
+            )
+            .unwrap();
+        assert_eq!(&buf, &data);
+    }
+
+    #[test]
+    fn test_peer_connection_tcp_failure() {
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+
+        // Nothing listens at the address anymore.
+        let host_address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let forwarding = VsockForwarding::Tcp {
+            port_map: vec![VsockTcpPortMapping {
+                port: LOCAL_PORT,
+                host_address,
+            }],
+        };
+        let mut ctx = MuxerTestContext::with_forwarding(
+            "peer_connection_tcp_failure",
+            forwarding,
+            VsockPortFilter::default(),
+        );
+
+        // The connection fails either right away, or once the host stream is done connecting.
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        for _ in 0..100 {
+            if ctx.muxer.has_pending_rx() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            ctx.notify_muxer();
+        }
+        assert!(ctx.muxer.pending_connects.is_empty());
+        assert!(ctx.muxer.conn_map.is_empty());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_port(), LOCAL_PORT);
+
+        // The guest can give up on a connection whose host stream is still connecting.
+        let forwarding = VsockForwarding::Tcp {
+            port_map: vec![VsockTcpPortMapping {
+                port: LOCAL_PORT,
+                host_address: "10.255.255.1:80".parse().unwrap(),
+            }],
+        };
+        let mut ctx = MuxerTestContext::with_forwarding(
+            "peer_connection_tcp_pending",
+            forwarding,
+            VsockPortFilter::default(),
+        );
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        // The address may also be unreachable right away, depending on the host routes.
+        if !ctx.muxer.pending_connects.is_empty() {
+            assert!(ctx.muxer.conn_map.is_empty());
+            assert!(!ctx.muxer.has_pending_rx());
+            ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_RST);
+            ctx.send();
+            assert!(ctx.muxer.pending_connects.is_empty());
+            assert!(ctx
+                .muxer
+                .listener_map
+                .values()
+                .all(|listener| !matches!(listener, EpollListener::PendingConnect(_))));
+            assert!(!ctx.muxer.has_pending_rx());
+        }
+    }
+
+    #[test]
+    fn test_muxer_invalid_config() {
+        let forwarding = VsockForwarding::Vsock {
+            cid: PEER_CID as u32,
+        };
+        assert!(matches!(
+            VsockMuxer::new(
+                PEER_CID,
+                get_file("muxer_invalid_config"),
+                forwarding,
+                VsockPortFilter::default(),
+                None,
+                false
+            ),
+            Err(Error::InvalidForwardingCid(_))
+        ));
+
+        let port_filter = VsockPortFilter {
+            rules: vec![VsockPortRule {
+                action: VsockPortAction::Deny,
+                first_port: 1025,
+                last_port: Some(1024),
+            }],
+            default_action: VsockPortAction::Allow,
+        };
+        assert!(matches!(
+            VsockMuxer::new(
+                PEER_CID,
+                get_file("muxer_invalid_config"),
+                VsockForwarding::Unix,
+                port_filter,
+                None,
+                false
+            ),
+            Err(Error::InvalidPortRule(1025))
+        ));
+    }
+
+    #[test]
+    fn test_dgram_disabled() {
+        let mut ctx = MuxerTestContext::new("dgram_disabled");
+        assert!(!ctx.muxer.supports_dgram());
+        assert!(ctx.muxer.dgram_rate_limiter().is_none());
+
+        // Datagrams are dropped, without any RST.
+        ctx.init_dgram_pkt(1025, 1024, &[1, 2, 3]);
+        ctx.send();
+        assert!(!ctx.muxer.has_pending_rx());
+        assert!(ctx.muxer.dgram_sock_path().is_none());
+        assert!(!Path::new(&MuxerDgram::sock_path(&ctx.muxer.host_sock_path)).exists());
+    }
+
+    #[test]
+    fn test_dgram_guest_to_host() {
+        let mut ctx = MuxerTestContext::with_dgram("dgram_guest_to_host", RateLimiter::default());
+        assert!(ctx.muxer.supports_dgram());
+        let local_port = 1025;
+        let peer_port = 1024;
+        let local = ctx.create_local_dgram_sock(local_port);
+
+        let data = [1, 2, 3, 4];
+        ctx.init_dgram_pkt(local_port, peer_port, &data);
+        ctx.send();
+        let mut buf = [0u8; 16];
+        let (len, addr) = local.sock.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &data);
+        assert_eq!(
+            addr.as_pathname().unwrap(),
+            Path::new(&ctx.muxer.dgram_sock_path().unwrap())
+        );
+
+        // Datagrams to unbound ports, to other CIDs, or that aren't RW, are dropped.
+        ctx.init_dgram_pkt(local_port + 1, peer_port, &data);
+        ctx.send();
+        ctx.init_dgram_pkt(local_port, peer_port, &data)
+            .set_dst_cid(PEER_CID + 1);
+        ctx.send();
+        ctx.init_dgram_pkt(local_port, peer_port, &data)
+            .set_op(uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        local.sock.recv_from(&mut buf).unwrap_err();
+        assert!(!ctx.muxer.has_pending_rx());
+    }
+
+    #[test]
+    fn test_dgram_denied() {
+        const ALLOWED_PORT: u32 = 1025;
+        const DENIED_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1024;
+
+        let port_filter = VsockPortFilter {
+            rules: vec![VsockPortRule {
+                action: VsockPortAction::Deny,
+                first_port: DENIED_PORT,
+                last_port: None,
+            }],
+            default_action: VsockPortAction::Allow,
+        };
+        let mut ctx = MuxerTestContext::with_config(
+            "dgram_denied",
+            VsockForwarding::Unix,
+            port_filter,
+            Some(RateLimiter::default()),
+            false,
+        );
+        let allowed = ctx.create_local_dgram_sock(ALLOWED_PORT);
+        let denied = ctx.create_local_dgram_sock(DENIED_PORT);
+
+        // The port filter applies to the datagrams sent by the guest as well.
+        let data = [1, 2, 3, 4];
+        let denied_count = METRICS.vsock.dgram_tx_denied.count();
+        ctx.init_dgram_pkt(DENIED_PORT, PEER_PORT, &data);
+        ctx.send();
+        let mut buf = [0u8; 16];
+        denied.sock.recv_from(&mut buf).unwrap_err();
+        assert_eq!(METRICS.vsock.dgram_tx_denied.count(), denied_count + 1);
+        assert!(!ctx.muxer.has_pending_rx());
+
+        ctx.init_dgram_pkt(ALLOWED_PORT, PEER_PORT, &data);
+        ctx.send();
+        let (len, _) = allowed.sock.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &data);
+    }
+
+    #[test]
+    fn test_dgram_host_to_guest() {
+        let mut ctx = MuxerTestContext::with_dgram("dgram_host_to_guest", RateLimiter::default());
+        let local_port = 1025;
+        let peer_port = 1024;
+        let local = ctx.create_local_dgram_sock(local_port);
+        let muxer_path = ctx.muxer.dgram_sock_path().unwrap();
+        let rx_dropped = METRICS.vsock.dgram_rx_dropped.count();
+
+        local
+            .sock
+            .send_to(format!("SEND {}\nhello", peer_port).as_bytes(), &muxer_path)
+            .unwrap();
+        // Malformed datagrams are dropped.
+        local.sock.send_to(b"SEND\nhello", &muxer_path).unwrap();
+        local.sock.send_to(b"hello", &muxer_path).unwrap();
+        // So are datagrams sent from unexpected addresses.
+        UnixDatagram::unbound()
+            .unwrap()
+            .send_to(b"SEND 1024\nhello", &muxer_path)
+            .unwrap();
+        ctx.notify_muxer();
+        assert_eq!(METRICS.vsock.dgram_rx_dropped.count(), rx_dropped + 3);
+
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert!(ctx.pkt.is_dgram());
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.src_cid(), uapi::VSOCK_HOST_CID);
+        assert_eq!(ctx.pkt.dst_cid(), PEER_CID);
+        assert_eq!(ctx.pkt.src_port(), local_port);
+        assert_eq!(ctx.pkt.dst_port(), peer_port);
+        assert_eq!(ctx.pkt.len(), 5);
+        let mut buf = Vec::new();
+        ctx.pkt
+            .write_from_offset_to(&ctx._vsock_test_ctx.mem, 0, &mut buf, 5)
+            .unwrap();
+        assert_eq!(buf.as_slice(), b"hello");
+
+        assert!(!ctx.muxer.has_pending_rx());
+        assert!(matches!(
+            ctx.muxer.recv_pkt(&mut ctx.pkt, &ctx._vsock_test_ctx.mem),
+            Err(VsockError::NoData)
+        ));
+    }
+
+    #[test]
+    fn test_dgram_rate_limiter() {
+        // Allow a single datagram per second.
+        let rate_limiter = RateLimiter::new(0, 0, 0, 1, 0, 1000).unwrap();
+        let mut ctx = MuxerTestContext::with_dgram("dgram_rate_limiter", rate_limiter);
+        let local_port = 1025;
+        let peer_port = 1024;
+        let local = ctx.create_local_dgram_sock(local_port);
+        let rate_limited = METRICS.vsock.dgram_tx_rate_limited.count();
+
+        ctx.init_dgram_pkt(local_port, peer_port, &[1]);
+        ctx.send();
+        ctx.init_dgram_pkt(local_port, peer_port, &[2]);
+        ctx.send();
+        assert_eq!(
+            METRICS.vsock.dgram_tx_rate_limited.count(),
+            rate_limited + 1
+        );
+        assert!(ctx.muxer.dgram_rate_limiter().unwrap().is_blocked());
+
+        let mut buf = [0u8; 16];
+        assert_eq!(local.sock.recv_from(&mut buf).unwrap().0, 1);
+        assert_eq!(buf[0], 1);
+        local.sock.recv_from(&mut buf).unwrap_err();
+    }
+
     #[test]
     fn test_local_connection() {
         let mut ctx = MuxerTestContext::new("local_connection");
@@ -1524,4 +2410,132 @@ mod tests {
         // Check that the connection was removed.
         assert_eq!(METRICS.vsock.conns_removed.count(), conns_removed + 1);
     }
+
+    #[test]
+    fn test_preserve_connections() {
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+        let local_init_peer_port = 1027;
+
+        // Connections aren't saved unless asked to.
+        {
+            let mut ctx = MuxerTestContext::new("preserve_connections_disabled");
+            let _stream = ctx.local_connect(local_init_peer_port);
+            assert!(!ctx.muxer.preserve_connections());
+            assert!(ctx.muxer.save_connections().is_empty());
+        }
+
+        let mut ctx = MuxerTestContext::with_preserved_connections("preserve_connections");
+        assert!(ctx.muxer.preserve_connections());
+
+        // Set up a guest-initiated connection and a host-initiated one.
+        let mut listener = ctx.create_local_listener(LOCAL_PORT);
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        let _peer_stream = listener.accept();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        let (_local_stream, local_port) = ctx.local_connect(local_init_peer_port);
+        // Connections which aren't established yet aren't saved.
+        let mut pending_stream = UnixStream::connect(ctx.muxer.host_sock_path.clone()).unwrap();
+        ctx.notify_muxer();
+        pending_stream.write_all(b"CONNECT 1028\n").unwrap();
+        ctx.notify_muxer();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_REQUEST);
+        assert_eq!(ctx.muxer.conn_map.len(), 3);
+
+        let mut states = ctx.muxer.save_connections();
+        states.sort_by_key(|state| state.local_init);
+        assert_eq!(states.len(), 2);
+        assert!(!states[0].local_init);
+        assert_eq!(states[0].local_port, LOCAL_PORT);
+        assert!(states[1].local_init);
+        assert_eq!(states[1].local_port, local_port);
+
+        // Restore the connections in another muxer, as if the snapshot had been restored.
+        let mut restored_ctx =
+            MuxerTestContext::with_preserved_connections("preserve_connections_restored");
+        let mut restored_listener = restored_ctx.create_local_listener(LOCAL_PORT);
+        restored_ctx.muxer.restore_connections(&states);
+        assert_eq!(restored_ctx.muxer.conn_map.len(), 2);
+        assert!(!restored_ctx.muxer.has_pending_rx());
+
+        // The guest-initiated connection is forwarded again, and the host is told about it.
+        let mut peer_stream = restored_listener.accept();
+        let mut buf = vec![0u8; 32];
+        let len = peer_stream.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], format!("RESUME {}\n", PEER_PORT).as_bytes());
+        let data = [1, 2, 3, 4];
+        restored_ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &data);
+        restored_ctx.send();
+        let mut buf = vec![0u8; data.len()];
+        peer_stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // The guest can send data to the host-initiated connection before the host resumes it.
+        assert!(restored_ctx.muxer.detached.contains_key(&local_port));
+        assert!(restored_ctx.muxer.local_port_set.contains(&local_port));
+        restored_ctx.init_data_pkt(local_port, local_init_peer_port, &data);
+        restored_ctx.send();
+
+        // Resuming an unknown connection fails.
+        let mut stream = UnixStream::connect(restored_ctx.muxer.host_sock_path.clone()).unwrap();
+        restored_ctx.notify_muxer();
+        stream.write_all(b"RESUME 1024\n").unwrap();
+        restored_ctx.notify_muxer();
+        let (local_lsn_count, _) = restored_ctx.count_epoll_listeners();
+        assert_eq!(local_lsn_count, 0);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        // The host resumes the host-initiated connection, and gets the buffered data.
+        let mut stream = UnixStream::connect(restored_ctx.muxer.host_sock_path.clone()).unwrap();
+        restored_ctx.notify_muxer();
+        stream
+            .write_all(format!("RESUME {}\n", local_port).as_bytes())
+            .unwrap();
+        restored_ctx.notify_muxer();
+        assert!(!restored_ctx.muxer.detached.contains_key(&local_port));
+        let mut buf = vec![0u8; 32];
+        let len = stream.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], format!("OK {}\n", local_port).as_bytes());
+        restored_ctx.notify_muxer();
+        let mut buf = vec![0u8; data.len()];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // Data flows from the host again.
+        stream.write_all(&data).unwrap();
+        restored_ctx.notify_muxer();
+        assert!(restored_ctx.muxer.has_pending_rx());
+        restored_ctx.recv();
+        assert_eq!(restored_ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(restored_ctx.pkt.src_port(), local_port);
+        assert_eq!(restored_ctx.pkt.dst_port(), local_init_peer_port);
+    }
+
+    #[test]
+    fn test_restore_connection_failure() {
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+
+        let mut ctx = MuxerTestContext::with_preserved_connections("restore_connection_failure");
+        let _listener = ctx.create_local_listener(LOCAL_PORT);
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST);
+        ctx.send();
+        ctx.recv();
+        let states = ctx.muxer.save_connections();
+        assert_eq!(states.len(), 1);
+
+        // Nothing listens for the guest-initiated connection anymore, so the guest gets reset.
+        let mut restored_ctx =
+            MuxerTestContext::with_preserved_connections("restore_connection_failure_restored");
+        restored_ctx.muxer.restore_connections(&states);
+        assert!(restored_ctx.muxer.conn_map.is_empty());
+        assert!(restored_ctx.muxer.has_pending_rx());
+        restored_ctx.recv();
+        assert_eq!(restored_ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(restored_ctx.pkt.src_port(), LOCAL_PORT);
+        assert_eq!(restored_ctx.pkt.dst_port(), PEER_PORT);
+    }
 }
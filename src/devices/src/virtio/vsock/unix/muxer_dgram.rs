@@ -0,0 +1,248 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+
+/// `MuxerDgram` implements the datagram side of the vsock muxer, bridging guest-side
+/// `SOCK_DGRAM` vsock sockets to host-side `SOCK_DGRAM` Unix sockets. There are no connections
+/// to keep track of, so datagrams are never retried: they are dropped whenever they can't be
+/// delivered right away.
+///
+/// - A datagram sent by the guest to host port `P` is forwarded, as is, to the Unix socket bound
+///   by the host at `<uds_path>_dgram_<P>`, provided that the datagram rate limiter has enough
+///   budget for it.
+/// - The host sends a datagram to guest port `G` by sending `SEND <G>\n`, followed by the
+///   payload, to the Unix socket bound by the muxer at `<uds_path>_dgram`. The sending socket has
+///   to be bound at `<uds_path>_dgram_<P>`, and the guest sees the datagram coming from host port
+///   `P`. Such datagrams wait in a bounded queue until the guest provides RX buffers.
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+use logger::{debug, warn, IncMetric, METRICS};
+use rate_limiter::{RateLimiter, TokenType};
+use vm_memory::GuestMemoryMmap;
+
+use super::super::defs::{uapi, MAX_PKT_BUF_SIZE};
+use super::super::packet::VsockPacket;
+use super::super::{Result as VsockResult, VsockError};
+use super::{defs, Error, Result};
+
+/// The maximum length of the `SEND <port>\n` header of host datagrams.
+const MAX_HDR_LEN: usize = 16;
+
+/// A datagram sent by the host, waiting to be delivered to the guest.
+struct HostDgram {
+    src_port: u32,
+    dst_port: u32,
+    data: Vec<u8>,
+}
+
+/// The datagram side of the muxer.
+pub struct MuxerDgram {
+    /// The Unix socket, through which datagrams are exchanged with the host.
+    sock: UnixDatagram,
+    /// The prefix of the paths at which the host binds its per-port sockets. I.e.
+    /// "<host socket path>_dgram_".
+    port_path_prefix: String,
+    /// The rate limiter applied to the datagrams sent by the guest.
+    rate_limiter: RateLimiter,
+    /// The datagrams sent by the host, waiting to be delivered to the guest.
+    rxq: VecDeque<HostDgram>,
+    /// Scratch buffer, large enough for any datagram that can be delivered, header included.
+    buf: Vec<u8>,
+}
+
+impl MuxerDgram {
+    const RXQ_SIZE: usize = defs::MUXER_DGRAM_RXQ_SIZE;
+
+    /// Binds the muxer datagram socket, next to the host socket at `host_sock_path`.
+    pub fn new(host_sock_path: &str, rate_limiter: RateLimiter) -> Result<Self> {
+        let sock = UnixDatagram::bind(Self::sock_path(host_sock_path))
+            .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
+            .map_err(Error::UnixBind)?;
+
+        Ok(Self {
+            sock,
+            port_path_prefix: format!("{}_dgram_", host_sock_path),
+            rate_limiter,
+            rxq: VecDeque::with_capacity(Self::RXQ_SIZE),
+            // One more byte than needed, to detect oversized datagrams.
+            buf: vec![0u8; MAX_HDR_LEN + MAX_PKT_BUF_SIZE + 1],
+        })
+    }
+
+    /// Returns the path of the muxer datagram socket, for the host socket at `host_sock_path`.
+    pub fn sock_path(host_sock_path: &str) -> String {
+        format!("{}_dgram", host_sock_path)
+    }
+
+    /// Returns the FD of the muxer datagram socket.
+    pub fn sock_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+
+    /// Returns the FD on which the rate limiter signals that it has been replenished.
+    pub fn rate_limiter_fd(&self) -> RawFd {
+        self.rate_limiter.as_raw_fd()
+    }
+
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// Check if there are host datagrams waiting to be delivered to the guest.
+    pub fn has_pending_rx(&self) -> bool {
+        !self.rxq.is_empty()
+    }
+
+    /// Forward a guest datagram to the host socket bound for its destination port.
+    pub fn send_pkt(&mut self, pkt: &VsockPacket, mem: &GuestMemoryMmap) {
+        let len = pkt.len() as usize;
+
+        if !self.rate_limiter.consume(1, TokenType::Ops) {
+            METRICS.vsock.dgram_tx_rate_limited.inc();
+            return;
+        }
+        if !self.rate_limiter.consume(len as u64, TokenType::Bytes) {
+            // The datagram is dropped, so give back the op.
+            self.rate_limiter.manual_replenish(1, TokenType::Ops);
+            METRICS.vsock.dgram_tx_rate_limited.inc();
+            return;
+        }
+
+        if len > 0 {
+            let mut dst = &mut self.buf[..len];
+            match pkt.write_from_offset_to(mem, 0, &mut dst, len) {
+                Ok(written) if written == len => (),
+                res => {
+                    warn!("vsock: unable to read guest datagram: {:?}", res);
+                    METRICS.vsock.dgram_tx_fails.inc();
+                    return;
+                }
+            }
+        }
+
+        let dst_path = format!("{}{}", self.port_path_prefix, pkt.dst_port());
+        match self.sock.send_to(&self.buf[..len], &dst_path) {
+            Ok(_) => {
+                METRICS.vsock.dgram_tx_count.inc();
+                METRICS.vsock.dgram_tx_bytes.add(len);
+            }
+            Err(err) => {
+                // Most likely, nothing is bound at the destination path.
+                debug!("vsock: unable to send datagram to {}: {}", dst_path, err);
+                METRICS.vsock.dgram_tx_fails.inc();
+            }
+        }
+    }
+
+    /// Fill `pkt` with the oldest host datagram that fits in it, addressed to guest `cid`.
+    pub fn recv_pkt(
+        &mut self,
+        pkt: &mut VsockPacket,
+        mem: &GuestMemoryMmap,
+        cid: u64,
+    ) -> VsockResult<()> {
+        while let Some(dgram) = self.rxq.pop_front() {
+            let len = dgram.data.len();
+            // Datagrams can't be split across packets.
+            if len > pkt.buf_size() {
+                debug!("vsock: dropping {} bytes datagram for the guest", len);
+                METRICS.vsock.dgram_rx_dropped.inc();
+                continue;
+            }
+
+            if len > 0 {
+                pkt.read_at_offset_from(mem, 0, &mut dgram.data.as_slice(), len)?;
+            }
+            pkt.set_op(uapi::VSOCK_OP_RW)
+                .set_type(uapi::VSOCK_TYPE_DGRAM)
+                .set_src_cid(uapi::VSOCK_HOST_CID)
+                .set_dst_cid(cid)
+                .set_src_port(dgram.src_port)
+                .set_dst_port(dgram.dst_port)
+                .set_len(len as u32)
+                .set_flags(0)
+                .set_buf_alloc(0)
+                .set_fwd_cnt(0);
+            METRICS.vsock.dgram_rx_count.inc();
+            METRICS.vsock.dgram_rx_bytes.add(len);
+            return Ok(());
+        }
+
+        Err(VsockError::NoData)
+    }
+
+    /// Read all the datagrams available on the muxer socket, queuing the valid ones for delivery
+    /// to the guest.
+    pub fn read_host_dgrams(&mut self) {
+        loop {
+            let (len, addr) = match self.sock.recv_from(&mut self.buf) {
+                Ok(res) => res,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    warn!("vsock: unable to read host datagram: {}", err);
+                    METRICS.vsock.muxer_event_fails.inc();
+                    break;
+                }
+            };
+
+            match self.parse_host_dgram(len, &addr) {
+                Some(dgram) if self.rxq.len() < Self::RXQ_SIZE => self.rxq.push_back(dgram),
+                _ => {
+                    debug!("vsock: dropping host datagram from {:?}", addr);
+                    METRICS.vsock.dgram_rx_dropped.inc();
+                }
+            }
+        }
+    }
+
+    /// Handle the replenishment of the rate limiter.
+    pub fn process_rate_limiter_event(&mut self) {
+        if let Err(err) = self.rate_limiter.event_handler() {
+            warn!("vsock: datagram rate limiter event failed: {:?}", err);
+            METRICS.vsock.muxer_event_fails.inc();
+        }
+    }
+
+    /// Parse the `len` bytes long datagram in the scratch buffer, sent from `addr`.
+    fn parse_host_dgram(&self, len: usize, addr: &SocketAddr) -> Option<HostDgram> {
+        // The datagram didn't fit in the buffer.
+        if len == self.buf.len() {
+            return None;
+        }
+
+        let src_port = addr
+            .as_pathname()?
+            .to_str()?
+            .strip_prefix(self.port_path_prefix.as_str())?
+            .parse::<u32>()
+            .ok()?;
+
+        let hdr_len = self.buf[..len.min(MAX_HDR_LEN)]
+            .iter()
+            .position(|&byte| byte == b'\n')?;
+        let mut word_iter = std::str::from_utf8(&self.buf[..hdr_len])
+            .ok()?
+            .split_whitespace();
+        if word_iter.next()?.to_lowercase() != "send" {
+            return None;
+        }
+        let dst_port = word_iter.next()?.parse::<u32>().ok()?;
+        if word_iter.next().is_some() {
+            return None;
+        }
+
+        let data = self.buf[hdr_len + 1..len].to_vec();
+        if data.len() > MAX_PKT_BUF_SIZE {
+            return None;
+        }
+
+        Some(HostDgram {
+            src_port,
+            dst_port,
+            data,
+        })
+    }
+}
@@ -0,0 +1,563 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Contains support for parsing client messages and writing server replies of the Dynamic Host
+//! Configuration Protocol (DHCP), for IPv4 over Ethernet.
+//!
+//! Only the fixed format message and the options needed by a minimal server are supported.
+//! Details of the message format can be found at [1], and the options are described at [2].
+//!
+//! [1]: https://tools.ietf.org/html/rfc2131
+//! [2]: https://tools.ietf.org/html/rfc2132
+
+use std::net::Ipv4Addr;
+use std::result::Result;
+
+use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
+
+use super::bytes::{InnerBytes, NetworkBytes, NetworkBytesMut};
+
+/// The UDP port of DHCP servers.
+pub const SERVER_PORT: u16 = 67;
+/// The UDP port of DHCP clients.
+pub const CLIENT_PORT: u16 = 68;
+
+/// The operation of messages sent by clients.
+pub const OP_BOOTREQUEST: u8 = 1;
+/// The operation of messages sent by servers.
+pub const OP_BOOTREPLY: u8 = 2;
+
+const HTYPE_ETHERNET: u8 = 1;
+const MAGIC_COOKIE: u32 = 0x6382_5363;
+const FLAG_BROADCAST: u16 = 0x8000;
+
+const OP_OFFSET: usize = 0;
+const HTYPE_OFFSET: usize = 1;
+const HLEN_OFFSET: usize = 2;
+const XID_OFFSET: usize = 4;
+const FLAGS_OFFSET: usize = 10;
+const CIADDR_OFFSET: usize = 12;
+const YIADDR_OFFSET: usize = 16;
+const SIADDR_OFFSET: usize = 20;
+const GIADDR_OFFSET: usize = 24;
+const CHADDR_OFFSET: usize = 28;
+const MAGIC_COOKIE_OFFSET: usize = 236;
+const OPTIONS_OFFSET: usize = 240;
+
+// Some clients drop replies shorter than a BOOTP message, so they are padded to this length.
+const MIN_MESSAGE_LEN: usize = 300;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_INTERFACE_MTU: u8 = 26;
+const OPTION_REQUESTED_IP_ADDR: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+/// Represents errors which may occur while parsing or writing a message.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The hardware type or address length is not the one of Ethernet.
+    HType,
+    /// The magic cookie which precedes the options is missing.
+    MagicCookie,
+    /// The specified byte sequence is shorter than the fixed part of a message.
+    MessageTooShort,
+    /// An option doesn't fit in a single option field.
+    OptionTooLong,
+    /// The provided slice can't hold the message.
+    SliceTooShort,
+}
+
+/// The DHCP message types, carried by the option of the same name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageType {
+    /// A client looks for servers.
+    Discover = 1,
+    /// A server offers an address.
+    Offer = 2,
+    /// A client requests the offered address, or renews its lease.
+    Request = 3,
+    /// A client found out the address is already in use.
+    Decline = 4,
+    /// A server confirms the lease.
+    Ack = 5,
+    /// A server refuses the requested address.
+    Nak = 6,
+    /// A client gives up its lease.
+    Release = 7,
+    /// A client only asks for configuration parameters.
+    Inform = 8,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => MessageType::Discover,
+            2 => MessageType::Offer,
+            3 => MessageType::Request,
+            4 => MessageType::Decline,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            7 => MessageType::Release,
+            8 => MessageType::Inform,
+            _ => return None,
+        })
+    }
+}
+
+/// The options which can be written on a server reply.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DhcpOption<'a> {
+    /// The DHCP message type.
+    MessageType(MessageType),
+    /// The address of the server which sends the reply.
+    ServerId(Ipv4Addr),
+    /// The lease time, in seconds.
+    LeaseTime(u32),
+    /// The subnet mask of the client.
+    SubnetMask(Ipv4Addr),
+    /// The default gateway of the client.
+    Router(Ipv4Addr),
+    /// The DNS servers, in order of preference.
+    DnsServers(&'a [Ipv4Addr]),
+    /// The MTU of the client interface.
+    InterfaceMtu(u16),
+}
+
+impl<'a> DhcpOption<'a> {
+    // Writes the option at the start of `buf` and returns its length.
+    fn write(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut value = [0u8; u8::MAX as usize];
+        let (code, value_len) = match *self {
+            DhcpOption::MessageType(message_type) => {
+                value[0] = message_type as u8;
+                (OPTION_MESSAGE_TYPE, 1)
+            }
+            DhcpOption::ServerId(addr) => {
+                value[..4].copy_from_slice(&addr.octets());
+                (OPTION_SERVER_ID, 4)
+            }
+            DhcpOption::LeaseTime(seconds) => {
+                value[..4].copy_from_slice(&seconds.to_be_bytes());
+                (OPTION_LEASE_TIME, 4)
+            }
+            DhcpOption::SubnetMask(mask) => {
+                value[..4].copy_from_slice(&mask.octets());
+                (OPTION_SUBNET_MASK, 4)
+            }
+            DhcpOption::Router(addr) => {
+                value[..4].copy_from_slice(&addr.octets());
+                (OPTION_ROUTER, 4)
+            }
+            DhcpOption::DnsServers(addrs) => {
+                if addrs.len() * 4 > value.len() {
+                    return Err(Error::OptionTooLong);
+                }
+                for (chunk, addr) in value.chunks_mut(4).zip(addrs) {
+                    chunk.copy_from_slice(&addr.octets());
+                }
+                (OPTION_DNS_SERVERS, addrs.len() * 4)
+            }
+            DhcpOption::InterfaceMtu(mtu) => {
+                value[..2].copy_from_slice(&mtu.to_be_bytes());
+                (OPTION_INTERFACE_MTU, 2)
+            }
+        };
+
+        let len = 2 + value_len;
+        if buf.len() < len {
+            return Err(Error::SliceTooShort);
+        }
+        buf[0] = code;
+        buf[1] = value_len as u8;
+        buf[2..len].copy_from_slice(&value[..value_len]);
+        Ok(len)
+    }
+}
+
+/// Interprets the inner bytes as a DHCP message.
+pub struct DhcpMessage<'a, T: 'a> {
+    bytes: InnerBytes<'a, T>,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl<'a, T: NetworkBytes> DhcpMessage<'a, T> {
+    /// Interprets `bytes` as a DHCP message without any validity checks.
+    ///
+    /// # Panics
+    ///
+    /// This method does not panic, but further method calls on the resulting object may panic if
+    /// `bytes` contains invalid input.
+    #[inline]
+    pub fn from_bytes_unchecked(bytes: T) -> Self {
+        DhcpMessage {
+            bytes: InnerBytes::new(bytes),
+        }
+    }
+
+    /// Attempts to interpret `bytes` as a DHCP message about an Ethernet interface.
+    ///
+    /// If no error occurs, the accessor methods are safe to call on the result.
+    pub fn from_bytes(bytes: T) -> Result<Self, Error> {
+        if bytes.len() < OPTIONS_OFFSET {
+            return Err(Error::MessageTooShort);
+        }
+
+        let message = DhcpMessage::from_bytes_unchecked(bytes);
+        if message.bytes[HTYPE_OFFSET] != HTYPE_ETHERNET
+            || message.bytes[HLEN_OFFSET] != MAC_ADDR_LEN as u8
+        {
+            return Err(Error::HType);
+        }
+        if message.bytes.ntohl_unchecked(MAGIC_COOKIE_OFFSET) != MAGIC_COOKIE {
+            return Err(Error::MagicCookie);
+        }
+
+        Ok(message)
+    }
+
+    /// Returns the operation of the message.
+    #[inline]
+    pub fn op(&self) -> u8 {
+        self.bytes[OP_OFFSET]
+    }
+
+    /// Returns the transaction ID chosen by the client.
+    #[inline]
+    pub fn xid(&self) -> u32 {
+        self.bytes.ntohl_unchecked(XID_OFFSET)
+    }
+
+    /// Returns `true` if the client asked for the replies to be broadcast.
+    #[inline]
+    pub fn broadcast(&self) -> bool {
+        self.bytes.ntohs_unchecked(FLAGS_OFFSET) & FLAG_BROADCAST != 0
+    }
+
+    /// Returns the current address of the client, set when it's already configured.
+    #[inline]
+    pub fn ciaddr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.bytes.ntohl_unchecked(CIADDR_OFFSET))
+    }
+
+    /// Returns the address assigned to the client by the server.
+    #[inline]
+    pub fn yiaddr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.bytes.ntohl_unchecked(YIADDR_OFFSET))
+    }
+
+    /// Returns the address of the server.
+    #[inline]
+    pub fn siaddr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.bytes.ntohl_unchecked(SIADDR_OFFSET))
+    }
+
+    /// Returns the MAC address of the client.
+    #[inline]
+    pub fn chaddr(&self) -> MacAddr {
+        MacAddr::from_bytes_unchecked(&self.bytes[CHADDR_OFFSET..CHADDR_OFFSET + MAC_ADDR_LEN])
+    }
+
+    /// Returns the value of the first option with the given code, if the message has one.
+    ///
+    /// Malformed options end the search.
+    pub fn option(&self, code: u8) -> Option<&[u8]> {
+        let mut options = &self.bytes[OPTIONS_OFFSET..];
+        loop {
+            match *options {
+                [] | [OPTION_END, ..] => return None,
+                [OPTION_PAD, ..] => options = &options[1..],
+                [option_code, len, ..] => {
+                    let end = 2 + len as usize;
+                    if options.len() < end {
+                        return None;
+                    }
+                    if option_code == code {
+                        return Some(&options[2..end]);
+                    }
+                    options = &options[end..];
+                }
+                [_] => return None,
+            }
+        }
+    }
+
+    /// Returns the DHCP message type, if the message carries a valid one.
+    pub fn message_type(&self) -> Option<MessageType> {
+        match self.option(OPTION_MESSAGE_TYPE)? {
+            [value] => MessageType::from_u8(*value),
+            _ => None,
+        }
+    }
+
+    /// Returns the address requested by the client, if any.
+    pub fn requested_ip_addr(&self) -> Option<Ipv4Addr> {
+        self.ipv4_addr_option(OPTION_REQUESTED_IP_ADDR)
+    }
+
+    /// Returns the address of the server selected by the client, if any.
+    pub fn server_id(&self) -> Option<Ipv4Addr> {
+        self.ipv4_addr_option(OPTION_SERVER_ID)
+    }
+
+    fn ipv4_addr_option(&self, code: u8) -> Option<Ipv4Addr> {
+        match *self.option(code)? {
+            [a, b, c, d] => Some(Ipv4Addr::new(a, b, c, d)),
+            _ => None,
+        }
+    }
+
+    /// Returns the length of the message.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl<'a, T: NetworkBytesMut> DhcpMessage<'a, T> {
+    /// Writes a server reply to `request` in `buf`, assigning `yiaddr` to the client.
+    ///
+    /// The reply carries the given `options`, and is padded to the length of a BOOTP message
+    /// when `buf` allows it. The slice is shrunk to the length of the reply.
+    pub fn write_reply<R: NetworkBytes>(
+        buf: T,
+        request: &DhcpMessage<R>,
+        yiaddr: Ipv4Addr,
+        siaddr: Ipv4Addr,
+        options: &[DhcpOption],
+    ) -> Result<Self, Error> {
+        if buf.len() < OPTIONS_OFFSET + 1 {
+            return Err(Error::SliceTooShort);
+        }
+
+        let mut reply = DhcpMessage::from_bytes_unchecked(buf);
+        for byte in reply.bytes[..OPTIONS_OFFSET].iter_mut() {
+            *byte = 0;
+        }
+        reply.bytes[OP_OFFSET] = OP_BOOTREPLY;
+        reply.bytes[HTYPE_OFFSET] = HTYPE_ETHERNET;
+        reply.bytes[HLEN_OFFSET] = MAC_ADDR_LEN as u8;
+        reply.bytes.htonl_unchecked(XID_OFFSET, request.xid());
+        reply.bytes.htons_unchecked(
+            FLAGS_OFFSET,
+            request.bytes.ntohs_unchecked(FLAGS_OFFSET) & FLAG_BROADCAST,
+        );
+        reply
+            .bytes
+            .htonl_unchecked(CIADDR_OFFSET, u32::from(request.ciaddr()));
+        reply
+            .bytes
+            .htonl_unchecked(YIADDR_OFFSET, u32::from(yiaddr));
+        reply
+            .bytes
+            .htonl_unchecked(SIADDR_OFFSET, u32::from(siaddr));
+        reply
+            .bytes
+            .htonl_unchecked(GIADDR_OFFSET, request.bytes.ntohl_unchecked(GIADDR_OFFSET));
+        reply.bytes[CHADDR_OFFSET..CHADDR_OFFSET + MAC_ADDR_LEN]
+            .copy_from_slice(&request.bytes[CHADDR_OFFSET..CHADDR_OFFSET + MAC_ADDR_LEN]);
+        reply
+            .bytes
+            .htonl_unchecked(MAGIC_COOKIE_OFFSET, MAGIC_COOKIE);
+
+        let mut len = OPTIONS_OFFSET;
+        for option in options {
+            len += option.write(&mut reply.bytes[len..])?;
+        }
+        if reply.bytes.len() <= len {
+            return Err(Error::SliceTooShort);
+        }
+        reply.bytes[len] = OPTION_END;
+        len += 1;
+
+        let padded_len = std::cmp::min(std::cmp::max(len, MIN_MESSAGE_LEN), reply.bytes.len());
+        for byte in reply.bytes[len..padded_len].iter_mut() {
+            *byte = OPTION_PAD;
+        }
+        reply.bytes.shrink_unchecked(padded_len);
+
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use super::*;
+
+    impl<'a, T: NetworkBytes> fmt::Debug for DhcpMessage<'a, T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "(DHCP message)")
+        }
+    }
+
+    // Builds a client request with the given options, which are written as raw bytes.
+    fn write_request(buf: &mut [u8], options: &[u8]) -> usize {
+        for byte in buf[..OPTIONS_OFFSET].iter_mut() {
+            *byte = 0;
+        }
+        buf[OP_OFFSET] = OP_BOOTREQUEST;
+        buf[HTYPE_OFFSET] = HTYPE_ETHERNET;
+        buf[HLEN_OFFSET] = MAC_ADDR_LEN as u8;
+        buf[XID_OFFSET..XID_OFFSET + 4].copy_from_slice(&0x1234_5678u32.to_be_bytes());
+        buf[FLAGS_OFFSET..FLAGS_OFFSET + 2].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+        buf[CHADDR_OFFSET..CHADDR_OFFSET + MAC_ADDR_LEN]
+            .copy_from_slice(&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+        buf[MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf[OPTIONS_OFFSET..OPTIONS_OFFSET + options.len()].copy_from_slice(options);
+        OPTIONS_OFFSET + options.len()
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let mut buf = [0u8; 400];
+
+        assert_eq!(
+            DhcpMessage::from_bytes(&buf[..OPTIONS_OFFSET - 1]).unwrap_err(),
+            Error::MessageTooShort
+        );
+
+        let len = write_request(&mut buf, &[OPTION_MESSAGE_TYPE, 1, 1, OPTION_END]);
+        buf[HLEN_OFFSET] = 8;
+        assert_eq!(
+            DhcpMessage::from_bytes(&buf[..len]).unwrap_err(),
+            Error::HType
+        );
+        buf[HLEN_OFFSET] = MAC_ADDR_LEN as u8;
+        buf[MAGIC_COOKIE_OFFSET] = 0;
+        assert_eq!(
+            DhcpMessage::from_bytes(&buf[..len]).unwrap_err(),
+            Error::MagicCookie
+        );
+
+        let len = write_request(
+            &mut buf,
+            &[
+                OPTION_PAD,
+                OPTION_MESSAGE_TYPE,
+                1,
+                3,
+                OPTION_REQUESTED_IP_ADDR,
+                4,
+                10,
+                0,
+                2,
+                15,
+                OPTION_END,
+            ],
+        );
+        let request = DhcpMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(request.op(), OP_BOOTREQUEST);
+        assert_eq!(request.xid(), 0x1234_5678);
+        assert!(request.broadcast());
+        assert_eq!(request.ciaddr(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(
+            request.chaddr(),
+            MacAddr::parse_str("12:34:56:78:9a:bc").unwrap()
+        );
+        assert_eq!(request.message_type(), Some(MessageType::Request));
+        assert_eq!(
+            request.requested_ip_addr(),
+            Some(Ipv4Addr::new(10, 0, 2, 15))
+        );
+        assert_eq!(request.server_id(), None);
+    }
+
+    #[test]
+    fn test_malformed_options() {
+        let mut buf = [0u8; 400];
+
+        // The option length goes past the end of the message.
+        let len = write_request(&mut buf, &[OPTION_MESSAGE_TYPE, 2, 1]);
+        let request = DhcpMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(request.message_type(), None);
+
+        // Invalid message type.
+        let len = write_request(&mut buf, &[OPTION_MESSAGE_TYPE, 1, 9, OPTION_END]);
+        let request = DhcpMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(request.message_type(), None);
+
+        // Options after the end option are ignored.
+        let len = write_request(&mut buf, &[OPTION_END, OPTION_MESSAGE_TYPE, 1, 1]);
+        let request = DhcpMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(request.message_type(), None);
+
+        // Wrong address length.
+        let len = write_request(&mut buf, &[OPTION_SERVER_ID, 3, 10, 0, 2, OPTION_END]);
+        let request = DhcpMessage::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(request.server_id(), None);
+    }
+
+    #[test]
+    fn test_write_reply() {
+        let mut request_buf = [0u8; 400];
+        let len = write_request(&mut request_buf, &[OPTION_MESSAGE_TYPE, 1, 1, OPTION_END]);
+        let request = DhcpMessage::from_bytes(&request_buf[..len]).unwrap();
+
+        let yiaddr = Ipv4Addr::new(10, 0, 2, 15);
+        let server = Ipv4Addr::new(10, 0, 2, 2);
+        let dns_servers = [Ipv4Addr::new(10, 0, 2, 3), Ipv4Addr::new(1, 1, 1, 1)];
+        let options = [
+            DhcpOption::MessageType(MessageType::Offer),
+            DhcpOption::ServerId(server),
+            DhcpOption::LeaseTime(86400),
+            DhcpOption::SubnetMask(Ipv4Addr::new(255, 255, 255, 0)),
+            DhcpOption::Router(server),
+            DhcpOption::DnsServers(&dns_servers),
+            DhcpOption::InterfaceMtu(1500),
+        ];
+
+        // Too short for the options.
+        let mut buf = [0u8; OPTIONS_OFFSET + 10];
+        assert_eq!(
+            DhcpMessage::write_reply(buf.as_mut(), &request, yiaddr, server, &options).unwrap_err(),
+            Error::SliceTooShort
+        );
+
+        let mut buf = [0xffu8; 1000];
+        let reply =
+            DhcpMessage::write_reply(buf.as_mut(), &request, yiaddr, server, &options).unwrap();
+        assert_eq!(reply.len(), MIN_MESSAGE_LEN);
+        assert_eq!(reply.op(), OP_BOOTREPLY);
+        assert_eq!(reply.xid(), request.xid());
+        assert!(reply.broadcast());
+        assert_eq!(reply.yiaddr(), yiaddr);
+        assert_eq!(reply.siaddr(), server);
+        assert_eq!(reply.chaddr(), request.chaddr());
+        assert_eq!(reply.message_type(), Some(MessageType::Offer));
+        assert_eq!(reply.server_id(), Some(server));
+        assert_eq!(
+            reply.option(OPTION_LEASE_TIME),
+            Some(&[0, 1, 0x51, 0x80][..])
+        );
+        assert_eq!(reply.option(OPTION_ROUTER), Some(&[10, 0, 2, 2][..]));
+        assert_eq!(
+            reply.option(OPTION_DNS_SERVERS),
+            Some(&[10, 0, 2, 3, 1, 1, 1, 1][..])
+        );
+        assert_eq!(reply.option(OPTION_INTERFACE_MTU), Some(&[5, 220][..]));
+
+        // The reply can be parsed back.
+        let len = reply.len();
+        assert!(DhcpMessage::from_bytes(&buf[..len]).is_ok());
+
+        // Padded up to the end of the buffer when it's too short for a BOOTP message.
+        let mut buf = [0u8; OPTIONS_OFFSET + 8];
+        let reply = DhcpMessage::write_reply(
+            buf.as_mut(),
+            &request,
+            yiaddr,
+            server,
+            &[DhcpOption::MessageType(MessageType::Nak)],
+        )
+        .unwrap();
+        assert_eq!(reply.len(), OPTIONS_OFFSET + 8);
+        assert_eq!(reply.message_type(), Some(MessageType::Nak));
+    }
+}
@@ -0,0 +1,501 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Contains support for parsing and writing ICMPv6 messages, with a focus on the neighbor
+//! solicitations and advertisements used by the Neighbor Discovery Protocol (NDP), which replaces
+//! ARP for IPv6.
+//!
+//! The layout of NDP messages can be found in [RFC 4861].
+//!
+//! [RFC 4861]: https://tools.ietf.org/html/rfc4861#section-4.3
+use std::convert::From;
+use std::net::Ipv6Addr;
+use std::result::Result;
+
+use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
+
+use super::bytes::{InnerBytes, NetworkBytes, NetworkBytesMut};
+use crate::pdu::ChecksumProto;
+
+const TYPE_OFFSET: usize = 0;
+const CODE_OFFSET: usize = 1;
+const CHECKSUM_OFFSET: usize = 2;
+const BODY_OFFSET: usize = 4;
+
+// The following constants are specific to neighbor solicitations/advertisements.
+const NDP_FLAGS_OFFSET: usize = 4;
+const NDP_TARGET_OFFSET: usize = 8;
+const NDP_OPTIONS_OFFSET: usize = 24;
+
+const IPV6_ADDR_LEN: usize = 16;
+
+// NDP option lengths are expressed in units of 8 octets.
+const NDP_OPTION_LEN_UNIT: usize = 8;
+
+/// ICMPv6 neighbor solicitation message type.
+pub const TYPE_NEIGHBOR_SOLICITATION: u8 = 135;
+/// ICMPv6 neighbor advertisement message type.
+pub const TYPE_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+/// Neighbor advertisement flag set when the sender is a router.
+pub const FLAG_ROUTER: u8 = 0x80;
+/// Neighbor advertisement flag set when the advertisement answers a solicitation.
+pub const FLAG_SOLICITED: u8 = 0x40;
+/// Neighbor advertisement flag set when the advertisement should override cached entries.
+pub const FLAG_OVERRIDE: u8 = 0x20;
+
+/// NDP option carrying the link-layer address of the sender.
+pub const OPTION_SOURCE_LINK_LAYER_ADDR: u8 = 1;
+/// NDP option carrying the link-layer address of the target.
+pub const OPTION_TARGET_LINK_LAYER_ADDR: u8 = 2;
+
+/// NDP messages must be sent with this hop limit, which allows receivers to discard those that
+/// have been forwarded by a router.
+pub const NDP_HOP_LIMIT: u8 = 255;
+
+/// The length of a neighbor solicitation or advertisement carrying a link-layer address option.
+pub const NDP_MESSAGE_LEN: usize = NDP_OPTIONS_OFFSET + NDP_OPTION_LEN_UNIT;
+
+/// Describes the errors which may occur while handling ICMPv6 messages.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The checksum is invalid.
+    Checksum,
+    /// The code is invalid for the message type.
+    Code,
+    /// The message type is not the expected one.
+    MessageType,
+    /// An NDP option has an invalid length.
+    OptionLen,
+    /// The length of the given slice is too short for the message.
+    SliceTooShort,
+}
+
+/// Interprets the inner bytes as an ICMPv6 message.
+pub struct Icmpv6Message<'a, T: 'a> {
+    bytes: InnerBytes<'a, T>,
+}
+
+#[allow(clippy::len_without_is_empty)]
+impl<'a, T: NetworkBytes> Icmpv6Message<'a, T> {
+    /// Interprets `bytes` as an ICMPv6 message without any validity checks.
+    ///
+    /// # Panics
+    ///
+    /// This method does not panic, but further method calls on the resulting object may panic if
+    /// `bytes` contains invalid input.
+    #[inline]
+    pub fn from_bytes_unchecked(bytes: T) -> Self {
+        Icmpv6Message {
+            bytes: InnerBytes::new(bytes),
+        }
+    }
+
+    /// Attempts to interpret `bytes` as an ICMPv6 message.
+    ///
+    /// The `verify_checksum` parameter must contain the source and destination addresses from the
+    /// enclosing IPv6 packet if the checksum must be validated.
+    #[inline]
+    pub fn from_bytes(
+        bytes: T,
+        verify_checksum: Option<(Ipv6Addr, Ipv6Addr)>,
+    ) -> Result<Self, Error> {
+        if bytes.len() < BODY_OFFSET {
+            return Err(Error::SliceTooShort);
+        }
+
+        let message = Self::from_bytes_unchecked(bytes);
+
+        if let Some((src_addr, dst_addr)) = verify_checksum {
+            if message.compute_checksum(src_addr, dst_addr) != 0 {
+                return Err(Error::Checksum);
+            }
+        }
+
+        Ok(message)
+    }
+
+    /// Attempts to interpret `bytes` as a neighbor solicitation, checking the message type, the
+    /// code, the length and the options, as well as the checksum when `verify_checksum` holds the
+    /// addresses from the enclosing IPv6 packet.
+    pub fn neighbor_solicitation_from_bytes(
+        bytes: T,
+        verify_checksum: Option<(Ipv6Addr, Ipv6Addr)>,
+    ) -> Result<Self, Error> {
+        let message = Self::from_bytes(bytes, verify_checksum)?;
+
+        if message.message_type() != TYPE_NEIGHBOR_SOLICITATION {
+            return Err(Error::MessageType);
+        }
+
+        if message.code() != 0 {
+            return Err(Error::Code);
+        }
+
+        if message.len() < NDP_OPTIONS_OFFSET {
+            return Err(Error::SliceTooShort);
+        }
+
+        // Walk the options once, so malformed ones are caught here.
+        message.link_layer_address_option(OPTION_SOURCE_LINK_LAYER_ADDR)?;
+
+        Ok(message)
+    }
+
+    /// Returns the message type.
+    #[inline]
+    pub fn message_type(&self) -> u8 {
+        self.bytes[TYPE_OFFSET]
+    }
+
+    /// Returns the message code.
+    #[inline]
+    pub fn code(&self) -> u8 {
+        self.bytes[CODE_OFFSET]
+    }
+
+    /// Returns the checksum.
+    #[inline]
+    pub fn checksum(&self) -> u16 {
+        self.bytes.ntohs_unchecked(CHECKSUM_OFFSET)
+    }
+
+    /// Returns the flags of a neighbor advertisement.
+    ///
+    /// # Panics
+    ///
+    /// This method may panic if the message is not a neighbor solicitation or advertisement.
+    #[inline]
+    pub fn ndp_flags(&self) -> u8 {
+        self.bytes[NDP_FLAGS_OFFSET]
+    }
+
+    /// Returns the target address of a neighbor solicitation or advertisement.
+    ///
+    /// # Panics
+    ///
+    /// This method may panic if the message is not a neighbor solicitation or advertisement.
+    #[inline]
+    pub fn target_address(&self) -> Ipv6Addr {
+        let mut octets = [0u8; IPV6_ADDR_LEN];
+        octets.copy_from_slice(&self.bytes[NDP_TARGET_OFFSET..NDP_OPTIONS_OFFSET]);
+        Ipv6Addr::from(octets)
+    }
+
+    /// Looks for a link-layer address option of the given type among the options of a neighbor
+    /// solicitation or advertisement, and returns the address it carries, if any.
+    ///
+    /// # Panics
+    ///
+    /// This method may panic if the message is not a neighbor solicitation or advertisement.
+    pub fn link_layer_address_option(&self, option_type: u8) -> Result<Option<MacAddr>, Error> {
+        let mut i = NDP_OPTIONS_OFFSET;
+        let mut found = None;
+
+        while i < self.len() {
+            if i + 2 > self.len() {
+                return Err(Error::OptionLen);
+            }
+
+            let option_len = self.bytes[i + 1] as usize * NDP_OPTION_LEN_UNIT;
+            if option_len == 0 || i + option_len > self.len() {
+                return Err(Error::OptionLen);
+            }
+
+            if self.bytes[i] == option_type && found.is_none() {
+                if option_len < 2 + MAC_ADDR_LEN {
+                    return Err(Error::OptionLen);
+                }
+                found = Some(MacAddr::from_bytes_unchecked(
+                    &self.bytes[i + 2..i + 2 + MAC_ADDR_LEN],
+                ));
+            }
+
+            i += option_len;
+        }
+
+        Ok(found)
+    }
+
+    /// Computes the ICMPv6 checksum of the message, which covers an IPv6 pseudo-header as well.
+    #[inline]
+    pub fn compute_checksum(&self, src_addr: Ipv6Addr, dst_addr: Ipv6Addr) -> u16 {
+        crate::pdu::compute_checksum(
+            &self.bytes,
+            src_addr.into(),
+            dst_addr.into(),
+            ChecksumProto::Icmpv6,
+        )
+    }
+
+    /// Returns the length of the message.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl<'a, T: NetworkBytesMut> Icmpv6Message<'a, T> {
+    /// Writes a neighbor solicitation for `target` to `buf`, carrying `source_mac` as the source
+    /// link-layer address option.
+    pub fn write_neighbor_solicitation(
+        buf: T,
+        target: Ipv6Addr,
+        source_mac: MacAddr,
+        src_addr: Ipv6Addr,
+        dst_addr: Ipv6Addr,
+    ) -> Result<Self, Error> {
+        Self::write_ndp_message(
+            buf,
+            TYPE_NEIGHBOR_SOLICITATION,
+            0,
+            target,
+            OPTION_SOURCE_LINK_LAYER_ADDR,
+            source_mac,
+            src_addr,
+            dst_addr,
+        )
+    }
+
+    /// Writes a neighbor advertisement for `target` to `buf`, carrying `target_mac` as the target
+    /// link-layer address option.
+    pub fn write_neighbor_advertisement(
+        buf: T,
+        target: Ipv6Addr,
+        target_mac: MacAddr,
+        flags: u8,
+        src_addr: Ipv6Addr,
+        dst_addr: Ipv6Addr,
+    ) -> Result<Self, Error> {
+        Self::write_ndp_message(
+            buf,
+            TYPE_NEIGHBOR_ADVERTISEMENT,
+            flags,
+            target,
+            OPTION_TARGET_LINK_LAYER_ADDR,
+            target_mac,
+            src_addr,
+            dst_addr,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write_ndp_message(
+        buf: T,
+        message_type: u8,
+        flags: u8,
+        target: Ipv6Addr,
+        option_type: u8,
+        mac: MacAddr,
+        src_addr: Ipv6Addr,
+        dst_addr: Ipv6Addr,
+    ) -> Result<Self, Error> {
+        if buf.len() < NDP_MESSAGE_LEN {
+            return Err(Error::SliceTooShort);
+        }
+
+        let mut message = Self::from_bytes_unchecked(buf);
+        message.bytes.shrink_unchecked(NDP_MESSAGE_LEN);
+
+        message
+            .set_message_type(message_type)
+            .set_code(0)
+            .set_checksum(0)
+            .set_target_address(target);
+
+        // The flags take up the first bits of a 32-bit field which is otherwise reserved.
+        message.bytes[NDP_FLAGS_OFFSET..NDP_TARGET_OFFSET].copy_from_slice(&[flags, 0, 0, 0]);
+
+        let option = &mut message.bytes[NDP_OPTIONS_OFFSET..NDP_MESSAGE_LEN];
+        option[0] = option_type;
+        option[1] = 1;
+        option[2..].copy_from_slice(mac.get_bytes());
+
+        let checksum = message.compute_checksum(src_addr, dst_addr);
+        message.set_checksum(checksum);
+
+        Ok(message)
+    }
+
+    /// Sets the message type.
+    #[inline]
+    pub fn set_message_type(&mut self, value: u8) -> &mut Self {
+        self.bytes[TYPE_OFFSET] = value;
+        self
+    }
+
+    /// Sets the message code.
+    #[inline]
+    pub fn set_code(&mut self, value: u8) -> &mut Self {
+        self.bytes[CODE_OFFSET] = value;
+        self
+    }
+
+    /// Sets the checksum.
+    #[inline]
+    pub fn set_checksum(&mut self, value: u16) -> &mut Self {
+        self.bytes.htons_unchecked(CHECKSUM_OFFSET, value);
+        self
+    }
+
+    /// Sets the target address of a neighbor solicitation or advertisement.
+    ///
+    /// # Panics
+    ///
+    /// This method may panic if the message is not a neighbor solicitation or advertisement.
+    #[inline]
+    pub fn set_target_address(&mut self, addr: Ipv6Addr) -> &mut Self {
+        self.bytes[NDP_TARGET_OFFSET..NDP_OPTIONS_OFFSET].copy_from_slice(&addr.octets());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+    use std::str::FromStr;
+
+    use super::*;
+
+    impl<'a, T: NetworkBytes> fmt::Debug for Icmpv6Message<'a, T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "(ICMPv6 message)")
+        }
+    }
+
+    #[test]
+    fn test_neighbor_solicitation() {
+        let mut buf = [0u8; 100];
+        let src_addr = Ipv6Addr::from_str("fe80::2").unwrap();
+        let dst_addr = Ipv6Addr::from_str("ff02::1:ff00:1").unwrap();
+        let target = Ipv6Addr::from_str("fe80::1").unwrap();
+        let mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+
+        let len = Icmpv6Message::write_neighbor_solicitation(
+            buf.as_mut(),
+            target,
+            mac,
+            src_addr,
+            dst_addr,
+        )
+        .unwrap()
+        .len();
+        assert_eq!(len, NDP_MESSAGE_LEN);
+
+        let ns = Icmpv6Message::neighbor_solicitation_from_bytes(
+            &buf[..len],
+            Some((src_addr, dst_addr)),
+        )
+        .unwrap();
+        assert_eq!(ns.message_type(), TYPE_NEIGHBOR_SOLICITATION);
+        assert_eq!(ns.code(), 0);
+        assert_eq!(ns.target_address(), target);
+        assert_eq!(
+            ns.link_layer_address_option(OPTION_SOURCE_LINK_LAYER_ADDR),
+            Ok(Some(mac))
+        );
+        assert_eq!(
+            ns.link_layer_address_option(OPTION_TARGET_LINK_LAYER_ADDR),
+            Ok(None)
+        );
+
+        // Wrong pseudo-header addresses.
+        assert_eq!(
+            Icmpv6Message::neighbor_solicitation_from_bytes(
+                &buf[..len],
+                Some((src_addr, src_addr))
+            )
+            .unwrap_err(),
+            Error::Checksum
+        );
+
+        // A message without options is fine.
+        assert!(
+            Icmpv6Message::neighbor_solicitation_from_bytes(&buf[..NDP_OPTIONS_OFFSET], None)
+                .is_ok()
+        );
+
+        // Truncated message.
+        assert_eq!(
+            Icmpv6Message::neighbor_solicitation_from_bytes(&buf[..NDP_OPTIONS_OFFSET - 1], None)
+                .unwrap_err(),
+            Error::SliceTooShort
+        );
+        assert_eq!(
+            Icmpv6Message::from_bytes(&buf[..BODY_OFFSET - 1], None).unwrap_err(),
+            Error::SliceTooShort
+        );
+
+        // Bad option length.
+        buf[NDP_OPTIONS_OFFSET + 1] = 0;
+        assert_eq!(
+            Icmpv6Message::neighbor_solicitation_from_bytes(&buf[..len], None).unwrap_err(),
+            Error::OptionLen
+        );
+        buf[NDP_OPTIONS_OFFSET + 1] = 2;
+        assert_eq!(
+            Icmpv6Message::neighbor_solicitation_from_bytes(&buf[..len], None).unwrap_err(),
+            Error::OptionLen
+        );
+        buf[NDP_OPTIONS_OFFSET + 1] = 1;
+
+        // Bad code.
+        Icmpv6Message::from_bytes_unchecked(buf.as_mut()).set_code(1);
+        assert_eq!(
+            Icmpv6Message::neighbor_solicitation_from_bytes(&buf[..len], None).unwrap_err(),
+            Error::Code
+        );
+
+        // Not a solicitation.
+        Icmpv6Message::from_bytes_unchecked(buf.as_mut())
+            .set_code(0)
+            .set_message_type(TYPE_NEIGHBOR_ADVERTISEMENT);
+        assert_eq!(
+            Icmpv6Message::neighbor_solicitation_from_bytes(&buf[..len], None).unwrap_err(),
+            Error::MessageType
+        );
+    }
+
+    #[test]
+    fn test_neighbor_advertisement() {
+        let mut buf = [0u8; 100];
+        let src_addr = Ipv6Addr::from_str("fe80::1").unwrap();
+        let dst_addr = Ipv6Addr::from_str("fe80::2").unwrap();
+        let mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+
+        let na = Icmpv6Message::write_neighbor_advertisement(
+            buf.as_mut(),
+            src_addr,
+            mac,
+            FLAG_SOLICITED | FLAG_OVERRIDE,
+            src_addr,
+            dst_addr,
+        )
+        .unwrap();
+
+        assert_eq!(na.len(), NDP_MESSAGE_LEN);
+        assert_eq!(na.message_type(), TYPE_NEIGHBOR_ADVERTISEMENT);
+        assert_eq!(na.code(), 0);
+        assert_eq!(na.ndp_flags(), FLAG_SOLICITED | FLAG_OVERRIDE);
+        assert_eq!(na.target_address(), src_addr);
+        assert_eq!(
+            na.link_layer_address_option(OPTION_TARGET_LINK_LAYER_ADDR),
+            Ok(Some(mac))
+        );
+        assert_eq!(na.compute_checksum(src_addr, dst_addr), 0);
+
+        let mut small_buf = [0u8; NDP_MESSAGE_LEN - 1];
+        assert_eq!(
+            Icmpv6Message::write_neighbor_advertisement(
+                small_buf.as_mut(),
+                src_addr,
+                mac,
+                0,
+                src_addr,
+                dst_addr,
+            )
+            .unwrap_err(),
+            Error::SliceTooShort
+        );
+    }
+}
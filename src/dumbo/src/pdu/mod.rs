@@ -7,15 +7,19 @@
 //! protocol. Ethernet frames, IP packets, and TCP segments are all examples of protocol data
 //! units.
 
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 
 use crate::pdu::bytes::NetworkBytes;
 use crate::pdu::ipv4::{PROTOCOL_TCP, PROTOCOL_UDP};
+use crate::pdu::ipv6::PROTOCOL_ICMPV6;
 
 pub mod arp;
 pub mod bytes;
+pub mod dhcp;
 pub mod ethernet;
+pub mod icmpv6;
 pub mod ipv4;
+pub mod ipv6;
 pub mod tcp;
 pub mod udp;
 
@@ -55,17 +59,21 @@ impl<T> Incomplete<T> {
 enum ChecksumProto {
     Tcp = PROTOCOL_TCP,
     Udp = PROTOCOL_UDP,
+    Icmpv6 = PROTOCOL_ICMPV6,
 }
 
 /// Computes the checksum of a TCP/UDP packet. Since both protocols use
-/// the same algorithm to compute the checksum.
+/// the same algorithm to compute the checksum. ICMPv6 messages also use it.
 ///
 /// # Arguments
-/// * `bytes` - Raw bytes of a TCP packet or a UDP datagram
-/// * `src_addr` - IPv4 source address
-/// * `dst_addr` - IPv4 destination address
+/// * `bytes` - Raw bytes of a TCP packet, a UDP datagram or an ICMPv6 message
+/// * `src_addr` - IPv4 or IPv6 source address
+/// * `dst_addr` - IPv4 or IPv6 destination address, of the same family as `src_addr`
 /// * `protocol` - **must** be either `PROTOCOL_TCP` or `PROTOCOL_UDP` defined in
-/// `ipv4` module
+/// `ipv4` module, or `PROTOCOL_ICMPV6` defined in `ipv6` module
+///
+/// The IPv6 pseudo-header carries a 32-bit length, but summing it as a single value yields the
+/// same result as summing its two halves, since the sum is folded afterwards anyway.
 ///
 /// More details about TCP checksum computation can be found [here].
 ///
@@ -73,21 +81,28 @@ enum ChecksumProto {
 #[inline]
 fn compute_checksum<T: NetworkBytes>(
     bytes: &T,
-    src_addr: Ipv4Addr,
-    dst_addr: Ipv4Addr,
+    src_addr: IpAddr,
+    dst_addr: IpAddr,
     protocol: ChecksumProto,
 ) -> u16 {
     // TODO: Is u32 enough to prevent overflow for the code in this function? I think so, but it
     // would be nice to double-check.
     let mut sum = 0u32;
 
-    let a = u32::from(src_addr);
-    sum += a & 0xffff;
-    sum += a >> 16;
-
-    let b = u32::from(dst_addr);
-    sum += b & 0xffff;
-    sum += b >> 16;
+    for addr in [src_addr, dst_addr].iter() {
+        match addr {
+            IpAddr::V4(addr) => {
+                let a = u32::from(*addr);
+                sum += a & 0xffff;
+                sum += a >> 16;
+            }
+            IpAddr::V6(addr) => {
+                for segment in addr.segments().iter() {
+                    sum += u32::from(*segment);
+                }
+            }
+        }
+    }
 
     let len = bytes.len();
     sum += protocol as u32;
@@ -8,7 +8,7 @@
 //! [Here]: https://en.wikipedia.org/wiki/Transmission_Control_Protocol#TCP_segment_structure
 
 use std::cmp::min;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::num::NonZeroU16;
 use std::result::Result;
 
@@ -208,7 +208,23 @@ impl<'a, T: NetworkBytes> TcpSegment<'a, T> {
     ///
     /// [here]: https://en.wikipedia.org/wiki/Transmission_Control_Protocol#Checksum_computation
     pub fn compute_checksum(&self, src_addr: Ipv4Addr, dst_addr: Ipv4Addr) -> u16 {
-        crate::pdu::compute_checksum(&self.bytes, src_addr, dst_addr, ChecksumProto::Tcp)
+        crate::pdu::compute_checksum(
+            &self.bytes,
+            src_addr.into(),
+            dst_addr.into(),
+            ChecksumProto::Tcp,
+        )
+    }
+
+    /// Computes the TCP checksum of a segment carried by an IPv6 packet, which only differs from
+    /// the IPv4 one in the layout of the pseudo-header.
+    pub fn compute_checksum_ipv6(&self, src_addr: Ipv6Addr, dst_addr: Ipv6Addr) -> u16 {
+        crate::pdu::compute_checksum(
+            &self.bytes,
+            src_addr.into(),
+            dst_addr.into(),
+            ChecksumProto::Tcp,
+        )
     }
 
     /// Parses TCP header options (only `MSS` is supported for now).
@@ -565,6 +581,28 @@ impl<'a, T: NetworkBytesMut> Incomplete<TcpSegment<'a, T>> {
         }
         self.inner
     }
+
+    /// Same as [`finalize`], but the checksum is computed using the addresses from the enclosing
+    /// IPv6 packet.
+    ///
+    /// [`finalize`]: #method.finalize
+    #[inline]
+    pub fn finalize_ipv6(
+        mut self,
+        src_port: u16,
+        dst_port: u16,
+        compute_checksum: Option<(Ipv6Addr, Ipv6Addr)>,
+    ) -> TcpSegment<'a, T> {
+        self.inner.set_source_port(src_port);
+        self.inner.set_destination_port(dst_port);
+        if let Some((src_addr, dst_addr)) = compute_checksum {
+            // Set this to 0 first.
+            self.inner.set_checksum(0);
+            let checksum = self.inner.compute_checksum_ipv6(src_addr, dst_addr);
+            self.inner.set_checksum(checksum);
+        }
+        self.inner
+    }
 }
 
 #[cfg(test)]
@@ -807,4 +845,31 @@ mod tests {
             Error::MssRemaining
         );
     }
+
+    #[test]
+    fn test_ipv6_checksum() {
+        let mut a = [0u8; 100];
+        let src_addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+        let dst_addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+
+        let mut segment = TcpSegment::write_incomplete_segment::<[u8]>(
+            a.as_mut(),
+            1,
+            2,
+            Flags::SYN,
+            1000,
+            None,
+            0,
+            None,
+        )
+        .unwrap()
+        .finalize_ipv6(1234, 80, Some((src_addr, dst_addr)));
+
+        // Value obtained independently, by summing the IPv6 pseudo-header and segment words.
+        assert_eq!(segment.checksum(), 0xab51);
+        assert_eq!(segment.compute_checksum_ipv6(src_addr, dst_addr), 0);
+
+        segment.set_checksum(0);
+        assert_eq!(segment.compute_checksum_ipv6(src_addr, dst_addr), 0xab51);
+    }
 }
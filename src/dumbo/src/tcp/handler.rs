@@ -1,24 +1,25 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-//! Exposes simple TCP over IPv4 listener functionality via the [`TcpIPv4Handler`] structure.
+//! Exposes simple TCP over IPv4 (and optionally IPv6) listener functionality via the
+//! [`TcpIPv4Handler`] structure.
 //!
 //! [`TcpIPv4Handler`]: struct.TcpIPv4Handler.html
 
 use std::collections::{HashMap, HashSet};
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::num::NonZeroUsize;
 
 use micro_http::{Request, Response};
 
-use crate::pdu::bytes::NetworkBytes;
+use crate::pdu::bytes::{NetworkBytes, NetworkBytesMut};
 use crate::pdu::ipv4::{Error as IPv4PacketError, IPv4Packet, PROTOCOL_TCP};
+use crate::pdu::ipv6::{Error as IPv6PacketError, IPv6Packet};
 use crate::pdu::tcp::{Error as TcpSegmentError, Flags as TcpFlags, TcpSegment};
+use crate::pdu::Incomplete;
 use crate::tcp::endpoint::Endpoint;
 use crate::tcp::{NextSegmentStatus, RstConfig};
 
-// TODO: This is currently IPv4 specific. Maybe change it to a more generic implementation.
-
 /// Describes events which may occur when the handler receives packets.
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub enum RecvEvent {
@@ -61,6 +62,8 @@ pub enum WriteEvent {
 pub enum RecvError {
     /// The inner segment has an invalid destination port.
     InvalidPort,
+    /// An IPv6 packet was received, but the handler has no local IPv6 address.
+    NoLocalIPv6Addr,
     /// The handler encountered an error while parsing the inner TCP segment.
     TcpSegment(TcpSegmentError),
 }
@@ -74,22 +77,27 @@ pub enum RecvError {
 pub enum WriteNextError {
     /// There was an error while writing the contents of the IPv4 packet.
     IPv4Packet(IPv4PacketError),
+    /// There was an error while writing the contents of the IPv6 packet.
+    IPv6Packet(IPv6PacketError),
+    /// A packet has to be sent over IPv6, but the handler has no local IPv6 address.
+    NoLocalIPv6Addr,
     /// There was an error while writing the contents of the inner TCP segment.
     TcpSegment(TcpSegmentError),
 }
 
-// Generally speaking, a TCP/IPv4 connection is identified using the four-tuple (src_addr, src_port,
-// dst_addr, dst_port). However, the IPv4 address and TCP port of the MMDS endpoint are fixed, so
+// Generally speaking, a TCP/IP connection is identified using the four-tuple (src_addr, src_port,
+// dst_addr, dst_port). However, the IP addresses and TCP port of the MMDS endpoint are fixed, so
 // we can get away with uniquely identifying connections using just the remote address and port.
+// The family of the remote address also tells which local address the connection uses.
 #[derive(Clone, Copy, Eq, Hash, PartialEq)]
 #[cfg_attr(test, derive(Debug))]
 struct ConnectionTuple {
-    remote_addr: Ipv4Addr,
+    remote_addr: IpAddr,
     remote_port: u16,
 }
 
 impl ConnectionTuple {
-    fn new(remote_addr: Ipv4Addr, remote_port: u16) -> Self {
+    fn new(remote_addr: IpAddr, remote_port: u16) -> Self {
         ConnectionTuple {
             remote_addr,
             remote_port,
@@ -97,7 +105,8 @@ impl ConnectionTuple {
     }
 }
 
-/// Implements a minimalist TCP over IPv4 listener.
+/// Implements a minimalist TCP over IPv4 listener, which also listens over IPv6 when it has a
+/// local IPv6 address.
 ///
 /// Forwards incoming TCP segments to the appropriate connection object, based on the associated
 /// tuple, or attempts to establish new connections (when receiving `SYN` segments). Aside from
@@ -113,17 +122,22 @@ impl ConnectionTuple {
 ///   handler itself (right now it can only mean an enqueued `RST`), or one of the existing
 ///   connections. On success, also describes any internal status changes triggered as the packet
 ///   gets transmitted.
+/// * [`receive_ipv6_packet`] does the same as [`receive_packet`] for an incoming IPv6 packet. The
+///   replies to such packets are written as IPv6 packets by [`write_next_packet`].
 /// * [`next_segment_status`] describes whether the handler can send a packet immediately, or after
 ///   some retransmission timeout associated with a connection fires, or if there's nothing to send
 ///   for the moment. This is used to determine whether it's appropriate to call
 ///   [`write_next_packet`].
 ///
 /// [`receive_packet`]: ../handler/struct.TcpIPv4Handler.html#method.receive_packet
+/// [`receive_ipv6_packet`]: ../handler/struct.TcpIPv4Handler.html#method.receive_ipv6_packet
 /// [`write_next_packet`]: ../handler/struct.TcpIPv4Handler.html#method.write_next_packet
 /// [`next_segment_status`]: ../handler/struct.TcpIPv4Handler.html#method.next_segment_status
 pub struct TcpIPv4Handler {
-    // Handler IPv4 address used for every connection.
+    // Handler IPv4 address used for every IPv4 connection.
     local_ipv4_addr: Ipv4Addr,
+    // Handler IPv6 address used for every IPv6 connection, if IPv6 is enabled.
+    local_ipv6_addr: Option<Ipv6Addr>,
     // Handler TCP port used for every connection.
     local_port: u16,
     // This map holds the currently active endpoints, identified by their connection tuple.
@@ -150,6 +164,84 @@ enum RecvSegmentOutcome {
     UnexpectedSegment(bool),
 }
 
+// Only used locally, in the write_next_packet method, to hold the local and remote addresses of
+// an outgoing packet. Both are picked based on the family of the remote address.
+#[derive(Clone, Copy)]
+enum PacketAddrs {
+    IPv4(Ipv4Addr, Ipv4Addr),
+    IPv6(Ipv6Addr, Ipv6Addr),
+}
+
+impl PacketAddrs {
+    fn new(
+        local_ipv4_addr: Ipv4Addr,
+        local_ipv6_addr: Option<Ipv6Addr>,
+        remote_addr: IpAddr,
+    ) -> Result<Self, WriteNextError> {
+        match remote_addr {
+            IpAddr::V4(remote_addr) => Ok(PacketAddrs::IPv4(local_ipv4_addr, remote_addr)),
+            IpAddr::V6(remote_addr) => local_ipv6_addr
+                .map(|local_addr| PacketAddrs::IPv6(local_addr, remote_addr))
+                .ok_or(WriteNextError::NoLocalIPv6Addr),
+        }
+    }
+
+    // Writes an incomplete packet header, which is completed once the segment has been written.
+    fn write_header(self, buf: &mut [u8]) -> Result<OutgoingPacket, WriteNextError> {
+        Ok(match self {
+            PacketAddrs::IPv4(local_addr, remote_addr) => OutgoingPacket::IPv4(
+                IPv4Packet::write_header(buf, PROTOCOL_TCP, local_addr, remote_addr)?,
+            ),
+            PacketAddrs::IPv6(local_addr, remote_addr) => OutgoingPacket::IPv6(
+                IPv6Packet::write_header(buf, PROTOCOL_TCP, local_addr, remote_addr)?,
+            ),
+        })
+    }
+
+    // Fills in the ports and the checksum of the segment, and returns its length.
+    fn finalize_segment<T: NetworkBytesMut>(
+        self,
+        segment: Incomplete<TcpSegment<T>>,
+        local_port: u16,
+        remote_port: u16,
+    ) -> usize {
+        match self {
+            PacketAddrs::IPv4(local_addr, remote_addr) => segment
+                .finalize(local_port, remote_port, Some((local_addr, remote_addr)))
+                .len(),
+            PacketAddrs::IPv6(local_addr, remote_addr) => segment
+                .finalize_ipv6(local_port, remote_port, Some((local_addr, remote_addr)))
+                .len(),
+        }
+    }
+}
+
+// Only used locally, in the write_next_packet method, to hold the header of an outgoing packet
+// until the length of the TCP segment it carries is known.
+enum OutgoingPacket<'a> {
+    IPv4(Incomplete<IPv4Packet<'a, &'a mut [u8]>>),
+    IPv6(Incomplete<IPv6Packet<'a, &'a mut [u8]>>),
+}
+
+impl<'a> OutgoingPacket<'a> {
+    fn payload_mut(&mut self) -> &mut [u8] {
+        match self {
+            OutgoingPacket::IPv4(packet) => packet.inner_mut().payload_mut(),
+            OutgoingPacket::IPv6(packet) => packet.inner_mut().payload_mut(),
+        }
+    }
+
+    // Completes the packet and returns its length.
+    fn with_payload_len_unchecked(self, payload_len: usize) -> usize {
+        match self {
+            OutgoingPacket::IPv4(packet) => {
+                packet.with_payload_len_unchecked(payload_len, true).len()
+            }
+            OutgoingPacket::IPv6(packet) => packet.with_payload_len_unchecked(payload_len).len(),
+        }
+    }
+}
+
 impl TcpIPv4Handler {
     /// Creates a new `TcpIPv4Handler`.
     ///
@@ -167,6 +259,7 @@ impl TcpIPv4Handler {
         let max_pending_resets = max_pending_resets.get();
         TcpIPv4Handler {
             local_ipv4_addr,
+            local_ipv6_addr: None,
             local_port,
             connections: HashMap::with_capacity(max_connections),
             max_connections,
@@ -187,6 +280,32 @@ impl TcpIPv4Handler {
         self.local_ipv4_addr
     }
 
+    /// Setter for the local IPv6 address of this TCP handler. IPv6 is disabled when `None`.
+    ///
+    /// Connections and pending resets which were using the previous IPv6 address are dropped,
+    /// since they cannot be carried on from a different address.
+    pub fn set_local_ipv6_addr(&mut self, ipv6_addr: Option<Ipv6Addr>) {
+        if ipv6_addr != self.local_ipv6_addr {
+            let ipv6_tuples: Vec<ConnectionTuple> = self
+                .connections
+                .keys()
+                .filter(|tuple| tuple.remote_addr.is_ipv6())
+                .copied()
+                .collect();
+            for tuple in ipv6_tuples {
+                self.remove_connection(tuple);
+            }
+            self.rst_queue
+                .retain(|(tuple, _)| !tuple.remote_addr.is_ipv6());
+        }
+        self.local_ipv6_addr = ipv6_addr;
+    }
+
+    /// Returns the local IPv6 address of this TCP handler, if it has one.
+    pub fn local_ipv6_addr(&self) -> Option<Ipv6Addr> {
+        self.local_ipv6_addr
+    }
+
     /// Returns the local port of this TCP handler.
     pub fn local_port(&self) -> u16 {
         self.local_port
@@ -209,17 +328,51 @@ impl TcpIPv4Handler {
         &mut self,
         packet: &IPv4Packet<T>,
         callback: F,
+    ) -> Result<RecvEvent, RecvError> {
+        self.receive_segment(
+            IpAddr::V4(packet.source_address()),
+            packet.payload(),
+            callback,
+        )
+    }
+
+    /// Contains logic for handling incoming segments carried by IPv6 packets.
+    ///
+    /// Works just like [`receive_packet`], but requires the handler to have a local IPv6 address.
+    ///
+    /// [`receive_packet`]: #method.receive_packet
+    pub fn receive_ipv6_packet<T: NetworkBytes, F: FnOnce(Request) -> Response>(
+        &mut self,
+        packet: &IPv6Packet<T>,
+        callback: F,
+    ) -> Result<RecvEvent, RecvError> {
+        if self.local_ipv6_addr.is_none() {
+            return Err(RecvError::NoLocalIPv6Addr);
+        }
+
+        self.receive_segment(
+            IpAddr::V6(packet.source_address()),
+            packet.payload(),
+            callback,
+        )
+    }
+
+    fn receive_segment<F: FnOnce(Request) -> Response>(
+        &mut self,
+        remote_addr: IpAddr,
+        bytes: &[u8],
+        callback: F,
     ) -> Result<RecvEvent, RecvError> {
         // TODO: We skip verifying the checksum, just in case the device model relies on offloading
         // checksum computation from the guest to some other entity. Clear this up at some point!
         // (Issue #520)
-        let segment = TcpSegment::from_bytes(packet.payload(), None)?;
+        let segment = TcpSegment::from_bytes(bytes, None)?;
 
         if segment.destination_port() != self.local_port {
             return Err(RecvError::InvalidPort);
         }
 
-        let tuple = ConnectionTuple::new(packet.source_address(), segment.source_port());
+        let tuple = ConnectionTuple::new(remote_addr, segment.source_port());
 
         let outcome = if let Some(endpoint) = self.connections.get_mut(&tuple) {
             endpoint.receive_segment(&segment, callback);
@@ -386,10 +539,6 @@ impl TcpIPv4Handler {
         let mut writer_status = None;
         let mut event = WriteEvent::Nothing;
 
-        // Write an incomplete Ipv4 packet and complete it afterwards with missing information.
-        let mut packet =
-            IPv4Packet::write_header(buf, PROTOCOL_TCP, Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST)?;
-
         // We set mss_used to 0, because we don't add any IP options.
         // TODO: Maybe get this nicely from packet at some point.
         let mss_reserved = 0;
@@ -398,9 +547,17 @@ impl TcpIPv4Handler {
         // number, and using mss_remaining = 0 is perfectly fine in this case, because we don't add
         // any TCP options, or a payload.
         if let Some((tuple, rst_cfg)) = self.rst_queue.pop() {
+            let addrs = PacketAddrs::new(
+                self.local_ipv4_addr,
+                self.local_ipv6_addr,
+                tuple.remote_addr,
+            )?;
+            // Write an incomplete packet and complete it afterwards with missing information.
+            let mut packet = addrs.write_header(buf)?;
+
             let (seq, ack, flags_after_ns) = rst_cfg.seq_ack_tcp_flags();
-            let segment_len = TcpSegment::write_incomplete_segment::<[u8]>(
-                packet.inner_mut().payload_mut(),
+            let segment = TcpSegment::write_incomplete_segment::<[u8]>(
+                packet.payload_mut(),
                 seq,
                 ack,
                 flags_after_ns,
@@ -408,20 +565,10 @@ impl TcpIPv4Handler {
                 None,
                 0,
                 None,
-            )?
-            .finalize(
-                self.local_port,
-                tuple.remote_port,
-                Some((self.local_ipv4_addr, tuple.remote_addr)),
-            )
-            .len();
+            )?;
+            let segment_len = addrs.finalize_segment(segment, self.local_port, tuple.remote_port);
 
-            packet
-                .inner_mut()
-                .set_source_address(self.local_ipv4_addr)
-                .set_destination_address(tuple.remote_addr);
-
-            let packet_len = packet.with_payload_len_unchecked(segment_len, true).len();
+            let packet_len = packet.with_payload_len_unchecked(segment_len);
             // The unwrap() is safe because packet_len > 0.
             return Ok((
                 Some(NonZeroUsize::new(packet_len).unwrap()),
@@ -434,33 +581,30 @@ impl TcpIPv4Handler {
             .iter()
             .chain(self.next_timeout.as_ref().map(|(_, x)| x))
         {
+            let addrs = PacketAddrs::new(
+                self.local_ipv4_addr,
+                self.local_ipv6_addr,
+                tuple.remote_addr,
+            )?;
+            let mut packet = addrs.write_header(buf)?;
+
             // Tuples in self.active_connection or self.next_timeout should also appear as keys
             // in self.connections.
             let endpoint = self.connections.get_mut(tuple).unwrap();
             // We need this block to clearly delimit the lifetime of the mutable borrow started by
-            // the following packet.inner_mut().
+            // the following packet.payload_mut().
             let segment_len = {
-                let maybe_segment =
-                    endpoint.write_next_segment(packet.inner_mut().payload_mut(), mss_reserved);
+                let maybe_segment = endpoint.write_next_segment(packet.payload_mut(), mss_reserved);
 
                 match maybe_segment {
-                    Some(segment) => segment
-                        .finalize(
-                            self.local_port,
-                            tuple.remote_port,
-                            Some((self.local_ipv4_addr, tuple.remote_addr)),
-                        )
-                        .len(),
+                    Some(segment) => {
+                        addrs.finalize_segment(segment, self.local_port, tuple.remote_port)
+                    }
                     None => continue,
                 }
             };
 
-            packet
-                .inner_mut()
-                .set_source_address(self.local_ipv4_addr)
-                .set_destination_address(tuple.remote_addr);
-
-            let ip_len = packet.with_payload_len_unchecked(segment_len, true).len();
+            let ip_len = packet.with_payload_len_unchecked(segment_len);
 
             // The unwrap is safe because ip_len > 0.
             len = Some(NonZeroUsize::new(ip_len).unwrap());
@@ -707,8 +851,8 @@ mod tests {
         assert_eq!(h.next_segment_status(), NextSegmentStatus::Available);
         assert_eq!(drain_packets(&mut h, local_addr, remote_addr), Ok(1));
 
-        let remote_tuple = ConnectionTuple::new(remote_addr, remote_port);
-        let remote_tuple2 = ConnectionTuple::new(remote_addr, remote_port + 1);
+        let remote_tuple = ConnectionTuple::new(remote_addr.into(), remote_port);
+        let remote_tuple2 = ConnectionTuple::new(remote_addr.into(), remote_port + 1);
 
         // Also, there should be a retransmission timer associated with the previous SYNACK now.
         assert_eq!(h.active_connections.len(), 0);
@@ -769,7 +913,7 @@ mod tests {
         // The timeout associated with the SYNACK of the second connection should be next.
         assert_eq!(h.active_connections.len(), 0);
         if let Some((_, tuple)) = h.next_timeout {
-            assert_ne!(tuple, ConnectionTuple::new(remote_addr, remote_port));
+            assert_ne!(tuple, ConnectionTuple::new(remote_addr.into(), remote_port));
         } else {
             panic!("missing third expected timeout");
         }
@@ -821,4 +965,94 @@ mod tests {
         assert_eq!(h.connections.len(), 1);
         assert_eq!(h.active_connections.len(), 0);
     }
+
+    #[test]
+    fn test_handler_ipv6() {
+        let mut buf = [0u8; 100];
+        let mut buf2 = [0u8; 2000];
+
+        let local_addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0xa9fe, 0xa9fe);
+        let local_port = 80;
+        let remote_addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+        let remote_port = 1012;
+
+        let mut h = TcpIPv4Handler::new(
+            Ipv4Addr::new(169, 254, 169, 254),
+            local_port,
+            NonZeroUsize::new(2).unwrap(),
+            NonZeroUsize::new(2).unwrap(),
+        );
+        assert_eq!(h.local_ipv6_addr(), None);
+
+        let mut p =
+            IPv6Packet::write_header(buf.as_mut(), PROTOCOL_TCP, remote_addr, local_addr).unwrap();
+        let s_len = TcpSegment::write_segment::<[u8]>(
+            p.inner_mut().payload_mut(),
+            remote_port,
+            local_port,
+            123,
+            456,
+            TcpFlags::SYN,
+            10000,
+            None,
+            100,
+            None,
+            Some((Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST)),
+        )
+        .unwrap()
+        .len();
+        let p = p.with_payload_len_unchecked(s_len);
+
+        // IPv6 is disabled until the handler gets a local IPv6 address.
+        assert_eq!(
+            h.receive_ipv6_packet(&p, mock_callback).unwrap_err(),
+            RecvError::NoLocalIPv6Addr
+        );
+
+        h.set_local_ipv6_addr(Some(local_addr));
+        assert_eq!(h.local_ipv6_addr(), Some(local_addr));
+        assert_eq!(
+            h.receive_ipv6_packet(&p, mock_callback),
+            Ok(RecvEvent::NewConnectionSuccessful)
+        );
+        assert_eq!(h.next_segment_status(), NextSegmentStatus::Available);
+
+        // The SYNACK goes out in an IPv6 packet.
+        {
+            let len = h.write_next_packet(buf2.as_mut()).unwrap().0.unwrap().get();
+            let p = IPv6Packet::from_bytes(&buf2[..len]).unwrap();
+            assert_eq!(p.next_header(), PROTOCOL_TCP);
+            assert_eq!(p.source_address(), local_addr);
+            assert_eq!(p.destination_address(), remote_addr);
+
+            let s = TcpSegment::from_bytes(p.payload(), None).unwrap();
+            assert_eq!(s.flags_after_ns(), TcpFlags::SYN | TcpFlags::ACK);
+            assert_eq!(s.source_port(), local_port);
+            assert_eq!(s.destination_port(), remote_port);
+            assert_eq!(s.compute_checksum_ipv6(local_addr, remote_addr), 0);
+        }
+
+        // An unexpected segment gets a RST over IPv6 as well.
+        let mut p =
+            IPv6Packet::from_bytes(&mut buf[..crate::pdu::ipv6::HEADER_LEN + s_len]).unwrap();
+        TcpSegment::from_bytes(p.payload_mut(), None)
+            .unwrap()
+            .set_flags_after_ns(TcpFlags::ACK)
+            .set_source_port(remote_port + 1);
+        assert_eq!(
+            h.receive_ipv6_packet(&p, mock_callback),
+            Ok(RecvEvent::UnexpectedSegment)
+        );
+        assert_eq!(h.rst_queue.len(), 1);
+
+        // Changing the IPv6 address drops the connection and the pending RST.
+        h.set_local_ipv6_addr(None);
+        assert!(h.connections.is_empty());
+        assert!(h.rst_queue.is_empty());
+        assert_eq!(h.next_segment_status(), NextSegmentStatus::Nothing);
+        assert_eq!(
+            h.write_next_packet(buf2.as_mut()),
+            Ok((None, WriteEvent::Nothing))
+        );
+    }
 }
@@ -15,9 +15,11 @@ use logger::{error, warn, ProcessTimeReporter};
 use seccompiler::BpfThreadMap;
 use utils::epoll::EventSet;
 use utils::eventfd::EventFd;
+use vmm::migration::PreCopy;
 use vmm::resources::VmResources;
-use vmm::rpc_interface::{PrebootApiController, RuntimeApiController, VmmAction};
+use vmm::rpc_interface::{ActionResult, PrebootApiController, RuntimeApiController, VmmAction};
 use vmm::vmm_config::instance_info::InstanceInfo;
+use vmm::vmm_config::migration::SendMigrationParams;
 use vmm::{EventManager, FcExitCode, Vmm};
 
 struct ApiServerAdapter {
@@ -25,6 +27,9 @@ struct ApiServerAdapter {
     from_api: Receiver<ApiRequest>,
     to_api: Sender<ApiResponse>,
     controller: RuntimeApiController,
+    // Migration whose pre-copy rounds are being sent, the API response is only sent once
+    // it completes.
+    migration: Option<PreCopy>,
 }
 
 impl ApiServerAdapter {
@@ -43,57 +48,123 @@ impl ApiServerAdapter {
             from_api,
             to_api,
             controller: RuntimeApiController::new(vm_resources, vmm.clone()),
+            migration: None,
         }));
         event_manager.add_subscriber(api_adapter);
         loop {
             event_manager
                 .run()
                 .expect("EventManager events driver fatal error");
-            if let Some(exit_code) = vmm.lock().unwrap().shutdown_exit_code() {
+            let mut locked_vmm = vmm.lock().unwrap();
+            if let Some(exit_code) = locked_vmm.shutdown_exit_code() {
                 return exit_code;
             }
+            // Devices can only be (un)subscribed while the event manager isn't running.
+            locked_vmm.update_event_subscribers(event_manager);
         }
     }
 
     fn handle_request(&mut self, req_action: VmmAction) {
         let response = self.controller.handle_request(req_action);
+        self.send_response(response);
+    }
+
+    fn send_response(&self, response: ActionResult) {
         // Send back the result.
         self.to_api
             .send(Box::new(response))
             .map_err(|_| ())
             .expect("one-shot channel closed");
     }
+
+    /// Starts a migration whose pre-copy rounds are sent while the event loop keeps emulating
+    /// the devices. The API response is sent once the migration completes.
+    fn start_migration(&mut self, params: &SendMigrationParams, ops: &mut EventOps) {
+        let mut pre_copy = match self.controller.start_migration(params) {
+            Ok(pre_copy) => pre_copy,
+            Err(err) => {
+                self.send_response(Err(err));
+                return;
+            }
+        };
+        if let Err(err) = ops.add(Events::new(pre_copy.round_evt(), EventSet::IN)) {
+            error!("Failed to register migration round event: {}", err);
+            // Fall back to waiting for the rounds on the VMM thread.
+            let response = loop {
+                match self.controller.next_migration_round(&mut pre_copy) {
+                    Ok(true) => (),
+                    Ok(false) => break self.controller.finish_migration(pre_copy),
+                    Err(err) => break Err(err),
+                }
+            };
+            self.send_response(response);
+            return;
+        }
+        self.migration = Some(pre_copy);
+    }
+
+    /// Handles the end of a pre-copy round, completing the migration once they're over.
+    fn handle_migration_round(&mut self, ops: &mut EventOps) {
+        let mut pre_copy = match self.migration.take() {
+            Some(pre_copy) => pre_copy,
+            None => return,
+        };
+        let result = self.controller.next_migration_round(&mut pre_copy);
+        if let Ok(true) = result {
+            self.migration = Some(pre_copy);
+            return;
+        }
+
+        if let Err(err) = ops.remove(Events::new(pre_copy.round_evt(), EventSet::IN)) {
+            error!("Failed to unregister migration round event: {}", err);
+        }
+        let response = match result {
+            Ok(_) => self.controller.finish_migration(pre_copy),
+            Err(err) => Err(err),
+        };
+        self.send_response(response);
+    }
 }
 impl MutEventSubscriber for ApiServerAdapter {
     /// Handle a read event (EPOLLIN).
-    fn process(&mut self, event: Events, _: &mut EventOps) {
+    fn process(&mut self, event: Events, ops: &mut EventOps) {
         let source = event.fd();
         let event_set = event.event_set();
 
-        if source == self.api_event_fd.as_raw_fd() && event_set == EventSet::IN {
+        if self
+            .migration
+            .as_ref()
+            .map_or(false, |pre_copy| pre_copy.round_evt().as_raw_fd() == source)
+        {
+            self.handle_migration_round(ops);
+        } else if source == self.api_event_fd.as_raw_fd() && event_set == EventSet::IN {
             match self.from_api.try_recv() {
-                Ok(api_request) => {
-                    let request_is_pause = *api_request == VmmAction::Pause;
-                    self.handle_request(*api_request);
+                Ok(api_request) => match *api_request {
+                    VmmAction::SendMigration(params) => self.start_migration(&params, ops),
+                    api_request => {
+                        let request_is_pause = api_request == VmmAction::Pause;
+                        self.handle_request(api_request);
 
-                    // If the latest req is a pause request, temporarily switch to a mode where we
-                    // do blocking `recv`s on the `from_api` receiver in a loop, until we get
-                    // unpaused. The device emulation is implicitly paused since we do not
-                    // relinquish control to the event manager because we're not returning from
-                    // `process`.
-                    if request_is_pause {
-                        // This loop only attempts to process API requests, so things like the
-                        // metric flush timerfd handling are frozen as well.
-                        loop {
-                            let req = self.from_api.recv().expect("Error receiving API request.");
-                            let req_is_resume = *req == VmmAction::Resume;
-                            self.handle_request(*req);
-                            if req_is_resume {
-                                break;
+                        // If the latest req is a pause request, temporarily switch to a mode
+                        // where we do blocking `recv`s on the `from_api` receiver in a loop,
+                        // until we get unpaused. The device emulation is implicitly paused since
+                        // we do not relinquish control to the event manager because we're not
+                        // returning from `process`.
+                        if request_is_pause {
+                            // This loop only attempts to process API requests, so things like
+                            // the metric flush timerfd handling are frozen as well.
+                            loop {
+                                let req =
+                                    self.from_api.recv().expect("Error receiving API request.");
+                                let req_is_resume = *req == VmmAction::Resume;
+                                self.handle_request(*req);
+                                if req_is_resume {
+                                    break;
+                                }
                             }
                         }
                     }
-                }
+                },
                 Err(TryRecvError::Empty) => {
                     warn!("Got a spurious notification from api thread");
                 }
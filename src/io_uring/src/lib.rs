@@ -6,7 +6,8 @@
 //!
 //! Aims to provide an easy-to-use interface, while making some Firecracker-specific simplifying
 //! assumptions. The crate does not currently aim at supporting all io_uring features and use
-//! cases. For example, it only works with pre-registered fds and read/write/fsync requests.
+//! cases. For example, it only works with pre-registered fds and read/write/fsync/fallocate
+//! requests.
 //!
 //! Requires at least kernel version 5.10.51.
 //! For more information on io_uring, refer to the man pages.
@@ -366,3 +366,53 @@ fn test_read() {
     // Verify the result.
     assert_eq!(buf, &init_contents[..]);
 }
+
+#[test]
+fn test_fallocate() {
+    skip_if_io_uring_unsupported!();
+
+    // Test that punching a hole in a file zeroes out the targeted range and keeps the file size.
+
+    const NUM_BYTES: usize = 8192;
+    const HOLE_OFFSET: u64 = 4096;
+    const HOLE_LEN: u64 = 4096;
+    // Setup.
+    let file = TempFile::new().unwrap().into_file();
+    let mut ring = IoUring::new(
+        NUM_ENTRIES,
+        vec![&file],
+        vec![
+            Restriction::RequireFixedFds,
+            Restriction::AllowOpCode(OpCode::Fallocate),
+        ],
+        None,
+    )
+    .unwrap();
+
+    // Init the file with all ones.
+    file.write_all_at(&[1; NUM_BYTES], 0).unwrap();
+
+    // Perform the IO.
+    unsafe {
+        ring.push(Operation::fallocate(
+            0,
+            (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u32,
+            HOLE_OFFSET,
+            HOLE_LEN,
+            71,
+        ))
+        .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    assert_eq!(
+        unsafe { ring.pop::<u8>().unwrap().unwrap().result().unwrap() },
+        0
+    );
+
+    // Verify the result.
+    assert_eq!(file.metadata().unwrap().len(), NUM_BYTES as u64);
+    let mut buf = [0u8; NUM_BYTES];
+    file.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(buf[..HOLE_OFFSET as usize], [1; HOLE_OFFSET as usize]);
+    assert_eq!(buf[HOLE_OFFSET as usize..], [0; HOLE_LEN as usize]);
+}
@@ -419,6 +419,10 @@ pub struct PutRequestsMetrics {
     pub vsock_count: SharedIncMetric,
     /// Number of failures in creating a vsock device.
     pub vsock_fails: SharedIncMetric,
+    /// Number of PUTs for creating a vhost-user device.
+    pub vhost_user_count: SharedIncMetric,
+    /// Number of failures in creating a vhost-user device.
+    pub vhost_user_fails: SharedIncMetric,
 }
 
 /// Metrics specific to PATCH API Requests for counting user triggered actions and/or failures.
@@ -466,6 +470,18 @@ pub struct BalloonDeviceMetrics {
     pub deflate_count: SharedIncMetric,
     /// Number of times when handling events on a balloon device failed.
     pub event_fails: SharedIncMetric,
+    /// Number of free page reports processed by the balloon device.
+    pub free_page_report_count: SharedIncMetric,
+    /// Number of bytes of guest memory freed from the free page reports.
+    pub free_page_report_freed: SharedIncMetric,
+    /// Number of reported ranges which could not be freed.
+    pub free_page_report_fails: SharedIncMetric,
+    /// Number of times the balloon controller inflated the balloon.
+    pub auto_inflate_count: SharedIncMetric,
+    /// Number of times the balloon controller deflated the balloon.
+    pub auto_deflate_count: SharedIncMetric,
+    /// Number of times the balloon controller failed to update the balloon size.
+    pub auto_adjust_fails: SharedIncMetric,
 }
 
 /// Block Device associated metrics.
@@ -501,6 +517,10 @@ pub struct BlockDeviceMetrics {
     pub read_count: SharedIncMetric,
     /// Number of successful write operations.
     pub write_count: SharedIncMetric,
+    /// Number of successful discard operations.
+    pub discard_count: SharedIncMetric,
+    /// Number of successful write zeroes operations.
+    pub write_zeroes_count: SharedIncMetric,
     /// Number of rate limiter throttling events.
     pub rate_limiter_throttled_events: SharedIncMetric,
     /// Number of virtio events throttled because of the IO engine.
@@ -538,6 +558,23 @@ pub struct LoggerSystemMetrics {
     pub log_fails: SharedIncMetric,
 }
 
+/// Virtio-mem device associated metrics.
+#[derive(Default, Serialize)]
+pub struct MemDeviceMetrics {
+    /// Number of times when activate failed on a virtio-mem device.
+    pub activate_fails: SharedIncMetric,
+    /// Number of times when handling events on a virtio-mem device failed.
+    pub event_fails: SharedIncMetric,
+    /// Number of plug requests handled.
+    pub plug_count: SharedIncMetric,
+    /// Number of plug requests rejected.
+    pub plug_fails: SharedIncMetric,
+    /// Number of unplug requests handled.
+    pub unplug_count: SharedIncMetric,
+    /// Number of unplug requests rejected.
+    pub unplug_fails: SharedIncMetric,
+}
+
 /// Metrics for the MMDS functionality.
 #[derive(Default, Serialize)]
 pub struct MmdsMetrics {
@@ -572,6 +609,10 @@ pub struct NetDeviceMetrics {
     pub activate_fails: SharedIncMetric,
     /// Number of times when interacting with the space config of a network device failed.
     pub cfg_fails: SharedIncMetric,
+    /// Number of control commands that failed or were not supported.
+    pub ctrl_fails: SharedIncMetric,
+    /// Number of events associated with the control queue.
+    pub ctrl_queue_event_count: SharedIncMetric,
     //// Number of times the mac address was updated through the config space.
     pub mac_address_updates: SharedIncMetric,
     /// No available buffer for the net device rx queue.
@@ -622,6 +663,32 @@ pub struct NetDeviceMetrics {
     pub tx_rate_limiter_throttled: SharedIncMetric,
     /// Number of packets with a spoofed mac, sent by the guest.
     pub tx_spoofed_mac_count: SharedIncMetric,
+    /// Number of frames dropped by the TX filter because of a spoofed source MAC.
+    pub tx_filter_spoofed_mac_drops: SharedIncMetric,
+    /// Number of frames dropped by the TX filter because of a disallowed IPv4 source address.
+    pub tx_filter_ipv4_source_drops: SharedIncMetric,
+    /// Number of ARP frames dropped by the TX filter because of spoofed sender addresses.
+    pub tx_filter_spoofed_arp_drops: SharedIncMetric,
+    /// Number of VLAN tagged frames dropped by the TX filter.
+    pub tx_filter_vlan_drops: SharedIncMetric,
+    /// Number of used buffer notifications from vhost-net relayed to the guest.
+    pub vhost_call_event_count: SharedIncMetric,
+    /// Number of TCP connections and UDP flows opened by the user-mode network stack.
+    pub user_flows_created: SharedIncMetric,
+    /// Number of host connections of the user-mode network stack that failed.
+    pub user_connect_fails: SharedIncMetric,
+    /// Number of frames for the guest dropped by the user-mode network stack.
+    pub user_rx_drops: SharedIncMetric,
+    /// Number of frames from the guest dropped by the user-mode network stack.
+    pub user_tx_drops: SharedIncMetric,
+    /// Number of DHCP requests sent by the guest to the built-in server.
+    pub dhcp_requests_count: SharedIncMetric,
+    /// Number of replies sent by the built-in DHCP server.
+    pub dhcp_replies_count: SharedIncMetric,
+    /// Number of requested addresses refused by the built-in DHCP server.
+    pub dhcp_naks_count: SharedIncMetric,
+    /// Number of DHCP requests which could not be answered.
+    pub dhcp_invalid_requests: SharedIncMetric,
 }
 
 /// Performance metrics related for the moment only to snapshots.
@@ -654,6 +721,13 @@ pub struct PerformanceMetrics {
     pub vmm_pause_vm: SharedStoreMetric,
     /// Measures the microVM resuming duration, at the VMM level, in microseconds.
     pub vmm_resume_vm: SharedStoreMetric,
+    /// Measures the microVM sending time, at the API (user) level, in microseconds.
+    pub send_migration: SharedStoreMetric,
+    /// Measures the microVM receiving time, at the API (user) level, in microseconds.
+    pub receive_migration: SharedStoreMetric,
+    /// Measures how long the microVM stays paused at the end of a migration, at the VMM level,
+    /// in microseconds.
+    pub vmm_migration_downtime: SharedStoreMetric,
 }
 
 /// Metrics specific to the RTC device.
@@ -755,6 +829,19 @@ pub struct VmmMetrics {
     pub panic_count: SharedStoreMetric,
 }
 
+/// Metrics of the vhost-user devices.
+#[derive(Default, Serialize)]
+pub struct VhostUserDeviceMetrics {
+    /// Number of times when activate failed on a vhost-user device.
+    pub activate_fails: SharedIncMetric,
+    /// Number of times when interacting with the space config of a vhost-user device failed.
+    pub cfg_fails: SharedIncMetric,
+    /// Number of used buffer notifications received from the backends.
+    pub call_event_count: SharedIncMetric,
+    /// Number of times when handling events on a vhost-user device failed.
+    pub event_fails: SharedIncMetric,
+}
+
 /// Vsock-related metrics.
 #[derive(Default, Serialize)]
 pub struct VsockDeviceMetrics {
@@ -790,6 +877,8 @@ pub struct VsockDeviceMetrics {
     pub conns_killed: SharedIncMetric,
     /// Number of removed connections.
     pub conns_removed: SharedIncMetric,
+    /// Number of guest connections refused by the port filter.
+    pub conns_denied: SharedIncMetric,
     /// How many times the killq has been resynced.
     pub killq_resync: SharedIncMetric,
     /// How many flush fails have been seen.
@@ -798,6 +887,22 @@ pub struct VsockDeviceMetrics {
     pub tx_write_fails: SharedIncMetric,
     /// Number of times read() has failed.
     pub rx_read_fails: SharedIncMetric,
+    /// Number of datagrams sent by the guest and delivered to the host.
+    pub dgram_tx_count: SharedIncMetric,
+    /// Number of bytes in the datagrams sent by the guest and delivered to the host.
+    pub dgram_tx_bytes: SharedIncMetric,
+    /// Number of datagrams sent by the guest that couldn't be delivered to the host.
+    pub dgram_tx_fails: SharedIncMetric,
+    /// Number of datagrams sent by the guest and dropped by the rate limiter.
+    pub dgram_tx_rate_limited: SharedIncMetric,
+    /// Number of datagrams sent by the guest to ports denied by the port filter.
+    pub dgram_tx_denied: SharedIncMetric,
+    /// Number of datagrams sent by the host and delivered to the guest.
+    pub dgram_rx_count: SharedIncMetric,
+    /// Number of bytes in the datagrams sent by the host and delivered to the guest.
+    pub dgram_rx_bytes: SharedIncMetric,
+    /// Number of invalid or undeliverable datagrams sent by the host.
+    pub dgram_rx_dropped: SharedIncMetric,
 }
 
 // The sole purpose of this struct is to produce an UTC timestamp when an instance is serialized.
@@ -832,6 +937,8 @@ pub struct FirecrackerMetrics {
     pub latencies_us: PerformanceMetrics,
     /// Logging related metrics.
     pub logger: LoggerSystemMetrics,
+    /// A virtio-mem device's related metrics.
+    pub mem: MemDeviceMetrics,
     /// Metrics specific to MMDS functionality.
     pub mmds: MmdsMetrics,
     /// A network device's related metrics.
@@ -853,6 +960,8 @@ pub struct FirecrackerMetrics {
     pub uart: Arc<SerialDeviceMetrics>,
     /// Metrics related to signals.
     pub signals: SignalMetrics,
+    /// Metrics related to the vhost-user devices.
+    pub vhost_user: VhostUserDeviceMetrics,
     /// Metrics related to virtio-vsockets.
     pub vsock: VsockDeviceMetrics,
 }
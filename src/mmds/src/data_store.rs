@@ -1,21 +1,27 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::borrow::Cow;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 
 use serde::{Deserialize, Serialize};
-use serde_json::{to_vec, Value};
+use serde_json::{to_vec, Map, Value};
 
 use crate::token::{Error as TokenError, TokenAuthority};
 
 /// The Mmds is the Microvm Metadata Service represented as an untyped json.
 pub struct Mmds {
     data_store: Value,
-    // None when MMDS V1 is configured, Some for MMDS V2.
+    version: MmdsVersion,
+    // Some for MMDS V2, or when some subtrees require a session token.
     token_authority: Option<TokenAuthority>,
     is_initialized: bool,
     data_store_limit: usize,
+    // The subtrees the guest has restricted access to.
+    acl: Vec<MmdsAclRule>,
+    // The subtree the guest can write to, if any.
+    guest_writable_path: Option<String>,
 }
 
 /// MMDS version.
@@ -40,6 +46,27 @@ impl Display for MmdsVersion {
     }
 }
 
+/// The access the guest has to a restricted MMDS subtree.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MmdsAccess {
+    /// The guest can't read the subtree.
+    Hidden,
+    /// The guest can only read the subtree with a valid session token, whatever the MMDS
+    /// version.
+    TokenRequired,
+}
+
+/// Restricts the access the guest has to an MMDS subtree, and to all of its children.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MmdsAclRule {
+    /// The path of the subtree, e.g. `/secrets`.
+    pub path: String,
+    /// The access the guest has to the subtree.
+    pub access: MmdsAccess,
+}
+
 /// MMDS possible outputs.
 pub enum OutputFormat {
     Json,
@@ -49,9 +76,12 @@ pub enum OutputFormat {
 #[derive(Debug, derive_more::From)]
 pub enum Error {
     DataStoreLimitExceeded,
+    InvalidPath(String),
     NotFound,
     NotInitialized,
+    NotWritable,
     TokenAuthority(TokenError),
+    TokenRequired,
     UnsupportedValueType,
 }
 
@@ -59,9 +89,17 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::DataStoreLimitExceeded => write!(f, "The MMDS patch request doesn't fit."),
+            Error::InvalidPath(path) => write!(
+                f,
+                "Invalid MMDS subtree path: {}. The path must start with '/', and can't be the \
+                 root of the data store.",
+                path
+            ),
             Error::NotFound => write!(f, "The MMDS resource does not exist."),
             Error::NotInitialized => write!(f, "The MMDS data store is not initialized."),
+            Error::NotWritable => write!(f, "The MMDS resource is not writable by the guest."),
             Error::TokenAuthority(err) => write!(f, "Token Authority error: {}", err),
+            Error::TokenRequired => write!(f, "The MMDS resource requires a valid token."),
             Error::UnsupportedValueType => write!(
                 f,
                 "Cannot retrieve value. The value has an unsupported type."
@@ -81,9 +119,12 @@ impl Mmds {
     pub fn default_with_limit(data_store_limit: usize) -> Self {
         Mmds {
             data_store: Value::default(),
+            version: MmdsVersion::default(),
             token_authority: None,
             is_initialized: false,
             data_store_limit,
+            acl: Vec::new(),
+            guest_writable_path: None,
         }
     }
 
@@ -100,27 +141,67 @@ impl Mmds {
 
     /// Set the MMDS version.
     pub fn set_version(&mut self, version: MmdsVersion) -> Result<(), Error> {
-        match version {
-            MmdsVersion::V1 => {
-                self.token_authority = None;
-                Ok(())
-            }
-            MmdsVersion::V2 => {
-                if self.token_authority.is_none() {
-                    self.token_authority = Some(TokenAuthority::new()?);
-                }
-                Ok(())
-            }
-        }
+        self.version = version;
+        self.update_token_authority()
     }
 
-    /// Return the MMDS version by checking the token authority field.
+    /// Return the MMDS version.
     pub fn version(&self) -> MmdsVersion {
-        if self.token_authority.is_none() {
-            MmdsVersion::V1
-        } else {
-            MmdsVersion::V2
+        self.version
+    }
+
+    /// Session tokens are needed by MMDS V2, and by the token-protected subtrees.
+    fn update_token_authority(&mut self) -> Result<(), Error> {
+        let uses_tokens = self.version == MmdsVersion::V2
+            || self
+                .acl
+                .iter()
+                .any(|rule| rule.access == MmdsAccess::TokenRequired);
+        if !uses_tokens {
+            self.token_authority = None;
+        } else if self.token_authority.is_none() {
+            self.token_authority = Some(TokenAuthority::new()?);
+        }
+        Ok(())
+    }
+
+    /// Check whether the guest can be issued session tokens.
+    pub fn uses_tokens(&self) -> bool {
+        self.token_authority.is_some()
+    }
+
+    /// Restrict the access the guest has to some subtrees. This replaces any previous rules.
+    pub fn set_acl(&mut self, mut acl: Vec<MmdsAclRule>) -> Result<(), Error> {
+        for rule in acl.iter_mut() {
+            rule.path = Self::subtree_pointer(&rule.path)?;
         }
+        self.acl = acl;
+        self.update_token_authority()
+    }
+
+    /// Returns the rules restricting the access the guest has to some subtrees.
+    pub fn acl(&self) -> &[MmdsAclRule] {
+        &self.acl
+    }
+
+    /// Set the subtree the guest can write to, if any.
+    pub fn set_guest_writable_path(&mut self, path: Option<String>) -> Result<(), Error> {
+        self.guest_writable_path = path.map(|path| Self::subtree_pointer(&path)).transpose()?;
+        Ok(())
+    }
+
+    /// Returns the subtree the guest can write to, if any.
+    pub fn guest_writable_path(&self) -> Option<&str> {
+        self.guest_writable_path.as_deref()
+    }
+
+    /// Check whether the guest can write to `path`.
+    pub fn is_guest_writable(&self, path: &str) -> bool {
+        self.guest_writable_path
+            .as_deref()
+            .map_or(false, |writable_path| {
+                Self::is_within(path.trim_end_matches('/'), writable_path)
+            })
     }
 
     /// Sets the Additional Authenticated Data to be used for encryption and
@@ -152,9 +233,7 @@ impl Mmds {
     }
 
     pub fn put_data(&mut self, data: Value) -> Result<(), Error> {
-        // It is safe to unwrap because any map keys are all strings and
-        // we are using default serializer which does not return error.
-        if to_vec(&data).unwrap().len() > self.data_store_limit {
+        if self.host_data_len(&data) > self.data_store_limit {
             Err(Error::DataStoreLimitExceeded)
         } else {
             self.data_store = data;
@@ -169,18 +248,104 @@ impl Mmds {
         let mut data_store_clone = self.data_store.clone();
 
         super::json_patch(&mut data_store_clone, &patch_data);
-        // It is safe to unwrap because our data store keys are all strings and
-        // we are using default serializer which does not return error.
-        if to_vec(&data_store_clone).unwrap().len() > self.data_store_limit {
+        if self.host_data_len(&data_store_clone) > self.data_store_limit {
+            return Err(Error::DataStoreLimitExceeded);
+        }
+        self.data_store = data_store_clone;
+        Ok(())
+    }
+
+    /// Store a value written by the guest at `path`, which has to be within the guest-writable
+    /// subtree. The guest-writable subtree has a size limit of its own, the same as the one of
+    /// the rest of the data store.
+    pub fn put_guest_data(&mut self, path: &str, value: Value) -> Result<(), Error> {
+        if !self.is_guest_writable(path) {
+            return Err(Error::NotWritable);
+        }
+
+        let mut data_store_clone = self.data_store.clone();
+        let mut target = &mut data_store_clone;
+        for token in path.trim_end_matches('/').split('/').skip(1) {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            // This is safe since we make sure target is an object beforehand.
+            target = target
+                .as_object_mut()
+                .unwrap()
+                .entry(Self::unescape_token(token))
+                .or_insert(Value::Null);
+        }
+        *target = value;
+
+        if self.guest_data_len(&data_store_clone) > self.data_store_limit {
             return Err(Error::DataStoreLimitExceeded);
         }
         self.data_store = data_store_clone;
         Ok(())
     }
 
+    /// Returns the size of `data`, without the guest-writable subtree.
+    fn host_data_len(&self, data: &Value) -> usize {
+        // It is safe to unwrap because any map keys are all strings and
+        // we are using default serializer which does not return error.
+        match self.guest_writable_path.as_deref() {
+            Some(path) if data.pointer(path).is_some() => {
+                let mut data = data.clone();
+                Self::remove_pointer(&mut data, path);
+                to_vec(&data).unwrap().len()
+            }
+            _ => to_vec(data).unwrap().len(),
+        }
+    }
+
+    /// Returns the size of the guest-writable subtree of `data`.
+    fn guest_data_len(&self, data: &Value) -> usize {
+        self.guest_writable_path
+            .as_deref()
+            .and_then(|path| data.pointer(path))
+            // It is safe to unwrap because any map keys are all strings and
+            // we are using default serializer which does not return error.
+            .map_or(0, |value| to_vec(value).unwrap().len())
+    }
+
+    /// Turns `path` into the JSON pointer of a subtree, other than the root of the data store.
+    fn subtree_pointer(path: &str) -> Result<String, Error> {
+        let pointer = super::sanitize_uri(path.to_owned());
+        let pointer = pointer.trim_end_matches('/');
+        if !pointer.starts_with('/') {
+            return Err(Error::InvalidPath(path.to_owned()));
+        }
+        Ok(pointer.to_owned())
+    }
+
+    /// Check whether the JSON pointer `pointer` is `subtree`, or one of its children.
+    fn is_within(pointer: &str, subtree: &str) -> bool {
+        pointer
+            .strip_prefix(subtree)
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Unescape a JSON pointer reference token.
+    fn unescape_token(token: &str) -> String {
+        token.replace("~1", "/").replace("~0", "~")
+    }
+
+    /// Remove the value at `pointer`, if it belongs to an object.
+    fn remove_pointer(value: &mut Value, pointer: &str) {
+        if let Some(pos) = pointer.rfind('/') {
+            if let Some(map) = value
+                .pointer_mut(&pointer[..pos])
+                .and_then(Value::as_object_mut)
+            {
+                map.remove(&Self::unescape_token(&pointer[pos + 1..]));
+            }
+        }
+    }
+
     // We do not check size of data_store before returning a result because due
-    // to limit from put/patch the data_store can not be bigger than the limit
-    // imposed by the server.
+    // to the limits from put/patch and from guest writes the data_store can not
+    // be bigger than the limits imposed by the server.
     pub fn data_store_value(&self) -> Value {
         self.data_store.clone()
     }
@@ -253,6 +418,13 @@ impl Mmds {
         }
     }
 
+    fn format_value(json: &Value, format: OutputFormat) -> Result<String, Error> {
+        match format {
+            OutputFormat::Json => Ok(json.to_string()),
+            OutputFormat::Imds => Mmds::format_imds(json),
+        }
+    }
+
     /// Returns the subtree located at path. When the path corresponds to a leaf, it returns the
     /// value. Returns Error::NotFound when the path is invalid.
     pub fn get_value(&self, path: String, format: OutputFormat) -> Result<String, Error> {
@@ -265,14 +437,50 @@ impl Mmds {
         };
 
         if let Some(json) = value {
-            match format {
-                OutputFormat::Json => Ok(json.to_string()),
-                OutputFormat::Imds => Mmds::format_imds(json),
-            }
+            Mmds::format_value(json, format)
         } else {
             Err(Error::NotFound)
         }
     }
+
+    /// Returns the subtree located at path, as seen by the guest: the restricted subtrees the
+    /// guest can't access are left out. `token_valid` tells whether the guest provided a valid
+    /// session token. Returns Error::NotFound when the path is invalid or hidden, and
+    /// Error::TokenRequired when the path requires a valid token.
+    pub fn get_guest_value(
+        &self,
+        path: String,
+        format: OutputFormat,
+        token_valid: bool,
+    ) -> Result<String, Error> {
+        let pointer = path.trim_end_matches('/');
+        let denied_rules: Vec<&MmdsAclRule> = self
+            .acl
+            .iter()
+            .filter(|rule| rule.access == MmdsAccess::Hidden || !token_valid)
+            .collect();
+
+        let mut token_required = false;
+        for rule in denied_rules.iter() {
+            if Self::is_within(pointer, &rule.path) {
+                match rule.access {
+                    MmdsAccess::Hidden => return Err(Error::NotFound),
+                    MmdsAccess::TokenRequired => token_required = true,
+                }
+            }
+        }
+        if token_required {
+            return Err(Error::TokenRequired);
+        }
+
+        let mut json = Cow::Borrowed(self.data_store.pointer(pointer).ok_or(Error::NotFound)?);
+        for rule in denied_rules {
+            if Self::is_within(&rule.path, pointer) {
+                Self::remove_pointer(json.to_mut(), &rule.path[pointer.len()..]);
+            }
+        }
+        Mmds::format_value(&json, format)
+    }
 }
 
 #[cfg(test)]
@@ -580,6 +788,167 @@ mod tests {
         assert_eq!(mmds.get_data_str().len(), 2);
     }
 
+    #[test]
+    fn test_acl() {
+        let mut mmds = Mmds::default();
+        let data = r#"{
+            "name": {
+                "first": "John",
+                "second": "Doe"
+            },
+            "secrets": {
+                "key": "value",
+                "a/b": "c"
+            }
+        }"#;
+        mmds.put_data(serde_json::from_str(data).unwrap()).unwrap();
+
+        // Test invalid paths.
+        for path in ["", "/", "//", "secrets"].iter() {
+            let rule = MmdsAclRule {
+                path: path.to_string(),
+                access: MmdsAccess::Hidden,
+            };
+            assert_eq!(
+                mmds.set_acl(vec![rule]).unwrap_err().to_string(),
+                Error::InvalidPath(path.to_string()).to_string()
+            );
+        }
+        assert!(mmds.acl().is_empty());
+
+        // Hidden subtrees.
+        mmds.set_acl(vec![
+            MmdsAclRule {
+                path: "//name//second/".to_string(),
+                access: MmdsAccess::Hidden,
+            },
+            MmdsAclRule {
+                path: "/secrets/a~1b".to_string(),
+                access: MmdsAccess::Hidden,
+            },
+        ])
+        .unwrap();
+        assert_eq!(mmds.acl()[0].path, "/name/second");
+        // Hidden subtrees don't need tokens.
+        assert!(!mmds.uses_tokens());
+        assert_eq!(
+            mmds.get_guest_value("/".to_string(), OutputFormat::Json, false)
+                .unwrap(),
+            r#"{"name":{"first":"John"},"secrets":{"key":"value"}}"#
+        );
+        assert_eq!(
+            mmds.get_guest_value("/name/second".to_string(), OutputFormat::Json, true)
+                .unwrap_err()
+                .to_string(),
+            Error::NotFound.to_string()
+        );
+        // The host still sees everything.
+        assert_eq!(
+            mmds.get_value("/name/second".to_string(), OutputFormat::Json)
+                .unwrap(),
+            r#""Doe""#
+        );
+
+        // Token-protected subtrees.
+        mmds.set_acl(vec![MmdsAclRule {
+            path: "/secrets".to_string(),
+            access: MmdsAccess::TokenRequired,
+        }])
+        .unwrap();
+        assert!(mmds.uses_tokens());
+        assert_eq!(mmds.version(), MmdsVersion::V1);
+        assert_eq!(
+            mmds.get_guest_value("/".to_string(), OutputFormat::Imds, false)
+                .unwrap(),
+            "name/"
+        );
+        assert_eq!(
+            mmds.get_guest_value("/secrets/key".to_string(), OutputFormat::Imds, false)
+                .unwrap_err()
+                .to_string(),
+            Error::TokenRequired.to_string()
+        );
+        assert_eq!(
+            mmds.get_guest_value("/secrets/key".to_string(), OutputFormat::Imds, true)
+                .unwrap(),
+            "value"
+        );
+        // Paths sharing a prefix with a restricted subtree are not restricted.
+        assert_eq!(
+            mmds.get_guest_value("/secretsfoo".to_string(), OutputFormat::Imds, false)
+                .unwrap_err()
+                .to_string(),
+            Error::NotFound.to_string()
+        );
+
+        // Removing the rules drops the token authority with MMDS V1.
+        mmds.set_acl(vec![]).unwrap();
+        assert!(!mmds.uses_tokens());
+    }
+
+    #[test]
+    fn test_put_guest_data() {
+        let mut mmds = Mmds::default_with_limit(32);
+
+        assert_eq!(
+            mmds.set_guest_writable_path(Some("/".to_string()))
+                .unwrap_err()
+                .to_string(),
+            Error::InvalidPath("/".to_string()).to_string()
+        );
+        assert!(mmds.guest_writable_path().is_none());
+        assert_eq!(
+            mmds.put_guest_data("/guest/a", Value::Bool(true))
+                .unwrap_err()
+                .to_string(),
+            Error::NotWritable.to_string()
+        );
+
+        mmds.set_guest_writable_path(Some("/guest/".to_string()))
+            .unwrap();
+        assert_eq!(mmds.guest_writable_path(), Some("/guest"));
+        assert!(mmds.is_guest_writable("/guest"));
+        assert!(mmds.is_guest_writable("/guest/a/"));
+        assert!(!mmds.is_guest_writable("/guests"));
+        assert!(!mmds.is_guest_writable("/"));
+
+        // Intermediate objects are created as needed.
+        mmds.put_guest_data("/guest/a/b", Value::from(1)).unwrap();
+        mmds.put_guest_data("/guest/c~1d", Value::from("e"))
+            .unwrap();
+        assert_eq!(mmds.get_data_str(), r#"{"guest":{"a":{"b":1},"c/d":"e"}}"#);
+        // Guest writes don't initialize the data store.
+        assert!(mmds.check_data_store_initialized().is_err());
+
+        // The guest-writable subtree has its own size limit.
+        let filling = (0..32).map(|_| "X").collect::<String>();
+        assert_eq!(
+            mmds.put_guest_data("/guest/f", Value::from(filling))
+                .unwrap_err()
+                .to_string(),
+            Error::DataStoreLimitExceeded.to_string()
+        );
+        // The host replaces the guest-writable subtree along with the rest of the data store.
+        mmds.put_data(serde_json::from_str(r#"{"host": "data store"}"#).unwrap())
+            .unwrap();
+        assert_eq!(mmds.get_data_str(), r#"{"host":"data store"}"#);
+
+        // The guest-writable subtree doesn't count towards the host size limit.
+        mmds.put_guest_data("/guest/a/b", Value::from(1)).unwrap();
+        mmds.patch_data(serde_json::from_str(r#"{"host": "data stores"}"#).unwrap())
+            .unwrap();
+        assert_eq!(
+            mmds.patch_data(serde_json::from_str(r#"{"more": "host data"}"#).unwrap())
+                .unwrap_err()
+                .to_string(),
+            Error::DataStoreLimitExceeded.to_string()
+        );
+        assert_eq!(
+            mmds.get_data_str(),
+            r#"{"guest":{"a":{"b":1}},"host":"data stores"}"#
+        );
+    }
+
     #[test]
     fn test_is_valid() {
         let mut mmds = Mmds::default();
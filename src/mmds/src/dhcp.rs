@@ -0,0 +1,595 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A DHCPv4 server which hands out a single, configured address to the guest behind a net
+//! device.
+//!
+//! Like the MMDS network stack, the server sits on the TX path of the device: it picks the DHCP
+//! requests out of the frames sent by the guest, and the device injects its replies on the RX
+//! path, before the frames coming from the host. Requests are recognized by their UDP ports, so
+//! renewals unicast to the server address are answered as well, whatever that address resolves
+//! to on the link.
+
+use std::net::Ipv4Addr;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+use dumbo::pdu::dhcp::{self, DhcpMessage, DhcpOption, MessageType};
+use dumbo::pdu::ethernet::{Error as EthernetFrameError, EthernetFrame, ETHERTYPE_IPV4};
+use dumbo::pdu::ipv4::{Error as IPv4PacketError, IPv4Packet, PROTOCOL_UDP};
+use dumbo::pdu::udp::{Error as UdpDatagramError, UdpDatagram};
+use logger::{IncMetric, METRICS};
+use serde::{Deserialize, Serialize};
+use utils::net::mac::MacAddr;
+
+use crate::ns::MmdsNetworkStack;
+
+const DEFAULT_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+const DEFAULT_LEASE_TIME: u32 = 86400;
+// The smallest MTU an IPv4 host has to support.
+const MIN_MTU: u16 = 68;
+// The DNS servers option holds at most 255 bytes.
+const MAX_DNS_SERVERS: usize = 63;
+// Large enough for the replies of the server.
+const REPLY_MAX_LEN: usize = 576;
+
+/// Errors associated with the configuration of the DHCP server.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The address handed out to the guest can't be assigned to an interface.
+    InvalidAddress,
+    /// The subnet mask isn't made of contiguous ones.
+    InvalidNetmask,
+    /// The gateway is outside of the subnet of the guest.
+    GatewayOutsideSubnet,
+    /// More DNS servers than a DHCP option can hold.
+    TooManyDnsServers,
+    /// The MTU is smaller than the one every IPv4 host has to support.
+    InvalidMtu,
+    /// The lease time is zero.
+    InvalidLeaseTime,
+}
+
+#[derive(derive_more::From)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+enum WriteReplyError {
+    Ethernet(EthernetFrameError),
+    IPv4Packet(IPv4PacketError),
+    UdpDatagram(UdpDatagramError),
+}
+
+/// The network configuration handed out to the guest through DHCP.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DhcpConfig {
+    /// The address of the guest.
+    pub ipv4_address: Ipv4Addr,
+    /// The subnet mask of the guest. Defaults to `255.255.255.0`.
+    #[serde(default = "default_netmask")]
+    pub netmask: Ipv4Addr,
+    /// The default gateway of the guest. When present, it's also the address the server
+    /// identifies itself with.
+    #[serde(default)]
+    pub gateway: Option<Ipv4Addr>,
+    /// The DNS servers of the guest, in order of preference.
+    #[serde(default)]
+    pub dns_servers: Vec<Ipv4Addr>,
+    /// The MTU of the guest interface. Left to the guest when missing.
+    #[serde(default)]
+    pub mtu: Option<u16>,
+    /// The lease time, in seconds. Defaults to one day.
+    #[serde(default = "default_lease_time")]
+    pub lease_time: u32,
+}
+
+fn default_netmask() -> Ipv4Addr {
+    DEFAULT_NETMASK
+}
+
+fn default_lease_time() -> u32 {
+    DEFAULT_LEASE_TIME
+}
+
+impl DhcpConfig {
+    fn validate(&self) -> Result<(), Error> {
+        let addr = self.ipv4_address;
+        if addr.is_unspecified() || addr.is_broadcast() || addr.is_multicast() || addr.is_loopback()
+        {
+            return Err(Error::InvalidAddress);
+        }
+        let mask = u32::from(self.netmask);
+        if mask.leading_ones() + mask.trailing_zeros() != 32 {
+            return Err(Error::InvalidNetmask);
+        }
+        if let Some(gateway) = self.gateway {
+            if u32::from(gateway) & mask != u32::from(addr) & mask || gateway == addr {
+                return Err(Error::GatewayOutsideSubnet);
+            }
+        }
+        if self.dns_servers.len() > MAX_DNS_SERVERS {
+            return Err(Error::TooManyDnsServers);
+        }
+        if self.mtu.map_or(false, |mtu| mtu < MIN_MTU) {
+            return Err(Error::InvalidMtu);
+        }
+        if self.lease_time == 0 {
+            return Err(Error::InvalidLeaseTime);
+        }
+        Ok(())
+    }
+}
+
+/// The binding of the configured address to a client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lease {
+    /// The MAC address of the client holding the lease.
+    pub client_mac: MacAddr,
+    /// The time the lease runs out at.
+    pub expires_at: Instant,
+}
+
+// A reply waiting to be written on the RX path.
+struct PendingReply {
+    dst_mac: MacAddr,
+    dst_addr: Ipv4Addr,
+    payload: Vec<u8>,
+}
+
+/// A DHCP server which answers the guest behind a single net device.
+pub struct DhcpServer {
+    config: DhcpConfig,
+    // The address the replies come from, and which the server identifies itself with.
+    server_addr: Ipv4Addr,
+    pub(crate) lease: Option<Lease>,
+    pending_reply: Option<PendingReply>,
+}
+
+impl DhcpServer {
+    /// Creates a server which hands out the given configuration, after validating it.
+    pub fn new(config: DhcpConfig) -> Result<Self, Error> {
+        config.validate()?;
+        let server_addr = config
+            .gateway
+            .unwrap_or_else(MmdsNetworkStack::default_ipv4_addr);
+        Ok(DhcpServer {
+            config,
+            server_addr,
+            lease: None,
+            pending_reply: None,
+        })
+    }
+
+    /// Provides the configuration handed out by the server.
+    pub fn config(&self) -> &DhcpConfig {
+        &self.config
+    }
+
+    /// Provides the current lease, if the configured address is bound to a client.
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+
+    // Whether the address is bound to another client than `client_mac`.
+    fn leased_to_other(&self, client_mac: MacAddr, now: Instant) -> bool {
+        self.lease.map_or(false, |lease| {
+            lease.client_mac != client_mac && lease.expires_at > now
+        })
+    }
+
+    /// Handles a frame sent by the guest, and returns whether it was a DHCP request. The `src`
+    /// slice should hold the contents of an Ethernet frame, of that exact size.
+    pub fn detour_frame(&mut self, src: &[u8]) -> bool {
+        let eth = match EthernetFrame::from_bytes(src) {
+            Ok(eth) if eth.ethertype() == ETHERTYPE_IPV4 => eth,
+            _ => return false,
+        };
+        // The checksums are not verified, in case the guest driver offloads their computation.
+        let packet = match IPv4Packet::from_bytes(eth.payload(), false) {
+            Ok(packet) if packet.protocol() == PROTOCOL_UDP => packet,
+            _ => return false,
+        };
+        let datagram = match UdpDatagram::from_bytes(packet.payload(), None) {
+            Ok(datagram)
+                if datagram.source_port() == dhcp::CLIENT_PORT
+                    && datagram.destination_port() == dhcp::SERVER_PORT =>
+            {
+                datagram
+            }
+            _ => return false,
+        };
+
+        METRICS.net.dhcp_requests_count.inc();
+        match DhcpMessage::from_bytes(datagram.payload()) {
+            Ok(request) if request.op() == dhcp::OP_BOOTREQUEST => {
+                self.handle_request(&request, Instant::now())
+            }
+            _ => METRICS.net.dhcp_invalid_requests.inc(),
+        }
+        // The request was meant for the server anyway.
+        true
+    }
+
+    fn handle_request(&mut self, request: &DhcpMessage<&[u8]>, now: Instant) {
+        let client_mac = request.chaddr();
+        let message_type = match request.message_type() {
+            Some(MessageType::Discover) => {
+                if self.leased_to_other(client_mac, now) {
+                    return;
+                }
+                MessageType::Offer
+            }
+            Some(MessageType::Request) => {
+                // The client selected another server.
+                if request
+                    .server_id()
+                    .map_or(false, |server_id| server_id != self.server_addr)
+                {
+                    return;
+                }
+                let requested_addr = request
+                    .requested_ip_addr()
+                    .unwrap_or_else(|| request.ciaddr());
+                if requested_addr == self.config.ipv4_address
+                    && !self.leased_to_other(client_mac, now)
+                {
+                    self.lease = Some(Lease {
+                        client_mac,
+                        expires_at: now + Duration::from_secs(u64::from(self.config.lease_time)),
+                    });
+                    MessageType::Ack
+                } else {
+                    METRICS.net.dhcp_naks_count.inc();
+                    MessageType::Nak
+                }
+            }
+            Some(MessageType::Decline) | Some(MessageType::Release) => {
+                if self
+                    .lease
+                    .map_or(false, |lease| lease.client_mac == client_mac)
+                {
+                    self.lease = None;
+                }
+                return;
+            }
+            Some(MessageType::Inform) => MessageType::Ack,
+            _ => {
+                METRICS.net.dhcp_invalid_requests.inc();
+                return;
+            }
+        };
+        self.prepare_reply(request, message_type);
+    }
+
+    fn prepare_reply(&mut self, request: &DhcpMessage<&[u8]>, message_type: MessageType) {
+        let mut options = vec![
+            DhcpOption::MessageType(message_type),
+            DhcpOption::ServerId(self.server_addr),
+        ];
+        // The clients which only ask for parameters already have an address.
+        let is_inform = request.message_type() == Some(MessageType::Inform);
+        let yiaddr = if message_type == MessageType::Nak || is_inform {
+            Ipv4Addr::UNSPECIFIED
+        } else {
+            options.push(DhcpOption::LeaseTime(self.config.lease_time));
+            self.config.ipv4_address
+        };
+        if message_type != MessageType::Nak {
+            options.push(DhcpOption::SubnetMask(self.config.netmask));
+            if let Some(gateway) = self.config.gateway {
+                options.push(DhcpOption::Router(gateway));
+            }
+            if !self.config.dns_servers.is_empty() {
+                options.push(DhcpOption::DnsServers(&self.config.dns_servers));
+            }
+            if let Some(mtu) = self.config.mtu {
+                options.push(DhcpOption::InterfaceMtu(mtu));
+            }
+        }
+
+        let mut payload = vec![0u8; REPLY_MAX_LEN];
+        let len = match DhcpMessage::write_reply(
+            payload.as_mut_slice(),
+            request,
+            yiaddr,
+            self.server_addr,
+            &options,
+        ) {
+            Ok(reply) => reply.len(),
+            Err(_) => {
+                METRICS.net.dhcp_invalid_requests.inc();
+                return;
+            }
+        };
+        payload.truncate(len);
+
+        // The client can't receive unicast datagrams before it's configured.
+        let dst_addr =
+            if message_type == MessageType::Nak || request.ciaddr() == Ipv4Addr::UNSPECIFIED {
+                Ipv4Addr::BROADCAST
+            } else {
+                request.ciaddr()
+            };
+        self.pending_reply = Some(PendingReply {
+            dst_mac: request.chaddr(),
+            dst_addr,
+            payload,
+        });
+    }
+
+    /// Writes the pending reply of the server to `buf`, if there is one. Returns the length of
+    /// the frame, or `None` when the buffer can be used for something else.
+    pub fn write_next_frame(&mut self, buf: &mut [u8]) -> Option<NonZeroUsize> {
+        let reply = self.pending_reply.take()?;
+        match self.write_reply(buf, &reply) {
+            Ok(len) => {
+                METRICS.net.dhcp_replies_count.inc();
+                NonZeroUsize::new(len)
+            }
+            Err(_) => {
+                METRICS.net.dhcp_invalid_requests.inc();
+                None
+            }
+        }
+    }
+
+    fn write_reply(&self, buf: &mut [u8], reply: &PendingReply) -> Result<usize, WriteReplyError> {
+        let mut eth = EthernetFrame::write_incomplete(
+            buf,
+            reply.dst_mac,
+            MmdsNetworkStack::default_mac_addr(),
+            ETHERTYPE_IPV4,
+        )?;
+        let mut packet = IPv4Packet::write_header(
+            eth.inner_mut().payload_mut(),
+            PROTOCOL_UDP,
+            self.server_addr,
+            reply.dst_addr,
+        )?;
+        let udp_len = UdpDatagram::write_incomplete_datagram(
+            packet.inner_mut().payload_mut(),
+            &reply.payload,
+        )?
+        .finalize(
+            dhcp::SERVER_PORT,
+            dhcp::CLIENT_PORT,
+            Some((self.server_addr, reply.dst_addr)),
+        )
+        .len();
+        let packet_len = packet
+            .with_payload_len_unchecked(usize::from(udp_len), true)
+            .len();
+        Ok(eth.with_payload_len_unchecked(packet_len).len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dumbo::pdu::ethernet::PAYLOAD_OFFSET;
+    use dumbo::pdu::udp::UDP_HEADER_SIZE;
+
+    use super::*;
+
+    const IPV4_HEADER_LEN: usize = 20;
+
+    const CLIENT_MAC: [u8; 6] = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc];
+    const OTHER_MAC: [u8; 6] = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbd];
+
+    fn config() -> DhcpConfig {
+        DhcpConfig {
+            ipv4_address: Ipv4Addr::new(172, 16, 0, 2),
+            netmask: Ipv4Addr::new(255, 255, 255, 252),
+            gateway: Some(Ipv4Addr::new(172, 16, 0, 1)),
+            dns_servers: vec![Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::new(8, 8, 4, 4)],
+            mtu: Some(1400),
+            lease_time: 3600,
+        }
+    }
+
+    // Writes a frame holding a client request with the given options, written as raw bytes.
+    fn write_request(buf: &mut [u8], client_mac: [u8; 6], options: &[u8]) -> usize {
+        let mut request = [0u8; 300];
+        request[0] = dhcp::OP_BOOTREQUEST;
+        request[1] = 1;
+        request[2] = 6;
+        request[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        request[28..34].copy_from_slice(&client_mac);
+        request[236..240].copy_from_slice(&[99, 130, 83, 99]);
+        request[240..240 + options.len()].copy_from_slice(options);
+
+        let src_mac = MacAddr::from_bytes_unchecked(&client_mac);
+        let mut eth = EthernetFrame::write_incomplete(
+            buf,
+            MacAddr::from_bytes_unchecked(&[0xff; 6]),
+            src_mac,
+            ETHERTYPE_IPV4,
+        )
+        .unwrap();
+        let mut packet = IPv4Packet::write_header(
+            eth.inner_mut().payload_mut(),
+            PROTOCOL_UDP,
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::BROADCAST,
+        )
+        .unwrap();
+        let udp_len =
+            UdpDatagram::write_incomplete_datagram(packet.inner_mut().payload_mut(), &request)
+                .unwrap()
+                .finalize(dhcp::CLIENT_PORT, dhcp::SERVER_PORT, None)
+                .len();
+        let packet_len = packet
+            .with_payload_len_unchecked(usize::from(udp_len), true)
+            .len();
+        eth.with_payload_len_unchecked(packet_len).len()
+    }
+
+    // Sends a request to the server, and returns the type and the address of the reply.
+    fn exchange(
+        server: &mut DhcpServer,
+        client_mac: [u8; 6],
+        options: &[u8],
+    ) -> Option<(MessageType, Ipv4Addr)> {
+        let mut frame = [0u8; 1000];
+        let mut buf = [0u8; 1000];
+        let len = write_request(&mut frame, client_mac, options);
+        assert!(server.detour_frame(&frame[..len]));
+
+        let len = server.write_next_frame(&mut buf)?.get();
+        let eth = EthernetFrame::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(eth.dst_mac().get_bytes(), &client_mac);
+        let packet = IPv4Packet::from_bytes(eth.payload(), true).unwrap();
+        assert_eq!(packet.source_address(), Ipv4Addr::new(172, 16, 0, 1));
+        assert_eq!(packet.destination_address(), Ipv4Addr::BROADCAST);
+        let datagram = UdpDatagram::from_bytes(
+            packet.payload(),
+            Some((packet.source_address(), packet.destination_address())),
+        )
+        .unwrap();
+        assert_eq!(datagram.source_port(), dhcp::SERVER_PORT);
+        assert_eq!(datagram.destination_port(), dhcp::CLIENT_PORT);
+        let reply = DhcpMessage::from_bytes(datagram.payload()).unwrap();
+        assert_eq!(reply.xid(), 0x0102_0304);
+        assert_eq!(reply.server_id(), Some(Ipv4Addr::new(172, 16, 0, 1)));
+        if reply.message_type() != Some(MessageType::Nak) {
+            assert_eq!(reply.option(1), Some(&[255, 255, 255, 252][..]));
+            assert_eq!(reply.option(3), Some(&[172, 16, 0, 1][..]));
+            assert_eq!(reply.option(6), Some(&[8, 8, 8, 8, 8, 8, 4, 4][..]));
+            assert_eq!(reply.option(26), Some(&1400u16.to_be_bytes()[..]));
+        }
+        Some((reply.message_type().unwrap(), reply.yiaddr()))
+    }
+
+    #[test]
+    fn test_config() {
+        assert!(DhcpServer::new(config()).is_ok());
+
+        let mut cfg = config();
+        cfg.ipv4_address = Ipv4Addr::BROADCAST;
+        assert_eq!(DhcpServer::new(cfg).err(), Some(Error::InvalidAddress));
+
+        let mut cfg = config();
+        cfg.netmask = Ipv4Addr::new(255, 0, 255, 0);
+        assert_eq!(DhcpServer::new(cfg).err(), Some(Error::InvalidNetmask));
+
+        let mut cfg = config();
+        cfg.gateway = Some(Ipv4Addr::new(172, 16, 0, 5));
+        assert_eq!(
+            DhcpServer::new(cfg).err(),
+            Some(Error::GatewayOutsideSubnet)
+        );
+
+        let mut cfg = config();
+        cfg.dns_servers = vec![Ipv4Addr::LOCALHOST; MAX_DNS_SERVERS + 1];
+        assert_eq!(DhcpServer::new(cfg).err(), Some(Error::TooManyDnsServers));
+
+        let mut cfg = config();
+        cfg.mtu = Some(MIN_MTU - 1);
+        assert_eq!(DhcpServer::new(cfg).err(), Some(Error::InvalidMtu));
+
+        let mut cfg = config();
+        cfg.lease_time = 0;
+        assert_eq!(DhcpServer::new(cfg).err(), Some(Error::InvalidLeaseTime));
+
+        // Without a gateway, the server uses the default MMDS address.
+        let mut cfg = config();
+        cfg.gateway = None;
+        let server = DhcpServer::new(cfg).unwrap();
+        assert_eq!(server.server_addr, MmdsNetworkStack::default_ipv4_addr());
+
+        let cfg: DhcpConfig = serde_json::from_str(r#"{"ipv4_address": "10.0.0.2"}"#).unwrap();
+        assert_eq!(cfg.netmask, DEFAULT_NETMASK);
+        assert_eq!(cfg.lease_time, DEFAULT_LEASE_TIME);
+        assert!(cfg.gateway.is_none() && cfg.dns_servers.is_empty() && cfg.mtu.is_none());
+    }
+
+    #[test]
+    fn test_detour_frame() {
+        let mut server = DhcpServer::new(config()).unwrap();
+        let mut frame = [0u8; 1000];
+
+        // Not even an Ethernet frame.
+        assert!(!server.detour_frame(&frame[..10]));
+
+        // A datagram which isn't heading to the server port.
+        let len = write_request(&mut frame, CLIENT_MAC, &[53, 1, 1, 255]);
+        frame[PAYLOAD_OFFSET + IPV4_HEADER_LEN + 3] = 68;
+        assert!(!server.detour_frame(&frame[..len]));
+
+        // A malformed request is still consumed, but not answered.
+        let len = write_request(&mut frame, CLIENT_MAC, &[53, 1, 1, 255]);
+        frame[PAYLOAD_OFFSET + IPV4_HEADER_LEN + UDP_HEADER_SIZE + 1] = 2;
+        assert!(server.detour_frame(&frame[..len]));
+        assert!(server.write_next_frame(&mut [0u8; 1000]).is_none());
+    }
+
+    #[test]
+    fn test_lease() {
+        let mut server = DhcpServer::new(config()).unwrap();
+        let addr = Ipv4Addr::new(172, 16, 0, 2);
+        let discover = [53, 1, 1, 255];
+        let request = [53, 1, 3, 50, 4, 172, 16, 0, 2, 255];
+
+        assert_eq!(
+            exchange(&mut server, CLIENT_MAC, &discover),
+            Some((MessageType::Offer, addr))
+        );
+        assert!(server.lease().is_none());
+        assert_eq!(
+            exchange(&mut server, CLIENT_MAC, &request),
+            Some((MessageType::Ack, addr))
+        );
+        assert_eq!(
+            server.lease().unwrap().client_mac.get_bytes(),
+            &CLIENT_MAC[..]
+        );
+
+        // A REQUEST for another address.
+        assert_eq!(
+            exchange(
+                &mut server,
+                CLIENT_MAC,
+                &[53, 1, 3, 50, 4, 172, 16, 0, 3, 255]
+            ),
+            Some((MessageType::Nak, Ipv4Addr::UNSPECIFIED))
+        );
+        // A REQUEST which selects another server.
+        assert_eq!(
+            exchange(
+                &mut server,
+                CLIENT_MAC,
+                &[53, 1, 3, 54, 4, 172, 16, 0, 3, 255]
+            ),
+            None
+        );
+
+        // The address is bound to the first client.
+        assert_eq!(exchange(&mut server, OTHER_MAC, &discover), None);
+        assert_eq!(
+            exchange(&mut server, OTHER_MAC, &request),
+            Some((MessageType::Nak, Ipv4Addr::UNSPECIFIED))
+        );
+
+        // Until it runs out.
+        server.lease.as_mut().unwrap().expires_at = Instant::now();
+        assert_eq!(
+            exchange(&mut server, OTHER_MAC, &discover),
+            Some((MessageType::Offer, addr))
+        );
+
+        // Or the client releases it. Releases from other clients are ignored.
+        assert_eq!(
+            exchange(&mut server, CLIENT_MAC, &request).unwrap().0,
+            MessageType::Ack
+        );
+        assert_eq!(exchange(&mut server, OTHER_MAC, &[53, 1, 7, 255]), None);
+        assert!(server.lease().is_some());
+        assert_eq!(exchange(&mut server, CLIENT_MAC, &[53, 1, 7, 255]), None);
+        assert!(server.lease().is_none());
+
+        // An INFORM only gets the parameters.
+        assert_eq!(
+            exchange(&mut server, OTHER_MAC, &[53, 1, 8, 255]),
+            Some((MessageType::Ack, Ipv4Addr::UNSPECIFIED))
+        );
+        assert!(server.lease().is_none());
+    }
+}
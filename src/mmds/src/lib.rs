@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod data_store;
+pub mod dhcp;
 pub mod ns;
 pub mod persist;
 mod token;
@@ -27,6 +28,7 @@ pub enum Error {
     NoTokenProvided,
     NoTtlProvided,
     ResourceNotFound(String),
+    TokenRequired(String),
 }
 
 impl fmt::Display for Error {
@@ -48,6 +50,12 @@ impl fmt::Display for Error {
             Error::ResourceNotFound(ref uri) => {
                 write!(f, "{}", format!("Resource not found: {}.", uri))
             }
+            Error::TokenRequired(ref uri) => write!(
+                f,
+                "Resource requires a valid MMDS token: {}. Use `X-metadata-token` header to \
+                 specify the session token.",
+                uri
+            ),
         }
     }
 }
@@ -122,15 +130,28 @@ pub fn convert_to_response(mmds: Arc<Mutex<Mmds>>, request: Request) -> Response
     let mut mmds_guard = mmds.lock().expect("Poisoned lock");
 
     match mmds_guard.version() {
-        MmdsVersion::V1 => respond_to_request_mmdsv1(&mmds_guard, request),
+        MmdsVersion::V1 => respond_to_request_mmdsv1(&mut mmds_guard, request),
         MmdsVersion::V2 => respond_to_request_mmdsv2(&mut mmds_guard, request),
     }
 }
 
-fn respond_to_request_mmdsv1(mmds: &Mmds, request: Request) -> Response {
-    // Allow only GET requests.
+fn respond_to_request_mmdsv1(mmds: &mut Mmds, request: Request) -> Response {
+    // Session tokens are optional with MMDS V1, and only grant access to the token-protected
+    // subtrees, so malformed token headers are ignored.
+    let token_headers =
+        TokenHeaders::try_from(request.headers.custom_entries()).unwrap_or_default();
+
+    // Allow only GET requests, and PUT requests when the guest can get tokens or write data.
     match request.method() {
-        Method::Get => respond_to_get_request_unchecked(mmds, request),
+        Method::Get => {
+            let token_valid = token_headers
+                .x_metadata_token()
+                .map_or(false, |token| mmds.is_valid_token(token).unwrap_or(false));
+            respond_to_get_request_unchecked(mmds, request, token_valid)
+        }
+        Method::Put if mmds.uses_tokens() || mmds.guest_writable_path().is_some() => {
+            respond_to_put_request(mmds, request, token_headers)
+        }
         _ => {
             let mut response = build_response(
                 request.http_version(),
@@ -193,7 +214,7 @@ fn respond_to_get_request_checked(
 
     // Validate MMDS token.
     match mmds.is_valid_token(token) {
-        Ok(true) => respond_to_get_request_unchecked(mmds, request),
+        Ok(true) => respond_to_get_request_unchecked(mmds, request, true),
         Ok(false) => build_response(
             request.http_version(),
             StatusCode::Unauthorized,
@@ -203,14 +224,14 @@ fn respond_to_get_request_checked(
     }
 }
 
-fn respond_to_get_request_unchecked(mmds: &Mmds, request: Request) -> Response {
+fn respond_to_get_request_unchecked(mmds: &Mmds, request: Request, token_valid: bool) -> Response {
     let uri = request.uri().get_abs_path();
 
     // The data store expects a strict json path, so we need to
     // sanitize the URI.
     let json_path = sanitize_uri(uri.to_string());
 
-    match mmds.get_value(json_path, request.headers.accept().into()) {
+    match mmds.get_guest_value(json_path, request.headers.accept().into(), token_valid) {
         Ok(response_body) => build_response(
             request.http_version(),
             StatusCode::OK,
@@ -235,6 +256,14 @@ fn respond_to_get_request_unchecked(mmds: &Mmds, request: Request) -> Response {
                 StatusCode::PayloadTooLarge,
                 Body::new(err.to_string()),
             ),
+            MmdsError::TokenRequired => {
+                let error_msg = Error::TokenRequired(String::from(uri)).to_string();
+                build_response(
+                    request.http_version(),
+                    StatusCode::Unauthorized,
+                    Body::new(error_msg),
+                )
+            }
             _ => unreachable!(),
         },
     }
@@ -266,7 +295,11 @@ fn respond_to_put_request(
     // Sanitize the URI into a strict json path.
     let json_path = sanitize_uri(uri.to_string());
 
-    // Only accept PUT requests towards TOKEN_PATH.
+    if mmds.is_guest_writable(&json_path) {
+        return respond_to_guest_write_request(mmds, request, token_headers, json_path);
+    }
+
+    // Otherwise, only accept PUT requests towards TOKEN_PATH.
     if json_path != PATH_TO_TOKEN {
         let error_msg = Error::ResourceNotFound(String::from(uri)).to_string();
         return build_response(
@@ -305,11 +338,58 @@ fn respond_to_put_request(
     }
 }
 
+fn respond_to_guest_write_request(
+    mmds: &mut Mmds,
+    request: Request,
+    token_headers: TokenHeaders,
+    json_path: String,
+) -> Response {
+    // With MMDS V2, guest writes need a valid token, just like reads.
+    if mmds.version() == MmdsVersion::V2 {
+        let error = match token_headers.x_metadata_token() {
+            None => Some(Error::NoTokenProvided),
+            Some(token) if !mmds.is_valid_token(token).unwrap_or(false) => {
+                Some(Error::InvalidToken)
+            }
+            Some(_) => None,
+        };
+        if let Some(err) = error {
+            return build_response(
+                request.http_version(),
+                StatusCode::Unauthorized,
+                Body::new(err.to_string()),
+            );
+        }
+    }
+
+    // The body is stored as JSON when it parses as such, and as a string otherwise.
+    let value = match request.body.as_ref() {
+        Some(body) => serde_json::from_slice(body.raw())
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body.raw()).into_owned())),
+        None => Value::String(String::new()),
+    };
+
+    match mmds.put_guest_data(&json_path, value) {
+        Ok(()) => Response::new(request.http_version(), StatusCode::NoContent),
+        Err(err @ MmdsError::DataStoreLimitExceeded) => build_response(
+            request.http_version(),
+            StatusCode::PayloadTooLarge,
+            Body::new(err.to_string()),
+        ),
+        Err(err) => build_response(
+            request.http_version(),
+            StatusCode::BadRequest,
+            Body::new(err.to_string()),
+        ),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::Duration;
 
     use super::*;
+    use crate::data_store::{MmdsAccess, MmdsAclRule};
     use crate::token::{MAX_TOKEN_TTL_SECONDS, MIN_TOKEN_TTL_SECONDS};
 
     fn populate_mmds() -> Arc<Mutex<Mmds>> {
@@ -633,6 +713,195 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_respond_to_request_acl() {
+        let mmds = populate_mmds();
+        mmds.lock()
+            .expect("Poisoned lock")
+            .set_acl(vec![
+                MmdsAclRule {
+                    path: "/name/second".to_string(),
+                    access: MmdsAccess::Hidden,
+                },
+                MmdsAclRule {
+                    path: "/phones".to_string(),
+                    access: MmdsAccess::TokenRequired,
+                },
+            ])
+            .unwrap();
+        // Token-protected subtrees need tokens, even with MMDS V1.
+        assert_eq!(
+            mmds.lock().expect("Poisoned lock").version(),
+            MmdsVersion::V1
+        );
+        assert!(mmds.lock().expect("Poisoned lock").uses_tokens());
+
+        // Hidden subtrees are left out, and look like they don't exist.
+        let request_bytes = b"GET http://169.254.169.254/name HTTP/1.0\r\n\
+                                    Accept: application/json\r\n\r\n";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let mut expected_response = Response::new(Version::Http10, StatusCode::OK);
+        expected_response.set_body(Body::new(r#"{"first":"John"}"#));
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response, expected_response);
+
+        let request_bytes = b"GET http://169.254.169.254/name/second HTTP/1.0\r\n\r\n";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let mut expected_response = Response::new(Version::Http10, StatusCode::NotFound);
+        expected_response.set_body(Body::new(
+            Error::ResourceNotFound(String::from("/name/second")).to_string(),
+        ));
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response, expected_response);
+
+        // Token-protected subtrees are left out without a valid token.
+        let request_bytes = b"GET http://169.254.169.254/ HTTP/1.0\r\n\
+                                    Accept: application/json\r\n\r\n";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let mut expected_response = Response::new(Version::Http10, StatusCode::OK);
+        expected_response.set_body(Body::new(r#"{"age":43,"name":{"first":"John"}}"#));
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response, expected_response);
+
+        let request_bytes = b"GET http://169.254.169.254/phones/mobile HTTP/1.0\r\n\
+                                    X-metadata-token: foo\r\n\r\n";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let mut expected_response = Response::new(Version::Http10, StatusCode::Unauthorized);
+        expected_response.set_body(Body::new(
+            Error::TokenRequired(String::from("/phones/mobile")).to_string(),
+        ));
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response, expected_response);
+
+        // The guest can get a token, even with MMDS V1.
+        let request_bytes = b"PUT http://169.254.169.254/latest/api/token HTTP/1.0\r\n\
+                                    X-metadata-token-ttl-seconds: 60\r\n\r\n";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response.status(), StatusCode::OK);
+        let valid_token = String::from_utf8(actual_response.body().unwrap().body).unwrap();
+
+        let request_bytes = format!(
+            "GET http://169.254.169.254/phones/mobile HTTP/1.0\r\nX-metadata-token: {}\r\n\r\n",
+            valid_token
+        );
+        let request = Request::try_from(request_bytes.as_bytes(), None).unwrap();
+        let mut expected_response = Response::new(Version::Http10, StatusCode::OK);
+        expected_response.set_body(Body::new("+442345678"));
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response, expected_response);
+
+        // Hidden subtrees stay hidden with a valid token.
+        let request_bytes = format!(
+            "GET http://169.254.169.254/name/second HTTP/1.0\r\nX-metadata-token: {}\r\n\r\n",
+            valid_token
+        );
+        let request = Request::try_from(request_bytes.as_bytes(), None).unwrap();
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response.status(), StatusCode::NotFound);
+
+        // Other PUT requests are not found.
+        let request_bytes = b"PUT http://169.254.169.254/name HTTP/1.0\r\n\r\n";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let actual_response = convert_to_response(mmds, request);
+        assert_eq!(actual_response.status(), StatusCode::NotFound);
+    }
+
+    #[test]
+    fn test_respond_to_guest_write_request() {
+        let mmds = populate_mmds();
+        mmds.lock()
+            .expect("Poisoned lock")
+            .set_guest_writable_path(Some("/guest/".to_string()))
+            .unwrap();
+        mmds.lock().expect("Poisoned lock").set_data_store_limit(64);
+
+        // JSON bodies are stored as such, and other bodies as strings.
+        let request_bytes = b"PUT http://169.254.169.254/guest/status HTTP/1.0\r\n\
+                                    Content-Length: 5\r\n\r\nready";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let expected_response = Response::new(Version::Http10, StatusCode::NoContent);
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response, expected_response);
+
+        let request_bytes = b"PUT http://169.254.169.254/guest/boot HTTP/1.0\r\n\
+                                    Content-Length: 12\r\n\r\n{\"time\":1.5}";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response.status(), StatusCode::NoContent);
+        assert_eq!(
+            mmds.lock().expect("Poisoned lock").data_store_value()["guest"].to_string(),
+            r#"{"boot":{"time":1.5},"status":"ready"}"#
+        );
+
+        // The guest-writable subtree has its own size limit.
+        let request_bytes = b"PUT http://169.254.169.254/guest/log HTTP/1.0\r\n\
+                                    Content-Length: 32\r\n\r\nsome rather long guest log entry";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let mut expected_response = Response::new(Version::Http10, StatusCode::PayloadTooLarge);
+        expected_response.set_body(Body::new(MmdsError::DataStoreLimitExceeded.to_string()));
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response, expected_response);
+
+        // Writes are only accepted in the guest-writable subtree.
+        let request_bytes = b"PUT http://169.254.169.254/guests HTTP/1.0\r\n\
+                                    Content-Length: 2\r\n\r\n{}";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let mut expected_response = Response::new(Version::Http10, StatusCode::NotFound);
+        expected_response.set_body(Body::new(
+            Error::ResourceNotFound(String::from("/guests")).to_string(),
+        ));
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response, expected_response);
+
+        // Unsupported `X-Forwarded-For` header present.
+        let request_bytes = b"PUT http://169.254.169.254/guest/status HTTP/1.0\r\n\
+                                    X-Forwarded-For: 203.0.113.195\r\n\r\n";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response.status(), StatusCode::BadRequest);
+
+        // With MMDS V2, writes need a valid token.
+        mmds.lock()
+            .expect("Poisoned lock")
+            .set_version(MmdsVersion::V2)
+            .unwrap();
+        let request_bytes = b"PUT http://169.254.169.254/guest/status HTTP/1.0\r\n\
+                                    Content-Length: 4\r\n\r\ndone";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let mut expected_response = Response::new(Version::Http10, StatusCode::Unauthorized);
+        expected_response.set_body(Body::new(Error::NoTokenProvided.to_string()));
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response, expected_response);
+
+        let request_bytes = b"PUT http://169.254.169.254/guest/status HTTP/1.0\r\n\
+                                    X-metadata-token: foo\r\n\
+                                    Content-Length: 4\r\n\r\ndone";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let mut expected_response = Response::new(Version::Http10, StatusCode::Unauthorized);
+        expected_response.set_body(Body::new(Error::InvalidToken.to_string()));
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response, expected_response);
+
+        let request_bytes = b"PUT http://169.254.169.254/latest/api/token HTTP/1.0\r\n\
+                                    X-metadata-token-ttl-seconds: 60\r\n\r\n";
+        let request = Request::try_from(request_bytes, None).unwrap();
+        let actual_response = convert_to_response(mmds.clone(), request);
+        let valid_token = String::from_utf8(actual_response.body().unwrap().body).unwrap();
+        let request_bytes = format!(
+            "PUT http://169.254.169.254/guest/status HTTP/1.0\r\nX-metadata-token: \
+             {}\r\nContent-Length: 4\r\n\r\ndone",
+            valid_token
+        );
+        let request = Request::try_from(request_bytes.as_bytes(), None).unwrap();
+        let actual_response = convert_to_response(mmds.clone(), request);
+        assert_eq!(actual_response.status(), StatusCode::NoContent);
+        assert_eq!(
+            mmds.lock().expect("Poisoned lock").data_store_value()["guest"]["status"],
+            "done"
+        );
+    }
+
     #[test]
     fn test_json_patch() {
         let mut data = serde_json::json!({
@@ -712,6 +981,12 @@ mod tests {
         assert_eq!(
             Error::ResourceNotFound(String::from("invalid/")).to_string(),
             "Resource not found: invalid/."
-        )
+        );
+
+        assert_eq!(
+            Error::TokenRequired(String::from("/secret")).to_string(),
+            "Resource requires a valid MMDS token: /secret. Use `X-metadata-token` header to \
+             specify the session token."
+        );
     }
 }
@@ -5,7 +5,7 @@
 #![allow(missing_docs)]
 
 use std::convert::From;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::num::NonZeroUsize;
 use std::result::Result;
 use std::sync::{Arc, Mutex};
@@ -13,15 +13,24 @@ use std::sync::{Arc, Mutex};
 use dumbo::pdu::arp::{
     test_speculative_tpa, Error as ArpFrameError, EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN,
 };
+use dumbo::pdu::bytes::NetworkBytes;
 use dumbo::pdu::ethernet::{
-    Error as EthernetFrameError, EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4,
+    Error as EthernetFrameError, EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4, ETHERTYPE_IPV6,
+};
+use dumbo::pdu::icmpv6::{
+    Error as Icmpv6MessageError, Icmpv6Message, FLAG_OVERRIDE, FLAG_SOLICITED, NDP_HOP_LIMIT,
+    OPTION_SOURCE_LINK_LAYER_ADDR,
 };
 use dumbo::pdu::ipv4::{
     test_speculative_dst_addr, Error as IPv4PacketError, IPv4Packet, PROTOCOL_TCP,
 };
+use dumbo::pdu::ipv6::{
+    self, test_speculative_dst_addr as test_speculative_ipv6_dst_addr, Error as IPv6PacketError,
+    IPv6Packet, IPV6_VERSION, PROTOCOL_ICMPV6,
+};
 use dumbo::pdu::tcp::Error as TcpSegmentError;
 use dumbo::pdu::Incomplete;
-use dumbo::tcp::handler::{RecvEvent, TcpIPv4Handler, WriteEvent, WriteNextError};
+use dumbo::tcp::handler::{RecvError, RecvEvent, TcpIPv4Handler, WriteEvent, WriteNextError};
 use dumbo::tcp::NextSegmentStatus;
 use logger::{IncMetric, METRICS};
 use utils::net::mac::MacAddr;
@@ -34,6 +43,7 @@ const DEFAULT_IPV4_ADDR: [u8; 4] = [169, 254, 169, 254];
 const DEFAULT_TCP_PORT: u16 = 80;
 const DEFAULT_MAX_CONNECTIONS: usize = 30;
 const DEFAULT_MAX_PENDING_RESETS: usize = 100;
+const IPV6_ALL_NODES_ADDR: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
 
 #[derive(derive_more::From)]
 #[cfg_attr(test, derive(Debug, PartialEq))]
@@ -43,6 +53,15 @@ enum WriteArpFrameError {
     Ethernet(EthernetFrameError),
 }
 
+#[derive(derive_more::From)]
+#[cfg_attr(test, derive(Debug, PartialEq))]
+enum WriteNdpFrameError {
+    NoPendingNdpReply,
+    Ethernet(EthernetFrameError),
+    IPv6Packet(IPv6PacketError),
+    Icmpv6(Icmpv6MessageError),
+}
+
 #[derive(derive_more::From)]
 #[cfg_attr(test, derive(Debug, PartialEq))]
 enum WritePacketError {
@@ -63,6 +82,12 @@ pub struct MmdsNetworkStack {
     // It is the Ipv4Addr of the network interface for which the MmdsNetworkStack
     // routes the packets.
     pending_arp_reply_dest: Option<Ipv4Addr>,
+    // MMDS server IPv6 address, when MMDS is also reachable over IPv6.
+    pub ipv6_addr: Option<Ipv6Addr>,
+    // Neighbor advertisement destination IPv6 address (the sender of the neighbor solicitation,
+    // or the all-nodes multicast address when answering duplicate address detection), along with
+    // the advertised MMDS address.
+    pending_ndp_reply: Option<(Ipv6Addr, Ipv6Addr)>,
     // This handles MMDS<->guest interaction at the TCP level.
     pub(crate) tcp_handler: TcpIPv4Handler,
     // Data store reference shared across all MmdsNetworkStack instances.
@@ -83,6 +108,8 @@ impl MmdsNetworkStack {
             mac_addr,
             ipv4_addr,
             pending_arp_reply_dest: None,
+            ipv6_addr: None,
+            pending_ndp_reply: None,
             tcp_handler: TcpIPv4Handler::new(
                 ipv4_addr,
                 tcp_port,
@@ -118,10 +145,25 @@ impl MmdsNetworkStack {
         self.ipv4_addr
     }
 
+    pub fn set_ipv6_addr(&mut self, ipv6_addr: Option<Ipv6Addr>) {
+        self.ipv6_addr = ipv6_addr;
+        self.pending_ndp_reply = None;
+        self.tcp_handler.set_local_ipv6_addr(ipv6_addr);
+    }
+
+    pub fn ipv6_addr(&self) -> Option<Ipv6Addr> {
+        self.ipv6_addr
+    }
+
     pub fn default_ipv4_addr() -> Ipv4Addr {
         Ipv4Addr::from(DEFAULT_IPV4_ADDR)
     }
 
+    pub fn default_mac_addr() -> MacAddr {
+        // The unwrap is safe if parse_str() is implemented properly.
+        MacAddr::parse_str(DEFAULT_MAC_ADDR).unwrap()
+    }
+
     // This is the entry point into the MMDS network stack. The src slice should hold the contents
     // of an Ethernet frame (of that exact size, without the CRC).
     pub fn detour_frame(&mut self, src: &[u8]) -> bool {
@@ -139,6 +181,20 @@ impl MmdsNetworkStack {
                     }
                     return self.detour_ipv4(eth);
                 }
+                ETHERTYPE_IPV6 => {
+                    if let Some(ipv6_addr) = self.ipv6_addr {
+                        // Neighbor solicitations are sent to the solicited-node multicast address.
+                        if test_speculative_ipv6_dst_addr(src, ipv6_addr)
+                            || test_speculative_ipv6_dst_addr(
+                                src,
+                                ipv6::solicited_node_multicast_addr(ipv6_addr),
+                            )
+                        {
+                            return self.detour_ipv6(eth, ipv6_addr);
+                        }
+                    }
+                    return false;
+                }
                 _ => (),
             };
         } else {
@@ -170,27 +226,9 @@ impl MmdsNetworkStack {
                 // each MmdsNetworkStack routes packets for only one network device.
                 self.remote_mac_addr = eth.src_mac();
                 let mmds_instance = self.mmds.clone();
-                match &mut self.tcp_handler.receive_packet(&ip, move |request| {
+                inc_recv_metrics(self.tcp_handler.receive_packet(&ip, move |request| {
                     super::convert_to_response(mmds_instance, request)
-                }) {
-                    Ok(event) => {
-                        METRICS.mmds.rx_count.inc();
-                        match event {
-                            RecvEvent::NewConnectionSuccessful => {
-                                METRICS.mmds.connections_created.inc()
-                            }
-                            RecvEvent::NewConnectionReplacing => {
-                                METRICS.mmds.connections_created.inc();
-                                METRICS.mmds.connections_destroyed.inc();
-                            }
-                            RecvEvent::EndpointDone => {
-                                METRICS.mmds.connections_destroyed.inc();
-                            }
-                            _ => (),
-                        }
-                    }
-                    Err(_) => METRICS.mmds.rx_accepted_err.inc(),
-                }
+                }));
             } else {
                 // A non-TCP IPv4 packet heading towards the MMDS; we consider it unusual.
                 METRICS.mmds.rx_accepted_unusual.inc();
@@ -201,6 +239,77 @@ impl MmdsNetworkStack {
         false
     }
 
+    fn detour_ipv6(&mut self, eth: EthernetFrame<&[u8]>, ipv6_addr: Ipv6Addr) -> bool {
+        if let Ok(ip) = IPv6Packet::from_bytes(eth.payload()) {
+            if ip.destination_address() != ipv6_addr {
+                // The solicited-node multicast group may be shared with other addresses, so only
+                // the neighbor solicitations looking for the MMDS are taken from it.
+                return ip.next_header() == PROTOCOL_ICMPV6
+                    && self.detour_ndp(&ip, eth.src_mac(), ipv6_addr);
+            }
+
+            match ip.next_header() {
+                PROTOCOL_TCP => {
+                    // Same as for IPv4, every routed packet comes from the same network device.
+                    self.remote_mac_addr = eth.src_mac();
+                    let mmds_instance = self.mmds.clone();
+                    inc_recv_metrics(self.tcp_handler.receive_ipv6_packet(&ip, move |request| {
+                        super::convert_to_response(mmds_instance, request)
+                    }));
+                }
+                PROTOCOL_ICMPV6 => {
+                    // ICMPv6 messages other than neighbor solicitations are unusual.
+                    if !self.detour_ndp(&ip, eth.src_mac(), ipv6_addr) {
+                        METRICS.mmds.rx_accepted_unusual.inc();
+                    }
+                }
+                // Any other IPv6 packet heading towards the MMDS; we consider it unusual.
+                _ => METRICS.mmds.rx_accepted_unusual.inc(),
+            }
+            return true;
+        }
+
+        false
+    }
+
+    fn detour_ndp<T: NetworkBytes>(
+        &mut self,
+        ip: &IPv6Packet<T>,
+        src_mac: MacAddr,
+        ipv6_addr: Ipv6Addr,
+    ) -> bool {
+        // Neighbor solicitations which went through a router must be discarded. Unlike for TCP,
+        // the checksum is verified, since guests don't offload it for ICMPv6.
+        if ip.hop_limit() != NDP_HOP_LIMIT {
+            return false;
+        }
+        let src_addr = ip.source_address();
+        if let Ok(ns) = Icmpv6Message::neighbor_solicitation_from_bytes(
+            ip.payload(),
+            Some((src_addr, ip.destination_address())),
+        ) {
+            if ns.target_address() != ipv6_addr {
+                return false;
+            }
+
+            if src_addr.is_unspecified() {
+                // The guest is checking whether the address is in use (duplicate address
+                // detection), and has no address to be answered at yet.
+                self.pending_ndp_reply = Some((IPV6_ALL_NODES_ADDR, ipv6_addr));
+            } else {
+                self.remote_mac_addr = ns
+                    .link_layer_address_option(OPTION_SOURCE_LINK_LAYER_ADDR)
+                    .ok()
+                    .flatten()
+                    .unwrap_or(src_mac);
+                self.pending_ndp_reply = Some((src_addr, ipv6_addr));
+            }
+            return true;
+        }
+
+        false
+    }
+
     // Allows the MMDS network stack to write a frame to the specified buffer. Will return:
     // - None, if the MMDS network stack has no frame to send at this point. The buffer can be
     // used for something else by the device model.
@@ -219,6 +328,18 @@ impl MmdsNetworkStack {
                     None
                 }
             };
+        } else if self.pending_ndp_reply.is_some() {
+            return match self.write_ndp_reply(buf) {
+                Ok(something) => {
+                    METRICS.mmds.tx_count.inc();
+                    self.pending_ndp_reply = None;
+                    something
+                }
+                Err(_) => {
+                    METRICS.mmds.tx_errors.inc();
+                    None
+                }
+            };
         } else {
             let call_write = match self.tcp_handler.next_segment_status() {
                 NextSegmentStatus::Available => true,
@@ -276,6 +397,49 @@ impl MmdsNetworkStack {
         ))
     }
 
+    fn write_ndp_reply(&self, buf: &mut [u8]) -> Result<Option<NonZeroUsize>, WriteNdpFrameError> {
+        let (ndp_reply_dest, ipv6_addr) = self
+            .pending_ndp_reply
+            .ok_or(WriteNdpFrameError::NoPendingNdpReply)?;
+
+        // Advertisements answering duplicate address detection are multicast, and unsolicited.
+        let (dst_mac, flags) = if ndp_reply_dest.is_multicast() {
+            (ipv6::multicast_mac_addr(ndp_reply_dest), FLAG_OVERRIDE)
+        } else {
+            (self.remote_mac_addr, FLAG_SOLICITED | FLAG_OVERRIDE)
+        };
+
+        let mut eth_unsized =
+            EthernetFrame::write_incomplete(buf, dst_mac, self.mac_addr, ETHERTYPE_IPV6)?;
+
+        let packet_len = {
+            let mut packet = IPv6Packet::write_header(
+                eth_unsized.inner_mut().payload_mut(),
+                PROTOCOL_ICMPV6,
+                ipv6_addr,
+                ndp_reply_dest,
+            )?;
+            packet.inner_mut().set_hop_limit(NDP_HOP_LIMIT);
+
+            let message_len = Icmpv6Message::write_neighbor_advertisement(
+                packet.inner_mut().payload_mut(),
+                ipv6_addr,
+                self.mac_addr,
+                flags,
+                ipv6_addr,
+                ndp_reply_dest,
+            )?
+            .len();
+
+            packet.with_payload_len_unchecked(message_len).len()
+        };
+
+        Ok(Some(
+            // The unwrap() is safe because packet_len > 0.
+            NonZeroUsize::new(eth_unsized.with_payload_len_unchecked(packet_len).len()).unwrap(),
+        ))
+    }
+
     fn write_packet(&mut self, buf: &mut [u8]) -> Result<Option<NonZeroUsize>, WritePacketError> {
         let mut eth_unsized = self.prepare_eth_unsized(buf, ETHERTYPE_IPV4)?;
 
@@ -288,6 +452,13 @@ impl MmdsNetworkStack {
         }
 
         if let Some(packet_len) = maybe_len {
+            // The handler replies over IPv6 to connections which were established over IPv6.
+            if IPv6Packet::from_bytes_unchecked(eth_unsized.inner().payload()).version()
+                == IPV6_VERSION
+            {
+                eth_unsized.inner_mut().set_ethertype(ETHERTYPE_IPV6);
+            }
+
             return Ok(Some(
                 // The unwrap() is safe because packet_len > 0.
                 NonZeroUsize::new(
@@ -303,10 +474,31 @@ impl MmdsNetworkStack {
     }
 }
 
+fn inc_recv_metrics(result: Result<RecvEvent, RecvError>) {
+    match result {
+        Ok(event) => {
+            METRICS.mmds.rx_count.inc();
+            match event {
+                RecvEvent::NewConnectionSuccessful => METRICS.mmds.connections_created.inc(),
+                RecvEvent::NewConnectionReplacing => {
+                    METRICS.mmds.connections_created.inc();
+                    METRICS.mmds.connections_destroyed.inc();
+                }
+                RecvEvent::EndpointDone => {
+                    METRICS.mmds.connections_destroyed.inc();
+                }
+                _ => (),
+            }
+        }
+        Err(_) => METRICS.mmds.rx_accepted_err.inc(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
+    use dumbo::pdu::icmpv6::{OPTION_TARGET_LINK_LAYER_ADDR, TYPE_NEIGHBOR_ADVERTISEMENT};
     use dumbo::pdu::tcp::{Flags as TcpFlags, TcpSegment};
 
     use super::*;
@@ -318,6 +510,8 @@ mod tests {
     const MMDS_PORT: u16 = 80;
     const REMOTE_PORT: u16 = 1235;
     const SEQ_NUMBER: u32 = 123;
+    const MMDS_IPV6_ADDR: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0xa9fe, 0xa9fe);
+    const REMOTE_IPV6_ADDR: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
 
     // Helper methods which only make sense for testing.
     impl MmdsNetworkStack {
@@ -383,6 +577,83 @@ mod tests {
             let eth = EthernetFrame::from_bytes(&buf[..len]).unwrap();
             IPv4Packet::from_bytes(&buf[eth.payload_offset()..len], true).unwrap()
         }
+
+        fn write_neighbor_solicitation(
+            &self,
+            buf: &mut [u8],
+            src_addr: Ipv6Addr,
+            target: Ipv6Addr,
+        ) -> usize {
+            let remote_mac = MacAddr::parse_str(REMOTE_MAC_STR).unwrap();
+            let dst_addr = ipv6::solicited_node_multicast_addr(target);
+            let mut eth_unsized = EthernetFrame::write_incomplete(
+                buf,
+                ipv6::multicast_mac_addr(dst_addr),
+                remote_mac,
+                ETHERTYPE_IPV6,
+            )
+            .unwrap();
+            let packet_len = {
+                let mut packet = IPv6Packet::write_header(
+                    eth_unsized.inner_mut().payload_mut(),
+                    PROTOCOL_ICMPV6,
+                    src_addr,
+                    dst_addr,
+                )
+                .unwrap();
+                packet.inner_mut().set_hop_limit(NDP_HOP_LIMIT);
+
+                let message_len = Icmpv6Message::write_neighbor_solicitation(
+                    packet.inner_mut().payload_mut(),
+                    target,
+                    remote_mac,
+                    src_addr,
+                    dst_addr,
+                )
+                .unwrap()
+                .len();
+
+                packet.with_payload_len_unchecked(message_len).len()
+            };
+
+            eth_unsized.with_payload_len_unchecked(packet_len).len()
+        }
+
+        fn write_incoming_ipv6_tcp_segment(
+            &self,
+            buf: &mut [u8],
+            addr: Ipv6Addr,
+            flags: TcpFlags,
+        ) -> usize {
+            let mut eth_unsized = self.prepare_eth_unsized(buf, ETHERTYPE_IPV6).unwrap();
+            let packet_len = {
+                let mut packet = IPv6Packet::write_header(
+                    eth_unsized.inner_mut().payload_mut(),
+                    PROTOCOL_TCP,
+                    REMOTE_IPV6_ADDR,
+                    addr,
+                )
+                .unwrap();
+
+                let segment_len = TcpSegment::write_incomplete_segment::<[u8]>(
+                    packet.inner_mut().payload_mut(),
+                    SEQ_NUMBER,
+                    1234,
+                    flags,
+                    10000,
+                    None,
+                    0,
+                    None,
+                )
+                .unwrap()
+                .finalize_ipv6(REMOTE_PORT, MMDS_PORT, Some((REMOTE_IPV6_ADDR, addr)))
+                .len();
+
+                packet.with_payload_len_unchecked(segment_len).len()
+            };
+
+            eth_unsized.with_payload_len_unchecked(packet_len).len()
+        }
     }
 
     #[test]
@@ -520,6 +791,117 @@ mod tests {
         assert!(ns.write_next_frame(buf.as_mut()).is_none());
     }
 
+    #[test]
+    #[allow(clippy::cognitive_complexity)]
+    fn test_ns_ipv6() {
+        let mut ns =
+            MmdsNetworkStack::new_with_defaults(None, Arc::new(Mutex::new(Mmds::default())));
+        let mut buf = [0u8; 2000];
+        let remote_mac = MacAddr::parse_str(REMOTE_MAC_STR).unwrap();
+
+        // IPv6 is disabled by default.
+        let len = ns.write_neighbor_solicitation(buf.as_mut(), REMOTE_IPV6_ADDR, MMDS_IPV6_ADDR);
+        assert!(!ns.detour_frame(&buf[..len]));
+        let len = ns.write_incoming_ipv6_tcp_segment(buf.as_mut(), MMDS_IPV6_ADDR, TcpFlags::SYN);
+        assert!(!ns.detour_frame(&buf[..len]));
+
+        ns.set_ipv6_addr(Some(MMDS_IPV6_ADDR));
+        assert_eq!(ns.ipv6_addr(), Some(MMDS_IPV6_ADDR));
+        assert_eq!(ns.tcp_handler.local_ipv6_addr(), Some(MMDS_IPV6_ADDR));
+
+        // Not looking for the MMDS.
+        let other_addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0xa9fe, 0xa9ff);
+        let len = ns.write_neighbor_solicitation(buf.as_mut(), REMOTE_IPV6_ADDR, other_addr);
+        assert!(!ns.detour_frame(&buf[..len]));
+        assert!(ns.write_next_frame(buf.as_mut()).is_none());
+
+        // A solicitation for the MMDS address gets a solicited advertisement in response.
+        let len = ns.write_neighbor_solicitation(buf.as_mut(), REMOTE_IPV6_ADDR, MMDS_IPV6_ADDR);
+        assert!(ns.detour_frame(&buf[..len]));
+        assert_eq!(ns.remote_mac_addr, remote_mac);
+        {
+            let len = ns.write_next_frame(buf.as_mut()).unwrap().get();
+            let eth = EthernetFrame::from_bytes(&buf[..len]).unwrap();
+            assert_eq!(eth.ethertype(), ETHERTYPE_IPV6);
+            assert_eq!(eth.dst_mac(), remote_mac);
+            assert_eq!(eth.src_mac(), ns.mac_addr);
+
+            let ip = IPv6Packet::from_bytes(eth.payload()).unwrap();
+            assert_eq!(ip.hop_limit(), NDP_HOP_LIMIT);
+            assert_eq!(ip.source_address(), MMDS_IPV6_ADDR);
+            assert_eq!(ip.destination_address(), REMOTE_IPV6_ADDR);
+
+            let na = Icmpv6Message::from_bytes(
+                ip.payload(),
+                Some((ip.source_address(), ip.destination_address())),
+            )
+            .unwrap();
+            assert_eq!(na.message_type(), TYPE_NEIGHBOR_ADVERTISEMENT);
+            assert_eq!(na.ndp_flags(), FLAG_SOLICITED | FLAG_OVERRIDE);
+            assert_eq!(na.target_address(), MMDS_IPV6_ADDR);
+            assert_eq!(
+                na.link_layer_address_option(OPTION_TARGET_LINK_LAYER_ADDR),
+                Ok(Some(ns.mac_addr))
+            );
+        }
+        assert!(ns.write_next_frame(buf.as_mut()).is_none());
+
+        // Duplicate address detection is answered to all nodes.
+        let len =
+            ns.write_neighbor_solicitation(buf.as_mut(), Ipv6Addr::UNSPECIFIED, MMDS_IPV6_ADDR);
+        assert!(ns.detour_frame(&buf[..len]));
+        {
+            let len = ns.write_next_frame(buf.as_mut()).unwrap().get();
+            let eth = EthernetFrame::from_bytes(&buf[..len]).unwrap();
+            assert_eq!(eth.dst_mac(), ipv6::multicast_mac_addr(IPV6_ALL_NODES_ADDR));
+
+            let ip = IPv6Packet::from_bytes(eth.payload()).unwrap();
+            assert_eq!(ip.destination_address(), IPV6_ALL_NODES_ADDR);
+            let na = Icmpv6Message::from_bytes(ip.payload(), None).unwrap();
+            assert_eq!(na.ndp_flags(), FLAG_OVERRIDE);
+        }
+
+        // A TCP SYN over IPv6 gets a SYNACK over IPv6.
+        let len = ns.write_incoming_ipv6_tcp_segment(buf.as_mut(), MMDS_IPV6_ADDR, TcpFlags::SYN);
+        assert!(ns.detour_frame(&buf[..len]));
+        {
+            let len = ns.write_next_frame(buf.as_mut()).unwrap().get();
+            let eth = EthernetFrame::from_bytes(&buf[..len]).unwrap();
+            assert_eq!(eth.ethertype(), ETHERTYPE_IPV6);
+
+            let ip = IPv6Packet::from_bytes(eth.payload()).unwrap();
+            assert_eq!(ip.source_address(), MMDS_IPV6_ADDR);
+            assert_eq!(ip.destination_address(), REMOTE_IPV6_ADDR);
+
+            let s = TcpSegment::from_bytes(ip.payload(), None).unwrap();
+            assert_eq!(s.flags_after_ns(), TcpFlags::SYN | TcpFlags::ACK);
+            assert_eq!(s.source_port(), MMDS_PORT);
+            assert_eq!(s.destination_port(), REMOTE_PORT);
+            assert_eq!(
+                s.compute_checksum_ipv6(ip.source_address(), ip.destination_address()),
+                0
+            );
+        }
+        assert!(ns.write_next_frame(buf.as_mut()).is_none());
+
+        // IPv4 keeps working alongside.
+        let len = ns.write_incoming_tcp_segment(buf.as_mut(), ns.ipv4_addr, TcpFlags::SYN);
+        assert!(ns.detour_frame(&buf[..len]));
+        assert_eq!(
+            ns.next_frame_as_ipv4_packet(buf.as_mut()).source_address(),
+            ns.ipv4_addr
+        );
+
+        // Other addresses are left alone.
+        let len = ns.write_incoming_ipv6_tcp_segment(buf.as_mut(), other_addr, TcpFlags::SYN);
+        assert!(!ns.detour_frame(&buf[..len]));
+
+        ns.set_ipv6_addr(None);
+        let len = ns.write_incoming_ipv6_tcp_segment(buf.as_mut(), MMDS_IPV6_ADDR, TcpFlags::SYN);
+        assert!(!ns.detour_frame(&buf[..len]));
+        assert_eq!(ns.tcp_handler.local_ipv6_addr(), None);
+    }
+
     #[test]
     fn test_set_ipv4_addr() {
         let mut ns =
@@ -1,16 +1,18 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-//! Defines the structures needed for saving/restoring MmdsNetworkStack.
+//! Defines the structures needed for saving/restoring MmdsNetworkStack and DhcpServer.
 
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use snapshot::Persist;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 
+use super::dhcp::{DhcpConfig, DhcpServer, Error as DhcpError, Lease};
 use super::ns::MmdsNetworkStack;
 use crate::Mmds;
 
@@ -23,6 +25,21 @@ pub struct MmdsNetworkStackState {
     tcp_port: u16,
     max_connections: usize,
     max_pending_resets: usize,
+    #[version(start = 2, ser_fn = "ipv6_addr_ser")]
+    ipv6_addr: Option<[u8; 16]>,
+}
+
+impl MmdsNetworkStackState {
+    fn ipv6_addr_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would leave the guest without an answer on the IPv6 address.
+        if target_version < 2 && self.ipv6_addr.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support MMDS over IPv6.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 impl Persist<'_> for MmdsNetworkStack {
@@ -40,6 +57,7 @@ impl Persist<'_> for MmdsNetworkStack {
             tcp_port: self.tcp_handler.local_port(),
             max_connections: self.tcp_handler.max_connections(),
             max_pending_resets: self.tcp_handler.max_pending_resets(),
+            ipv6_addr: self.ipv6_addr.map(|addr| addr.octets()),
         }
     }
 
@@ -47,14 +65,94 @@ impl Persist<'_> for MmdsNetworkStack {
         mmds: Self::ConstructorArgs,
         state: &Self::State,
     ) -> std::result::Result<Self, Self::Error> {
-        Ok(MmdsNetworkStack::new(
+        let mut ns = MmdsNetworkStack::new(
             MacAddr::from_bytes_unchecked(&state.mac_addr),
             Ipv4Addr::from(state.ipv4_addr),
             state.tcp_port,
             std::num::NonZeroUsize::new(state.max_connections).unwrap(),
             std::num::NonZeroUsize::new(state.max_pending_resets).unwrap(),
             mmds,
-        ))
+        );
+        ns.set_ipv6_addr(state.ipv6_addr.map(Ipv6Addr::from));
+        Ok(ns)
+    }
+}
+
+/// State of the lease of a DhcpServer.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct DhcpLeaseState {
+    client_mac: [u8; MAC_ADDR_LEN],
+    // Saved relative to the time of the snapshot, since the monotonic clock of the host where it
+    // gets restored may be anywhere.
+    remaining_secs: u64,
+}
+
+/// State of a DhcpServer.
+#[derive(Clone, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct DhcpServerState {
+    ipv4_address: u32,
+    netmask: u32,
+    gateway: Option<u32>,
+    dns_servers: Vec<u32>,
+    mtu: Option<u16>,
+    lease_time: u32,
+    lease: Option<DhcpLeaseState>,
+}
+
+impl Persist<'_> for DhcpServer {
+    type State = DhcpServerState;
+    type ConstructorArgs = ();
+    type Error = DhcpError;
+
+    fn save(&self) -> Self::State {
+        let config = self.config();
+        let now = Instant::now();
+        DhcpServerState {
+            ipv4_address: config.ipv4_address.into(),
+            netmask: config.netmask.into(),
+            gateway: config.gateway.map(u32::from),
+            dns_servers: config.dns_servers.iter().copied().map(u32::from).collect(),
+            mtu: config.mtu,
+            lease_time: config.lease_time,
+            // Expired leases are dropped.
+            lease: self
+                .lease()
+                .filter(|lease| lease.expires_at > now)
+                .map(|lease| {
+                    let mut client_mac = [0; MAC_ADDR_LEN];
+                    client_mac.copy_from_slice(lease.client_mac.get_bytes());
+                    DhcpLeaseState {
+                        client_mac,
+                        remaining_secs: (lease.expires_at - now).as_secs(),
+                    }
+                }),
+        }
+    }
+
+    fn restore(
+        _: Self::ConstructorArgs,
+        state: &Self::State,
+    ) -> std::result::Result<Self, Self::Error> {
+        let mut server = DhcpServer::new(DhcpConfig {
+            ipv4_address: Ipv4Addr::from(state.ipv4_address),
+            netmask: Ipv4Addr::from(state.netmask),
+            gateway: state.gateway.map(Ipv4Addr::from),
+            dns_servers: state
+                .dns_servers
+                .iter()
+                .copied()
+                .map(Ipv4Addr::from)
+                .collect(),
+            mtu: state.mtu,
+            lease_time: state.lease_time,
+        })?;
+        server.lease = state.lease.as_ref().map(|lease| Lease {
+            client_mac: MacAddr::from_bytes_unchecked(&lease.client_mac),
+            expires_at: Instant::now() + Duration::from_secs(lease.remaining_secs),
+        });
+        Ok(server)
     }
 }
 
@@ -93,5 +191,80 @@ mod tests {
             restored_ns.tcp_handler.max_pending_resets(),
             ns.tcp_handler.max_pending_resets()
         );
+        assert_eq!(restored_ns.ipv6_addr(), None);
+    }
+
+    #[test]
+    fn test_persistence_ipv6() {
+        let mut ns =
+            MmdsNetworkStack::new_with_defaults(None, Arc::new(Mutex::new(Mmds::default())));
+        let ipv6_addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0xa9fe, 0xa9fe);
+        ns.set_ipv6_addr(Some(ipv6_addr));
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(MmdsNetworkStackState::type_id(), 2);
+
+        // Older versions don't know about the IPv6 address.
+        assert!(ns
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+
+        ns.save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+
+        let restored_ns = MmdsNetworkStack::restore(
+            Arc::new(Mutex::new(Mmds::default())),
+            &MmdsNetworkStackState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(restored_ns.ipv6_addr(), Some(ipv6_addr));
+        assert_eq!(restored_ns.tcp_handler.local_ipv6_addr(), Some(ipv6_addr));
+    }
+    #[test]
+    fn test_dhcp_persistence() {
+        let mut server = DhcpServer::new(DhcpConfig {
+            ipv4_address: Ipv4Addr::new(172, 16, 0, 2),
+            netmask: Ipv4Addr::new(255, 255, 255, 252),
+            gateway: Some(Ipv4Addr::new(172, 16, 0, 1)),
+            dns_servers: vec![Ipv4Addr::new(8, 8, 8, 8)],
+            mtu: Some(1400),
+            lease_time: 3600,
+        })
+        .unwrap();
+        let client_mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        server.lease = Some(Lease {
+            client_mac,
+            expires_at: Instant::now() + Duration::from_secs(600),
+        });
+
+        let mut mem = vec![0; 4096];
+        let version_map = VersionMap::new();
+
+        server
+            .save()
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+
+        let restored_server = DhcpServer::restore(
+            (),
+            &DhcpServerState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(restored_server.config(), server.config());
+        let lease = restored_server.lease().unwrap();
+        assert_eq!(lease.client_mac, client_mac);
+        let remaining = lease.expires_at - Instant::now();
+        assert!(remaining <= Duration::from_secs(600) && remaining > Duration::from_secs(590));
+
+        // Expired leases are not saved.
+        server.lease.as_mut().unwrap().expires_at = Instant::now();
+        assert!(server.save().lease.is_none());
     }
 }
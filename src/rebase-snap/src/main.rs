@@ -13,6 +13,7 @@ const REBASE_SNAP_VERSION: &str = env!("FIRECRACKER_VERSION");
 const EXIT_CODE_SUCCESS: i32 = 0;
 const BASE_FILE: &str = "base-file";
 const DIFF_FILE: &str = "diff-file";
+const SQUASH_SUBCOMMAND: &str = "squash";
 
 #[derive(Debug)]
 enum Error {
@@ -43,28 +44,50 @@ fn build_arg_parser<'a>() -> ArgParser<'a> {
     arg_parser
 }
 
-fn extract_args<'a>(arg_parser: &'a mut ArgParser<'a>) -> &'a Arguments<'a> {
-    arg_parser.parse_from_cmdline().unwrap_or_else(|err| {
+fn build_squash_arg_parser<'a>() -> ArgParser<'a> {
+    ArgParser::new()
+        .arg(
+            Argument::new(BASE_FILE)
+                .required(true)
+                .takes_value(true)
+                .help("File path of the full mem snapshot at the base of the chain."),
+        )
+        .arg(
+            Argument::new(DIFF_FILE)
+                .required(true)
+                .allow_multiple(true)
+                .help(
+                    "File path of a diff mem snapshot of the chain. Repeat the argument for each \
+                     layer, from the oldest to the newest.",
+                ),
+        )
+}
+
+fn extract_args<'a>(
+    arg_parser: &'a ArgParser<'a>,
+    cmdline_args: &[String],
+    description: &str,
+) -> Arguments<'a> {
+    let mut arguments = arg_parser.arguments().clone();
+    arguments.parse(cmdline_args).unwrap_or_else(|err| {
         panic!(
             "Arguments parsing error: {} \n\nFor more information try --help.",
             err
         );
     });
 
-    if arg_parser.arguments().flag_present("help") {
+    if arguments.flag_present("help") {
         println!("Rebase_snap v{}", REBASE_SNAP_VERSION);
-        println!(
-            "Tool that copies all the non-sparse sections from a diff file onto a base file\n"
-        );
+        println!("{}\n", description);
         println!("{}", arg_parser.formatted_help());
         process::exit(EXIT_CODE_SUCCESS);
     }
-    if arg_parser.arguments().flag_present("version") {
+    if arguments.flag_present("version") {
         println!("Rebase_snap v{}\n", REBASE_SNAP_VERSION);
         process::exit(EXIT_CODE_SUCCESS);
     }
 
-    arg_parser.arguments()
+    arguments
 }
 
 fn parse_args(args: &Arguments) -> Result<(File, File), Error> {
@@ -86,6 +109,31 @@ fn parse_args(args: &Arguments) -> Result<(File, File), Error> {
     Ok((base_file, diff_file))
 }
 
+fn parse_squash_args(args: &Arguments) -> Result<(File, Vec<File>), Error> {
+    // Safe to unwrap since the required arguments are checked as part of
+    // `arguments.parse()`
+    let base_file_path = args.single_value(BASE_FILE).unwrap();
+    let base_file = OpenOptions::new()
+        .write(true)
+        .open(base_file_path)
+        .map_err(Error::InvalidBaseFile)?;
+    // Safe to unwrap since the required arguments are checked as part of
+    // `arguments.parse()`
+    let diff_files = args
+        .multiple_values(DIFF_FILE)
+        .unwrap()
+        .iter()
+        .map(|diff_file_path| {
+            OpenOptions::new()
+                .read(true)
+                .open(diff_file_path)
+                .map_err(Error::InvalidDiffFile)
+        })
+        .collect::<Result<Vec<File>, Error>>()?;
+
+    Ok((base_file, diff_files))
+}
+
 fn rebase(base_file: &mut File, diff_file: &mut File) -> Result<(), Error> {
     let mut cursor: u64 = 0;
     while let Some(block_start) = diff_file.seek_data(cursor).map_err(Error::SeekData)? {
@@ -116,11 +164,46 @@ fn rebase(base_file: &mut File, diff_file: &mut File) -> Result<(), Error> {
     Ok(())
 }
 
+/// Squashes a chain of diff files onto the base file, turning it into a full mem snapshot of the
+/// newest layer.
+fn squash(base_file: &mut File, diff_files: &mut [File]) -> Result<(), Error> {
+    for diff_file in diff_files.iter_mut() {
+        rebase(base_file, diff_file)?;
+    }
+
+    Ok(())
+}
+
 fn main() {
-    let mut arg_parser = build_arg_parser();
-    let args = extract_args(&mut arg_parser);
-    let (mut base_file, mut diff_file) =
-        parse_args(args).unwrap_or_else(|err| panic!("Error parsing the cmd line args: {:?}", err));
+    let mut cmdline_args: Vec<String> = env::args().collect();
+
+    if cmdline_args.get(1).map(String::as_str) == Some(SQUASH_SUBCOMMAND) {
+        // The arguments following the subcommand belong to it.
+        cmdline_args.remove(1);
+        let arg_parser = build_squash_arg_parser();
+        let args = extract_args(
+            &arg_parser,
+            &cmdline_args,
+            "Tool that squashes a chain of diff mem snapshots onto the full mem snapshot at its \
+             base",
+        );
+        let (mut base_file, mut diff_files) = parse_squash_args(&args)
+            .unwrap_or_else(|err| panic!("Error parsing the cmd line args: {:?}", err));
+
+        squash(&mut base_file, &mut diff_files)
+            .unwrap_or_else(|err| panic!("Error squashing the files: {:?}", err));
+        return;
+    }
+
+    let arg_parser = build_arg_parser();
+    let args = extract_args(
+        &arg_parser,
+        &cmdline_args,
+        "Tool that copies all the non-sparse sections from a diff file onto a base file. Use the \
+         `squash` subcommand to merge a chain of diff files",
+    );
+    let (mut base_file, mut diff_file) = parse_args(&args)
+        .unwrap_or_else(|err| panic!("Error parsing the cmd line args: {:?}", err));
 
     rebase(&mut base_file, &mut diff_file)
         .unwrap_or_else(|err| panic!("Error merging the files: {:?}", err));
@@ -210,6 +293,56 @@ mod tests {
         assert!(parse_args(arguments).is_ok());
     }
 
+    #[test]
+    fn test_parse_squash_args() {
+        let base_file = tempfile::TempFile::new().unwrap();
+        let base_file_path = base_file.as_path().to_str().unwrap().to_string();
+        let diff_file = tempfile::TempFile::new().unwrap();
+        let diff_file_path = diff_file.as_path().to_str().unwrap().to_string();
+
+        let arg_parser = build_squash_arg_parser();
+        let arguments = &mut arg_parser.arguments().clone();
+        arguments
+            .parse(
+                vec![
+                    "rebase_snap",
+                    "--base-file",
+                    &base_file_path,
+                    "--diff-file",
+                    &diff_file_path,
+                    "--diff-file",
+                    "diff_file",
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .as_ref(),
+            )
+            .unwrap();
+        assert_err!(parse_squash_args(arguments), Error::InvalidDiffFile(_));
+
+        let arguments = &mut arg_parser.arguments().clone();
+        arguments
+            .parse(
+                vec![
+                    "rebase_snap",
+                    "--base-file",
+                    &base_file_path,
+                    "--diff-file",
+                    &diff_file_path,
+                    "--diff-file",
+                    &diff_file_path,
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .as_ref(),
+            )
+            .unwrap();
+        let (_, diff_files) = parse_squash_args(arguments).unwrap();
+        assert_eq!(diff_files.len(), 2);
+    }
+
     fn check_file_content(file: &mut File, expected_content: &[u8]) {
         let mut buf = vec![0u8; expected_content.len()];
         file.read_exact_at(buf.as_mut_slice(), 0).unwrap();
@@ -297,4 +430,40 @@ mod tests {
             check_file_content(&mut base_file, &expected_result);
         }
     }
+
+    #[test]
+    fn test_squash() {
+        let block_size = 4096;
+        let mut base_file = tempfile::TempFile::new().unwrap().into_file();
+        let mut diff_files = vec![
+            tempfile::TempFile::new().unwrap().into_file(),
+            tempfile::TempFile::new().unwrap().into_file(),
+        ];
+
+        // Three blocks in the base file. The first layer overwrites the last two, the second
+        // layer overwrites the last one.
+        let base_data = rand::rand_alphanumerics(3 * block_size)
+            .into_string()
+            .unwrap();
+        base_file.write_all(base_data.as_bytes()).unwrap();
+        let first_layer = rand::rand_alphanumerics(2 * block_size)
+            .into_string()
+            .unwrap();
+        diff_files[0]
+            .write_all_at(first_layer.as_bytes(), block_size as u64)
+            .unwrap();
+        let second_layer = rand::rand_alphanumerics(block_size).into_string().unwrap();
+        diff_files[1]
+            .write_all_at(second_layer.as_bytes(), 2 * block_size as u64)
+            .unwrap();
+
+        squash(&mut base_file, &mut diff_files).unwrap();
+        let expected_result = [
+            &base_data.as_bytes()[..block_size],
+            &first_layer.as_bytes()[..block_size],
+            second_layer.as_bytes(),
+        ]
+        .concat();
+        check_file_content(&mut base_file, &expected_result);
+    }
 }
@@ -0,0 +1,21 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Feeds arbitrary blocks to the LZ4 decompressor, which must reject the malformed ones without
+//! panicking or exceeding the output limit.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use utils::lz4;
+
+fuzz_target!(|data: &[u8]| {
+    // The first two bytes pick the output limit, the rest is the block.
+    if data.len() < 2 {
+        return;
+    }
+    let max_len = u16::from_le_bytes([data[0], data[1]]) as usize;
+    if let Ok(output) = lz4::decompress(&data[2..], max_len) {
+        assert!(output.len() <= max_len);
+    }
+});
@@ -0,0 +1,14 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Checks that any input is decompressed back from the block the LZ4 compressor makes of it.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use utils::lz4;
+
+fuzz_target!(|data: &[u8]| {
+    let compressed = lz4::compress(data);
+    assert_eq!(lz4::decompress(&compressed, data.len()).unwrap(), data);
+});
@@ -34,6 +34,7 @@ generate_read_fn!(read_le_i32, i32, i8, 4, from_le_bytes);
 
 generate_read_fn!(read_be_u16, u16, u8, 2, from_be_bytes);
 generate_read_fn!(read_be_u32, u32, u8, 4, from_be_bytes);
+generate_read_fn!(read_be_u64, u64, u8, 8, from_be_bytes);
 
 generate_write_fn!(write_le_u16, u16, u8, to_le_bytes);
 generate_write_fn!(write_le_u32, u32, u8, to_le_bytes);
@@ -42,6 +43,7 @@ generate_write_fn!(write_le_i32, i32, i8, to_le_bytes);
 
 generate_write_fn!(write_be_u16, u16, u8, to_be_bytes);
 generate_write_fn!(write_be_u32, u32, u8, to_be_bytes);
+generate_write_fn!(write_be_u64, u64, u8, to_be_bytes);
 
 #[cfg(test)]
 mod tests {
@@ -106,4 +108,5 @@ mod tests {
     byte_order_test_read_write!(test_le_i32, write_le_i32, read_le_i32, false, i32);
     byte_order_test_read_write!(test_be_u16, write_be_u16, read_be_u16, true, u16);
     byte_order_test_read_write!(test_be_u32, write_be_u32, read_be_u32, true, u32);
+    byte_order_test_read_write!(test_be_u64, write_be_u64, read_be_u64, true, u64);
 }
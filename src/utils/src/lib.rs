@@ -6,13 +6,15 @@
 // More specifically, we are re-exporting modules from `vmm_sys_util` as part
 // of the `utils` crate.
 pub use vmm_sys_util::{
-    epoll, errno, eventfd, fam, generate_fam_struct_impl, ioctl, ioctl_expr, ioctl_ioc_nr,
-    ioctl_iow_nr, rand, seek_hole, sock_ctrl_msg, syscall, tempdir, tempfile, terminal,
+    epoll, errno, eventfd, fam, generate_fam_struct_impl, ioctl, ioctl_expr, ioctl_io_nr,
+    ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr, rand, seek_hole, sock_ctrl_msg,
+    syscall, tempdir, tempfile, terminal,
 };
 
 pub mod arg_parser;
 pub mod byte_order;
 pub mod kernel_version;
+pub mod lz4;
 pub mod net;
 pub mod signal;
 pub mod sm;
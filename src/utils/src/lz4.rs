@@ -0,0 +1,310 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compression and decompression of the LZ4 block format.
+//!
+//! Only single blocks are handled, without the LZ4 frame around them, so the caller has to keep
+//! track of the uncompressed length. The compressor is a greedy one, using a single hash table
+//! lookup per position.
+//!
+//! The decompressor handles untrusted input. It is covered by the cargo-fuzz targets of
+//! `src/utils/fuzz`, run with `cargo +nightly fuzz run lz4_decompress` from `src/utils`.
+
+use std::cmp;
+
+/// Minimum length of a match.
+const MIN_MATCH: usize = 4;
+/// The last bytes of a block are always literals.
+const LAST_LITERALS: usize = 5;
+/// The last match must start at least this many bytes before the end of the block.
+const MF_LIMIT: usize = 12;
+/// Largest distance a match can refer back to.
+const MAX_DISTANCE: usize = 0xffff;
+const HASH_LOG: u32 = 12;
+/// Value of a length nibble of the token, meaning that extra length bytes follow.
+const RUN_MASK: usize = 0xf;
+
+/// Errors associated with decompressing an LZ4 block.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// A match refers to data before the start of the block.
+    InvalidOffset,
+    /// The decompressed data is longer than expected.
+    OutputTooLong,
+    /// The compressed data ends in the middle of a sequence.
+    Truncated,
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([input[pos], input[pos + 1], input[pos + 2], input[pos + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+fn push_length(output: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        output.push(255);
+        len -= 255;
+    }
+    output.push(len as u8);
+}
+
+fn push_literals(output: &mut Vec<u8>, literals: &[u8], match_nibble: u8) {
+    let literal_nibble = cmp::min(literals.len(), RUN_MASK) as u8;
+    output.push(literal_nibble << 4 | match_nibble);
+    if literals.len() >= RUN_MASK {
+        push_length(output, literals.len() - RUN_MASK);
+    }
+    output.extend_from_slice(literals);
+}
+
+fn push_sequence(output: &mut Vec<u8>, literals: &[u8], offset: usize, match_len: usize) {
+    let match_len = match_len - MIN_MATCH;
+    push_literals(output, literals, cmp::min(match_len, RUN_MASK) as u8);
+    output.extend_from_slice(&(offset as u16).to_le_bytes());
+    if match_len >= RUN_MASK {
+        push_length(output, match_len - RUN_MASK);
+    }
+}
+
+/// Compresses `input` into a single LZ4 block.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 2);
+    // Positions are stored plus one, so that zero marks an empty slot.
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    if input.len() > MF_LIMIT {
+        let match_start_limit = input.len() - MF_LIMIT;
+        let match_end_limit = input.len() - LAST_LITERALS;
+        while pos < match_start_limit {
+            let sequence = read_u32(input, pos);
+            let slot = &mut table[hash(sequence)];
+            let candidate = *slot;
+            *slot = pos + 1;
+
+            if candidate != 0
+                && pos - (candidate - 1) <= MAX_DISTANCE
+                && read_u32(input, candidate - 1) == sequence
+            {
+                let candidate = candidate - 1;
+                let mut match_len = MIN_MATCH;
+                while pos + match_len < match_end_limit
+                    && input[candidate + match_len] == input[pos + match_len]
+                {
+                    match_len += 1;
+                }
+                push_sequence(&mut output, &input[anchor..pos], pos - candidate, match_len);
+                pos += match_len;
+                anchor = pos;
+            } else {
+                pos += 1;
+            }
+        }
+    }
+
+    push_literals(&mut output, &input[anchor..], 0);
+    output
+}
+
+fn read_length(input: &[u8], pos: &mut usize) -> Result<usize, Error> {
+    let mut len = 0usize;
+    loop {
+        let byte = *input.get(*pos).ok_or(Error::Truncated)?;
+        *pos += 1;
+        len = len.checked_add(byte as usize).ok_or(Error::OutputTooLong)?;
+        if byte != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+/// Decompresses a single LZ4 block, which must not expand to more than `max_len` bytes.
+pub fn decompress(input: &[u8], max_len: usize) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::with_capacity(max_len);
+    let mut pos = 0;
+
+    loop {
+        let token = *input.get(pos).ok_or(Error::Truncated)? as usize;
+        pos += 1;
+
+        let mut literals_len = token >> 4;
+        if literals_len == RUN_MASK {
+            literals_len += read_length(input, &mut pos)?;
+        }
+        let literals_end = pos.checked_add(literals_len).ok_or(Error::Truncated)?;
+        let literals = input.get(pos..literals_end).ok_or(Error::Truncated)?;
+        if output.len() + literals_len > max_len {
+            return Err(Error::OutputTooLong);
+        }
+        output.extend_from_slice(literals);
+        pos = literals_end;
+
+        // The last sequence of the block only holds literals.
+        if pos == input.len() {
+            return Ok(output);
+        }
+
+        let offset = input.get(pos..pos + 2).ok_or(Error::Truncated)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        pos += 2;
+        if offset == 0 || offset > output.len() {
+            return Err(Error::InvalidOffset);
+        }
+
+        let mut match_len = (token & RUN_MASK) + MIN_MATCH;
+        if token & RUN_MASK == RUN_MASK {
+            match_len += read_length(input, &mut pos)?;
+        }
+        if output.len() + match_len > max_len {
+            return Err(Error::OutputTooLong);
+        }
+        // The match may overlap the bytes it produces, so it's copied one byte at a time.
+        for _ in 0..match_len {
+            let byte = output[output.len() - offset];
+            output.push(byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_round_trip(input: &[u8]) -> Vec<u8> {
+        let compressed = compress(input);
+        assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+        compressed
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Too short to hold any match.
+        check_round_trip(&[]);
+        check_round_trip(b"abc");
+        check_round_trip(b"abcdefghijkl");
+
+        // Repeated data is compressed.
+        let compressed = check_round_trip(&[0u8; 4096]);
+        assert!(compressed.len() < 32);
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(100);
+        let compressed = check_round_trip(&text);
+        assert!(compressed.len() < text.len() / 10);
+
+        // Long literal runs and long matches need extra length bytes.
+        let mut data: Vec<u8> = (0..1000u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        data.extend_from_slice(&[0xaa; 1000]);
+        data.extend_from_slice(&data.clone());
+        check_round_trip(&data);
+
+        // Data that doesn't compress.
+        let random = crate::rand::rand_alphanumerics(10000)
+            .into_string()
+            .unwrap();
+        check_round_trip(random.as_bytes());
+    }
+
+    /// Inputs along with the blocks produced for them by the reference LZ4 implementation
+    /// (lz4 v1.9.4).
+    fn reference_blocks() -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut mixed: Vec<u8> = (0..40u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        mixed.extend_from_slice(&[0xaa; 300]);
+        mixed.extend_from_slice(&mixed.clone());
+
+        vec![
+            (
+                b"The quick brown fox jumps over the lazy dog. ".repeat(8),
+                vec![
+                    0xff, 0x1e, 0x54, 0x68, 0x65, 0x20, 0x71, 0x75, 0x69, 0x63, 0x6b, 0x20, 0x62,
+                    0x72, 0x6f, 0x77, 0x6e, 0x20, 0x66, 0x6f, 0x78, 0x20, 0x6a, 0x75, 0x6d, 0x70,
+                    0x73, 0x20, 0x6f, 0x76, 0x65, 0x72, 0x20, 0x74, 0x68, 0x65, 0x20, 0x6c, 0x61,
+                    0x7a, 0x79, 0x20, 0x64, 0x6f, 0x67, 0x2e, 0x20, 0x2d, 0x00, 0xff, 0x24, 0x50,
+                    0x64, 0x6f, 0x67, 0x2e, 0x20,
+                ],
+            ),
+            // A long match overlapping the bytes it produces.
+            (
+                vec![0u8; 4096],
+                vec![
+                    0x1f, 0x00, 0x01, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xf6, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00,
+                ],
+            ),
+            // Literal runs needing extra length bytes and matches of distant data.
+            (
+                mixed,
+                vec![
+                    0xff, 0x1a, 0x00, 0x07, 0x0e, 0x15, 0x1c, 0x23, 0x2a, 0x31, 0x38, 0x3f, 0x46,
+                    0x4d, 0x54, 0x5c, 0x63, 0x6a, 0x71, 0x78, 0x7f, 0x86, 0x8d, 0x94, 0x9b, 0xa2,
+                    0xa9, 0xb0, 0xb8, 0xbf, 0xc6, 0xcd, 0xd4, 0xdb, 0xe2, 0xe9, 0xf0, 0xf7, 0xfe,
+                    0x05, 0x0c, 0x14, 0xaa, 0x01, 0x00, 0xff, 0x19, 0x0f, 0x54, 0x01, 0xff, 0x3d,
+                    0x50, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa,
+                ],
+            ),
+            (
+                [b"abc".repeat(100), b"xyz".to_vec()].concat(),
+                vec![
+                    0x3f, 0x61, 0x62, 0x63, 0x03, 0x00, 0xff, 0x15, 0x50, 0x62, 0x63, 0x78, 0x79,
+                    0x7a,
+                ],
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_reference_blocks() {
+        for (input, block) in reference_blocks() {
+            assert_eq!(decompress(&block, input.len()).unwrap(), input);
+            // On these inputs, the compressor picks the same sequences as the reference one.
+            assert_eq!(check_round_trip(&input), block);
+        }
+    }
+
+    #[test]
+    fn test_decompress_malformed() {
+        // Truncated and corrupted blocks are either rejected or decompressed within the limit,
+        // never read or written out of bounds.
+        for (input, block) in reference_blocks() {
+            let max_len = input.len();
+            for len in 0..block.len() {
+                if let Ok(output) = decompress(&block[..len], max_len) {
+                    assert!(output.len() <= max_len);
+                }
+            }
+            for i in 0..block.len() {
+                for value in [0x00, 0x0f, 0xf0, 0xff, block[i] ^ 0x01].iter() {
+                    let mut corrupted = block.clone();
+                    corrupted[i] = *value;
+                    if let Ok(output) = decompress(&corrupted, max_len) {
+                        assert!(output.len() <= max_len);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_decompress_errors() {
+        let compressed = compress(&[1u8; 100]);
+
+        assert_eq!(decompress(&[], 100), Err(Error::Truncated));
+        assert_eq!(
+            decompress(&compressed[..compressed.len() - 1], 100),
+            Err(Error::Truncated)
+        );
+        assert_eq!(decompress(&compressed, 99), Err(Error::OutputTooLong));
+        // A literal followed by a match going back two bytes.
+        assert_eq!(
+            decompress(&[0x10, 1, 2, 0, 0x00], 100),
+            Err(Error::InvalidOffset)
+        );
+        // A zero offset is invalid as well.
+        assert_eq!(
+            decompress(&[0x10, 1, 0, 0, 0x00], 100),
+            Err(Error::InvalidOffset)
+        );
+    }
+}
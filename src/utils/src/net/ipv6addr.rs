@@ -0,0 +1,66 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::net::Ipv6Addr;
+
+/// Checks if an IPv6 address is either a link-local (RFC 4291) or a unique local (RFC 4193)
+/// unicast address, which are the only ones that can't be routed to the outside world.
+/// The subnet-router anycast address of the prefix (all-zero interface identifier) is rejected.
+/// # Examples
+///
+/// ```
+/// use std::net::Ipv6Addr;
+/// use utils::net::ipv6addr::is_link_local_or_unique_local;
+///
+/// is_link_local_or_unique_local(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1));
+pub fn is_link_local_or_unique_local(ipv6_addr: Ipv6Addr) -> bool {
+    let segments = ipv6_addr.segments();
+    let prefix_valid = match segments[0] {
+        // fe80::/10
+        s if s & 0xffc0 == 0xfe80 => true,
+        // fc00::/7
+        s if s & 0xfe00 == 0xfc00 => true,
+        _ => false,
+    };
+    prefix_valid && segments[4..].iter().any(|s| *s != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use crate::net::ipv6addr::is_link_local_or_unique_local;
+
+    #[test]
+    fn test_is_link_local_or_unique_local() {
+        // Global, loopback and multicast addresses.
+        let mut ipv6_addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        assert!(!is_link_local_or_unique_local(ipv6_addr));
+        ipv6_addr = Ipv6Addr::LOCALHOST;
+        assert!(!is_link_local_or_unique_local(ipv6_addr));
+        ipv6_addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+        assert!(!is_link_local_or_unique_local(ipv6_addr));
+
+        // Link-local addresses (fe80::/10).
+        ipv6_addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0xa9fe, 0xa9fe);
+        assert!(is_link_local_or_unique_local(ipv6_addr));
+        ipv6_addr = Ipv6Addr::new(0xfebf, 0, 0, 0, 0, 0, 0, 1);
+        assert!(is_link_local_or_unique_local(ipv6_addr));
+        ipv6_addr = Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 1);
+        assert!(!is_link_local_or_unique_local(ipv6_addr));
+
+        // Unique local addresses (fc00::/7).
+        ipv6_addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        assert!(is_link_local_or_unique_local(ipv6_addr));
+        ipv6_addr = Ipv6Addr::new(0xfc00, 0x1234, 0, 0, 0, 0, 0, 1);
+        assert!(is_link_local_or_unique_local(ipv6_addr));
+        ipv6_addr = Ipv6Addr::new(0xfb00, 0, 0, 0, 0, 0, 0, 1);
+        assert!(!is_link_local_or_unique_local(ipv6_addr));
+
+        // Subnet-router anycast addresses can not be used.
+        ipv6_addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0);
+        assert!(!is_link_local_or_unique_local(ipv6_addr));
+        ipv6_addr = Ipv6Addr::new(0xfd00, 0, 0, 1, 0, 0, 0, 0);
+        assert!(!is_link_local_or_unique_local(ipv6_addr));
+    }
+}
@@ -8,6 +8,8 @@
 // ```
 // --with-derive-default --with-derive-partialeq
 // --allowlist-var "VIRTIO_F_.*" --allowlist-var "VIRTIO_NET_F_.*"
+// --allowlist-var "VIRTIO_NET_OK" --allowlist-var "VIRTIO_NET_ERR"
+// --allowlist-var "VIRTIO_NET_CTRL_MQ.*"
 // --allowlist-type "virtio_net_hdr_v1"
 // ```
 
@@ -46,6 +48,12 @@ pub const VIRTIO_NET_F_RSC_EXT: u32 = 61;
 pub const VIRTIO_NET_F_STANDBY: u32 = 62;
 pub const VIRTIO_NET_F_SPEED_DUPLEX: u32 = 63;
 pub const VIRTIO_NET_F_GSO: u32 = 6;
+pub const VIRTIO_NET_OK: u32 = 0;
+pub const VIRTIO_NET_ERR: u32 = 1;
+pub const VIRTIO_NET_CTRL_MQ: u32 = 4;
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u32 = 0;
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN: u32 = 1;
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX: u32 = 32768;
 pub type __u8 = ::std::os::raw::c_uchar;
 pub type __u16 = ::std::os::raw::c_ushort;
 pub type __le16 = __u16;
@@ -5,8 +5,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use std::fs::File;
 use std::io::Error as IoError;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 use vm_memory_upstream::bitmap::AtomicBitmap;
 pub use vm_memory_upstream::bitmap::Bitmap;
@@ -23,6 +24,10 @@ pub type GuestRegionMmap = vm_memory_upstream::GuestRegionMmap<Option<AtomicBitm
 pub type GuestMmapRegion = vm_memory_upstream::MmapRegion<Option<AtomicBitmap>>;
 
 const GUARD_PAGE_COUNT: usize = 1;
+/// `f_type` of the hugetlbfs file systems.
+const HUGETLBFS_MAGIC: libc::c_long = 0x9584_58f6;
+/// Offset of the log2 of the huge page size in the `memfd_create` flags.
+const MFD_HUGE_SHIFT: u32 = 26;
 
 /// Build a `MmapRegion` surrounded by guard pages.
 ///
@@ -37,17 +42,23 @@ const GUARD_PAGE_COUNT: usize = 1;
 /// This results in a border of `GUARD_PAGE_COUNT` pages on either side of the region, which
 /// acts as a safety net for accessing out-of-bounds addresses that are not allocated for the
 /// guest's memory.
+///
+/// Huge pages can only be mapped at addresses aligned to their size, so the guard region is
+/// extended by `align` - `page_size`, and the accessible region starts at the first address
+/// aligned to `align`, past the left border.
 fn build_guarded_region(
     maybe_file_offset: Option<FileOffset>,
     size: usize,
+    align: usize,
     prot: i32,
     flags: i32,
     track_dirty_pages: bool,
 ) -> Result<GuestMmapRegion, MmapRegionError> {
     let page_size = utils::get_page_size().expect("Cannot retrieve page size.");
-    // Create the guarded range size (received size + X pages),
+    let align = align.max(page_size);
+    // Create the guarded range size (received size + X pages + alignment slack),
     // where X is defined as a constant GUARD_PAGE_COUNT.
-    let guarded_size = size + GUARD_PAGE_COUNT * 2 * page_size;
+    let guarded_size = size + GUARD_PAGE_COUNT * 2 * page_size + align - page_size;
 
     // Map the guarded range to PROT_NONE
     let guard_addr = unsafe {
@@ -73,7 +84,8 @@ fn build_guarded_region(
         None => (-1, 0),
     };
 
-    let region_start_addr = guard_addr as usize + page_size * GUARD_PAGE_COUNT;
+    let region_start_addr =
+        (guard_addr as usize + page_size * GUARD_PAGE_COUNT + align - 1) / align * align;
 
     // Inside the protected range, starting with guard_addr + PAGE_SIZE,
     // map the requested range with received protection and flags
@@ -120,9 +132,15 @@ pub fn create_guest_memory(
             Some(_) => libc::MAP_NORESERVE | libc::MAP_PRIVATE,
         };
 
-        let mmap_region =
-            build_guarded_region(region.0.clone(), region.2, prot, flags, track_dirty_pages)
-                .map_err(Error::MmapRegion)?;
+        let mmap_region = build_guarded_region(
+            region.0.clone(),
+            region.2,
+            0,
+            prot,
+            flags,
+            track_dirty_pages,
+        )
+        .map_err(Error::MmapRegion)?;
 
         mmap_regions.push(GuestRegionMmap::new(mmap_region, region.1)?);
     }
@@ -130,6 +148,88 @@ pub fn create_guest_memory(
     GuestMemoryMmap::from_regions(mmap_regions)
 }
 
+/// Helper for creating guest memory which can be shared with other processes.
+///
+/// Each region is backed by its own memfd and mapped `MAP_SHARED`, so that the file descriptor
+/// of the region can be mapped by a vhost-user backend. With `huge_page_size`, the memfds are
+/// created on hugetlbfs, with pages of that size (2 MiB or 1 GiB). The huge pages are reserved
+/// when the regions are mapped, so running out of them fails here rather than in the guest.
+pub fn create_shared_guest_memory(
+    regions: &[(GuestAddress, usize)],
+    huge_page_size: Option<usize>,
+    track_dirty_pages: bool,
+) -> std::result::Result<GuestMemoryMmap, Error> {
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let flags = match huge_page_size {
+        None => libc::MAP_NORESERVE | libc::MAP_SHARED,
+        Some(_) => libc::MAP_SHARED,
+    };
+    let mut mmap_regions = Vec::with_capacity(regions.len());
+
+    for region in regions {
+        let file = create_memfd(region.1, huge_page_size).map_err(Error::MmapRegion)?;
+        let mmap_region = build_guarded_region(
+            Some(FileOffset::new(file, 0)),
+            region.1,
+            huge_page_size.unwrap_or(0),
+            prot,
+            flags,
+            track_dirty_pages,
+        )
+        .map_err(Error::MmapRegion)?;
+
+        mmap_regions.push(GuestRegionMmap::new(mmap_region, region.0)?);
+    }
+
+    GuestMemoryMmap::from_regions(mmap_regions)
+}
+
+/// Returns the size of the huge pages backing `region`, if it is backed by a hugetlbfs file.
+pub fn huge_page_size(region: &GuestRegionMmap) -> Option<usize> {
+    let file_offset = region.file_offset()?;
+    // Safe because statfs is a plain old data struct, for which all zeroes is a valid value.
+    let mut statfs: libc::statfs = unsafe { std::mem::zeroed() };
+    // Safe because the descriptor is valid, and we check the return value.
+    let ret = unsafe { libc::fstatfs(file_offset.file().as_raw_fd(), &mut statfs) };
+    if ret < 0 || statfs.f_type as libc::c_long != HUGETLBFS_MAGIC {
+        return None;
+    }
+    Some(statfs.f_bsize as usize)
+}
+
+// Creates an anonymous memory file of `size` bytes, on hugetlbfs with `huge_page_size`.
+fn create_memfd(
+    size: usize,
+    huge_page_size: Option<usize>,
+) -> std::result::Result<File, MmapRegionError> {
+    let name = b"guest_mem\0";
+    let mut flags = libc::MFD_CLOEXEC;
+    if let Some(huge_page_size) = huge_page_size {
+        // Hugetlbfs files can only be sized in whole huge pages.
+        if !huge_page_size.is_power_of_two() || size % huge_page_size != 0 {
+            return Err(MmapRegionError::Mmap(IoError::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "The region size {:#x} is not a multiple of the huge page size {:#x}",
+                    size, huge_page_size
+                ),
+            )));
+        }
+        // The log2 of the page size goes in the MFD_HUGE_* bits.
+        flags |=
+            libc::MFD_HUGETLB | (huge_page_size.trailing_zeros() << MFD_HUGE_SHIFT) as libc::c_uint;
+    }
+    // Safe because the name is a valid nul terminated string, and we check the return value.
+    let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(MmapRegionError::Mmap(IoError::last_os_error()));
+    }
+    // Safe because the descriptor was just created, and is owned by nobody else.
+    let file = unsafe { File::from_raw_fd(fd as RawFd) };
+    file.set_len(size as u64).map_err(MmapRegionError::Mmap)?;
+    Ok(file)
+}
+
 pub fn mark_dirty_mem(mem: &GuestMemoryMmap, addr: GuestAddress, len: usize) {
     let _ = mem.try_access(len, addr, |_total, count, caddr, region| {
         if let Some(bitmap) = region.bitmap() {
@@ -189,6 +289,8 @@ pub mod test_utils {
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::fs::FileExt;
+
     use utils::get_page_size;
     use utils::tempfile::TempFile;
 
@@ -298,7 +400,7 @@ mod tests {
             let prot = libc::PROT_READ | libc::PROT_WRITE;
             let flags = libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_PRIVATE;
 
-            let region = build_guarded_region(None, size, prot, flags, false).unwrap();
+            let region = build_guarded_region(None, size, 0, prot, flags, false).unwrap();
 
             // Verify that the region was built correctly
             assert_eq!(region.size(), size);
@@ -323,6 +425,7 @@ mod tests {
             let region = build_guarded_region(
                 Some(FileOffset::new(file, offset)),
                 size,
+                0,
                 prot,
                 flags,
                 false,
@@ -337,6 +440,23 @@ mod tests {
 
             validate_guard_region(&region);
         }
+
+        // Create guarded region aligned past the left border.
+        {
+            let page_size = get_page_size().unwrap();
+            let size = page_size * 16;
+            let align = page_size * 8;
+            let prot = libc::PROT_READ | libc::PROT_WRITE;
+            let flags = libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_PRIVATE;
+
+            let region = build_guarded_region(None, size, align, prot, flags, false).unwrap();
+
+            assert_eq!(region.size(), size);
+            assert_eq!(region.as_ptr() as usize % align, 0);
+
+            validate_guard_region(&region);
+            loop_guard_region_to_sigsegv(&region);
+        }
     }
 
     #[test]
@@ -391,6 +511,58 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_create_shared_guest_memory() {
+        let region_size = 0x10000;
+        let regions = vec![
+            (GuestAddress(0x0), region_size),
+            (GuestAddress(0x20000), region_size),
+        ];
+
+        let guest_memory = create_shared_guest_memory(&regions, None, false).unwrap();
+        guest_memory.iter().for_each(|region| {
+            validate_guard_region(&region);
+            assert!(huge_page_size(region).is_none());
+            assert_eq!(region.flags(), libc::MAP_NORESERVE | libc::MAP_SHARED);
+            let file_offset = region.file_offset().unwrap();
+            assert_eq!(file_offset.start(), 0);
+            assert_eq!(
+                file_offset.file().metadata().unwrap().len(),
+                region_size as u64
+            );
+        });
+
+        // The writes to the guest memory land in the backing file.
+        guest_memory
+            .write_obj(0xAAu8, GuestAddress(0x20010))
+            .unwrap();
+        let region = guest_memory.find_region(GuestAddress(0x20000)).unwrap();
+        let mut byte = [0u8];
+        region
+            .file_offset()
+            .unwrap()
+            .file()
+            .read_exact_at(&mut byte, 0x10)
+            .unwrap();
+        assert_eq!(byte[0], 0xAA);
+
+        // Hugetlbfs regions are sized in whole huge pages.
+        let huge_page_size = 2 << 20;
+        assert!(create_shared_guest_memory(&regions, Some(huge_page_size), false).is_err());
+
+        // The host may not have any huge pages to spare.
+        if let Ok(guest_memory) = create_shared_guest_memory(
+            &[(GuestAddress(0x0), huge_page_size)],
+            Some(huge_page_size),
+            false,
+        ) {
+            let region = guest_memory.find_region(GuestAddress(0x0)).unwrap();
+            assert_eq!(region.as_ptr() as usize % huge_page_size, 0);
+            assert_eq!(region.flags(), libc::MAP_SHARED);
+            assert_eq!(super::huge_page_size(region), Some(huge_page_size));
+        }
+    }
+
     #[test]
     fn test_mark_dirty_mem() {
         let page_size = utils::get_page_size().unwrap();
@@ -0,0 +1,310 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::{cmp, io};
+
+use devices::virtio::balloon::device::BalloonStats;
+use devices::virtio::{Balloon, VirtioDevice, VirtioMem};
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use logger::{error, info, IncMetric, METRICS};
+use utils::epoll::EventSet;
+use utils::eventfd::EventFd;
+
+use crate::vmm_config::balloon::BalloonAutoAdjustConfig;
+
+const MIB: u64 = 1 << 20;
+
+/// Adjusts the target size of the balloon each time the guest provides new statistics, following
+/// the policy of a `BalloonAutoAdjustConfig`.
+pub struct BalloonController {
+    balloon: Arc<Mutex<Balloon>>,
+    config: BalloonAutoAdjustConfig,
+    // The size of the memory the guest booted with.
+    boot_mem_size_mib: u64,
+    // The virtio-mem device, if any. Only the memory it plugged is part of the guest memory.
+    mem: Option<Arc<Mutex<dyn VirtioDevice>>>,
+    // Signaled by the balloon device when the statistics are updated.
+    stats_update_evt: EventFd,
+}
+
+impl BalloonController {
+    /// Creates a controller for the balloon of a guest with `boot_mem_size_mib` MiB of boot
+    /// memory, plus the memory plugged through the `mem` virtio-mem device, if any.
+    pub fn new(
+        balloon: Arc<Mutex<Balloon>>,
+        config: BalloonAutoAdjustConfig,
+        boot_mem_size_mib: u64,
+        mem: Option<Arc<Mutex<dyn VirtioDevice>>>,
+    ) -> io::Result<Self> {
+        let stats_update_evt = balloon
+            .lock()
+            .expect("Poisoned lock")
+            .stats_update_evt()
+            .try_clone()?;
+
+        Ok(BalloonController {
+            balloon,
+            config,
+            boot_mem_size_mib,
+            mem,
+            stats_update_evt,
+        })
+    }
+
+    // Returns the maximum target size of the balloon. The balloon cannot have a target size
+    // greater than the size of the guest memory, which follows the plugged memory.
+    fn max_amount_mib(&self) -> u32 {
+        let plugged_mib = self
+            .mem
+            .as_ref()
+            .and_then(|mem| {
+                mem.lock()
+                    .expect("Poisoned lock")
+                    .as_any()
+                    .downcast_ref::<VirtioMem>()
+                    .map(|mem| mem.plugged_size() / MIB)
+            })
+            .unwrap_or(0);
+        cmp::min(
+            u64::from(self.config.max_amount_mib),
+            self.boot_mem_size_mib + plugged_mib,
+        ) as u32
+    }
+
+    // Returns the target size of the balloon, up to `max_amount_mib`, bringing the available
+    // memory of the guest closer to the target, or `None` if the balloon should keep its current
+    // target size.
+    fn next_amount_mib(
+        &self,
+        amount_mib: u32,
+        max_amount_mib: u32,
+        stats: &BalloonStats,
+    ) -> Option<u32> {
+        let total_memory = stats.total_memory.filter(|&total| total > 0)?;
+        // The free memory doesn't account for the caches the guest can drop, so the estimate of
+        // the available memory is preferred, when the guest provides it.
+        let free_memory = stats.available_memory.or(stats.free_memory)?;
+        let target_free_memory =
+            total_memory / 100 * u64::from(self.config.target_free_memory_percent);
+        let step_mib = u64::from(self.config.step_mib);
+
+        let next_amount_mib = if free_memory > target_free_memory {
+            // Reclaims the memory exceeding the target.
+            let excess_mib = (free_memory - target_free_memory) / MIB;
+            amount_mib.saturating_add(cmp::min(excess_mib, step_mib) as u32)
+        } else {
+            // Gives back the memory missing to reach the target.
+            let missing_mib = (target_free_memory - free_memory + MIB - 1) / MIB;
+            amount_mib.saturating_sub(cmp::min(missing_mib, step_mib) as u32)
+        };
+        let next_amount_mib = cmp::max(
+            cmp::min(next_amount_mib, max_amount_mib),
+            self.config.min_amount_mib,
+        );
+
+        if next_amount_mib != amount_mib {
+            Some(next_amount_mib)
+        } else {
+            None
+        }
+    }
+
+    fn process_stats_update(&mut self) {
+        // The virtio-mem device isn't locked along with the balloon.
+        let max_amount_mib = self.max_amount_mib();
+        let mut balloon = self.balloon.lock().expect("Poisoned lock");
+        let amount_mib = balloon.size_mb();
+        if let Some(next_amount_mib) = balloon
+            .latest_stats()
+            .and_then(|stats| self.next_amount_mib(amount_mib, max_amount_mib, stats))
+        {
+            Self::update_size(&mut balloon, amount_mib, next_amount_mib);
+        }
+    }
+
+    fn update_size(balloon: &mut Balloon, amount_mib: u32, next_amount_mib: u32) {
+        if let Err(err) = balloon.update_size(next_amount_mib) {
+            error!(
+                "Balloon controller failed to update the balloon size to {} MiB: {:?}",
+                next_amount_mib, err
+            );
+            METRICS.balloon.auto_adjust_fails.inc();
+            return;
+        }
+
+        if next_amount_mib > amount_mib {
+            METRICS.balloon.auto_inflate_count.inc();
+            info!(
+                "Balloon controller inflated the balloon from {} MiB to {} MiB.",
+                amount_mib, next_amount_mib
+            );
+        } else {
+            METRICS.balloon.auto_deflate_count.inc();
+            info!(
+                "Balloon controller deflated the balloon from {} MiB to {} MiB.",
+                amount_mib, next_amount_mib
+            );
+        }
+    }
+}
+
+impl MutEventSubscriber for BalloonController {
+    fn process(&mut self, event: Events, _: &mut EventOps) {
+        let source = event.fd();
+        let event_set = event.event_set();
+
+        if source == self.stats_update_evt.as_raw_fd() && event_set == EventSet::IN {
+            let _ = self.stats_update_evt.read();
+            self.process_stats_update();
+        } else {
+            error!("Spurious EventManager event for handler: BalloonController");
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        if let Err(err) = ops.add(Events::new(&self.stats_update_evt, EventSet::IN)) {
+            error!(
+                "Failed to register balloon statistics update event: {}",
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vm_memory::GuestAddress;
+
+    use super::*;
+
+    fn default_controller() -> BalloonController {
+        let balloon = Balloon::new(16, false, 1, false, false).unwrap();
+        let config = BalloonAutoAdjustConfig {
+            target_free_memory_percent: 25,
+            min_amount_mib: 8,
+            max_amount_mib: 256,
+            step_mib: 16,
+        };
+        BalloonController::new(Arc::new(Mutex::new(balloon)), config, 128, None).unwrap()
+    }
+
+    fn stats(total_mib: u64, free_mib: u64) -> BalloonStats {
+        BalloonStats {
+            total_memory: Some(total_mib * MIB),
+            available_memory: Some(free_mib * MIB),
+            ..BalloonStats::default()
+        }
+    }
+
+    #[test]
+    fn test_next_amount_mib() {
+        let controller = default_controller();
+        // The maximum size is capped to the size of the guest memory.
+        let max = controller.max_amount_mib();
+        assert_eq!(max, 128);
+
+        // No decision without the memory statistics.
+        assert_eq!(
+            controller.next_amount_mib(16, max, &BalloonStats::default()),
+            None
+        );
+        assert_eq!(controller.next_amount_mib(16, max, &stats(0, 0)), None);
+        // The target is reached.
+        assert_eq!(controller.next_amount_mib(16, max, &stats(100, 25)), None);
+
+        // Inflates by the excess of free memory, up to a step.
+        assert_eq!(
+            controller.next_amount_mib(16, max, &stats(100, 29)),
+            Some(20)
+        );
+        assert_eq!(
+            controller.next_amount_mib(16, max, &stats(100, 90)),
+            Some(32)
+        );
+        // Deflates by the missing free memory, up to a step.
+        assert_eq!(
+            controller.next_amount_mib(16, max, &stats(100, 21)),
+            Some(12)
+        );
+        assert_eq!(
+            controller.next_amount_mib(40, max, &stats(100, 0)),
+            Some(24)
+        );
+        // The free memory is preferred to nothing, but the available memory to the free memory.
+        let free_only = BalloonStats {
+            total_memory: Some(100 * MIB),
+            free_memory: Some(29 * MIB),
+            ..BalloonStats::default()
+        };
+        assert_eq!(controller.next_amount_mib(16, max, &free_only), Some(20));
+        let both = BalloonStats {
+            free_memory: Some(MIB),
+            ..stats(100, 29)
+        };
+        assert_eq!(controller.next_amount_mib(16, max, &both), Some(20));
+
+        // The target size stays between the minimum and maximum sizes.
+        assert_eq!(controller.next_amount_mib(10, max, &stats(100, 0)), Some(8));
+        assert_eq!(controller.next_amount_mib(8, max, &stats(100, 0)), None);
+        assert_eq!(
+            controller.next_amount_mib(120, max, &stats(100, 90)),
+            Some(128)
+        );
+        assert_eq!(controller.next_amount_mib(128, max, &stats(100, 90)), None);
+        assert_eq!(
+            controller.next_amount_mib(200, max, &stats(100, 25)),
+            Some(128)
+        );
+    }
+
+    #[test]
+    fn test_max_amount_mib() {
+        let balloon = Arc::new(Mutex::new(Balloon::new(0, false, 1, false, false).unwrap()));
+        let config = BalloonAutoAdjustConfig {
+            target_free_memory_percent: 25,
+            min_amount_mib: 0,
+            max_amount_mib: 1024,
+            step_mib: 16,
+        };
+        let mem =
+            VirtioMem::new(GuestAddress(1 << 30), 512 * MIB, 2 * MIB, 256 * MIB, false).unwrap();
+        let controller = BalloonController::new(
+            balloon,
+            config,
+            128,
+            Some(Arc::new(Mutex::new(mem)) as Arc<Mutex<dyn VirtioDevice>>),
+        )
+        .unwrap();
+
+        // The memory the guest didn't plug isn't counted in, even if requested.
+        assert_eq!(controller.max_amount_mib(), 128);
+    }
+
+    #[test]
+    fn test_update_size() {
+        let controller = default_controller();
+        let mut balloon = controller.balloon.lock().unwrap();
+
+        // The balloon isn't activated, so its size can't be updated.
+        let fails = METRICS.balloon.auto_adjust_fails.count();
+        BalloonController::update_size(&mut balloon, 16, 32);
+        assert_eq!(METRICS.balloon.auto_adjust_fails.count(), fails + 1);
+        assert_eq!(balloon.size_mb(), 16);
+
+        let mem =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false)
+                .unwrap();
+        balloon.activate(mem).unwrap();
+        let inflations = METRICS.balloon.auto_inflate_count.count();
+        BalloonController::update_size(&mut balloon, 16, 32);
+        assert_eq!(METRICS.balloon.auto_inflate_count.count(), inflations + 1);
+        assert_eq!(balloon.size_mb(), 32);
+
+        let deflations = METRICS.balloon.auto_deflate_count.count();
+        BalloonController::update_size(&mut balloon, 32, 24);
+        assert_eq!(METRICS.balloon.auto_deflate_count.count(), deflations + 1);
+        assert_eq!(balloon.size_mb(), 24);
+    }
+}
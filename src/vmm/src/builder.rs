@@ -16,7 +16,10 @@ use devices::legacy::serial::ReadableFd;
 #[cfg(target_arch = "aarch64")]
 use devices::legacy::RTCDevice;
 use devices::legacy::{EventFdTrigger, SerialDevice, SerialEventsWrapper, SerialWrapper};
-use devices::virtio::{Balloon, Block, MmioTransport, Net, VirtioDevice, Vsock, VsockUnixBackend};
+use devices::virtio::{
+    Balloon, Block, MmioTransport, Net, VhostUser, VirtioDevice, VirtioMem, Vsock,
+    VsockUnixBackend, MEM_DEV_ID, TYPE_MEM,
+};
 use event_manager::{MutEventSubscriber, SubscriberOps};
 use libc::EFD_NONBLOCK;
 use linux_loader::cmdline::Cmdline as LoaderKernelCmdline;
@@ -37,21 +40,25 @@ use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
 use vm_superio::Rtc;
 use vm_superio::Serial;
 
+use crate::balloon_controller::BalloonController;
 #[cfg(target_arch = "aarch64")]
 use crate::construct_kvm_mpidrs;
 #[cfg(target_arch = "x86_64")]
 use crate::device_manager::legacy::PortIODeviceManager;
-use crate::device_manager::mmio::MMIODeviceManager;
+use crate::device_manager::mmio::{MMIODeviceManager, HOTPLUG_SLOTS_COUNT};
 use crate::device_manager::persist::MMIODevManagerConstructorArgs;
 use crate::persist::{MicrovmState, MicrovmStateError};
 use crate::resources::VmResources;
+use crate::vmm_config::balloon::BalloonAutoAdjustConfig;
 use crate::vmm_config::boot_source::BootConfig;
 use crate::vmm_config::instance_info::InstanceInfo;
-use crate::vmm_config::machine_config::{VmConfigError, VmUpdateConfig};
+use crate::vmm_config::machine_config::{MemoryBacking, VmConfigError, VmUpdateConfig};
 use crate::vstate::system::KvmContext;
 use crate::vstate::vcpu::{Vcpu, VcpuConfig};
 use crate::vstate::vm::Vm;
-use crate::{device_manager, mem_size_mib, Error, EventManager, Vmm, VmmEventsObserver};
+use crate::{
+    boot_memory, device_manager, mem_size_mib, Error, EventManager, Vmm, VmmEventsObserver,
+};
 
 /// Errors associated with starting the instance.
 #[derive(Debug)]
@@ -60,6 +67,10 @@ pub enum StartMicrovmError {
     AttachBlockDevice(io::Error),
     /// This error is thrown by the minimal boot loader implementation.
     ConfigureSystem(arch::Error),
+    /// Cannot create the controller adjusting the size of the balloon.
+    CreateBalloonController(io::Error),
+    /// Cannot create the virtio-mem device of the hot-pluggable memory.
+    CreateMemDevice(devices::virtio::mem::Error),
     /// Internal errors are due to resource exhaustion.
     CreateNetDevice(devices::virtio::net::Error),
     /// Failed to create a `RateLimiter` object.
@@ -112,6 +123,10 @@ impl Display for StartMicrovmError {
                 write!(f, "Unable to attach block device to Vmm: {}", err)
             }
             ConfigureSystem(err) => write!(f, "System configuration error: {:?}", err),
+            CreateBalloonController(err) => {
+                write!(f, "Cannot create the balloon controller: {}", err)
+            }
+            CreateMemDevice(err) => write!(f, "Cannot create the virtio-mem device: {:?}", err),
             CreateRateLimiter(err) => write!(f, "Cannot create RateLimiter: {}", err),
             CreateNetDevice(err) => {
                 let mut err_msg = format!("{:?}", err);
@@ -299,10 +314,13 @@ fn create_vmm_and_vcpus(
         guest_memory,
         uffd,
         vcpus_handles: Vec::new(),
+        plugged_vcpu_count: vcpu_count,
         vcpus_exit_evt,
         mmio_device_manager,
         #[cfg(target_arch = "x86_64")]
         pio_device_manager,
+        pending_subscriber_updates: Vec::new(),
+        snapshot_layer: None,
     };
 
     Ok((vmm, vcpus))
@@ -329,11 +347,19 @@ pub fn build_microvm_for_boot(
     let boot_config = vm_resources.boot_source().ok_or(MissingKernelConfig)?;
 
     let track_dirty_pages = vm_resources.track_dirty_pages();
-    let guest_memory =
-        create_guest_memory(vm_resources.vm_config().mem_size_mib, track_dirty_pages)?;
+    let vm_config = vm_resources.vm_config();
+    let guest_memory = create_guest_memory(
+        vm_config.mem_size_mib,
+        vm_config.hotplug_size_mib,
+        track_dirty_pages,
+        vm_resources.memory_backing(),
+    )?;
+    let hotplug_region = hotplug_memory_region(vm_config.mem_size_mib, vm_config.hotplug_size_mib)
+        .map(|(addr, size)| (addr, size as u64));
     let vcpu_config = vm_resources.vcpu_config();
-    let entry_addr = load_kernel(boot_config, &guest_memory)?;
-    let initrd = load_initrd_from_config(boot_config, &guest_memory)?;
+    // The kernel and the initrd must be loaded in the memory the guest boots with.
+    let entry_addr = load_kernel(boot_config, &boot_memory(&guest_memory, hotplug_region))?;
+    let initrd = load_initrd_from_config(boot_config, &boot_memory(&guest_memory, hotplug_region))?;
     // Clone the command-line so that a failed boot doesn't pollute the original.
     #[allow(unused_mut)]
     let mut boot_cmdline = linux_loader::cmdline::Cmdline::new(arch::CMDLINE_MAX_SIZE);
@@ -358,6 +384,9 @@ pub fn build_microvm_for_boot(
 
     boot_cmdline.insert_str(boot_args)?;
 
+    // The hot-pluggable vCPUs are created at boot as well, since neither can the VMM thread
+    // spawn the vCPU threads once its seccomp filter is installed, nor can vCPUs be added to
+    // the interrupt controller once initialized on aarch64. They are only plugged at runtime.
     let (mut vmm, mut vcpus) = create_vmm_and_vcpus(
         instance_info,
         event_manager,
@@ -366,6 +395,7 @@ pub fn build_microvm_for_boot(
         track_dirty_pages,
         vcpu_config.vcpu_count,
     )?;
+    vmm.plugged_vcpu_count = vm_config.vcpu_count;
 
     // The boot timer device needs to be the first device attached in order
     // to maintain the same MMIO address referenced in the documentation
@@ -390,9 +420,60 @@ pub fn build_microvm_for_boot(
         vm_resources.net_builder.iter(),
         event_manager,
     )?;
+    attach_vhost_user_devices(
+        &mut vmm,
+        &mut boot_cmdline,
+        vm_resources.vhost_user.iter(),
+        event_manager,
+    )?;
     if let Some(unix_vsock) = vm_resources.vsock.get() {
         attach_unixsock_vsock_device(&mut vmm, &mut boot_cmdline, unix_vsock, event_manager)?;
     }
+    if let Some((addr, size)) = hotplug_region {
+        let block_size = (vm_resources.memory_backing().hotplug_block_size_mib() as u64) << 20;
+        let mem = VirtioMem::new(
+            addr,
+            size,
+            block_size,
+            (vm_config.hotplug_requested_mib as u64) << 20,
+            false,
+        )
+        .map_err(CreateMemDevice)?;
+        attach_mem_device(
+            &mut vmm,
+            &mut boot_cmdline,
+            &Arc::new(Mutex::new(mem)),
+            event_manager,
+        )?;
+    }
+    // The balloon controller follows the memory plugged through the virtio-mem device.
+    if let (Some(balloon), Some(config)) = (
+        vm_resources.balloon.get(),
+        vm_resources.balloon.auto_adjust(),
+    ) {
+        attach_balloon_controller(
+            &vmm,
+            balloon,
+            config,
+            vm_config.mem_size_mib as u64,
+            event_manager,
+        )?;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    if vm_config.max_vcpus() > vm_config.vcpu_count {
+        attach_cpu_hotplug_controller(&mut vmm, vm_config.max_vcpus(), vm_config.vcpu_count)?;
+    }
+    // The guest only brings up the boot vCPUs, the others are onlined once plugged.
+    #[cfg(target_arch = "aarch64")]
+    if vm_config.max_vcpus() > vm_config.vcpu_count {
+        boot_cmdline.insert("maxcpus", &vm_config.vcpu_count.to_string())?;
+    }
+
+    // Reserve the hot-plug slots after all the boot devices, so that they get the IRQs first.
+    vmm.mmio_device_manager
+        .reserve_hotplug_slots(HOTPLUG_SLOTS_COUNT, &mut boot_cmdline)
+        .map_err(RegisterMmioDevice)?;
 
     if let Some(init) = init_params {
         boot_cmdline.insert_str(format!("--{}", init))?;
@@ -460,6 +541,7 @@ pub fn build_microvm_from_snapshot(
     let vcpu_count = u8::try_from(microvm_state.vcpu_states.len())
         .map_err(|_| MicrovmStateError::InvalidInput)
         .map_err(RestoreMicrovmState)?;
+    let plugged_vcpu_count = microvm_state.plugged_vcpu_count();
 
     // Build Vmm.
     let (mut vmm, vcpus) = create_vmm_and_vcpus(
@@ -521,15 +603,36 @@ pub fn build_microvm_from_snapshot(
         .map_err(MicrovmStateError::RestoreVmState)
         .map_err(RestoreMicrovmState)?;
 
+    // The guest memory of the snapshot includes the hot-pluggable memory, if any.
+    let (hotplug_size, hotplug_requested) =
+        microvm_state
+            .device_states
+            .mem_device
+            .as_ref()
+            .map_or((0, 0), |mem| {
+                (
+                    mem.device_state.region_size(),
+                    mem.device_state.requested_size(),
+                )
+            });
     vm_resources
         .update_vm_config(&VmUpdateConfig {
-            vcpu_count: Some(vcpu_count),
-            mem_size_mib: Some(mem_size_mib(&guest_memory) as usize),
+            vcpu_count: Some(plugged_vcpu_count),
+            mem_size_mib: Some((mem_size_mib(&guest_memory) - (hotplug_size >> 20)) as usize),
             smt: Some(false),
             cpu_template: None,
             track_dirty_pages: Some(track_dirty_pages),
+            backing: Some(microvm_state.memory_state.backing.into()),
+            hotplug_size_mib: Some((hotplug_size >> 20) as usize),
+            hotplug_requested_mib: Some((hotplug_requested >> 20) as usize),
+            max_vcpu_count: Some(if plugged_vcpu_count < vcpu_count {
+                vcpu_count
+            } else {
+                0
+            }),
         })
         .map_err(SetVmResources)?;
+    vmm.plugged_vcpu_count = plugged_vcpu_count;
 
     // Restore devices states.
     let mmio_ctor_args = MMIODevManagerConstructorArgs {
@@ -548,6 +651,20 @@ pub fn build_microvm_from_snapshot(
     vmm.emulate_serial_init()
         .map_err(StartMicrovmError::Internal)?;
 
+    // The policy of the balloon controller is restored along with the balloon device.
+    if let (Some(balloon), Some(config)) = (
+        vm_resources.balloon.get(),
+        vm_resources.balloon.auto_adjust(),
+    ) {
+        attach_balloon_controller(
+            &vmm,
+            balloon,
+            config,
+            vm_resources.vm_config().mem_size_mib as u64,
+            event_manager,
+        )?;
+    }
+
     // Move vcpus to their own threads and start their state machine in the 'Paused' state.
     vmm.start_vcpus(
         vcpus,
@@ -578,14 +695,42 @@ pub fn build_microvm_from_snapshot(
     Ok(vmm)
 }
 
-/// Creates GuestMemory of `mem_size_mib` MiB in size.
+/// Returns the hot-pluggable memory region of `hotplug_size_mib` MiB, if any, for a guest
+/// with `mem_size_mib` MiB of boot memory.
+fn hotplug_memory_region(
+    mem_size_mib: usize,
+    hotplug_size_mib: usize,
+) -> Option<(GuestAddress, usize)> {
+    if hotplug_size_mib == 0 {
+        return None;
+    }
+    Some(arch::hotplug_memory_region(
+        mem_size_mib << 20,
+        hotplug_size_mib << 20,
+    ))
+}
+
+/// Creates GuestMemory of `mem_size_mib` MiB in size, followed by `hotplug_size_mib` MiB of
+/// hot-pluggable memory, backed by the given kind of memory.
+/// Shared memory can be mapped by other processes.
 pub fn create_guest_memory(
     mem_size_mib: usize,
+    hotplug_size_mib: usize,
     track_dirty_pages: bool,
+    backing: MemoryBacking,
 ) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
     let mem_size = mem_size_mib << 20;
-    let arch_mem_regions = arch::arch_memory_regions(mem_size);
-
+    let mut arch_mem_regions = arch::arch_memory_regions(mem_size);
+    arch_mem_regions.extend(hotplug_memory_region(mem_size_mib, hotplug_size_mib));
+
+    if backing.is_shared() {
+        return vm_memory::create_shared_guest_memory(
+            &arch_mem_regions,
+            backing.huge_page_size(),
+            track_dirty_pages,
+        )
+        .map_err(StartMicrovmError::GuestMemoryMmap);
+    }
     vm_memory::create_guest_memory(
         &arch_mem_regions
             .iter()
@@ -818,12 +963,14 @@ pub fn configure_system_for_boot(
     boot_cmdline: LoaderKernelCmdline,
 ) -> std::result::Result<(), StartMicrovmError> {
     use self::StartMicrovmError::*;
+    // The guest only finds the hot-pluggable memory through the virtio-mem device.
+    let boot_memory = vmm.boot_memory();
     #[cfg(target_arch = "x86_64")]
     {
         for vcpu in vcpus.iter_mut() {
             vcpu.kvm_vcpu
                 .configure(
-                    vmm.guest_memory(),
+                    &boot_memory,
                     entry_addr,
                     &vcpu_config,
                     vmm.vm.supported_cpuid().clone(),
@@ -835,17 +982,31 @@ pub fn configure_system_for_boot(
         // Write the kernel command line to guest memory. This is x86_64 specific, since on
         // aarch64 the command line will be specified through the FDT.
         linux_loader::loader::load_cmdline::<vm_memory::GuestMemoryMmap>(
-            vmm.guest_memory(),
+            &boot_memory,
             GuestAddress(arch::x86_64::layout::CMDLINE_START),
             &boot_cmdline,
         )
         .map_err(LoadCommandline)?;
+        // The guest finds the hot-pluggable vCPUs in the ACPI tables.
+        let cpu_hotplug = vmm
+            .mmio_device_manager
+            .get_device_info()
+            .get(&(
+                arch::DeviceType::CpuHotplug,
+                arch::DeviceType::CpuHotplug.to_string(),
+            ))
+            .map(|dev_info| arch::x86_64::acpi::CpuHotplugInfo {
+                max_cpus: vcpus.len() as u8,
+                mmio_addr: dev_info.addr,
+                irq: dev_info.irqs[0],
+            });
         arch::x86_64::configure_system(
-            &vmm.guest_memory,
+            &boot_memory,
             vm_memory::GuestAddress(arch::x86_64::layout::CMDLINE_START),
             boot_cmdline.as_str().len() + 1,
             initrd,
-            vcpus.len() as u8,
+            vmm.plugged_vcpu_count,
+            cpu_hotplug.as_ref(),
         )
         .map_err(ConfigureSystem)?;
     }
@@ -853,7 +1014,7 @@ pub fn configure_system_for_boot(
     {
         for vcpu in vcpus.iter_mut() {
             vcpu.kvm_vcpu
-                .configure(vmm.guest_memory(), entry_addr)
+                .configure(&boot_memory, entry_addr)
                 .map_err(Error::VcpuConfigure)
                 .map_err(Internal)?;
         }
@@ -863,10 +1024,10 @@ pub fn configure_system_for_boot(
             .map(|cpu| cpu.kvm_vcpu.get_mpidr())
             .collect();
         arch::aarch64::configure_system(
-            &vmm.guest_memory,
+            &boot_memory,
             boot_cmdline.as_str(),
             vcpu_mpidr,
-            vmm.mmio_device_manager.get_device_info(),
+            &vmm.mmio_device_manager.get_fdt_device_info(),
             vmm.vm.get_irqchip(),
             initrd,
         )
@@ -895,6 +1056,25 @@ fn attach_virtio_device<T: 'static + VirtioDevice + MutEventSubscriber>(
         .map(|_| ())
 }
 
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn attach_cpu_hotplug_controller(
+    vmm: &mut Vmm,
+    max_vcpus: u8,
+    plugged_vcpus: u8,
+) -> std::result::Result<(), StartMicrovmError> {
+    use self::StartMicrovmError::*;
+
+    let controller = devices::pseudo::CpuHotplugController::new(max_vcpus, plugged_vcpus)
+        .map_err(Error::EventFd)
+        .map_err(Internal)?;
+
+    vmm.mmio_device_manager
+        .register_mmio_cpu_hotplug(vmm.vm.fd(), Arc::new(Mutex::new(controller)), None)
+        .map_err(RegisterMmioDevice)?;
+
+    Ok(())
+}
+
 pub(crate) fn attach_boot_timer_device(
     vmm: &mut Vmm,
     request_ts: TimestampUs,
@@ -927,7 +1107,12 @@ fn attach_block_devices<'a>(
                     "root=/dev/vda".to_string()
                 })?;
 
-                let flags = if locked.is_read_only() { "ro" } else { "rw" };
+                // The guest can write to read-only drives with a copy-on-write overlay.
+                let flags = if locked.is_read_only() && locked.cow_overlay_path().is_none() {
+                    "ro"
+                } else {
+                    "rw"
+                };
                 cmdline.insert_str(flags)?;
             }
             locked.id().clone()
@@ -952,6 +1137,20 @@ fn attach_net_devices<'a>(
     Ok(())
 }
 
+fn attach_vhost_user_devices<'a>(
+    vmm: &mut Vmm,
+    cmdline: &mut LoaderKernelCmdline,
+    devices: impl Iterator<Item = &'a Arc<Mutex<VhostUser>>>,
+    event_manager: &mut EventManager,
+) -> std::result::Result<(), StartMicrovmError> {
+    for device in devices {
+        let id = device.lock().expect("Poisoned lock").id().clone();
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        attach_virtio_device(event_manager, vmm, id, device.clone(), cmdline)?;
+    }
+    Ok(())
+}
+
 fn attach_unixsock_vsock_device(
     vmm: &mut Vmm,
     cmdline: &mut LoaderKernelCmdline,
@@ -974,6 +1173,44 @@ fn attach_balloon_device(
     attach_virtio_device(event_manager, vmm, id, balloon.clone(), cmdline)
 }
 
+fn attach_balloon_controller(
+    vmm: &Vmm,
+    balloon: &Arc<Mutex<Balloon>>,
+    config: BalloonAutoAdjustConfig,
+    boot_mem_size_mib: u64,
+    event_manager: &mut EventManager,
+) -> std::result::Result<(), StartMicrovmError> {
+    // The guest memory includes the whole hot-pluggable region, so the controller looks up the
+    // plugged size on the virtio-mem device instead.
+    let mem = vmm
+        .get_bus_device(arch::DeviceType::Virtio(TYPE_MEM), MEM_DEV_ID)
+        .map(|busdev| {
+            busdev
+                .lock()
+                .expect("Poisoned lock")
+                .as_any()
+                .downcast_ref::<MmioTransport>()
+                // Only MmioTransport implements BusDevice at this point.
+                .expect("Unexpected BusDevice type")
+                .device()
+        });
+    let controller = BalloonController::new(balloon.clone(), config, boot_mem_size_mib, mem)
+        .map_err(StartMicrovmError::CreateBalloonController)?;
+    event_manager.add_subscriber(Arc::new(Mutex::new(controller)));
+    Ok(())
+}
+
+fn attach_mem_device(
+    vmm: &mut Vmm,
+    cmdline: &mut LoaderKernelCmdline,
+    mem: &Arc<Mutex<VirtioMem>>,
+    event_manager: &mut EventManager,
+) -> std::result::Result<(), StartMicrovmError> {
+    let id = String::from(mem.lock().expect("Poisoned lock").id());
+    // The device mutex mustn't be locked here otherwise it will deadlock.
+    attach_virtio_device(event_manager, vmm, id, mem.clone(), cmdline)
+}
+
 // Adds `O_NONBLOCK` to the stdout flags.
 pub(crate) fn set_stdout_nonblocking() {
     let flags = unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_GETFL, 0) };
@@ -991,18 +1228,21 @@ pub mod tests {
     use std::io::Cursor;
 
     use arch::DeviceType;
+    use devices::virtio::net::NetBackend;
     use devices::virtio::vsock::VSOCK_DEV_ID;
-    use devices::virtio::{TYPE_BALLOON, TYPE_BLOCK, TYPE_VSOCK};
+    use devices::virtio::{MEM_DEV_ID, TYPE_BALLOON, TYPE_BLOCK, TYPE_MEM, TYPE_VSOCK};
     use linux_loader::cmdline::Cmdline;
     use mmds::data_store::{Mmds, MmdsVersion};
     use mmds::ns::MmdsNetworkStack;
     use utils::tempfile::TempFile;
-    use vm_memory::GuestMemory;
+    use vm_memory::{GuestMemory, GuestMemoryRegion};
 
     use super::*;
     use crate::vmm_config::balloon::{BalloonBuilder, BalloonDeviceConfig, BALLOON_DEV_ID};
     use crate::vmm_config::boot_source::DEFAULT_KERNEL_CMDLINE;
-    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, CacheType, FileEngineType};
+    use crate::vmm_config::drive::{
+        BlockBuilder, BlockDeviceConfig, CacheType, FileEngineType, ImageFormat,
+    };
     use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::vsock::{VsockBuilder, VsockDeviceConfig};
@@ -1013,6 +1253,7 @@ pub mod tests {
         partuuid: Option<String>,
         is_read_only: bool,
         cache_type: CacheType,
+        num_queues: usize,
     }
 
     impl CustomBlockConfig {
@@ -1029,8 +1270,14 @@ pub mod tests {
                 partuuid,
                 is_read_only,
                 cache_type,
+                num_queues: 1,
             }
         }
+
+        pub(crate) fn with_num_queues(mut self, num_queues: usize) -> Self {
+            self.num_queues = num_queues;
+            self
+        }
     }
 
     fn default_mmio_device_manager() -> MMIODeviceManager {
@@ -1068,7 +1315,7 @@ pub mod tests {
     }
 
     pub(crate) fn default_vmm() -> Vmm {
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, 0, false, MemoryBacking::Anonymous).unwrap();
 
         let vcpus_exit_evt = EventFd::new(libc::EFD_NONBLOCK)
             .map_err(Error::EventFd)
@@ -1098,10 +1345,13 @@ pub mod tests {
             guest_memory,
             uffd: None,
             vcpus_handles: Vec::new(),
+            plugged_vcpu_count: 0,
             vcpus_exit_evt,
             mmio_device_manager,
             #[cfg(target_arch = "x86_64")]
             pio_device_manager,
+            pending_subscriber_updates: Vec::new(),
+            snapshot_layer: None,
         }
     }
 
@@ -1130,6 +1380,9 @@ pub mod tests {
                 cache_type: custom_block_cfg.cache_type,
                 rate_limiter: None,
                 file_engine_type: FileEngineType::default(),
+                num_queues: custom_block_cfg.num_queues,
+                format: ImageFormat::Raw,
+                cow_overlay_path: None,
             };
             block_dev_configs.insert(block_device_config).unwrap();
         }
@@ -1165,6 +1418,7 @@ pub mod tests {
         mmds.set_version(mmds_version).unwrap();
         net.lock().unwrap().configure_mmds_network_stack(
             MmdsNetworkStack::default_ipv4_addr(),
+            None,
             Arc::new(Mutex::new(mmds)),
         );
 
@@ -1207,6 +1461,24 @@ pub mod tests {
             .is_some());
     }
 
+    pub(crate) fn insert_mem_device(
+        vmm: &mut Vmm,
+        cmdline: &mut Cmdline,
+        event_manager: &mut EventManager,
+        hotplug_size_mib: usize,
+    ) -> (GuestAddress, u64) {
+        let (addr, size) = hotplug_memory_region(128, hotplug_size_mib).unwrap();
+        let mem = VirtioMem::new(addr, size as u64, 2 << 20, 0, false).unwrap();
+
+        assert!(attach_mem_device(vmm, cmdline, &Arc::new(Mutex::new(mem)), event_manager).is_ok());
+
+        assert!(vmm
+            .mmio_device_manager
+            .get_device(DeviceType::Virtio(TYPE_MEM), MEM_DEV_ID)
+            .is_some());
+        (addr, size as u64)
+    }
+
     fn make_test_bin() -> Vec<u8> {
         let mut fake_bin = Vec::new();
         fake_bin.resize(1_000_000, 0xAA);
@@ -1283,21 +1555,53 @@ pub mod tests {
 
         // Case 1: create guest memory without dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, false).unwrap();
+            let guest_memory =
+                create_guest_memory(mem_size, 0, false, MemoryBacking::Anonymous).unwrap();
             assert!(!is_dirty_tracking_enabled(&guest_memory));
         }
 
         // Case 2: create guest memory with dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, true).unwrap();
+            let guest_memory =
+                create_guest_memory(mem_size, 0, true, MemoryBacking::Anonymous).unwrap();
             assert!(is_dirty_tracking_enabled(&guest_memory));
         }
+
+        // Case 3: create guest memory which can be shared with vhost-user backends
+        {
+            let guest_memory =
+                create_guest_memory(mem_size, 0, false, MemoryBacking::Memfd).unwrap();
+            assert!(guest_memory
+                .iter()
+                .all(|region| region.file_offset().is_some()));
+        }
+
+        // Case 4: the memory gap splits the guest memory in regions which can't be backed by
+        // 1 GiB pages.
+        #[cfg(target_arch = "x86_64")]
+        assert!(create_guest_memory(mem_size, 0, false, MemoryBacking::Hugetlbfs1G).is_err());
+
+        // Case 5: the hot-pluggable memory follows the boot memory, and is left out of the
+        // memory the guest boots with.
+        {
+            let guest_memory =
+                create_guest_memory(128, 256, false, MemoryBacking::Anonymous).unwrap();
+            assert_eq!(mem_size_mib(&guest_memory), 384);
+            let (addr, size) = hotplug_memory_region(128, 256).unwrap();
+            assert_eq!(size, 256 << 20);
+            assert!(guest_memory.find_region(addr).is_some());
+
+            let boot_memory = boot_memory(&guest_memory, Some((addr, size as u64)));
+            assert_eq!(mem_size_mib(&boot_memory), 128);
+            assert!(boot_memory.find_region(addr).is_none());
+        }
+        assert!(hotplug_memory_region(128, 0).is_none());
     }
 
     #[test]
     fn test_create_vcpus() {
         let vcpu_count = 2;
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, 0, false, MemoryBacking::Anonymous).unwrap();
 
         #[allow(unused_mut)]
         let mut vm = setup_kvm_vm(&guest_memory, false).unwrap();
@@ -1321,6 +1625,11 @@ pub mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            backend: NetBackend::Userspace,
+            tx_filter: None,
+            dhcp: None,
+            user_network: None,
         };
 
         let mut cmdline = default_kernel_cmdline();
@@ -1535,6 +1844,8 @@ pub mod tests {
             amount_mib: 0,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            free_page_reporting: false,
+            auto_adjust: None,
         };
 
         let mut cmdline = default_kernel_cmdline();
@@ -1546,6 +1857,80 @@ pub mod tests {
             .contains("virtio_mmio.device=4K@0xd0000000:5"));
     }
 
+    #[test]
+    fn test_attach_balloon_controller() {
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let mut vmm = default_vmm();
+        let mut cmdline = default_kernel_cmdline();
+        let auto_adjust = BalloonAutoAdjustConfig {
+            target_free_memory_percent: 20,
+            min_amount_mib: 0,
+            max_amount_mib: 64,
+            step_mib: 8,
+        };
+
+        let mut builder = BalloonBuilder::new();
+        builder
+            .set(BalloonDeviceConfig {
+                amount_mib: 0,
+                deflate_on_oom: false,
+                stats_polling_interval_s: 1,
+                free_page_reporting: false,
+                auto_adjust: Some(auto_adjust),
+            })
+            .unwrap();
+        let balloon = builder.get().unwrap();
+        attach_balloon_device(&mut vmm, &mut cmdline, balloon, &mut event_manager).unwrap();
+        // The controller looks up the plugged memory on the virtio-mem device.
+        insert_mem_device(&mut vmm, &mut cmdline, &mut event_manager, 256);
+        attach_balloon_controller(
+            &vmm,
+            balloon,
+            builder.auto_adjust().unwrap(),
+            128,
+            &mut event_manager,
+        )
+        .unwrap();
+
+        // The controller waits for the statistics updates of the balloon.
+        balloon.lock().unwrap().stats_update_evt().write(1).unwrap();
+        assert_eq!(event_manager.run_with_timeout(50).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_attach_mem_device() {
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let mut vmm = default_vmm();
+
+        let mut cmdline = default_kernel_cmdline();
+        let region = insert_mem_device(&mut vmm, &mut cmdline, &mut event_manager, 256);
+        assert_eq!(vmm.hotplug_memory_region(), Some(region));
+        // Check if the virtio-mem device is described in kernel_cmdline.
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        assert!(cmdline
+            .as_str()
+            .contains("virtio_mmio.device=4K@0xd0000000:5"));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_attach_cpu_hotplug_controller() {
+        let mut vmm = default_vmm();
+
+        attach_cpu_hotplug_controller(&mut vmm, 4, 2).unwrap();
+        let controller = vmm
+            .get_bus_device(DeviceType::CpuHotplug, &DeviceType::CpuHotplug.to_string())
+            .unwrap();
+        let locked_controller = controller.lock().unwrap();
+        let controller = locked_controller
+            .as_any()
+            .downcast_ref::<devices::pseudo::CpuHotplugController>()
+            .unwrap();
+        assert_eq!(controller.max_cpus(), 4);
+        assert!(controller.is_enabled(1));
+        assert!(!controller.is_enabled(2));
+    }
+
     #[test]
     fn test_attach_vsock_device() {
         let mut event_manager = EventManager::new().expect("Unable to create EventManager");
@@ -1570,6 +1955,12 @@ pub mod tests {
         let err = AttachBlockDevice(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
 
+        let err = CreateBalloonController(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = CreateMemDevice(devices::virtio::mem::Error::InvalidRegion);
+        let _ = format!("{}{:?}", err, err);
+
         let err = CreateNetDevice(devices::virtio::net::Error::EventFd(
             io::Error::from_raw_os_error(0),
         ));
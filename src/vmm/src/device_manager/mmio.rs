@@ -18,14 +18,17 @@ use devices::legacy::RTCDevice;
 #[cfg(target_arch = "aarch64")]
 use devices::legacy::SerialDevice;
 use devices::pseudo::BootTimer;
+#[cfg(target_arch = "x86_64")]
+use devices::pseudo::CpuHotplugController;
 use devices::virtio::{
-    Balloon, Block, MmioTransport, Net, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET,
-    TYPE_VSOCK,
+    Balloon, Block, MmioHotplugSlot, MmioTransport, Net, VhostUser, VirtioDevice, VirtioMem,
+    TYPE_BALLOON, TYPE_BLOCK, TYPE_MEM, TYPE_NET, TYPE_VSOCK,
 };
 use devices::BusDevice;
+use event_manager::SubscriberId;
 use kvm_ioctls::{IoEventAddress, VmFd};
 use linux_loader::cmdline as kernel_cmdline;
-use logger::info;
+use logger::{info, warn};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_allocator::{AddressAllocator, AllocPolicy, IdAllocator};
@@ -41,6 +44,8 @@ pub enum Error {
     Cmdline(linux_loader::cmdline::Error),
     /// The device couldn't be found.
     DeviceNotFound,
+    /// The device wasn't hot-plugged, so it can't be unplugged.
+    DeviceNotHotplugged,
     /// Failure in creating or cloning an event fd.
     EventFd(io::Error),
     /// Incorrect device type.
@@ -49,10 +54,16 @@ pub enum Error {
     InternalDeviceError(String),
     /// Invalid configuration attempted.
     InvalidInput,
+    /// All the hot-plug slots are in use.
+    NoFreeHotplugSlot,
     /// Registering an IO Event failed.
     RegisterIoEvent(kvm_ioctls::Error),
     /// Registering an IRQ FD failed.
     RegisterIrqFd(kvm_ioctls::Error),
+    /// Unregistering an IO Event failed.
+    UnregisterIoEvent(kvm_ioctls::Error),
+    /// Unregistering an IRQ FD failed.
+    UnregisterIrqFd(kvm_ioctls::Error),
     /// Failed to update the mmio device.
     UpdateFailed,
     /// Allocation logic error.
@@ -73,6 +84,10 @@ impl fmt::Display for Error {
             Error::RegisterIoEvent(e) => write!(f, "failed to register IO event: {}", e),
             Error::RegisterIrqFd(e) => write!(f, "failed to register irqfd: {}", e),
             Error::DeviceNotFound => write!(f, "the device couldn't be found"),
+            Error::DeviceNotHotplugged => write!(f, "the device wasn't hot-plugged"),
+            Error::NoFreeHotplugSlot => write!(f, "no free hot-plug slot left"),
+            Error::UnregisterIoEvent(e) => write!(f, "failed to unregister IO event: {}", e),
+            Error::UnregisterIrqFd(e) => write!(f, "failed to unregister irqfd: {}", e),
             Error::UpdateFailed => write!(f, "failed to update the mmio device"),
             Error::AllocatorError(e) => write!(f, "failed to allocate requested resource: {}", e),
         }
@@ -99,12 +114,32 @@ pub struct MMIODeviceInfo {
     pub irqs: Vec<u32>,
 }
 
+/// Number of MMIO slots reserved at boot for the devices hot-plugged in the running microVM.
+pub const HOTPLUG_SLOTS_COUNT: usize = 4;
+
+/// A device plugged in a hot-plug slot.
+struct HotpluggedDevice {
+    identifier: (DeviceType, String),
+    transport: Arc<Mutex<MmioTransport>>,
+    // Set once the device is subscribed to the event manager.
+    subscriber_id: Option<SubscriberId>,
+}
+
+/// A MMIO slot reserved at boot for hot-plugging devices.
+struct HotplugSlot {
+    info: MMIODeviceInfo,
+    // The bus device registered at the slot address.
+    slot: Arc<Mutex<MmioHotplugSlot>>,
+    device: Option<HotpluggedDevice>,
+}
+
 /// Manages the complexities of registering a MMIO device.
 pub struct MMIODeviceManager {
     pub(crate) bus: devices::Bus,
     pub(crate) irq_allocator: IdAllocator,
     pub(crate) address_allocator: AddressAllocator,
     pub(crate) id_to_dev_info: HashMap<(DeviceType, String), MMIODeviceInfo>,
+    hotplug_slots: Vec<HotplugSlot>,
 }
 
 impl MMIODeviceManager {
@@ -120,6 +155,7 @@ impl MMIODeviceManager {
                 .map_err(Error::AllocatorError)?,
             bus: devices::Bus::new(),
             id_to_dev_info: HashMap::new(),
+            hotplug_slots: Vec::new(),
         })
     }
 
@@ -155,7 +191,40 @@ impl MMIODeviceManager {
         Ok(())
     }
 
+    /// Register the queue events and the interrupt event of a virtio device at a specific slot.
+    fn register_virtio_notifiers(
+        vm: &VmFd,
+        device: &dyn VirtioDevice,
+        slot: &MMIODeviceInfo,
+    ) -> Result<()> {
+        let io_addr =
+            IoEventAddress::Mmio(slot.addr + u64::from(devices::virtio::NOTIFY_REG_OFFSET));
+        for (i, queue_evt) in device.queue_events().iter().enumerate() {
+            vm.register_ioevent(queue_evt, &io_addr, i as u32)
+                .map_err(Error::RegisterIoEvent)?;
+        }
+        vm.register_irqfd(device.interrupt_evt(), slot.irqs[0])
+            .map_err(Error::RegisterIrqFd)
+    }
+
+    /// Unregister the queue events and the interrupt event of a virtio device at a specific slot.
+    fn unregister_virtio_notifiers(
+        vm: &VmFd,
+        device: &dyn VirtioDevice,
+        slot: &MMIODeviceInfo,
+    ) -> Result<()> {
+        let io_addr =
+            IoEventAddress::Mmio(slot.addr + u64::from(devices::virtio::NOTIFY_REG_OFFSET));
+        for (i, queue_evt) in device.queue_events().iter().enumerate() {
+            vm.unregister_ioevent(queue_evt, &io_addr, i as u32)
+                .map_err(Error::UnregisterIoEvent)?;
+        }
+        vm.unregister_irqfd(device.interrupt_evt(), slot.irqs[0])
+            .map_err(Error::UnregisterIrqFd)
+    }
+
     /// Register a virtio-over-MMIO device to be used via MMIO transport at a specific slot.
+    /// If the slot is a hot-plug slot, the device is plugged in it.
     pub fn register_mmio_virtio(
         &mut self,
         vm: &VmFd,
@@ -172,17 +241,216 @@ impl MMIODeviceManager {
         {
             let locked_device = mmio_device.locked_device();
             identifier = (DeviceType::Virtio(locked_device.device_type()), device_id);
-            for (i, queue_evt) in locked_device.queue_events().iter().enumerate() {
-                let io_addr =
-                    IoEventAddress::Mmio(slot.addr + u64::from(devices::virtio::NOTIFY_REG_OFFSET));
-                vm.register_ioevent(queue_evt, &io_addr, i as u32)
-                    .map_err(Error::RegisterIoEvent)?;
+            Self::register_virtio_notifiers(vm, &*locked_device, slot)?;
+        }
+
+        if let Some(index) = self
+            .hotplug_slots
+            .iter()
+            .position(|hotplug_slot| hotplug_slot.info == *slot)
+        {
+            self.plug_mmio_virtio(index, identifier, mmio_device)
+        } else {
+            self.register_mmio_device(identifier, slot.clone(), Arc::new(Mutex::new(mmio_device)))
+        }
+    }
+
+    /// Plug an already registered virtio-over-MMIO device in the hot-plug slot at `index`.
+    fn plug_mmio_virtio(
+        &mut self,
+        index: usize,
+        identifier: (DeviceType, String),
+        mmio_device: MmioTransport,
+    ) -> Result<()> {
+        let hotplug_slot = &mut self.hotplug_slots[index];
+        if hotplug_slot.device.is_some() {
+            return Err(Error::InvalidInput);
+        }
+
+        let transport = Arc::new(Mutex::new(mmio_device));
+        hotplug_slot
+            .slot
+            .lock()
+            .expect("Poisoned lock")
+            .set_transport(Some(transport.clone()));
+        hotplug_slot.device = Some(HotpluggedDevice {
+            identifier: identifier.clone(),
+            transport,
+            subscriber_id: None,
+        });
+        self.id_to_dev_info
+            .insert(identifier, hotplug_slot.info.clone());
+        Ok(())
+    }
+
+    /// Reserve up to `count` slots for hot-plugging devices in the running microVM. The empty
+    /// slots are announced to the guest like regular devices.
+    ///
+    /// The slots are reserved with the IRQs left after attaching the boot devices, so fewer
+    /// slots may be available.
+    pub fn reserve_hotplug_slots(
+        &mut self,
+        count: usize,
+        _cmdline: &mut kernel_cmdline::Cmdline,
+    ) -> Result<()> {
+        for _ in 0..count {
+            let slot = match self.allocate_new_slot(1) {
+                Ok(slot) => slot,
+                Err(err) => {
+                    warn!(
+                        "Reserved {} out of {} hot-plug slots: {}",
+                        self.hotplug_slots.len(),
+                        count,
+                        err
+                    );
+                    break;
+                }
+            };
+            self.register_hotplug_slot(&slot)?;
+            #[cfg(target_arch = "x86_64")]
+            Self::add_virtio_device_to_cmdline(_cmdline, &slot)?;
+        }
+        Ok(())
+    }
+
+    /// Register an empty hot-plug slot on the bus.
+    pub(crate) fn register_hotplug_slot(&mut self, slot: &MMIODeviceInfo) -> Result<()> {
+        if slot.irqs.len() != 1 {
+            return Err(Error::InvalidInput);
+        }
+        let hotplug_slot = Arc::new(Mutex::new(MmioHotplugSlot::new()));
+        self.bus
+            .insert(hotplug_slot.clone(), slot.addr, slot.len)
+            .map_err(Error::BusError)?;
+        self.hotplug_slots.push(HotplugSlot {
+            info: slot.clone(),
+            slot: hotplug_slot,
+            device: None,
+        });
+        Ok(())
+    }
+
+    /// Gets the resources of the reserved hot-plug slots, whether they're in use or not.
+    pub fn hotplug_slots(&self) -> Vec<MMIODeviceInfo> {
+        self.hotplug_slots
+            .iter()
+            .map(|hotplug_slot| hotplug_slot.info.clone())
+            .collect()
+    }
+
+    /// Checks whether `addr` is the address of a hot-plug slot.
+    pub fn is_hotplug_slot(&self, addr: u64) -> bool {
+        self.hotplug_slots
+            .iter()
+            .any(|hotplug_slot| hotplug_slot.info.addr == addr)
+    }
+
+    fn hotplugged_device(&self, identifier: &(DeviceType, String)) -> Option<&HotpluggedDevice> {
+        self.hotplug_slots
+            .iter()
+            .filter_map(|hotplug_slot| hotplug_slot.device.as_ref())
+            .find(|device| device.identifier == *identifier)
+    }
+
+    /// Checks whether the specified device was hot-plugged.
+    pub fn is_hotplugged(&self, device_type: DeviceType, device_id: &str) -> bool {
+        self.hotplugged_device(&(device_type, device_id.to_string()))
+            .is_some()
+    }
+
+    /// Plug a virtio-over-MMIO device in a free hot-plug slot of the running microVM.
+    pub fn hotplug_mmio_virtio(
+        &mut self,
+        vm: &VmFd,
+        device_id: String,
+        mmio_device: MmioTransport,
+    ) -> Result<MMIODeviceInfo> {
+        let slot = self
+            .hotplug_slots
+            .iter()
+            .find(|hotplug_slot| hotplug_slot.device.is_none())
+            .map(|hotplug_slot| hotplug_slot.info.clone())
+            .ok_or(Error::NoFreeHotplugSlot)?;
+        let device_type = DeviceType::Virtio(mmio_device.locked_device().device_type());
+        if self
+            .id_to_dev_info
+            .contains_key(&(device_type, device_id.clone()))
+        {
+            return Err(Error::InvalidInput);
+        }
+
+        self.register_mmio_virtio(vm, device_id, mmio_device, &slot)?;
+        Ok(slot)
+    }
+
+    /// Unplug a hot-plugged virtio device from the running microVM, leaving its slot empty.
+    /// The guest driver is notified before the device goes away.
+    ///
+    /// Returns the unplugged device along with its event manager subscription, if any.
+    pub fn hotunplug_mmio_virtio(
+        &mut self,
+        vm: &VmFd,
+        virtio_type: u32,
+        device_id: &str,
+    ) -> Result<(Arc<Mutex<dyn VirtioDevice>>, Option<SubscriberId>)> {
+        let identifier = (DeviceType::Virtio(virtio_type), device_id.to_string());
+        let not_found = if self.id_to_dev_info.contains_key(&identifier) {
+            Error::DeviceNotHotplugged
+        } else {
+            Error::DeviceNotFound
+        };
+        let hotplug_slot = self
+            .hotplug_slots
+            .iter_mut()
+            .find(|hotplug_slot| {
+                hotplug_slot
+                    .device
+                    .as_ref()
+                    .map_or(false, |device| device.identifier == identifier)
+            })
+            .ok_or(not_found)?;
+
+        {
+            // Safe to unwrap because the slot was matched against the device.
+            let mut transport = hotplug_slot
+                .device
+                .as_ref()
+                .unwrap()
+                .transport
+                .lock()
+                .expect("Poisoned lock");
+            if let Err(err) = transport.notify_removal() {
+                warn!("Failed to notify the removal of {}: {}", device_id, err);
             }
-            vm.register_irqfd(locked_device.interrupt_evt(), slot.irqs[0])
-                .map_err(Error::RegisterIrqFd)?;
+            Self::unregister_virtio_notifiers(vm, &*transport.locked_device(), &hotplug_slot.info)?;
         }
+        hotplug_slot
+            .slot
+            .lock()
+            .expect("Poisoned lock")
+            .set_transport(None);
+        let device = hotplug_slot.device.take().unwrap();
+        self.id_to_dev_info.remove(&identifier);
+        let virtio_device = device.transport.lock().expect("Poisoned lock").device();
+        Ok((virtio_device, device.subscriber_id))
+    }
 
-        self.register_mmio_device(identifier, slot.clone(), Arc::new(Mutex::new(mmio_device)))
+    /// Record the event manager subscription of a hot-plugged device.
+    pub fn set_hotplug_subscriber(
+        &mut self,
+        device_type: DeviceType,
+        device_id: &str,
+        subscriber_id: SubscriberId,
+    ) -> Result<()> {
+        let identifier = (device_type, device_id.to_string());
+        let device = self
+            .hotplug_slots
+            .iter_mut()
+            .filter_map(|hotplug_slot| hotplug_slot.device.as_mut())
+            .find(|device| device.identifier == identifier)
+            .ok_or(Error::DeviceNotHotplugged)?;
+        device.subscriber_id = Some(subscriber_id);
+        Ok(())
     }
 
     /// Append a registered virtio-over-MMIO device to the kernel cmdline.
@@ -288,11 +556,55 @@ impl MMIODeviceManager {
         self.register_mmio_device(identifier, slot, Arc::new(Mutex::new(device)))
     }
 
+    #[cfg(target_arch = "x86_64")]
+    /// Register the vCPU hotplug controller at the specified MMIO address if given as parameter,
+    /// otherwise allocate a new MMIO slot for it.
+    pub fn register_mmio_cpu_hotplug(
+        &mut self,
+        vm: &VmFd,
+        controller: Arc<Mutex<CpuHotplugController>>,
+        dev_info_opt: Option<MMIODeviceInfo>,
+    ) -> Result<MMIODeviceInfo> {
+        // Create a new MMIODeviceInfo object on boot path or unwrap the
+        // existing object on restore path.
+        let slot = if let Some(dev_info) = dev_info_opt {
+            dev_info
+        } else {
+            self.allocate_new_slot(1)?
+        };
+
+        vm.register_irqfd(
+            controller.lock().expect("Poisoned lock").interrupt_evt(),
+            slot.irqs[0],
+        )
+        .map_err(Error::RegisterIrqFd)?;
+
+        let identifier = (DeviceType::CpuHotplug, DeviceType::CpuHotplug.to_string());
+        self.register_mmio_device(identifier, slot.clone(), controller)?;
+        Ok(slot)
+    }
+
     /// Gets the information of the devices registered up to some point in time.
     pub fn get_device_info(&self) -> &HashMap<(DeviceType, String), MMIODeviceInfo> {
         &self.id_to_dev_info
     }
 
+    #[cfg(target_arch = "aarch64")]
+    /// Gets the information of the devices registered up to some point in time, along with the
+    /// empty hot-plug slots, which have to be described to the guest as well.
+    pub fn get_fdt_device_info(&self) -> HashMap<(DeviceType, String), MMIODeviceInfo> {
+        let mut device_info = self.id_to_dev_info.clone();
+        for (index, hotplug_slot) in self.hotplug_slots.iter().enumerate() {
+            if hotplug_slot.device.is_none() {
+                device_info.insert(
+                    (DeviceType::Virtio(0), format!("hotplug_slot{}", index)),
+                    hotplug_slot.info.clone(),
+                );
+            }
+        }
+        device_info
+    }
+
     #[cfg(target_arch = "x86_64")]
     /// Gets the number of interrupts used by the devices registered.
     pub fn used_irqs_count(&self) -> usize {
@@ -309,10 +621,13 @@ impl MMIODeviceManager {
         device_type: DeviceType,
         device_id: &str,
     ) -> Option<&Mutex<dyn BusDevice>> {
-        if let Some(dev_info) = self
-            .id_to_dev_info
-            .get(&(device_type, device_id.to_string()))
-        {
+        let identifier = (device_type, device_id.to_string());
+        if let Some(device) = self.hotplugged_device(&identifier) {
+            // Hot-plugged devices sit behind the slot registered on the bus.
+            let transport: &Mutex<dyn BusDevice> = &*device.transport;
+            return Some(transport);
+        }
+        if let Some(dev_info) = self.id_to_dev_info.get(&identifier) {
             if let Some((_, device)) = self.bus.get_device(dev_info.addr) {
                 return Some(device);
             }
@@ -393,6 +708,20 @@ impl MMIODeviceManager {
         Ok(())
     }
 
+    /// Returns whether any of the registered virtio devices is served by a vhost-user backend.
+    pub fn has_vhost_user_devices(&self) -> bool {
+        let mut found = false;
+        let _: Result<()> = self.for_each_virtio_device(|_, _, _, dev| {
+            found |= dev
+                .lock()
+                .expect("Poisoned lock")
+                .as_any()
+                .is::<VhostUser>();
+            Ok(())
+        });
+        found
+    }
+
     /// Artificially kick devices as if they had external events.
     pub fn kick_devices(&self) {
         info!("Artificially kick devices.");
@@ -410,26 +739,39 @@ impl MMIODeviceManager {
                         balloon.process_virtio_queues();
                     }
                 }
+                // Block and net devices served by a vhost-user backend have their queues
+                // processed outside of Firecracker, so only the in-process ones are kicked.
                 TYPE_BLOCK => {
-                    let block = virtio.as_mut_any().downcast_mut::<Block>().unwrap();
-                    // If device is activated, kick the block queue(s) to make up for any
-                    // pending or in-flight epoll events we may have not captured in snapshot.
-                    // No need to kick Ratelimiters because they are restored 'unblocked' so
-                    // any inflight `timer_fd` events can be safely discarded.
-                    if block.is_activated() {
-                        info!("kick block {}.", id);
-                        block.process_virtio_queues();
+                    if let Some(block) = virtio.as_mut_any().downcast_mut::<Block>() {
+                        // If device is activated, kick the block queue(s) to make up for any
+                        // pending or in-flight epoll events we may have not captured in
+                        // snapshot. No need to kick Ratelimiters because they are restored
+                        // 'unblocked' so any inflight `timer_fd` events can be safely discarded.
+                        if block.is_activated() {
+                            info!("kick block {}.", id);
+                            block.process_virtio_queues();
+                        }
                     }
                 }
                 TYPE_NET => {
-                    let net = virtio.as_mut_any().downcast_mut::<Net>().unwrap();
-                    // If device is activated, kick the net queue(s) to make up for any
+                    if let Some(net) = virtio.as_mut_any().downcast_mut::<Net>() {
+                        // If device is activated, kick the net queue(s) to make up for any
+                        // pending or in-flight epoll events we may have not captured in
+                        // snapshot. No need to kick Ratelimiters because they are restored
+                        // 'unblocked' so any inflight `timer_fd` events can be safely discarded.
+                        if net.is_activated() {
+                            info!("kick net {}.", id);
+                            net.process_virtio_queues();
+                        }
+                    }
+                }
+                TYPE_MEM => {
+                    let mem = virtio.as_mut_any().downcast_mut::<VirtioMem>().unwrap();
+                    // If device is activated, kick the request queue to make up for any
                     // pending or in-flight epoll events we may have not captured in snapshot.
-                    // No need to kick Ratelimiters because they are restored 'unblocked' so
-                    // any inflight `timer_fd` events can be safely discarded.
-                    if net.is_activated() {
-                        info!("kick net {}.", id);
-                        net.process_virtio_queues();
+                    if mem.is_activated() {
+                        info!("kick virtio-mem {}.", id);
+                        mem.process_virtio_queues();
                     }
                 }
                 TYPE_VSOCK => {
@@ -648,6 +990,31 @@ mod tests {
         );
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_register_cpu_hotplug_controller() {
+        let guest_mem =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x1000)], false)
+                .unwrap();
+        let mut vm = builder::setup_kvm_vm(&guest_mem, false).unwrap();
+        let mut device_manager = MMIODeviceManager::new(
+            0xd000_0000,
+            arch::MMIO_MEM_SIZE,
+            (arch::IRQ_BASE, arch::IRQ_MAX),
+        )
+        .unwrap();
+        assert!(builder::setup_interrupt_controller(&mut vm).is_ok());
+
+        let controller = Arc::new(Mutex::new(CpuHotplugController::new(4, 1).unwrap()));
+        let slot = device_manager
+            .register_mmio_cpu_hotplug(vm.fd(), controller, None)
+            .unwrap();
+        assert_eq!(slot.irqs, vec![arch::IRQ_BASE]);
+        assert!(device_manager
+            .get_device(DeviceType::CpuHotplug, &DeviceType::CpuHotplug.to_string())
+            .is_some());
+    }
+
     #[test]
     fn test_dummy_device() {
         let dummy = DummyDevice::new();
@@ -664,12 +1031,16 @@ mod tests {
                 Error::BusError(_) => format!("{}{:?}", err, err),
                 Error::Cmdline(_) => format!("{}{:?}", err, err),
                 Error::DeviceNotFound => format!("{}{:?}", err, err),
+                Error::DeviceNotHotplugged => format!("{}{:?}", err, err),
                 Error::EventFd(_) => format!("{}{:?}", err, err),
                 Error::IncorrectDeviceType => format!("{}{:?}", err, err),
                 Error::InternalDeviceError(_) => format!("{}{:?}", err, err),
                 Error::InvalidInput => format!("{}{:?}", err, err),
+                Error::NoFreeHotplugSlot => format!("{}{:?}", err, err),
                 Error::RegisterIoEvent(_) => format!("{}{:?}", err, err),
                 Error::RegisterIrqFd(_) => format!("{}{:?}", err, err),
+                Error::UnregisterIoEvent(_) => format!("{}{:?}", err, err),
+                Error::UnregisterIrqFd(_) => format!("{}{:?}", err, err),
                 Error::UpdateFailed => format!("{}{:?}", err, err),
                 Error::AllocatorError(_) => format!("{}{:?}", err, err),
             };
@@ -678,13 +1049,17 @@ mod tests {
         check_fmt_err(Error::BusError(devices::BusError::Overlap));
         check_fmt_err(Error::Cmdline(linux_loader::cmdline::Error::TooLarge));
         check_fmt_err(Error::DeviceNotFound);
+        check_fmt_err(Error::DeviceNotHotplugged);
         check_fmt_err(Error::EventFd(io::Error::from_raw_os_error(0)));
         check_fmt_err(Error::IncorrectDeviceType);
         check_fmt_err(Error::InternalDeviceError(String::new()));
         check_fmt_err(Error::InvalidInput);
+        check_fmt_err(Error::NoFreeHotplugSlot);
         check_fmt_err(Error::AllocatorError(vm_allocator::Error::Overflow));
         check_fmt_err(Error::RegisterIoEvent(errno::Error::new(0)));
         check_fmt_err(Error::RegisterIrqFd(errno::Error::new(0)));
+        check_fmt_err(Error::UnregisterIoEvent(errno::Error::new(0)));
+        check_fmt_err(Error::UnregisterIrqFd(errno::Error::new(0)));
         check_fmt_err(Error::UpdateFailed);
     }
 
@@ -758,6 +1133,161 @@ mod tests {
         assert_eq!(device_manager.used_irqs_count(), 2);
     }
 
+    #[test]
+    fn test_hotplug() {
+        let guest_mem =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0x0), 0x1000)], false)
+                .unwrap();
+        let mut vm = builder::setup_kvm_vm(&guest_mem, false).unwrap();
+        #[cfg(target_arch = "x86_64")]
+        assert!(builder::setup_interrupt_controller(&mut vm).is_ok());
+        #[cfg(target_arch = "aarch64")]
+        assert!(builder::setup_interrupt_controller(&mut vm, 1).is_ok());
+
+        let mut device_manager = MMIODeviceManager::new(
+            0xd000_0000,
+            arch::MMIO_MEM_SIZE,
+            (arch::IRQ_BASE, arch::IRQ_MAX),
+        )
+        .unwrap();
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
+        let boot_addr = device_manager
+            .register_virtio_test_device(
+                vm.fd(),
+                guest_mem.clone(),
+                Arc::new(Mutex::new(DummyDevice::new())),
+                &mut cmdline,
+                "boot",
+            )
+            .unwrap();
+        device_manager
+            .reserve_hotplug_slots(2, &mut cmdline)
+            .unwrap();
+        let slots = device_manager.hotplug_slots();
+        assert_eq!(slots.len(), 2);
+        assert!(!device_manager.is_hotplug_slot(boot_addr));
+        assert!(device_manager.is_hotplug_slot(slots[0].addr));
+        assert!(device_manager.is_hotplug_slot(slots[1].addr));
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(cmdline.as_str().matches("virtio_mmio.device=").count(), 3);
+        // The empty slots are not reported as devices.
+        assert_eq!(device_manager.get_device_info().len(), 1);
+
+        // Hot-plug a device in each slot.
+        let slot = device_manager
+            .hotplug_mmio_virtio(
+                vm.fd(),
+                "foo".to_string(),
+                MmioTransport::new(guest_mem.clone(), Arc::new(Mutex::new(DummyDevice::new()))),
+            )
+            .unwrap();
+        assert_eq!(slot, slots[0]);
+        assert!(device_manager.is_hotplugged(DeviceType::Virtio(0), "foo"));
+        assert!(!device_manager.is_hotplugged(DeviceType::Virtio(0), "boot"));
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(0), "foo")
+            .is_some());
+        assert_eq!(
+            device_manager.id_to_dev_info[&(DeviceType::Virtio(0), "foo".to_string())],
+            slots[0]
+        );
+        // The device answers on the bus through the slot.
+        let mut data = [0xff; 4];
+        assert!(device_manager.bus.read(slots[0].addr + 0x70, &mut data));
+        assert_eq!(data, [0; 4]);
+
+        // Ids can't be reused.
+        assert_eq!(
+            format!(
+                "{}",
+                device_manager
+                    .hotplug_mmio_virtio(
+                        vm.fd(),
+                        "foo".to_string(),
+                        MmioTransport::new(
+                            guest_mem.clone(),
+                            Arc::new(Mutex::new(DummyDevice::new()))
+                        ),
+                    )
+                    .unwrap_err()
+            ),
+            "invalid configuration"
+        );
+
+        device_manager
+            .hotplug_mmio_virtio(
+                vm.fd(),
+                "bar".to_string(),
+                MmioTransport::new(guest_mem.clone(), Arc::new(Mutex::new(DummyDevice::new()))),
+            )
+            .unwrap();
+        assert_eq!(
+            format!(
+                "{}",
+                device_manager
+                    .hotplug_mmio_virtio(
+                        vm.fd(),
+                        "baz".to_string(),
+                        MmioTransport::new(guest_mem, Arc::new(Mutex::new(DummyDevice::new()))),
+                    )
+                    .unwrap_err()
+            ),
+            "no free hot-plug slot left"
+        );
+
+        device_manager
+            .set_hotplug_subscriber(DeviceType::Virtio(0), "bar", SubscriberId::default())
+            .unwrap();
+        assert_eq!(
+            format!(
+                "{}",
+                device_manager
+                    .set_hotplug_subscriber(DeviceType::Virtio(0), "boot", SubscriberId::default())
+                    .unwrap_err()
+            ),
+            "the device wasn't hot-plugged"
+        );
+
+        // Only hot-plugged devices can be unplugged.
+        assert_eq!(
+            format!(
+                "{}",
+                device_manager
+                    .hotunplug_mmio_virtio(vm.fd(), 0, "boot")
+                    .unwrap_err()
+            ),
+            "the device wasn't hot-plugged"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                device_manager
+                    .hotunplug_mmio_virtio(vm.fd(), 0, "baz")
+                    .unwrap_err()
+            ),
+            "the device couldn't be found"
+        );
+
+        let (device, subscriber_id) = device_manager
+            .hotunplug_mmio_virtio(vm.fd(), 0, "foo")
+            .unwrap();
+        assert_eq!(device.lock().unwrap().device_type(), 0);
+        assert!(subscriber_id.is_none());
+        let (_, subscriber_id) = device_manager
+            .hotunplug_mmio_virtio(vm.fd(), 0, "bar")
+            .unwrap();
+        assert!(subscriber_id.is_some());
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(0), "foo")
+            .is_none());
+        assert!(!device_manager.is_hotplugged(DeviceType::Virtio(0), "bar"));
+        assert_eq!(device_manager.get_device_info().len(), 1);
+        assert_eq!(device_manager.hotplug_slots(), slots);
+        // The slot is empty again.
+        assert!(device_manager.bus.read(slots[0].addr + 0x08, &mut data));
+        assert_eq!(data, [0; 4]);
+    }
+
     #[test]
     fn test_slot_irq_allocation() {
         let mut device_manager = MMIODeviceManager::new(
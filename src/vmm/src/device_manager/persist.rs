@@ -6,19 +6,23 @@
 use std::result::Result;
 use std::sync::{Arc, Mutex};
 
-#[cfg(target_arch = "aarch64")]
 use arch::DeviceType;
+use devices::pseudo::cpu_hotplug::CpuHotplugControllerState;
+#[cfg(target_arch = "x86_64")]
+use devices::pseudo::CpuHotplugController;
 use devices::virtio::balloon::persist::{BalloonConstructorArgs, BalloonState};
 use devices::virtio::balloon::{Balloon, Error as BalloonError};
 use devices::virtio::block::persist::{BlockConstructorArgs, BlockState};
 use devices::virtio::block::{Block, Error as BlockError};
+use devices::virtio::mem::persist::{MemConstructorArgs, MemState};
+use devices::virtio::mem::{Error as MemError, VirtioMem};
 use devices::virtio::net::persist::{Error as NetError, NetConstructorArgs, NetState};
 use devices::virtio::net::Net;
 use devices::virtio::persist::{MmioTransportConstructorArgs, MmioTransportState};
 use devices::virtio::vsock::persist::{VsockConstructorArgs, VsockState, VsockUdsConstructorArgs};
 use devices::virtio::vsock::{Vsock, VsockError, VsockUnixBackend, VsockUnixBackendError};
 use devices::virtio::{
-    MmioTransport, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET, TYPE_VSOCK,
+    MmioTransport, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_MEM, TYPE_NET, TYPE_VSOCK,
 };
 use event_manager::{MutEventSubscriber, SubscriberOps};
 use kvm_ioctls::VmFd;
@@ -40,10 +44,13 @@ use crate::EventManager;
 pub enum Error {
     Balloon(BalloonError),
     Block(BlockError),
+    #[cfg(target_arch = "x86_64")]
+    CpuHotplug(std::io::Error),
     DeviceManager(super::mmio::Error),
     MmioTransport,
     #[cfg(target_arch = "aarch64")]
     Legacy(crate::Error),
+    Mem(MemError),
     Net(NetError),
     Vsock(VsockError),
     VsockUnixBackend(VsockUnixBackendError),
@@ -78,6 +85,20 @@ pub struct ConnectedBlockState {
     pub mmio_slot: MMIODeviceInfo,
 }
 
+#[derive(Clone, Versionize)]
+/// Holds the state of a virtio-mem device connected to the MMIO space.
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct ConnectedMemState {
+    /// Device identifier.
+    pub device_id: String,
+    /// Device state.
+    pub device_state: MemState,
+    /// Mmio transport state.
+    pub transport_state: MmioTransportState,
+    /// VmmResources.
+    pub mmio_slot: MMIODeviceInfo,
+}
+
 #[derive(Clone, Versionize)]
 /// Holds the state of a net device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
@@ -106,6 +127,16 @@ pub struct ConnectedVsockState {
     pub mmio_slot: MMIODeviceInfo,
 }
 
+#[derive(Clone, Versionize)]
+/// Holds the state of the vCPU hotplug controller connected to the MMIO space.
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct ConnectedCpuHotplugState {
+    /// Device state.
+    pub device_state: CpuHotplugControllerState,
+    /// VmmResources.
+    pub mmio_slot: MMIODeviceInfo,
+}
+
 #[cfg(target_arch = "aarch64")]
 #[derive(Clone, Versionize)]
 /// Holds the state of a legacy device connected to the MMIO space.
@@ -161,6 +192,15 @@ pub struct DeviceStates {
     /// Mmds version.
     #[version(start = 3, ser_fn = "mmds_version_serialize")]
     pub mmds_version: Option<MmdsVersionState>,
+    /// Resources of the reserved hot-plug slots.
+    #[version(start = 4, ser_fn = "hotplug_slots_serialize")]
+    pub hotplug_slots: Vec<MMIODeviceInfo>,
+    /// Virtio-mem device state.
+    #[version(start = 5, ser_fn = "mem_device_serialize")]
+    pub mem_device: Option<ConnectedMemState>,
+    /// vCPU hotplug controller state.
+    #[version(start = 6, ser_fn = "cpu_hotplug_serialize")]
+    pub cpu_hotplug: Option<ConnectedCpuHotplugState>,
 }
 
 /// A type used to extract the concrete Arc<Mutex<T>> for each of the device types when restoring
@@ -193,6 +233,37 @@ impl DeviceStates {
 
         Ok(())
     }
+
+    fn hotplug_slots_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 4 && !self.hotplug_slots.is_empty() {
+            warn!(
+                "Target version does not support hot-plug slots. Hot-plugged devices will be \
+                 restored as regular devices and the free slots will be dropped."
+            );
+        }
+
+        Ok(())
+    }
+
+    fn mem_device_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 5 && self.mem_device.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the virtio-mem device.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn cpu_hotplug_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 6 && self.cpu_hotplug.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement vCPU hotplug.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 pub struct MMIODevManagerConstructorArgs<'a> {
@@ -218,6 +289,9 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             #[cfg(target_arch = "aarch64")]
             legacy_devices: Vec::new(),
             mmds_version: None,
+            hotplug_slots: self.hotplug_slots(),
+            mem_device: None,
+            cpu_hotplug: None,
         };
         let _: Result<(), ()> = self.for_each_device(|devtype, devid, devinfo, bus_dev| {
             if *devtype == arch::DeviceType::BootTimer {
@@ -225,6 +299,22 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                 return Ok(());
             }
 
+            #[cfg(target_arch = "x86_64")]
+            {
+                if *devtype == DeviceType::CpuHotplug {
+                    let locked_bus_dev = bus_dev.lock().expect("Poisoned lock");
+                    let controller = locked_bus_dev
+                        .as_any()
+                        .downcast_ref::<CpuHotplugController>()
+                        .expect("Unexpected BusDevice type");
+                    states.cpu_hotplug = Some(ConnectedCpuHotplugState {
+                        device_state: controller.save(),
+                        mmio_slot: devinfo.clone(),
+                    });
+                    return Ok(());
+                }
+            }
+
             #[cfg(target_arch = "aarch64")]
             {
                 if *devtype == DeviceType::Serial || *devtype == DeviceType::Rtc {
@@ -270,6 +360,19 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                         mmio_slot: devinfo.clone(),
                     });
                 }
+                TYPE_MEM => {
+                    let mem_state = locked_device
+                        .as_any()
+                        .downcast_ref::<VirtioMem>()
+                        .unwrap()
+                        .save();
+                    states.mem_device = Some(ConnectedMemState {
+                        device_id: devid.clone(),
+                        device_state: mem_state,
+                        transport_state,
+                        mmio_slot: devinfo.clone(),
+                    });
+                }
                 TYPE_NET => {
                     let net = locked_device.as_any().downcast_ref::<Net>().unwrap();
                     if let (Some(mmds_ns), None) =
@@ -299,8 +402,8 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                     };
 
                     // Send Transport event to reset connections if device
-                    // is activated.
-                    if vsock.is_activated() {
+                    // is activated, unless the connections are preserved.
+                    if vsock.is_activated() && !vsock.backend().preserve_connections() {
                         vsock.send_transport_reset_event().unwrap_or_else(|err| {
                             error!("Failed to send reset transport event: {:?}", err);
                         });
@@ -370,6 +473,39 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             }
         }
 
+        // The guest reaches the vCPU hotplug controller through its ACPI tables, so it must be
+        // restored at the same address and interrupt line.
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(cpu_hotplug_state) = &state.cpu_hotplug {
+                let controller =
+                    CpuHotplugController::restore((), &cpu_hotplug_state.device_state)?;
+                dev_manager
+                    .address_allocator
+                    .allocate(
+                        MMIO_LEN,
+                        MMIO_LEN,
+                        AllocPolicy::ExactMatch(cpu_hotplug_state.mmio_slot.addr),
+                    )
+                    .map_err(|e| Error::DeviceManager(super::mmio::Error::AllocatorError(e)))?;
+                dev_manager.register_mmio_cpu_hotplug(
+                    vm,
+                    Arc::new(Mutex::new(controller)),
+                    Some(cpu_hotplug_state.mmio_slot.clone()),
+                )?;
+            }
+        }
+
+        // The hot-plug slots must be back on the bus before restoring the devices, so that the
+        // hot-plugged ones are plugged in their original slot.
+        for slot in &state.hotplug_slots {
+            dev_manager
+                .address_allocator
+                .allocate(MMIO_LEN, MMIO_LEN, AllocPolicy::ExactMatch(slot.addr))
+                .map_err(|e| Error::DeviceManager(super::mmio::Error::AllocatorError(e)))?;
+            dev_manager.register_hotplug_slot(slot)?;
+        }
+
         let mut restore_helper = |device: Arc<Mutex<dyn VirtioDevice>>,
                                   as_subscriber: Arc<Mutex<dyn MutEventSubscriber>>,
                                   id: &String,
@@ -394,14 +530,23 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             // For now this is why we do not restore the state of the
             // `IdAllocator` under `dev_manager`.
 
-            dev_manager
-                .address_allocator
-                .allocate(MMIO_LEN, MMIO_LEN, AllocPolicy::ExactMatch(slot.addr))
-                .map_err(|e| Error::DeviceManager(super::mmio::Error::AllocatorError(e)))?;
+            // The address of hot-plug slots has already been allocated above.
+            if !dev_manager.is_hotplug_slot(slot.addr) {
+                dev_manager
+                    .address_allocator
+                    .allocate(MMIO_LEN, MMIO_LEN, AllocPolicy::ExactMatch(slot.addr))
+                    .map_err(|e| Error::DeviceManager(super::mmio::Error::AllocatorError(e)))?;
+            }
 
+            let device_type = DeviceType::Virtio(mmio_transport.locked_device().device_type());
             dev_manager.register_mmio_virtio(vm, id.clone(), mmio_transport, slot)?;
 
-            event_manager.add_subscriber(as_subscriber);
+            let subscriber_id = event_manager.add_subscriber(as_subscriber);
+            // Hot-plugged devices must keep track of their subscription so they can be
+            // hot-unplugged later on.
+            if dev_manager.is_hotplugged(device_type, id) {
+                dev_manager.set_hotplug_subscriber(device_type, id, subscriber_id)?;
+            }
             Ok(())
         };
 
@@ -519,6 +664,22 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                 constructor_args.event_manager,
             )?;
         }
+
+        if let Some(mem_state) = &state.mem_device {
+            let device = Arc::new(Mutex::new(VirtioMem::restore(
+                MemConstructorArgs { mem: mem.clone() },
+                &mem_state.device_state,
+            )?));
+
+            restore_helper(
+                device.clone(),
+                device,
+                &mem_state.device_id,
+                &mem_state.transport_state,
+                &mem_state.mmio_slot,
+                constructor_args.event_manager,
+            )?;
+        }
         Ok(dev_manager)
     }
 }
@@ -526,6 +687,8 @@ impl<'a> Persist<'a> for MMIODeviceManager {
 #[cfg(test)]
 mod tests {
     use devices::virtio::block::CacheType;
+    use devices::virtio::net::NetBackend;
+    use devices::virtio::MEM_DEV_ID;
     use utils::tempfile::TempFile;
 
     use super::*;
@@ -569,6 +732,23 @@ mod tests {
         }
     }
 
+    impl PartialEq for ConnectedMemState {
+        fn eq(&self, other: &ConnectedMemState) -> bool {
+            // Actual device state equality is checked by the device's tests.
+            self.transport_state == other.transport_state && self.mmio_slot == other.mmio_slot
+        }
+    }
+
+    impl std::fmt::Debug for ConnectedMemState {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(
+                f,
+                "ConnectedMemDevice {{ transport_state: {:?}, mmio_slot: {:?} }}",
+                self.transport_state, self.mmio_slot
+            )
+        }
+    }
+
     impl PartialEq for ConnectedNetState {
         fn eq(&self, other: &ConnectedNetState) -> bool {
             // Actual device state equality is checked by the device's tests.
@@ -603,12 +783,21 @@ mod tests {
         }
     }
 
+    impl PartialEq for ConnectedCpuHotplugState {
+        fn eq(&self, other: &ConnectedCpuHotplugState) -> bool {
+            // Actual device state equality is checked by the device's tests.
+            self.mmio_slot == other.mmio_slot
+        }
+    }
+
     impl PartialEq for DeviceStates {
         fn eq(&self, other: &DeviceStates) -> bool {
             self.balloon_device == other.balloon_device
                 && self.block_devices == other.block_devices
                 && self.net_devices == other.net_devices
                 && self.vsock_device == other.vsock_device
+                && self.mem_device == other.mem_device
+                && self.cpu_hotplug == other.cpu_hotplug
         }
     }
 
@@ -678,6 +867,8 @@ mod tests {
                 amount_mib: 123,
                 deflate_on_oom: false,
                 stats_polling_interval_s: 1,
+                free_page_reporting: false,
+                auto_adjust: None,
             };
             insert_balloon_device(&mut vmm, &mut cmdline, &mut event_manager, balloon_cfg);
             // Add a block device.
@@ -698,6 +889,11 @@ mod tests {
                 guest_mac: None,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                num_queue_pairs: 1,
+                backend: NetBackend::Userspace,
+                tx_filter: None,
+                dhcp: None,
+                user_network: None,
             };
             insert_net_device_with_mmds(
                 &mut vmm,
@@ -712,6 +908,10 @@ mod tests {
                 vsock_id: Some(vsock_dev_id.to_string()),
                 guest_cid: 3,
                 uds_path: tmp_sock_file.as_path().to_str().unwrap().to_string(),
+                forwarding: None,
+                port_filter: None,
+                dgram: None,
+                preserve_connections: None,
             };
             insert_vsock_device(&mut vmm, &mut cmdline, &mut event_manager, vsock_config);
 
@@ -778,7 +978,9 @@ mod tests {
   "balloon": {{
     "amount_mib": 123,
     "deflate_on_oom": false,
-    "stats_polling_interval_s": 1
+    "stats_polling_interval_s": 1,
+    "free_page_reporting": false,
+    "auto_adjust": null
   }},
   "drives": [
     {{
@@ -789,7 +991,10 @@ mod tests {
       "is_read_only": true,
       "cache_type": "Unsafe",
       "rate_limiter": null,
-      "io_engine": "Sync"
+      "io_engine": "Sync",
+      "num_queues": 1,
+      "format": "raw",
+      "cow_overlay_path": null
     }}
   ],
   "boot-source": {{
@@ -801,7 +1006,11 @@ mod tests {
     "vcpu_count": 1,
     "mem_size_mib": 128,
     "smt": false,
-    "track_dirty_pages": false
+    "track_dirty_pages": false,
+    "backing": "anonymous",
+    "hotplug_size_mib": 0,
+    "hotplug_requested_mib": 0,
+    "max_vcpu_count": 0
   }},
   "metrics": null,
   "mmds-config": {{
@@ -809,7 +1018,10 @@ mod tests {
     "network_interfaces": [
       "netif"
     ],
-    "ipv4_address": "169.254.169.254"
+    "ipv4_address": "169.254.169.254",
+    "ipv6_address": null,
+    "guest_writable_path": null,
+    "acl": []
   }},
   "network-interfaces": [
     {{
@@ -817,9 +1029,16 @@ mod tests {
       "host_dev_name": "hostname",
       "guest_mac": "00:00:00:00:00:00",
       "rx_rate_limiter": null,
-      "tx_rate_limiter": null
+      "tx_rate_limiter": null,
+      "num_queue_pairs": 1,
+      "backend": "userspace",
+      "tx_filter": null,
+      "dhcp": null,
+      "user_network": null
     }}
   ],
+  "vhost-user-block": [],
+  "vhost-user-net": [],
   "vsock": {{
     "guest_cid": 3,
     "uds_path": "{}"
@@ -853,4 +1072,126 @@ mod tests {
             serde_json::to_string_pretty(&VmmConfig::from(&*vm_resources)).unwrap()
         );
     }
+
+    #[test]
+    fn test_mem_device_persistence() {
+        let mut buf = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let mut vmm = default_vmm();
+        let mut cmdline = default_kernel_cmdline();
+        let region = insert_mem_device(&mut vmm, &mut cmdline, &mut event_manager, 256);
+
+        // Snapshots of a microVM with a virtio-mem device need DeviceStates version 5.
+        version_map
+            .new_version()
+            .set_type_version(DeviceStates::type_id(), 4);
+        assert_eq!(
+            vmm.mmio_device_manager
+                .save()
+                .serialize(&mut buf.as_mut_slice(), &version_map, 2),
+            Err(VersionizeError::Semantic(
+                "Target version does not implement the virtio-mem device.".to_string()
+            ))
+        );
+        version_map
+            .new_version()
+            .set_type_version(DeviceStates::type_id(), 5);
+        vmm.mmio_device_manager
+            .save()
+            .serialize(&mut buf.as_mut_slice(), &version_map, 3)
+            .unwrap();
+
+        let device_states: DeviceStates =
+            DeviceStates::deserialize(&mut buf.as_slice(), &version_map, 3).unwrap();
+        let mem_state = device_states.mem_device.as_ref().unwrap();
+        assert_eq!(mem_state.device_id, MEM_DEV_ID);
+        assert_eq!(mem_state.device_state.region_size(), region.1);
+        assert_eq!(mem_state.device_state.requested_size(), 0);
+
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let restored_vmm = default_vmm();
+        let vm_resources = &mut VmResources::default();
+        let restore_args = MMIODevManagerConstructorArgs {
+            mem: restored_vmm.guest_memory().clone(),
+            vm: restored_vmm.vm.fd(),
+            event_manager: &mut event_manager,
+            for_each_restored_device: VmResources::update_from_restored_device,
+            vm_resources,
+            instance_id: "microvm-id",
+        };
+        let restored_dev_manager =
+            MMIODeviceManager::restore(restore_args, &device_states).unwrap();
+        assert_eq!(restored_dev_manager, vmm.mmio_device_manager.soft_clone());
+        restored_dev_manager
+            .with_virtio_device_with_id(TYPE_MEM, MEM_DEV_ID, |mem: &mut VirtioMem| {
+                assert_eq!(mem.region(), region);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_cpu_hotplug_persistence() {
+        let mut buf = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        let mut vmm = default_vmm();
+        let mut controller = CpuHotplugController::new(4, 2).unwrap();
+        controller.plug(2).unwrap();
+        let slot = vmm
+            .mmio_device_manager
+            .register_mmio_cpu_hotplug(vmm.vm.fd(), Arc::new(Mutex::new(controller)), None)
+            .unwrap();
+
+        // Snapshots of a microVM with hot-pluggable vCPUs need DeviceStates version 6.
+        version_map
+            .new_version()
+            .set_type_version(DeviceStates::type_id(), 5);
+        assert_eq!(
+            vmm.mmio_device_manager
+                .save()
+                .serialize(&mut buf.as_mut_slice(), &version_map, 2),
+            Err(VersionizeError::Semantic(
+                "Target version does not implement vCPU hotplug.".to_string()
+            ))
+        );
+        version_map
+            .new_version()
+            .set_type_version(DeviceStates::type_id(), 6);
+        vmm.mmio_device_manager
+            .save()
+            .serialize(&mut buf.as_mut_slice(), &version_map, 3)
+            .unwrap();
+
+        let device_states: DeviceStates =
+            DeviceStates::deserialize(&mut buf.as_slice(), &version_map, 3).unwrap();
+        assert_eq!(device_states.cpu_hotplug.as_ref().unwrap().mmio_slot, slot);
+
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let restored_vmm = default_vmm();
+        let vm_resources = &mut VmResources::default();
+        let restore_args = MMIODevManagerConstructorArgs {
+            mem: restored_vmm.guest_memory().clone(),
+            vm: restored_vmm.vm.fd(),
+            event_manager: &mut event_manager,
+            for_each_restored_device: VmResources::update_from_restored_device,
+            vm_resources,
+            instance_id: "microvm-id",
+        };
+        let restored_dev_manager =
+            MMIODeviceManager::restore(restore_args, &device_states).unwrap();
+        assert_eq!(restored_dev_manager, vmm.mmio_device_manager.soft_clone());
+        let restored_controller = restored_dev_manager
+            .get_device(DeviceType::CpuHotplug, &DeviceType::CpuHotplug.to_string())
+            .unwrap();
+        let locked_controller = restored_controller.lock().unwrap();
+        let controller = locked_controller
+            .as_any()
+            .downcast_ref::<CpuHotplugController>()
+            .unwrap();
+        assert_eq!(controller.max_cpus(), 4);
+        assert!(controller.is_enabled(2));
+        assert!(!controller.is_enabled(3));
+    }
 }
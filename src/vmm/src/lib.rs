@@ -10,10 +10,14 @@
 //! machine (microVM).
 #![deny(missing_docs)]
 
+/// Automatic adjustment of the balloon size.
+pub mod balloon_controller;
 /// Handles setup and initialization a `Vmm` object.
 pub mod builder;
 pub(crate) mod device_manager;
 pub mod memory_snapshot;
+/// Live migration utilities.
+pub mod migration;
 /// Save/restore utilities.
 pub mod persist;
 /// Resource store for configured microVM resources.
@@ -43,12 +47,16 @@ use std::time::Duration;
 use arch::DeviceType;
 use devices::legacy::serial::{IER_RDA_BIT, IER_RDA_OFFSET};
 use devices::virtio::balloon::Error as BalloonError;
+use devices::virtio::net::TxFilter;
 use devices::virtio::{
-    Balloon, BalloonConfig, BalloonStats, Block, MmioTransport, Net, BALLOON_DEV_ID, TYPE_BALLOON,
-    TYPE_BLOCK, TYPE_NET,
+    Balloon, BalloonConfig, BalloonStats, Block, MmioTransport, Net, VirtioMem, BALLOON_DEV_ID,
+    MEM_DEV_ID, TYPE_BALLOON, TYPE_BLOCK, TYPE_MEM, TYPE_NET,
 };
 use devices::BusDevice;
-use event_manager::{EventManager as BaseEventManager, EventOps, Events, MutEventSubscriber};
+use event_manager::{
+    EventManager as BaseEventManager, EventOps, Events, MutEventSubscriber, SubscriberId,
+    SubscriberOps,
+};
 use logger::{error, info, warn, LoggerError, MetricsError, METRICS};
 use rate_limiter::BucketUpdate;
 use seccompiler::BpfProgram;
@@ -56,13 +64,13 @@ use snapshot::Persist;
 use userfaultfd::Uffd;
 use utils::epoll::EventSet;
 use utils::eventfd::EventFd;
-use vm_memory::{GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
 #[cfg(target_arch = "x86_64")]
 use crate::device_manager::legacy::PortIODeviceManager;
 use crate::device_manager::mmio::MMIODeviceManager;
 use crate::memory_snapshot::SnapshotMemory;
-use crate::persist::{MicrovmState, MicrovmStateError, VmInfo};
+use crate::persist::{MicrovmState, MicrovmStateError, SnapshotLayerState, VmInfo};
 use crate::vmm_config::instance_info::{InstanceInfo, VmState};
 use crate::vstate::vcpu::{Vcpu, VcpuEvent, VcpuHandle, VcpuResponse, VcpuState};
 use crate::vstate::vm::Vm;
@@ -130,6 +138,8 @@ pub enum Error {
     EventFd(io::Error),
     /// I8042 Error.
     I8042Error(devices::legacy::I8042DeviceError),
+    /// The number of plugged vCPUs is out of range.
+    InvalidVcpuCount(u8),
     /// Cannot access kernel file.
     KernelFile(io::Error),
     /// Cannot open /dev/kvm. Either the host does not have KVM or Firecracker does not have
@@ -169,6 +179,9 @@ pub enum Error {
     VcpuResume,
     /// Vcpu send message failed.
     VcpuMessage,
+    #[cfg(target_arch = "aarch64")]
+    /// The vCPU can't be unplugged while it's online in the guest.
+    VcpuOnline(u8),
     /// Cannot spawn a new Vcpu thread.
     VcpuSpawn(io::Error),
     /// Vm error.
@@ -190,6 +203,12 @@ impl Display for Error {
             DirtyBitmap(err) => write!(f, "Error getting the KVM dirty bitmap. {}", err),
             EventFd(err) => write!(f, "Event fd error: {}", err),
             I8042Error(err) => write!(f, "I8042 error: {}", err),
+            InvalidVcpuCount(vcpu_count) => write!(
+                f,
+                "Cannot plug {} vCPUs, at least 1 and at most the maximum vCPU count can be \
+                 plugged.",
+                vcpu_count
+            ),
             KernelFile(err) => write!(f, "Cannot access kernel file: {}", err),
             KvmContext(err) => write!(f, "Failed to validate KVM support: {}", err),
             #[cfg(target_arch = "x86_64")]
@@ -210,6 +229,12 @@ impl Display for Error {
             VcpuExit => write!(f, "Failed to exit the vCPUs."),
             VcpuResume => write!(f, "Failed to resume the vCPUs."),
             VcpuMessage => write!(f, "Failed to message the vCPUs."),
+            #[cfg(target_arch = "aarch64")]
+            VcpuOnline(index) => write!(
+                f,
+                "vCPU {} is online in the guest, it must be offlined before being unplugged.",
+                index
+            ),
             VcpuSpawn(err) => write!(f, "Cannot spawn Vcpu thread: {}", err),
             Vm(err) => write!(f, "Vm error: {}", err),
             VmmObserverInit(err) => write!(
@@ -251,6 +276,30 @@ pub(crate) fn mem_size_mib(guest_memory: &GuestMemoryMmap) -> u64 {
     guest_memory.iter().map(|region| region.len()).sum::<u64>() >> 20
 }
 
+/// Returns the guest memory without the hot-pluggable memory region, if any. The guest is
+/// booted with this memory only, and finds the hot-pluggable memory through the virtio-mem
+/// device.
+pub(crate) fn boot_memory(
+    guest_memory: &GuestMemoryMmap,
+    hotplug_region: Option<(GuestAddress, u64)>,
+) -> GuestMemoryMmap {
+    match hotplug_region {
+        Some((addr, size)) => guest_memory
+            .remove_region(addr, size)
+            .map(|(boot_memory, _)| boot_memory)
+            .unwrap_or_else(|_| guest_memory.clone()),
+        None => guest_memory.clone(),
+    }
+}
+
+/// A change to the event manager subscribers, requested by a hot-plug operation.
+enum SubscriberUpdate {
+    /// Subscribe a hot-plugged device.
+    Add(DeviceType, String, Arc<Mutex<dyn MutEventSubscriber>>),
+    /// Drop the subscription of an unplugged device.
+    Remove(SubscriberId),
+}
+
 /// Contains the state and associated methods required for the Firecracker VMM.
 pub struct Vmm {
     events_observer: Option<Box<dyn VmmEventsObserver>>,
@@ -265,6 +314,9 @@ pub struct Vmm {
     #[allow(dead_code)]
     uffd: Option<Uffd>,
     vcpus_handles: Vec<VcpuHandle>,
+    // Number of vCPUs plugged in the guest. The handles of all the hot-pluggable vCPUs are
+    // created at boot, and the first `plugged_vcpu_count` ones are plugged.
+    plugged_vcpu_count: u8,
     // Used by Vcpus and devices to initiate teardown; Vmm should never write here.
     vcpus_exit_evt: EventFd,
 
@@ -272,6 +324,10 @@ pub struct Vmm {
     mmio_device_manager: MMIODeviceManager,
     #[cfg(target_arch = "x86_64")]
     pio_device_manager: PortIODeviceManager,
+    // Subscribers of the hot-(un)plugged devices, waiting for the event manager to be updated.
+    pending_subscriber_updates: Vec<SubscriberUpdate>,
+    // Last snapshot taken or restored, which the next diff snapshot is layered on.
+    snapshot_layer: Option<SnapshotLayerState>,
 }
 
 impl Vmm {
@@ -334,19 +390,60 @@ impl Vmm {
         Ok(())
     }
 
+    // Returns the handles of the vCPUs which run along with the microVM. The vCPUs which aren't
+    // plugged are kept paused, and are only resumed once plugged by `update_vcpu_count`. On
+    // x86_64, the guest offlines the unplugged vCPUs before ejecting them through the hotplug
+    // controller, so they keep running until ejected.
+    fn running_vcpus_handles(&self) -> Vec<&VcpuHandle> {
+        #[cfg(target_arch = "x86_64")]
+        let plugged = self
+            .with_cpu_hotplug_controller(|controller| {
+                (0..self.vcpus_handles.len())
+                    .map(|index| controller.is_enabled(index as u8))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_else(|| vec![true; self.vcpus_handles.len()]);
+        #[cfg(target_arch = "aarch64")]
+        let plugged = (0..self.vcpus_handles.len())
+            .map(|index| index < self.plugged_vcpu_count as usize)
+            .collect::<Vec<_>>();
+
+        self.vcpus_handles
+            .iter()
+            .zip(plugged)
+            .filter(|(_, plugged)| *plugged)
+            .map(|(handle, _)| handle)
+            .collect()
+    }
+
+    // Runs `f` on the vCPU hotplug controller, if the microVM has hot-pluggable vCPUs.
+    #[cfg(target_arch = "x86_64")]
+    fn with_cpu_hotplug_controller<T>(
+        &self,
+        f: impl FnOnce(&mut devices::pseudo::CpuHotplugController) -> T,
+    ) -> Option<T> {
+        let controller =
+            self.get_bus_device(DeviceType::CpuHotplug, &DeviceType::CpuHotplug.to_string())?;
+        let mut locked_controller = controller.lock().expect("Poisoned lock");
+        Some(f(locked_controller
+            .as_mut_any()
+            .downcast_mut::<devices::pseudo::CpuHotplugController>()
+            .expect("Unexpected BusDevice type")))
+    }
+
     /// Sends a resume command to the vCPUs.
     pub fn resume_vm(&mut self) -> Result<()> {
         self.mmio_device_manager.kick_devices();
 
         // Send the events.
-        self.vcpus_handles
+        let vcpus_handles = self.running_vcpus_handles();
+        vcpus_handles
             .iter()
             .try_for_each(|handle| handle.send_event(VcpuEvent::Resume))
             .map_err(|_| Error::VcpuMessage)?;
 
         // Check the responses.
-        if self
-            .vcpus_handles
+        if vcpus_handles
             .iter()
             .map(|handle| handle.response_receiver().recv_timeout(RECV_TIMEOUT_SEC))
             .any(|response| !matches!(response, Ok(VcpuResponse::Resumed)))
@@ -380,6 +477,133 @@ impl Vmm {
         Ok(())
     }
 
+    /// Plugs or unplugs vCPUs, until `vcpu_count` vCPUs are plugged.
+    ///
+    /// The vCPUs which can be plugged are all created at boot, and kept paused until plugged:
+    /// the VMM thread can't create vCPUs nor spawn their threads once its seccomp filter is
+    /// installed, and on aarch64 the interrupt controller can't take new vCPUs once initialized.
+    ///
+    /// On x86_64, the guest is notified through the vCPU hotplug controller, and onlines or
+    /// offlines the vCPUs itself. The unplugged vCPUs keep running until ejected by the guest,
+    /// and are paused on the next update. On aarch64, plugged vCPUs can be onlined by the guest,
+    /// and vCPUs can only be unplugged once offlined by the guest.
+    pub fn update_vcpu_count(&mut self, vcpu_count: u8) -> Result<()> {
+        if vcpu_count == 0 || vcpu_count as usize > self.vcpus_handles.len() {
+            return Err(Error::InvalidVcpuCount(vcpu_count));
+        }
+        let running = self.instance_info.state == VmState::Running;
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            use devices::pseudo::cpu_hotplug::Error as CpuHotplugError;
+
+            let not_found = || Error::DeviceManager(device_manager::mmio::Error::DeviceNotFound);
+            let internal_error = |err: CpuHotplugError| {
+                Error::DeviceManager(device_manager::mmio::Error::InternalDeviceError(format!(
+                    "{:?}",
+                    err
+                )))
+            };
+            // The vCPUs which are neither plugged nor waiting to be ejected are paused, and the
+            // ones about to be plugged are resumed before the guest looks for them.
+            let enabled = self
+                .with_cpu_hotplug_controller(|controller| {
+                    (0..controller.max_cpus())
+                        .map(|index| controller.is_enabled(index))
+                        .collect::<Vec<_>>()
+                })
+                .ok_or_else(not_found)?;
+            // The controller isn't locked meanwhile, since the vCPUs may be waiting for it.
+            if running {
+                for (index, enabled) in enabled.into_iter().enumerate() {
+                    let index = index as u8;
+                    if index < vcpu_count {
+                        self.send_vcpu_event(index, VcpuEvent::Resume, Error::VcpuResume)?;
+                    } else if !enabled {
+                        self.send_vcpu_event(index, VcpuEvent::Pause, Error::VcpuPause)?;
+                    }
+                }
+            }
+
+            self.with_cpu_hotplug_controller(|controller| {
+                for index in 0..controller.max_cpus() {
+                    if index < vcpu_count {
+                        controller.plug(index)?;
+                    } else {
+                        controller.unplug(index)?;
+                    }
+                }
+                controller.notify()
+            })
+            .ok_or_else(not_found)?
+            .map_err(internal_error)?;
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            if vcpu_count < self.plugged_vcpu_count {
+                self.unplug_vcpus(vcpu_count, running)?;
+            }
+            if running {
+                for index in self.plugged_vcpu_count..vcpu_count {
+                    self.send_vcpu_event(index, VcpuEvent::Resume, Error::VcpuResume)?;
+                }
+            }
+        }
+
+        self.plugged_vcpu_count = vcpu_count;
+        Ok(())
+    }
+
+    // Pauses the plugged vCPUs starting with `first`, which the guest must have powered off
+    // already. Either all of them are unplugged, or none.
+    #[cfg(target_arch = "aarch64")]
+    fn unplug_vcpus(&mut self, first: u8, running: bool) -> Result<()> {
+        let indexes = first..self.plugged_vcpu_count;
+        if running {
+            for index in indexes.clone() {
+                self.send_vcpu_event(index, VcpuEvent::Pause, Error::VcpuPause)?;
+            }
+        }
+
+        let mut online_vcpu = None;
+        for index in indexes.clone() {
+            let handle = &self.vcpus_handles[index as usize];
+            handle
+                .send_event(VcpuEvent::SaveState)
+                .map_err(Error::VcpuEvent)?;
+            match handle.response_receiver().recv_timeout(RECV_TIMEOUT_SEC) {
+                Ok(VcpuResponse::SavedState(state)) => {
+                    if state.mp_state.mp_state != kvm_bindings::KVM_MP_STATE_STOPPED {
+                        online_vcpu = online_vcpu.or(Some(index));
+                    }
+                }
+                _ => return Err(Error::VcpuMessage),
+            }
+        }
+
+        if let Some(index) = online_vcpu {
+            if running {
+                for index in indexes {
+                    self.send_vcpu_event(index, VcpuEvent::Resume, Error::VcpuResume)?;
+                }
+            }
+            return Err(Error::VcpuOnline(index));
+        }
+        Ok(())
+    }
+
+    fn send_vcpu_event(&self, index: u8, event: VcpuEvent, error: Error) -> Result<()> {
+        let handle = &self.vcpus_handles[index as usize];
+        let expect_paused = matches!(event, VcpuEvent::Pause);
+        handle.send_event(event).map_err(Error::VcpuEvent)?;
+        match handle.response_receiver().recv_timeout(RECV_TIMEOUT_SEC) {
+            Ok(VcpuResponse::Paused) if expect_paused => Ok(()),
+            Ok(VcpuResponse::Resumed) if !expect_paused => Ok(()),
+            _ => Err(error),
+        }
+    }
+
     /// Returns a reference to the inner `GuestMemoryMmap` object.
     pub fn guest_memory(&self) -> &GuestMemoryMmap {
         &self.guest_memory
@@ -434,9 +658,21 @@ impl Vmm {
             .map_err(Error::I8042Error)
     }
 
+    /// Checks that the state of the microVM can be saved. The state of the devices served by
+    /// vhost-user backends lives in the backend processes, so it can't be part of a snapshot.
+    pub fn check_state_savable(&self) -> std::result::Result<(), MicrovmStateError> {
+        if self.mmio_device_manager.has_vhost_user_devices() {
+            return Err(MicrovmStateError::NotAllowed(String::from(
+                "the microVM has devices served by vhost-user backends",
+            )));
+        }
+        Ok(())
+    }
+
     /// Saves the state of a paused Microvm.
     pub fn save_state(&mut self) -> std::result::Result<MicrovmState, MicrovmStateError> {
         use self::MicrovmStateError::SaveVmState;
+        self.check_state_savable()?;
         let vcpu_states = self.save_vcpu_states()?;
         let vm_state = {
             #[cfg(target_arch = "x86_64")]
@@ -461,6 +697,8 @@ impl Vmm {
             vm_state,
             vcpu_states,
             device_states,
+            layer_state: SnapshotLayerState::default(),
+            plugged_vcpu_count: self.plugged_vcpu_count,
         })
     }
 
@@ -573,6 +811,106 @@ impl Vmm {
             .map_err(Error::DeviceManager)
     }
 
+    /// Redirects the writes to the block device with id `drive_id` to a new copy-on-write
+    /// overlay, found at `cow_overlay_path`.
+    pub fn update_block_cow_overlay(
+        &mut self,
+        drive_id: &str,
+        cow_overlay_path: String,
+    ) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_BLOCK, drive_id, |block: &mut Block| {
+                block
+                    .update_cow_overlay(cow_overlay_path)
+                    .map_err(|err| format!("{:?}", err))
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Plugs `block` in a free hot-plug slot of the running microVM.
+    ///
+    /// The device gets subscribed to the event manager on the next call of
+    /// `update_event_subscribers()`.
+    pub fn hotplug_block_device(&mut self, block: Arc<Mutex<Block>>) -> Result<()> {
+        let drive_id = block.lock().expect("Poisoned lock").id().clone();
+        let device = MmioTransport::new(self.guest_memory.clone(), block.clone());
+        let slot = self
+            .mmio_device_manager
+            .hotplug_mmio_virtio(self.vm.fd(), drive_id.clone(), device)
+            .map_err(Error::DeviceManager)?;
+        info!(
+            "Hot-plugged block device {} at 0x{:x}, irq {}.",
+            drive_id, slot.addr, slot.irqs[0]
+        );
+
+        self.pending_subscriber_updates.push(SubscriberUpdate::Add(
+            DeviceType::Virtio(TYPE_BLOCK),
+            drive_id,
+            block,
+        ));
+        Ok(())
+    }
+
+    /// Unplugs the hot-plugged block device with id `drive_id` from the running microVM.
+    /// The in-flight requests are completed before the device goes away.
+    ///
+    /// The device subscription is dropped on the next call of `update_event_subscribers()`.
+    pub fn hotunplug_block_device(&mut self, drive_id: &str) -> Result<()> {
+        let device_type = DeviceType::Virtio(TYPE_BLOCK);
+        let (device, subscriber_id) = self
+            .mmio_device_manager
+            .hotunplug_mmio_virtio(self.vm.fd(), TYPE_BLOCK, drive_id)
+            .map_err(Error::DeviceManager)?;
+        // The guest can't submit new requests anymore, complete the in-flight ones.
+        if let Some(block) = device
+            .lock()
+            .expect("Poisoned lock")
+            .as_mut_any()
+            .downcast_mut::<Block>()
+        {
+            block.prepare_save();
+        }
+        info!("Hot-unplugged block device {}.", drive_id);
+
+        match subscriber_id {
+            Some(subscriber_id) => self
+                .pending_subscriber_updates
+                .push(SubscriberUpdate::Remove(subscriber_id)),
+            // The device was never subscribed, so just drop the pending subscription.
+            None => self
+                .pending_subscriber_updates
+                .retain(|update| match update {
+                    SubscriberUpdate::Add(update_type, update_id, _) => {
+                        *update_type != device_type || update_id != drive_id
+                    }
+                    SubscriberUpdate::Remove(_) => true,
+                }),
+        }
+        Ok(())
+    }
+
+    /// Applies the event manager subscriber changes requested by the hot-plug operations.
+    pub fn update_event_subscribers(&mut self, event_manager: &mut EventManager) {
+        for update in self.pending_subscriber_updates.drain(..) {
+            match update {
+                SubscriberUpdate::Add(device_type, device_id, subscriber) => {
+                    let subscriber_id = event_manager.add_subscriber(subscriber);
+                    // The device could only have been unplugged along with its pending update.
+                    self.mmio_device_manager
+                        .set_hotplug_subscriber(device_type, &device_id, subscriber_id)
+                        .unwrap_or_else(|err| {
+                            error!("Failed to record the subscriber of {}: {}", device_id, err)
+                        });
+                }
+                SubscriberUpdate::Remove(subscriber_id) => {
+                    if let Err(err) = event_manager.remove_subscriber(subscriber_id) {
+                        error!("Failed to remove an unplugged device subscriber: {:?}", err);
+                    }
+                }
+            }
+        }
+    }
+
     /// Updates the rate limiter parameters for block device with `drive_id` id.
     pub fn update_block_rate_limiter(
         &mut self,
@@ -599,9 +937,48 @@ impl Vmm {
     ) -> Result<()> {
         self.mmio_device_manager
             .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
-                net.patch_rate_limiters(rx_bytes, rx_ops, tx_bytes, tx_ops);
+                net.patch_rate_limiters(rx_bytes, rx_ops, tx_bytes, tx_ops)
+                    .map_err(|err| format!("{:?}", err))
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Replaces the TX filter of the net device with `net_id` id.
+    pub fn update_net_tx_filter(&mut self, net_id: &str, tx_filter: TxFilter) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                net.set_tx_filter(tx_filter)
+                    .map_err(|err| format!("{:?}", err))
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Returns the start address and the size of the hot-pluggable memory region, if the
+    /// microVM has a virtio-mem device.
+    pub fn hotplug_memory_region(&self) -> Option<(GuestAddress, u64)> {
+        let mut region = None;
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_MEM, MEM_DEV_ID, |mem: &mut VirtioMem| {
+                region = Some(mem.region());
                 Ok(())
             })
+            .ok()?;
+        region
+    }
+
+    /// Returns the guest memory the microVM was booted with, without the hot-pluggable memory.
+    pub fn boot_memory(&self) -> GuestMemoryMmap {
+        boot_memory(&self.guest_memory, self.hotplug_memory_region())
+    }
+
+    /// Requests the guest to plug or unplug hot-pluggable memory, until `requested_mib` MiB
+    /// are plugged.
+    pub fn update_hotplug_memory(&mut self, requested_mib: usize) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_MEM, MEM_DEV_ID, |mem: &mut VirtioMem| {
+                mem.update_requested_size((requested_mib as u64) << 20)
+                    .map_err(|err| format!("{:?}", err))
+            })
             .map_err(Error::DeviceManager)
     }
 
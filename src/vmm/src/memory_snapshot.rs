@@ -3,22 +3,40 @@
 
 //! Defines functionality for creating guest memory snapshots.
 
+use std::cmp;
 use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::io::SeekFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
-use utils::{errno, get_page_size};
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use utils::byte_order::{read_le_u32, read_le_u64, write_le_u32, write_le_u64};
+use utils::seek_hole::SeekHole;
+use utils::{errno, get_page_size, lz4};
+use versionize::crc::CRC64Writer;
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{
     Bitmap, Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
     GuestMemoryRegion, MemoryRegionAddress,
 };
 
+use crate::vmm_config::machine_config::MemoryBacking;
+use crate::vmm_config::snapshot::MemFileFormat;
 use crate::DirtyBitmap;
 
+/// Identifies the compressed memory files ("FCMEMLZ4").
+const COMPRESSED_MAGIC: u64 = 0x345a_4c4d_454d_4346;
+const COMPRESSED_VERSION: u32 = 1;
+/// Magic, version, page size, number of chunks and memory size.
+const COMPRESSED_HEADER_LEN: usize = 32;
+/// Offset and length of the chunk data, flags, zero pages bitmap and checksum.
+const COMPRESSED_INDEX_ENTRY_LEN: usize = 32;
+/// Number of pages in a chunk, one for each bit of the zero pages bitmap.
+const CHUNK_PAGES: usize = 64;
+/// The data of the chunk is LZ4 compressed, otherwise it's stored as is.
+const CHUNK_FLAG_LZ4: u32 = 1;
+
 /// State of a guest memory region saved to file/buffer.
-#[derive(Debug, PartialEq, Versionize)]
+#[derive(Clone, Debug, PartialEq, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct GuestMemoryRegionState {
     // This should have been named `base_guest_addr` since it's _guest_ addr, but for
@@ -31,12 +49,101 @@ pub struct GuestMemoryRegionState {
     pub offset: u64,
 }
 
+/// Format of the memory file of a snapshot.
+#[derive(Clone, Copy, Debug, PartialEq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum MemFileFormatState {
+    Raw,
+    Compressed,
+}
+
+impl Default for MemFileFormatState {
+    fn default() -> Self {
+        MemFileFormatState::Raw
+    }
+}
+
+impl From<MemFileFormat> for MemFileFormatState {
+    fn from(mem_file_format: MemFileFormat) -> Self {
+        match mem_file_format {
+            MemFileFormat::Raw => MemFileFormatState::Raw,
+            MemFileFormat::Compressed => MemFileFormatState::Compressed,
+        }
+    }
+}
+
+/// Kind of memory backing the guest memory of a snapshot.
+#[derive(Clone, Copy, Debug, PartialEq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum MemoryBackingState {
+    Anonymous,
+    Memfd,
+    Hugetlbfs2M,
+    Hugetlbfs1G,
+}
+
+impl Default for MemoryBackingState {
+    fn default() -> Self {
+        MemoryBackingState::Anonymous
+    }
+}
+
+impl From<MemoryBacking> for MemoryBackingState {
+    fn from(backing: MemoryBacking) -> Self {
+        match backing {
+            MemoryBacking::Anonymous => MemoryBackingState::Anonymous,
+            MemoryBacking::Memfd => MemoryBackingState::Memfd,
+            MemoryBacking::Hugetlbfs2M => MemoryBackingState::Hugetlbfs2M,
+            MemoryBacking::Hugetlbfs1G => MemoryBackingState::Hugetlbfs1G,
+        }
+    }
+}
+
+impl From<MemoryBackingState> for MemoryBacking {
+    fn from(state: MemoryBackingState) -> Self {
+        match state {
+            MemoryBackingState::Anonymous => MemoryBacking::Anonymous,
+            MemoryBackingState::Memfd => MemoryBacking::Memfd,
+            MemoryBackingState::Hugetlbfs2M => MemoryBacking::Hugetlbfs2M,
+            MemoryBackingState::Hugetlbfs1G => MemoryBacking::Hugetlbfs1G,
+        }
+    }
+}
+
 /// Describes guest memory regions and their snapshot file mappings.
-#[derive(Debug, Default, PartialEq, Versionize)]
+#[derive(Clone, Debug, Default, PartialEq, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct GuestMemoryState {
     /// List of regions.
     pub regions: Vec<GuestMemoryRegionState>,
+    /// Format of the memory file. The region offsets refer to the uncompressed memory.
+    #[version(start = 2, ser_fn = "file_format_serialize")]
+    pub file_format: MemFileFormatState,
+    /// Kind of memory backing the guest memory, which is restored in the same kind of memory.
+    #[version(start = 2, ser_fn = "backing_serialize")]
+    pub backing: MemoryBackingState,
+}
+
+impl GuestMemoryState {
+    fn file_format_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && self.file_format != MemFileFormatState::Raw {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support compressed memory files.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn backing_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 2 && self.backing != MemoryBackingState::Anonymous {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support shared guest memory.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 /// Defines the interface for snapshotting memory.
@@ -55,12 +162,27 @@ where
         dirty_bitmap: &DirtyBitmap,
     ) -> std::result::Result<(), Error>;
     /// Creates a GuestMemoryMmap given a `file` containing the data
-    /// and a `state` containing mapping information.
+    /// and a `state` containing mapping information and the kind of memory backing it.
     fn restore(
         file: Option<&File>,
         state: &GuestMemoryState,
         track_dirty_pages: bool,
     ) -> std::result::Result<Self, Error>;
+    /// Copies the pages saved in the diff memory `file` of a snapshot over the guest memory.
+    /// The holes of the file are skipped. Layout is described in the `state` param.
+    fn overlay(&self, file: &mut File, state: &GuestMemoryState) -> std::result::Result<(), Error>;
+    /// Dumps all contents of GuestMemoryMmap to a writer, in the compressed format.
+    fn dump_compressed<T: std::io::Write + std::io::Seek>(
+        &self,
+        writer: &mut T,
+    ) -> std::result::Result<(), Error>;
+    /// Creates a GuestMemoryMmap backed by the kind of memory of the snapshot, filled with the
+    /// contents of a compressed memory `file`. Layout is described in the `state` param.
+    fn restore_compressed(
+        file: &mut File,
+        state: &GuestMemoryState,
+        track_dirty_pages: bool,
+    ) -> std::result::Result<Self, Error>;
 }
 
 /// Errors associated with dumping guest memory to file.
@@ -72,8 +194,19 @@ pub enum Error {
     CreateMemory(vm_memory::Error),
     /// Cannot create region.
     CreateRegion(vm_memory::MmapRegionError),
+    /// Checksum mismatch for the given chunk of a compressed memory file.
+    #[from(ignore)]
+    ChunkChecksum(u64),
+    /// Cannot decompress a chunk of a compressed memory file.
+    Decompress(lz4::Error),
+    /// The compressed memory file is malformed.
+    #[from(ignore)]
+    InvalidCompressedFile(&'static str),
     /// Cannot fetch system's page size.
     PageSize(errno::Error),
+    /// Cannot load memory.
+    #[from(ignore)]
+    ReadMemory(GuestMemoryError),
     /// Cannot dump memory.
     WriteMemory(GuestMemoryError),
 }
@@ -85,7 +218,15 @@ impl Display for Error {
             FileHandle(err) => write!(f, "Cannot access file: {:?}", err),
             CreateMemory(err) => write!(f, "Cannot create memory: {:?}", err),
             CreateRegion(err) => write!(f, "Cannot create memory region: {:?}", err),
+            ChunkChecksum(chunk) => write!(
+                f,
+                "Checksum mismatch for chunk {} of the compressed memory file",
+                chunk
+            ),
+            Decompress(err) => write!(f, "Cannot decompress memory: {:?}", err),
+            InvalidCompressedFile(err) => write!(f, "Invalid compressed memory file: {}", err),
             PageSize(err) => write!(f, "Cannot fetch system's page size: {:?}", err),
+            ReadMemory(err) => write!(f, "Cannot load memory: {:?}", err),
             WriteMemory(err) => write!(f, "Cannot dump memory: {:?}", err),
         }
     }
@@ -105,6 +246,20 @@ impl SnapshotMemory for GuestMemoryMmap {
 
             offset += region.len();
         });
+        // All the regions are backed by the same kind of memory.
+        if let Some(region) = self.iter().next() {
+            guest_memory_state.backing = if region.flags() & libc::MAP_SHARED == 0 {
+                MemoryBackingState::Anonymous
+            } else {
+                match vm_memory::huge_page_size(region) {
+                    None => MemoryBackingState::Memfd,
+                    Some(size) if Some(size) == MemoryBacking::Hugetlbfs2M.huge_page_size() => {
+                        MemoryBackingState::Hugetlbfs2M
+                    }
+                    Some(_) => MemoryBackingState::Hugetlbfs1G,
+                }
+            };
+        }
         guest_memory_state
     }
 
@@ -180,11 +335,43 @@ impl SnapshotMemory for GuestMemoryMmap {
 
     /// Creates a GuestMemoryMmap backed by a `file` if present, otherwise backed
     /// by anonymous memory. Memory layout and ranges are described in `state` param.
+    ///
+    /// Shared memory can't be backed by the `file`, so it is created empty and filled with the
+    /// contents of the `file`, if present.
     fn restore(
         file: Option<&File>,
         state: &GuestMemoryState,
         track_dirty_pages: bool,
     ) -> std::result::Result<Self, Error> {
+        let backing = MemoryBacking::from(state.backing);
+        if backing.is_shared() {
+            let regions: Vec<_> = state
+                .regions
+                .iter()
+                .map(|region| (GuestAddress(region.base_address), region.size))
+                .collect();
+            let guest_memory = vm_memory::create_shared_guest_memory(
+                &regions,
+                backing.huge_page_size(),
+                track_dirty_pages,
+            )
+            .map_err(Error::CreateMemory)?;
+            if let Some(file) = file {
+                let mut file = file.try_clone()?;
+                for (region, region_state) in guest_memory.iter().zip(state.regions.iter()) {
+                    file.seek(SeekFrom::Start(region_state.offset))?;
+                    region
+                        .read_exact_from(MemoryRegionAddress(0), &mut file, region_state.size)
+                        .map_err(Error::ReadMemory)?;
+                    // The memory now matches the snapshot, none of its pages are dirty.
+                    if let Some(bitmap) = region.bitmap() {
+                        bitmap.reset();
+                    }
+                }
+            }
+            return Ok(guest_memory);
+        }
+
         let mut regions = vec![];
         for region in state.regions.iter() {
             let f = match file {
@@ -197,12 +384,233 @@ impl SnapshotMemory for GuestMemoryMmap {
 
         vm_memory::create_guest_memory(&regions, track_dirty_pages).map_err(Error::CreateMemory)
     }
+
+    /// Copies the pages saved in the diff memory `file` of a snapshot over the guest memory.
+    /// The holes of the file are skipped. Layout is described in the `state` param.
+    fn overlay(&self, file: &mut File, state: &GuestMemoryState) -> std::result::Result<(), Error> {
+        for (region, region_state) in self.iter().zip(state.regions.iter()) {
+            let region_end = region_state.offset + region_state.size as u64;
+            let mut cursor = region_state.offset;
+            while let Some(data_start) = file.seek_data(cursor)? {
+                if data_start >= region_end {
+                    break;
+                }
+                let data_end = file
+                    .seek_hole(data_start)?
+                    .map_or(region_end, |hole_start| hole_start.min(region_end));
+                file.seek(SeekFrom::Start(data_start))?;
+                region
+                    .read_exact_from(
+                        MemoryRegionAddress(data_start - region_state.offset),
+                        file,
+                        (data_end - data_start) as usize,
+                    )
+                    .map_err(Error::ReadMemory)?;
+                cursor = data_end;
+            }
+
+            // The memory now matches the snapshot, none of its pages are dirty.
+            if let Some(bitmap) = region.bitmap() {
+                bitmap.reset();
+            }
+        }
+        Ok(())
+    }
+
+    /// Dumps all contents of GuestMemoryMmap to a writer, in the compressed format.
+    ///
+    /// The file starts with a header and an index with one entry per chunk of `CHUNK_PAGES`
+    /// pages, followed by the chunk data. The zero pages of a chunk are only recorded in its
+    /// index entry, the other ones are compressed together unless that doesn't save any space.
+    fn dump_compressed<T: std::io::Write + std::io::Seek>(
+        &self,
+        writer: &mut T,
+    ) -> std::result::Result<(), Error> {
+        let page_size = get_page_size()?;
+        let chunk_size = page_size * CHUNK_PAGES;
+        let chunk_count: usize = self
+            .iter()
+            .map(|region| (region.len() as usize + chunk_size - 1) / chunk_size)
+            .sum();
+        let mut index = vec![0u8; chunk_count * COMPRESSED_INDEX_ENTRY_LEN];
+        let mut data_offset = (COMPRESSED_HEADER_LEN + index.len()) as u64;
+        writer.seek(SeekFrom::Start(data_offset))?;
+
+        let mut chunk_buf = vec![0u8; chunk_size];
+        let mut index_entries = index.chunks_exact_mut(COMPRESSED_INDEX_ENTRY_LEN);
+        for region in self.iter() {
+            for chunk_start in (0..region.len() as usize).step_by(chunk_size) {
+                let chunk_len = cmp::min(chunk_size, region.len() as usize - chunk_start);
+                let chunk = &mut chunk_buf[..chunk_len];
+                region
+                    .read_slice(chunk, MemoryRegionAddress(chunk_start as u64))
+                    .map_err(Error::WriteMemory)?;
+
+                let mut zero_pages = 0u64;
+                let mut pages = Vec::with_capacity(chunk_len);
+                for (i, page) in chunk.chunks(page_size).enumerate() {
+                    if page.iter().all(|&byte| byte == 0) {
+                        zero_pages |= 1 << i;
+                    } else {
+                        pages.extend_from_slice(page);
+                    }
+                }
+                let compressed = lz4::compress(&pages);
+                let (data, flags) = if compressed.len() < pages.len() {
+                    (compressed.as_slice(), CHUNK_FLAG_LZ4)
+                } else {
+                    (pages.as_slice(), 0)
+                };
+                writer.write_all(data)?;
+
+                // There is an index entry for each chunk.
+                let entry = index_entries.next().unwrap();
+                write_le_u64(&mut entry[0..8], data_offset);
+                write_le_u32(&mut entry[8..12], data.len() as u32);
+                write_le_u32(&mut entry[12..16], flags);
+                write_le_u64(&mut entry[16..24], zero_pages);
+                write_le_u64(&mut entry[24..32], checksum(&pages));
+                data_offset += data.len() as u64;
+            }
+        }
+
+        let mem_len: u64 = self.iter().map(|region| region.len()).sum();
+        let mut header = [0u8; COMPRESSED_HEADER_LEN];
+        write_le_u64(&mut header[0..8], COMPRESSED_MAGIC);
+        write_le_u32(&mut header[8..12], COMPRESSED_VERSION);
+        write_le_u32(&mut header[12..16], page_size as u32);
+        write_le_u64(&mut header[16..24], chunk_count as u64);
+        write_le_u64(&mut header[24..32], mem_len);
+        writer.seek(SeekFrom::Start(0))?;
+        writer.write_all(&header)?;
+        writer.write_all(&index)?;
+        Ok(())
+    }
+
+    /// Creates a GuestMemoryMmap backed by the kind of memory of the snapshot, filled with the
+    /// contents of a compressed memory `file`. Layout is described in the `state` param.
+    fn restore_compressed(
+        file: &mut File,
+        state: &GuestMemoryState,
+        track_dirty_pages: bool,
+    ) -> std::result::Result<Self, Error> {
+        use self::Error::InvalidCompressedFile;
+
+        let page_size = get_page_size()?;
+        let chunk_size = page_size * CHUNK_PAGES;
+        let chunk_count: usize = state
+            .regions
+            .iter()
+            .map(|region| (region.size + chunk_size - 1) / chunk_size)
+            .sum();
+        let mem_len: u64 = state.regions.iter().map(|region| region.size as u64).sum();
+
+        let mut header = [0u8; COMPRESSED_HEADER_LEN];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        if read_le_u64(&header[0..8]) != COMPRESSED_MAGIC {
+            return Err(InvalidCompressedFile("bad magic"));
+        }
+        if read_le_u32(&header[8..12]) != COMPRESSED_VERSION {
+            return Err(InvalidCompressedFile("unsupported version"));
+        }
+        if read_le_u32(&header[12..16]) as usize != page_size {
+            return Err(InvalidCompressedFile("page size differs from the host one"));
+        }
+        if read_le_u64(&header[16..24]) != chunk_count as u64
+            || read_le_u64(&header[24..32]) != mem_len
+        {
+            return Err(InvalidCompressedFile(
+                "memory size differs from the snapshot one",
+            ));
+        }
+        let mut index = vec![0u8; chunk_count * COMPRESSED_INDEX_ENTRY_LEN];
+        file.read_exact(&mut index)?;
+
+        let guest_memory = Self::restore(None, state, track_dirty_pages)?;
+        let mut index_entries = index.chunks_exact(COMPRESSED_INDEX_ENTRY_LEN).enumerate();
+        let mut data = Vec::with_capacity(chunk_size);
+        for region in guest_memory.iter() {
+            for chunk_start in (0..region.len() as usize).step_by(chunk_size) {
+                let chunk_pages =
+                    cmp::min(chunk_size, region.len() as usize - chunk_start) / page_size;
+                // The number of entries was checked against the memory size.
+                let (chunk_index, entry) = index_entries.next().unwrap();
+                let data_offset = read_le_u64(&entry[0..8]);
+                let data_len = read_le_u32(&entry[8..12]) as usize;
+                let flags = read_le_u32(&entry[12..16]);
+                let zero_pages = read_le_u64(&entry[16..24]);
+                let extra_pages = zero_pages.checked_shr(chunk_pages as u32).unwrap_or(0);
+                if data_len > chunk_size || extra_pages != 0 {
+                    return Err(InvalidCompressedFile("bad index entry"));
+                }
+
+                data.resize(data_len, 0);
+                file.seek(SeekFrom::Start(data_offset))?;
+                file.read_exact(&mut data)?;
+                let pages_len = (chunk_pages - zero_pages.count_ones() as usize) * page_size;
+                let decompressed;
+                let pages = if flags & CHUNK_FLAG_LZ4 != 0 {
+                    decompressed = lz4::decompress(&data, pages_len)?;
+                    &decompressed
+                } else {
+                    &data
+                };
+                if pages.len() != pages_len {
+                    return Err(InvalidCompressedFile("bad chunk length"));
+                }
+                if checksum(pages) != read_le_u64(&entry[24..32]) {
+                    return Err(Error::ChunkChecksum(chunk_index as u64));
+                }
+
+                // The new memory is already zeroed.
+                let non_zero_pages = (0..chunk_pages).filter(|&i| zero_pages & (1 << i) == 0);
+                for (page, i) in pages.chunks(page_size).zip(non_zero_pages) {
+                    region
+                        .write_slice(
+                            page,
+                            MemoryRegionAddress((chunk_start + i * page_size) as u64),
+                        )
+                        .map_err(Error::ReadMemory)?;
+                }
+            }
+
+            // The memory now matches the snapshot, none of its pages are dirty.
+            if let Some(bitmap) = region.bitmap() {
+                bitmap.reset();
+            }
+        }
+
+        Ok(guest_memory)
+    }
+}
+
+/// Tells the format of a memory file from its first bytes, for the memory files which are not
+/// described by the state of the snapshot being loaded.
+pub fn probe_file_format(file: &mut File) -> std::result::Result<MemFileFormatState, Error> {
+    let mut magic = [0u8; 8];
+    file.seek(SeekFrom::Start(0))?;
+    match file.read_exact(&mut magic) {
+        Ok(()) if read_le_u64(&magic) == COMPRESSED_MAGIC => Ok(MemFileFormatState::Compressed),
+        Ok(()) => Ok(MemFileFormatState::Raw),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(MemFileFormatState::Raw),
+        Err(err) => Err(Error::FileHandle(err)),
+    }
+}
+
+/// Computes the checksum of the pages of a chunk.
+fn checksum(pages: &[u8]) -> u64 {
+    let mut sink = io::sink();
+    let mut crc_writer = CRC64Writer::new(&mut sink);
+    // Writing to a sink can't fail.
+    crc_writer.write_all(pages).unwrap();
+    crc_writer.checksum()
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
-    use std::io::{Read, Seek};
+    use std::io::{Read, Write};
 
     use utils::get_page_size;
     use utils::tempfile::TempFile;
@@ -234,6 +642,8 @@ mod tests {
                     offset: page_size as u64,
                 },
             ],
+            file_format: MemFileFormatState::Raw,
+            backing: MemoryBackingState::Anonymous,
         };
 
         let actual_memory_state = guest_memory.describe();
@@ -259,12 +669,90 @@ mod tests {
                     offset: page_size as u64 * 3,
                 },
             ],
+            file_format: MemFileFormatState::Raw,
+            backing: MemoryBackingState::Anonymous,
         };
 
         let actual_memory_state = guest_memory.describe();
         assert_eq!(expected_memory_state, actual_memory_state);
     }
 
+    #[test]
+    fn test_restore_shared_memory() {
+        let page_size: usize = get_page_size().unwrap();
+
+        // Two regions of two pages each, with a one page gap between them.
+        let mem_regions = [
+            (GuestAddress(0), page_size * 2),
+            (GuestAddress(page_size as u64 * 3), page_size * 2),
+        ];
+        let guest_memory =
+            vm_memory::create_shared_guest_memory(&mem_regions[..], None, true).unwrap();
+        let first_region = vec![1u8; page_size * 2];
+        guest_memory
+            .write(&first_region[..], GuestAddress(0))
+            .unwrap();
+        let second_region = vec![2u8; page_size * 2];
+        guest_memory
+            .write(&second_region[..], GuestAddress(page_size as u64 * 3))
+            .unwrap();
+
+        let memory_state = guest_memory.describe();
+        assert_eq!(memory_state.backing, MemoryBackingState::Memfd);
+
+        let memory_file = TempFile::new().unwrap();
+        guest_memory.dump(&mut memory_file.as_file()).unwrap();
+
+        // The memory is restored in memfds, which are not backed by the memory file.
+        let restored_guest_memory =
+            GuestMemoryMmap::restore(Some(memory_file.as_file()), &memory_state, true).unwrap();
+        assert_eq!(
+            restored_guest_memory.describe().backing,
+            MemoryBackingState::Memfd
+        );
+        memory_file.as_file().set_len(0).unwrap();
+
+        let mut actual_region = vec![0u8; page_size * 2];
+        restored_guest_memory
+            .read(&mut actual_region.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(first_region, actual_region);
+        restored_guest_memory
+            .read(
+                &mut actual_region.as_mut_slice(),
+                GuestAddress(page_size as u64 * 3),
+            )
+            .unwrap();
+        assert_eq!(second_region, actual_region);
+        restored_guest_memory.iter().for_each(|region| {
+            assert_eq!(region.flags() & libc::MAP_SHARED, libc::MAP_SHARED);
+            assert!(!region.bitmap().dirty_at(0));
+        });
+
+        // Without a memory file, the memory is empty.
+        let restored_guest_memory = GuestMemoryMmap::restore(None, &memory_state, false).unwrap();
+        restored_guest_memory
+            .read(&mut actual_region.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(vec![0u8; page_size * 2], actual_region);
+
+        // Older snapshot versions can only describe anonymous memory.
+        let mut buf = vec![0u8; 1024];
+        let mut version_map = VersionMap::new();
+        assert!(memory_state
+            .serialize(&mut buf.as_mut_slice(), &version_map, 1)
+            .is_err());
+        version_map
+            .new_version()
+            .set_type_version(GuestMemoryState::type_id(), 2);
+        memory_state
+            .serialize(&mut buf.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_state =
+            GuestMemoryState::deserialize(&mut buf.as_slice(), &version_map, 2).unwrap();
+        assert_eq!(restored_state, memory_state);
+    }
+
     #[test]
     fn test_restore_memory() {
         let page_size: usize = get_page_size().unwrap();
@@ -383,4 +871,158 @@ mod tests {
             assert_eq!(expected_first_region, diff_file_content);
         }
     }
+
+    #[test]
+    fn test_overlay_memory() {
+        let page_size: usize = get_page_size().unwrap();
+
+        // Two regions of two pages each, with a one page gap between them.
+        let mem_regions = [
+            (None, GuestAddress(0), page_size * 2),
+            (None, GuestAddress(page_size as u64 * 3), page_size * 2),
+        ];
+        let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true).unwrap();
+        let memory_state = guest_memory.describe();
+
+        let ones = vec![1u8; page_size];
+        let twos = vec![2u8; page_size];
+        guest_memory
+            .write(
+                &[ones.as_slice(), ones.as_slice()].concat(),
+                GuestAddress(0),
+            )
+            .unwrap();
+        guest_memory
+            .write(
+                &[ones.as_slice(), ones.as_slice()].concat(),
+                GuestAddress(page_size as u64 * 3),
+            )
+            .unwrap();
+
+        // The diff file only holds the second page of each region.
+        let diff_file = TempFile::new().unwrap();
+        let mut diff_file = diff_file.into_file();
+        diff_file.set_len(page_size as u64 * 4).unwrap();
+        diff_file.seek(SeekFrom::Start(page_size as u64)).unwrap();
+        diff_file.write_all(&twos).unwrap();
+        diff_file
+            .seek(SeekFrom::Start(page_size as u64 * 3))
+            .unwrap();
+        diff_file.write_all(&twos).unwrap();
+
+        guest_memory.overlay(&mut diff_file, &memory_state).unwrap();
+
+        let mut actual_region = vec![0u8; page_size * 2];
+        guest_memory
+            .read(&mut actual_region.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!([ones.as_slice(), twos.as_slice()].concat(), actual_region);
+        guest_memory
+            .read(
+                &mut actual_region.as_mut_slice(),
+                GuestAddress(page_size as u64 * 3),
+            )
+            .unwrap();
+        assert_eq!([ones.as_slice(), twos.as_slice()].concat(), actual_region);
+
+        // The overlaid pages are not dirty.
+        guest_memory.iter().for_each(|region| {
+            assert!(!region.bitmap().dirty_at(0));
+            assert!(!region.bitmap().dirty_at(page_size));
+        });
+    }
+
+    #[test]
+    fn test_compressed_memory() {
+        let page_size: usize = get_page_size().unwrap();
+
+        // Two regions, the first one spanning more than one chunk.
+        let mem_regions = [
+            (None, GuestAddress(0), page_size * (CHUNK_PAGES + 2)),
+            (
+                None,
+                GuestAddress(page_size as u64 * (CHUNK_PAGES as u64 + 3)),
+                page_size * 2,
+            ),
+        ];
+        let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true).unwrap();
+        let mut memory_state = guest_memory.describe();
+        memory_state.file_format = MemFileFormatState::Compressed;
+
+        // Zero pages, repeated data and data that doesn't compress.
+        let ones = vec![1u8; page_size];
+        let random = utils::rand::rand_alphanumerics(page_size)
+            .into_string()
+            .unwrap();
+        guest_memory
+            .write(&ones, GuestAddress(page_size as u64))
+            .unwrap();
+        guest_memory
+            .write(
+                random.as_bytes(),
+                GuestAddress(page_size as u64 * (CHUNK_PAGES as u64 + 1)),
+            )
+            .unwrap();
+        guest_memory
+            .write(
+                random.as_bytes(),
+                GuestAddress(page_size as u64 * (CHUNK_PAGES as u64 + 3)),
+            )
+            .unwrap();
+
+        let memory_file = TempFile::new().unwrap();
+        let mut memory_file = memory_file.into_file();
+        guest_memory.dump_compressed(&mut memory_file).unwrap();
+        // The zero pages are not stored.
+        let file_len = memory_file.metadata().unwrap().len() as usize;
+        assert!(file_len < page_size * 4);
+
+        let restored_guest_memory =
+            GuestMemoryMmap::restore_compressed(&mut memory_file, &memory_state, true).unwrap();
+        for region in restored_guest_memory.iter() {
+            let mut expected_region = vec![0u8; region.len() as usize];
+            let mut actual_region = vec![0u8; region.len() as usize];
+            guest_memory
+                .read(expected_region.as_mut_slice(), region.start_addr())
+                .unwrap();
+            restored_guest_memory
+                .read(actual_region.as_mut_slice(), region.start_addr())
+                .unwrap();
+            assert_eq!(expected_region, actual_region);
+            assert!(!region.bitmap().dirty_at(page_size));
+        }
+
+        // A memory layout that doesn't match the file is rejected.
+        let mut other_state = guest_memory.describe();
+        other_state.regions.pop();
+        assert!(matches!(
+            GuestMemoryMmap::restore_compressed(&mut memory_file, &other_state, false),
+            Err(Error::InvalidCompressedFile(_))
+        ));
+
+        // So is a corrupted chunk.
+        let mut last_byte = [0u8];
+        memory_file
+            .seek(SeekFrom::Start(file_len as u64 - 1))
+            .unwrap();
+        memory_file.read_exact(&mut last_byte).unwrap();
+        last_byte[0] ^= 0xff;
+        memory_file
+            .seek(SeekFrom::Start(file_len as u64 - 1))
+            .unwrap();
+        memory_file.write_all(&last_byte).unwrap();
+        assert!(matches!(
+            GuestMemoryMmap::restore_compressed(&mut memory_file, &memory_state, false),
+            Err(Error::ChunkChecksum(_)) | Err(Error::Decompress(_))
+        ));
+
+        // And a file that is not in the compressed format.
+        let raw_file = TempFile::new().unwrap();
+        let mut raw_file = raw_file.into_file();
+        guest_memory.dump(&mut raw_file).unwrap();
+        assert!(matches!(
+            GuestMemoryMmap::restore_compressed(&mut raw_file, &memory_state, false),
+            Err(Error::InvalidCompressedFile(_))
+        ));
+    }
 }
@@ -0,0 +1,935 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pre-copy live migration of a microVM to another Firecracker process, over a socket.
+//!
+//! The source first sends the whole guest memory while the microVM keeps running, then the
+//! pages dirtied in the meantime, round after round, until a round sends few enough pages or
+//! the maximum number of rounds is reached. These rounds are sent by a dedicated thread, so that
+//! the VMM thread keeps emulating the devices; it only gets the KVM dirty log between the
+//! rounds. The microVM is then paused and the last dirty pages are sent, followed by the microVM
+//! state. The destination builds the microVM out of them the same way a snapshot is loaded, and
+//! acknowledges the migration, after which the source stays paused.
+//!
+//! Every message starts with a header holding its type and the length of its payload. The
+//! memory layout and the microVM state are sent in the snapshot format, with their checksum.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use logger::{error, info, update_metric_with_elapsed_time, METRICS};
+use seccompiler::BpfThreadMap;
+use snapshot::Snapshot;
+use utils::byte_order::{read_le_u32, read_le_u64, write_le_u32, write_le_u64};
+use utils::eventfd::EventFd;
+use utils::get_page_size;
+use versionize::{VersionMap, Versionize};
+use vm_memory::{
+    Bitmap, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
+    MemoryRegionAddress,
+};
+
+use crate::builder::{self, StartMicrovmError};
+use crate::memory_snapshot::{self, GuestMemoryState, SnapshotMemory};
+use crate::persist::{
+    snapshot_state_sanity_check, LoadSnapshotError, MicrovmState, MicrovmStateError,
+};
+use crate::resources::VmResources;
+use crate::vmm_config::instance_info::{InstanceInfo, VmState};
+use crate::vmm_config::migration::{
+    MigrationSocketConfig, MigrationTransport, ReceiveMigrationParams, SendMigrationParams,
+};
+use crate::{DirtyBitmap, Error as VmmError, EventManager, Vmm};
+
+/// Type and payload length.
+const MESSAGE_HEADER_LEN: usize = 12;
+/// Layout of the guest memory, sent first.
+const MSG_MEMORY_LAYOUT: u32 = 1;
+/// Guest address followed by the contents of contiguous pages.
+const MSG_PAGES: u32 = 2;
+/// State of the paused microVM, sent last.
+const MSG_STATE: u32 = 3;
+/// Sent back by the destination once the microVM is built.
+const MSG_DONE: u32 = 4;
+/// Maximum number of contiguous pages sent in a single message.
+const MAX_BATCH_PAGES: usize = 256;
+/// Upper bound of the size of the memory layout and microVM state messages.
+const MAX_STATE_LEN: u64 = 64 << 20;
+/// A peer not sending or receiving data for this long is considered gone.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// Interval at which the destination checks whether the source connected.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Errors associated with live migration.
+#[derive(Debug)]
+pub enum MigrationError {
+    /// Failed to build the received microVM.
+    BuildMicroVm(StartMicrovmError),
+    /// Failed to get the dirty bitmap.
+    DirtyBitmap(VmmError),
+    /// Failed to create or read the event of the migration thread.
+    EventFd(io::Error),
+    /// Failed to access the guest memory.
+    GuestMemory(GuestMemoryError),
+    /// The address of the migration socket is invalid.
+    InvalidAddress(String),
+    /// A message doesn't follow the migration protocol.
+    InvalidMessage(&'static str),
+    /// The received microVM state failed sanity checks.
+    InvalidState(LoadSnapshotError),
+    /// Failed to create the guest memory.
+    Memory(memory_snapshot::Error),
+    /// Failed to save the microVM state.
+    MicrovmState(MicrovmStateError),
+    /// Failed to pause the microVM for the last round.
+    PauseMicroVm(VmmError),
+    /// Failed to resume the received microVM.
+    ResumeMicroVm(VmmError),
+    /// Failed to deserialize the memory layout or the microVM state.
+    DeserializeState(snapshot::Error),
+    /// Failed to serialize the memory layout or the microVM state.
+    SerializeState(snapshot::Error),
+    /// Failed to set up the migration socket.
+    Socket(&'static str, io::Error),
+    /// Failed to spawn the thread sending the pre-copy rounds.
+    Spawn(io::Error),
+    /// Failed to send or receive data on the migration socket.
+    Stream(io::Error),
+    /// A message of an unexpected type was received.
+    UnexpectedMessage(u32),
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        use self::MigrationError::*;
+        match self {
+            BuildMicroVm(err) => write!(f, "Cannot build the received microVM: {}", err),
+            DirtyBitmap(err) => write!(f, "Cannot get dirty bitmap: {}", err),
+            EventFd(err) => write!(f, "Migration thread event error: {}", err),
+            GuestMemory(err) => write!(f, "Cannot access the guest memory: {:?}", err),
+            InvalidAddress(address) => write!(f, "Invalid migration socket address: {}", address),
+            InvalidMessage(err) => write!(f, "Invalid migration message: {}", err),
+            InvalidState(err) => write!(f, "Invalid microVM state: {}", err),
+            Memory(err) => write!(f, "Cannot create the guest memory: {}", err),
+            MicrovmState(err) => write!(f, "Cannot save the microVM state: {}", err),
+            PauseMicroVm(err) => write!(f, "Cannot pause the microVM: {}", err),
+            ResumeMicroVm(err) => write!(f, "Cannot resume the received microVM: {}", err),
+            DeserializeState(err) => write!(f, "Cannot deserialize the microVM state: {:?}", err),
+            SerializeState(err) => write!(f, "Cannot serialize the microVM state: {:?}", err),
+            Socket(action, err) => write!(
+                f,
+                "Cannot perform {} on the migration socket: {}",
+                action, err
+            ),
+            Spawn(err) => write!(f, "Cannot spawn the migration thread: {}", err),
+            Stream(err) => write!(f, "Cannot transfer migration data: {}", err),
+            UnexpectedMessage(kind) => write!(f, "Unexpected migration message type: {}", kind),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, MigrationError>;
+
+enum MigrationStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl MigrationStream {
+    /// Connects to the destination listening on the `config` socket.
+    fn connect(config: &MigrationSocketConfig) -> Result<MigrationStream> {
+        use self::MigrationError::Socket;
+        let stream = match config.transport {
+            MigrationTransport::Tcp => {
+                let stream = TcpStream::connect(parse_tcp_address(&config.address)?)
+                    .map_err(|err| Socket("connect", err))?;
+                // Pages are sent in large messages, the last one shouldn't wait for more data.
+                stream
+                    .set_nodelay(true)
+                    .map_err(|err| Socket("setsockopt", err))?;
+                MigrationStream::Tcp(stream)
+            }
+            MigrationTransport::Unix => MigrationStream::Unix(
+                UnixStream::connect(&config.address).map_err(|err| Socket("connect", err))?,
+            ),
+        };
+        stream.set_timeouts()?;
+        Ok(stream)
+    }
+
+    /// Waits for the source to connect to the `config` socket, for at most `IO_TIMEOUT`.
+    fn accept(config: &MigrationSocketConfig) -> Result<MigrationStream> {
+        use self::MigrationError::Socket;
+        let stream = match config.transport {
+            MigrationTransport::Tcp => {
+                let listener = TcpListener::bind(parse_tcp_address(&config.address)?)
+                    .map_err(|err| Socket("bind", err))?;
+                let stream = listener
+                    .set_nonblocking(true)
+                    .and_then(|()| accept_until(|| listener.accept(), IO_TIMEOUT))
+                    .and_then(|stream| stream.set_nonblocking(false).map(|()| stream))
+                    .map_err(|err| Socket("accept", err))?;
+                MigrationStream::Tcp(stream)
+            }
+            MigrationTransport::Unix => {
+                let path = Path::new(&config.address);
+                let listener = UnixListener::bind(path).map_err(|err| Socket("bind", err))?;
+                let accepted = listener
+                    .set_nonblocking(true)
+                    .and_then(|()| accept_until(|| listener.accept(), IO_TIMEOUT))
+                    .and_then(|stream| stream.set_nonblocking(false).map(|()| stream));
+                // The socket is only used for a single migration.
+                if let Err(err) = std::fs::remove_file(path) {
+                    error!("Cannot remove the migration socket: {}", err);
+                }
+                MigrationStream::Unix(accepted.map_err(|err| Socket("accept", err))?)
+            }
+        };
+        stream.set_timeouts()?;
+        Ok(stream)
+    }
+
+    fn set_timeouts(&self) -> Result<()> {
+        let result = match self {
+            MigrationStream::Tcp(stream) => stream
+                .set_read_timeout(Some(IO_TIMEOUT))
+                .and_then(|()| stream.set_write_timeout(Some(IO_TIMEOUT))),
+            MigrationStream::Unix(stream) => stream
+                .set_read_timeout(Some(IO_TIMEOUT))
+                .and_then(|()| stream.set_write_timeout(Some(IO_TIMEOUT))),
+        };
+        result.map_err(|err| MigrationError::Socket("setsockopt", err))
+    }
+}
+
+impl Read for MigrationStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            MigrationStream::Tcp(stream) => stream.read(buf),
+            MigrationStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for MigrationStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MigrationStream::Tcp(stream) => stream.write(buf),
+            MigrationStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MigrationStream::Tcp(stream) => stream.flush(),
+            MigrationStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Polls `accept` on a non-blocking listener until a peer connects, or fails with `TimedOut`
+/// once `timeout` elapsed.
+fn accept_until<S, A>(
+    mut accept: impl FnMut() -> io::Result<(S, A)>,
+    timeout: Duration,
+) -> io::Result<S> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match accept() {
+            Ok((stream, _)) => return Ok(stream),
+            Err(err) if err.kind() != io::ErrorKind::WouldBlock => return Err(err),
+            Err(_) if Instant::now() >= deadline => {
+                return Err(io::Error::from(io::ErrorKind::TimedOut))
+            }
+            Err(_) => thread::sleep(ACCEPT_POLL_INTERVAL),
+        }
+    }
+}
+
+fn parse_tcp_address(address: &str) -> Result<SocketAddr> {
+    address
+        .parse::<SocketAddr>()
+        .map_err(|_| MigrationError::InvalidAddress(address.to_string()))
+}
+
+fn write_message_header<W: Write>(stream: &mut W, kind: u32, len: u64) -> Result<()> {
+    let mut header = [0u8; MESSAGE_HEADER_LEN];
+    write_le_u32(&mut header[0..4], kind);
+    write_le_u64(&mut header[4..12], len);
+    stream.write_all(&header).map_err(MigrationError::Stream)
+}
+
+fn read_message_header<R: Read>(stream: &mut R) -> Result<(u32, u64)> {
+    let mut header = [0u8; MESSAGE_HEADER_LEN];
+    stream
+        .read_exact(&mut header)
+        .map_err(MigrationError::Stream)?;
+    Ok((read_le_u32(&header[0..4]), read_le_u64(&header[4..12])))
+}
+
+fn send_versionized<W: Write, O: Versionize>(
+    stream: &mut W,
+    kind: u32,
+    object: &O,
+    version_map: VersionMap,
+) -> Result<()> {
+    let data_version = version_map.latest_version();
+    let mut payload = Vec::new();
+    Snapshot::new(version_map, data_version)
+        .save(&mut payload, object)
+        .map_err(MigrationError::SerializeState)?;
+    write_message_header(stream, kind, payload.len() as u64)?;
+    stream.write_all(&payload).map_err(MigrationError::Stream)
+}
+
+fn receive_versionized<R: Read, O: Versionize>(
+    stream: &mut R,
+    len: u64,
+    version_map: VersionMap,
+) -> Result<O> {
+    if len > MAX_STATE_LEN {
+        return Err(MigrationError::InvalidMessage("state too large"));
+    }
+    Snapshot::load(stream, len as usize, version_map).map_err(MigrationError::DeserializeState)
+}
+
+/// Guest memory pages sent in a round.
+#[derive(Clone, Copy)]
+enum Round<'a> {
+    /// All the pages which are not zeroed.
+    Full,
+    /// The pages written by the vCPUs since the previous round, as logged by KVM. The devices
+    /// keep writing to the guest memory during the pre-copy rounds, so their writes are only
+    /// sent in the last round.
+    PreCopy(&'a DirtyBitmap),
+    /// The pages written by the vCPUs since the previous round, and by the devices since the
+    /// migration started.
+    Last(&'a DirtyBitmap),
+}
+
+/// Sends the guest memory pages selected by `round`. Returns the number of pages sent.
+fn send_memory<W: Write>(
+    stream: &mut W,
+    guest_memory: &GuestMemoryMmap,
+    round: Round,
+) -> Result<u64> {
+    let page_size = get_page_size().map_err(|err| MigrationError::Memory(err.into()))?;
+    let mut page = vec![0u8; page_size];
+    let mut batch = Vec::with_capacity(MAX_BATCH_PAGES * page_size);
+    let mut batch_addr = 0;
+    let mut pages_sent = 0;
+
+    for (slot, region) in guest_memory.iter().enumerate() {
+        let firecracker_bitmap = region.bitmap();
+        let kvm_dirty_at = |dirty_bitmap: &DirtyBitmap, page_index: usize| {
+            dirty_bitmap
+                .get(&slot)
+                .and_then(|words| words.get(page_index / 64))
+                .map_or(false, |word| (word >> (page_index % 64)) & 1 != 0)
+        };
+        for page_offset in (0..region.len() as usize).step_by(page_size) {
+            let page_index = page_offset / page_size;
+            let is_dirty = match round {
+                Round::Full => true,
+                Round::PreCopy(dirty_bitmap) => kvm_dirty_at(dirty_bitmap, page_index),
+                Round::Last(dirty_bitmap) => {
+                    kvm_dirty_at(dirty_bitmap, page_index)
+                        || firecracker_bitmap.dirty_at(page_offset)
+                }
+            };
+            if !is_dirty {
+                continue;
+            }
+
+            region
+                .read_slice(&mut page, MemoryRegionAddress(page_offset as u64))
+                .map_err(MigrationError::GuestMemory)?;
+            // The memory of the destination starts zeroed.
+            if matches!(round, Round::Full) && page.iter().all(|&byte| byte == 0) {
+                continue;
+            }
+
+            let page_addr = region.start_addr().0 + page_offset as u64;
+            if batch_addr + batch.len() as u64 != page_addr
+                || batch.len() == MAX_BATCH_PAGES * page_size
+            {
+                send_pages(stream, batch_addr, &batch)?;
+                batch.clear();
+            }
+            if batch.is_empty() {
+                batch_addr = page_addr;
+            }
+            batch.extend_from_slice(&page);
+            pages_sent += 1;
+        }
+
+        if let (Round::Last(_), Some(bitmap)) = (round, firecracker_bitmap) {
+            bitmap.reset();
+        }
+    }
+    send_pages(stream, batch_addr, &batch)?;
+
+    Ok(pages_sent)
+}
+
+fn send_pages<W: Write>(stream: &mut W, addr: u64, pages: &[u8]) -> Result<()> {
+    if pages.is_empty() {
+        return Ok(());
+    }
+    write_message_header(stream, MSG_PAGES, (pages.len() + 8) as u64)?;
+    stream
+        .write_all(&addr.to_le_bytes())
+        .and_then(|()| stream.write_all(pages))
+        .map_err(MigrationError::Stream)
+}
+
+/// Writes the received pages into `guest_memory` until the state of the microVM is received.
+fn receive_memory<R: Read, O: Versionize>(
+    stream: &mut R,
+    guest_memory: &GuestMemoryMmap,
+    version_map: VersionMap,
+) -> Result<O> {
+    let page_size = get_page_size().map_err(|err| MigrationError::Memory(err.into()))?;
+    loop {
+        match read_message_header(stream)? {
+            (MSG_PAGES, len) => {
+                let pages_len = len
+                    .checked_sub(8)
+                    .filter(|&pages_len| pages_len <= (MAX_BATCH_PAGES * page_size) as u64)
+                    .ok_or(MigrationError::InvalidMessage("bad pages length"))?;
+                let mut addr = [0u8; 8];
+                stream
+                    .read_exact(&mut addr)
+                    .map_err(MigrationError::Stream)?;
+                guest_memory
+                    .read_exact_from(
+                        GuestAddress(u64::from_le_bytes(addr)),
+                        stream,
+                        pages_len as usize,
+                    )
+                    .map_err(MigrationError::GuestMemory)?;
+            }
+            (MSG_STATE, len) => return receive_versionized(stream, len, version_map),
+            (kind, _) => return Err(MigrationError::UnexpectedMessage(kind)),
+        }
+    }
+}
+
+/// Pre-copy rounds of a migration, sent by a dedicated thread.
+pub struct PreCopy {
+    /// Signaled by the migration thread at the end of each round.
+    round_evt: EventFd,
+    dirty_bitmaps: Sender<DirtyBitmap>,
+    pages_sent: Receiver<Result<u64>>,
+    thread: thread::JoinHandle<MigrationStream>,
+    round: u32,
+    max_rounds: u32,
+    dirty_pages_threshold: u64,
+}
+
+impl PreCopy {
+    /// Event signaled by the migration thread at the end of each round.
+    pub fn round_evt(&self) -> &EventFd {
+        &self.round_evt
+    }
+}
+
+/// Connects to the Firecracker process listening on the destination socket and starts sending
+/// the guest memory of the running microVM, from a dedicated thread. `next_round` must be
+/// called each time the round event of the returned `PreCopy` is signaled.
+pub fn start_migration(
+    vmm: &Vmm,
+    params: &SendMigrationParams,
+    version_map: VersionMap,
+) -> Result<PreCopy> {
+    // Fail before transferring the guest memory if the state can't be sent in the end.
+    vmm.check_state_savable()
+        .map_err(MigrationError::MicrovmState)?;
+    let mut stream = MigrationStream::connect(&params.destination)?;
+    send_versionized(
+        &mut stream,
+        MSG_MEMORY_LAYOUT,
+        &vmm.guest_memory().describe(),
+        version_map,
+    )?;
+
+    // Clear the dirty pages, all the pages are sent in the first round anyway. The pages
+    // written by the devices from now on are tracked until the last round.
+    vmm.get_dirty_bitmap()
+        .map_err(MigrationError::DirtyBitmap)?;
+    vmm.guest_memory().iter().for_each(|region| {
+        if let Some(bitmap) = region.bitmap() {
+            bitmap.reset();
+        }
+    });
+
+    let round_evt = EventFd::new(0).map_err(MigrationError::EventFd)?;
+    let thread_round_evt = round_evt.try_clone().map_err(MigrationError::EventFd)?;
+    let guest_memory = vmm.guest_memory().clone();
+    let (dirty_bitmap_sender, dirty_bitmap_receiver) = channel();
+    let (pages_sent_sender, pages_sent_receiver) = channel();
+    let thread = thread::Builder::new()
+        .spawn(move || {
+            send_pre_copy_rounds(
+                stream,
+                guest_memory,
+                dirty_bitmap_receiver,
+                pages_sent_sender,
+                thread_round_evt,
+            )
+        })
+        .map_err(MigrationError::Spawn)?;
+
+    Ok(PreCopy {
+        round_evt,
+        dirty_bitmaps: dirty_bitmap_sender,
+        pages_sent: pages_sent_receiver,
+        thread,
+        round: 0,
+        max_rounds: params.max_rounds,
+        dirty_pages_threshold: params.dirty_pages_threshold,
+    })
+}
+
+/// Body of the migration thread: sends the whole guest memory, then the pages dirty in each
+/// bitmap received from the VMM thread, until the VMM thread stops sending bitmaps. Only reads
+/// the guest memory, the microVM keeps running in the meantime.
+fn send_pre_copy_rounds(
+    mut stream: MigrationStream,
+    guest_memory: GuestMemoryMmap,
+    dirty_bitmaps: Receiver<DirtyBitmap>,
+    pages_sent: Sender<Result<u64>>,
+    round_evt: EventFd,
+) -> MigrationStream {
+    let mut result = send_memory(&mut stream, &guest_memory, Round::Full);
+    loop {
+        let failed = result.is_err();
+        if pages_sent.send(result).is_err() {
+            break;
+        }
+        if let Err(err) = round_evt.write(1) {
+            error!("Cannot signal the end of a migration round: {}", err);
+            break;
+        }
+        if failed {
+            break;
+        }
+        // The VMM thread stops sending bitmaps once the microVM is about to be paused.
+        match dirty_bitmaps.recv() {
+            Ok(dirty_bitmap) => {
+                result = send_memory(&mut stream, &guest_memory, Round::PreCopy(&dirty_bitmap))
+            }
+            Err(_) => break,
+        }
+    }
+    stream
+}
+
+/// Handles the end of a pre-copy round, waiting for it if needed. Starts the next round and
+/// returns `true`, or returns `false` once `finish_migration` should be called.
+pub fn next_round(vmm: &Vmm, pre_copy: &mut PreCopy) -> Result<bool> {
+    pre_copy.round_evt.read().map_err(MigrationError::EventFd)?;
+    let pages_sent = pre_copy
+        .pages_sent
+        .recv()
+        .expect("The migration thread exited")?;
+    if pre_copy.round == 0 {
+        info!("Migration: sent {} pages of guest memory.", pages_sent);
+    } else {
+        info!(
+            "Migration: sent {} dirty pages in round {}.",
+            pages_sent, pre_copy.round
+        );
+        if pages_sent <= pre_copy.dirty_pages_threshold {
+            return Ok(false);
+        }
+    }
+    if pre_copy.round == pre_copy.max_rounds {
+        return Ok(false);
+    }
+
+    let dirty_bitmap = vmm
+        .get_dirty_bitmap()
+        .map_err(MigrationError::DirtyBitmap)?;
+    pre_copy
+        .dirty_bitmaps
+        .send(dirty_bitmap)
+        .expect("The migration thread exited");
+    pre_copy.round += 1;
+    Ok(true)
+}
+
+/// Pauses the microVM once the pre-copy rounds are over, and sends the last dirty pages and the
+/// microVM state. The microVM is left paused once the destination acknowledged the migration,
+/// or resumed if the migration fails after it was paused.
+pub fn finish_migration(vmm: &mut Vmm, pre_copy: PreCopy, version_map: VersionMap) -> Result<()> {
+    let PreCopy {
+        dirty_bitmaps,
+        thread,
+        ..
+    } = pre_copy;
+    // Lets the migration thread return the stream.
+    drop(dirty_bitmaps);
+    let mut stream = thread.join().expect("The migration thread panicked");
+
+    let was_running = vmm.instance_info.state == VmState::Running;
+    let pause_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+    vmm.pause_vm().map_err(MigrationError::PauseMicroVm)?;
+    let result = send_final_round(vmm, &mut stream, version_map);
+    match result {
+        Ok(()) => {
+            let downtime_us = update_metric_with_elapsed_time(
+                &METRICS.latencies_us.vmm_migration_downtime,
+                pause_start_us,
+            );
+            info!(
+                "Migration completed, the microVM was paused for {} us.",
+                downtime_us
+            );
+        }
+        // The destination didn't take over, the microVM keeps running here.
+        Err(_) if was_running => {
+            if let Err(err) = vmm.resume_vm() {
+                error!(
+                    "Cannot resume the microVM after a failed migration: {}",
+                    err
+                );
+            }
+        }
+        Err(_) => (),
+    }
+    result
+}
+
+fn send_final_round(
+    vmm: &mut Vmm,
+    stream: &mut MigrationStream,
+    version_map: VersionMap,
+) -> Result<()> {
+    let dirty_bitmap = vmm
+        .get_dirty_bitmap()
+        .map_err(MigrationError::DirtyBitmap)?;
+    let pages_sent = send_memory(stream, vmm.guest_memory(), Round::Last(&dirty_bitmap))?;
+    info!(
+        "Migration: sent {} dirty pages in the last round.",
+        pages_sent
+    );
+
+    let microvm_state = vmm.save_state().map_err(MigrationError::MicrovmState)?;
+    send_versionized(stream, MSG_STATE, &microvm_state, version_map)?;
+
+    match read_message_header(stream)? {
+        (MSG_DONE, _) => Ok(()),
+        (kind, _) => Err(MigrationError::UnexpectedMessage(kind)),
+    }
+}
+
+/// Waits for a microVM to be migrated to the socket described in `params` and builds it. The
+/// microVM is left paused.
+pub fn receive_migration(
+    instance_info: &InstanceInfo,
+    event_manager: &mut EventManager,
+    seccomp_filters: &BpfThreadMap,
+    params: &ReceiveMigrationParams,
+    version_map: VersionMap,
+    vm_resources: &mut VmResources,
+) -> Result<Arc<Mutex<Vmm>>> {
+    let mut stream = MigrationStream::accept(&params.source)?;
+    info!("Migration: receiving a microVM.");
+
+    let memory_state: GuestMemoryState = match read_message_header(&mut stream)? {
+        (MSG_MEMORY_LAYOUT, len) => receive_versionized(&mut stream, len, version_map.clone())?,
+        (kind, _) => return Err(MigrationError::UnexpectedMessage(kind)),
+    };
+    let track_dirty_pages = params.enable_diff_snapshots;
+    let guest_memory = GuestMemoryMmap::restore(None, &memory_state, track_dirty_pages)
+        .map_err(MigrationError::Memory)?;
+    let microvm_state: MicrovmState = receive_memory(&mut stream, &guest_memory, version_map)?;
+
+    snapshot_state_sanity_check(&microvm_state).map_err(MigrationError::InvalidState)?;
+    if microvm_state.memory_state != memory_state {
+        return Err(MigrationError::InvalidMessage(
+            "the memory layout doesn't match the microVM state",
+        ));
+    }
+    // The memory now matches the source, none of its pages are dirty.
+    guest_memory.iter().for_each(|region| {
+        if let Some(bitmap) = region.bitmap() {
+            bitmap.reset();
+        }
+    });
+
+    let vmm = builder::build_microvm_from_snapshot(
+        instance_info,
+        event_manager,
+        microvm_state,
+        guest_memory,
+        None,
+        track_dirty_pages,
+        seccomp_filters,
+        vm_resources,
+    )
+    .map_err(MigrationError::BuildMicroVm)?;
+
+    write_message_header(&mut stream, MSG_DONE, 0)?;
+    info!("Migration: received the microVM.");
+    Ok(vmm)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::version_map::VERSION_MAP;
+
+    fn test_memory(page_size: usize) -> GuestMemoryMmap {
+        // Two regions, with a one page gap between them.
+        let mem_regions = [
+            (None, GuestAddress(0), page_size * 4),
+            (None, GuestAddress(page_size as u64 * 5), page_size * 2),
+        ];
+        vm_memory::create_guest_memory(&mem_regions[..], true).unwrap()
+    }
+
+    fn read_memory(guest_memory: &GuestMemoryMmap) -> Vec<Vec<u8>> {
+        guest_memory
+            .iter()
+            .map(|region| {
+                let mut contents = vec![0u8; region.len() as usize];
+                region
+                    .read_slice(&mut contents, MemoryRegionAddress(0))
+                    .unwrap();
+                contents
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_error_display() {
+        use self::MigrationError::*;
+
+        let err = InvalidAddress(String::new());
+        let _ = format!("{}{:?}", err, err);
+
+        let err = InvalidMessage("");
+        let _ = format!("{}{:?}", err, err);
+
+        let err = InvalidState(LoadSnapshotError::InvalidSnapshot(String::new()));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = EventFd(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = DeserializeState(snapshot::Error::InvalidMagic(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = SerializeState(snapshot::Error::InvalidMagic(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = Socket("bind", io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = Spawn(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = Stream(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = UnexpectedMessage(0);
+        let _ = format!("{}{:?}", err, err);
+    }
+
+    #[test]
+    fn test_invalid_socket_address() {
+        let config = MigrationSocketConfig {
+            transport: MigrationTransport::Tcp,
+            address: "/tmp/migration.sock".to_string(),
+        };
+        assert!(matches!(
+            MigrationStream::connect(&config),
+            Err(MigrationError::InvalidAddress(_))
+        ));
+        assert!(matches!(
+            MigrationStream::accept(&config),
+            Err(MigrationError::InvalidAddress(_))
+        ));
+    }
+
+    #[test]
+    fn test_send_receive_memory() {
+        let page_size = get_page_size().unwrap();
+        let src_memory = test_memory(page_size);
+        let dst_memory = test_memory(page_size);
+        let (mut src_stream, mut dst_stream) = UnixStream::pair().unwrap();
+
+        // Only the pages which are not zeroed are sent in the first round.
+        src_memory
+            .write(&vec![1u8; page_size * 2], GuestAddress(page_size as u64))
+            .unwrap();
+        src_memory
+            .write(&vec![2u8; page_size], GuestAddress(page_size as u64 * 6))
+            .unwrap();
+        assert_eq!(
+            send_memory(&mut src_stream, &src_memory, Round::Full).unwrap(),
+            3
+        );
+
+        // The pre-copy rounds only send the pages dirtied by the vCPUs, as logged by KVM.
+        let mut dirty_bitmap: DirtyBitmap = HashMap::new();
+        dirty_bitmap.insert(0, vec![0b1000]);
+        dirty_bitmap.insert(1, vec![0]);
+        src_memory
+            .write(&vec![3u8; page_size], GuestAddress(page_size as u64 * 5))
+            .unwrap();
+        assert_eq!(
+            send_memory(&mut src_stream, &src_memory, Round::PreCopy(&dirty_bitmap)).unwrap(),
+            1
+        );
+        // The last round also sends the pages written by the devices, as tracked by Firecracker.
+        assert_eq!(
+            send_memory(&mut src_stream, &src_memory, Round::Last(&dirty_bitmap)).unwrap(),
+            2
+        );
+        dirty_bitmap.insert(0, vec![0]);
+        assert_eq!(
+            send_memory(&mut src_stream, &src_memory, Round::Last(&dirty_bitmap)).unwrap(),
+            0
+        );
+
+        let memory_state = src_memory.describe();
+        send_versionized(
+            &mut src_stream,
+            MSG_STATE,
+            &memory_state,
+            VERSION_MAP.clone(),
+        )
+        .unwrap();
+        let received_state: GuestMemoryState =
+            receive_memory(&mut dst_stream, &dst_memory, VERSION_MAP.clone()).unwrap();
+        assert_eq!(received_state, memory_state);
+        assert_eq!(read_memory(&src_memory), read_memory(&dst_memory));
+    }
+
+    #[test]
+    fn test_send_pre_copy_rounds() {
+        let page_size = get_page_size().unwrap();
+        let src_memory = test_memory(page_size);
+        let dst_memory = test_memory(page_size);
+        let (src_stream, mut dst_stream) = UnixStream::pair().unwrap();
+        src_memory
+            .write(&vec![1u8; page_size], GuestAddress(0))
+            .unwrap();
+
+        let round_evt = EventFd::new(0).unwrap();
+        let thread_round_evt = round_evt.try_clone().unwrap();
+        let thread_memory = src_memory.clone();
+        let (dirty_bitmap_sender, dirty_bitmap_receiver) = channel();
+        let (pages_sent_sender, pages_sent_receiver) = channel();
+        let thread = thread::spawn(move || {
+            send_pre_copy_rounds(
+                MigrationStream::Unix(src_stream),
+                thread_memory,
+                dirty_bitmap_receiver,
+                pages_sent_sender,
+                thread_round_evt,
+            )
+        });
+
+        // The whole guest memory is sent first.
+        assert_eq!(round_evt.read().unwrap(), 1);
+        assert_eq!(pages_sent_receiver.recv().unwrap().unwrap(), 1);
+
+        // Then the pages dirty in each bitmap sent by the VMM thread.
+        src_memory
+            .write(&vec![2u8; page_size], GuestAddress(page_size as u64 * 5))
+            .unwrap();
+        let mut dirty_bitmap: DirtyBitmap = HashMap::new();
+        dirty_bitmap.insert(1, vec![0b1]);
+        dirty_bitmap_sender.send(dirty_bitmap).unwrap();
+        assert_eq!(round_evt.read().unwrap(), 1);
+        assert_eq!(pages_sent_receiver.recv().unwrap().unwrap(), 1);
+
+        // The stream is handed back once the VMM thread stops sending bitmaps.
+        drop(dirty_bitmap_sender);
+        let mut src_stream = thread.join().unwrap();
+        send_versionized(
+            &mut src_stream,
+            MSG_STATE,
+            &src_memory.describe(),
+            VERSION_MAP.clone(),
+        )
+        .unwrap();
+        let _: GuestMemoryState =
+            receive_memory(&mut dst_stream, &dst_memory, VERSION_MAP.clone()).unwrap();
+        assert_eq!(read_memory(&src_memory), read_memory(&dst_memory));
+    }
+
+    #[test]
+    fn test_accept_until() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        let timeout = Duration::from_millis(50);
+        let err = accept_until(|| listener.accept(), timeout).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let _source = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert!(accept_until(|| listener.accept(), timeout).is_ok());
+    }
+
+    #[test]
+    fn test_receive_invalid_messages() {
+        let page_size = get_page_size().unwrap();
+        let guest_memory = test_memory(page_size);
+        let (mut src_stream, mut dst_stream) = UnixStream::pair().unwrap();
+
+        write_message_header(&mut src_stream, MSG_DONE, 0).unwrap();
+        assert!(matches!(
+            receive_memory::<_, GuestMemoryState>(
+                &mut dst_stream,
+                &guest_memory,
+                VERSION_MAP.clone()
+            ),
+            Err(MigrationError::UnexpectedMessage(MSG_DONE))
+        ));
+
+        write_message_header(&mut src_stream, MSG_PAGES, 4).unwrap();
+        assert!(matches!(
+            receive_memory::<_, GuestMemoryState>(
+                &mut dst_stream,
+                &guest_memory,
+                VERSION_MAP.clone()
+            ),
+            Err(MigrationError::InvalidMessage(_))
+        ));
+
+        write_message_header(&mut src_stream, MSG_STATE, MAX_STATE_LEN + 1).unwrap();
+        assert!(matches!(
+            receive_memory::<_, GuestMemoryState>(
+                &mut dst_stream,
+                &guest_memory,
+                VERSION_MAP.clone()
+            ),
+            Err(MigrationError::InvalidMessage(_))
+        ));
+
+        // Pages in the gap between the regions.
+        send_pages(&mut src_stream, page_size as u64 * 4, &vec![1u8; page_size]).unwrap();
+        assert!(matches!(
+            receive_memory::<_, GuestMemoryState>(
+                &mut dst_stream,
+                &guest_memory,
+                VERSION_MAP.clone()
+            ),
+            Err(MigrationError::GuestMemory(_))
+        ));
+    }
+}
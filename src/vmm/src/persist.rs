@@ -5,39 +5,46 @@
 
 use std::fmt::{Display, Formatter};
 use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixStream;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 #[cfg(target_arch = "aarch64")]
 use arch::regs::{get_manufacturer_id_from_host, get_manufacturer_id_from_state};
 #[cfg(target_arch = "x86_64")]
 use cpuid::common::{get_vendor_id_from_cpuid, get_vendor_id_from_host};
-use devices::virtio::TYPE_NET;
-use logger::{error, info};
+use devices::virtio::{TYPE_BALLOON, TYPE_BLOCK, TYPE_NET, VIRTIO_BALLOON_F_FREE_PAGE_REPORTING};
+use logger::{error, info, warn};
 use seccompiler::BpfThreadMap;
 use serde::Serialize;
 use snapshot::Snapshot;
 use userfaultfd::{FeatureFlags, Uffd, UffdBuilder};
+use utils::byte_order::{read_le_u32, read_le_u64, write_le_u32, write_le_u64};
 use utils::sock_ctrl_msg::ScmSocket;
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
+use virtio_gen::virtio_blk::{VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_WRITE_ZEROES};
+use virtio_gen::virtio_net::VIRTIO_NET_F_MQ;
 use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{GuestMemory, GuestMemoryMmap};
 
 use crate::builder::{self, StartMicrovmError};
 use crate::device_manager::persist::{DeviceStates, Error as DevicePersistError};
-use crate::memory_snapshot::{GuestMemoryState, SnapshotMemory};
+use crate::memory_snapshot::{
+    GuestMemoryState, MemFileFormatState, MemoryBackingState, SnapshotMemory,
+};
 use crate::resources::VmResources;
 #[cfg(target_arch = "x86_64")]
 use crate::version_map::FC_V0_23_SNAP_VERSION;
-use crate::version_map::{FC_V1_0_SNAP_VERSION, FC_V1_1_SNAP_VERSION, FC_VERSION_TO_SNAP_VERSION};
+use crate::version_map::{
+    FC_V1_0_SNAP_VERSION, FC_V1_1_SNAP_VERSION, FC_V1_2_SNAP_VERSION, FC_VERSION_TO_SNAP_VERSION,
+};
 use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::machine_config::MAX_SUPPORTED_VCPUS;
 use crate::vmm_config::snapshot::{
-    CreateSnapshotParams, LoadSnapshotParams, MemBackendType, SnapshotType,
+    CreateSnapshotParams, LoadSnapshotParams, MemBackendType, MemFileFormat, SnapshotType,
 };
 use crate::vstate::vcpu::VcpuState;
 use crate::vstate::vm::VmState;
@@ -45,6 +52,12 @@ use crate::{mem_size_mib, memory_snapshot, vstate, Error as VmmError, EventManag
 
 #[cfg(target_arch = "x86_64")]
 const FC_V0_23_MAX_DEVICES: u32 = 11;
+/// Length of the randomly generated snapshot IDs.
+const SNAPSHOT_ID_LEN: usize = 16;
+/// Identifies the layer trailer of the memory files ("FCLAYER1").
+const LAYER_TRAILER_MAGIC: u64 = 0x3152_4559_414c_4346;
+/// Magic, snapshot ID, parent snapshot ID, layer index and padding.
+const LAYER_TRAILER_LEN: usize = 48;
 
 /// Holds information related to the VM that is not part of VmState.
 #[derive(Debug, PartialEq, Versionize)]
@@ -54,6 +67,56 @@ pub struct VmInfo {
     pub mem_size_mib: u64,
 }
 
+/// Position of a snapshot in a chain of diff snapshots.
+#[derive(Clone, Debug, Default, PartialEq, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct SnapshotLayerState {
+    /// Randomly generated ID of the snapshot.
+    pub id: String,
+    /// ID of the snapshot whose memory this diff snapshot is applied on top of.
+    pub parent_id: Option<String>,
+    /// Number of diff snapshots between this one and the full snapshot at the base of the chain.
+    pub layer: u32,
+}
+
+impl SnapshotLayerState {
+    /// Encodes the layer in the trailer appended to the memory file of the snapshot.
+    fn to_trailer(&self) -> [u8; LAYER_TRAILER_LEN] {
+        let mut trailer = [0u8; LAYER_TRAILER_LEN];
+        // The generated IDs fit in their zero padded fields.
+        let encode_id = |field: &mut [u8], id: &str| {
+            let len = std::cmp::min(id.len(), SNAPSHOT_ID_LEN);
+            field[..len].copy_from_slice(&id.as_bytes()[..len]);
+        };
+        write_le_u64(&mut trailer[0..8], LAYER_TRAILER_MAGIC);
+        encode_id(&mut trailer[8..24], &self.id);
+        if let Some(parent_id) = self.parent_id.as_ref() {
+            encode_id(&mut trailer[24..40], parent_id);
+        }
+        write_le_u32(&mut trailer[40..44], self.layer);
+        trailer
+    }
+
+    /// Decodes the layer from the trailer of a memory file, if the file has one.
+    fn from_trailer(trailer: &[u8; LAYER_TRAILER_LEN]) -> Option<Self> {
+        if read_le_u64(&trailer[0..8]) != LAYER_TRAILER_MAGIC {
+            return None;
+        }
+        // The IDs are zero padded.
+        let decode_id = |field: &[u8]| {
+            let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+            String::from_utf8(field[..len].to_vec()).ok()
+        };
+        let id = decode_id(&trailer[8..24])?;
+        let parent_id = decode_id(&trailer[24..40])?;
+        Some(SnapshotLayerState {
+            id,
+            parent_id: (!parent_id.is_empty()).then(|| parent_id),
+            layer: read_le_u32(&trailer[40..44]),
+        })
+    }
+}
+
 /// Contains the necesary state for saving/restoring a microVM.
 #[derive(Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
@@ -68,6 +131,33 @@ pub struct MicrovmState {
     pub vcpu_states: Vec<VcpuState>,
     /// Device states.
     pub device_states: DeviceStates,
+    /// Position of the snapshot in its chain of diff snapshots.
+    #[version(start = 2)]
+    pub layer_state: SnapshotLayerState,
+    /// Number of vCPUs plugged in the guest, out of the `vcpu_states`. Null in the states which
+    /// predate vCPU hotplug, where all the vCPUs are plugged.
+    #[version(start = 3, ser_fn = "plugged_vcpu_count_serialize")]
+    pub plugged_vcpu_count: u8,
+}
+
+impl MicrovmState {
+    fn plugged_vcpu_count_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 3 && (self.plugged_vcpu_count as usize) < self.vcpu_states.len() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not support vCPU hotplug.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of vCPUs plugged in the guest.
+    pub fn plugged_vcpu_count(&self) -> u8 {
+        match self.plugged_vcpu_count {
+            0 => self.vcpu_states.len() as u8,
+            count => count,
+        }
+    }
 }
 
 /// This describes the mapping between Firecracker base virtual address and
@@ -137,6 +227,8 @@ impl Display for MicrovmStateError {
 pub enum CreateSnapshotError {
     /// Failed to get dirty bitmap.
     DirtyBitmap(VmmError),
+    /// The memory file format can't hold the requested snapshot type.
+    IncompatibleMemFileFormat,
     /// The virtio devices uses a features that is incompatible with older versions of Firecracker.
     IncompatibleVirtioFeature(&'static str),
     /// Invalid microVM version format
@@ -163,6 +255,10 @@ impl Display for CreateSnapshotError {
         use self::CreateSnapshotError::*;
         match self {
             DirtyBitmap(err) => write!(f, "Cannot get dirty bitmap: {}", err),
+            IncompatibleMemFileFormat => write!(
+                f,
+                "Diff snapshots can only be created with the Raw memory file format"
+            ),
             IncompatibleVirtioFeature(feature) => write!(
                 f,
                 "The virtio devices use a features that is incompatible with older versions of \
@@ -213,6 +309,13 @@ pub enum LoadSnapshotError {
     DeserializeMemory(memory_snapshot::Error),
     /// Failed to deserialize microVM state.
     DeserializeMicrovmState(snapshot::Error),
+    /// The memory file format can't be loaded with the requested memory backend.
+    IncompatibleMemFileFormat,
+    /// The kind of guest memory of the snapshot can't be loaded with the requested memory
+    /// backend.
+    IncompatibleMemoryBacking,
+    /// The memory files do not match the chain of the snapshot.
+    InvalidMemoryLayers(String),
     /// Snapshot failed sanity checks.
     InvalidSnapshot(String),
     /// Failed to open memory backing file.
@@ -243,6 +346,15 @@ impl Display for LoadSnapshotError {
             DeserializeMicrovmState(err) => {
                 write!(f, "Cannot deserialize the microVM state: {:?}", err)
             }
+            IncompatibleMemFileFormat => write!(
+                f,
+                "Compressed memory files can only be loaded with the File memory backend"
+            ),
+            IncompatibleMemoryBacking => write!(
+                f,
+                "Shared guest memory can only be loaded with the File memory backend"
+            ),
+            InvalidMemoryLayers(err) => write!(f, "Invalid memory layers: {}", err),
             InvalidSnapshot(err) => write!(f, "Snapshot sanity check failed: {}", err),
             MemoryBackingFile(err) => write!(f, "Cannot open the memory file: {}", err),
             ResumeMicroVm(err) => write!(
@@ -277,10 +389,27 @@ pub fn create_snapshot(
 ) -> std::result::Result<(), CreateSnapshotError> {
     // Fail early from invalid target version.
     let snapshot_data_version = get_snapshot_data_version(&params.version, &version_map, &vmm)?;
+    // The compressed format has no holes to leave the clean pages in.
+    if params.mem_file_format == MemFileFormat::Compressed
+        && params.snapshot_type == SnapshotType::Diff
+    {
+        return Err(CreateSnapshotError::IncompatibleMemFileFormat);
+    }
 
-    let microvm_state = vmm
+    let mut microvm_state = vmm
         .save_state()
         .map_err(CreateSnapshotError::MicrovmState)?;
+    microvm_state.layer_state =
+        next_layer_state(vmm.snapshot_layer.as_ref(), &params.snapshot_type);
+    if version_map.get_type_version(snapshot_data_version, MicrovmState::type_id()) < 2
+        && microvm_state.layer_state.layer > 0
+    {
+        warn!(
+            "Target version does not support snapshot chains. The memory files of the chain must \
+             be squashed with rebase-snap before restoring."
+        );
+    }
+    microvm_state.memory_state.file_format = params.mem_file_format.into();
 
     snapshot_state_to_file(
         &microvm_state,
@@ -289,11 +418,49 @@ pub fn create_snapshot(
         version_map,
     )?;
 
-    snapshot_memory_to_file(vmm, &params.mem_file_path, &params.snapshot_type)?;
+    snapshot_memory_to_file(
+        vmm,
+        &params.mem_file_path,
+        &params.snapshot_type,
+        params.mem_file_format,
+        &microvm_state.layer_state,
+    )?;
+
+    info!(
+        "Created snapshot {} at layer {} of its chain.",
+        microvm_state.layer_state.id, microvm_state.layer_state.layer
+    );
+    vmm.snapshot_layer = Some(microvm_state.layer_state);
 
     Ok(())
 }
 
+/// Places a new snapshot in the chain of the previous one.
+///
+/// The dirty pages are tracked since the last snapshot taken or restored, so a diff snapshot
+/// only holds the changes made on top of that snapshot. Full snapshots start a new chain.
+fn next_layer_state(
+    parent: Option<&SnapshotLayerState>,
+    snapshot_type: &SnapshotType,
+) -> SnapshotLayerState {
+    // The generated characters are all alphanumeric, so the conversion can't fail.
+    let id = utils::rand::rand_alphanumerics(SNAPSHOT_ID_LEN)
+        .into_string()
+        .unwrap();
+    match (snapshot_type, parent) {
+        (SnapshotType::Diff, Some(parent)) => SnapshotLayerState {
+            id,
+            parent_id: Some(parent.id.clone()),
+            layer: parent.layer + 1,
+        },
+        _ => SnapshotLayerState {
+            id,
+            parent_id: None,
+            layer: 0,
+        },
+    }
+}
+
 fn snapshot_state_to_file(
     microvm_state: &MicrovmState,
     snapshot_path: &Path,
@@ -323,6 +490,8 @@ fn snapshot_memory_to_file(
     vmm: &Vmm,
     mem_file_path: &Path,
     snapshot_type: &SnapshotType,
+    mem_file_format: MemFileFormat,
+    layer_state: &SnapshotLayerState,
 ) -> std::result::Result<(), CreateSnapshotError> {
     use self::CreateSnapshotError::*;
     let mut file = OpenOptions::new()
@@ -332,20 +501,33 @@ fn snapshot_memory_to_file(
         .open(mem_file_path)
         .map_err(|err| MemoryBackingFile("open", err))?;
 
-    // Set the length of the file to the full size of the memory area.
-    let mem_size_mib = mem_size_mib(vmm.guest_memory());
-    file.set_len((mem_size_mib * 1024 * 1024) as u64)
-        .map_err(|err| MemoryBackingFile("set_length", err))?;
-
-    match snapshot_type {
-        SnapshotType::Diff => {
-            let dirty_bitmap = vmm.get_dirty_bitmap().map_err(DirtyBitmap)?;
-            vmm.guest_memory()
-                .dump_dirty(&mut file, &dirty_bitmap)
-                .map_err(Memory)
-        }
-        SnapshotType::Full => vmm.guest_memory().dump(&mut file).map_err(Memory),
-    }?;
+    if mem_file_format == MemFileFormat::Compressed {
+        vmm.guest_memory()
+            .dump_compressed(&mut file)
+            .map_err(Memory)?;
+    } else {
+        // Set the length of the file to the full size of the memory area.
+        let mem_size_mib = mem_size_mib(vmm.guest_memory());
+        file.set_len((mem_size_mib * 1024 * 1024) as u64)
+            .map_err(|err| MemoryBackingFile("set_length", err))?;
+
+        match snapshot_type {
+            SnapshotType::Diff => {
+                let dirty_bitmap = vmm.get_dirty_bitmap().map_err(DirtyBitmap)?;
+                vmm.guest_memory()
+                    .dump_dirty(&mut file, &dirty_bitmap)
+                    .map_err(Memory)
+            }
+            SnapshotType::Full => vmm.guest_memory().dump(&mut file).map_err(Memory),
+        }?;
+    }
+
+    // The layer is recorded past the end of the memory contents, where it is ignored when the
+    // memory is loaded. rebase-snap copies it along with the pages of the diff files, so a
+    // squashed memory file carries the layer of its newest diff.
+    file.seek(SeekFrom::End(0))
+        .and_then(|_| file.write_all(&layer_state.to_trailer()))
+        .map_err(|err| MemoryBackingFile("write", err))?;
     file.flush()
         .map_err(|err| MemoryBackingFile("flush", err))?;
     file.sync_all()
@@ -391,6 +573,53 @@ pub fn get_snapshot_data_version(
             })?;
     }
 
+    if data_version < FC_V1_2_SNAP_VERSION {
+        vmm.mmio_device_manager
+            .for_each_virtio_device(|virtio_type, _id, _info, dev| {
+                // Versions older than 1.2 can't restore the extra queues of a net or block device.
+                if virtio_type == TYPE_NET
+                    && dev.lock().expect("Poisoned lock").avail_features() & (1 << VIRTIO_NET_F_MQ)
+                        != 0
+                {
+                    return Err(CreateSnapshotError::IncompatibleVirtioFeature(
+                        "multi-queue",
+                    ));
+                }
+                if virtio_type == TYPE_BLOCK
+                    && dev.lock().expect("Poisoned lock").avail_features() & (1 << VIRTIO_BLK_F_MQ)
+                        != 0
+                {
+                    return Err(CreateSnapshotError::IncompatibleVirtioFeature(
+                        "multi-queue",
+                    ));
+                }
+                // Nor the free page reporting queue of a balloon device.
+                if virtio_type == TYPE_BALLOON
+                    && dev.lock().expect("Poisoned lock").avail_features()
+                        & (1 << VIRTIO_BALLOON_F_FREE_PAGE_REPORTING)
+                        != 0
+                {
+                    return Err(CreateSnapshotError::IncompatibleVirtioFeature(
+                        "free page reporting",
+                    ));
+                }
+                // Nor the discard and write zeroes requests of a block device, once the guest
+                // driver has started to issue them.
+                if virtio_type == TYPE_BLOCK {
+                    let dev = dev.lock().expect("Poisoned lock");
+                    if dev.has_feature(u64::from(VIRTIO_BLK_F_DISCARD)) {
+                        return Err(CreateSnapshotError::IncompatibleVirtioFeature("discard"));
+                    }
+                    if dev.has_feature(u64::from(VIRTIO_BLK_F_WRITE_ZEROES)) {
+                        return Err(CreateSnapshotError::IncompatibleVirtioFeature(
+                            "write zeroes",
+                        ));
+                    }
+                }
+                Ok(())
+            })?;
+    }
+
     Ok(data_version)
 }
 
@@ -465,6 +694,11 @@ pub fn snapshot_state_sanity_check(
             "Invalid vCPU count.".to_owned(),
         ));
     }
+    if microvm_state.plugged_vcpu_count as usize > microvm_state.vcpu_states.len() {
+        return Err(LoadSnapshotError::InvalidSnapshot(
+            "Invalid plugged vCPU count.".to_owned(),
+        ));
+    }
 
     // Check if the snapshot contains at least 1 mem region.
     // Upper bound check will be done when creating guest memory by comparing against
@@ -498,24 +732,59 @@ pub fn restore_from_snapshot(
     // Some sanity checks before building the microvm.
     snapshot_state_sanity_check(&microvm_state)?;
 
+    // The base memory file holds layer 0 and each diff file one of the following layers, unless
+    // no diff files are given, in which case the chain was already squashed into the base.
+    let layer_state = microvm_state.layer_state.clone();
+    if !params.mem_diff_paths.is_empty()
+        && params.mem_diff_paths.len() != layer_state.layer as usize
+    {
+        return Err(InvalidMemoryLayers(format!(
+            "The snapshot is at layer {} of its chain, but {} diff memory files were provided.",
+            layer_state.layer,
+            params.mem_diff_paths.len()
+        )));
+    }
+
     let mem_backend_path = &params.mem_backend.backend_path;
     let mem_state = &microvm_state.memory_state;
     let track_dirty_pages = params.enable_diff_snapshots;
     let (guest_memory, uffd) = match params.mem_backend.backend_type {
-        MemBackendType::File => (
-            guest_memory_from_file(mem_backend_path, mem_state, track_dirty_pages)?,
-            None,
-        ),
+        MemBackendType::File => {
+            check_memory_layers(mem_backend_path, &params.mem_diff_paths, &layer_state)?;
+            (
+                guest_memory_from_files(
+                    mem_backend_path,
+                    &params.mem_diff_paths,
+                    mem_state,
+                    track_dirty_pages,
+                )?,
+                None,
+            )
+        }
+        MemBackendType::Uffd if mem_state.file_format != MemFileFormatState::Raw => {
+            return Err(IncompatibleMemFileFormat)
+        }
+        // The page fault handler can only populate anonymous memory.
+        MemBackendType::Uffd if mem_state.backing != MemoryBackingState::Anonymous => {
+            return Err(IncompatibleMemoryBacking)
+        }
+        MemBackendType::Uffd if !params.mem_diff_paths.is_empty() => {
+            return Err(InvalidMemoryLayers(
+                "Diff memory files can only be loaded with the File memory backend.".to_owned(),
+            ))
+        }
         MemBackendType::Uffd => guest_memory_from_uffd(
             mem_backend_path,
             mem_state,
             track_dirty_pages,
-            // We enable the UFFD_FEATURE_EVENT_REMOVE feature only if a balloon device
-            // is present in the microVM state.
-            microvm_state.device_states.balloon_device.is_some(),
+            // We enable the UFFD_FEATURE_EVENT_REMOVE feature only if a balloon or a
+            // virtio-mem device, which both discard guest memory, is present in the microVM
+            // state.
+            microvm_state.device_states.balloon_device.is_some()
+                || microvm_state.device_states.mem_device.is_some(),
         )?,
     };
-    builder::build_microvm_from_snapshot(
+    let vmm = builder::build_microvm_from_snapshot(
         instance_info,
         event_manager,
         microvm_state,
@@ -525,7 +794,18 @@ pub fn restore_from_snapshot(
         seccomp_filters,
         vm_resources,
     )
-    .map_err(BuildMicroVm)
+    .map_err(BuildMicroVm)?;
+
+    // Snapshots of older versions are not part of a chain, their diff snapshots can't be
+    // placed on top of them.
+    if !layer_state.id.is_empty() {
+        info!(
+            "Restored snapshot {} at layer {} of its chain.",
+            layer_state.id, layer_state.layer
+        );
+        vmm.lock().expect("Poisoned lock").snapshot_layer = Some(layer_state);
+    }
+    Ok(vmm)
 }
 
 fn snapshot_state_from_file(
@@ -541,15 +821,103 @@ fn snapshot_state_from_file(
     Snapshot::load(&mut snapshot_reader, snapshot_len, version_map).map_err(DeserializeMicrovmState)
 }
 
-fn guest_memory_from_file(
+/// Checks that each memory file of the chain is layered on top of the previous one and that the
+/// newest one belongs to the snapshot being loaded.
+fn check_memory_layers(
+    mem_file_path: &Path,
+    mem_diff_paths: &[PathBuf],
+    layer_state: &SnapshotLayerState,
+) -> std::result::Result<(), LoadSnapshotError> {
+    use self::LoadSnapshotError::InvalidMemoryLayers;
+    // Snapshots of older versions are not part of a chain.
+    if layer_state.id.is_empty() {
+        return Ok(());
+    }
+
+    // Memory files of older versions don't record their layer, the chain is then checked from
+    // the first diff file.
+    let mut parent = memory_file_layer(mem_file_path)?;
+    for mem_diff_path in mem_diff_paths {
+        let layer = memory_file_layer(mem_diff_path)?.ok_or_else(|| {
+            InvalidMemoryLayers(format!(
+                "{} is not the memory file of a diff snapshot.",
+                mem_diff_path.display()
+            ))
+        })?;
+        if let Some(parent) = parent.as_ref() {
+            if layer.parent_id.as_ref() != Some(&parent.id) || layer.layer != parent.layer + 1 {
+                return Err(InvalidMemoryLayers(format!(
+                        "{} holds snapshot {} at layer {}, which is not layered on top of \
+                         snapshot                      {} at layer {}.",
+                        mem_diff_path.display(),
+                        layer.id,
+                        layer.layer,
+                        parent.id,
+                        parent.layer
+                    )));
+            }
+        }
+        parent = Some(layer);
+    }
+
+    match parent {
+        Some(newest) if newest.id != layer_state.id => Err(InvalidMemoryLayers(format!(
+            "The newest memory file holds snapshot {}, but snapshot {} is being loaded.",
+            newest.id, layer_state.id
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Reads the layer recorded in the trailer of a memory file.
+fn memory_file_layer(
     mem_file_path: &Path,
+) -> std::result::Result<Option<SnapshotLayerState>, LoadSnapshotError> {
+    use self::LoadSnapshotError::MemoryBackingFile;
+    let mut mem_file = File::open(mem_file_path).map_err(MemoryBackingFile)?;
+    let len = mem_file.metadata().map_err(MemoryBackingFile)?.len();
+    if len < LAYER_TRAILER_LEN as u64 {
+        return Ok(None);
+    }
+    let mut trailer = [0u8; LAYER_TRAILER_LEN];
+    mem_file
+        .seek(SeekFrom::Start(len - LAYER_TRAILER_LEN as u64))
+        .and_then(|_| mem_file.read_exact(&mut trailer))
+        .map_err(MemoryBackingFile)?;
+    Ok(SnapshotLayerState::from_trailer(&trailer))
+}
+
+fn guest_memory_from_files(
+    mem_file_path: &Path,
+    mem_diff_paths: &[PathBuf],
     mem_state: &GuestMemoryState,
     track_dirty_pages: bool,
 ) -> std::result::Result<GuestMemoryMmap, LoadSnapshotError> {
     use self::LoadSnapshotError::{DeserializeMemory, MemoryBackingFile};
-    let mem_file = File::open(mem_file_path).map_err(MemoryBackingFile)?;
-    GuestMemoryMmap::restore(Some(&mem_file), mem_state, track_dirty_pages)
-        .map_err(DeserializeMemory)
+    let mut mem_file = File::open(mem_file_path).map_err(MemoryBackingFile)?;
+    // The state describes the memory file of the newest layer. The full snapshot at the base of
+    // a chain of raw diff snapshots may have been saved in another format.
+    let file_format = if mem_diff_paths.is_empty() {
+        mem_state.file_format
+    } else {
+        memory_snapshot::probe_file_format(&mut mem_file).map_err(DeserializeMemory)?
+    };
+    let guest_memory = match file_format {
+        MemFileFormatState::Raw => {
+            GuestMemoryMmap::restore(Some(&mem_file), mem_state, track_dirty_pages)
+        }
+        MemFileFormatState::Compressed => {
+            GuestMemoryMmap::restore_compressed(&mut mem_file, mem_state, track_dirty_pages)
+        }
+    }
+    .map_err(DeserializeMemory)?;
+    for mem_diff_path in mem_diff_paths {
+        let mut mem_diff_file = File::open(mem_diff_path).map_err(MemoryBackingFile)?;
+        guest_memory
+            .overlay(&mut mem_diff_file, mem_state)
+            .map_err(DeserializeMemory)?;
+    }
+    Ok(guest_memory)
 }
 
 fn guest_memory_from_uffd(
@@ -664,6 +1032,7 @@ fn validate_fc_version_format(version: &str) -> Result<(), CreateSnapshotError>
 
 #[cfg(test)]
 mod tests {
+    use devices::virtio::net::NetBackend;
     use snapshot::Persist;
     use utils::errno;
     use utils::tempfile::TempFile;
@@ -696,6 +1065,8 @@ mod tests {
             amount_mib: 0,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            free_page_reporting: false,
+            auto_adjust: None,
         };
         insert_balloon_device(&mut vmm, &mut cmdline, &mut event_manager, balloon_config);
 
@@ -717,6 +1088,11 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            backend: NetBackend::Userspace,
+            tx_filter: None,
+            dhcp: None,
+            user_network: None,
         };
         insert_net_device(
             &mut vmm,
@@ -751,7 +1127,7 @@ mod tests {
         let vcpu_states = vec![VcpuState::default()];
         #[cfg(target_arch = "aarch64")]
         let mpidrs = construct_kvm_mpidrs(&vcpu_states);
-        let microvm_state = MicrovmState {
+        let mut microvm_state = MicrovmState {
             device_states: states,
             memory_state,
             vcpu_states,
@@ -760,6 +1136,12 @@ mod tests {
             vm_state: vmm.vm.save_state(&mpidrs).unwrap(),
             #[cfg(target_arch = "x86_64")]
             vm_state: vmm.vm.save_state().unwrap(),
+            layer_state: SnapshotLayerState {
+                id: String::from("snapshot"),
+                parent_id: Some(String::from("parent")),
+                layer: 1,
+            },
+            plugged_vcpu_count: 1,
         };
 
         let mut buf = vec![0; 10000];
@@ -783,7 +1165,206 @@ mod tests {
         assert_eq!(
             restored_microvm_state.device_states,
             microvm_state.device_states
+        );
+        // The layer state is only saved starting with version 2 of the microVM state.
+        assert_eq!(
+            restored_microvm_state.layer_state,
+            SnapshotLayerState::default()
+        );
+
+        version_map.set_type_version(MicrovmState::type_id(), 2);
+        microvm_state
+            .serialize(&mut buf.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_microvm_state =
+            MicrovmState::deserialize(&mut buf.as_slice(), &version_map, 2).unwrap();
+        assert_eq!(
+            restored_microvm_state.layer_state,
+            microvm_state.layer_state
+        );
+        // The plugged vCPU count is only saved starting with version 3 of the microVM state.
+        assert_eq!(restored_microvm_state.plugged_vcpu_count, 0);
+        assert_eq!(restored_microvm_state.plugged_vcpu_count(), 1);
+
+        // A microVM with unplugged vCPUs can't be saved in a version without vCPU hotplug.
+        microvm_state.vcpu_states.push(VcpuState::default());
+        assert_eq!(
+            microvm_state
+                .serialize(&mut buf.as_mut_slice(), &version_map, 2)
+                .unwrap_err(),
+            VersionizeError::Semantic("Target version does not support vCPU hotplug.".to_owned())
+        );
+        version_map.set_type_version(MicrovmState::type_id(), 3);
+        microvm_state
+            .serialize(&mut buf.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_microvm_state =
+            MicrovmState::deserialize(&mut buf.as_slice(), &version_map, 2).unwrap();
+        assert_eq!(restored_microvm_state.vcpu_states.len(), 2);
+        assert_eq!(restored_microvm_state.plugged_vcpu_count(), 1);
+    }
+
+    #[test]
+    fn test_next_layer_state() {
+        // Snapshots of a fresh microVM start a chain.
+        let full = next_layer_state(None, &SnapshotType::Full);
+        assert_eq!(full.id.len(), SNAPSHOT_ID_LEN);
+        assert_eq!(full.parent_id, None);
+        assert_eq!(full.layer, 0);
+        let diff = next_layer_state(None, &SnapshotType::Diff);
+        assert_eq!(diff.parent_id, None);
+        assert_eq!(diff.layer, 0);
+
+        // Diff snapshots are layered on the previous snapshot.
+        let diff = next_layer_state(Some(&full), &SnapshotType::Diff);
+        assert_ne!(diff.id, full.id);
+        assert_eq!(diff.parent_id, Some(full.id.clone()));
+        assert_eq!(diff.layer, 1);
+        let next_diff = next_layer_state(Some(&diff), &SnapshotType::Diff);
+        assert_eq!(next_diff.parent_id, Some(diff.id.clone()));
+        assert_eq!(next_diff.layer, 2);
+
+        // Full snapshots start a new chain.
+        let full = next_layer_state(Some(&next_diff), &SnapshotType::Full);
+        assert_eq!(full.parent_id, None);
+        assert_eq!(full.layer, 0);
+    }
+
+    #[test]
+    fn test_layer_trailer() {
+        let base = next_layer_state(None, &SnapshotType::Full);
+        let diff = next_layer_state(Some(&base), &SnapshotType::Diff);
+        assert_eq!(
+            SnapshotLayerState::from_trailer(&base.to_trailer()).unwrap(),
+            base
+        );
+        assert_eq!(
+            SnapshotLayerState::from_trailer(&diff.to_trailer()).unwrap(),
+            diff
+        );
+        // Memory files without a trailer end with the guest memory.
+        assert!(SnapshotLayerState::from_trailer(&[0u8; LAYER_TRAILER_LEN]).is_none());
+    }
+
+    #[test]
+    fn test_guest_memory_from_compressed_base() {
+        use vm_memory::{Bytes, GuestAddress};
+
+        let page_size = utils::get_page_size().unwrap();
+        let mem_regions = [(None, GuestAddress(0), page_size * 4)];
+        let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], false).unwrap();
+        let ones = vec![1u8; page_size];
+        let twos = vec![2u8; page_size];
+        guest_memory
+            .write(
+                &[ones.as_slice(), ones.as_slice()].concat(),
+                GuestAddress(0),
+            )
+            .unwrap();
+
+        // A compressed full snapshot followed by a raw diff snapshot of the second page, whose
+        // state is the one loaded.
+        let base_file = TempFile::new().unwrap();
+        guest_memory
+            .dump_compressed(&mut base_file.as_file())
+            .unwrap();
+        let diff_file = TempFile::new().unwrap();
+        diff_file.as_file().set_len(page_size as u64 * 4).unwrap();
+        diff_file
+            .as_file()
+            .seek(SeekFrom::Start(page_size as u64))
+            .unwrap();
+        diff_file.as_file().write_all(&twos).unwrap();
+        let mem_state = guest_memory.describe();
+        assert_eq!(mem_state.file_format, MemFileFormatState::Raw);
+
+        let restored_memory = guest_memory_from_files(
+            base_file.as_path(),
+            &[diff_file.as_path().to_path_buf()],
+            &mem_state,
+            false,
         )
+        .unwrap();
+        let mut actual = vec![0u8; page_size * 4];
+        restored_memory
+            .read(actual.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        let zeros = vec![0u8; page_size * 2];
+        assert_eq!(
+            actual,
+            [ones.as_slice(), twos.as_slice(), zeros.as_slice()].concat()
+        );
+    }
+
+    #[test]
+    fn test_check_memory_layers() {
+        let base = next_layer_state(None, &SnapshotType::Full);
+        let diff1 = next_layer_state(Some(&base), &SnapshotType::Diff);
+        let diff2 = next_layer_state(Some(&diff1), &SnapshotType::Diff);
+        let mem_file = |layer: Option<&SnapshotLayerState>| {
+            let file = TempFile::new().unwrap();
+            file.as_file().write_all(&[0xAA; 4096]).unwrap();
+            if let Some(layer) = layer {
+                file.as_file().write_all(&layer.to_trailer()).unwrap();
+            }
+            file
+        };
+        let base_file = mem_file(Some(&base));
+        let diff1_file = mem_file(Some(&diff1));
+        let diff2_file = mem_file(Some(&diff2));
+        let paths = |files: &[&TempFile]| -> Vec<PathBuf> {
+            files.iter().map(|f| f.as_path().to_path_buf()).collect()
+        };
+
+        // The whole chain, in order.
+        check_memory_layers(
+            base_file.as_path(),
+            &paths(&[&diff1_file, &diff2_file]),
+            &diff2,
+        )
+        .unwrap();
+        // A full snapshot, or a chain which was squashed into its base.
+        check_memory_layers(base_file.as_path(), &[], &base).unwrap();
+        check_memory_layers(diff2_file.as_path(), &[], &diff2).unwrap();
+        // The layer is unknown for memory files of older versions.
+        let old_base_file = mem_file(None);
+        check_memory_layers(
+            old_base_file.as_path(),
+            &paths(&[&diff1_file, &diff2_file]),
+            &diff2,
+        )
+        .unwrap();
+        check_memory_layers(old_base_file.as_path(), &[], &SnapshotLayerState::default()).unwrap();
+
+        // Diff files out of order.
+        let err = check_memory_layers(
+            base_file.as_path(),
+            &paths(&[&diff2_file, &diff1_file]),
+            &diff2,
+        )
+        .unwrap_err();
+        assert!(matches!(err, LoadSnapshotError::InvalidMemoryLayers(_)));
+        // A diff file of another chain.
+        let other_diff = next_layer_state(Some(&diff1), &SnapshotType::Diff);
+        let other_diff_file = mem_file(Some(&other_diff));
+        let err = check_memory_layers(
+            base_file.as_path(),
+            &paths(&[&diff1_file, &other_diff_file]),
+            &diff2,
+        )
+        .unwrap_err();
+        assert!(matches!(err, LoadSnapshotError::InvalidMemoryLayers(_)));
+        // The base without the diffs of the snapshot.
+        let err = check_memory_layers(base_file.as_path(), &[], &diff2).unwrap_err();
+        assert!(matches!(err, LoadSnapshotError::InvalidMemoryLayers(_)));
+        // A diff file which doesn't record its layer.
+        let err = check_memory_layers(
+            base_file.as_path(),
+            &paths(&[&diff1_file, &old_base_file]),
+            &diff2,
+        )
+        .unwrap_err();
+        assert!(matches!(err, LoadSnapshotError::InvalidMemoryLayers(_)));
     }
 
     #[test]
@@ -826,6 +1407,65 @@ mod tests {
         assert!(get_snapshot_data_version(&Some("0.24.0".to_string()), &VERSION_MAP, &vmm).is_ok());
     }
 
+    #[test]
+    fn test_get_snapshot_data_version_block_features() {
+        let vmm = default_vmm_with_devices();
+        let ack_block_features = |features: u64| {
+            vmm.mmio_device_manager
+                .for_each_virtio_device(|virtio_type, _id, _info, dev| {
+                    if virtio_type == TYPE_BLOCK {
+                        dev.lock().unwrap().set_acked_features(features);
+                    }
+                    Ok::<(), ()>(())
+                })
+                .unwrap();
+        };
+
+        for (feature, name) in [
+            (VIRTIO_BLK_F_DISCARD, "discard"),
+            (VIRTIO_BLK_F_WRITE_ZEROES, "write zeroes"),
+        ]
+        .iter()
+        {
+            ack_block_features(1u64 << feature);
+            match get_snapshot_data_version(&Some("1.1.0".to_string()), &VERSION_MAP, &vmm) {
+                Err(CreateSnapshotError::IncompatibleVirtioFeature(err_feature)) => {
+                    assert_eq!(err_feature, *name)
+                }
+                _ => panic!("Unexpected result."),
+            }
+            assert!(
+                get_snapshot_data_version(&Some("1.2.0".to_string()), &VERSION_MAP, &vmm).is_ok()
+            );
+        }
+
+        // Offering the features is fine as long as the guest has not negotiated them.
+        ack_block_features(0);
+        assert!(get_snapshot_data_version(&Some("1.1.0".to_string()), &VERSION_MAP, &vmm).is_ok());
+    }
+
+    #[test]
+    fn test_get_snapshot_data_version_block_mq() {
+        let mut event_manager = EventManager::new().expect("Cannot create EventManager");
+        let mut vmm = default_vmm();
+        let mut cmdline = default_kernel_cmdline();
+        let block_configs =
+            vec![
+                CustomBlockConfig::new(String::from("root"), true, None, true, CacheType::Unsafe)
+                    .with_num_queues(2),
+            ];
+        insert_block_devices(&mut vmm, &mut cmdline, &mut event_manager, block_configs);
+
+        // The extra queues are saved even if the guest didn't negotiate multi-queue.
+        match get_snapshot_data_version(&Some("1.1.0".to_string()), &VERSION_MAP, &vmm) {
+            Err(CreateSnapshotError::IncompatibleVirtioFeature(feature)) => {
+                assert_eq!(feature, "multi-queue")
+            }
+            _ => panic!("Unexpected result."),
+        }
+        assert!(get_snapshot_data_version(&Some("1.2.0".to_string()), &VERSION_MAP, &vmm).is_ok());
+    }
+
     #[test]
     fn test_create_snapshot_error_display() {
         use vm_memory::GuestMemoryError;
@@ -835,6 +1475,9 @@ mod tests {
         let err = DirtyBitmap(VmmError::DirtyBitmap(kvm_ioctls::Error::new(20)));
         let _ = format!("{}{:?}", err, err);
 
+        let err = IncompatibleMemFileFormat;
+        let _ = format!("{}{:?}", err, err);
+
         let err = InvalidVersionFormat;
         let _ = format!("{}{:?}", err, err);
 
@@ -880,6 +1523,15 @@ mod tests {
         let err = DeserializeMicrovmState(snapshot::Error::Io(0));
         let _ = format!("{}{:?}", err, err);
 
+        let err = IncompatibleMemFileFormat;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = IncompatibleMemoryBacking;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = InvalidMemoryLayers(String::new());
+        let _ = format!("{}{:?}", err, err);
+
         let err = MemoryBackingFile(io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
 
@@ -4,11 +4,13 @@
 use std::convert::From;
 use std::sync::{Arc, Mutex, MutexGuard};
 
+use devices::virtio::{TYPE_BLOCK, TYPE_NET};
 use logger::info;
 use mmds::data_store::{Mmds, MmdsVersion};
 use mmds::ns::MmdsNetworkStack;
 use serde::{Deserialize, Serialize};
 use utils::net::ipv4addr::is_link_local_valid;
+use utils::net::ipv6addr::is_link_local_or_unique_local;
 
 use crate::device_manager::persist::SharedDeviceType;
 use crate::vmm_config::balloon::*;
@@ -16,10 +18,13 @@ use crate::vmm_config::boot_source::{BootConfig, BootSourceConfig, BootSourceCon
 use crate::vmm_config::drive::*;
 use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::logger::{init_logger, LoggerConfig, LoggerConfigError};
-use crate::vmm_config::machine_config::{VmConfig, VmConfigError, VmUpdateConfig};
+use crate::vmm_config::machine_config::{
+    MemoryBacking, VmConfig, VmConfigError, VmUpdateConfig, MAX_SUPPORTED_VCPUS,
+};
 use crate::vmm_config::metrics::{init_metrics, MetricsConfig, MetricsConfigError};
 use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::*;
+use crate::vmm_config::vhost_user::*;
 use crate::vmm_config::vsock::*;
 use crate::vstate::vcpu::VcpuConfig;
 
@@ -46,6 +51,8 @@ pub enum Error {
     MmdsConfig(MmdsConfigError),
     /// Net device configuration error.
     NetDevice(NetworkInterfaceError),
+    /// vhost-user device configuration error.
+    VhostUserDevice(VhostUserConfigError),
     /// microVM vCpus or memory configuration error.
     VmConfig(VmConfigError),
     /// Vsock device configuration error.
@@ -64,6 +71,7 @@ impl std::fmt::Display for Error {
             Error::Mmds(err) => write!(f, "MMDS error: {}", err),
             Error::MmdsConfig(err) => write!(f, "MMDS config error: {}", err),
             Error::NetDevice(err) => write!(f, "Network device error: {}", err),
+            Error::VhostUserDevice(err) => write!(f, "vhost-user device error: {}", err),
             Error::VmConfig(err) => write!(f, "VM config error: {}", err),
             Error::VsockDevice(err) => write!(f, "Vsock device error: {}", err),
         }
@@ -89,6 +97,10 @@ pub struct VmmConfig {
     mmds_config: Option<MmdsConfig>,
     #[serde(rename = "network-interfaces", default)]
     net_devices: Vec<NetworkInterfaceConfig>,
+    #[serde(rename = "vhost-user-block", default)]
+    vhost_user_block_devices: Vec<VhostUserBlockConfig>,
+    #[serde(rename = "vhost-user-net", default)]
+    vhost_user_net_devices: Vec<VhostUserNetConfig>,
     #[serde(rename = "vsock")]
     vsock_device: Option<VsockDeviceConfig>,
 }
@@ -109,6 +121,8 @@ pub struct VmResources {
     pub balloon: BalloonBuilder,
     /// The network devices builder.
     pub net_builder: NetBuilder,
+    /// The devices served by vhost-user backends.
+    pub vhost_user: VhostUserBuilder,
     /// The optional Mmds data store.
     // This is initialised on demand (if ever used), so that we don't allocate it unless it's
     // actually used.
@@ -156,6 +170,14 @@ impl VmResources {
             resources.build_net_device(net_config)?;
         }
 
+        for vhost_user_config in vmm_config.vhost_user_block_devices.into_iter() {
+            resources.set_vhost_user_block_device(vhost_user_config)?;
+        }
+
+        for vhost_user_config in vmm_config.vhost_user_net_devices.into_iter() {
+            resources.set_vhost_user_net_device(vhost_user_config)?;
+        }
+
         if let Some(vsock_config) = vmm_config.vsock_device {
             resources.set_vsock_device(vsock_config)?;
         }
@@ -215,12 +237,13 @@ impl VmResources {
         }
     }
 
-    /// Returns a VcpuConfig based on the vm config.
+    /// Returns a VcpuConfig based on the vm config. The vCPUs which can be hot-plugged are
+    /// created and configured along with the boot vCPUs, so they are counted in.
     pub fn vcpu_config(&self) -> VcpuConfig {
         // The unwraps are ok to use because the values are initialized using defaults if not
         // supplied by the user.
         VcpuConfig {
-            vcpu_count: self.vm_config().vcpu_count,
+            vcpu_count: self.vm_config().max_vcpus(),
             smt: self.vm_config().smt,
             cpu_template: self.vm_config().cpu_template,
         }
@@ -241,6 +264,23 @@ impl VmResources {
         &self.vm_config
     }
 
+    /// Returns the kind of memory backing the guest memory. The vhost-user backends map the
+    /// guest memory from its file descriptors, so it can't be anonymous with them.
+    pub fn memory_backing(&self) -> MemoryBacking {
+        match self.vm_config.backing {
+            MemoryBacking::Anonymous if !self.vhost_user.is_empty() => MemoryBacking::Memfd,
+            backing => backing,
+        }
+    }
+
+    /// Returns the VmConfig, with the memory backing which is actually used.
+    pub fn machine_config(&self) -> VmConfig {
+        VmConfig {
+            backing: self.memory_backing(),
+            ..self.vm_config.clone()
+        }
+    }
+
     /// Update the machine configuration of the microVM.
     pub fn update_vm_config(&mut self, machine_config: &VmUpdateConfig) -> Result<VmConfigError> {
         let vcpu_count = machine_config
@@ -259,8 +299,22 @@ impl VmResources {
             return Err(VmConfigError::InvalidVcpuCount);
         }
 
+        // The vCPUs which can be hot-plugged are added to the vCPUs the microVM boots with, so
+        // the maximum vcpu count follows the same rules.
+        let max_vcpu_count = machine_config
+            .max_vcpu_count
+            .unwrap_or(self.vm_config.max_vcpu_count);
+        if max_vcpu_count != 0
+            && (max_vcpu_count < vcpu_count
+                || max_vcpu_count > MAX_SUPPORTED_VCPUS
+                || (smt && max_vcpu_count > 1 && max_vcpu_count % 2 == 1))
+        {
+            return Err(VmConfigError::InvalidMaxVcpuCount);
+        }
+
         self.vm_config.vcpu_count = vcpu_count;
         self.vm_config.smt = smt;
+        self.vm_config.max_vcpu_count = max_vcpu_count;
 
         let mem_size_mib = machine_config
             .mem_size_mib
@@ -283,7 +337,37 @@ impl VmResources {
             return Err(VmConfigError::IncompatibleBalloonSize);
         }
 
+        // Hugetlbfs files can only be sized in whole huge pages, so each region of the guest
+        // memory has to be.
+        let backing = machine_config.backing.unwrap_or(self.vm_config.backing);
+        if let Some(huge_page_size) = backing.huge_page_size() {
+            if arch::arch_memory_regions(mem_size_mib << 20)
+                .iter()
+                .any(|(_, size)| size % huge_page_size != 0)
+            {
+                return Err(VmConfigError::IncompatibleMemoryBacking(backing));
+            }
+        }
+
+        // The hot-pluggable memory is plugged and unplugged in whole blocks.
+        let hotplug_size_mib = machine_config
+            .hotplug_size_mib
+            .unwrap_or(self.vm_config.hotplug_size_mib);
+        let hotplug_requested_mib = machine_config
+            .hotplug_requested_mib
+            .unwrap_or(self.vm_config.hotplug_requested_mib);
+        let block_size_mib = backing.hotplug_block_size_mib();
+        if hotplug_size_mib % block_size_mib != 0 {
+            return Err(VmConfigError::InvalidHotplugSize);
+        }
+        if hotplug_requested_mib > hotplug_size_mib || hotplug_requested_mib % block_size_mib != 0 {
+            return Err(VmConfigError::InvalidHotplugRequestedSize);
+        }
+
         self.vm_config.mem_size_mib = mem_size_mib;
+        self.vm_config.backing = backing;
+        self.vm_config.hotplug_size_mib = hotplug_size_mib;
+        self.vm_config.hotplug_requested_mib = hotplug_requested_mib;
 
         // Update the CPU template
         if let Some(cpu_template) = machine_config.cpu_template {
@@ -298,6 +382,20 @@ impl VmResources {
         Ok(())
     }
 
+    /// Updates the number of vCPUs plugged in a started microVM, before they are plugged or
+    /// unplugged. Without hot-pluggable vCPUs, only the boot vCPU count can be requested.
+    pub fn update_vcpu_count(&mut self, vcpu_count: u8) -> Result<VmConfigError> {
+        if vcpu_count > self.vm_config.max_vcpus()
+            || (self.vm_config.max_vcpu_count == 0 && vcpu_count != self.vm_config.vcpu_count)
+        {
+            return Err(VmConfigError::InvalidVcpuCount);
+        }
+        self.update_vm_config(&VmUpdateConfig {
+            vcpu_count: Some(vcpu_count),
+            ..Default::default()
+        })
+    }
+
     // Repopulate the MmdsConfig based on information from the data store
     // and the associated net devices.
     fn mmds_config(&self) -> Option<MmdsConfig> {
@@ -313,11 +411,16 @@ impl VmResources {
             .collect();
 
         if !net_devs_with_mmds.is_empty() {
+            let mmds_guard = mmds.lock().expect("Poisoned lock");
             let mut inner_mmds_config = MmdsConfig {
-                version: mmds.lock().expect("Poisoned lock").version(),
+                version: mmds_guard.version(),
                 network_interfaces: vec![],
                 ipv4_address: None,
+                ipv6_address: None,
+                guest_writable_path: mmds_guard.guest_writable_path().map(str::to_owned),
+                acl: mmds_guard.acl().to_vec(),
             };
+            drop(mmds_guard);
 
             for net_dev in net_devs_with_mmds {
                 let net = net_dev.lock().unwrap();
@@ -327,6 +430,7 @@ impl VmResources {
                     // Safe to unwrap the mmds_ns as the filter() explicitly checks for
                     // its existence.
                     inner_mmds_config.ipv4_address = Some(net.mmds_ns().unwrap().ipv4_addr());
+                    inner_mmds_config.ipv6_address = net.mmds_ns().unwrap().ipv6_addr();
                 }
             }
 
@@ -352,6 +456,19 @@ impl VmResources {
             return Err(BalloonConfigError::TooManyPagesRequested);
         }
 
+        if let Some(auto_adjust) = config.auto_adjust.as_ref() {
+            auto_adjust
+                .validate(config.stats_polling_interval_s)
+                .map_err(BalloonConfigError::InvalidAutoAdjustConfig)?;
+            // Neither can the balloon controller set such a target size, although it can also
+            // reclaim the memory plugged through the virtio-mem device.
+            if auto_adjust.max_amount_mib as usize
+                > self.vm_config.mem_size_mib + self.vm_config.hotplug_size_mib
+            {
+                return Err(BalloonConfigError::TooManyPagesRequested);
+            }
+        }
+
         self.balloon.set(config)
     }
 
@@ -371,6 +488,12 @@ impl VmResources {
         &mut self,
         block_device_config: BlockDeviceConfig,
     ) -> Result<DriveError> {
+        if self
+            .vhost_user
+            .has_device(TYPE_BLOCK, &block_device_config.drive_id)
+        {
+            return Err(DriveError::DriveIdInUse(block_device_config.drive_id));
+        }
         self.block.insert(block_device_config)
     }
 
@@ -379,10 +502,45 @@ impl VmResources {
         &mut self,
         body: NetworkInterfaceConfig,
     ) -> Result<NetworkInterfaceError> {
+        if self.vhost_user.has_device(TYPE_NET, &body.iface_id) {
+            return Err(NetworkInterfaceError::IfaceIdInUse(body.iface_id));
+        }
         let _ = self.net_builder.build(body)?;
         Ok(())
     }
 
+    /// Sets a block device served by a vhost-user backend to be attached when the VM starts.
+    pub fn set_vhost_user_block_device(
+        &mut self,
+        config: VhostUserBlockConfig,
+    ) -> Result<VhostUserConfigError> {
+        if self
+            .block
+            .list
+            .iter()
+            .any(|block| block.lock().expect("Poisoned lock").id() == &config.drive_id)
+        {
+            return Err(VhostUserConfigError::DeviceIdInUse(config.drive_id));
+        }
+        self.vhost_user.insert_block(config)
+    }
+
+    /// Sets a network interface served by a vhost-user backend to be attached when the VM
+    /// starts.
+    pub fn set_vhost_user_net_device(
+        &mut self,
+        config: VhostUserNetConfig,
+    ) -> Result<VhostUserConfigError> {
+        if self
+            .net_builder
+            .iter()
+            .any(|net| net.lock().expect("Poisoned lock").id() == &config.iface_id)
+        {
+            return Err(VhostUserConfigError::DeviceIdInUse(config.iface_id));
+        }
+        self.vhost_user.insert_net(config)
+    }
+
     /// Sets a vsock device to be attached when the VM starts.
     pub fn set_vsock_device(&mut self, config: VsockDeviceConfig) -> Result<VsockConfigError> {
         self.vsock.insert(config)
@@ -394,7 +552,10 @@ impl VmResources {
         config: MmdsConfig,
         instance_id: &str,
     ) -> Result<MmdsConfigError> {
+        self.set_mmds_guest_access(&config)?;
         self.set_mmds_network_stack_config(&config)?;
+        // The version goes last, so that the token authority needed by the ACL gets the
+        // instance ID as well.
         self.set_mmds_version(config.version, instance_id)?;
 
         Ok(())
@@ -415,6 +576,17 @@ impl VmResources {
         Ok(())
     }
 
+    // Updates the access the guest has to the MMDS data store.
+    fn set_mmds_guest_access(&mut self, config: &MmdsConfig) -> Result<MmdsConfigError> {
+        let mut mmds_guard = self.locked_mmds_or_default();
+        mmds_guard
+            .set_acl(config.acl())
+            .map_err(MmdsConfigError::GuestAccess)?;
+        mmds_guard
+            .set_guest_writable_path(config.guest_writable_path())
+            .map_err(MmdsConfigError::GuestAccess)
+    }
+
     // Updates MMDS Network Stack for network interfaces to allow forwarding
     // requests to MMDS (or not).
     fn set_mmds_network_stack_config(&mut self, config: &MmdsConfig) -> Result<MmdsConfigError> {
@@ -425,6 +597,13 @@ impl VmResources {
             _ => Err(MmdsConfigError::InvalidIpv4Addr),
         }?;
 
+        // Check IPv6 address validity. MMDS is only reachable over IPv6 when one is configured.
+        let ipv6_addr = match config.ipv6_addr() {
+            Some(ipv6_addr) if is_link_local_or_unique_local(ipv6_addr) => Ok(Some(ipv6_addr)),
+            None => Ok(None),
+            _ => Err(MmdsConfigError::InvalidIpv6Addr),
+        }?;
+
         let network_interfaces = config.network_interfaces();
         // Ensure that at least one network ID is specified.
         if network_interfaces.is_empty() {
@@ -444,13 +623,13 @@ impl VmResources {
         // Safe to unwrap because we've just made sure that it's initialised.
         let mmds = self.mmds_or_default().clone();
 
-        // Create `MmdsNetworkStack` and configure the IPv4 address for
+        // Create `MmdsNetworkStack` and configure the IP addresses for
         // existing built network devices whose names are defined in the
         // network interface ID list.
         for net_device in self.net_builder.iter_mut() {
             let mut net_device_lock = net_device.lock().expect("Poisoned lock");
             if network_interfaces.contains(net_device_lock.id()) {
-                net_device_lock.configure_mmds_network_stack(ipv4_addr, mmds.clone());
+                net_device_lock.configure_mmds_network_stack(ipv4_addr, ipv6_addr, mmds.clone());
             } else {
                 net_device_lock.disable_mmds_network_stack();
             }
@@ -477,6 +656,8 @@ impl From<&VmResources> for VmmConfig {
             metrics: None,
             mmds_config: resources.mmds_config(),
             net_devices: resources.net_builder.configs(),
+            vhost_user_block_devices: resources.vhost_user.block_configs(),
+            vhost_user_net_devices: resources.vhost_user.net_configs(),
             vsock_device: resources.vsock.config(),
         }
     }
@@ -487,8 +668,11 @@ mod tests {
     use std::fs::File;
     use std::os::linux::fs::MetadataExt;
 
+    use devices::virtio::net::NetBackend;
+    use devices::virtio::vhost_user::test_utils::{default_block_backend, default_net_backend};
     use devices::virtio::vsock::{VsockError, VSOCK_DEV_ID};
     use logger::{LevelFilter, LOGGER};
+    use mmds::data_store::{MmdsAccess, MmdsAclRule};
     use serde_json::{Map, Value};
     use utils::net::mac::MacAddr;
     use utils::tempfile::TempFile;
@@ -496,7 +680,7 @@ mod tests {
     use super::*;
     use crate::resources::VmResources;
     use crate::vmm_config::boot_source::{BootConfig, BootSourceConfig, DEFAULT_KERNEL_CMDLINE};
-    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, FileEngineType};
+    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, FileEngineType, ImageFormat};
     use crate::vmm_config::machine_config::{CpuFeaturesTemplate, VmConfig, VmConfigError};
     use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
@@ -518,6 +702,11 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str("01:23:45:67:89:0a").unwrap()),
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
+            num_queue_pairs: 1,
+            backend: NetBackend::Userspace,
+            tx_filter: None,
+            dhcp: None,
+            user_network: None,
         }
     }
 
@@ -540,6 +729,9 @@ mod tests {
                 is_read_only: false,
                 rate_limiter: Some(RateLimiterConfig::default()),
                 file_engine_type: FileEngineType::default(),
+                num_queues: 1,
+                format: ImageFormat::Raw,
+                cow_overlay_path: None,
             },
             tmp_file,
         )
@@ -572,6 +764,7 @@ mod tests {
             vsock: Default::default(),
             balloon: Default::default(),
             net_builder: default_net_builder(),
+            vhost_user: Default::default(),
             mmds: None,
             boot_timer: false,
             mmds_size_limit: HTTP_MAX_PAYLOAD_SIZE,
@@ -1043,6 +1236,137 @@ mod tests {
             resources.mmds.unwrap().lock().unwrap().data_store_value(),
             Value::Object(map)
         );
+
+        // Test MMDS over IPv6, which requires a link-local or unique local address.
+        for (host_dev_name, ipv6_address, valid) in [
+            ("hostname10", "fd00::a9fe:a9fe", true),
+            ("hostname11", "2001:db8::1", false),
+        ]
+        .iter()
+        {
+            json = format!(
+                r#"{{
+                        "boot-source": {{
+                            "kernel_image_path": "{}",
+                            "boot_args": "console=ttyS0 reboot=k panic=1 pci=off"
+                        }},
+                        "drives": [
+                            {{
+                                "drive_id": "rootfs",
+                                "path_on_host": "{}",
+                                "is_root_device": true,
+                                "is_read_only": false
+                            }}
+                        ],
+                        "network-interfaces": [
+                            {{
+                                "iface_id": "netif",
+                                "host_dev_name": "{}"
+                            }}
+                        ],
+                        "mmds-config": {{
+                            "network_interfaces": ["netif"],
+                            "ipv6_address": "{}"
+                        }}
+                }}"#,
+                kernel_file.as_path().to_str().unwrap(),
+                rootfs_file.as_path().to_str().unwrap(),
+                host_dev_name,
+                ipv6_address,
+            );
+            let result = VmResources::from_json(
+                json.as_str(),
+                &default_instance_info,
+                HTTP_MAX_PAYLOAD_SIZE,
+                None,
+            );
+            if *valid {
+                let resources = result.unwrap();
+                {
+                    let net = resources.net_builder.iter().next().unwrap();
+                    assert_eq!(
+                        net.lock().unwrap().mmds_ns().unwrap().ipv6_addr(),
+                        Some(ipv6_address.parse().unwrap())
+                    );
+                }
+                assert_eq!(
+                    resources.mmds_config().unwrap().ipv6_address,
+                    Some(ipv6_address.parse().unwrap())
+                );
+            } else {
+                match result {
+                    Err(Error::MmdsConfig(MmdsConfigError::InvalidIpv6Addr)) => (),
+                    _ => unreachable!(),
+                }
+            }
+        }
+
+        // Test the access the guest has to the data store.
+        for (host_dev_name, guest_writable_path, valid) in
+            [("hostname12", "/guest/", true), ("hostname13", "/", false)].iter()
+        {
+            json = format!(
+                r#"{{
+                        "boot-source": {{
+                            "kernel_image_path": "{}",
+                            "boot_args": "console=ttyS0 reboot=k panic=1 pci=off"
+                        }},
+                        "drives": [
+                            {{
+                                "drive_id": "rootfs",
+                                "path_on_host": "{}",
+                                "is_root_device": true,
+                                "is_read_only": false
+                            }}
+                        ],
+                        "network-interfaces": [
+                            {{
+                                "iface_id": "netif",
+                                "host_dev_name": "{}"
+                            }}
+                        ],
+                        "mmds-config": {{
+                            "network_interfaces": ["netif"],
+                            "guest_writable_path": "{}",
+                            "acl": [
+                                {{
+                                    "path": "/secrets",
+                                    "access": "token_required"
+                                }}
+                            ]
+                        }}
+                }}"#,
+                kernel_file.as_path().to_str().unwrap(),
+                rootfs_file.as_path().to_str().unwrap(),
+                host_dev_name,
+                guest_writable_path,
+            );
+            let result = VmResources::from_json(
+                json.as_str(),
+                &default_instance_info,
+                HTTP_MAX_PAYLOAD_SIZE,
+                None,
+            );
+            if *valid {
+                let resources = result.unwrap();
+                let mmds_config = resources.mmds_config().unwrap();
+                assert_eq!(mmds_config.version, MmdsVersion::V1);
+                assert_eq!(mmds_config.guest_writable_path.unwrap(), "/guest");
+                assert_eq!(
+                    mmds_config.acl,
+                    vec![MmdsAclRule {
+                        path: "/secrets".to_string(),
+                        access: MmdsAccess::TokenRequired,
+                    }]
+                );
+                assert!(resources.mmds.unwrap().lock().unwrap().uses_tokens());
+            } else {
+                match result {
+                    Err(Error::MmdsConfig(MmdsConfigError::GuestAccess(_))) => (),
+                    _ => unreachable!(),
+                }
+            }
+        }
     }
 
     #[test]
@@ -1271,6 +1595,10 @@ mod tests {
             smt: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
             track_dirty_pages: Some(false),
+            backing: Some(MemoryBacking::Hugetlbfs2M),
+            hotplug_size_mib: Some(0),
+            hotplug_requested_mib: Some(0),
+            max_vcpu_count: Some(0),
         };
 
         assert_ne!(
@@ -1296,6 +1624,31 @@ mod tests {
         );
         aux_vm_config.vcpu_count = Some(32);
 
+        // Invalid max vcpu count.
+        aux_vm_config.vcpu_count = Some(4);
+        aux_vm_config.max_vcpu_count = Some(2);
+        assert_eq!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidMaxVcpuCount)
+        );
+        aux_vm_config.max_vcpu_count = Some(34);
+        assert_eq!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidMaxVcpuCount)
+        );
+        // Odd vcpu counts are not allowed with SMT.
+        aux_vm_config.max_vcpu_count = Some(7);
+        assert_eq!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidMaxVcpuCount)
+        );
+        aux_vm_config.max_vcpu_count = Some(8);
+        vm_resources.update_vm_config(&aux_vm_config).unwrap();
+        assert_eq!(vm_resources.vm_config.max_vcpu_count, 8);
+        assert_eq!(vm_resources.vcpu_config().vcpu_count, 8);
+        aux_vm_config.vcpu_count = Some(32);
+        aux_vm_config.max_vcpu_count = Some(0);
+
         // Invalid mem_size_mib.
         aux_vm_config.mem_size_mib = Some(0);
         assert_eq!(
@@ -1310,6 +1663,8 @@ mod tests {
                 amount_mib: 100,
                 deflate_on_oom: false,
                 stats_polling_interval_s: 0,
+                free_page_reporting: false,
+                auto_adjust: None,
             })
             .unwrap();
         aux_vm_config.mem_size_mib = Some(90);
@@ -1321,6 +1676,81 @@ mod tests {
         // mem_size_mib compatible with balloon size.
         aux_vm_config.mem_size_mib = Some(256);
         assert!(vm_resources.update_vm_config(&aux_vm_config).is_ok());
+
+        // mem_size_mib incompatible with the huge page size.
+        aux_vm_config.mem_size_mib = Some(257);
+        assert_eq!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::IncompatibleMemoryBacking(
+                MemoryBacking::Hugetlbfs2M
+            ))
+        );
+        aux_vm_config.mem_size_mib = Some(256);
+        aux_vm_config.backing = Some(MemoryBacking::Hugetlbfs1G);
+        assert_eq!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::IncompatibleMemoryBacking(
+                MemoryBacking::Hugetlbfs1G
+            ))
+        );
+        assert_eq!(vm_resources.vm_config.backing, MemoryBacking::Hugetlbfs2M);
+        aux_vm_config.mem_size_mib = Some(1024);
+        vm_resources.update_vm_config(&aux_vm_config).unwrap();
+        assert_eq!(vm_resources.vm_config.backing, MemoryBacking::Hugetlbfs1G);
+        assert_eq!(vm_resources.memory_backing(), MemoryBacking::Hugetlbfs1G);
+
+        // The hot-pluggable memory is sized in whole blocks.
+        aux_vm_config.hotplug_size_mib = Some(1536);
+        assert_eq!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidHotplugSize)
+        );
+        aux_vm_config.backing = Some(MemoryBacking::Hugetlbfs2M);
+        vm_resources.update_vm_config(&aux_vm_config).unwrap();
+        assert_eq!(vm_resources.vm_config.hotplug_size_mib, 1536);
+
+        // The requested size can't exceed the hot-pluggable memory.
+        aux_vm_config.hotplug_requested_mib = Some(2048);
+        assert_eq!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidHotplugRequestedSize)
+        );
+        aux_vm_config.hotplug_requested_mib = Some(513);
+        assert_eq!(
+            vm_resources.update_vm_config(&aux_vm_config),
+            Err(VmConfigError::InvalidHotplugRequestedSize)
+        );
+        aux_vm_config.hotplug_requested_mib = Some(512);
+        vm_resources.update_vm_config(&aux_vm_config).unwrap();
+        assert_eq!(vm_resources.vm_config.hotplug_requested_mib, 512);
+    }
+
+    #[test]
+    fn test_update_vcpu_count() {
+        let mut vm_resources = default_vm_resources();
+        vm_resources.vm_config.vcpu_count = 2;
+
+        // Without hot-pluggable vCPUs, the vCPU count can't change.
+        vm_resources.update_vcpu_count(2).unwrap();
+        assert_eq!(
+            vm_resources.update_vcpu_count(1),
+            Err(VmConfigError::InvalidVcpuCount)
+        );
+
+        vm_resources.vm_config.max_vcpu_count = 8;
+        vm_resources.update_vcpu_count(1).unwrap();
+        vm_resources.update_vcpu_count(8).unwrap();
+        assert_eq!(vm_resources.vm_config.vcpu_count, 8);
+        assert_eq!(vm_resources.vcpu_config().vcpu_count, 8);
+        assert_eq!(
+            vm_resources.update_vcpu_count(9),
+            Err(VmConfigError::InvalidVcpuCount)
+        );
+        assert_eq!(
+            vm_resources.update_vcpu_count(0),
+            Err(VmConfigError::InvalidVcpuCount)
+        );
+        assert_eq!(vm_resources.vm_config.vcpu_count, 8);
     }
 
     #[test]
@@ -1331,6 +1761,8 @@ mod tests {
             amount_mib: 100,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            free_page_reporting: false,
+            auto_adjust: None,
         };
         assert!(vm_resources.balloon.get().is_none());
         vm_resources
@@ -1351,7 +1783,46 @@ mod tests {
         let mut vm_resources = default_vm_resources();
         vm_resources.balloon = BalloonBuilder::new();
         new_balloon_cfg.amount_mib = 256;
-        assert!(vm_resources.set_balloon_device(new_balloon_cfg).is_err());
+        assert!(vm_resources
+            .set_balloon_device(new_balloon_cfg.clone())
+            .is_err());
+
+        // The balloon controller is validated as well.
+        new_balloon_cfg.amount_mib = 0;
+        new_balloon_cfg.stats_polling_interval_s = 1;
+        let auto_adjust = BalloonAutoAdjustConfig {
+            target_free_memory_percent: 10,
+            min_amount_mib: 0,
+            max_amount_mib: 100,
+            step_mib: 4,
+        };
+        new_balloon_cfg.auto_adjust = Some(auto_adjust);
+        vm_resources
+            .set_balloon_device(new_balloon_cfg.clone())
+            .unwrap();
+        assert_eq!(vm_resources.balloon.auto_adjust(), Some(auto_adjust));
+
+        new_balloon_cfg.auto_adjust = Some(BalloonAutoAdjustConfig {
+            max_amount_mib: 256,
+            ..auto_adjust
+        });
+        assert!(matches!(
+            vm_resources.set_balloon_device(new_balloon_cfg.clone()),
+            Err(BalloonConfigError::TooManyPagesRequested)
+        ));
+        // Unless the guest can plug that much memory.
+        vm_resources.vm_config.hotplug_size_mib = 128;
+        vm_resources
+            .set_balloon_device(new_balloon_cfg.clone())
+            .unwrap();
+        vm_resources.vm_config.hotplug_size_mib = 0;
+
+        new_balloon_cfg.stats_polling_interval_s = 0;
+        new_balloon_cfg.auto_adjust = Some(auto_adjust);
+        assert!(matches!(
+            vm_resources.set_balloon_device(new_balloon_cfg),
+            Err(BalloonConfigError::InvalidAutoAdjustConfig(_))
+        ));
     }
 
     #[test]
@@ -1445,6 +1916,70 @@ mod tests {
         assert_eq!(vm_resources.net_builder.len(), 2);
     }
 
+    #[test]
+    fn test_set_vhost_user_devices() {
+        let mut vm_resources = default_vm_resources();
+        let block_id = vm_resources.block.list[0].lock().unwrap().id().clone();
+        let net_id = default_net_cfg().iface_id;
+
+        // The vhost-user devices can't take the IDs of the other block and net devices.
+        let backend = default_block_backend();
+        let block_cfg = VhostUserBlockConfig {
+            drive_id: block_id.clone(),
+            socket: String::from(backend.socket_path()),
+            num_queues: 1,
+            queue_size: 256,
+        };
+        assert!(matches!(
+            vm_resources.set_vhost_user_block_device(block_cfg.clone()),
+            Err(VhostUserConfigError::DeviceIdInUse(_))
+        ));
+        let backend = default_net_backend();
+        let net_cfg = VhostUserNetConfig {
+            iface_id: net_id.clone(),
+            socket: String::from(backend.socket_path()),
+            guest_mac: None,
+            queue_size: 256,
+        };
+        assert!(matches!(
+            vm_resources.set_vhost_user_net_device(net_cfg.clone()),
+            Err(VhostUserConfigError::DeviceIdInUse(_))
+        ));
+        assert!(vm_resources.vhost_user.is_empty());
+        assert_eq!(vm_resources.memory_backing(), MemoryBacking::Anonymous);
+
+        vm_resources.block = BlockBuilder::new();
+        vm_resources.net_builder = NetBuilder::new();
+        vm_resources
+            .set_vhost_user_block_device(block_cfg.clone())
+            .unwrap();
+        vm_resources
+            .set_vhost_user_net_device(net_cfg.clone())
+            .unwrap();
+        assert_eq!(vm_resources.vhost_user.block_configs(), vec![block_cfg]);
+        assert_eq!(vm_resources.vhost_user.net_configs(), vec![net_cfg]);
+        // The vhost-user backends need the guest memory to be shared.
+        assert_eq!(vm_resources.vm_config().backing, MemoryBacking::Anonymous);
+        assert_eq!(vm_resources.memory_backing(), MemoryBacking::Memfd);
+        assert_eq!(vm_resources.machine_config().backing, MemoryBacking::Memfd);
+
+        // Nor the other way around.
+        let (mut block_cfg, _file) = default_block_cfg();
+        block_cfg.drive_id = block_id;
+        assert!(matches!(
+            vm_resources.set_block_device(block_cfg),
+            Err(DriveError::DriveIdInUse(_))
+        ));
+        assert!(matches!(
+            vm_resources.build_net_device(default_net_cfg()),
+            Err(NetworkInterfaceError::IfaceIdInUse(_))
+        ));
+
+        let vmm_config = VmmConfig::from(&vm_resources);
+        assert_eq!(vmm_config.vhost_user_block_devices.len(), 1);
+        assert_eq!(vmm_config.vhost_user_net_devices.len(), 1);
+    }
+
     #[test]
     fn test_error_display() {
         assert_eq!(
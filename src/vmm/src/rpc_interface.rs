@@ -11,17 +11,20 @@ use seccompiler::BpfThreadMap;
 use serde_json::Value;
 #[cfg(test)]
 use tests::{
-    build_microvm_for_boot, create_snapshot, restore_from_snapshot, MockVmRes as VmResources,
+    build_microvm_for_boot, create_snapshot, finish_migration, next_round, receive_migration,
+    restore_from_snapshot, start_migration, MockPreCopy as PreCopy, MockVmRes as VmResources,
     MockVmm as Vmm,
 };
 
 use super::Error as VmmError;
 #[cfg(not(test))]
 use super::{
-    builder::build_microvm_for_boot, persist::create_snapshot, persist::restore_from_snapshot,
-    resources::VmResources, Vmm,
+    builder::build_microvm_for_boot, migration::finish_migration, migration::next_round,
+    migration::receive_migration, migration::start_migration, migration::PreCopy,
+    persist::create_snapshot, persist::restore_from_snapshot, resources::VmResources, Vmm,
 };
 use crate::builder::StartMicrovmError;
+use crate::migration::MigrationError;
 use crate::persist::{CreateSnapshotError, LoadSnapshotError};
 use crate::resources::VmmConfig;
 use crate::version_map::VERSION_MAP;
@@ -35,11 +38,15 @@ use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::logger::{LoggerConfig, LoggerConfigError};
 use crate::vmm_config::machine_config::{VmConfig, VmConfigError, VmUpdateConfig};
 use crate::vmm_config::metrics::{MetricsConfig, MetricsConfigError};
+use crate::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
 use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::{
     NetworkInterfaceConfig, NetworkInterfaceError, NetworkInterfaceUpdateConfig,
 };
 use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
+use crate::vmm_config::vhost_user::{
+    VhostUserBlockConfig, VhostUserConfigError, VhostUserNetConfig,
+};
 use crate::vmm_config::vsock::{VsockConfigError, VsockDeviceConfig};
 use crate::vmm_config::{self, RateLimiterUpdate};
 use crate::{EventManager, FcExitCode};
@@ -77,7 +84,7 @@ pub enum VmmAction {
     /// Flush the metrics. This action can only be called after the logger has been configured.
     FlushMetrics,
     /// Add a new block device or update one that already exists using the `BlockDeviceConfig` as
-    /// input. This action can only be called before the microVM has booted.
+    /// input. After the microVM has booted, this action hot-plugs a new non-root block device.
     InsertBlockDevice(BlockDeviceConfig),
     /// Add a new network interface config or update one that already exists using the
     /// `NetworkInterfaceConfig` as input. This action can only be called before the microVM has
@@ -93,14 +100,34 @@ pub enum VmmAction {
     Pause,
     /// Repopulate the MMDS contents.
     PutMMDS(Value),
+    /// Wait for a microVM to be migrated from another Firecracker process, using as input the
+    /// `ReceiveMigrationParams`. This action can only be called before the microVM has booted.
+    /// If this action is successful, the received microVM will be in `Paused` state, unless
+    /// it is resumed right away.
+    ReceiveMigration(ReceiveMigrationParams),
+    /// Hot-unplug the block device with the given id. This action can only be called after the
+    /// microVM has booted, for devices that were hot-plugged.
+    RemoveBlockDevice(String),
     /// Resume the guest, by resuming the microVM VCPUs.
     Resume,
+    /// Live migrate the microVM to another Firecracker process, using as input the
+    /// `SendMigrationParams`. This action can only be called after the microVM has booted. If
+    /// this action is successful, the microVM is left in `Paused` state.
+    SendMigration(SendMigrationParams),
     /// Set the balloon device or update the one that already exists using the
     /// `BalloonDeviceConfig` as input. This action can only be called before the microVM
     /// has booted.
     SetBalloonDevice(BalloonDeviceConfig),
     /// Set the MMDS configuration.
     SetMmdsConfiguration(MmdsConfig),
+    /// Add a block device served by a vhost-user backend, or update the one with the same ID,
+    /// using the `VhostUserBlockConfig` as input. This action can only be called before the
+    /// microVM has booted.
+    SetVhostUserBlockDevice(VhostUserBlockConfig),
+    /// Add a network interface served by a vhost-user backend, or update the one with the same
+    /// ID, using the `VhostUserNetConfig` as input. This action can only be called before the
+    /// microVM has booted.
+    SetVhostUserNetDevice(VhostUserNetConfig),
     /// Set the vsock device or update the one that already exists using the
     /// `VsockDeviceConfig` as input. This action can only be called before the microVM has
     /// booted.
@@ -120,8 +147,8 @@ pub enum VmmAction {
     /// Update a network interface, after microVM start. Currently, the only updatable properties
     /// are the RX and TX rate limiters.
     UpdateNetworkInterface(NetworkInterfaceUpdateConfig),
-    /// Update the microVM configuration (memory & vcpu) using `VmUpdateConfig` as input. This
-    /// action can only be called before the microVM has booted.
+    /// Update the microVM configuration (memory & vcpu) using `VmUpdateConfig` as input. After
+    /// the microVM has booted, only the requested size of hot-pluggable memory can be updated.
     UpdateVmConfiguration(VmUpdateConfig),
 }
 
@@ -150,6 +177,8 @@ pub enum VmmActionError {
     MachineConfig(VmConfigError),
     /// The action `ConfigureMetrics` failed because of bad user input.
     Metrics(MetricsConfigError),
+    /// One of the actions `SendMigration` or `ReceiveMigration` failed.
+    Migration(MigrationError),
     /// One of the `GetMmds`, `PutMmds` or `PatchMmds` actions failed.
     #[from(ignore)]
     Mmds(data_store::Error),
@@ -166,8 +195,12 @@ pub enum VmmActionError {
     OperationNotSupportedPostBoot,
     /// The requested operation is not supported before starting the microVM.
     OperationNotSupportedPreBoot,
+    /// Receiving a migrated microVM not allowed after configuring boot-specific resources.
+    ReceiveMigrationNotAllowed,
     /// The action `StartMicroVm` failed because of an internal error.
     StartMicrovm(StartMicrovmError),
+    /// One of the actions `SetVhostUserBlockDevice` or `SetVhostUserNetDevice` failed.
+    VhostUserConfig(VhostUserConfigError),
     /// The action `SetVsockDevice` failed because of bad user input.
     VsockConfig(VsockConfigError),
 }
@@ -194,6 +227,7 @@ impl Display for VmmActionError {
                 Logger(err) => err.to_string(),
                 MachineConfig(err) => err.to_string(),
                 Metrics(err) => err.to_string(),
+                Migration(err) => format!("Migration error: {}", err),
                 Mmds(err) => err.to_string(),
                 MmdsConfig(err) => err.to_string(),
                 MmdsLimitExceeded(err) => err.to_string(),
@@ -207,7 +241,13 @@ impl Display for VmmActionError {
                     "The requested operation is not supported before starting the microVM."
                         .to_string()
                 }
+                ReceiveMigrationNotAllowed => {
+                    "Receiving a migrated microVM not allowed after configuring boot-specific \
+                     resources."
+                        .to_string()
+                }
                 StartMicrovm(err) => err.to_string(),
+                VhostUserConfig(err) => err.to_string(),
                 // The action `SetVsockDevice` failed because of bad user input.
                 VsockConfig(err) => err.to_string(),
             }
@@ -410,7 +450,7 @@ impl<'a> PrebootApiController<'a> {
             }
             GetMMDS => self.get_mmds(),
             GetVmMachineConfig => Ok(VmmData::MachineConfiguration(
-                self.vm_resources.vm_config().clone(),
+                self.vm_resources.machine_config(),
             )),
             GetVmInstanceInfo => Ok(VmmData::InstanceInformation(self.instance_info.clone())),
             GetVmmVersion => Ok(VmmData::VmmVersion(self.instance_info.vmm_version.clone())),
@@ -419,9 +459,12 @@ impl<'a> PrebootApiController<'a> {
             LoadSnapshot(config) => self.load_snapshot(&config),
             PatchMMDS(value) => self.patch_mmds(value),
             PutMMDS(value) => self.put_mmds(value),
+            ReceiveMigration(config) => self.receive_migration(&config),
             SetBalloonDevice(config) => self.set_balloon_device(config),
             SetVsockDevice(config) => self.set_vsock_device(config),
             SetMmdsConfiguration(config) => self.set_mmds_config(config),
+            SetVhostUserBlockDevice(config) => self.set_vhost_user_block_device(config),
+            SetVhostUserNetDevice(config) => self.set_vhost_user_net_device(config),
             StartMicroVm => self.start_microvm(),
             UpdateVmConfiguration(config) => self.update_vm_config(config),
             // Operations not allowed pre-boot.
@@ -430,6 +473,8 @@ impl<'a> PrebootApiController<'a> {
             | Pause
             | Resume
             | GetBalloonStats
+            | RemoveBlockDevice(_)
+            | SendMigration(_)
             | UpdateBalloon(_)
             | UpdateBalloonStatistics(_)
             | UpdateBlockDevice(_)
@@ -503,6 +548,22 @@ impl<'a> PrebootApiController<'a> {
             .map_err(VmmActionError::VsockConfig)
     }
 
+    fn set_vhost_user_block_device(&mut self, cfg: VhostUserBlockConfig) -> ActionResult {
+        self.boot_path = true;
+        self.vm_resources
+            .set_vhost_user_block_device(cfg)
+            .map(|()| VmmData::Empty)
+            .map_err(VmmActionError::VhostUserConfig)
+    }
+
+    fn set_vhost_user_net_device(&mut self, cfg: VhostUserNetConfig) -> ActionResult {
+        self.boot_path = true;
+        self.vm_resources
+            .set_vhost_user_net_device(cfg)
+            .map(|()| VmmData::Empty)
+            .map_err(VmmActionError::VhostUserConfig)
+    }
+
     // On success, this command will end the pre-boot stage and this controller
     // will be replaced by a runtime controller.
     fn start_microvm(&mut self) -> ActionResult {
@@ -576,6 +637,44 @@ impl<'a> PrebootApiController<'a> {
 
         result
     }
+
+    // On success, this command will end the pre-boot stage and this controller
+    // will be replaced by a runtime controller.
+    fn receive_migration(&mut self, params: &ReceiveMigrationParams) -> ActionResult {
+        if self.boot_path {
+            let err = VmmActionError::ReceiveMigrationNotAllowed;
+            info!("{}", err);
+            return Err(err);
+        }
+
+        if params.enable_diff_snapshots {
+            self.vm_resources.set_track_dirty_pages(true);
+        }
+
+        receive_migration(
+            &self.instance_info,
+            &mut self.event_manager,
+            self.seccomp_filters,
+            params,
+            VERSION_MAP.clone(),
+            self.vm_resources,
+        )
+        .and_then(|vmm| {
+            if params.resume_vm {
+                vmm.lock()
+                    .expect("Poisoned lock")
+                    .resume_vm()
+                    .map_err(MigrationError::ResumeMicroVm)?;
+            }
+            self.built_vmm = Some(vmm);
+            Ok(VmmData::Empty)
+        })
+        .map_err(|err| {
+            // The process is too dirty to recover at this point.
+            self.fatal_error = Some(FcExitCode::BadConfiguration);
+            VmmActionError::Migration(err)
+        })
+    }
 }
 
 /// Enables RPC interaction with a running Firecracker VMM.
@@ -615,7 +714,7 @@ impl RuntimeApiController {
             GetFullVmConfig => Ok(VmmData::FullVmConfig((&self.vm_resources).into())),
             GetMMDS => self.get_mmds(),
             GetVmMachineConfig => Ok(VmmData::MachineConfiguration(
-                self.vm_resources.vm_config().clone(),
+                self.vm_resources.machine_config(),
             )),
             GetVmInstanceInfo => Ok(VmmData::InstanceInformation(
                 self.vmm.lock().expect("Poisoned lock").instance_info(),
@@ -623,19 +722,16 @@ impl RuntimeApiController {
             GetVmmVersion => Ok(VmmData::VmmVersion(
                 self.vmm.lock().expect("Poisoned lock").version(),
             )),
+            InsertBlockDevice(config) => self.hotplug_block_device(config),
             PatchMMDS(value) => self.patch_mmds(value),
             Pause => self.pause(),
             PutMMDS(value) => self.put_mmds(value),
+            RemoveBlockDevice(drive_id) => self.hotunplug_block_device(&drive_id),
             Resume => self.resume(),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => self.send_ctrl_alt_del(),
-            UpdateBalloon(balloon_update) => self
-                .vmm
-                .lock()
-                .expect("Poisoned lock")
-                .update_balloon_config(balloon_update.amount_mib)
-                .map(|_| VmmData::Empty)
-                .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
+            SendMigration(params) => self.send_migration(&params),
+            UpdateBalloon(balloon_update) => self.update_balloon_config(balloon_update.amount_mib),
             UpdateBalloonStatistics(balloon_stats_update) => self
                 .vmm
                 .lock()
@@ -644,20 +740,22 @@ impl RuntimeApiController {
                 .map(|_| VmmData::Empty)
                 .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
             UpdateBlockDevice(new_cfg) => self.update_block_device(new_cfg),
-            UpdateNetworkInterface(netif_update) => self.update_net_rate_limiters(netif_update),
+            UpdateNetworkInterface(netif_update) => self.update_net_device(netif_update),
+            UpdateVmConfiguration(config) => self.update_machine_config(config),
 
             // Operations not allowed post-boot.
             ConfigureBootSource(_)
             | ConfigureLogger(_)
             | ConfigureMetrics(_)
-            | InsertBlockDevice(_)
             | InsertNetworkDevice(_)
             | LoadSnapshot(_)
+            | ReceiveMigration(_)
             | SetBalloonDevice(_)
             | SetVsockDevice(_)
             | SetMmdsConfiguration(_)
-            | StartMicroVm
-            | UpdateVmConfiguration(_) => Err(VmmActionError::OperationNotSupportedPostBoot),
+            | SetVhostUserBlockDevice(_)
+            | SetVhostUserNetDevice(_)
+            | StartMicroVm => Err(VmmActionError::OperationNotSupportedPostBoot),
         }
     }
 
@@ -692,6 +790,22 @@ impl RuntimeApiController {
         Ok(VmmData::Empty)
     }
 
+    /// Updates the target size of the balloon, unless the balloon controller adjusts it.
+    fn update_balloon_config(&mut self, amount_mib: u32) -> ActionResult {
+        if self.vm_resources.balloon.auto_adjust().is_some() {
+            return Err(VmmActionError::BalloonConfig(
+                BalloonConfigError::AutoAdjustEnabled,
+            ));
+        }
+
+        self.vmm
+            .lock()
+            .expect("Poisoned lock")
+            .update_balloon_config(amount_mib)
+            .map(|_| VmmData::Empty)
+            .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err)))
+    }
+
     /// Write the metrics on user demand (flush). We use the word `flush` here to highlight the fact
     /// that the metrics will be written immediately.
     /// Defer to inner Vmm. We'll move to a variant where the Vmm simply exposes functionality like
@@ -759,9 +873,87 @@ impl RuntimeApiController {
         Ok(VmmData::Empty)
     }
 
+    /// Migrates the microVM, blocking the VMM thread until the pre-copy rounds are over. The
+    /// API server adapter drives them from the event loop instead, through `start_migration`.
+    fn send_migration(&mut self, params: &SendMigrationParams) -> ActionResult {
+        let mut pre_copy = self.start_migration(params)?;
+        while self.next_migration_round(&mut pre_copy)? {}
+        self.finish_migration(pre_copy)
+    }
+
+    /// Starts migrating the microVM to another Firecracker process. The guest memory is sent
+    /// by a dedicated thread while the devices keep being emulated; `next_migration_round` must
+    /// be called each time the round event of the returned `PreCopy` is signaled.
+    pub fn start_migration(
+        &mut self,
+        params: &SendMigrationParams,
+    ) -> result::Result<PreCopy, VmmActionError> {
+        if !self.vm_resources.track_dirty_pages() {
+            return Err(VmmActionError::NotSupported(
+                "Live migration is not allowed on uVMs with dirty page tracking disabled."
+                    .to_string(),
+            ));
+        }
+
+        start_migration(
+            &self.vmm.lock().expect("Poisoned lock"),
+            params,
+            VERSION_MAP.clone(),
+        )
+        .map_err(VmmActionError::Migration)
+    }
+
+    /// Handles the end of a pre-copy round of the migration. Returns `true` if another round
+    /// was started, or `false` once `finish_migration` should be called.
+    pub fn next_migration_round(
+        &mut self,
+        pre_copy: &mut PreCopy,
+    ) -> result::Result<bool, VmmActionError> {
+        next_round(&self.vmm.lock().expect("Poisoned lock"), pre_copy)
+            .map_err(VmmActionError::Migration)
+    }
+
+    /// Pauses the microVM and sends its last dirty pages and its state, completing the
+    /// migration.
+    pub fn finish_migration(&mut self, pre_copy: PreCopy) -> ActionResult {
+        finish_migration(
+            &mut self.vmm.lock().expect("Poisoned lock"),
+            pre_copy,
+            VERSION_MAP.clone(),
+        )
+        .map(|()| VmmData::Empty)
+        .map_err(VmmActionError::Migration)
+    }
+
+    /// Hot-plugs a new block device, as described in `cfg`, in the running microVM.
+    fn hotplug_block_device(&mut self, cfg: BlockDeviceConfig) -> ActionResult {
+        let block = Arc::new(Mutex::new(
+            self.vm_resources.block.create_hotplug_block(cfg)?,
+        ));
+        self.vmm
+            .lock()
+            .expect("Poisoned lock")
+            .hotplug_block_device(block.clone())
+            .map_err(DriveError::Hotplug)?;
+        self.vm_resources.block.add_device(block);
+        Ok(VmmData::Empty)
+    }
+
+    /// Hot-unplugs the block device with id `drive_id` from the running microVM.
+    fn hotunplug_block_device(&mut self, drive_id: &str) -> ActionResult {
+        self.vmm
+            .lock()
+            .expect("Poisoned lock")
+            .hotunplug_block_device(drive_id)
+            .map_err(DriveError::Hotunplug)?;
+        self.vm_resources.block.remove(drive_id);
+        Ok(VmmData::Empty)
+    }
+
     /// Updates block device properties:
     ///  - path of the host file backing the emulated block device, update the disk image on the
     ///    device and its virtio configuration
+    ///  - path of the copy-on-write overlay of the device
     ///  - rate limiter configuration.
     fn update_block_device(&mut self, new_cfg: BlockDeviceUpdateConfig) -> ActionResult {
         let mut vmm = self.vmm.lock().expect("Poisoned lock");
@@ -770,6 +962,11 @@ impl RuntimeApiController {
                 .map(|()| VmmData::Empty)
                 .map_err(DriveError::DeviceUpdate)?;
         }
+        if let Some(new_overlay_path) = new_cfg.cow_overlay_path {
+            vmm.update_block_cow_overlay(&new_cfg.drive_id, new_overlay_path)
+                .map(|()| VmmData::Empty)
+                .map_err(DriveError::DeviceUpdate)?;
+        }
         if new_cfg.rate_limiter.is_some() {
             vmm.update_block_rate_limiter(
                 &new_cfg.drive_id,
@@ -782,21 +979,84 @@ impl RuntimeApiController {
         Ok(VmmData::Empty)
     }
 
-    /// Updates configuration for an emulated net device as described in `new_cfg`.
-    fn update_net_rate_limiters(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
-        self.vmm
-            .lock()
-            .expect("Poisoned lock")
-            .update_net_rate_limiters(
-                &new_cfg.iface_id,
-                RateLimiterUpdate::from(new_cfg.rx_rate_limiter).bandwidth,
-                RateLimiterUpdate::from(new_cfg.rx_rate_limiter).ops,
-                RateLimiterUpdate::from(new_cfg.tx_rate_limiter).bandwidth,
-                RateLimiterUpdate::from(new_cfg.tx_rate_limiter).ops,
-            )
-            .map(|()| VmmData::Empty)
-            .map_err(NetworkInterfaceError::DeviceUpdate)
-            .map_err(VmmActionError::NetworkConfig)
+    /// Updates configuration for an emulated net device as described in `new_cfg`:
+    ///  - rate limiter configurations
+    ///  - rules of the TX filter.
+    fn update_net_device(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
+        let mut vmm = self.vmm.lock().expect("Poisoned lock");
+        vmm.update_net_rate_limiters(
+            &new_cfg.iface_id,
+            RateLimiterUpdate::from(new_cfg.rx_rate_limiter).bandwidth,
+            RateLimiterUpdate::from(new_cfg.rx_rate_limiter).ops,
+            RateLimiterUpdate::from(new_cfg.tx_rate_limiter).bandwidth,
+            RateLimiterUpdate::from(new_cfg.tx_rate_limiter).ops,
+        )
+        .map_err(NetworkInterfaceError::DeviceUpdate)?;
+        if let Some(tx_filter) = new_cfg.tx_filter {
+            vmm.update_net_tx_filter(&new_cfg.iface_id, tx_filter)
+                .map_err(NetworkInterfaceError::DeviceUpdate)?;
+        }
+        Ok(VmmData::Empty)
+    }
+
+    /// Requests the guest to plug or unplug vCPUs or memory. The number of plugged vCPUs and the
+    /// requested size of hot-pluggable memory are the only parts of the machine configuration
+    /// which can be updated after boot.
+    fn update_machine_config(&mut self, cfg: VmUpdateConfig) -> ActionResult {
+        let other_fields = VmUpdateConfig {
+            vcpu_count: None,
+            hotplug_requested_mib: None,
+            ..cfg.clone()
+        };
+        if !other_fields.is_empty() || cfg.is_empty() {
+            return Err(VmmActionError::OperationNotSupportedPostBoot);
+        }
+
+        // Both updates are validated before any reaches the guest.
+        let vm_config = self.vm_resources.vm_config().clone();
+        if let Some(vcpu_count) = cfg.vcpu_count {
+            self.vm_resources
+                .update_vcpu_count(vcpu_count)
+                .map_err(VmmActionError::MachineConfig)?;
+        }
+        if cfg.hotplug_requested_mib.is_some() {
+            let memory_update = VmUpdateConfig {
+                vcpu_count: None,
+                ..cfg.clone()
+            };
+            if let Err(err) = self.vm_resources.update_vm_config(&memory_update) {
+                self.vm_resources
+                    .update_vcpu_count(vm_config.vcpu_count)
+                    .map_err(VmmActionError::MachineConfig)?;
+                return Err(VmmActionError::MachineConfig(err));
+            }
+        }
+        if let Some(requested_mib) = cfg.hotplug_requested_mib {
+            // Without hot-pluggable memory, only a null size can be requested, which is a no-op.
+            if vm_config.hotplug_size_mib > 0 {
+                self.vmm
+                    .lock()
+                    .expect("Poisoned lock")
+                    .update_hotplug_memory(requested_mib)?;
+            }
+        }
+        if let Some(vcpu_count) = cfg.vcpu_count {
+            if vm_config.max_vcpu_count > 0 {
+                let result = self
+                    .vmm
+                    .lock()
+                    .expect("Poisoned lock")
+                    .update_vcpu_count(vcpu_count);
+                if let Err(err) = result {
+                    // The vCPUs plugged are still the ones before the update.
+                    self.vm_resources
+                        .update_vcpu_count(vm_config.vcpu_count)
+                        .map_err(VmmActionError::MachineConfig)?;
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok(VmmData::Empty)
     }
 }
 
@@ -805,15 +1065,17 @@ mod tests {
     use std::path::PathBuf;
 
     use devices::virtio::balloon::{BalloonConfig, Error as BalloonError};
+    use devices::virtio::net::{NetBackend, TxFilter};
     use devices::virtio::VsockError;
     use mmds::data_store::MmdsVersion;
     use seccompiler::BpfThreadMap;
 
     use super::*;
-    use crate::vmm_config::balloon::BalloonBuilder;
-    use crate::vmm_config::drive::{CacheType, FileEngineType};
+    use crate::vmm_config::balloon::{BalloonAutoAdjustConfig, BalloonBuilder};
+    use crate::vmm_config::drive::{BlockBuilder, CacheType, FileEngineType, ImageFormat};
     use crate::vmm_config::logger::LoggerLevel;
-    use crate::vmm_config::snapshot::{MemBackendConfig, MemBackendType};
+    use crate::vmm_config::migration::{MigrationSocketConfig, MigrationTransport};
+    use crate::vmm_config::snapshot::{MemBackendConfig, MemBackendType, MemFileFormat};
     use crate::vmm_config::vsock::VsockBuilder;
     use crate::HTTP_MAX_PAYLOAD_SIZE;
 
@@ -832,6 +1094,7 @@ mod tests {
                     | (Logger(_), Logger(_))
                     | (MachineConfig(_), MachineConfig(_))
                     | (Metrics(_), Metrics(_))
+                    | (Migration(_), Migration(_))
                     | (Mmds(_), Mmds(_))
                     | (MmdsLimitExceeded(_), MmdsLimitExceeded(_))
                     | (MmdsConfig(_), MmdsConfig(_))
@@ -839,7 +1102,9 @@ mod tests {
                     | (NotSupported(_), NotSupported(_))
                     | (OperationNotSupportedPostBoot, OperationNotSupportedPostBoot)
                     | (OperationNotSupportedPreBoot, OperationNotSupportedPreBoot)
+                    | (ReceiveMigrationNotAllowed, ReceiveMigrationNotAllowed)
                     | (StartMicrovm(_), StartMicrovm(_))
+                    | (VhostUserConfig(_), VhostUserConfig(_))
                     | (VsockConfig(_), VsockConfig(_))
             )
         }
@@ -850,6 +1115,7 @@ mod tests {
     pub struct MockVmRes {
         vm_config: VmConfig,
         pub balloon: BalloonBuilder,
+        pub block: BlockBuilder,
         pub vsock: VsockBuilder,
         balloon_config_called: bool,
         balloon_set: bool,
@@ -857,6 +1123,7 @@ mod tests {
         block_set: bool,
         vsock_set: bool,
         net_set: bool,
+        vhost_user_set: bool,
         pub mmds: Option<Arc<Mutex<Mmds>>>,
         pub mmds_size_limit: usize,
         pub boot_timer: bool,
@@ -869,6 +1136,10 @@ mod tests {
             &self.vm_config
         }
 
+        pub fn machine_config(&self) -> VmConfig {
+            self.vm_config.clone()
+        }
+
         pub fn balloon_config(&mut self) -> Result<BalloonConfig, BalloonError> {
             if self.force_errors {
                 return Err(BalloonError::DeviceNotFound);
@@ -893,12 +1164,41 @@ mod tests {
                 return Err(VmConfigError::InvalidVcpuCount);
             }
 
-            self.vm_config.vcpu_count = machine_config.vcpu_count.unwrap();
-            self.vm_config.mem_size_mib = machine_config.mem_size_mib.unwrap();
-            self.vm_config.smt = machine_config.smt.unwrap();
-            self.vm_config.cpu_template = machine_config.cpu_template.unwrap();
-            self.vm_config.track_dirty_pages = machine_config.track_dirty_pages.unwrap();
+            let vm_config = &mut self.vm_config;
+            vm_config.vcpu_count = machine_config.vcpu_count.unwrap_or(vm_config.vcpu_count);
+            vm_config.mem_size_mib = machine_config
+                .mem_size_mib
+                .unwrap_or(vm_config.mem_size_mib);
+            vm_config.smt = machine_config.smt.unwrap_or(vm_config.smt);
+            vm_config.cpu_template = machine_config
+                .cpu_template
+                .unwrap_or(vm_config.cpu_template);
+            vm_config.track_dirty_pages = machine_config
+                .track_dirty_pages
+                .unwrap_or(vm_config.track_dirty_pages);
+            vm_config.backing = machine_config.backing.unwrap_or(vm_config.backing);
+            vm_config.hotplug_size_mib = machine_config
+                .hotplug_size_mib
+                .unwrap_or(vm_config.hotplug_size_mib);
+            vm_config.hotplug_requested_mib = machine_config
+                .hotplug_requested_mib
+                .unwrap_or(vm_config.hotplug_requested_mib);
+            vm_config.max_vcpu_count = machine_config
+                .max_vcpu_count
+                .unwrap_or(vm_config.max_vcpu_count);
+
+            Ok(())
+        }
 
+        pub fn update_vcpu_count(&mut self, vcpu_count: u8) -> Result<(), VmConfigError> {
+            let vm_config = &mut self.vm_config;
+            if self.force_errors
+                || vcpu_count > vm_config.max_vcpus()
+                || (vm_config.max_vcpu_count == 0 && vcpu_count != vm_config.vcpu_count)
+            {
+                return Err(VmConfigError::InvalidVcpuCount);
+            }
+            vm_config.vcpu_count = vcpu_count;
             Ok(())
         }
 
@@ -955,6 +1255,28 @@ mod tests {
             Ok(())
         }
 
+        pub fn set_vhost_user_block_device(
+            &mut self,
+            cfg: VhostUserBlockConfig,
+        ) -> Result<(), VhostUserConfigError> {
+            if self.force_errors {
+                return Err(VhostUserConfigError::DeviceIdInUse(cfg.drive_id));
+            }
+            self.vhost_user_set = true;
+            Ok(())
+        }
+
+        pub fn set_vhost_user_net_device(
+            &mut self,
+            cfg: VhostUserNetConfig,
+        ) -> Result<(), VhostUserConfigError> {
+            if self.force_errors {
+                return Err(VhostUserConfigError::DeviceIdInUse(cfg.iface_id));
+            }
+            self.vhost_user_set = true;
+            Ok(())
+        }
+
         pub fn set_mmds_config(
             &mut self,
             mmds_config: MmdsConfig,
@@ -1003,7 +1325,13 @@ mod tests {
         pub update_balloon_config_called: bool,
         pub update_balloon_stats_config_called: bool,
         pub update_block_device_path_called: bool,
+        pub update_block_cow_overlay_called: bool,
         pub update_net_rate_limiters_called: bool,
+        pub update_net_tx_filter_called: bool,
+        pub hotplug_block_device_called: bool,
+        pub hotunplug_block_device_called: bool,
+        pub update_hotplug_memory_called: bool,
+        pub update_vcpu_count_called: bool,
         // when `true`, all self methods are forced to fail
         pub force_errors: bool,
     }
@@ -1060,6 +1388,26 @@ mod tests {
             Ok(())
         }
 
+        pub fn update_hotplug_memory(&mut self, _: usize) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::DeviceNotFound,
+                ));
+            }
+            self.update_hotplug_memory_called = true;
+            Ok(())
+        }
+
+        pub fn update_vcpu_count(&mut self, _: u8) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::DeviceNotFound,
+                ));
+            }
+            self.update_vcpu_count_called = true;
+            Ok(())
+        }
+
         pub fn update_balloon_stats_config(&mut self, _: u16) -> Result<(), BalloonError> {
             if self.force_errors {
                 return Err(BalloonError::DeviceNotFound);
@@ -1078,6 +1426,39 @@ mod tests {
             Ok(())
         }
 
+        pub fn update_block_cow_overlay(&mut self, _: &str, _: String) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.update_block_cow_overlay_called = true;
+            Ok(())
+        }
+
+        pub fn hotplug_block_device(
+            &mut self,
+            _: Arc<Mutex<devices::virtio::Block>>,
+        ) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::NoFreeHotplugSlot,
+                ));
+            }
+            self.hotplug_block_device_called = true;
+            Ok(())
+        }
+
+        pub fn hotunplug_block_device(&mut self, _: &str) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::DeviceNotHotplugged,
+                ));
+            }
+            self.hotunplug_block_device_called = true;
+            Ok(())
+        }
+
         pub fn update_block_rate_limiter(
             &mut self,
             _: &str,
@@ -1104,6 +1485,16 @@ mod tests {
             Ok(())
         }
 
+        pub fn update_net_tx_filter(&mut self, _: &str, _: TxFilter) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.update_net_tx_filter_called = true;
+            Ok(())
+        }
+
         pub fn instance_info(&self) -> InstanceInfo {
             InstanceInfo::default()
         }
@@ -1147,6 +1538,43 @@ mod tests {
         Ok(Arc::new(Mutex::new(MockVmm::default())))
     }
 
+    pub struct MockPreCopy;
+
+    // Need to redefine these since the non-test ones use real Vmm
+    // instead of our mocks.
+    pub fn start_migration(
+        _: &Vmm,
+        _: &SendMigrationParams,
+        _: versionize::VersionMap,
+    ) -> std::result::Result<MockPreCopy, MigrationError> {
+        Ok(MockPreCopy)
+    }
+
+    pub fn next_round(_: &Vmm, _: &mut MockPreCopy) -> std::result::Result<bool, MigrationError> {
+        Ok(false)
+    }
+
+    pub fn finish_migration(
+        _: &mut Vmm,
+        _: MockPreCopy,
+        _: versionize::VersionMap,
+    ) -> std::result::Result<(), MigrationError> {
+        Ok(())
+    }
+
+    // Need to redefine this since the non-test one uses real Vmm
+    // instead of our mocks.
+    pub fn receive_migration(
+        _: &InstanceInfo,
+        _: &mut EventManager,
+        _: &BpfThreadMap,
+        _: &ReceiveMigrationParams,
+        _: versionize::VersionMap,
+        _: &mut MockVmRes,
+    ) -> Result<Arc<Mutex<Vmm>>, MigrationError> {
+        Ok(Arc::new(Mutex::new(MockVmm::default())))
+    }
+
     fn default_preboot<'a>(
         vm_resources: &'a mut VmResources,
         event_manager: &'a mut EventManager,
@@ -1285,6 +1713,9 @@ mod tests {
             drive_id: String::new(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1300,6 +1731,9 @@ mod tests {
             drive_id: String::new(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         });
         check_preboot_request_err(
             req,
@@ -1315,6 +1749,11 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            backend: NetBackend::Userspace,
+            tx_filter: None,
+            dhcp: None,
+            user_network: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1327,6 +1766,11 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            backend: NetBackend::Userspace,
+            tx_filter: None,
+            dhcp: None,
+            user_network: None,
         });
         check_preboot_request_err(
             req,
@@ -1342,6 +1786,10 @@ mod tests {
             vsock_id: Some(String::new()),
             guest_cid: 0,
             uds_path: String::new(),
+            forwarding: None,
+            port_filter: None,
+            dgram: None,
+            preserve_connections: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1352,6 +1800,10 @@ mod tests {
             vsock_id: Some(String::new()),
             guest_cid: 0,
             uds_path: String::new(),
+            forwarding: None,
+            port_filter: None,
+            dgram: None,
+            preserve_connections: None,
         });
         check_preboot_request_err(
             req,
@@ -1361,10 +1813,51 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_preboot_set_vhost_user_devices() {
+        let block_config = VhostUserBlockConfig {
+            drive_id: String::from("vhu_blk"),
+            socket: String::new(),
+            num_queues: 1,
+            queue_size: 256,
+        };
+        let net_config = VhostUserNetConfig {
+            iface_id: String::from("vhu_net"),
+            socket: String::new(),
+            guest_mac: None,
+            queue_size: 256,
+        };
+
+        let req = VmmAction::SetVhostUserBlockDevice(block_config.clone());
+        check_preboot_request(req, |result, vm_res| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vm_res.vhost_user_set)
+        });
+        let req = VmmAction::SetVhostUserNetDevice(net_config.clone());
+        check_preboot_request(req, |result, vm_res| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vm_res.vhost_user_set)
+        });
+
+        let req = VmmAction::SetVhostUserBlockDevice(block_config);
+        check_preboot_request_err(
+            req,
+            VmmActionError::VhostUserConfig(VhostUserConfigError::DeviceIdInUse(String::new())),
+        );
+        let req = VmmAction::SetVhostUserNetDevice(net_config);
+        check_preboot_request_err(
+            req,
+            VmmActionError::VhostUserConfig(VhostUserConfigError::DeviceIdInUse(String::new())),
+        );
+    }
+
     #[test]
     fn test_preboot_set_mmds_config() {
         let req = VmmAction::SetMmdsConfiguration(MmdsConfig {
             ipv4_address: None,
+            ipv6_address: None,
+            guest_writable_path: None,
+            acl: Vec::new(),
             version: MmdsVersion::V2,
             network_interfaces: Vec::new(),
         });
@@ -1378,6 +1871,9 @@ mod tests {
 
         let req = VmmAction::SetMmdsConfiguration(MmdsConfig {
             ipv4_address: None,
+            ipv6_address: None,
+            guest_writable_path: None,
+            acl: Vec::new(),
             version: MmdsVersion::default(),
             network_interfaces: Vec::new(),
         });
@@ -1619,6 +2115,7 @@ mod tests {
                 backend_type: MemBackendType::File,
                 backend_path: PathBuf::new(),
             },
+            mem_diff_paths: Vec::new(),
             enable_diff_snapshots: false,
             resume_vm: false,
         });
@@ -1635,6 +2132,7 @@ mod tests {
                 backend_type: MemBackendType::File,
                 backend_path: PathBuf::new(),
             },
+            mem_diff_paths: Vec::new(),
             enable_diff_snapshots: false,
             resume_vm: true,
         });
@@ -1647,6 +2145,81 @@ mod tests {
         assert!(!vmm.pause_called);
     }
 
+    fn receive_migration_params(resume_vm: bool) -> ReceiveMigrationParams {
+        ReceiveMigrationParams {
+            source: MigrationSocketConfig {
+                transport: MigrationTransport::Unix,
+                address: String::new(),
+            },
+            enable_diff_snapshots: false,
+            resume_vm,
+        }
+    }
+
+    fn send_migration_params() -> SendMigrationParams {
+        SendMigrationParams {
+            destination: MigrationSocketConfig {
+                transport: MigrationTransport::Unix,
+                address: String::new(),
+            },
+            max_rounds: 1,
+            dirty_pages_threshold: 0,
+        }
+    }
+
+    #[test]
+    fn test_preboot_receive_migration() {
+        let mut vm_resources = MockVmRes::default();
+        let mut evmgr = EventManager::new().unwrap();
+        let seccomp_filters = BpfThreadMap::new();
+        let mut preboot = default_preboot(&mut vm_resources, &mut evmgr, &seccomp_filters);
+
+        // Without resume.
+        let req = VmmAction::ReceiveMigration(receive_migration_params(false));
+        preboot.handle_preboot_request(req).unwrap();
+        let vmm = preboot.built_vmm.take().unwrap();
+        assert_eq!(*vmm.lock().unwrap(), MockVmm::default());
+
+        // With resume.
+        let req = VmmAction::ReceiveMigration(receive_migration_params(true));
+        preboot.handle_preboot_request(req).unwrap();
+        let vmm = preboot.built_vmm.as_ref().unwrap().lock().unwrap();
+        assert!(vmm.resume_called);
+        assert!(!vmm.pause_called);
+    }
+
+    #[test]
+    fn test_preboot_receive_migration_disallowed_after_boot_resources() {
+        let mut vm_resources = MockVmRes::default();
+        let mut evmgr = EventManager::new().unwrap();
+        let seccomp_filters = BpfThreadMap::new();
+        let mut preboot = default_preboot(&mut vm_resources, &mut evmgr, &seccomp_filters);
+
+        let req = VmmAction::ConfigureBootSource(BootSourceConfig::default());
+        preboot.handle_preboot_request(req).unwrap();
+        let req = VmmAction::ReceiveMigration(receive_migration_params(false));
+        assert_eq!(
+            preboot.handle_preboot_request(req),
+            Err(VmmActionError::ReceiveMigrationNotAllowed)
+        );
+    }
+
+    #[test]
+    fn test_runtime_send_migration() {
+        // Dirty page tracking is needed to send the pages dirtied during the migration.
+        let req = VmmAction::SendMigration(send_migration_params());
+        check_runtime_request(req, |result, _| {
+            assert_eq!(result, Err(VmmActionError::NotSupported(String::new())));
+        });
+
+        let mut vm_resources = MockVmRes::default();
+        vm_resources.set_track_dirty_pages(true);
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(vm_resources, vmm);
+        let req = VmmAction::SendMigration(send_migration_params());
+        assert_eq!(runtime.handle_request(req), Ok(VmmData::Empty));
+    }
+
     #[test]
     fn test_preboot_disallowed() {
         check_preboot_request_err(
@@ -1665,6 +2238,10 @@ mod tests {
             VmmAction::GetBalloonStats,
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::SendMigration(send_migration_params()),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         check_preboot_request_err(
             VmmAction::UpdateBalloon(BalloonUpdateConfig { amount_mib: 0 }),
             VmmActionError::OperationNotSupportedPreBoot,
@@ -1679,11 +2256,16 @@ mod tests {
             VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig::default()),
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::RemoveBlockDevice(String::new()),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         check_preboot_request_err(
             VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
                 iface_id: String::new(),
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                tx_filter: None,
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
@@ -1692,6 +2274,7 @@ mod tests {
                 snapshot_type: SnapshotType::Full,
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
+                mem_file_format: MemFileFormat::Raw,
                 version: None,
             }),
             VmmActionError::OperationNotSupportedPreBoot,
@@ -1894,6 +2477,147 @@ mod tests {
             req,
             VmmActionError::BalloonConfig(BalloonConfigError::DeviceNotFound),
         );
+
+        // The target size is left to the balloon controller, when enabled.
+        let mut vm_res = MockVmRes::default();
+        vm_res
+            .balloon
+            .set(BalloonDeviceConfig {
+                stats_polling_interval_s: 1,
+                auto_adjust: Some(BalloonAutoAdjustConfig {
+                    target_free_memory_percent: 20,
+                    min_amount_mib: 0,
+                    max_amount_mib: 64,
+                    step_mib: 8,
+                }),
+                ..Default::default()
+            })
+            .unwrap();
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(vm_res, vmm.clone());
+        let req = VmmAction::UpdateBalloon(BalloonUpdateConfig { amount_mib: 0 });
+        assert!(matches!(
+            runtime.handle_request(req),
+            Err(VmmActionError::BalloonConfig(
+                BalloonConfigError::AutoAdjustEnabled
+            ))
+        ));
+        assert!(!vmm.lock().unwrap().update_balloon_config_called);
+    }
+
+    #[test]
+    fn test_runtime_update_hotplug_memory() {
+        let req = VmmAction::UpdateVmConfiguration(VmUpdateConfig {
+            hotplug_requested_mib: Some(512),
+            ..VmUpdateConfig::from(VmConfig::default())
+        });
+        check_runtime_request_err(req, VmmActionError::OperationNotSupportedPostBoot);
+
+        let hotplug_update = VmUpdateConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            smt: None,
+            cpu_template: None,
+            track_dirty_pages: None,
+            backing: None,
+            hotplug_size_mib: None,
+            hotplug_requested_mib: Some(512),
+            max_vcpu_count: None,
+        };
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let vm_res = MockVmRes {
+            vm_config: VmConfig {
+                hotplug_size_mib: 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut runtime = RuntimeApiController::new(vm_res, vmm.clone());
+        let res = runtime.handle_request(VmmAction::UpdateVmConfiguration(hotplug_update.clone()));
+        assert_eq!(res, Ok(VmmData::Empty));
+        assert!(vmm.lock().unwrap().update_hotplug_memory_called);
+        assert_eq!(runtime.vm_resources.vm_config().hotplug_requested_mib, 512);
+
+        // Without hot-pluggable memory, there is no virtio-mem device to update.
+        check_runtime_request(
+            VmmAction::UpdateVmConfiguration(hotplug_update),
+            |result, vmm| {
+                assert_eq!(result, Ok(VmmData::Empty));
+                assert!(!vmm.update_hotplug_memory_called);
+            },
+        );
+    }
+
+    #[test]
+    fn test_runtime_update_vcpu_count() {
+        let vcpu_update = VmUpdateConfig {
+            vcpu_count: Some(4),
+            mem_size_mib: None,
+            smt: None,
+            cpu_template: None,
+            track_dirty_pages: None,
+            backing: None,
+            hotplug_size_mib: None,
+            hotplug_requested_mib: None,
+            max_vcpu_count: None,
+        };
+        // Without hot-pluggable vCPUs, the vCPU count can't change.
+        check_runtime_request(
+            VmmAction::UpdateVmConfiguration(vcpu_update.clone()),
+            |result, vmm| {
+                assert_eq!(
+                    result,
+                    Err(VmmActionError::MachineConfig(
+                        VmConfigError::InvalidVcpuCount
+                    ))
+                );
+                assert!(!vmm.update_vcpu_count_called);
+            },
+        );
+
+        let hotplug_vm_res = || MockVmRes {
+            vm_config: VmConfig {
+                vcpu_count: 2,
+                max_vcpu_count: 8,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(hotplug_vm_res(), vmm.clone());
+        let res = runtime.handle_request(VmmAction::UpdateVmConfiguration(vcpu_update.clone()));
+        assert_eq!(res, Ok(VmmData::Empty));
+        assert!(vmm.lock().unwrap().update_vcpu_count_called);
+        assert_eq!(runtime.vm_resources.vm_config().vcpu_count, 4);
+
+        // More vCPUs than the maximum can't be plugged.
+        let res = runtime.handle_request(VmmAction::UpdateVmConfiguration(VmUpdateConfig {
+            vcpu_count: Some(9),
+            ..vcpu_update.clone()
+        }));
+        assert_eq!(
+            res,
+            Err(VmmActionError::MachineConfig(
+                VmConfigError::InvalidVcpuCount
+            ))
+        );
+        // The maximum vCPU count can't change after boot.
+        let res = runtime.handle_request(VmmAction::UpdateVmConfiguration(VmUpdateConfig {
+            max_vcpu_count: Some(16),
+            ..vcpu_update.clone()
+        }));
+        assert_eq!(res, Err(VmmActionError::OperationNotSupportedPostBoot));
+
+        // The vCPU count is left untouched when the vCPUs can't be plugged.
+        let vmm = Arc::new(Mutex::new(MockVmm {
+            force_errors: true,
+            ..Default::default()
+        }));
+        let mut runtime = RuntimeApiController::new(hotplug_vm_res(), vmm);
+        assert!(runtime
+            .handle_request(VmmAction::UpdateVmConfiguration(vcpu_update))
+            .is_err());
+        assert_eq!(runtime.vm_resources.vm_config().vcpu_count, 2);
     }
 
     #[test]
@@ -1938,12 +2662,131 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_update_block_cow_overlay() {
+        let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
+            cow_overlay_path: Some(String::new()),
+            ..Default::default()
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_block_cow_overlay_called);
+            assert!(!vmm.update_block_device_path_called);
+        });
+
+        let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
+            cow_overlay_path: Some(String::new()),
+            ..Default::default()
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::DriveConfig(DriveError::DeviceUpdate(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::IncorrectDeviceType,
+            ))),
+        );
+    }
+
+    #[test]
+    fn test_runtime_hotplug_block_device() {
+        let backing_file = utils::tempfile::TempFile::new().unwrap();
+        let config = BlockDeviceConfig {
+            path_on_host: backing_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("scratch"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
+        };
+
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(MockVmRes::default(), vmm.clone());
+        assert_eq!(
+            runtime.handle_request(VmmAction::InsertBlockDevice(config.clone())),
+            Ok(VmmData::Empty)
+        );
+        assert!(vmm.lock().unwrap().hotplug_block_device_called);
+        assert_eq!(runtime.vm_resources.block.list.len(), 1);
+
+        // The drive id is already in use.
+        assert_eq!(
+            runtime.handle_request(VmmAction::InsertBlockDevice(config.clone())),
+            Err(VmmActionError::DriveConfig(DriveError::DriveIdInUse(
+                String::from("scratch")
+            )))
+        );
+
+        // Root devices can't be hot-plugged.
+        let mut root_config = config.clone();
+        root_config.drive_id = String::from("root");
+        root_config.is_root_device = true;
+        assert_eq!(
+            runtime.handle_request(VmmAction::InsertBlockDevice(root_config)),
+            Err(VmmActionError::DriveConfig(DriveError::HotplugRootDevice))
+        );
+
+        check_runtime_request_err(
+            VmmAction::InsertBlockDevice(config),
+            VmmActionError::DriveConfig(DriveError::Hotplug(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::NoFreeHotplugSlot,
+            ))),
+        );
+    }
+
+    #[test]
+    fn test_runtime_hotunplug_block_device() {
+        let req = VmmAction::RemoveBlockDevice(String::from("scratch"));
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.hotunplug_block_device_called);
+        });
+
+        let req = VmmAction::RemoveBlockDevice(String::from("scratch"));
+        check_runtime_request_err(
+            req,
+            VmmActionError::DriveConfig(DriveError::Hotunplug(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::DeviceNotHotplugged,
+            ))),
+        );
+    }
+
+    #[test]
+    fn test_runtime_update_net_tx_filter() {
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            iface_id: String::new(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            tx_filter: Some(TxFilter::default()),
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_net_tx_filter_called)
+        });
+
+        // The filter is left alone when it's missing from the request.
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            iface_id: String::new(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            tx_filter: None,
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(!vmm.update_net_tx_filter_called)
+        });
+    }
+
     #[test]
     fn test_runtime_update_net_rate_limiters() {
         let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
             iface_id: String::new(),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            tx_filter: None,
         });
         check_runtime_request(req, |result, vmm| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1954,6 +2797,7 @@ mod tests {
             iface_id: String::new(),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            tx_filter: None,
         });
         check_runtime_request_err(
             req,
@@ -1984,19 +2828,6 @@ mod tests {
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
-        check_runtime_request_err(
-            VmmAction::InsertBlockDevice(BlockDeviceConfig {
-                path_on_host: String::new(),
-                is_root_device: false,
-                partuuid: None,
-                cache_type: CacheType::Unsafe,
-                is_read_only: false,
-                drive_id: String::new(),
-                rate_limiter: None,
-                file_engine_type: FileEngineType::default(),
-            }),
-            VmmActionError::OperationNotSupportedPostBoot,
-        );
         check_runtime_request_err(
             VmmAction::InsertNetworkDevice(NetworkInterfaceConfig {
                 iface_id: String::new(),
@@ -2004,6 +2835,11 @@ mod tests {
                 guest_mac: None,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                num_queue_pairs: 1,
+                backend: NetBackend::Userspace,
+                tx_filter: None,
+                dhcp: None,
+                user_network: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2012,6 +2848,10 @@ mod tests {
                 vsock_id: Some(String::new()),
                 guest_cid: 0,
                 uds_path: String::new(),
+                forwarding: None,
+                port_filter: None,
+                dgram: None,
+                preserve_connections: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2019,17 +2859,42 @@ mod tests {
             VmmAction::SetBalloonDevice(BalloonDeviceConfig::default()),
             VmmActionError::OperationNotSupportedPostBoot,
         );
+        check_runtime_request_err(
+            VmmAction::SetVhostUserBlockDevice(VhostUserBlockConfig {
+                drive_id: String::new(),
+                socket: String::new(),
+                num_queues: 1,
+                queue_size: 256,
+            }),
+            VmmActionError::OperationNotSupportedPostBoot,
+        );
+        check_runtime_request_err(
+            VmmAction::SetVhostUserNetDevice(VhostUserNetConfig {
+                iface_id: String::new(),
+                socket: String::new(),
+                guest_mac: None,
+                queue_size: 256,
+            }),
+            VmmActionError::OperationNotSupportedPostBoot,
+        );
         check_runtime_request_err(
             VmmAction::SetVsockDevice(VsockDeviceConfig {
                 vsock_id: Some(String::new()),
                 guest_cid: 0,
                 uds_path: String::new(),
+                forwarding: None,
+                port_filter: None,
+                dgram: None,
+                preserve_connections: None,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
         check_runtime_request_err(
             VmmAction::SetMmdsConfiguration(MmdsConfig {
                 ipv4_address: None,
+                ipv6_address: None,
+                guest_writable_path: None,
+                acl: Vec::new(),
                 version: MmdsVersion::default(),
                 network_interfaces: Vec::new(),
             }),
@@ -2046,11 +2911,16 @@ mod tests {
                     backend_type: MemBackendType::File,
                     backend_path: PathBuf::new(),
                 },
+                mem_diff_paths: Vec::new(),
                 enable_diff_snapshots: false,
                 resume_vm: false,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
+        check_runtime_request_err(
+            VmmAction::ReceiveMigration(receive_migration_params(false)),
+            VmmActionError::OperationNotSupportedPostBoot,
+        );
     }
 
     fn verify_load_snap_disallowed_after_boot_resources(res: VmmAction, res_name: &str) {
@@ -2068,6 +2938,7 @@ mod tests {
                 backend_type: MemBackendType::File,
                 backend_path: PathBuf::new(),
             },
+            mem_diff_paths: Vec::new(),
             enable_diff_snapshots: false,
             resume_vm: false,
         });
@@ -2095,6 +2966,9 @@ mod tests {
             drive_id: String::new(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertBlockDevice");
 
@@ -2104,6 +2978,11 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            backend: NetBackend::Userspace,
+            tx_filter: None,
+            dhcp: None,
+            user_network: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertNetworkDevice");
 
@@ -2114,14 +2993,37 @@ mod tests {
             vsock_id: Some(String::new()),
             guest_cid: 0,
             uds_path: String::new(),
+            forwarding: None,
+            port_filter: None,
+            dgram: None,
+            preserve_connections: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "SetVsockDevice");
 
+        let req = VmmAction::SetVhostUserBlockDevice(VhostUserBlockConfig {
+            drive_id: String::new(),
+            socket: String::new(),
+            num_queues: 1,
+            queue_size: 256,
+        });
+        verify_load_snap_disallowed_after_boot_resources(req, "SetVhostUserBlockDevice");
+
+        let req = VmmAction::SetVhostUserNetDevice(VhostUserNetConfig {
+            iface_id: String::new(),
+            socket: String::new(),
+            guest_mac: None,
+            queue_size: 256,
+        });
+        verify_load_snap_disallowed_after_boot_resources(req, "SetVhostUserNetDevice");
+
         let req = VmmAction::UpdateVmConfiguration(VmUpdateConfig::from(VmConfig::default()));
         verify_load_snap_disallowed_after_boot_resources(req, "SetVmConfiguration");
 
         let req = VmmAction::SetMmdsConfiguration(MmdsConfig {
             ipv4_address: None,
+            ipv6_address: None,
+            guest_writable_path: None,
+            acl: Vec::new(),
             version: MmdsVersion::default(),
             network_interfaces: Vec::new(),
         });
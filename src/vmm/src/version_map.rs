@@ -5,12 +5,18 @@
 
 use std::collections::HashMap;
 
-use devices::virtio::block::persist::BlockState;
+use devices::virtio::balloon::persist::BalloonState;
+use devices::virtio::block::persist::{BlockState, FileEngineTypeState};
+use devices::virtio::net::persist::NetState;
+use devices::virtio::vsock::persist::VsockUdsState;
 use devices::virtio::QueueState;
 use lazy_static::lazy_static;
+use mmds::persist::MmdsNetworkStackState;
 use versionize::{VersionMap, Versionize};
 
 use crate::device_manager::persist::DeviceStates;
+use crate::memory_snapshot::GuestMemoryState;
+use crate::persist::MicrovmState;
 #[cfg(target_arch = "x86_64")]
 use crate::vstate::vcpu::VcpuState;
 
@@ -25,6 +31,8 @@ pub const FC_V0_25_SNAP_VERSION: u16 = 3;
 pub const FC_V1_0_SNAP_VERSION: u16 = 4;
 /// Snap version for Firecracker v1.1
 pub const FC_V1_1_SNAP_VERSION: u16 = 5;
+/// Snap version for Firecracker v1.2
+pub const FC_V1_2_SNAP_VERSION: u16 = 6;
 
 lazy_static! {
     // Note: until we have a better design, this needs to be updated when the version changes.
@@ -48,6 +56,17 @@ lazy_static! {
         // v1.1 state change mappings.
         version_map.new_version().set_type_version(DeviceStates::type_id(), 3);
 
+        // v1.2 state change mappings.
+        version_map.new_version().set_type_version(NetState::type_id(), 2);
+        version_map.set_type_version(BalloonState::type_id(), 2);
+        version_map.set_type_version(BlockState::type_id(), 4);
+        version_map.set_type_version(DeviceStates::type_id(), 6);
+        version_map.set_type_version(FileEngineTypeState::type_id(), 2);
+        version_map.set_type_version(MicrovmState::type_id(), 3);
+        version_map.set_type_version(GuestMemoryState::type_id(), 2);
+        version_map.set_type_version(MmdsNetworkStackState::type_id(), 2);
+        version_map.set_type_version(VsockUdsState::type_id(), 2);
+
         version_map
     };
 
@@ -62,6 +81,7 @@ lazy_static! {
         mapping.insert(String::from("0.25.0"), FC_V0_25_SNAP_VERSION);
         mapping.insert(String::from("1.0.0"), FC_V1_0_SNAP_VERSION);
         mapping.insert(String::from("1.1.0"), FC_V1_1_SNAP_VERSION);
+        mapping.insert(String::from("1.2.0"), FC_V1_2_SNAP_VERSION);
 
         mapping
     };
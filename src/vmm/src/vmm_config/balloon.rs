@@ -5,6 +5,7 @@ use std::fmt;
 use std::sync::{Arc, Mutex};
 
 pub use devices::virtio::balloon::device::BalloonStats;
+pub use devices::virtio::balloon::BalloonAutoAdjustConfig;
 pub use devices::virtio::BALLOON_DEV_ID;
 use devices::virtio::{Balloon, BalloonConfig};
 use serde::{Deserialize, Serialize};
@@ -29,6 +30,10 @@ pub enum BalloonConfigError {
     CreateFailure(devices::virtio::balloon::Error),
     /// Failed to update the configuration of the ballon device.
     UpdateFailure(std::io::Error),
+    /// The configuration of the balloon controller is invalid.
+    InvalidAutoAdjustConfig(&'static str),
+    /// The user tried to update the target size while the balloon controller adjusts it.
+    AutoAdjustEnabled,
 }
 
 impl fmt::Display for BalloonConfigError {
@@ -49,6 +54,13 @@ impl fmt::Display for BalloonConfigError {
                 "Error updating the balloon device configuration: {:?}",
                 err
             ),
+            InvalidAutoAdjustConfig(err) => {
+                write!(f, "Invalid balloon controller configuration: {}", err)
+            }
+            AutoAdjustEnabled => write!(
+                f,
+                "The target size of the balloon is adjusted by the balloon controller."
+            ),
         }
     }
 }
@@ -67,6 +79,12 @@ pub struct BalloonDeviceConfig {
     /// Interval in seconds between refreshing statistics.
     #[serde(default)]
     pub stats_polling_interval_s: u16,
+    /// Option to free the guest memory the guest reports as unused.
+    #[serde(default)]
+    pub free_page_reporting: bool,
+    /// Option to let Firecracker adjust the target size of the balloon from the statistics.
+    #[serde(default)]
+    pub auto_adjust: Option<BalloonAutoAdjustConfig>,
 }
 
 impl From<BalloonConfig> for BalloonDeviceConfig {
@@ -75,6 +93,8 @@ impl From<BalloonConfig> for BalloonDeviceConfig {
             amount_mib: state.amount_mib,
             deflate_on_oom: state.deflate_on_oom,
             stats_polling_interval_s: state.stats_polling_interval_s,
+            free_page_reporting: state.free_page_reporting,
+            auto_adjust: state.auto_adjust,
         }
     }
 }
@@ -120,14 +140,17 @@ impl BalloonBuilder {
     /// Inserts a Balloon device in the store.
     /// If an entry already exists, it will overwrite it.
     pub fn set(&mut self, cfg: BalloonDeviceConfig) -> Result<()> {
-        self.inner = Some(Arc::new(Mutex::new(Balloon::new(
+        let mut balloon = Balloon::new(
             cfg.amount_mib,
             cfg.deflate_on_oom,
             cfg.stats_polling_interval_s,
+            cfg.free_page_reporting,
             // `restored` flag is false because this code path
             // is never called by snapshot restore functionality.
             false,
-        )?)));
+        )?;
+        balloon.set_auto_adjust(cfg.auto_adjust);
+        self.inner = Some(Arc::new(Mutex::new(balloon)));
 
         Ok(())
     }
@@ -142,6 +165,12 @@ impl BalloonBuilder {
         self.inner.as_ref()
     }
 
+    /// Provides the policy of the balloon controller, if enabled.
+    pub fn auto_adjust(&self) -> Option<BalloonAutoAdjustConfig> {
+        self.get()
+            .and_then(|balloon| balloon.lock().expect("Poisoned lock").auto_adjust())
+    }
+
     /// Returns the same structure that was used to configure the device.
     pub fn get_config(&self) -> Result<BalloonDeviceConfig> {
         self.get()
@@ -160,6 +189,8 @@ pub(crate) mod tests {
             amount_mib: 0,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            free_page_reporting: false,
+            auto_adjust: None,
         }
     }
 
@@ -178,6 +209,8 @@ pub(crate) mod tests {
             amount_mib: 0,
             deflate_on_oom: false,
             stats_polling_interval_s: 0,
+            free_page_reporting: false,
+            auto_adjust: None,
         };
         assert_eq!(default_balloon_config, balloon_config);
         let mut builder = BalloonBuilder::new();
@@ -199,12 +232,16 @@ pub(crate) mod tests {
             amount_mib: 5,
             deflate_on_oom: false,
             stats_polling_interval_s: 3,
+            free_page_reporting: true,
+            auto_adjust: None,
         };
 
         let actual_balloon_config = BalloonDeviceConfig::from(BalloonConfig {
             amount_mib: 5,
             deflate_on_oom: false,
             stats_polling_interval_s: 3,
+            free_page_reporting: true,
+            auto_adjust: None,
         });
 
         assert_eq!(expected_balloon_config, actual_balloon_config);
@@ -234,13 +271,50 @@ pub(crate) mod tests {
 
         let err = StatsNotFound;
         let _ = format!("{}{:?}", err, err);
+
+        let err = InvalidAutoAdjustConfig("");
+        let _ = format!("{}{:?}", err, err);
+
+        let err = AutoAdjustEnabled;
+        let _ = format!("{}{:?}", err, err);
     }
 
     #[test]
     fn test_set_device() {
         let mut builder = BalloonBuilder::new();
-        let balloon = Balloon::new(0, true, 0, true).unwrap();
+        let mut balloon = Balloon::new(0, true, 1, false, true).unwrap();
+        let auto_adjust = BalloonAutoAdjustConfig {
+            target_free_memory_percent: 20,
+            min_amount_mib: 0,
+            max_amount_mib: 64,
+            step_mib: 8,
+        };
+        balloon.set_auto_adjust(Some(auto_adjust));
         builder.set_device(Arc::new(Mutex::new(balloon)));
         assert!(builder.inner.is_some());
+        // The policy of the balloon controller is restored along with the device.
+        assert_eq!(builder.auto_adjust(), Some(auto_adjust));
+    }
+
+    #[test]
+    fn test_auto_adjust_config() {
+        let auto_adjust = BalloonAutoAdjustConfig {
+            target_free_memory_percent: 20,
+            min_amount_mib: 0,
+            max_amount_mib: 64,
+            step_mib: 8,
+        };
+
+        let mut builder = BalloonBuilder::new();
+        assert!(builder.auto_adjust().is_none());
+        builder
+            .set(BalloonDeviceConfig {
+                stats_polling_interval_s: 1,
+                auto_adjust: Some(auto_adjust),
+                ..default_config()
+            })
+            .unwrap();
+        assert_eq!(builder.auto_adjust(), Some(auto_adjust));
+        assert_eq!(builder.get_config().unwrap().auto_adjust, Some(auto_adjust));
     }
 }
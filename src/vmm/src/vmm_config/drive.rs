@@ -9,8 +9,8 @@ use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::{io, result};
 
-pub use devices::virtio::block::device::FileEngineType;
-use devices::virtio::block::Error as BlockError;
+pub use devices::virtio::block::device::{FileEngineType, ImageFormat};
+use devices::virtio::block::{Error as BlockError, DEFAULT_NUM_QUEUES};
 use devices::virtio::Block;
 pub use devices::virtio::CacheType;
 use serde::{Deserialize, Serialize};
@@ -32,6 +32,14 @@ pub enum DriveError {
     CreateRateLimiter(io::Error),
     /// Error during drive update (patch).
     DeviceUpdate(VmmError),
+    /// A drive with the same id is already attached to the running microVM.
+    DriveIdInUse(String),
+    /// Cannot hot-plug the block device.
+    Hotplug(VmmError),
+    /// A root block device cannot be hot-plugged.
+    HotplugRootDevice,
+    /// Cannot hot-unplug the block device.
+    Hotunplug(VmmError),
     /// The block device path is invalid.
     InvalidBlockDevicePath(String),
     /// Cannot open block device due to invalid permissions or path.
@@ -48,6 +56,10 @@ impl Display for DriveError {
             BlockDeviceUpdateFailed(err) => write!(f, "The update operation failed: {}", err),
             CreateRateLimiter(err) => write!(f, "Cannot create RateLimiter: {}", err),
             DeviceUpdate(err) => write!(f, "Error during drive update (patch): {}", err),
+            DriveIdInUse(id) => write!(f, "A drive with id {} is already attached.", id),
+            Hotplug(err) => write!(f, "Cannot hot-plug the drive: {}", err),
+            HotplugRootDevice => write!(f, "A root block device cannot be hot-plugged."),
+            Hotunplug(err) => write!(f, "Cannot hot-unplug the drive: {}", err),
             InvalidBlockDevicePath(path) => write!(f, "Invalid block device path: {}", path),
             OpenBlockDevice(err) => write!(
                 f,
@@ -87,6 +99,20 @@ pub struct BlockDeviceConfig {
     #[serde(default)]
     #[serde(rename = "io_engine")]
     pub file_engine_type: FileEngineType,
+    /// The number of virtio queues exposed to the guest. Each queue is served by its own
+    /// IO engine instance.
+    #[serde(default = "default_num_queues")]
+    pub num_queues: usize,
+    /// The format of the disk image found at `path_on_host`.
+    #[serde(default)]
+    pub format: ImageFormat,
+    /// Path of a sparse file receiving the writes of the guest to a read-only drive. The file
+    /// is created if it doesn't exist and its previous contents are ignored.
+    pub cow_overlay_path: Option<String>,
+}
+
+fn default_num_queues() -> usize {
+    DEFAULT_NUM_QUEUES
 }
 
 impl From<&Block> for BlockDeviceConfig {
@@ -101,6 +127,9 @@ impl From<&Block> for BlockDeviceConfig {
             cache_type: block.cache_type(),
             rate_limiter: rl.into_option(),
             file_engine_type: block.file_engine_type(),
+            num_queues: block.num_queues(),
+            format: block.image_format(),
+            cow_overlay_path: block.cow_overlay_path(),
         }
     }
 }
@@ -116,6 +145,8 @@ pub struct BlockDeviceUpdateConfig {
     pub path_on_host: Option<String>,
     /// New rate limiter config.
     pub rate_limiter: Option<RateLimiterConfig>,
+    /// New copy-on-write overlay path. The data written to the previous overlay is dropped.
+    pub cow_overlay_path: Option<String>,
 }
 
 /// Wrapper for the collection that holds all the Block Devices
@@ -202,11 +233,30 @@ impl BlockBuilder {
         Ok(())
     }
 
+    /// Creates a `Block` to be hot-plugged in the running microVM from a BlockDeviceConfig.
+    /// Hot-plugged devices can neither be root devices nor replace attached devices.
+    pub fn create_hotplug_block(&self, config: BlockDeviceConfig) -> Result<Block> {
+        if config.is_root_device {
+            return Err(DriveError::HotplugRootDevice);
+        }
+        if self.get_index_of_drive_id(&config.drive_id).is_some() {
+            return Err(DriveError::DriveIdInUse(config.drive_id));
+        }
+
+        Self::create_block(config)
+    }
+
+    /// Removes the device with the specified `drive_id` from the list.
+    pub fn remove(&mut self, drive_id: &str) -> Option<Arc<Mutex<Block>>> {
+        self.get_index_of_drive_id(drive_id)
+            .and_then(|index| self.list.remove(index))
+    }
+
     /// Creates a Block device from a BlockDeviceConfig.
     pub fn create_block(block_device_config: BlockDeviceConfig) -> Result<Block> {
-        // check if the path exists
+        // check if the path exists, NBD drives are given the URI of their export instead
         let path_on_host = PathBuf::from(&block_device_config.path_on_host);
-        if !path_on_host.exists() {
+        if block_device_config.file_engine_type != FileEngineType::Nbd && !path_on_host.exists() {
             return Err(DriveError::InvalidBlockDevicePath(format!(
                 "{}",
                 path_on_host.display()
@@ -229,6 +279,9 @@ impl BlockBuilder {
             block_device_config.is_root_device,
             rate_limiter.unwrap_or_default(),
             block_device_config.file_engine_type,
+            block_device_config.num_queues,
+            block_device_config.format,
+            block_device_config.cow_overlay_path,
         )
         .map_err(DriveError::CreateBlockDevice)
     }
@@ -269,6 +322,9 @@ mod tests {
                 drive_id: self.drive_id.clone(),
                 rate_limiter: None,
                 file_engine_type: FileEngineType::default(),
+                num_queues: self.num_queues,
+                format: self.format,
+                cow_overlay_path: self.cow_overlay_path.clone(),
             }
         }
     }
@@ -293,6 +349,9 @@ mod tests {
             drive_id: dummy_id.clone(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -324,6 +383,9 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -352,6 +414,9 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -365,6 +430,9 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -389,6 +457,9 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -402,6 +473,9 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
@@ -415,6 +489,9 @@ mod tests {
             drive_id: String::from("3"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -453,6 +530,9 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -466,6 +546,9 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
@@ -479,6 +562,9 @@ mod tests {
             drive_id: String::from("3"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -518,6 +604,9 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -531,6 +620,9 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -590,6 +682,9 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
         // Switch roots and add a PARTUUID for the new one.
         let mut root_block_device_old = root_block_device;
@@ -603,6 +698,9 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
         assert!(block_devs.insert(root_block_device_old).is_ok());
         let root_block_id = root_block_device_new.drive_id.clone();
@@ -625,6 +723,9 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -635,6 +736,118 @@ mod tests {
         assert_eq!(configs.first().unwrap(), &dummy_block_device);
     }
 
+    #[test]
+    fn test_block_config_num_queues() {
+        let dummy_file = TempFile::new().unwrap();
+
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            num_queues: 4,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        assert!(block_devs.insert(dummy_block_device.clone()).is_ok());
+        assert_eq!(block_devs.list[0].lock().unwrap().num_queues(), 4);
+        assert_eq!(block_devs.configs().first().unwrap(), &dummy_block_device);
+
+        // A block device needs at least one queue.
+        dummy_block_device.num_queues = 0;
+        assert!(matches!(
+            block_devs.insert(dummy_block_device),
+            Err(DriveError::CreateBlockDevice(BlockError::InvalidNumQueues(
+                0
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_block_config_format() {
+        let json = r#"{
+            "drive_id": "1",
+            "path_on_host": "dummy",
+            "is_root_device": false,
+            "is_read_only": true
+        }"#;
+        let config: BlockDeviceConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.format, ImageFormat::Raw);
+
+        let json = r#"{
+            "drive_id": "1",
+            "path_on_host": "dummy",
+            "is_root_device": false,
+            "is_read_only": true,
+            "format": "qcow2"
+        }"#;
+        let config: BlockDeviceConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(config.format, ImageFormat::Qcow2);
+
+        // A raw file can't be used as a qcow2 image.
+        let dummy_file = TempFile::new().unwrap();
+        dummy_file.as_file().set_len(0x1000).unwrap();
+        let dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: true,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Qcow2,
+            cow_overlay_path: None,
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        assert!(matches!(
+            block_devs.insert(dummy_block_device),
+            Err(DriveError::CreateBlockDevice(BlockError::Qcow2Image(_)))
+        ));
+    }
+
+    #[test]
+    fn test_block_config_cow_overlay() {
+        let backing_file = TempFile::new().unwrap();
+        backing_file.as_file().set_len(0x1000).unwrap();
+        let overlay_file = TempFile::new().unwrap();
+        let overlay_path = overlay_file.as_path().to_str().unwrap().to_string();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: backing_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: true,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: true,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::Sync,
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: Some(overlay_path),
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        block_devs.insert(dummy_block_device.clone()).unwrap();
+        assert_eq!(block_devs.configs(), vec![dummy_block_device.clone()]);
+
+        // The overlay only makes sense on top of read-only drives.
+        dummy_block_device.is_read_only = false;
+        assert!(matches!(
+            block_devs.insert(dummy_block_device),
+            Err(DriveError::CreateBlockDevice(
+                BlockError::UnsupportedCowOverlay
+            ))
+        ));
+    }
+
     #[test]
     fn test_add_device() {
         let mut block_devs = BlockBuilder::new();
@@ -649,11 +862,15 @@ mod tests {
             true,
             RateLimiter::default(),
             FileEngineType::default(),
+            1,
+            ImageFormat::Raw,
+            None,
         )
         .unwrap();
 
         block_devs.add_device(Arc::new(Mutex::new(block)));
         assert_eq!(block_devs.list.len(), 1);
+        assert!(block_devs.remove("other_id").is_none());
         assert_eq!(
             block_devs
                 .list
@@ -666,4 +883,43 @@ mod tests {
             block_id
         )
     }
+
+    #[test]
+    fn test_create_hotplug_block() {
+        let dummy_file = TempFile::new().unwrap();
+        let dummy_path = dummy_file.as_path().to_str().unwrap().to_string();
+        let mut config = BlockDeviceConfig {
+            path_on_host: dummy_path,
+            is_root_device: true,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            format: ImageFormat::Raw,
+            cow_overlay_path: None,
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        assert_eq!(
+            block_devs.create_hotplug_block(config.clone()).unwrap_err(),
+            DriveError::HotplugRootDevice
+        );
+
+        config.is_root_device = false;
+        let block = block_devs.create_hotplug_block(config.clone()).unwrap();
+        assert_eq!(block.id(), "1");
+        block_devs.add_device(Arc::new(Mutex::new(block)));
+        assert_eq!(
+            block_devs.create_hotplug_block(config.clone()).unwrap_err(),
+            DriveError::DriveIdInUse("1".to_string())
+        );
+
+        let removed = block_devs.remove("1").unwrap();
+        assert_eq!(removed.lock().unwrap().id(), "1");
+        assert_eq!(block_devs.list.len(), 0);
+        assert!(block_devs.create_hotplug_block(config).is_ok());
+    }
 }
@@ -10,12 +10,25 @@ pub const DEFAULT_MEM_SIZE_MIB: usize = 128;
 /// Firecracker aims to support small scale workloads only, so limit the maximum
 /// vCPUs supported.
 pub const MAX_SUPPORTED_VCPUS: u8 = 32;
+/// The smallest block of hot-pluggable memory, in MiB.
+pub const HOTPLUG_BLOCK_SIZE_MIB: usize = 2;
 
 /// Errors associated with configuring the microVM.
 #[derive(Debug, PartialEq)]
 pub enum VmConfigError {
     /// The memory size is smaller than the target size set in the balloon device configuration.
     IncompatibleBalloonSize,
+    /// The memory size is not a multiple of the huge page size of the memory backing, once
+    /// split into the regions of the guest memory layout.
+    IncompatibleMemoryBacking(MemoryBacking),
+    /// The requested size of hot-plugged memory is larger than the hot-pluggable memory, or not
+    /// a multiple of the hot-pluggable memory block size.
+    InvalidHotplugRequestedSize,
+    /// The size of the hot-pluggable memory is not a multiple of its block size.
+    InvalidHotplugSize,
+    /// The maximum vcpu count is invalid. It can't be smaller than the vcpu count or exceed
+    /// the supported vCPUs, and must be either 1 or an even number when SMT is enabled.
+    InvalidMaxVcpuCount,
     /// The memory size is invalid. The memory can only be an unsigned integer.
     InvalidMemorySize,
     /// The vcpu count is invalid. When SMT is enabled, the `cpu_count` must be either
@@ -35,6 +48,27 @@ impl fmt::Display for VmConfigError {
                 "The memory size (MiB) is smaller than the previously set balloon device target \
                  size.",
             ),
+            IncompatibleMemoryBacking(backing) => write!(
+                f,
+                "The memory size (MiB) can't be backed by {} memory: the guest memory regions \
+                 must be sized in whole huge pages.",
+                backing
+            ),
+            InvalidHotplugRequestedSize => write!(
+                f,
+                "The requested hot-plugged memory size (MiB) is invalid: it can't exceed the \
+                 hot-pluggable memory size, and must be a multiple of the block size.",
+            ),
+            InvalidHotplugSize => write!(
+                f,
+                "The hot-pluggable memory size (MiB) must be a multiple of the block size.",
+            ),
+            InvalidMaxVcpuCount => write!(
+                f,
+                "The maximum vCPU number is invalid! It can't be smaller than the vCPU number or \
+                 larger than {}, and can only be 1 or an even number when SMT is enabled.",
+                MAX_SUPPORTED_VCPUS
+            ),
             InvalidMemorySize => write!(f, "The memory size (MiB) is invalid.",),
             InvalidVcpuCount => write!(
                 f,
@@ -73,6 +107,19 @@ pub struct VmConfig {
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     #[serde(default)]
     pub track_dirty_pages: bool,
+    /// The kind of memory backing the guest memory.
+    #[serde(default)]
+    pub backing: MemoryBacking,
+    /// The size of the memory which can be hot-plugged after boot, in MiB.
+    #[serde(default)]
+    pub hotplug_size_mib: usize,
+    /// The size of the hot-pluggable memory the guest is requested to plug, in MiB.
+    #[serde(default)]
+    pub hotplug_requested_mib: usize,
+    /// The number of vCPUs the microVM can have once vCPUs are hot-plugged. 0 means vCPUs can't
+    /// be hot-plugged.
+    #[serde(default)]
+    pub max_vcpu_count: u8,
 }
 
 impl Default for VmConfig {
@@ -83,17 +130,38 @@ impl Default for VmConfig {
             smt: false,
             cpu_template: CpuFeaturesTemplate::None,
             track_dirty_pages: false,
+            backing: MemoryBacking::Anonymous,
+            hotplug_size_mib: 0,
+            hotplug_requested_mib: 0,
+            max_vcpu_count: 0,
         }
     }
 }
 
+impl VmConfig {
+    /// Returns the number of vCPUs the microVM is created with, which includes the vCPUs that
+    /// can be hot-plugged.
+    pub fn max_vcpus(&self) -> u8 {
+        std::cmp::max(self.vcpu_count, self.max_vcpu_count)
+    }
+}
+
 impl fmt::Display for VmConfig {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
             "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?}, \"smt\": {:?}, \"cpu_template\": \
-             {:?}, \"track_dirty_pages\": {:?} }}",
-            self.vcpu_count, self.mem_size_mib, self.smt, self.cpu_template, self.track_dirty_pages
+             {:?}, \"track_dirty_pages\": {:?}, \"backing\": \"{}\", \"hotplug_size_mib\": {:?}, \
+             \"hotplug_requested_mib\": {:?}, \"max_vcpu_count\": {:?} }}",
+            self.vcpu_count,
+            self.mem_size_mib,
+            self.smt,
+            self.cpu_template,
+            self.track_dirty_pages,
+            self.backing,
+            self.hotplug_size_mib,
+            self.hotplug_requested_mib,
+            self.max_vcpu_count
         )
     }
 }
@@ -103,10 +171,11 @@ impl fmt::Display for VmConfig {
 /// All fields are optional, but at least one needs to be specified.
 /// If a field is `Some(value)` then we assume an update is requested
 /// for that field.
-#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct VmUpdateConfig {
     /// Number of vcpu to start.
+    /// This field can also be updated after boot, when vCPUs can be hot-plugged.
     #[serde(
         default,
         skip_serializing_if = "Option::is_none",
@@ -133,6 +202,19 @@ pub struct VmUpdateConfig {
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub track_dirty_pages: Option<bool>,
+    /// The kind of memory backing the guest memory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backing: Option<MemoryBacking>,
+    /// The size of the memory which can be hot-plugged after boot, in MiB.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hotplug_size_mib: Option<usize>,
+    /// The size of the hot-pluggable memory the guest is requested to plug, in MiB.
+    /// This field can also be updated after boot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hotplug_requested_mib: Option<usize>,
+    /// The number of vCPUs the microVM can have once vCPUs are hot-plugged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_vcpu_count: Option<u8>,
 }
 
 impl VmUpdateConfig {
@@ -145,6 +227,10 @@ impl VmUpdateConfig {
             && self.cpu_template.is_none()
             && self.smt.is_none()
             && self.track_dirty_pages.is_none()
+            && self.backing.is_none()
+            && self.hotplug_size_mib.is_none()
+            && self.hotplug_requested_mib.is_none()
+            && self.max_vcpu_count.is_none()
         {
             return true;
         }
@@ -161,6 +247,10 @@ impl From<VmConfig> for VmUpdateConfig {
             smt: Some(cfg.smt),
             cpu_template: Some(cfg.cpu_template),
             track_dirty_pages: Some(cfg.track_dirty_pages),
+            backing: Some(cfg.backing),
+            hotplug_size_mib: Some(cfg.hotplug_size_mib),
+            hotplug_requested_mib: Some(cfg.hotplug_requested_mib),
+            max_vcpu_count: Some(cfg.max_vcpu_count),
         }
     }
 }
@@ -269,6 +359,65 @@ impl Default for CpuFeaturesTemplate {
     }
 }
 
+/// Kinds of memory that can back the guest memory.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MemoryBacking {
+    /// Private anonymous memory.
+    #[serde(rename = "anonymous")]
+    Anonymous,
+    /// Shared memory in memfds, which can be mapped by other processes.
+    #[serde(rename = "memfd")]
+    Memfd,
+    /// Shared memory in memfds on hugetlbfs, with 2 MiB pages.
+    #[serde(rename = "hugetlbfs-2M")]
+    Hugetlbfs2M,
+    /// Shared memory in memfds on hugetlbfs, with 1 GiB pages.
+    #[serde(rename = "hugetlbfs-1G")]
+    Hugetlbfs1G,
+}
+
+impl MemoryBacking {
+    /// Returns the size of the huge pages of the backing, if it uses any.
+    pub fn huge_page_size(self) -> Option<usize> {
+        match self {
+            MemoryBacking::Anonymous | MemoryBacking::Memfd => None,
+            MemoryBacking::Hugetlbfs2M => Some(2 << 20),
+            MemoryBacking::Hugetlbfs1G => Some(1 << 30),
+        }
+    }
+
+    /// Returns the size of the blocks of hot-pluggable memory, in MiB. Blocks can't be smaller
+    /// than the huge pages of the backing.
+    pub fn hotplug_block_size_mib(self) -> usize {
+        std::cmp::max(
+            HOTPLUG_BLOCK_SIZE_MIB,
+            self.huge_page_size().unwrap_or(0) >> 20,
+        )
+    }
+
+    /// Returns whether the memory can be mapped by other processes.
+    pub fn is_shared(self) -> bool {
+        self != MemoryBacking::Anonymous
+    }
+}
+
+impl fmt::Display for MemoryBacking {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryBacking::Anonymous => write!(f, "anonymous"),
+            MemoryBacking::Memfd => write!(f, "memfd"),
+            MemoryBacking::Hugetlbfs2M => write!(f, "hugetlbfs-2M"),
+            MemoryBacking::Hugetlbfs1G => write!(f, "hugetlbfs-1G"),
+        }
+    }
+}
+
+impl Default for MemoryBacking {
+    fn default() -> Self {
+        MemoryBacking::Anonymous
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -287,5 +436,80 @@ mod tests {
 
         let expected_str = "The memory size (MiB) is invalid.";
         assert_eq!(VmConfigError::InvalidMemorySize.to_string(), expected_str);
+
+        let expected_str = "The memory size (MiB) can't be backed by hugetlbfs-1G memory: the \
+                            guest memory regions must be sized in whole huge pages.";
+        assert_eq!(
+            VmConfigError::IncompatibleMemoryBacking(MemoryBacking::Hugetlbfs1G).to_string(),
+            expected_str
+        );
+
+        let expected_str =
+            "The hot-pluggable memory size (MiB) must be a multiple of the block size.";
+        assert_eq!(VmConfigError::InvalidHotplugSize.to_string(), expected_str);
+    }
+
+    #[test]
+    fn test_hotplug_config() {
+        let config: VmConfig =
+            serde_json::from_str(r#"{"vcpu_count": 1, "mem_size_mib": 128}"#).unwrap();
+        assert_eq!(config.hotplug_size_mib, 0);
+        assert_eq!(config.hotplug_requested_mib, 0);
+
+        let config: VmUpdateConfig =
+            serde_json::from_str(r#"{"hotplug_requested_mib": 512}"#).unwrap();
+        assert!(!config.is_empty());
+        assert_eq!(config.hotplug_requested_mib, Some(512));
+        assert_eq!(config.hotplug_size_mib, None);
+    }
+
+    #[test]
+    fn test_max_vcpu_count_config() {
+        let config: VmConfig =
+            serde_json::from_str(r#"{"vcpu_count": 2, "mem_size_mib": 128}"#).unwrap();
+        assert_eq!(config.max_vcpu_count, 0);
+        assert_eq!(config.max_vcpus(), 2);
+
+        let config: VmConfig =
+            serde_json::from_str(r#"{"vcpu_count": 2, "mem_size_mib": 128, "max_vcpu_count": 8}"#)
+                .unwrap();
+        assert_eq!(config.max_vcpu_count, 8);
+        assert_eq!(config.max_vcpus(), 8);
+
+        let config: VmUpdateConfig = serde_json::from_str(r#"{"max_vcpu_count": 4}"#).unwrap();
+        assert!(!config.is_empty());
+        assert_eq!(config.max_vcpu_count, Some(4));
+        assert_eq!(config.vcpu_count, None);
+    }
+
+    #[test]
+    fn test_memory_backing() {
+        assert_eq!(MemoryBacking::default(), MemoryBacking::Anonymous);
+        assert_eq!(MemoryBacking::Anonymous.huge_page_size(), None);
+        assert_eq!(MemoryBacking::Memfd.huge_page_size(), None);
+        assert_eq!(MemoryBacking::Hugetlbfs2M.huge_page_size(), Some(0x20_0000));
+        assert_eq!(
+            MemoryBacking::Hugetlbfs1G.huge_page_size(),
+            Some(0x4000_0000)
+        );
+        assert_eq!(MemoryBacking::Anonymous.hotplug_block_size_mib(), 2);
+        assert_eq!(MemoryBacking::Hugetlbfs2M.hotplug_block_size_mib(), 2);
+        assert_eq!(MemoryBacking::Hugetlbfs1G.hotplug_block_size_mib(), 1024);
+        assert!(!MemoryBacking::Anonymous.is_shared());
+        assert!(MemoryBacking::Memfd.is_shared());
+        assert!(MemoryBacking::Hugetlbfs2M.is_shared());
+
+        let config: VmConfig = serde_json::from_str(
+            r#"{"vcpu_count": 1, "mem_size_mib": 128, "backing": "hugetlbfs-2M"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.backing, MemoryBacking::Hugetlbfs2M);
+        let config: VmConfig =
+            serde_json::from_str(r#"{"vcpu_count": 1, "mem_size_mib": 128}"#).unwrap();
+        assert_eq!(config.backing, MemoryBacking::Anonymous);
+        assert!(serde_json::from_str::<VmConfig>(
+            r#"{"vcpu_count": 1, "mem_size_mib": 128, "backing": "hugetlbfs-4K"}"#
+        )
+        .is_err());
     }
 }
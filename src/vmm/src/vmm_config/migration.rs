@@ -0,0 +1,69 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Configurations used in the live migration context.
+
+use serde::{Deserialize, Serialize};
+
+/// Default maximum number of rounds of dirty pages sent while the microVM is running.
+const DEFAULT_MAX_ROUNDS: u32 = 8;
+/// Default number of dirty pages under which the microVM is paused for the last round.
+const DEFAULT_DIRTY_PAGES_THRESHOLD: u64 = 256;
+
+/// The kinds of sockets the migration stream can go through.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MigrationTransport {
+    /// Unix domain socket, the address is the path of the socket.
+    Unix,
+    /// TCP socket, the address is `<ip>:<port>`.
+    Tcp,
+}
+
+/// Socket through which a microVM is migrated.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MigrationSocketConfig {
+    /// Kind of the socket.
+    pub transport: MigrationTransport,
+    /// Path of the unix domain socket or address of the TCP socket.
+    pub address: String,
+}
+
+/// Stores the configuration used for sending a running microVM to another Firecracker process.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SendMigrationParams {
+    /// Socket the destination Firecracker process listens on.
+    pub destination: MigrationSocketConfig,
+    /// Maximum number of rounds of dirty pages sent while the microVM is running, after the
+    /// whole guest memory was sent.
+    #[serde(default = "default_max_rounds")]
+    pub max_rounds: u32,
+    /// The microVM is paused for the last round once a round sends no more than this number
+    /// of dirty pages.
+    #[serde(default = "default_dirty_pages_threshold")]
+    pub dirty_pages_threshold: u64,
+}
+
+/// Stores the configuration used for receiving a microVM from another Firecracker process.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReceiveMigrationParams {
+    /// Socket to listen on for the source Firecracker process.
+    pub source: MigrationSocketConfig,
+    /// Setting this flag will enable KVM dirty page tracking on the received microVM, which
+    /// allows taking diff snapshots of it or migrating it again.
+    #[serde(default)]
+    pub enable_diff_snapshots: bool,
+    /// When set to true, the microVM is also resumed once it is received.
+    #[serde(default)]
+    pub resume_vm: bool,
+}
+
+fn default_max_rounds() -> u32 {
+    DEFAULT_MAX_ROUNDS
+}
+
+fn default_dirty_pages_threshold() -> u64 {
+    DEFAULT_DIRTY_PAGES_THRESHOLD
+}
@@ -2,10 +2,10 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::fmt::{Display, Formatter, Result};
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 use mmds::data_store;
-use mmds::data_store::MmdsVersion;
+use mmds::data_store::{MmdsAclRule, MmdsVersion};
 use serde::{Deserialize, Serialize};
 
 /// Keeps the MMDS configuration.
@@ -19,6 +19,15 @@ pub struct MmdsConfig {
     pub network_interfaces: Vec<String>,
     /// MMDS IPv4 configured address.
     pub ipv4_address: Option<Ipv4Addr>,
+    /// MMDS IPv6 configured address. MMDS is only reachable over IPv6 when one is configured.
+    #[serde(default)]
+    pub ipv6_address: Option<Ipv6Addr>,
+    /// The MMDS subtree the guest can write to, if any.
+    #[serde(default)]
+    pub guest_writable_path: Option<String>,
+    /// Rules restricting the access the guest has to some MMDS subtrees.
+    #[serde(default)]
+    pub acl: Vec<MmdsAclRule>,
 }
 
 impl MmdsConfig {
@@ -37,6 +46,23 @@ impl MmdsConfig {
     pub fn ipv4_addr(&self) -> Option<Ipv4Addr> {
         self.ipv4_address
     }
+
+    /// Returns the MMDS IPv6 address if one was configured.
+    /// Otherwise returns None.
+    pub fn ipv6_addr(&self) -> Option<Ipv6Addr> {
+        self.ipv6_address
+    }
+
+    /// Returns the MMDS subtree the guest can write to, if one was configured.
+    /// Otherwise returns None.
+    pub fn guest_writable_path(&self) -> Option<String> {
+        self.guest_writable_path.clone()
+    }
+
+    /// Returns the rules restricting the access the guest has to some MMDS subtrees.
+    pub fn acl(&self) -> Vec<MmdsAclRule> {
+        self.acl.clone()
+    }
 }
 
 /// MMDS configuration related errors.
@@ -44,8 +70,12 @@ impl MmdsConfig {
 pub enum MmdsConfigError {
     /// The network interfaces list provided is empty.
     EmptyNetworkIfaceList,
+    /// The access the guest has to the data store could not be configured.
+    GuestAccess(data_store::Error),
     /// The provided IPv4 address is not link-local valid.
     InvalidIpv4Addr,
+    /// The provided IPv6 address is neither link-local nor unique local.
+    InvalidIpv6Addr,
     /// The network interfaces list provided contains IDs that
     /// does not correspond to any existing network interface.
     InvalidNetworkInterfaceId,
@@ -63,9 +93,22 @@ impl Display for MmdsConfigError {
                      empty."
                 )
             }
+            MmdsConfigError::GuestAccess(err) => {
+                write!(
+                    f,
+                    "The access the guest has to the MMDS could not be configured: {}",
+                    err
+                )
+            }
             MmdsConfigError::InvalidIpv4Addr => {
                 write!(f, "The MMDS IPv4 address is not link local.")
             }
+            MmdsConfigError::InvalidIpv6Addr => {
+                write!(
+                    f,
+                    "The MMDS IPv6 address is neither link local nor unique local."
+                )
+            }
             MmdsConfigError::InvalidNetworkInterfaceId => {
                 write!(
                     f,
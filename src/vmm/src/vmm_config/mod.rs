@@ -25,12 +25,16 @@ pub mod logger;
 pub mod machine_config;
 /// Wrapper for configuring the metrics.
 pub mod metrics;
+/// Wrapper for configuring the live migration of the microVM.
+pub mod migration;
 /// Wrapper for configuring the MMDS.
 pub mod mmds;
 /// Wrapper for configuring the network devices attached to the microVM.
 pub mod net;
 /// Wrapper for configuring microVM snapshots and the microVM state.
 pub mod snapshot;
+/// Wrapper for configuring the devices served by vhost-user backends.
+pub mod vhost_user;
 /// Wrapper for configuring the vsock devices attached to the microVM.
 pub mod vsock;
 
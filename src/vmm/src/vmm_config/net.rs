@@ -6,8 +6,11 @@ use std::ops::Deref;
 use std::sync::{Arc, Mutex};
 use std::{fmt, result};
 
-use devices::virtio::net::TapError;
+use devices::virtio::net::{
+    NetBackend, TapError, TxFilter, UserNetworkConfig, DEFAULT_NUM_QUEUE_PAIRS,
+};
 use devices::virtio::Net;
+use mmds::dhcp::{DhcpConfig, DhcpServer, Error as DhcpError};
 use serde::{Deserialize, Serialize};
 use utils::net::mac::MacAddr;
 
@@ -21,7 +24,8 @@ use crate::Error as VmmError;
 pub struct NetworkInterfaceConfig {
     /// ID of the guest network interface.
     pub iface_id: String,
-    /// Host level path for the guest network interface.
+    /// Host level path for the guest network interface. Empty with the user backend.
+    #[serde(default)]
     pub host_dev_name: String,
     /// Guest MAC address.
     pub guest_mac: Option<MacAddr>,
@@ -29,6 +33,27 @@ pub struct NetworkInterfaceConfig {
     pub rx_rate_limiter: Option<RateLimiterConfig>,
     /// Rate Limiter for transmitted packages.
     pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// The number of RX/TX queue pairs exposed to the guest. Each pair is served by its own
+    /// queue of the tap device, and the rate limiters apply to each pair separately.
+    #[serde(default = "default_num_queue_pairs")]
+    pub num_queue_pairs: usize,
+    /// The data path of the queue pairs.
+    #[serde(default)]
+    pub backend: NetBackend,
+    /// The rules applied to the frames sent by the guest.
+    #[serde(default)]
+    pub tx_filter: Option<TxFilter>,
+    /// When present, a built-in DHCP server hands out this configuration to the guest.
+    #[serde(default)]
+    pub dhcp: Option<DhcpConfig>,
+    /// The destinations the guest can reach through the user backend. When absent, the guest
+    /// can't reach the host loopback interface nor the link-local network.
+    #[serde(default)]
+    pub user_network: Option<UserNetworkConfig>,
+}
+
+fn default_num_queue_pairs() -> usize {
+    DEFAULT_NUM_QUEUE_PAIRS
 }
 
 impl From<&Net> for NetworkInterfaceConfig {
@@ -41,12 +66,20 @@ impl From<&Net> for NetworkInterfaceConfig {
             guest_mac: net.guest_mac().copied(),
             rx_rate_limiter: rx_rl.into_option(),
             tx_rate_limiter: tx_rl.into_option(),
+            num_queue_pairs: net.num_queue_pairs(),
+            backend: net.backend(),
+            tx_filter: Some(net.tx_filter().clone()).filter(TxFilter::is_enabled),
+            dhcp: net.dhcp_server().map(|server| server.config().clone()),
+            user_network: net
+                .user_network_config()
+                .filter(|config| **config != UserNetworkConfig::default())
+                .cloned(),
         }
     }
 }
 
 /// The data fed into a network iface update request. Currently, only the RX and TX rate limiters
-/// can be updated.
+/// and the TX filter can be updated.
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct NetworkInterfaceUpdateConfig {
@@ -58,6 +91,8 @@ pub struct NetworkInterfaceUpdateConfig {
     /// New TX rate limiter config. Only provided data will be updated. I.e. if any optional data
     /// is missing, it will not be nullified, but left unchanged.
     pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// New TX filter. When provided, it replaces all the current rules.
+    pub tx_filter: Option<TxFilter>,
 }
 
 /// Errors associated with `NetworkInterfaceConfig`.
@@ -69,10 +104,14 @@ pub enum NetworkInterfaceError {
     CreateRateLimiter(std::io::Error),
     /// The MAC address is already in use.
     GuestMacAddressInUse(String),
+    /// The ID is already used by a network interface served by a vhost-user backend.
+    IfaceIdInUse(String),
     /// Error during interface update (patch).
     DeviceUpdate(VmmError),
     /// Cannot open/create tap device.
     OpenTap(TapError),
+    /// The DHCP configuration is invalid.
+    InvalidDhcpConfig(DhcpError),
 }
 
 impl fmt::Display for NetworkInterfaceError {
@@ -86,7 +125,9 @@ impl fmt::Display for NetworkInterfaceError {
                 "{}",
                 format!("The guest MAC address {} is already in use.", mac_addr)
             ),
+            IfaceIdInUse(id) => write!(f, "The interface ID {} is already in use.", id),
             DeviceUpdate(err) => write!(f, "Error during interface update (patch): {}", err),
+            InvalidDhcpConfig(err) => write!(f, "Invalid DHCP configuration: {:?}", err),
             OpenTap(err) => {
                 // We are propagating the Tap Error. This error can contain
                 // imbricated quotes which would result in an invalid json.
@@ -183,14 +224,29 @@ impl NetBuilder {
             .transpose()?;
 
         // Create and return the Net device
-        devices::virtio::net::Net::new_with_tap(
+        let mut net = devices::virtio::net::Net::new_with_tap(
             cfg.iface_id,
             cfg.host_dev_name.clone(),
             cfg.guest_mac.as_ref(),
             rx_rate_limiter.unwrap_or_default(),
             tx_rate_limiter.unwrap_or_default(),
+            cfg.num_queue_pairs,
+            cfg.backend,
         )
-        .map_err(NetworkInterfaceError::CreateNetworkDevice)
+        .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        if let Some(tx_filter) = cfg.tx_filter {
+            net.set_tx_filter(tx_filter)
+                .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        }
+        if let Some(dhcp) = cfg.dhcp {
+            net.set_dhcp_server(Some(DhcpServer::new(dhcp)?))
+                .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        }
+        if let Some(user_network) = cfg.user_network {
+            net.set_user_network_config(user_network)
+                .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        }
+        Ok(net)
     }
 
     /// Returns a vec with the structures used to configure the net devices.
@@ -228,6 +284,11 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str(mac).unwrap()),
             rx_rate_limiter: RateLimiterConfig::default().into_option(),
             tx_rate_limiter: RateLimiterConfig::default().into_option(),
+            num_queue_pairs: 1,
+            backend: NetBackend::Userspace,
+            tx_filter: None,
+            dhcp: None,
+            user_network: None,
         }
     }
 
@@ -239,6 +300,11 @@ mod tests {
                 guest_mac: self.guest_mac,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                num_queue_pairs: self.num_queue_pairs,
+                backend: self.backend,
+                tx_filter: self.tx_filter.clone(),
+                dhcp: self.dhcp.clone(),
+                user_network: self.user_network.clone(),
             }
         }
     }
@@ -356,6 +422,10 @@ mod tests {
             NetworkInterfaceError::OpenTap(TapError::InvalidIfname),
             NetworkInterfaceError::OpenTap(TapError::InvalidIfname)
         );
+        let err = NetworkInterfaceError::IfaceIdInUse(String::from("id"));
+        let _ = format!("{}{:?}", err, err);
+        let err = NetworkInterfaceError::InvalidDhcpConfig(DhcpError::InvalidNetmask);
+        let _ = format!("{}{:?}", err, err);
     }
 
     #[test]
@@ -379,6 +449,208 @@ mod tests {
         assert_eq!(configs.first().unwrap(), &net_if_cfg);
     }
 
+    #[test]
+    fn test_net_config_num_queue_pairs() {
+        let mut net_if_cfg = create_netif("id", "dev5", "01:23:45:67:89:0c");
+        net_if_cfg.num_queue_pairs = 4;
+
+        let mut net_builder = NetBuilder::new();
+        let net = net_builder.build(net_if_cfg.clone()).unwrap();
+        assert_eq!(net.lock().unwrap().num_queue_pairs(), 4);
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+
+        // A net device needs at least one queue pair.
+        net_if_cfg.num_queue_pairs = 0;
+        assert!(matches!(
+            net_builder.build(net_if_cfg),
+            Err(NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::InvalidNumQueuePairs(0)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_net_config_tx_filter() {
+        let mut net_if_cfg = create_netif("id", "dev6", "01:23:45:67:89:0d");
+        net_if_cfg.tx_filter = Some(TxFilter {
+            drop_spoofed_mac: true,
+            allowed_ipv4_sources: Some(vec!["192.168.0.2".parse().unwrap()]),
+            drop_spoofed_arp: true,
+        });
+
+        let mut net_builder = NetBuilder::new();
+        let net = net_builder.build(net_if_cfg.clone()).unwrap();
+        assert_eq!(
+            Some(net.lock().unwrap().tx_filter()),
+            net_if_cfg.tx_filter.as_ref()
+        );
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+
+        // A filter without rules isn't reported.
+        net_if_cfg.tx_filter = Some(TxFilter::default());
+        net_builder.build(net_if_cfg).unwrap();
+        assert_eq!(net_builder.configs().first().unwrap().tx_filter, None);
+
+        // The MAC rules need a guest MAC.
+        let mut net_if_cfg = create_netif("id", "dev6", "01:23:45:67:89:0d");
+        net_if_cfg.guest_mac = None;
+        net_if_cfg.tx_filter = Some(TxFilter {
+            drop_spoofed_mac: true,
+            ..Default::default()
+        });
+        assert!(matches!(
+            net_builder.build(net_if_cfg),
+            Err(NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::TxFilterWithoutGuestMac
+            ))
+        ));
+
+        // The filter can be given in the configuration file.
+        let net_if_cfg: NetworkInterfaceConfig = serde_json::from_str(
+            r#"{
+                "iface_id": "id",
+                "host_dev_name": "dev6",
+                "guest_mac": "01:23:45:67:89:0d",
+                "tx_filter": {
+                    "drop_spoofed_mac": true,
+                    "allowed_ipv4_sources": ["192.168.0.2"]
+                }
+            }"#,
+        )
+        .unwrap();
+        let tx_filter = net_if_cfg.tx_filter.unwrap();
+        assert!(tx_filter.drop_spoofed_mac);
+        assert!(!tx_filter.drop_spoofed_arp);
+        assert!(serde_json::from_str::<TxFilter>(r#"{"drop_spoofed_ipv6": true}"#).is_err());
+        assert!(serde_json::from_str::<TxFilter>(r#"{"allowed_ipv4_sources": ["::1"]}"#).is_err());
+    }
+
+    #[test]
+    fn test_net_config_user_backend() {
+        let net_if_cfg: NetworkInterfaceConfig = serde_json::from_str(
+            r#"{
+                "iface_id": "id",
+                "guest_mac": "01:23:45:67:89:0e",
+                "backend": "user"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(net_if_cfg.backend, NetBackend::User);
+        assert_eq!(net_if_cfg.host_dev_name, "");
+
+        let mut net_builder = NetBuilder::new();
+        let net = net_builder.build(net_if_cfg.clone()).unwrap();
+        assert_eq!(net.lock().unwrap().backend(), NetBackend::User);
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+
+        // The user-mode network stack doesn't go along with a tap device.
+        let mut net_if_cfg = create_netif("id", "dev7", "01:23:45:67:89:0e");
+        net_if_cfg.backend = NetBackend::User;
+        assert!(matches!(
+            net_builder.build(net_if_cfg),
+            Err(NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::UserBackendWithTap
+            ))
+        ));
+
+        let net_if_cfg: NetworkInterfaceConfig = serde_json::from_str(
+            r#"{
+                "iface_id": "id",
+                "backend": "user",
+                "user_network": {
+                    "host_loopback": true,
+                    "allowed_networks": ["10.1.0.0/16"]
+                }
+            }"#,
+        )
+        .unwrap();
+        let user_network = net_if_cfg.user_network.as_ref().unwrap();
+        assert!(user_network.host_loopback);
+        assert_eq!(
+            user_network.allowed_networks.as_ref().unwrap()[0].to_string(),
+            "10.1.0.0/16"
+        );
+        // The link-local network stays denied unless the list is given.
+        assert_eq!(
+            user_network.denied_networks[0].to_string(),
+            "169.254.0.0/16"
+        );
+        let net = net_builder.build(net_if_cfg.clone()).unwrap();
+        assert_eq!(
+            net.lock().unwrap().user_network_config(),
+            net_if_cfg.user_network.as_ref()
+        );
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+        assert!(serde_json::from_str::<UserNetworkConfig>(
+            r#"{"denied_networks": ["169.254.169.254"]}"#
+        )
+        .is_err());
+
+        // Only the user backend filters destinations.
+        let mut net_if_cfg = create_netif("id", "dev7", "01:23:45:67:89:0e");
+        net_if_cfg.user_network = Some(UserNetworkConfig::default());
+        assert!(matches!(
+            net_builder.build(net_if_cfg),
+            Err(NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::UserNetworkConfigWithTap
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_net_config_dhcp() {
+        let net_if_cfg: NetworkInterfaceConfig = serde_json::from_str(
+            r#"{
+                "iface_id": "id",
+                "host_dev_name": "dev8",
+                "guest_mac": "01:23:45:67:89:0f",
+                "dhcp": {
+                    "ipv4_address": "172.16.0.2",
+                    "netmask": "255.255.255.252",
+                    "gateway": "172.16.0.1",
+                    "dns_servers": ["8.8.8.8"],
+                    "mtu": 1400
+                }
+            }"#,
+        )
+        .unwrap();
+        let dhcp = net_if_cfg.dhcp.as_ref().unwrap();
+        assert_eq!(dhcp.gateway, Some("172.16.0.1".parse().unwrap()));
+        assert_eq!(dhcp.mtu, Some(1400));
+
+        let mut net_builder = NetBuilder::new();
+        let net = net_builder.build(net_if_cfg.clone()).unwrap();
+        assert!(net.lock().unwrap().dhcp_server().is_some());
+        assert_eq!(net_builder.configs().first().unwrap(), &net_if_cfg);
+
+        let mut net_if_cfg = net_if_cfg;
+        net_if_cfg.dhcp.as_mut().unwrap().mtu = Some(1);
+        assert!(matches!(
+            net_builder.build(net_if_cfg),
+            Err(NetworkInterfaceError::InvalidDhcpConfig(
+                DhcpError::InvalidMtu
+            ))
+        ));
+
+        // The user-mode network stack has its own server.
+        let mut net_if_cfg = create_netif("id", "", "01:23:45:67:89:0f");
+        net_if_cfg.backend = NetBackend::User;
+        net_if_cfg.dhcp = Some(DhcpConfig {
+            ipv4_address: "10.0.2.15".parse().unwrap(),
+            netmask: "255.255.255.0".parse().unwrap(),
+            gateway: None,
+            dns_servers: vec![],
+            mtu: None,
+            lease_time: 3600,
+        });
+        assert!(matches!(
+            net_builder.build(net_if_cfg),
+            Err(NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::UserBackendWithDhcp
+            ))
+        ));
+    }
+
     #[test]
     fn test_add_device() {
         let mut net_builder = NetBuilder::new();
@@ -392,6 +664,8 @@ mod tests {
             Some(&MacAddr::parse_str(guest_mac).unwrap()),
             RateLimiter::default(),
             RateLimiter::default(),
+            1,
+            NetBackend::Userspace,
         )
         .unwrap();
 
@@ -23,6 +23,21 @@ impl Default for SnapshotType {
     }
 }
 
+/// The formats of the guest memory file of a snapshot.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MemFileFormat {
+    /// Plain copy of the guest memory.
+    Raw,
+    /// LZ4 compressed chunks of pages, without the zero pages and with a checksum per chunk.
+    Compressed,
+}
+
+impl Default for MemFileFormat {
+    fn default() -> Self {
+        MemFileFormat::Raw
+    }
+}
+
 /// Specifies the method through which guest memory will get populated when
 /// resuming from a snapshot:
 /// 1) A file that contains the guest memory to be loaded,
@@ -48,6 +63,10 @@ pub struct CreateSnapshotParams {
     pub snapshot_path: PathBuf,
     /// Path to the file that will contain the guest memory.
     pub mem_file_path: PathBuf,
+    /// Format of the guest memory file. The default value is `Raw`. Diff snapshots can only be
+    /// saved in the `Raw` format.
+    #[serde(default)]
+    pub mem_file_format: MemFileFormat,
     /// Optional field for the microVM version. The default
     /// value is the current version.
     pub version: Option<String>,
@@ -60,6 +79,9 @@ pub struct LoadSnapshotParams {
     pub snapshot_path: PathBuf,
     /// Specifies guest memory backend configuration.
     pub mem_backend: MemBackendConfig,
+    /// Paths to the memory files of the diff snapshots applied on top of the memory backend,
+    /// from the oldest to the newest.
+    pub mem_diff_paths: Vec<PathBuf>,
     /// Setting this flag will enable KVM dirty page tracking and will
     /// allow taking subsequent incremental snapshots.
     pub enable_diff_snapshots: bool,
@@ -82,6 +104,10 @@ pub struct LoadSnapshotConfig {
     /// None value is allowed only if `mem_file_path` is present.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mem_backend: Option<MemBackendConfig>,
+    /// Paths to the memory files of the diff snapshots applied on top of the guest memory, from
+    /// the oldest to the newest. Only supported with the `File` memory backend.
+    #[serde(default)]
+    pub mem_diff_paths: Vec<PathBuf>,
     /// Whether or not to enable KVM dirty page tracking.
     #[serde(default)]
     pub enable_diff_snapshots: bool,
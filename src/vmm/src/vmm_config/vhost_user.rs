@@ -0,0 +1,287 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use devices::virtio::{
+    VhostUser, VhostUserError, VirtioDevice, TYPE_BLOCK, TYPE_NET, VHOST_USER_QUEUE_SIZE,
+};
+use serde::{Deserialize, Serialize};
+use utils::net::mac::MacAddr;
+
+/// Errors associated with the vhost-user device configs.
+#[derive(Debug)]
+pub enum VhostUserConfigError {
+    /// Failed to set up the device with its backend.
+    CreateVhostUserDevice(VhostUserError),
+    /// The ID is already used by another device of the same kind.
+    DeviceIdInUse(String),
+}
+
+impl fmt::Display for VhostUserConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::VhostUserConfigError::*;
+        match self {
+            CreateVhostUserDevice(err) => write!(f, "Cannot create vhost-user device: {:?}", err),
+            DeviceIdInUse(id) => write!(f, "The device ID {} is already in use.", id),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, VhostUserConfigError>;
+
+fn default_num_queues() -> usize {
+    1
+}
+
+fn default_queue_size() -> u16 {
+    VHOST_USER_QUEUE_SIZE
+}
+
+/// Configuration of a block device served by a vhost-user backend.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VhostUserBlockConfig {
+    /// Unique identifier of the drive.
+    pub drive_id: String,
+    /// Path of the unix socket the backend listens on.
+    pub socket: String,
+    /// The number of queues exposed to the guest.
+    #[serde(default = "default_num_queues")]
+    pub num_queues: usize,
+    /// The size of each queue.
+    #[serde(default = "default_queue_size")]
+    pub queue_size: u16,
+}
+
+/// Configuration of a network interface served by a vhost-user backend.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VhostUserNetConfig {
+    /// ID of the guest network interface.
+    pub iface_id: String,
+    /// Path of the unix socket the backend listens on.
+    pub socket: String,
+    /// Guest MAC address.
+    pub guest_mac: Option<MacAddr>,
+    /// The size of the RX and TX queues.
+    #[serde(default = "default_queue_size")]
+    pub queue_size: u16,
+}
+
+impl From<&VhostUser> for VhostUserBlockConfig {
+    fn from(device: &VhostUser) -> Self {
+        VhostUserBlockConfig {
+            drive_id: device.id().clone(),
+            socket: device.socket_path().clone(),
+            num_queues: device.num_queues(),
+            queue_size: device.queue_size(),
+        }
+    }
+}
+
+impl From<&VhostUser> for VhostUserNetConfig {
+    fn from(device: &VhostUser) -> Self {
+        VhostUserNetConfig {
+            iface_id: device.id().clone(),
+            socket: device.socket_path().clone(),
+            guest_mac: device.guest_mac(),
+            queue_size: device.queue_size(),
+        }
+    }
+}
+
+/// Builder for the vhost-user devices. The devices connect to their backend when they are
+/// configured, so that a misconfigured backend is reported to the user right away.
+#[derive(Default)]
+pub struct VhostUserBuilder {
+    devices: Vec<Arc<Mutex<VhostUser>>>,
+}
+
+impl VhostUserBuilder {
+    /// Creates an empty list of vhost-user devices.
+    pub fn new() -> Self {
+        VhostUserBuilder {
+            devices: Vec::new(),
+        }
+    }
+
+    /// Returns an immutable iterator over the vhost-user devices.
+    pub fn iter(&self) -> ::std::slice::Iter<Arc<Mutex<VhostUser>>> {
+        self.devices.iter()
+    }
+
+    /// Returns whether no vhost-user device is configured.
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Returns whether a device of type `device_type` uses the ID `id`.
+    pub fn has_device(&self, device_type: u32, id: &str) -> bool {
+        self.position(device_type, id).is_some()
+    }
+
+    fn position(&self, device_type: u32, id: &str) -> Option<usize> {
+        self.devices.iter().position(|device| {
+            let device = device.lock().expect("Poisoned lock");
+            device.device_type() == device_type && device.id() == id
+        })
+    }
+
+    // Drops the device with the same type and ID, if any. This is done before connecting to the
+    // backend of the new device, since a backend may only serve a single connection.
+    fn remove(&mut self, device_type: u32, id: &str) {
+        if let Some(index) = self.position(device_type, id) {
+            self.devices.remove(index);
+        }
+    }
+
+    /// Creates a vhost-user block device, or replaces the one with the same ID.
+    pub fn insert_block(&mut self, config: VhostUserBlockConfig) -> Result<()> {
+        self.remove(TYPE_BLOCK, &config.drive_id);
+        let device = VhostUser::new_block(
+            config.drive_id,
+            config.socket,
+            config.num_queues,
+            config.queue_size,
+        )
+        .map_err(VhostUserConfigError::CreateVhostUserDevice)?;
+        self.devices.push(Arc::new(Mutex::new(device)));
+        Ok(())
+    }
+
+    /// Creates a vhost-user network device, or replaces the one with the same ID.
+    pub fn insert_net(&mut self, config: VhostUserNetConfig) -> Result<()> {
+        self.remove(TYPE_NET, &config.iface_id);
+        let device = VhostUser::new_net(
+            config.iface_id,
+            config.socket,
+            config.guest_mac.as_ref(),
+            config.queue_size,
+        )
+        .map_err(VhostUserConfigError::CreateVhostUserDevice)?;
+        self.devices.push(Arc::new(Mutex::new(device)));
+        Ok(())
+    }
+
+    /// Returns the configs of the vhost-user block devices.
+    pub fn block_configs(&self) -> Vec<VhostUserBlockConfig> {
+        self.configs(TYPE_BLOCK)
+    }
+
+    /// Returns the configs of the vhost-user network devices.
+    pub fn net_configs(&self) -> Vec<VhostUserNetConfig> {
+        self.configs(TYPE_NET)
+    }
+
+    fn configs<T>(&self, device_type: u32) -> Vec<T>
+    where
+        T: for<'a> From<&'a VhostUser>,
+    {
+        self.devices
+            .iter()
+            .map(|device| device.lock().expect("Poisoned lock"))
+            .filter(|device| device.device_type() == device_type)
+            .map(|device| T::from(&*device))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use devices::virtio::vhost_user::test_utils::{default_block_backend, default_net_backend};
+
+    use super::*;
+
+    fn block_config(drive_id: &str, socket: &str) -> VhostUserBlockConfig {
+        VhostUserBlockConfig {
+            drive_id: String::from(drive_id),
+            socket: String::from(socket),
+            num_queues: 1,
+            queue_size: VHOST_USER_QUEUE_SIZE,
+        }
+    }
+
+    fn net_config(iface_id: &str, socket: &str) -> VhostUserNetConfig {
+        VhostUserNetConfig {
+            iface_id: String::from(iface_id),
+            socket: String::from(socket),
+            guest_mac: Some(MacAddr::parse_str("01:23:45:67:89:0A").unwrap()),
+            queue_size: 512,
+        }
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut builder = VhostUserBuilder::new();
+        assert!(builder.is_empty());
+
+        let block_backend = default_block_backend();
+        let block_cfg = block_config("vhu", block_backend.socket_path());
+        builder.insert_block(block_cfg.clone()).unwrap();
+        let net_backend = default_net_backend();
+        let net_cfg = net_config("vhu", net_backend.socket_path());
+        builder.insert_net(net_cfg.clone()).unwrap();
+
+        // Devices of different kinds may share an ID.
+        assert_eq!(builder.iter().count(), 2);
+        assert!(builder.has_device(TYPE_BLOCK, "vhu"));
+        assert!(builder.has_device(TYPE_NET, "vhu"));
+        assert!(!builder.has_device(TYPE_BLOCK, "other"));
+
+        assert_eq!(builder.block_configs(), vec![block_cfg]);
+        assert_eq!(builder.net_configs(), vec![net_cfg]);
+    }
+
+    #[test]
+    fn test_update() {
+        let mut builder = VhostUserBuilder::new();
+        let backend = default_block_backend();
+        builder
+            .insert_block(block_config("vhu", backend.socket_path()))
+            .unwrap();
+
+        // The device is replaced, with the new backend.
+        let backend = default_block_backend();
+        let mut cfg = block_config("vhu", backend.socket_path());
+        cfg.num_queues = 2;
+        builder.insert_block(cfg.clone()).unwrap();
+        assert_eq!(builder.block_configs(), vec![cfg]);
+
+        // A failed update drops the old device, which lost its backend.
+        let err = builder
+            .insert_block(block_config("vhu", "/invalid/socket"))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            VhostUserConfigError::CreateVhostUserDevice(VhostUserError::Connect(_))
+        ));
+        assert!(builder.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let mut builder = VhostUserBuilder::new();
+        let backend = default_net_backend();
+        let mut cfg = net_config("vhu", backend.socket_path());
+        cfg.queue_size = 100;
+        assert!(matches!(
+            builder.insert_net(cfg).unwrap_err(),
+            VhostUserConfigError::CreateVhostUserDevice(VhostUserError::InvalidQueueSize(100))
+        ));
+        assert!(builder.is_empty());
+    }
+
+    #[test]
+    fn test_error_messages() {
+        use std::io;
+
+        use super::VhostUserConfigError::*;
+        let err = CreateVhostUserDevice(VhostUserError::Connect(io::Error::from_raw_os_error(0)));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = DeviceIdInUse(String::from("vhu"));
+        let _ = format!("{}{:?}", err, err);
+    }
+}
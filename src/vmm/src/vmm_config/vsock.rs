@@ -1,18 +1,24 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::convert::TryFrom;
+use std::convert::{TryFrom, TryInto};
 use std::fmt;
 use std::sync::{Arc, Mutex};
 
-use devices::virtio::{Vsock, VsockError, VsockUnixBackend, VsockUnixBackendError};
+use devices::virtio::{
+    Vsock, VsockError, VsockForwarding, VsockPortFilter, VsockUnixBackend, VsockUnixBackendError,
+};
 use serde::{Deserialize, Serialize};
 
+use super::RateLimiterConfig;
+
 type MutexVsockUnix = Arc<Mutex<Vsock<VsockUnixBackend>>>;
 
 /// Errors associated with `NetworkInterfaceConfig`.
 #[derive(Debug, derive_more::From)]
 pub enum VsockConfigError {
+    /// Failed to create the datagram `RateLimiter`.
+    CreateRateLimiter(std::io::Error),
     /// Failed to create the backend for the vsock device.
     CreateVsockBackend(VsockUnixBackendError),
     /// Failed to create the vsock device.
@@ -23,6 +29,7 @@ impl fmt::Display for VsockConfigError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::VsockConfigError::*;
         match *self {
+            CreateRateLimiter(ref err) => write!(f, "Cannot create RateLimiter: {}", err),
             CreateVsockBackend(ref err) => {
                 write!(f, "Cannot create backend for vsock device: {:?}", err)
             }
@@ -46,6 +53,34 @@ pub struct VsockDeviceConfig {
     pub guest_cid: u32,
     /// Path to local unix socket.
     pub uds_path: String,
+    /// Where the connections initiated by the guest are forwarded to. Defaults to the Unix
+    /// sockets at `<uds_path>_<port>`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forwarding: Option<VsockForwarding>,
+    /// The rules deciding which ports the guest can connect to. Defaults to allowing all of them.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port_filter: Option<VsockPortFilter>,
+    /// Enables datagrams, which are disabled by default.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dgram: Option<VsockDgramConfig>,
+    /// Preserves the established connections across snapshot/restore, instead of resetting
+    /// them. Disabled by default.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preserve_connections: Option<bool>,
+}
+
+/// The configuration of vsock datagrams.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VsockDgramConfig {
+    /// The rate limiter applied to the datagrams sent by the guest.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limiter: Option<RateLimiterConfig>,
 }
 
 struct VsockAndUnixPath {
@@ -56,10 +91,22 @@ struct VsockAndUnixPath {
 impl From<&VsockAndUnixPath> for VsockDeviceConfig {
     fn from(vsock: &VsockAndUnixPath) -> Self {
         let vsock_lock = vsock.vsock.lock().unwrap();
+        let backend = vsock_lock.backend();
         VsockDeviceConfig {
             vsock_id: None,
             guest_cid: u32::try_from(vsock_lock.cid()).unwrap(),
             uds_path: vsock.uds_path.clone(),
+            forwarding: Some(backend.forwarding().clone())
+                .filter(|forwarding| *forwarding != VsockForwarding::Unix),
+            port_filter: Some(backend.port_filter().clone())
+                .filter(|port_filter| *port_filter != VsockPortFilter::default()),
+            dgram: backend
+                .dgram_rate_limiter()
+                .map(|rate_limiter| VsockDgramConfig {
+                    rate_limiter: RateLimiterConfig::from(rate_limiter).into_option(),
+                }),
+            preserve_connections: Some(backend.preserve_connections())
+                .filter(|preserve_connections| *preserve_connections),
         }
     }
 }
@@ -94,7 +141,16 @@ impl VsockBuilder {
     pub fn insert(&mut self, cfg: VsockDeviceConfig) -> Result<()> {
         // Make sure to drop the old one and remove the socket before creating a new one.
         if let Some(existing) = self.inner.take() {
+            let dgram_sock_path = existing
+                .vsock
+                .lock()
+                .expect("Poisoned lock")
+                .backend()
+                .dgram_sock_path();
             std::fs::remove_file(existing.uds_path).map_err(VsockUnixBackendError::UnixBind)?;
+            if let Some(dgram_sock_path) = dgram_sock_path {
+                std::fs::remove_file(dgram_sock_path).map_err(VsockUnixBackendError::UnixBind)?;
+            }
         }
         self.inner = Some(VsockAndUnixPath {
             uds_path: cfg.uds_path.clone(),
@@ -110,7 +166,24 @@ impl VsockBuilder {
 
     /// Creates a Vsock device from a VsockDeviceConfig.
     pub fn create_unixsock_vsock(cfg: VsockDeviceConfig) -> Result<Vsock<VsockUnixBackend>> {
-        let backend = VsockUnixBackend::new(u64::from(cfg.guest_cid), cfg.uds_path)?;
+        let dgram_rate_limiter = cfg
+            .dgram
+            .map(|dgram| {
+                dgram
+                    .rate_limiter
+                    .map(RateLimiterConfig::try_into)
+                    .transpose()
+                    .map(Option::unwrap_or_default)
+            })
+            .transpose()?;
+        let backend = VsockUnixBackend::new(
+            u64::from(cfg.guest_cid),
+            cfg.uds_path,
+            cfg.forwarding.unwrap_or_default(),
+            cfg.port_filter.unwrap_or_default(),
+            dgram_rate_limiter,
+            cfg.preserve_connections.unwrap_or(false),
+        )?;
 
         Vsock::new(u64::from(cfg.guest_cid), backend).map_err(VsockConfigError::CreateVsockDevice)
     }
@@ -133,6 +206,10 @@ pub(crate) mod tests {
             vsock_id: None,
             guest_cid: 3,
             uds_path: tmp_sock_file.as_path().to_str().unwrap().to_string(),
+            forwarding: None,
+            port_filter: None,
+            dgram: None,
+            preserve_connections: None,
         }
     }
 
@@ -175,6 +252,120 @@ pub(crate) mod tests {
         assert_eq!(config.unwrap(), vsock_config);
     }
 
+    #[test]
+    fn test_vsock_forwarding_config() {
+        let mut vsock_builder = VsockBuilder::new();
+        let mut tmp_sock_file = TempFile::new().unwrap();
+        tmp_sock_file.remove().unwrap();
+        let vsock_config: VsockDeviceConfig = serde_json::from_str(&format!(
+            r#"{{
+                "guest_cid": 3,
+                "uds_path": "{}",
+                "forwarding": {{
+                    "type": "tcp",
+                    "port_map": [{{"port": 52, "host_address": "127.0.0.1:8080"}}]
+                }},
+                "port_filter": {{
+                    "rules": [{{"action": "allow", "first_port": 52}}],
+                    "default_action": "deny"
+                }}
+            }}"#,
+            tmp_sock_file.as_path().to_str().unwrap()
+        ))
+        .unwrap();
+        vsock_builder.insert(vsock_config.clone()).unwrap();
+        assert_eq!(vsock_builder.config().unwrap(), vsock_config);
+
+        let vsock = vsock_builder.get().unwrap().lock().unwrap();
+        assert!(vsock.backend().port_filter().is_allowed(52));
+        assert!(!vsock.backend().port_filter().is_allowed(53));
+        drop(vsock);
+
+        // The forwarding to the guest's own CID is refused.
+        let mut vsock_config = default_config(&tmp_sock_file);
+        vsock_config.forwarding = Some(VsockForwarding::Vsock { cid: 3 });
+        assert!(matches!(
+            vsock_builder.insert(vsock_config),
+            Err(VsockConfigError::CreateVsockBackend(
+                VsockUnixBackendError::InvalidForwardingCid(3)
+            ))
+        ));
+
+        assert!(serde_json::from_str::<VsockForwarding>(r#"{"type": "udp"}"#).is_err());
+        assert!(serde_json::from_str::<VsockPortFilter>(r#"{"default_action": "drop"}"#).is_err());
+    }
+
+    #[test]
+    fn test_vsock_dgram_config() {
+        let mut vsock_builder = VsockBuilder::new();
+        let mut tmp_sock_file = TempFile::new().unwrap();
+        tmp_sock_file.remove().unwrap();
+        let vsock_config: VsockDeviceConfig = serde_json::from_str(&format!(
+            r#"{{
+                "guest_cid": 3,
+                "uds_path": "{}",
+                "dgram": {{
+                    "rate_limiter": {{"ops": {{"size": 100, "refill_time": 1000}}}}
+                }}
+            }}"#,
+            tmp_sock_file.as_path().to_str().unwrap()
+        ))
+        .unwrap();
+        vsock_builder.insert(vsock_config.clone()).unwrap();
+        assert_eq!(vsock_builder.config().unwrap(), vsock_config);
+        let dgram_sock_path = format!("{}_dgram", vsock_config.uds_path);
+        assert!(std::path::Path::new(&dgram_sock_path).exists());
+
+        // Datagrams can be enabled without any rate limiting. Replacing the device also
+        // replaces the datagram socket.
+        let mut vsock_config = default_config(&tmp_sock_file);
+        vsock_config.dgram = Some(VsockDgramConfig::default());
+        vsock_builder.insert(vsock_config.clone()).unwrap();
+        assert_eq!(vsock_builder.config().unwrap(), vsock_config);
+
+        vsock_builder
+            .insert(default_config(&tmp_sock_file))
+            .unwrap();
+        assert!(vsock_builder.config().unwrap().dgram.is_none());
+        assert!(!std::path::Path::new(&dgram_sock_path).exists());
+
+        assert!(serde_json::from_str::<VsockDgramConfig>(r#"{"rate": {}}"#).is_err());
+    }
+
+    #[test]
+    fn test_vsock_preserve_connections_config() {
+        let mut vsock_builder = VsockBuilder::new();
+        let mut tmp_sock_file = TempFile::new().unwrap();
+        tmp_sock_file.remove().unwrap();
+        let vsock_config: VsockDeviceConfig = serde_json::from_str(&format!(
+            r#"{{
+                "guest_cid": 3,
+                "uds_path": "{}",
+                "preserve_connections": true
+            }}"#,
+            tmp_sock_file.as_path().to_str().unwrap()
+        ))
+        .unwrap();
+        vsock_builder.insert(vsock_config.clone()).unwrap();
+        assert_eq!(vsock_builder.config().unwrap(), vsock_config);
+        assert!(vsock_builder
+            .get()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .backend()
+            .preserve_connections());
+
+        // Explicitly disabling it is the same as the default.
+        let mut vsock_config = default_config(&tmp_sock_file);
+        vsock_config.preserve_connections = Some(false);
+        vsock_builder.insert(vsock_config).unwrap();
+        assert_eq!(
+            vsock_builder.config().unwrap(),
+            default_config(&tmp_sock_file)
+        );
+    }
+
     #[test]
     fn test_error_messages() {
         use std::io;
@@ -189,6 +380,9 @@ pub(crate) mod tests {
             io::Error::from_raw_os_error(0),
         ));
         let _ = format!("{}{:?}", err, err);
+
+        let err = CreateRateLimiter(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
     }
 
     #[test]
@@ -198,8 +392,15 @@ pub(crate) mod tests {
         tmp_sock_file.remove().unwrap();
         let vsock = Vsock::new(
             0,
-            VsockUnixBackend::new(1, tmp_sock_file.as_path().to_str().unwrap().to_string())
-                .unwrap(),
+            VsockUnixBackend::new(
+                1,
+                tmp_sock_file.as_path().to_str().unwrap().to_string(),
+                VsockForwarding::Unix,
+                VsockPortFilter::default(),
+                None,
+                false,
+            )
+            .unwrap(),
         )
         .unwrap();
 
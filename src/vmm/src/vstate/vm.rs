@@ -20,6 +20,7 @@ use kvm_bindings::{
 };
 use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
 use kvm_ioctls::{Kvm, VmFd};
+use logger::warn;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
@@ -30,6 +31,8 @@ pub enum Error {
     #[cfg(target_arch = "x86_64")]
     /// Retrieving supported guest MSRs fails.
     GuestMSRs(arch::x86_64::msr::Error),
+    /// A memory region backed by huge pages is not aligned to their size.
+    HugePageAlignment(u64),
     /// The number of configured slots is bigger than the maximum reported by KVM.
     NotEnoughMemorySlots,
     /// Cannot set the memory regions.
@@ -78,6 +81,11 @@ impl Display for Error {
                 "Error creating the global interrupt controller: {:?}",
                 err
             ),
+            HugePageAlignment(addr) => write!(
+                f,
+                "The memory region at guest address {:#x} is not aligned to its huge pages",
+                addr
+            ),
             VmFd(err) => write!(f, "Cannot open the VM file descriptor: {}", err),
             VmSetup(err) => write!(f, "Cannot configure the microvm: {}", err),
             NotEnoughMemorySlots => write!(
@@ -170,6 +178,25 @@ impl Vm {
         if guest_mem.num_regions() > kvm_max_memslots {
             return Err(Error::NotEnoughMemorySlots);
         }
+        for region in guest_mem.iter() {
+            if let Some(huge_page_size) = vm_memory::huge_page_size(region) {
+                // KVM only maps the guest memory with huge pages when both the guest and the
+                // host addresses of the region are aligned to them.
+                let guest_addr = region.start_addr().raw_value();
+                if guest_addr % huge_page_size as u64 != 0
+                    || region.as_ptr() as usize % huge_page_size != 0
+                {
+                    return Err(Error::HugePageAlignment(guest_addr));
+                }
+                if track_dirty_pages {
+                    warn!(
+                        "Dirty page tracking splits the huge pages of the guest memory at {:#x} \
+                         into small ones in the KVM mappings.",
+                        guest_addr
+                    );
+                }
+            }
+        }
         self.set_kvm_memory_regions(guest_mem, track_dirty_pages)?;
         #[cfg(target_arch = "x86_64")]
         self.fd
@@ -406,6 +433,21 @@ pub(crate) mod tests {
         assert!(vm
             .memory_init(&gm, kvm_context.max_memslots(), true)
             .is_ok());
+
+        // Huge pages can't back a region which is not aligned to them. The host may not have
+        // any huge pages to spare.
+        let huge_page_size = 2 << 20;
+        if let Ok(gm) = vm_memory::create_shared_guest_memory(
+            &[(GuestAddress(0x1000), huge_page_size)],
+            Some(huge_page_size),
+            false,
+        ) {
+            let mut vm = Vm::new(kvm_context.fd()).expect("Cannot create new vm");
+            assert!(matches!(
+                vm.memory_init(&gm, kvm_context.max_memslots(), false),
+                Err(Error::HugePageAlignment(0x1000))
+            ));
+        }
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -17,7 +17,7 @@ use vmm::utilities::test_utils::dirty_tracking_vmm;
 use vmm::utilities::test_utils::{create_vmm, default_vmm};
 use vmm::version_map::VERSION_MAP;
 use vmm::vmm_config::instance_info::InstanceInfo;
-use vmm::vmm_config::snapshot::{CreateSnapshotParams, SnapshotType};
+use vmm::vmm_config::snapshot::{CreateSnapshotParams, MemFileFormat, SnapshotType};
 use vmm::{EventManager, FcExitCode};
 
 #[test]
@@ -160,6 +160,7 @@ fn verify_create_snapshot(is_diff: bool) -> (TempFile, TempFile) {
         snapshot_type,
         snapshot_path: snapshot_file.as_path().to_path_buf(),
         mem_file_path: memory_file.as_path().to_path_buf(),
+        mem_file_format: MemFileFormat::Raw,
         version: Some(String::from("0.24.0")),
     };
 
@@ -47,6 +47,7 @@ from framework.resources import (
     MMDS,
     MachineConfigure,
     Metrics,
+    Migration,
     Network,
     Vm,
     Vsock,
@@ -130,6 +131,7 @@ class Microvm:
         self.full_cfg = None
         self.logger = None
         self.metrics = None
+        self.migration = None
         self.mmds = None
         self.network = None
         self.machine_cfg = None
@@ -528,6 +530,7 @@ class Microvm:
             self._api_socket, self._api_session, self.firecracker_version
         )
         self.metrics = Metrics(self._api_socket, self._api_session)
+        self.migration = Migration(self._api_socket, self._api_session)
         self.mmds = MMDS(self._api_socket, self._api_session)
         self.network = Network(self._api_socket, self._api_session)
         self.snapshot = SnapshotHelper(self._api_socket, self._api_session)
@@ -430,6 +430,41 @@ class SnapshotLoad:
         return datax
 
 
+class Migration:
+    """Facility for sending live migration commands on the microvm."""
+
+    MIGRATION_URL = "migration"
+
+    def __init__(self, api_usocket_full_name, api_session):
+        """Specify the information needed for sending API requests."""
+        url_encoded_path = urllib.parse.quote_plus(api_usocket_full_name)
+        api_url = API_USOCKET_URL_PREFIX + url_encoded_path + "/"
+        self._migration_cfg_url = api_url + self.MIGRATION_URL
+        self._api_session = api_session
+
+    def send(self, transport, address, max_rounds=None, dirty_pages_threshold=None):
+        """Migrate the microvm to the Firecracker process at `address`."""
+        datax = {"destination": {"transport": transport, "address": address}}
+        if max_rounds is not None:
+            datax["max_rounds"] = max_rounds
+        if dirty_pages_threshold is not None:
+            datax["dirty_pages_threshold"] = dirty_pages_threshold
+        return self._api_session.put(
+            "{}/send".format(self._migration_cfg_url), json=datax
+        )
+
+    def receive(self, transport, address, diff=False, resume=False, timeout=None):
+        """Wait for a microvm to be migrated to `address`."""
+        datax = {"source": {"transport": transport, "address": address}}
+        if diff:
+            datax["enable_diff_snapshots"] = True
+        if resume:
+            datax["resume_vm"] = True
+        return self._api_session.put(
+            "{}/receive".format(self._migration_cfg_url), json=datax, timeout=timeout
+        )
+
+
 class SnapshotHelper:
     """Facility for creation and loading of microvm snapshots."""
 
@@ -1241,6 +1241,8 @@ def test_get_full_config_after_restoring_snapshot(bin_cloner_path):
 
     setup_cfg["logger"] = None
     setup_cfg["metrics"] = None
+    setup_cfg["vhost-user-block"] = []
+    setup_cfg["vhost-user-net"] = []
     setup_cfg["mmds-config"] = {
         "version": "V1",
         "network_interfaces": [DEFAULT_DEV_NAME],
@@ -1395,6 +1397,8 @@ def test_get_full_config(test_microvm_with_api):
 
     expected_cfg["logger"] = None
     expected_cfg["metrics"] = None
+    expected_cfg["vhost-user-block"] = []
+    expected_cfg["vhost-user-net"] = []
     expected_cfg["mmds-config"] = {
         "version": "V2",
         "ipv4_address": "169.254.169.250",
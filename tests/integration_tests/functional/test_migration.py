@@ -0,0 +1,163 @@
+# Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+# SPDX-License-Identifier: Apache-2.0
+"""Tests for the live migration of microVMs between Firecracker processes."""
+
+import os
+import socket
+import time
+from concurrent.futures import ThreadPoolExecutor
+
+from conftest import init_microvm
+from framework.artifacts import NetIfaceConfig
+from framework.builder import MicrovmBuilder
+import host_tools.network as net_tools  # pylint: disable=import-error
+
+MIGRATION_SOCKET = "migration.sock"
+
+
+def _spawn_destination(builder, bin_cloner_path, vm_instance):
+    """Spawn a fresh Firecracker process able to take over `vm_instance`."""
+    dest = init_microvm(builder.root_path, bin_cloner_path)
+    dest.spawn()
+    # The receive request blocks until the migration completes.
+    dest.api_session.untime()
+
+    # The destination uses the same disks, at the same path in its jail.
+    for disk in vm_instance.disks:
+        dest.create_jailed_resource(disk.local_path())
+
+    iface = NetIfaceConfig()
+    dest.create_tap_and_ssh_config(
+        host_ip=iface.host_ip,
+        guest_ip=iface.guest_ip,
+        netmask_len=iface.netmask,
+        tapname=iface.tap_name,
+    )
+    dest.ssh_config["ssh_key_path"] = vm_instance.ssh_key.local_path()
+    return dest
+
+
+def _wait_for_file(path, timeout=10):
+    """Wait for `path` to be created."""
+    deadline = time.time() + timeout
+    while not os.path.exists(path):
+        assert time.time() < deadline, "{} was not created".format(path)
+        time.sleep(0.1)
+
+
+def test_live_migration(bin_cloner_path):
+    """
+    Test migrating a running microVM over a unix domain socket.
+
+    @type: functional
+    """
+    builder = MicrovmBuilder(bin_cloner_path)
+    vm_instance = builder.build_vm_nano(diff_snapshots=True)
+    source = vm_instance.vm
+    source.start()
+
+    ssh_connection = net_tools.SSHConnection(source.ssh_config)
+    # Keep the guest dirtying memory while it is migrated.
+    exit_code, _, _ = ssh_connection.execute_command(
+        "echo migrated > /tmp/marker && sync && "
+        "(dd if=/dev/urandom of=/dev/shm/blob bs=1M count=32 > /dev/null 2>&1 &)"
+    )
+    assert exit_code == 0
+
+    dest = _spawn_destination(builder, bin_cloner_path, vm_instance)
+    source.api_session.untime()
+    with ThreadPoolExecutor(max_workers=1) as executor:
+        receive = executor.submit(
+            dest.migration.receive, "Unix", "/" + MIGRATION_SOCKET, resume=True
+        )
+
+        # Make the socket created in the destination jail reachable from the
+        # source jail.
+        dest_socket = os.path.join(dest.jailer.chroot_path(), MIGRATION_SOCKET)
+        _wait_for_file(dest_socket)
+        os.link(
+            dest_socket, os.path.join(source.jailer.chroot_path(), MIGRATION_SOCKET)
+        )
+
+        response = source.migration.send(
+            "Unix", "/" + MIGRATION_SOCKET, max_rounds=4, dirty_pages_threshold=64
+        )
+        assert source.api_session.is_status_no_content(response.status_code)
+        response = receive.result()
+        assert dest.api_session.is_status_no_content(response.status_code)
+
+    # The source is left paused.
+    response = source.desc_inst.get()
+    assert source.api_session.is_status_ok(response.status_code)
+    assert "Paused" in response.text
+    source.kill()
+
+    # The guest keeps running on the destination.
+    ssh_connection = net_tools.SSHConnection(dest.ssh_config)
+    exit_code, stdout, _ = ssh_connection.execute_command("cat /tmp/marker")
+    assert exit_code == 0
+    assert stdout.read().strip() == "migrated"
+
+    dest.kill()
+
+
+def test_send_migration_without_dirty_tracking(bin_cloner_path):
+    """
+    Test that live migration requires dirty page tracking.
+
+    @type: functional
+    """
+    builder = MicrovmBuilder(bin_cloner_path)
+    vm_instance = builder.build_vm_nano()
+    microvm = vm_instance.vm
+    microvm.start()
+
+    response = microvm.migration.send("Unix", "/" + MIGRATION_SOCKET)
+    assert microvm.api_session.is_status_bad_request(response.status_code)
+    assert "dirty page tracking disabled" in response.text
+
+
+def test_guest_io_during_pre_copy(bin_cloner_path):
+    """
+    Test that the devices keep serving the guest while its memory is sent.
+
+    @type: functional
+    """
+    builder = MicrovmBuilder(bin_cloner_path)
+    vm_instance = builder.build_vm_nano(diff_snapshots=True)
+    microvm = vm_instance.vm
+    microvm.start()
+    ssh_connection = net_tools.SSHConnection(microvm.ssh_config)
+
+    # Stand-in destination which doesn't read the guest memory, so that the
+    # first round stalls once the socket buffer is full.
+    socket_path = os.path.join(microvm.jailer.chroot_path(), MIGRATION_SOCKET)
+    listener = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    listener.bind(socket_path)
+    os.chmod(socket_path, 0o777)
+    listener.listen(1)
+    listener.settimeout(10)
+
+    microvm.api_session.untime()
+    with ThreadPoolExecutor(max_workers=1) as executor:
+        send = executor.submit(microvm.migration.send, "Unix", "/" + MIGRATION_SOCKET)
+        conn, _ = listener.accept()
+
+        # The network and block devices are served during the pre-copy.
+        exit_code, stdout, _ = ssh_connection.execute_command(
+            "echo pre-copy > /tmp/marker && sync && cat /tmp/marker"
+        )
+        assert exit_code == 0
+        assert stdout.read().strip() == "pre-copy"
+        assert not send.done()
+
+        # The migration fails once the destination goes away, and the microVM
+        # keeps running.
+        conn.close()
+        response = send.result()
+        assert microvm.api_session.is_status_bad_request(response.status_code)
+    listener.close()
+
+    response = microvm.desc_inst.get()
+    assert microvm.api_session.is_status_ok(response.status_code)
+    assert "Running" in response.text
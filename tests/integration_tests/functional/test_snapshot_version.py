@@ -163,7 +163,14 @@ def test_create_with_newer_virtio_features(bin_cloner_path):
     # we can be sure that the block device was activated.
     iface = NetIfaceConfig()
     test_microvm.ssh_config["hostname"] = iface.guest_ip
-    _ssh_connection = net_tools.SSHConnection(test_microvm.ssh_config)
+    ssh_connection = net_tools.SSHConnection(test_microvm.ssh_config)
+
+    # Guest kernels with virtio-blk discard support negotiate it for the
+    # writable rootfs, which older versions can't restore.
+    _, stdout, _ = ssh_connection.execute_command(
+        "cat /sys/block/vda/queue/discard_max_bytes"
+    )
+    discard_negotiated = int(stdout.read().strip()) != 0
 
     # Create directory and files for saving snapshot state and memory.
     snapshot_builder = SnapshotBuilder(test_microvm)
@@ -205,10 +212,26 @@ def test_create_with_newer_virtio_features(bin_cloner_path):
         "with older versions of Firecracker: notification suppression" in response.text
     )
 
-    # It should work when we target a version >= 1.1.0
+    # Targeting 1.1.0 fails only if the guest negotiated discard, which
+    # is supported starting with 1.2.0.
     response = test_microvm.snapshot.create(
         mem_file_path="/snapshot/vm.mem",
         snapshot_path="/snapshot/vm.vmstate",
         version="1.1.0",
     )
+    if discard_negotiated:
+        assert test_microvm.api_session.is_status_bad_request(response.status_code)
+        assert (
+            "The virtio devices use a features that is incompatible "
+            "with older versions of Firecracker: discard" in response.text
+        )
+    else:
+        assert test_microvm.api_session.is_status_no_content(response.status_code)
+
+    # It should work when we target a version >= 1.2.0
+    response = test_microvm.snapshot.create(
+        mem_file_path="/snapshot/vm.mem",
+        snapshot_path="/snapshot/vm.vmstate",
+        version="1.2.0",
+    )
     assert test_microvm.api_session.is_status_no_content(response.status_code)